@@ -0,0 +1,23 @@
+//! Generates the gRPC service code of `proto/deepclaude.proto`.
+//!
+//! The messages are written by hand in `src/grpc.rs` with prost's derive
+//! macros, so the build does not need `protoc`; only the service traits,
+//! server and client are generated here.
+
+fn main() {
+    let chat = tonic_build::manual::Method::builder()
+        .name("chat")
+        .route_name("Chat")
+        .input_type("crate::grpc::ChatRequest")
+        .output_type("crate::grpc::ChatEvent")
+        .codec_path("tonic_prost::ProstCodec")
+        .server_streaming()
+        .build();
+    let service = tonic_build::manual::Service::builder()
+        .name("DeepClaude")
+        .package("deepclaude.v1")
+        .method(chat)
+        .build();
+    tonic_build::manual::Builder::new().compile(&[service]);
+    println!("cargo:rerun-if-changed=build.rs");
+}
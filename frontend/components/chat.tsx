@@ -484,6 +484,12 @@ export function Chat({ apiTokens }: ChatProps) {
               throw new Error(data.error.message)
             }
 
+            // 推理阶段结束，从“思考中”切换到“回答中”
+            if (data.deepclaude_event === "responder_started") {
+              setIsThinkingComplete(true)
+              return
+            }
+
             if (data.choices && data.choices[0]) {
               const choice = data.choices[0]
               if (!currentMessageRef.current) return
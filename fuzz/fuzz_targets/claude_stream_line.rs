@@ -0,0 +1,10 @@
+#![no_main]
+
+use deepclaude::clients::{hosts, parse};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|line: &str| {
+    let _ = parse::claude_stream_line(line, &hosts::OPENAI_USAGE);
+    let (repaired, _) = parse::repair_line(line);
+    let _ = parse::claude_stream_line(&repaired, &hosts::OPENAI_USAGE);
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use deepclaude::clients::{hosts, parse};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|body: &str| {
+    let _ = parse::extract_content_from_response(body);
+    let _ = parse::extract_id_from_response(body);
+    let _ = parse::extract_model_from_response(body);
+    let _ = parse::extract_usage_from_response(body, &hosts::OPENAI_USAGE);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use deepclaude::clients::{hosts, parse};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|body: &str| {
+    let _ = parse::parse_deepseek_response(body, &hosts::OPENAI_USAGE);
+});
@@ -0,0 +1,28 @@
+#![no_main]
+
+//! Splitting a stream into chunks at any point must not change its lines,
+//! and every line must parse without panicking.
+
+use deepclaude::clients::{
+    hosts,
+    parse::{self, LineBuffer},
+};
+use libfuzzer_sys::fuzz_target;
+
+fn lines(chunks: &[&[u8]]) -> Vec<String> {
+    let mut buffer = LineBuffer::default();
+    let mut lines: Vec<String> = chunks.iter().flat_map(|chunk| buffer.push(chunk)).collect();
+    lines.extend(buffer.finish());
+    lines
+}
+
+fuzz_target!(|input: (u16, &[u8])| {
+    let (split, stream) = input;
+    let split = usize::from(split) % (stream.len() + 1);
+    let whole = lines(&[stream]);
+    assert_eq!(whole, lines(&[&stream[..split], &stream[split..]]));
+    for line in &whole {
+        let _ = parse::sse_data(line);
+        let _ = parse::claude_stream_line(line, &hosts::OPENAI_USAGE);
+    }
+});
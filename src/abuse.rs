@@ -0,0 +1,343 @@
+//! Per-IP throttling and bans, independent of API keys.
+//!
+//! With `[abuse] enabled`, every request is counted against its client
+//! address: past `requests_per_minute` within a minute further requests get
+//! 429 with `Retry-After`, and an address whose requests were refused as
+//! unauthenticated (401) `max_auth_failures` times within
+//! `failure_window_secs` is banned for `ban_secs`. Banned addresses get 403
+//! on every route. Addresses in `allow` are never throttled or banned.
+//!
+//! The client address is the peer of the connection. With
+//! `trust_forwarded_for`, for deployments behind reverse proxies, requests
+//! whose peer is one of `trusted_proxies` are instead counted against the
+//! rightmost `X-Forwarded-For` entry that is not itself a trusted proxy:
+//! entries further left were written by the client and prove nothing.
+//! Requests over a Unix socket have no address and pass.
+//!
+//! The ban table is shown and edited through the admin API; bans added
+//! there apply even when `[abuse]` is disabled:
+//!
+//! - `GET /admin/bans` - list the active bans
+//! - `PUT /admin/bans/{ip}` - ban an address
+//! - `DELETE /admin/bans/{ip}` - lift a ban
+//!
+//! Bans are kept in memory and do not survive a restart. Throttled requests
+//! and automatic bans are counted at `GET /metrics`.
+
+use crate::{
+    config::AbuseConfig,
+    error::{ApiError, Result},
+    handlers::AppState,
+};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
+
+/// Length of a throttling window.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Tracked addresses above which idle ones are forgotten.
+const MAX_TRACKED: usize = 10_000;
+
+/// Bans kept at most; past it the automatic ban that ends first is lifted.
+const MAX_BANS: usize = 10_000;
+
+/// `banned_by` of the bans added after authentication failures.
+const AUTOMATIC: &str = "automatic";
+
+/// Recent activity of one address.
+struct Client {
+    window_start: Instant,
+    requests: u32,
+    failures: VecDeque<Instant>,
+}
+
+impl Client {
+    fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            requests: 0,
+            failures: VecDeque::new(),
+        }
+    }
+
+    fn idle(&self, failure_window: Duration) -> bool {
+        self.window_start.elapsed() >= WINDOW && self.failures.back().is_none_or(|at| at.elapsed() >= failure_window)
+    }
+}
+
+/// An entry of the ban table.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Ban {
+    pub ip: String,
+    pub reason: String,
+    /// The admin who added the ban, or `automatic`.
+    pub banned_by: String,
+    pub created_at: DateTime<Utc>,
+    /// When the ban ends; `None` keeps it until it is lifted.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Ban {
+    fn active(&self) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > Utc::now())
+    }
+}
+
+/// A ban added through `PUT /admin/bans/{ip}`.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct BanRequest {
+    /// How long the ban lasts; `[abuse] ban_secs` if omitted, 0 until it is
+    /// lifted.
+    pub duration_secs: Option<u64>,
+    pub reason: Option<String>,
+}
+
+/// Throttles and bans client addresses.
+pub struct IpGuard {
+    config: AbuseConfig,
+    allow: Vec<IpAddr>,
+    trusted_proxies: Vec<IpAddr>,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+    bans: Mutex<HashMap<IpAddr, Ban>>,
+    throttled: AtomicU64,
+    auto_bans: AtomicU64,
+}
+
+impl IpGuard {
+    /// # Errors
+    ///
+    /// Fails if an `allow` or `trusted_proxies` entry is not an IP address,
+    /// or if `trust_forwarded_for` is set without any trusted proxy.
+    pub fn new(config: &AbuseConfig) -> anyhow::Result<Self> {
+        let parse = |list: &[String], name: &str| {
+            list.iter()
+                .map(|ip| ip.parse().map_err(|_| anyhow::anyhow!("[abuse] {}中的地址无效: {}", name, ip)))
+                .collect::<anyhow::Result<Vec<IpAddr>>>()
+        };
+        let allow = parse(&config.allow, "allow")?;
+        let trusted_proxies = parse(&config.trusted_proxies, "trusted_proxies")?;
+        if config.trust_forwarded_for && trusted_proxies.is_empty() {
+            anyhow::bail!("[abuse] trust_forwarded_for需要在trusted_proxies中列出反向代理的地址");
+        }
+        Ok(Self {
+            config: config.clone(),
+            allow,
+            trusted_proxies,
+            clients: Default::default(),
+            bans: Default::default(),
+            throttled: AtomicU64::new(0),
+            auto_bans: AtomicU64::new(0),
+        })
+    }
+
+    /// The address a request comes from, if known.
+    pub fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())?;
+        if !self.config.trust_forwarded_for || !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+        // 从右往左跳过可信代理自己添加的条目，更左边的条目由客户端填写，不可信
+        let mut client = peer;
+        let forwarded: Vec<_> = request.headers().get_all("x-forwarded-for").iter().collect();
+        for value in forwarded.into_iter().rev() {
+            let Ok(value) = value.to_str() else {
+                return Some(client);
+            };
+            for entry in value.rsplit(',') {
+                match entry.trim().parse() {
+                    Ok(ip) if self.trusted_proxies.contains(&ip) => client = ip,
+                    Ok(ip) => return Some(ip),
+                    Err(_) => return Some(client),
+                }
+            }
+        }
+        Some(client)
+    }
+
+    /// Admits a request from `ip`, counting it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Forbidden` while the address is banned, and
+    /// `ApiError::RateLimited` once it has used up its requests of the
+    /// current minute.
+    pub fn admit(&self, ip: IpAddr) -> Result<()> {
+        if self.allow.contains(&ip) {
+            return Ok(());
+        }
+        if let Some(ban) = self.active_ban(ip) {
+            let until = ban.expires_at.map_or("it is lifted".to_string(), |at| at.to_rfc3339());
+            return Err(ApiError::Forbidden {
+                message: format!("Requests from {} are banned until {}", ip, until),
+            });
+        }
+        if !self.config.enabled || self.config.requests_per_minute == 0 {
+            return Ok(());
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() > MAX_TRACKED {
+            let failure_window = Duration::from_secs(self.config.failure_window_secs);
+            clients.retain(|_, client| !client.idle(failure_window));
+        }
+        let client = clients.entry(ip).or_insert_with(Client::new);
+        if client.window_start.elapsed() >= WINDOW {
+            client.window_start = Instant::now();
+            client.requests = 0;
+        }
+        if client.requests >= self.config.requests_per_minute {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            let remaining = WINDOW.saturating_sub(client.window_start.elapsed());
+            return Err(ApiError::RateLimited {
+                provider: format!("client {}", ip),
+                retry_after_secs: remaining.as_secs().max(1),
+            });
+        }
+        client.requests += 1;
+        Ok(())
+    }
+
+    /// Records a request from `ip` refused as unauthenticated, banning the
+    /// address once it has failed too often.
+    pub fn record_auth_failure(&self, ip: IpAddr) {
+        if !self.config.enabled || self.config.max_auth_failures == 0 || self.allow.contains(&ip) {
+            return;
+        }
+        let failure_window = Duration::from_secs(self.config.failure_window_secs);
+        let failures = {
+            let mut clients = self.clients.lock().unwrap();
+            let client = clients.entry(ip).or_insert_with(Client::new);
+            client.failures.retain(|at| at.elapsed() < failure_window);
+            client.failures.push_back(Instant::now());
+            if client.failures.len() < self.config.max_auth_failures as usize {
+                return;
+            }
+            client.failures.clear();
+            self.config.max_auth_failures
+        };
+
+        self.auto_bans.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!("{}在{}秒内认证失败{}次，封禁{}秒", ip, self.config.failure_window_secs, failures, self.config.ban_secs);
+        let reason = format!("{} authentication failures within {}s", failures, self.config.failure_window_secs);
+        self.ban(ip, Some(self.config.ban_secs), reason, AUTOMATIC);
+    }
+
+    /// Bans an address for `duration_secs` (`[abuse] ban_secs` if `None`,
+    /// until lifted if 0), replacing any ban it already has.
+    pub fn ban(&self, ip: IpAddr, duration_secs: Option<u64>, reason: String, banned_by: &str) -> Ban {
+        let created_at = Utc::now();
+        let duration = duration_secs.unwrap_or(self.config.ban_secs);
+        let ban = Ban {
+            ip: ip.to_string(),
+            reason,
+            banned_by: banned_by.to_string(),
+            created_at,
+            expires_at: (duration > 0).then(|| created_at + chrono::Duration::seconds(duration.min(i64::MAX as u64) as i64)),
+        };
+        let mut bans = self.bans.lock().unwrap();
+        if bans.len() >= MAX_BANS && !bans.contains_key(&ip) {
+            bans.retain(|_, ban| ban.active());
+            // 仍然已满时解除最早到期的自动封禁，管理员添加的封禁始终保留
+            if bans.len() >= MAX_BANS {
+                let soonest = bans
+                    .iter()
+                    .filter(|(_, ban)| ban.banned_by == AUTOMATIC)
+                    .min_by_key(|(_, ban)| ban.expires_at)
+                    .map(|(ip, _)| *ip);
+                if let Some(soonest) = soonest {
+                    bans.remove(&soonest);
+                }
+            }
+        }
+        bans.insert(ip, ban.clone());
+        ban
+    }
+
+    /// Lifts a ban and forgets the address's failures; `false` if it had
+    /// no active ban.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&ip) {
+            client.failures.clear();
+        }
+        self.bans.lock().unwrap().remove(&ip).is_some_and(|ban| ban.active())
+    }
+
+    fn active_ban(&self, ip: IpAddr) -> Option<Ban> {
+        let mut bans = self.bans.lock().unwrap();
+        match bans.get(&ip) {
+            Some(ban) if ban.active() => Some(ban.clone()),
+            Some(_) => {
+                bans.remove(&ip);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// The active bans, oldest first.
+    pub fn bans(&self) -> Vec<Ban> {
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|_, ban| ban.active());
+        let mut active: Vec<_> = bans.values().cloned().collect();
+        active.sort_by_key(|ban| ban.created_at);
+        active
+    }
+
+    /// Renders the throttling and ban counters in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP deepclaude_ip_throttled_total Requests refused because their client address exceeded requests_per_minute.\n# TYPE deepclaude_ip_throttled_total counter\ndeepclaude_ip_throttled_total {}",
+            self.throttled.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP deepclaude_ip_auto_bans_total Client addresses banned after repeated authentication failures.\n# TYPE deepclaude_ip_auto_bans_total counter\ndeepclaude_ip_auto_bans_total {}",
+            self.auto_bans.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP deepclaude_ip_bans Client addresses currently banned.\n# TYPE deepclaude_ip_bans gauge\ndeepclaude_ip_bans {}",
+            self.bans().len()
+        );
+        out
+    }
+}
+
+/// Middleware applying the guard to every route.
+pub async fn guard(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let guard = &state.ip_guard;
+    let Some(ip) = guard.client_ip(&request) else {
+        return next.run(request).await;
+    };
+    if let Err(e) = guard.admit(ip) {
+        return e.into_response();
+    }
+    let response = next.run(request).await;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        guard.record_auth_failure(ip);
+    }
+    response
+}
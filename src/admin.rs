@@ -0,0 +1,475 @@
+//! Administrative endpoints.
+//!
+//! - `POST /admin/providers/reload` - re-resolve upstream provider settings
+//! - `GET /admin/deadletter` - list failed requests awaiting replay
+//! - `POST /admin/deadletter/{id}/replay` - replay a failed request
+//! - `GET /admin/streams` - list the running streaming responses
+//! - `GET /admin/flags` - show the runtime feature flags and their audit trail
+//! - `PUT /admin/flags` - change runtime feature flags
+//! - `GET /admin/bans` - list the banned client addresses
+//! - `PUT /admin/bans/{ip}` - ban a client address
+//! - `DELETE /admin/bans/{ip}` - lift a ban
+//! - `GET /admin/logs/stream` - follow the log, see [`crate::logs`]
+//! - `GET /admin/requests/{id}/payloads` - the bodies a request sent
+//!   upstream, see [`crate::payloads`]
+//! - `POST /admin/purge` - purge expired data or erase a tenant's, see
+//!   [`crate::retention`]
+//! - `GET /admin/tenants/{id}/export` - export a tenant's data, see
+//!   [`crate::tenants`]
+//!
+//! All routes under `/admin` require `Authorization: Bearer <token>` with the
+//! `[admin] token` setting or one of the `[[admin.tokens]]`, and are disabled
+//! when no token is configured. Each named token has a role: viewers can
+//! read the dashboards (usage, streams, flags, bans), operators can also
+//! reload providers, list and replay dead letters, which hold whole
+//! conversations, change flags and edit the ban table, and owners
+//! can do everything, including editing `.env` and purging or exporting data. The shared `[admin] token`
+//! is an owner. Once any admin token is configured, the `.env` endpoints
+//! require an owner token too; without one they stay open for the bundled
+//! settings page. With `[signing] admin_secret`, calls that change something
+//! must also be signed, see [`crate::signing`].
+
+use crate::{
+    abuse::BanRequest,
+    clients::providers,
+    config::AdminRole,
+    error::{ApiError, ErrorResponse, Result},
+    flags::{self, FlagUpdate},
+    handlers::{self, AppState},
+    models::response::OpenAICompatibleResponse,
+    secrets,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{net::IpAddr, sync::Arc};
+use utoipa::IntoParams;
+
+/// Request header naming the person behind an admin call, for audit trails.
+pub const ACTOR_HEADER: &str = "x-admin-user";
+
+/// Query parameters for `POST /admin/providers/reload`.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct ReloadParams {
+    pub dry_run: bool,
+}
+
+/// The admin behind a request.
+#[derive(Debug, Clone)]
+pub struct Admin {
+    pub name: String,
+    pub role: AdminRole,
+}
+
+/// Checks the admin bearer token on a request and that its role allows
+/// `role`'s endpoints.
+///
+/// # Errors
+///
+/// Returns `ApiError::Forbidden` if the admin API is disabled or the token's
+/// role is below `role`, or `ApiError::Unauthorized` if the token is missing
+/// or wrong.
+pub(crate) fn require_admin(state: &AppState, headers: &HeaderMap, role: AdminRole) -> Result<Admin> {
+    let admin = &state.config.admin;
+    let shared = secrets::expose(&admin.token).unwrap_or_default();
+    let mut tokens: Vec<(String, String, AdminRole)> = admin
+        .tokens
+        .iter()
+        .filter_map(|entry| Some((entry.name.clone(), secrets::expose(&entry.token)?, entry.role)))
+        .collect();
+    if !shared.is_empty() {
+        // 共用的令牌没有名称，审计时以X-Admin-User区分操作人
+        tokens.push((actor(headers), shared, AdminRole::Owner));
+    }
+    tokens.retain(|(_, token, _)| !token.is_empty());
+    if tokens.is_empty() {
+        return Err(ApiError::Forbidden {
+            message: "Admin API is disabled; set [admin] token to enable it".to_string(),
+        });
+    }
+
+    let provided = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    // 比较摘要而不是原文，避免按字节提前返回泄露令牌长度和前缀
+    let provided = Sha256::digest(provided.as_bytes());
+    let (name, _, granted) = tokens
+        .into_iter()
+        .find(|(_, token, _)| Sha256::digest(token.as_bytes()) == provided)
+        .ok_or_else(|| ApiError::Unauthorized {
+            message: "Invalid admin token".to_string(),
+        })?;
+    if granted < role {
+        return Err(ApiError::Forbidden {
+            message: format!(
+                "Admin token '{}' has the {} role; this endpoint needs {}",
+                name,
+                granted.name(),
+                role.name()
+            ),
+        });
+    }
+    Ok(Admin { name, role: granted })
+}
+
+/// Whether any admin token is configured, shared or named.
+pub(crate) fn admin_configured(state: &AppState) -> bool {
+    let admin = &state.config.admin;
+    !admin.token.is_empty() || admin.tokens.iter().any(|entry| !entry.token.is_empty())
+}
+
+/// Re-resolves upstream provider URLs, models and keys.
+///
+/// New requests pick up the new settings as soon as they are swapped in;
+/// requests already in flight finish with the settings they started with.
+/// With `?dry_run=true` the new settings are validated and diffed against
+/// the current ones without being applied.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` listing every validation error if the new
+/// settings are invalid. The current settings stay in effect in that case.
+#[utoipa::path(
+    post,
+    path = "/admin/providers/reload",
+    tag = "admin",
+    params(ReloadParams),
+    responses(
+        (status = 200, description = "The applied or previewed changes", body = Object),
+        (status = 400, description = "The new settings are invalid", body = ErrorResponse),
+    )
+)]
+pub async fn reload_providers(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<ReloadParams>,
+) -> Result<Json<serde_json::Value>> {
+    let admin = require_admin(&state, &headers, AdminRole::Operator)?;
+
+    let outcome = providers::reload(params.dry_run, &state.config.secrets)
+        .await
+        .map_err(|errors| ApiError::BadRequest {
+            message: format!("Provider settings are invalid: {}", errors.join("; ")),
+        })?;
+    if outcome.applied {
+        state.audit.record(&admin.name, "providers.reload", json!({ "changes": outcome.changes }));
+    }
+
+    Ok(Json(json!({
+        "status": "success",
+        "dry_run": params.dry_run,
+        "applied": outcome.applied,
+        "changes": outcome.changes,
+        "warnings": outcome.warnings,
+    })))
+}
+
+/// Lists the running streaming responses with their stage and age.
+#[utoipa::path(
+    get,
+    path = "/admin/streams",
+    tag = "admin",
+    responses((status = 200, description = "The running streams", body = Object))
+)]
+pub async fn list_streams(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>> {
+    require_admin(&state, &headers, AdminRole::Viewer)?;
+
+    let now = chrono::Utc::now();
+    let streams: Vec<_> = state
+        .streams
+        .list()
+        .into_iter()
+        .map(|info| {
+            let elapsed_ms = (now - info.started_at).num_milliseconds();
+            let mut entry = json!(info);
+            entry["elapsed_ms"] = json!(elapsed_ms);
+            entry
+        })
+        .collect();
+    Ok(Json(json!({
+        "status": "success",
+        "streams": streams,
+    })))
+}
+
+/// Who made a call with the shared admin token: the `X-Admin-User` header,
+/// or `admin`.
+pub(crate) fn actor(headers: &HeaderMap) -> String {
+    headers
+        .get(ACTOR_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or("admin")
+        .to_string()
+}
+
+/// Shows the runtime feature flags and the changes made to them.
+#[utoipa::path(
+    get,
+    path = "/admin/flags",
+    tag = "admin",
+    responses((status = 200, description = "The flags in effect and their audit trail", body = Object))
+)]
+pub async fn get_flags(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>> {
+    require_admin(&state, &headers, AdminRole::Viewer)?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "flags": flags::current(&state),
+        "audit": state.flags.audit(),
+    })))
+}
+
+/// Changes runtime feature flags for the requests that start afterwards.
+///
+/// Flags left out of the body keep their value. The change is recorded
+/// under the admin token's name, or the `X-Admin-User` header's with the
+/// shared token.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` for an unknown mode.
+#[utoipa::path(
+    put,
+    path = "/admin/flags",
+    tag = "admin",
+    request_body = FlagUpdate,
+    responses(
+        (status = 200, description = "The flags now in effect", body = Object),
+        (status = 400, description = "The change is invalid", body = ErrorResponse),
+    )
+)]
+pub async fn put_flags(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(update): Json<FlagUpdate>,
+) -> Result<Json<serde_json::Value>> {
+    let admin = require_admin(&state, &headers, AdminRole::Operator)?;
+
+    let flags = flags::apply(&state, update, &admin.name)?;
+    Ok(Json(json!({
+        "status": "success",
+        "flags": flags,
+    })))
+}
+
+/// Parses the address of a ban route.
+fn ban_ip(ip: &str) -> Result<IpAddr> {
+    ip.parse().map_err(|_| ApiError::BadRequest {
+        message: format!("'{}' is not an IP address", ip),
+    })
+}
+
+/// Lists the active bans of client addresses.
+#[utoipa::path(
+    get,
+    path = "/admin/bans",
+    tag = "admin",
+    responses((status = 200, description = "The active bans", body = Object))
+)]
+pub async fn list_bans(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>> {
+    require_admin(&state, &headers, AdminRole::Viewer)?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "bans": state.ip_guard.bans(),
+    })))
+}
+
+/// Bans a client address, replacing any ban it already has.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if `ip` is not an IP address.
+#[utoipa::path(
+    put,
+    path = "/admin/bans/{ip}",
+    tag = "admin",
+    params(("ip" = String, Path, description = "Client address")),
+    request_body = BanRequest,
+    responses(
+        (status = 200, description = "The ban", body = crate::abuse::Ban),
+        (status = 400, description = "Not an IP address", body = ErrorResponse),
+    )
+)]
+pub async fn put_ban(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(ip): Path<String>,
+    Json(request): Json<BanRequest>,
+) -> Result<Json<serde_json::Value>> {
+    let admin = require_admin(&state, &headers, AdminRole::Operator)?;
+
+    let ip = ban_ip(&ip)?;
+    let reason = request.reason.unwrap_or_else(|| "banned by an admin".to_string());
+    let ban = state.ip_guard.ban(ip, request.duration_secs, reason, &admin.name);
+    tracing::info!("{}封禁了{}", admin.name, ip);
+    state.audit.record(&admin.name, "bans.create", json!(ban));
+    Ok(Json(json!({
+        "status": "success",
+        "ban": ban,
+    })))
+}
+
+/// Lifts the ban of a client address.
+///
+/// # Errors
+///
+/// Returns `ApiError::NotFound` if the address is not banned.
+#[utoipa::path(
+    delete,
+    path = "/admin/bans/{ip}",
+    tag = "admin",
+    params(("ip" = String, Path, description = "Client address")),
+    responses(
+        (status = 200, description = "The ban was lifted", body = Object),
+        (status = 404, description = "The address is not banned", body = ErrorResponse),
+    )
+)]
+pub async fn delete_ban(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(ip): Path<String>,
+) -> Result<Json<serde_json::Value>> {
+    let admin = require_admin(&state, &headers, AdminRole::Operator)?;
+
+    let ip = ban_ip(&ip)?;
+    if !state.ip_guard.unban(ip) {
+        return Err(ApiError::NotFound {
+            message: format!("{} is not banned", ip),
+        });
+    }
+    tracing::info!("{}解除了{}的封禁", admin.name, ip);
+    state.audit.record(&admin.name, "bans.delete", json!({ "ip": ip }));
+    Ok(Json(json!({
+        "status": "success",
+        "ip": ip.to_string(),
+    })))
+}
+
+/// Lists the requests waiting in the dead-letter store.
+#[utoipa::path(
+    get,
+    path = "/admin/deadletter",
+    tag = "admin",
+    responses((status = 200, description = "The dead-lettered requests", body = Object))
+)]
+pub async fn list_dead_letters(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>> {
+    // 死信保存了完整的对话，与请求体一样只对operator开放
+    require_admin(&state, &headers, AdminRole::Operator)?;
+
+    let entries = state.dead_letters.list()?;
+    Ok(Json(json!({
+        "status": "success",
+        "dead_letters": entries,
+    })))
+}
+
+/// Re-runs a dead-lettered request as a non-streaming request.
+///
+/// The request goes through the whole chat pipeline again with the same
+/// virtual key it was originally made with, or, if it ran on them, with
+/// the server's own upstream keys. A successful replay returns the chat
+/// response and removes the entry; a failed one records the attempt and
+/// keeps the entry.
+///
+/// # Errors
+///
+/// Returns `ApiError::NotFound` for an unknown id, `ApiError::Forbidden` if
+/// the original virtual key no longer exists or the request was made with
+/// the caller's own upstream keys, or the replay's own error.
+#[utoipa::path(
+    post,
+    path = "/admin/deadletter/{id}/replay",
+    tag = "admin",
+    params(("id" = String, Path, description = "Dead letter id")),
+    responses(
+        (status = 200, description = "The replayed chat response", body = OpenAICompatibleResponse),
+        (status = 404, description = "No such dead letter", body = ErrorResponse),
+    )
+)]
+pub async fn replay_dead_letter(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>> {
+    let admin = require_admin(&state, &headers, AdminRole::Operator)?;
+
+    let mut entry = state.dead_letters.get(&id)?.ok_or_else(|| ApiError::NotFound {
+        message: format!("Dead letter '{}' not found", id),
+    })?;
+
+    let mut replay_headers = HeaderMap::new();
+    if let Some(name) = &entry.key_name {
+        let key = state.key_store.find_by_name(name).ok_or_else(|| ApiError::Forbidden {
+            message: format!("Virtual key '{}' no longer exists", name),
+        })?;
+        let value = HeaderValue::from_str(&format!("Bearer {}", key.key)).map_err(|e| ApiError::Internal {
+            message: format!("无效的虚拟密钥: {}", e),
+        })?;
+        replay_headers.insert(header::AUTHORIZATION, value);
+    } else if !entry.server_keys {
+        // 调用方自带的上游密钥没有保存，不能改用服务器的密钥重放
+        return Err(ApiError::Forbidden {
+            message: format!("Dead letter '{}' was made with the caller's own upstream keys and cannot be replayed", id),
+        });
+    }
+
+    let mut request = entry.request.clone();
+    request.stream = false;
+
+    let replayed = match handlers::process_chat(State(state.clone()), replay_headers, request).await {
+        Ok(response) => {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.map_err(|e| ApiError::Internal {
+                message: format!("读取重放响应失败: {}", e),
+            })?;
+            serde_json::from_slice::<serde_json::Value>(&body).map_err(|e| ApiError::Internal {
+                message: format!("重放响应不是有效的JSON: {}", e),
+            })
+        }
+        Err(e) => Err(e),
+    };
+    state.audit.record(&admin.name, "deadletter.replay", json!({ "id": id, "succeeded": replayed.is_ok() }));
+    match replayed {
+        Ok(response) => {
+            state.dead_letters.remove(&id)?;
+            tracing::info!("死信请求{}重放成功", id);
+            Ok(Json(json!({
+                "status": "success",
+                "id": id,
+                "attempts": entry.attempts + 1,
+                "response": response,
+            })))
+        }
+        Err(e) => {
+            entry.attempts += 1;
+            entry.last_error = e.to_string();
+            state.dead_letters.save(&entry)?;
+            tracing::warn!("死信请求{}重放失败: {}", id, e);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Routing hints for replicas behind a load balancer.
+//!
+//! Session prompt-cache state and captured reasoning live in the
+//! `[storage]` key-value cache, so replicas sharing a database share them.
+//! Anthropic's prompt cache and the in-memory parts of a replica still work
+//! best when one conversation keeps reaching the same replica. With
+//! `[affinity] enabled`, every chat response names its conversation's
+//! affinity key in the `X-DeepClaude-Affinity` header and a cookie, which a
+//! load balancer can hash on consistently (nginx:
+//! `hash $cookie_deepclaude_affinity consistent;`, or
+//! `hash $http_x_session_id consistent;` for clients sending sessions).
+//!
+//! The key is the `X-DeepClaude-Affinity` header the client sent back, or a
+//! hash of its `X-Session-Id`, or else a hash of the conversation's start:
+//! its tenant, system prompt and first user message, which stay the same
+//! across its turns and retries.
+
+use crate::{
+    config::AffinityConfig,
+    models::request::{ApiRequest, Role},
+    sessions,
+};
+use axum::http::{header, HeaderMap, HeaderValue};
+use sha2::{Digest, Sha256};
+
+/// Header carrying the affinity key, on responses and from clients.
+pub const AFFINITY_HEADER: &str = "x-deepclaude-affinity";
+
+/// The affinity key of a request.
+pub fn key(headers: &HeaderMap, request: &ApiRequest) -> String {
+    if let Some(key) = headers
+        .get(AFFINITY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|key| !key.is_empty() && key.len() <= 64 && key.bytes().all(|b| b.is_ascii_alphanumeric()))
+    {
+        return key.to_string();
+    }
+    let source = match sessions::session(headers) {
+        Some(session) => format!("session\n{}", session),
+        None => {
+            let first = request.messages.iter().find(|m| m.role == Role::User).map(|m| m.content.as_str());
+            serde_json::json!([request.scope.tenant, request.get_system_prompt(), first]).to_string()
+        }
+    };
+    hex::encode(&Sha256::digest(source.as_bytes())[..8])
+}
+
+/// Adds the affinity header and cookie to a response.
+pub fn apply(config: &AffinityConfig, key: &str, headers: &mut HeaderMap) {
+    if let Ok(value) = HeaderValue::from_str(key) {
+        headers.insert(AFFINITY_HEADER, value);
+    }
+    let cookie = format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+        config.cookie, key, config.max_age_secs
+    );
+    if let Ok(value) = HeaderValue::from_str(&cookie) {
+        headers.append(header::SET_COOKIE, value);
+    }
+}
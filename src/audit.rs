@@ -0,0 +1,342 @@
+//! Tamper-evident log of administrative actions.
+//!
+//! With `[audit] enabled`, every administrative change is appended to
+//! `[audit] path` as one JSON line: flag updates, bans, provider reloads,
+//! dead-letter replays, `.env` updates, and keys issued or revoked through
+//! `/v1/keys`. Each line holds its sequence number, the hash of the line
+//! before it, the record, and its own hash, the hex HMAC-SHA256 of
+//! `<seq>.<previous hash>.<record>` under a key derived from the master key
+//! of `DEEPCLAUDE_MASTER_KEY` (see [`crate::crypto`]), which the log needs.
+//! Changing, removing or reordering a line therefore breaks the chain from
+//! that line on, and without the master key the chain cannot be rebuilt
+//! over edited lines. With `[audit] encrypt`, the record is also stored
+//! encrypted with the master key. Lines written before the master key is
+//! rotated only verify with the old key.
+//!
+//! `deepclaude audit verify` walks the chain with the master key and prints
+//! the number of records and the hash of the last one. Keeping that hash
+//! elsewhere also makes cutting lines off the end detectable.
+//!
+//! With `[retention] audit_days`, the scheduled purge (see
+//! [`crate::retention`]) removes the records older than that from the start
+//! of the log and puts a purge marker, signed with the master key, in their
+//! place. The marker names the last removed line and its hash, so the chain
+//! continues from it. `verify` only accepts a chain that starts at line 1 or
+//! at a valid marker, so lines cut off the start are detected too.
+
+use crate::{
+    config::AuditConfig,
+    crypto::{self, MasterKey},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// `prev` of the first line.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One administrative action.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub at: DateTime<Utc>,
+    /// Admin name, or the name of the key that made the change.
+    pub actor: String,
+    /// What was done, such as `flags.update` or `bans.create`.
+    pub action: String,
+    /// What it was done to and how.
+    pub detail: serde_json::Value,
+}
+
+/// One line of the log.
+#[derive(Debug, Serialize, Deserialize)]
+struct Line {
+    seq: u64,
+    prev: String,
+    /// JSON of the record, or its `enc:v1:` encryption.
+    record: String,
+    hash: String,
+}
+
+/// First line of a purged log, in place of the removed lines.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PurgeMarker {
+    /// Sequence number of the last removed line.
+    purged_through: u64,
+    /// Hash of the last removed line.
+    head: String,
+    /// HMAC of `purge.<purged_through>.<head>`.
+    mac: String,
+}
+
+impl PurgeMarker {
+    fn new(key: &MasterKey, purged_through: u64, head: String) -> Self {
+        let mac = key.mac(&format!("purge.{}.{}", purged_through, head));
+        Self { purged_through, head, mac }
+    }
+
+    fn is_valid(&self, key: &MasterKey) -> bool {
+        self.mac == key.mac(&format!("purge.{}.{}", self.purged_through, self.head))
+    }
+}
+
+fn hash(key: &MasterKey, seq: u64, prev: &str, record: &str) -> String {
+    key.mac(&format!("{}.{}.{}", seq, prev, record))
+}
+
+/// Appends records to the log.
+pub struct AuditLog {
+    enabled: bool,
+    path: PathBuf,
+    /// Signs the chain, and decrypts and encrypts records.
+    master: Option<MasterKey>,
+    encrypt: bool,
+    /// Sequence number and hash of the last line.
+    head: Mutex<(u64, String)>,
+}
+
+impl AuditLog {
+    /// Opens the log for the `[audit]` settings, continuing its chain.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the last line cannot be read, or if the log is
+    /// enabled and no master key is set.
+    pub fn new(config: &AuditConfig) -> anyhow::Result<Self> {
+        let master = if config.enabled {
+            let master = MasterKey::from_env(crypto::MASTER_KEY_ENV)
+                .ok_or_else(|| anyhow::anyhow!("[audit]需要设置{}来签名哈希链", crypto::MASTER_KEY_ENV))?;
+            Some(master)
+        } else {
+            None
+        };
+        let path = PathBuf::from(&config.path);
+        let head = if config.enabled { last_line(&path)? } else { None };
+        Ok(Self {
+            enabled: config.enabled,
+            path,
+            master,
+            encrypt: config.encrypt,
+            head: Mutex::new(head.unwrap_or_else(|| (0, GENESIS.to_string()))),
+        })
+    }
+
+    /// Appends a record. Failures are logged, not returned, since the
+    /// action has already been taken.
+    pub fn record(&self, actor: &str, action: &str, detail: serde_json::Value) {
+        if !self.enabled {
+            return;
+        }
+        let record = AuditRecord {
+            at: Utc::now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            detail,
+        };
+        // 持有锁直到写入完成，保证链的顺序与文件中的顺序一致
+        let Some(master) = &self.master else {
+            return;
+        };
+        let mut head = self.head.lock().unwrap();
+        let result = (|| {
+            let mut record = serde_json::to_string(&record)?;
+            if self.encrypt {
+                record = master.encrypt(&record)?;
+            }
+            let seq = head.0 + 1;
+            let line = Line {
+                seq,
+                hash: hash(master, seq, &head.1, &record),
+                prev: head.1.clone(),
+                record,
+            };
+            if let Some(dir) = self.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            file.write_all(format!("{}\n", serde_json::to_string(&line)?).as_bytes())?;
+            file.sync_data()?;
+            anyhow::Ok((seq, line.hash))
+        })();
+        match result {
+            Ok(appended) => *head = appended,
+            Err(e) => tracing::error!("写入审计记录{}失败: {}", action, e),
+        }
+    }
+
+    /// The records in the log, oldest first. Lines that cannot be read are
+    /// skipped; `deepclaude audit verify` reports them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the log cannot be read.
+    pub fn records(&self) -> anyhow::Result<Vec<AuditRecord>> {
+        if !self.enabled {
+            return Ok(Vec::new());
+        }
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(content.lines().filter_map(|line| self.read_record(line)).collect())
+    }
+
+    /// Removes the records made before `before` from the start of the log,
+    /// returning how many.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the log cannot be read or rewritten.
+    pub fn purge(&self, before: DateTime<Utc>) -> anyhow::Result<u64> {
+        let Some(master) = self.master.as_ref().filter(|_| self.enabled) else {
+            return Ok(0);
+        };
+        // 持有锁，重写期间不会追加新记录
+        let _head = self.head.lock().unwrap();
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines: Vec<&str> = content.lines().filter(|line| !line.trim().is_empty()).collect();
+        // 上次清理留下的标记由这次的标记取代
+        if lines.first().is_some_and(|line| serde_json::from_str::<PurgeMarker>(line).is_ok()) {
+            lines.remove(0);
+        }
+        // 只删除开头连续的旧记录，保留的部分仍是一条完整的链
+        let removed = lines.iter().take_while(|line| self.made_before(line, before)).count();
+        if removed == 0 {
+            return Ok(0);
+        }
+        let last: Line = serde_json::from_str(lines[removed - 1])?;
+        let marker = PurgeMarker::new(master, last.seq, last.hash);
+        let kept: String = std::iter::once(serde_json::to_string(&marker)?)
+            .chain(lines[removed..].iter().map(|line| line.to_string()))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, kept)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(removed as u64)
+    }
+
+    /// Whether a line holds a record made before `before`. Lines that cannot
+    /// be read are kept.
+    fn made_before(&self, line: &str, before: DateTime<Utc>) -> bool {
+        self.read_record(line).is_some_and(|record| record.at < before)
+    }
+
+    /// The record of a line, decrypted if need be.
+    fn read_record(&self, line: &str) -> Option<AuditRecord> {
+        let line = serde_json::from_str::<Line>(line).ok()?;
+        let record = match (&self.master, crypto::is_encrypted(&line.record)) {
+            (_, false) => line.record,
+            (Some(master), true) => master.decrypt(&line.record).ok()?,
+            (None, true) => return None,
+        };
+        serde_json::from_str(&record).ok()
+    }
+}
+
+/// Reads the sequence number and hash the log ends at, if it has a line.
+fn last_line(path: &Path) -> anyhow::Result<Option<(u64, String)>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            last = Some(line);
+        }
+    }
+    let Some(last) = last else {
+        return Ok(None);
+    };
+    // 全部记录都被清理时只剩清理标记
+    if let Ok(marker) = serde_json::from_str::<PurgeMarker>(&last) {
+        return Ok(Some((marker.purged_through, marker.head)));
+    }
+    let line: Line = serde_json::from_str(&last).map_err(|e| anyhow::anyhow!("审计日志最后一行无效: {}", e))?;
+    Ok(Some((line.seq, line.hash)))
+}
+
+/// Outcome of a successful verification.
+#[derive(Debug)]
+pub struct Verified {
+    pub records: u64,
+    /// Sequence number of the first record, above 1 after a purge.
+    pub first: u64,
+    /// Hash of the last line.
+    pub head: String,
+}
+
+/// Checks the chain of the log at `path` with `master`, decrypting
+/// encrypted records. The chain starts at line 1, or after a purge at the
+/// signed purge marker in place of the removed lines.
+///
+/// # Errors
+///
+/// Returns an error naming the first line that is malformed, out of
+/// sequence, does not follow the line before it, does not match its hash,
+/// or cannot be decrypted, or if the log does not start at line 1 or a
+/// valid purge marker.
+pub fn verify(path: &Path, master: &MasterKey) -> anyhow::Result<Verified> {
+    let file = std::fs::File::open(path).map_err(|e| anyhow::anyhow!("无法打开审计日志{}: {}", path.display(), e))?;
+    let (mut seq, mut prev) = (0, GENESIS.to_string());
+    let mut records = 0;
+    let mut started = false;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let number = number + 1;
+        if !started {
+            started = true;
+            // 清理过的日志从签名的清理标记接续
+            if let Ok(marker) = serde_json::from_str::<PurgeMarker>(&line) {
+                if !marker.is_valid(master) {
+                    anyhow::bail!("第{}行的清理标记签名无效", number);
+                }
+                (seq, prev) = (marker.purged_through, marker.head);
+                continue;
+            }
+        }
+        let line: Line =
+            serde_json::from_str(&line).map_err(|e| anyhow::anyhow!("第{}行不是有效的审计记录: {}", number, e))?;
+        if line.seq != seq + 1 {
+            anyhow::bail!("第{}行的序号是{}，应为{}，有记录被删除或重排", number, line.seq, seq + 1);
+        }
+        if line.prev != prev {
+            anyhow::bail!("第{}行（序号{}）没有接在上一条记录之后", number, line.seq);
+        }
+        if line.hash != hash(master, line.seq, &line.prev, &line.record) {
+            anyhow::bail!("第{}行（序号{}）的哈希不匹配，记录已被修改", number, line.seq);
+        }
+        let record = if crypto::is_encrypted(&line.record) {
+            master
+                .decrypt(&line.record)
+                .map_err(|e| anyhow::anyhow!("第{}行（序号{}）无法解密: {}", number, line.seq, e))?
+        } else {
+            line.record
+        };
+        serde_json::from_str::<AuditRecord>(&record)
+            .map_err(|e| anyhow::anyhow!("第{}行（序号{}）的记录无效: {}", number, line.seq, e))?;
+        (seq, prev) = (line.seq, line.hash);
+        records += 1;
+    }
+    Ok(Verified {
+        records,
+        first: seq + 1 - records,
+        head: prev,
+    })
+}
@@ -0,0 +1,245 @@
+//! Latency benchmark of the streaming path.
+//!
+//! `deepclaude bench` sends `--requests` streaming chat requests to a
+//! running server, `--concurrency` at a time, and reports the latency
+//! percentiles of each stage: the time to the first delta, the reasoning
+//! stage (first reasoning delta to first answer delta), the answer stage
+//! (first answer delta to the end of the stream) and the whole request,
+//! along with the output rate in estimated tokens per second. With
+//! `--echo` the requests go to `POST /debug/echo-completions` instead, so
+//! the proxy's own overhead can be measured without upstream calls or
+//! spend; the server needs `[debug] echo` enabled for that.
+
+use crate::ratelimit;
+use futures::StreamExt;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+
+/// Settings of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Base URL of the server.
+    pub url: String,
+    /// Virtual key or upstream tokens sent as the bearer token.
+    pub key: Option<String>,
+    pub requests: usize,
+    pub concurrency: usize,
+    pub prompt_file: Option<PathBuf>,
+    pub echo: bool,
+    pub echo_tokens: usize,
+    pub echo_tokens_per_sec: f64,
+}
+
+/// Timings of one streamed request.
+#[derive(Debug, Default)]
+struct Sample {
+    first_delta: Option<Duration>,
+    first_reasoning: Option<Duration>,
+    first_answer: Option<Duration>,
+    total: Duration,
+    tokens: u32,
+}
+
+/// Latency percentiles of one stage, in milliseconds.
+#[derive(Debug, Serialize)]
+pub struct Percentiles {
+    pub count: usize,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+impl Percentiles {
+    fn of(mut values: Vec<f64>) -> Self {
+        values.sort_by(f64::total_cmp);
+        let at = |quantile: f64| {
+            if values.is_empty() {
+                return 0.0;
+            }
+            // 最近秩法
+            let rank = (quantile * values.len() as f64).ceil() as usize;
+            values[rank.clamp(1, values.len()) - 1]
+        };
+        Self {
+            count: values.len(),
+            p50: at(0.50),
+            p95: at(0.95),
+            p99: at(0.99),
+        }
+    }
+}
+
+/// Results of a benchmark run.
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub requests: usize,
+    pub failed: usize,
+    pub elapsed_secs: f64,
+    pub requests_per_sec: f64,
+    pub ttft_ms: Percentiles,
+    pub reasoning_ms: Percentiles,
+    pub answer_ms: Percentiles,
+    pub total_ms: Percentiles,
+    /// Estimated output tokens per second of each request.
+    pub tokens_per_sec: Percentiles,
+}
+
+impl BenchReport {
+    /// Prints the report as a table.
+    pub fn print(&self) {
+        println!(
+            "{} requests, {} failed, {:.2}s, {:.2} req/s",
+            self.requests, self.failed, self.elapsed_secs, self.requests_per_sec
+        );
+        println!("{:<14} {:>7} {:>10} {:>10} {:>10}", "stage", "count", "p50", "p95", "p99");
+        for (name, stage) in [
+            ("ttft ms", &self.ttft_ms),
+            ("reasoning ms", &self.reasoning_ms),
+            ("answer ms", &self.answer_ms),
+            ("total ms", &self.total_ms),
+            ("tokens/s", &self.tokens_per_sec),
+        ] {
+            println!(
+                "{:<14} {:>7} {:>10.1} {:>10.1} {:>10.1}",
+                name, stage.count, stage.p50, stage.p95, stage.p99
+            );
+        }
+    }
+}
+
+/// Runs the benchmark.
+///
+/// # Errors
+///
+/// Returns an error if the prompt file cannot be read. Failed requests are
+/// counted in the report instead.
+pub async fn run(options: BenchOptions) -> anyhow::Result<BenchReport> {
+    let prompt = match &options.prompt_file {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => "Explain in two sentences why the sky is blue.".to_string(),
+    };
+    let (path, body) = if options.echo {
+        (
+            "/debug/echo-completions",
+            json!({ "tokens": options.echo_tokens, "tokens_per_sec": options.echo_tokens_per_sec }),
+        )
+    } else {
+        (
+            "/v1/chat/completions",
+            json!({ "stream": true, "messages": [{ "role": "user", "content": prompt }] }),
+        )
+    };
+    let url = format!("{}{}", options.url.trim_end_matches('/'), path);
+    let client = reqwest::Client::new();
+    let permits = Arc::new(Semaphore::new(options.concurrency.max(1)));
+
+    let started = Instant::now();
+    let mut tasks = Vec::with_capacity(options.requests);
+    for _ in 0..options.requests {
+        let permit = permits.clone().acquire_owned().await?;
+        let client = client.clone();
+        let url = url.clone();
+        let key = options.key.clone();
+        let body = body.clone();
+        tasks.push(tokio::spawn(async move {
+            let sample = send(&client, &url, key.as_deref(), &body).await;
+            drop(permit);
+            sample
+        }));
+    }
+
+    let mut samples = Vec::new();
+    let mut failed = 0;
+    for task in tasks {
+        match task.await? {
+            Ok(sample) => samples.push(sample),
+            Err(e) => {
+                tracing::warn!("基准请求失败: {}", e);
+                failed += 1;
+            }
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    Ok(BenchReport {
+        requests: options.requests,
+        failed,
+        elapsed_secs: elapsed,
+        requests_per_sec: samples.len() as f64 / elapsed.max(f64::EPSILON),
+        ttft_ms: Percentiles::of(samples.iter().filter_map(|s| s.first_delta).map(ms).collect()),
+        reasoning_ms: Percentiles::of(
+            samples
+                .iter()
+                .filter_map(|s| Some(s.first_answer.unwrap_or(s.total) - s.first_reasoning?))
+                .map(ms)
+                .collect(),
+        ),
+        answer_ms: Percentiles::of(samples.iter().filter_map(|s| Some(s.total - s.first_answer?)).map(ms).collect()),
+        total_ms: Percentiles::of(samples.iter().map(|s| ms(s.total)).collect()),
+        tokens_per_sec: Percentiles::of(
+            samples
+                .iter()
+                .filter_map(|s| {
+                    let streaming = s.total - s.first_delta?;
+                    (!streaming.is_zero()).then(|| f64::from(s.tokens) / streaming.as_secs_f64())
+                })
+                .collect(),
+        ),
+    })
+}
+
+/// Sends one streaming request and times its events.
+async fn send(client: &reqwest::Client, url: &str, key: Option<&str>, body: &serde_json::Value) -> anyhow::Result<Sample> {
+    let started = Instant::now();
+    let mut request = client.post(url).json(body);
+    if let Some(key) = key {
+        request = request.bearer_auth(key);
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        anyhow::bail!("{}: {}", response.status(), response.text().await.unwrap_or_default());
+    }
+
+    let mut sample = Sample::default();
+    let mut text = String::new();
+    let mut data = String::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        data.push_str(&String::from_utf8_lossy(&chunk?));
+        while let Some(end) = data.find("\n\n") {
+            let event: String = data.drain(..end + 2).collect();
+            let Some(payload) = event.lines().find_map(|line| line.strip_prefix("data: ")) else {
+                continue;
+            };
+            let Ok(value) = serde_json::from_str::<serde_json::Value>(payload) else {
+                continue;
+            };
+            if let Some(message) = value["error"]["message"].as_str() {
+                anyhow::bail!("{}", message);
+            }
+            let delta = &value["choices"][0]["delta"];
+            let (reasoning, answer) = (delta["reasoning_content"].as_str(), delta["content"].as_str());
+            let elapsed = started.elapsed();
+            if reasoning.is_some_and(|r| !r.is_empty()) {
+                sample.first_reasoning.get_or_insert(elapsed);
+            }
+            if answer.is_some_and(|a| !a.is_empty()) {
+                sample.first_answer.get_or_insert(elapsed);
+            }
+            for piece in [reasoning, answer].into_iter().flatten().filter(|piece| !piece.is_empty()) {
+                sample.first_delta.get_or_insert(elapsed);
+                text.push_str(piece);
+            }
+        }
+    }
+    sample.total = started.elapsed();
+    sample.tokens = ratelimit::estimate_tokens(&text);
+    Ok(sample)
+}
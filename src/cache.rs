@@ -0,0 +1,213 @@
+//! Response cache for non-streaming chat requests.
+//!
+//! With `[cache] enabled` the answer to a non-streaming request is kept for
+//! `ttl_secs` and returned, marked `cached: true`, to a later request that
+//! is identical: same messages, system prompt, stage parameters, mode,
+//! variant and tenant. With `semantic` also set, the latest user message is
+//! embedded through the `[rag.embedding]` endpoint, and a request whose
+//! other inputs match a cached one is answered from it when the two user
+//! messages have a cosine similarity of at least `similarity_threshold`.
+//! Answers are kept in the `[storage]` key-value cache under
+//! `response:<hash>`, so replicas sharing a database share them; the
+//! embeddings for semantic matching stay in memory, at most `max_entries`
+//! of them, dropping the oldest first, and are searched linearly. Virtual
+//! keys can opt out with `cache = false`.
+
+use crate::{
+    config::CacheConfig,
+    models::{
+        request::{ApiConfig, ApiRequest, Message, Role},
+        response::OpenAICompatibleResponse,
+    },
+    rag::{self, Retriever},
+    storage::KvCache,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The embedding of a cached answer's latest user message.
+struct Entry {
+    /// Hash of the whole request, the answer's key.
+    exact: String,
+    /// Hash of the request without its latest user message.
+    context: String,
+    embedding: Vec<f32>,
+    stored: Instant,
+}
+
+/// A request looked up in the cache, kept to store its answer on a miss.
+pub struct Lookup {
+    exact: String,
+    context: String,
+    embedding: Option<Vec<f32>>,
+    /// The cached answer, if any.
+    pub hit: Option<OpenAICompatibleResponse>,
+}
+
+/// Cached answers of recent requests.
+pub struct ResponseCache {
+    config: CacheConfig,
+    /// `[cache] enabled`, switchable at runtime.
+    enabled: AtomicBool,
+    store: Arc<dyn KvCache>,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig, store: Arc<dyn KvCache>) -> Self {
+        Self {
+            config: config.clone(),
+            enabled: AtomicBool::new(config.enabled),
+            store,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turns caching on or off for later requests.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Looks a request up, `None` if caching is off for it.
+    pub async fn lookup(&self, request: &ApiRequest, retriever: &Retriever) -> Option<Lookup> {
+        if !self.enabled() || request.stream {
+            return None;
+        }
+        let latest = request.messages.iter().rposition(|m| m.role == Role::User);
+        let exact = fingerprint(request, &request.messages);
+        let context = match latest {
+            Some(index) => {
+                let mut messages = request.messages.clone();
+                messages.remove(index);
+                fingerprint(request, &messages)
+            }
+            None => exact.clone(),
+        };
+
+        if let Some(hit) = self.find(&exact).await {
+            tracing::debug!("响应缓存精确命中");
+            return Some(Lookup { exact, context, embedding: None, hit: Some(hit) });
+        }
+
+        let mut embedding = None;
+        if let (true, Some(index)) = (self.config.semantic, latest) {
+            match retriever.embed(&request.messages[index].content).await {
+                Ok(vector) => embedding = Some(vector),
+                Err(e) => tracing::warn!("嵌入用户消息失败，跳过语义缓存: {}", e),
+            }
+        }
+        let similar = embedding.as_ref().and_then(|vector| {
+            let ttl = Duration::from_secs(self.config.ttl_secs);
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|entry| entry.stored.elapsed() < ttl);
+            entries
+                .iter()
+                .rev()
+                .find(|entry| {
+                    entry.context == context
+                        && rag::cosine_similarity(&entry.embedding, vector) >= self.config.similarity_threshold
+                })
+                .map(|entry| entry.exact.clone())
+        });
+        let hit = match similar {
+            Some(similar) => self.find(&similar).await,
+            None => None,
+        };
+        if hit.is_some() {
+            tracing::debug!("响应缓存语义命中");
+        }
+        Some(Lookup { exact, context, embedding, hit })
+    }
+
+    /// Stores the answer to a request that missed the cache.
+    pub async fn store(&self, lookup: Lookup, response: &OpenAICompatibleResponse) {
+        let ttl = Duration::from_secs(self.config.ttl_secs);
+        let value = match serde_json::to_string(response) {
+            Ok(value) => value,
+            Err(e) => {
+                tracing::warn!("序列化缓存响应失败: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.store.put(&key(&lookup.exact), value, ttl).await {
+            tracing::warn!("写入响应缓存失败: {}", e);
+            return;
+        }
+        let Some(embedding) = lookup.embedding else {
+            return;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.exact != lookup.exact);
+        entries.push_back(Entry {
+            exact: lookup.exact,
+            context: lookup.context,
+            embedding,
+            stored: Instant::now(),
+        });
+        while entries.len() > self.config.max_entries {
+            entries.pop_front();
+        }
+    }
+
+    /// Returns the unexpired answer stored under a request hash, as a new
+    /// response marked cached.
+    async fn find(&self, exact: &str) -> Option<OpenAICompatibleResponse> {
+        let value = match self.store.get(&key(exact)).await {
+            Ok(value) => value?,
+            Err(e) => {
+                tracing::warn!("读取响应缓存失败: {}", e);
+                return None;
+            }
+        };
+        let mut response: OpenAICompatibleResponse = serde_json::from_str(&value)
+            .inspect_err(|e| tracing::warn!("跳过无法解析的缓存响应: {}", e))
+            .ok()?;
+        response.id = uuid::Uuid::new_v4().to_string();
+        response.quota = None;
+        response.cached = true;
+        Some(response)
+    }
+}
+
+fn key(exact: &str) -> String {
+    format!("response:{}", exact)
+}
+
+/// Hashes the inputs that decide a request's answer, with `messages` in
+/// place of its own.
+fn fingerprint(request: &ApiRequest, messages: &[Message]) -> String {
+    let inputs = serde_json::json!({
+        "messages": messages,
+        "system": request.system,
+        "deepseek": body(&request.deepseek_config),
+        "anthropic": body(&request.anthropic_config),
+        "mode": request.mode,
+        "variant": request.variant,
+        "target_language": request.target_language,
+        "tenant": request.scope.tenant,
+        "consensus": request.consensus,
+        "refine": request.refine,
+    });
+    hex::encode(Sha256::digest(inputs.to_string().as_bytes()))
+}
+
+/// Returns a stage's body parameters without the end user, so that users
+/// share answers.
+fn body(config: &ApiConfig) -> serde_json::Value {
+    let mut body = config.body.clone();
+    if let Some(body) = body.as_object_mut() {
+        body.remove("user");
+    }
+    body
+}
@@ -0,0 +1,125 @@
+//! Feature discovery for client integrations.
+//!
+//! - `GET /v1/capabilities` - describe what this deployment supports
+//!
+//! The document lists the pipeline modes, the default model of each stage,
+//! the upstream hosts, profiles, aliases, pools and presets requests can
+//! name, which optional request fields and features are available, and the
+//! limits that apply. A caller presenting a virtual key also gets that
+//! key's restrictions. Clients can feature-detect from it instead of
+//! assuming what a deployment offers; nothing secret is included.
+
+use crate::{
+    clients::{hosts, providers},
+    handlers::AppState,
+};
+use axum::{extract::State, http::HeaderMap, Json};
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Optional fields of a chat request beyond the OpenAI ones.
+const REQUEST_FIELDS: &[&str] = &[
+    "mode",
+    "preset",
+    "verbose",
+    "system",
+    "user",
+    "target_language",
+    "file_ids",
+    "deepseek",
+    "deepseek_config",
+    "anthropic_config",
+    "consensus",
+    "refine",
+];
+
+/// Names in alphabetical order, for a stable document.
+fn sorted<'a>(names: impl Iterator<Item = &'a String>) -> Vec<&'a String> {
+    let mut names: Vec<_> = names.collect();
+    names.sort();
+    names
+}
+
+/// Describes the modes, models, features and limits of this deployment.
+#[utoipa::path(
+    get,
+    path = "/v1/capabilities",
+    tag = "chat",
+    responses((status = 200, description = "The capabilities document", body = Object))
+)]
+pub async fn capabilities(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Json<serde_json::Value> {
+    let config = &state.config;
+    let settings = providers::current();
+
+    let aliases: BTreeMap<_, _> = config
+        .model_aliases
+        .iter()
+        .map(|(name, alias)| (name, json!({ "host": alias.host, "model": alias.model })))
+        .collect();
+    let rate_limits: BTreeMap<_, _> = config
+        .rate_limits
+        .iter()
+        .map(|(provider, budget)| (provider, json!({ "rpm": budget.rpm, "tpm": budget.tpm })))
+        .collect();
+    let key = state.key_store.lookup(&headers).map(|key| {
+        json!({
+            "name": key.name,
+            "allowed_models": key.allowed_models,
+            "allowed_modes": key.allowed_modes,
+            "max_tokens": key.max_tokens,
+            "priority": key.priority,
+        })
+    });
+
+    Json(json!({
+        "object": "capabilities",
+        "version": env!("CARGO_PKG_VERSION"),
+        "modes": ["normal", "full"],
+        "default_mode": state.flags.default_mode(),
+        "stages": {
+            "deepseek": { "model": settings.deepseek_model },
+            "anthropic": {
+                "model": settings.claude_model,
+                "api_format": if settings.use_openai_format() { "openai" } else { "anthropic" },
+            },
+        },
+        "providers": {
+            "hosts": hosts::names().collect::<Vec<_>>(),
+            "profiles": sorted(config.providers.keys()),
+        },
+        "model_aliases": aliases,
+        "model_pools": sorted(state.pools.names()),
+        "presets": sorted(config.presets.keys()),
+        "request_fields": REQUEST_FIELDS,
+        "features": {
+            "streaming": true,
+            // 工具由服务端的MCP服务器提供，请求不能携带自己的工具定义
+            "tools": {
+                "client_defined": false,
+                "mcp_servers": sorted(config.mcp.servers.keys()),
+            },
+            "vision": false,
+            "json_mode": {
+                "response_format": true,
+                "envelopes": sorted(config.envelopes.keys()),
+            },
+            "consensus": { "streaming": false, "strategy": config.consensus.strategy },
+            "refine": { "streaming": false, "default": config.refine.enabled },
+            "retrieval": state.retriever.enabled(),
+            "files": true,
+            "response_cache": state.cache.enabled(),
+            "reasoning_reuse": state.reasoning.enabled(),
+            "coalescing": state.coalescer.enabled(),
+            "reasoning_content": true,
+            "gemini_api": true,
+            "grpc": !config.grpc.listen.is_empty(),
+            "passthrough": { "deepseek": config.proxy.deepseek, "anthropic": config.proxy.anthropic },
+        },
+        "limits": {
+            "max_upload_bytes": state.files.max_upload_bytes(),
+            "rate_limits": rate_limits,
+            "max_in_flight": config.concurrency.max_in_flight,
+            "key": key,
+        },
+    }))
+}
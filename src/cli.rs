@@ -0,0 +1,229 @@
+//! Command-line interface.
+//!
+//! Running `deepclaude` without a subcommand starts the API server.
+//! Subcommands provide operational tooling that runs and exits.
+
+use crate::{
+    audit,
+    bench::{self, BenchOptions},
+    config::Config,
+    crypto::{self, MasterKey},
+    paths,
+    repl::{self, ChatOptions},
+    secrets, utils,
+};
+use clap::{Parser, Subcommand};
+use std::{io::IsTerminal, path::PathBuf};
+
+#[derive(Debug, Parser)]
+#[command(name = "deepclaude", version, about)]
+pub struct Cli {
+    /// Directory holding `.env` and `config.toml` [default: $DEEPCLAUDE_HOME or the working directory]
+    #[arg(long, global = true)]
+    pub config_dir: Option<PathBuf>,
+    /// Serve `GET /debug/tasks` listing the live pipeline tasks
+    #[arg(long)]
+    pub diagnostics: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage the virtual key store
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Measure streaming latency against a running server
+    Bench(BenchArgs),
+    /// Chat with a running server in the terminal
+    Chat(ChatArgs),
+    /// Check the audit log
+    #[command(subcommand)]
+    Audit(AuditCommand),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ChatArgs {
+    /// Base URL of the server [default: http://127.0.0.1:$PORT]
+    #[arg(long)]
+    pub url: Option<String>,
+    /// Bearer token sent with each request, such as a virtual key
+    #[arg(long)]
+    pub key: Option<String>,
+    /// Claude key sent as X-Anthropic-API-Token, if the server has none configured
+    #[arg(long)]
+    pub anthropic_key: Option<String>,
+    /// Pipeline mode, normal or full [default: the server's]
+    #[arg(long)]
+    pub mode: Option<String>,
+    /// Claude stage model [default: the server's]
+    #[arg(long)]
+    pub model: Option<String>,
+    /// Print without ANSI colors
+    #[arg(long)]
+    pub no_color: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct BenchArgs {
+    /// Base URL of the server [default: http://127.0.0.1:$PORT]
+    #[arg(long)]
+    pub url: Option<String>,
+    /// Bearer token sent with each request, such as a virtual key
+    #[arg(long)]
+    pub key: Option<String>,
+    /// Number of requests to send
+    #[arg(long, default_value_t = 20)]
+    pub requests: usize,
+    /// Requests in flight at once
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+    /// File whose content is sent as the user message
+    #[arg(long)]
+    pub prompt_file: Option<PathBuf>,
+    /// Use the synthetic echo endpoint instead of the upstreams
+    #[arg(long)]
+    pub echo: bool,
+    /// Answer tokens per echo stream
+    #[arg(long, default_value_t = 256)]
+    pub echo_tokens: usize,
+    /// Token rate of echo streams, 0 for unthrottled
+    #[arg(long, default_value_t = 0.0)]
+    pub echo_tokens_per_sec: f64,
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeysCommand {
+    /// Encrypt an upstream API key for use in a `[[keys]]` entry
+    Encrypt {
+        /// The plaintext upstream key
+        plaintext: String,
+    },
+    /// Re-encrypt every stored upstream key with a new master key
+    RotateMaster {
+        /// Environment variable holding the new master key
+        #[arg(long, default_value = "DEEPCLAUDE_NEW_MASTER_KEY")]
+        new_key_env: String,
+        /// Config file containing the encrypted keys [default: config.toml in the config directory]
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuditCommand {
+    /// Check that no audit record was changed, removed or reordered
+    Verify {
+        /// Audit log to check [default: [audit] path]
+        #[arg(long)]
+        path: Option<PathBuf>,
+    },
+}
+
+/// Runs a subcommand to completion.
+pub async fn run(command: Command) -> anyhow::Result<()> {
+    // 加载.env，使主密钥可以来自.env或外部密钥引用
+    let config = Config::load()?;
+
+    match command {
+        Command::Keys(KeysCommand::Encrypt { plaintext }) => {
+            let master = load_master_key(crypto::MASTER_KEY_ENV, &config).await?;
+            println!("{}", master.encrypt(&plaintext)?);
+        }
+        Command::Keys(KeysCommand::RotateMaster { new_key_env, config: path }) => {
+            let old = load_master_key(crypto::MASTER_KEY_ENV, &config).await?;
+            let new = load_master_key(&new_key_env, &config).await?;
+            let path = path.unwrap_or_else(paths::config_file);
+            let count = rotate_file(&path, &old, &new)?;
+            println!("已使用新主密钥重新加密{}个上游密钥: {}", count, path.display());
+            println!("请将{}更新为{}的值后重启服务", crypto::MASTER_KEY_ENV, new_key_env);
+        }
+        Command::Audit(AuditCommand::Verify { path }) => {
+            let path = path.unwrap_or_else(|| {
+                let mut config = config.clone();
+                config.resolve_paths();
+                PathBuf::from(config.audit.path)
+            });
+            // 哈希链由主密钥签名，校验时同样需要主密钥
+            let master = load_master_key(crypto::MASTER_KEY_ENV, &config).await?;
+            let verified = audit::verify(&path, &master)?;
+            println!("审计日志完整: {}条记录，最后一条的哈希为{}", verified.records, verified.head);
+            if verified.first > 1 {
+                println!("序号{}之前的记录已被清理", verified.first);
+            }
+        }
+        Command::Bench(args) => {
+            let report = bench::run(BenchOptions {
+                url: args
+                    .url
+                    .unwrap_or_else(|| format!("http://127.0.0.1:{}", utils::get_env_var("PORT", "1337"))),
+                key: args.key,
+                requests: args.requests,
+                concurrency: args.concurrency,
+                prompt_file: args.prompt_file,
+                echo: args.echo,
+                echo_tokens: args.echo_tokens,
+                echo_tokens_per_sec: args.echo_tokens_per_sec,
+            })
+            .await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                report.print();
+            }
+        }
+        Command::Chat(args) => {
+            let options = ChatOptions {
+                url: args
+                    .url
+                    .unwrap_or_else(|| format!("http://127.0.0.1:{}", utils::get_env_var("PORT", "1337"))),
+                key: args.key,
+                anthropic_key: args.anthropic_key,
+                mode: args.mode,
+                model: args.model,
+                color: !args.no_color && std::io::stdout().is_terminal(),
+            };
+            let input = tokio::io::BufReader::new(tokio::io::stdin());
+            repl::run(options, input, &mut std::io::stdout()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn load_master_key(var: &str, config: &Config) -> anyhow::Result<MasterKey> {
+    let value = std::env::var(var).unwrap_or_default();
+    if secrets::is_reference(&value) {
+        secrets::resolve_all(std::slice::from_ref(&value), &config.secrets).await?;
+    }
+    MasterKey::from_env(var).ok_or_else(|| anyhow::anyhow!("未设置{}", var))
+}
+
+/// Re-encrypts every `enc:v1:` value in the file, preserving all other content.
+fn rotate_file(path: &PathBuf, old: &MasterKey, new: &MasterKey) -> anyhow::Result<usize> {
+    let content = std::fs::read_to_string(path)?;
+    let mut rotated = String::with_capacity(content.len());
+    let mut rest = content.as_str();
+    let mut count = 0;
+
+    while let Some(start) = rest.find("enc:v1:") {
+        rotated.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find(['"', '\'', '\n'])
+            .map_or(rest.len(), |offset| start + offset);
+        let plaintext = old.decrypt(&rest[start..end])?;
+        rotated.push_str(&new.encrypt(&plaintext)?);
+        rest = &rest[end..];
+        count += 1;
+    }
+    rotated.push_str(rest);
+
+    // 先写入临时文件再替换，避免中途失败损坏配置
+    let tmp = path.with_extension("toml.rotating");
+    std::fs::write(&tmp, rotated)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(count)
+}
@@ -20,11 +20,11 @@
 //!
 //! ```no_run
 //! use deepclaude::clients::AnthropicClient;
-//! use deepclaude::models::{Message, ApiConfig};
+//! use deepclaude::models::request::{ApiConfig, Message};
 //!
 //! async fn example() -> Result<(), Box<dyn std::error::Error>> {
 //!     let client = AnthropicClient::new("your-api-key".to_string());
-//!     let messages = vec![/* your messages */];
+//!     let messages: Vec<Message> = vec![/* your messages */];
 //!     let config = ApiConfig::default();
 //!
 //!     // Non-streaming request
@@ -39,6 +39,7 @@
 use crate::{
     error::{ApiError, Result},
     models::request::{ApiConfig, Message, Role},
+    pacing, payloads,
 };
 use futures::Stream;
 use reqwest::{header::HeaderMap, Client};
@@ -47,63 +48,31 @@ use std::{collections::HashMap, pin::Pin};
 use futures::StreamExt;
 use serde_json;
 use tracing;
-use std::env;
-use std::fs;
-use std::path::PathBuf;
+use super::{
+    capture::{self, LastResponse, ResponseMeta},
+    hosts::{self, HostRoute, UsageFields},
+    parse::{self, ClaudeLine, LineBuffer, ParseError},
+    providers,
+    quota::{self, LastQuota, Quota},
+    repair::Repairer,
+    stats::{LastStream, Meter, StreamTiming},
+};
 
-// 从.env文件中读取API URL，如果未设置则使用默认值
+// 以下配置均来自当前生效的上游服务配置快照，可通过管理接口热重载
 pub(crate) fn get_anthropic_api_url() -> String {
-    read_env_from_dotenv("ANTHROPIC_API_URL").unwrap_or_else(|| String::from("https://api.gptsapi.net/v1/messages"))
+    providers::current().anthropic_api_url.clone()
 }
 
-// 从.env文件中读取Claude的OpenAI格式API URL，如果未设置则使用默认值
 pub(crate) fn get_claude_openai_type_api_url() -> String {
-    read_env_from_dotenv("CLAUDE_OPENAI_TYPE_API_URL").unwrap_or_else(|| String::from("https://api.claude-Plus.top/v1/chat/completions"))
+    providers::current().claude_openai_type_api_url.clone()
 }
 
-// 从.env文件中读取DeepSeek的OpenAI格式API URL，如果未设置则使用默认值
 pub(crate) fn get_deepseek_openai_type_api_url() -> String {
-    read_env_from_dotenv("DEEPSEEK_OPENAI_TYPE_API_URL").unwrap_or_else(|| String::from("https://ark.cn-beijing.volces.com/api/v3/chat/completions"))
+    providers::current().deepseek_api_url.clone()
 }
 
-// 从.env文件中读取Claude模型名称，如果未设置则使用默认值
 pub(crate) fn get_claude_default_model() -> String {
-    read_env_from_dotenv("CLAUDE_DEFAULT_MODEL").unwrap_or_else(|| String::from("wild-3-7-sonnet-20250219"))
-}
-
-// 辅助函数，从.env文件读取配置
-fn read_env_from_dotenv(key: &str) -> Option<String> {
-    // 只从.env文件读取配置
-    let current_dir = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
-    let env_path = current_dir.join(".env");
-    
-    // 确保.env文件存在
-    if !ensure_env_file_exists(&env_path) {
-        return None;
-    }
-    
-    match fs::read_to_string(&env_path) {
-        Ok(content) => {
-            content.lines()
-                .find(|line| line.starts_with(&format!("{}=", key)))
-                .and_then(|line| line.split('=').nth(1))
-                .map(|value| value.trim().trim_matches('"').to_string())
-        }
-        Err(e) => {
-            tracing::debug!("读取.env文件失败: {}", e);
-            None
-        }
-    }
-}
-
-// 确保.env文件存在
-fn ensure_env_file_exists(env_path: &PathBuf) -> bool {
-    if !env_path.exists() {
-        tracing::error!(".env文件不存在: {:?}", env_path);
-        tracing::error!("请在项目根目录创建.env文件并设置必要的配置项");
-        return false;
-    }
-    true
+    providers::current().claude_model.clone()
 }
 
 // 为了向后兼容，保留这些常量，但它们现在使用函数获取值
@@ -136,7 +105,12 @@ const CLAUDE_DEFAULT_MODEL: &str = "wild-3-7-sonnet-20250219";
 #[derive(Debug)]
 pub struct AnthropicClient {
     pub(crate) client: Client,
-    _api_token: String,  // 添加下划线前缀，表示有意不使用
+    api_token: String,
+    route: Option<HostRoute>,
+    prompt_cache: bool,
+    last_stream: LastStream,
+    last_quota: LastQuota,
+    last_response: LastResponse,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -156,10 +130,14 @@ pub struct AnthropicResponse {
 pub struct ContentBlock {
     #[serde(rename = "type")]
     pub content_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub text: String,
+    /// Fields of non-text blocks, such as the `id`, `name` and `input` of a `tool_use` block.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Usage {
     #[serde(default)]
     pub input_tokens: u32,
@@ -171,31 +149,91 @@ pub struct Usage {
     pub cache_read_input_tokens: u32,
 }
 
-impl Default for Usage {
-    fn default() -> Self {
+impl AnthropicResponse {
+    /// Concatenated text of the response's text blocks.
+    pub fn text(&self) -> String {
+        self.content.iter().map(|block| block.text.as_str()).collect()
+    }
+}
+
+impl Usage {
+    /// Reads a usage object in either format: Anthropic's `input_tokens` and
+    /// `output_tokens`, or an OpenAI-format gateway's counts found where
+    /// `fields` says, where the input tokens exclude those read from the
+    /// cache.
+    pub fn from_value(usage: &serde_json::Value, fields: &UsageFields) -> Self {
+        if usage.get("input_tokens").is_some() || usage.get("output_tokens").is_some() {
+            return serde_json::from_value(usage.clone()).unwrap_or_default();
+        }
+        let tokens = |pointer: &str| {
+            usage
+                .pointer(pointer)
+                .and_then(|v| v.as_u64())
+                .map_or(0, |tokens| u32::try_from(tokens).unwrap_or(u32::MAX))
+        };
+        let cached = tokens(fields.cached);
         Self {
-            input_tokens: 0,
-            output_tokens: 0,
+            input_tokens: tokens(fields.input).saturating_sub(cached),
+            output_tokens: tokens(fields.output),
             cache_creation_input_tokens: 0,
-            cache_read_input_tokens: 0,
+            cache_read_input_tokens: cached,
         }
     }
+
+    /// Adds the tokens of another call.
+    pub fn add(&mut self, other: &Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+    }
+
+    /// Takes in usage reported again during the same stream, which counts
+    /// cumulatively, keeping the larger count of each field.
+    pub fn update(&mut self, reported: &Usage) {
+        self.input_tokens = self.input_tokens.max(reported.input_tokens);
+        self.output_tokens = self.output_tokens.max(reported.output_tokens);
+        self.cache_creation_input_tokens = self.cache_creation_input_tokens.max(reported.cache_creation_input_tokens);
+        self.cache_read_input_tokens = self.cache_read_input_tokens.max(reported.cache_read_input_tokens);
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct AnthropicRequest {
     messages: Vec<AnthropicMessage>,
     stream: bool,
+    /// Plain text, or an array of text blocks.
     #[serde(skip_serializing_if = "Option::is_none")]
-    system: Option<String>,
+    system: Option<serde_json::Value>,
     #[serde(flatten)]
     additional_params: serde_json::Value,
 }
 
+impl AnthropicRequest {
+    /// Offers tools to the model, in the Anthropic tool definition format.
+    pub(crate) fn set_tools(&mut self, tools: Vec<serde_json::Value>) {
+        self.additional_params["tools"] = serde_json::Value::Array(tools);
+    }
+
+    /// Sets how the model may use the offered tools.
+    pub(crate) fn set_tool_choice(&mut self, choice: serde_json::Value) {
+        self.additional_params["tool_choice"] = choice;
+    }
+
+    /// Appends a conversation turn whose content may be an array of content blocks.
+    pub(crate) fn push_turn(&mut self, role: &str, content: serde_json::Value) {
+        self.messages.push(AnthropicMessage {
+            role: role.to_string(),
+            content,
+        });
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct AnthropicMessage {
     role: String,
-    content: String,
+    /// Plain text, or an array of content blocks.
+    content: serde_json::Value,
 }
 
 // Event types for streaming responses
@@ -265,7 +303,69 @@ impl AnthropicClient {
     pub fn new(api_token: String) -> Self {
         Self {
             client: Client::new(),
-            _api_token: api_token,
+            api_token,
+            route: None,
+            prompt_cache: false,
+            last_stream: LastStream::default(),
+            last_quota: LastQuota::default(),
+            last_response: LastResponse::default(),
+        }
+    }
+
+    /// Returns the time to first token and throughput of the latest stream.
+    pub fn stream_timing(&self) -> Option<StreamTiming> {
+        self.last_stream.timing()
+    }
+
+    /// Returns the upstream quota reported by the latest response.
+    pub fn quota(&self) -> Option<Quota> {
+        self.last_quota.get()
+    }
+
+    /// Returns the status and selected headers of the latest non-streaming response.
+    pub fn last_response(&self) -> Option<ResponseMeta> {
+        self.last_response.get()
+    }
+
+    /// Sends requests to an OpenAI-compatible host such as OpenRouter
+    /// instead of the configured Claude endpoint.
+    pub fn with_route(mut self, route: Option<HostRoute>) -> Self {
+        self.route = route;
+        self
+    }
+
+    /// Marks the system prompt of each request as a cached prefix, see
+    /// [`crate::sessions`]. Ignored for OpenAI-compatible hosts.
+    pub fn with_prompt_cache(mut self, enabled: bool) -> Self {
+        self.prompt_cache = enabled;
+        self
+    }
+
+    /// Where the OpenAI-format responses of this client's host report usage.
+    fn usage_fields(&self) -> &'static UsageFields {
+        self.route.as_ref().map_or(&hosts::OPENAI_USAGE, |route| &route.profile.usage)
+    }
+
+    /// Returns true if requests for this model go to the Anthropic Messages
+    /// API, the only format tool calls are supported in.
+    pub fn supports_tools(&self, config: &ApiConfig) -> bool {
+        let model = config.body.get("model").and_then(|v| v.as_str()).unwrap_or_default();
+        let is_deepseek = model.starts_with("deepseek") || model == "deepclaude";
+        self.route.is_none() && !is_deepseek && !should_use_openai_format()
+    }
+
+    /// Chooses the endpoint for a request.
+    fn api_url(&self, is_deepseek: bool) -> String {
+        if let Some(route) = &self.route {
+            route.api_url.clone()
+        } else if is_deepseek {
+            get_deepseek_openai_type_api_url()
+        } else if should_use_openai_format() {
+            // 使用OpenAI格式的API
+            get_claude_openai_type_api_url()
+        } else {
+            // 使用Anthropic原生API
+            get_anthropic_api_url()
         }
     }
 
@@ -289,9 +389,23 @@ impl AnthropicClient {
         let mut headers = HeaderMap::new();
         
         // 根据API类型添加不同的认证头
-        if is_deepseek {
+        if let Some(route) = &self.route {
+            // 路由到第三方服务商时使用服务商的密钥及其认证方式
+            headers.insert(
+                "Authorization",
+                route
+                    .authorization()?
+                    .parse()
+                    .map_err(|e| ApiError::Internal { 
+                        message: format!("无效的Authorization头: {}", e) 
+                    })?,
+            );
+        } else if is_deepseek {
             // DeepSeek API认证
-            let deepseek_token = read_env_from_dotenv("DEEPSEEK_API_KEY")
+            let deepseek_token = providers::current()
+                .deepseek_api_key
+                .as_deref()
+                .and_then(crate::secrets::expose)
                 .ok_or_else(|| ApiError::Internal { 
                     message: "未在.env文件中找到DEEPSEEK_API_KEY".to_string() 
                 })?;
@@ -306,7 +420,7 @@ impl AnthropicClient {
             );
         } else if should_use_openai_format() {
             // OpenAI格式API认证
-            let api_token = self._api_token.clone();
+            let api_token = self.api_token.clone();
             
             headers.insert(
                 "Authorization",
@@ -320,11 +434,18 @@ impl AnthropicClient {
             // OpenAI格式API不需要额外的头部
         } else {
             // Anthropic原生格式API认证
-            // 从.env文件获取API密钥
-            let anthropic_token = read_env_from_dotenv("ANTHROPIC_API_KEY")
-                .ok_or_else(|| ApiError::Internal { 
-                    message: "未在.env文件中找到ANTHROPIC_API_KEY".to_string() 
-                })?;
+            // 优先使用客户端持有的密钥（如虚拟密钥绑定的上游密钥），否则从.env文件获取
+            let anthropic_token = if !self.api_token.is_empty() {
+                self.api_token.clone()
+            } else {
+                providers::current()
+                    .anthropic_api_key
+                    .as_deref()
+                    .and_then(crate::secrets::expose)
+                    .ok_or_else(|| ApiError::Internal { 
+                        message: "未在.env文件中找到ANTHROPIC_API_KEY".to_string() 
+                    })?
+            };
             
             headers.insert(
                 "x-api-key",
@@ -378,10 +499,10 @@ impl AnthropicClient {
 
         // 添加自定义头部
         if let Some(custom) = custom_headers {
-            headers.extend(super::build_headers(custom)?);
+            super::merge_headers(&mut headers, custom)?;
         }
 
-        tracing::debug!("最终请求头: {:?}", headers);
+        tracing::debug!("最终请求头: {:?}", headers.keys().collect::<Vec<_>>());
 
         Ok(headers)
     }
@@ -405,7 +526,7 @@ impl AnthropicClient {
         stream: bool,
         config: &ApiConfig,
     ) -> AnthropicRequest {
-        let filtered_messages = messages
+        let mut filtered_messages: Vec<AnthropicMessage> = messages
             .into_iter()
             .filter(|msg| msg.role != Role::System)
             .filter(|msg| !msg.content.trim().is_empty())
@@ -415,10 +536,22 @@ impl AnthropicClient {
                     Role::Assistant => "assistant".to_string(),
                     Role::System => unreachable!(),
                 },
-                content: msg.content,
+                content: serde_json::Value::String(msg.content),
             })
             .collect();
 
+        // OpenAI格式的服务商不支持顶层system字段，改为系统消息
+        let system = match (&self.route, system) {
+            (Some(_), Some(system)) => {
+                filtered_messages.insert(0, AnthropicMessage {
+                    role: "system".to_string(),
+                    content: serde_json::Value::String(system),
+                });
+                None
+            }
+            (_, system) => system,
+        };
+
         // Create base request with required fields
         let default_model = get_claude_default_model();
         let default_model_json = serde_json::json!(default_model);
@@ -449,7 +582,13 @@ impl AnthropicClient {
         // Add system if present
         if let Some(ref sys) = system {
             if let serde_json::Value::Object(mut map) = request_value {
-                map.insert("system".to_string(), serde_json::json!(sys));
+                // 会话请求把系统提示词作为缓存前缀发送
+                let sys = if self.prompt_cache {
+                    serde_json::json!([{ "type": "text", "text": sys, "cache_control": { "type": "ephemeral" } }])
+                } else {
+                    serde_json::json!(sys)
+                };
+                map.insert("system".to_string(), sys);
                 request_value = serde_json::Value::Object(map);
             }
         }
@@ -467,6 +606,28 @@ impl AnthropicClient {
                     map.insert(key, value);
                 }
             }
+
+            // 模型别名替换为服务商的模型id，并加上服务商作为回答模型时需要的参数
+            if let Some(route) = &self.route {
+                map.insert("model".to_string(), serde_json::json!(route.model));
+                for (key, value) in route.profile.responder_params() {
+                    map.entry(key.to_string()).or_insert(value);
+                }
+                if stream && route.profile.stream_usage_opt_in {
+                    map.entry("stream_options".to_string())
+                        .or_insert(serde_json::json!({ "include_usage": true }));
+                }
+            }
+
+            // Messages API没有user字段，终端用户放在metadata.user_id中
+            if self.supports_tools(config) {
+                if let Some(user) = map.remove("user").filter(|user| user.is_string()) {
+                    let metadata = map.entry("metadata").or_insert_with(|| serde_json::json!({}));
+                    if metadata.is_object() && metadata.get("user_id").is_none() {
+                        metadata["user_id"] = user;
+                    }
+                }
+            }
             request_value = serde_json::Value::Object(map);
         }
 
@@ -476,7 +637,7 @@ impl AnthropicClient {
         serde_json::from_value(request_value).unwrap_or_else(|_| AnthropicRequest {
             messages: filtered_messages,
             stream,
-            system,
+            system: system.map(serde_json::Value::String),
             additional_params: config.body.clone(),
         })
     }
@@ -527,6 +688,54 @@ impl AnthropicClient {
             }
         }
 
+        let request = self.build_request(messages, system, false, config);
+        self.send(&request, config).await
+    }
+
+    /// Asks Claude to revise an answer it gave.
+    ///
+    /// `messages` and `system` are the input that produced `answer`; the
+    /// answer and `feedback` are added as the next two turns.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::AnthropicError` if the request fails.
+    pub async fn revise(
+        &self,
+        mut messages: Vec<Message>,
+        system: Option<String>,
+        config: &ApiConfig,
+        answer: &str,
+        feedback: &str,
+    ) -> Result<AnthropicResponse> {
+        // 推理内容是预填的assistant消息，回答接在其后以保持角色交替
+        match messages.last_mut() {
+            Some(last) if last.role == Role::Assistant => {
+                last.content.push_str("\n\n");
+                last.content.push_str(answer);
+            }
+            _ => messages.push(Message {
+                role: Role::Assistant,
+                content: answer.to_string(),
+            }),
+        }
+        messages.push(Message {
+            role: Role::User,
+            content: feedback.to_string(),
+        });
+        self.chat(messages, system, config).await
+    }
+
+    /// Sends a prepared non-streaming request.
+    ///
+    /// Used directly when the request is extended beyond plain text turns,
+    /// such as with tool calls and results.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::AnthropicError` if the request fails or the
+    /// response cannot be parsed.
+    pub(crate) async fn send(&self, request: &AnthropicRequest, config: &ApiConfig) -> Result<AnthropicResponse> {
         // 获取模型名称，决定使用哪个API端点
         let default_model = get_claude_default_model();
         let default_model_json = serde_json::json!(default_model);
@@ -535,30 +744,22 @@ impl AnthropicClient {
         let _is_deepseek = model_str.starts_with("deepseek") || model_str == "deepclaude";
         
         // 选择API端点
-        let api_url = if _is_deepseek {
-            get_deepseek_openai_type_api_url()
-        } else if should_use_openai_format() {
-            // 使用OpenAI格式的API
-            get_claude_openai_type_api_url()
-        } else {
-            // 使用Anthropic原生API
-            get_anthropic_api_url()
-        };
+        let api_url = self.api_url(_is_deepseek);
         
-        // 构建请求头和请求体
+        // 构建请求头
         let headers = self.build_headers(Some(&config.headers), _is_deepseek)?;
-        let request = self.build_request(messages, system, false, config);
         
         // 记录请求信息
         tracing::debug!("API请求URL: {}", api_url);
-        tracing::debug!("API请求头: {:?}", headers);
+        tracing::debug!("API请求头: {:?}", headers.keys().collect::<Vec<_>>());
         //tracing::debug!("Anthropic请求体: {}", serde_json::to_string(&request).unwrap_or_default());
         
         // 发送请求
+        payloads::record("anthropic", &api_url, request);
         let response = self.client
-            .post(api_url)
+            .post(&api_url)
             .headers(headers)
-            .json(&request)
+            .json(request)
             .send()
             .await
             .map_err(|e| ApiError::AnthropicError {
@@ -568,6 +769,9 @@ impl AnthropicClient {
                 code: None
             })?;
         
+        quota::observe("anthropic", &api_url, response.headers(), &self.last_quota);
+        pacing::observe("anthropic", response.status(), response.headers());
+        capture::observe(response.status(), response.headers(), &self.last_response);
         let _status = response.status();
         let raw_response = response.text().await.map_err(|e| ApiError::AnthropicError {
             message: format!("获取响应文本失败: {}", e),
@@ -576,12 +780,17 @@ impl AnthropicClient {
             code: None
         })?;
 
-        tracing::debug!("原始Anthropic块的响应: {}", raw_response);
+        tracing::debug!("原始Anthropic块的响应: {}字节", raw_response.len());
 
         // 处理不同API的响应格式
-        if _is_deepseek {
-            // 处理Deepseek API响应
-            return parse_deepseek_response(&raw_response);
+        if _is_deepseek || self.route.is_some() {
+            // 处理Deepseek及其他OpenAI格式服务商的响应
+            return parse::parse_deepseek_response(&raw_response, self.usage_fields()).map_err(|e| ApiError::AnthropicError {
+                message: format!("解析Deepseek响应JSON失败: {}", e),
+                type_: "parse_error".to_string(),
+                param: None,
+                code: None
+            });
         } else {
             // 处理原有Anthropic API响应
             // 即使响应包含错误信息，也尝试提取有效内容
@@ -592,21 +801,21 @@ impl AnthropicClient {
                 }
                 
                 // 尝试提取内容
-                if let Ok(content_blocks) = extract_content_from_response(&raw_response) {
+                if let Ok(content_blocks) = parse::extract_content_from_response(&raw_response) {
                     if !content_blocks.is_empty() && !content_blocks[0].text.is_empty() {
                         // 构造响应
                         return Ok(AnthropicResponse {
-                            id: extract_id_from_response(&raw_response).unwrap_or_else(|| "generated_id".to_string()),
+                            id: parse::extract_id_from_response(&raw_response).unwrap_or_else(|| "generated_id".to_string()),
                             response_type: "message".to_string(),
                             role: "assistant".to_string(),
                             model: {
                                 let default_model = get_claude_default_model();
-                                extract_model_from_response(&raw_response).unwrap_or_else(|| default_model)
+                                parse::extract_model_from_response(&raw_response).unwrap_or(default_model)
                             },
                             content: content_blocks,
                             stop_reason: Some("stop".to_string()),
                             stop_sequence: None,
-                            usage: extract_usage_from_response(&raw_response).unwrap_or_default(),
+                            usage: parse::extract_usage_from_response(&raw_response, self.usage_fields()).unwrap_or_default(),
                         });
                     }
                 }
@@ -656,15 +865,7 @@ impl AnthropicClient {
         let _is_deepseek = model_str.starts_with("deepseek") || model_str == "deepclaude";
         
         // 选择API端点
-        let api_url = if _is_deepseek {
-            get_deepseek_openai_type_api_url()
-        } else if should_use_openai_format() {
-            // 使用OpenAI格式的API
-            get_claude_openai_type_api_url()
-        } else {
-            // 使用Anthropic原生API
-            get_anthropic_api_url()
-        };
+        let api_url = self.api_url(_is_deepseek);
         
         tracing::info!("使用API端点: {}, 模型: {}", api_url, model_str);
         
@@ -678,10 +879,15 @@ impl AnthropicClient {
         let system = system.clone();
         let request = self.build_request(messages, system, true, config);
         let client = self.client.clone();
+        let last_stream = self.last_stream.clone();
+        let last_quota = self.last_quota.clone();
+        let usage_fields = self.usage_fields();
 
         Box::pin(async_stream::stream! {
+            let meter = Meter::start("anthropic", &api_url, &last_stream);
+            payloads::record("anthropic", &api_url, &request);
             let response = match client
-                .post(api_url)
+                .post(&api_url)
                 .headers(headers)
                 .json(&request)
                 .send()
@@ -701,6 +907,8 @@ impl AnthropicClient {
             
             let status = response.status();
             tracing::debug!("流式响应状态码: {}", status);
+            quota::observe("anthropic", &api_url, response.headers(), &last_quota);
+            pacing::observe("anthropic", status, response.headers());
             
             if !status.is_success() {
                 let error_text = response.text().await.unwrap_or_else(|_| "无法获取错误详情".to_string());
@@ -715,144 +923,18 @@ impl AnthropicClient {
             }
             
             let mut stream = response.bytes_stream();
-            let mut data = String::new();
+            let mut lines = LineBuffer::default();
+            let repairer = Repairer::new(&api_url);
             let mut content_buffer = String::new();
-            let mut _has_content = false;
             let mut stream_ended = false;
             
             tracing::debug!("开始处理流式响应");
             
-            while let Some(chunk_result) = stream.next().await {
-                match chunk_result {
-                    Ok(chunk) => {
-                        if let Ok(text) = String::from_utf8(chunk.to_vec()) {
-                            // 检查是否为OpenAI格式的最终标记
-                            if text.trim() == "data: [DONE]" {
-                                tracing::debug!("接收到OpenAI格式的流结束标记");
-                                stream_ended = true;
-                                yield Ok(StreamEvent::MessageStop);
-                                break;
-                            }
-                            
-                            // 处理流式响应
-                            let lines: Vec<&str> = text.lines().collect();
-                            
-                            for line in lines {
-                                // 跳过空行
-                                if line.trim().is_empty() {
-                                    continue;
-                                }
-                                
-                                // 处理OpenAI格式的数据行
-                                if line.starts_with("data: ") {
-                                    let json_str = &line[6..]; // 移除 "data: " 前缀
-                                    
-                                    // 跳过[DONE]标记，已在前面处理
-                                    if json_str.trim() == "[DONE]" {
-                                        continue;
-                                    }
-                                    
-                                    // 先尝试解析为OpenAI格式
-                                    if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(json_str) {
-                                        // 调试输出原始JSON
-                                        tracing::debug!("OpenAI格式原始响应: {}", json_str);
-                                        
-                                        // 检查是否有choices字段，判断是否为OpenAI格式
-                                        if let Some(choices) = json_value.get("choices").and_then(|v| v.as_array()) {
-                                            if !choices.is_empty() {
-                                                // 提取delta内容
-                                                if let Some(choice) = choices.first() {
-                                                    // 提取delta中的content字段
-                                                    if let Some(delta) = choice.get("delta") {
-                                                        let content = delta.get("content").and_then(|c| c.as_str());
-                                                        if let Some(content_str) = content {
-                                                            if !content_str.is_empty() {
-                                                                tracing::debug!("解析到OpenAI格式的内容: {}", content_str);
-                                                                content_buffer.push_str(content_str);
-                                                                yield Ok(StreamEvent::ContentBlockDelta {
-                                                                    index: 0,
-                                                                    delta: ContentDelta {
-                                                                        delta_type: "text".to_string(),
-                                                                        text: content_str.to_string(),
-                                                                    },
-                                                                });
-                                                            }
-                                                        }
-                                                        continue;
-                                                    }
-                                                    
-                                                    // 检查是否为完成原因
-                                                    if let Some(finish_reason) = choice.get("finish_reason") {
-                                                        if !finish_reason.is_null() {
-                                                            tracing::debug!("检测到完成原因: {:?}", finish_reason);
-                                                            stream_ended = true;
-                                                            yield Ok(StreamEvent::MessageStop);
-                                                            break;
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                            
-                                            // 处理没有type字段的JSON响应
-                                            if json_value.get("type").is_none() && json_value.get("choices").is_some() {
-                                                tracing::debug!("处理没有type字段的OpenAI格式响应");
-                                                // 这里是处理最后一个块的代码，通常包含完整的usage信息
-                                                // 如果需要提取usage信息并更新，可以在这里添加代码
-                                                
-                                                // 由于这不是流式内容块，我们只需记录并继续处理
-                                                tracing::info!("收到非流式块: {}", json_str);
-                                                continue;
-                                            }
-                                        }
-                                    }
-                                    
-                                    // 如果不是OpenAI格式，尝试解析为Anthropic格式
-                                    match serde_json::from_str::<StreamEvent>(json_str) {
-                                        Ok(event) => {
-                                            _has_content = true;
-                                            match &event {
-                                                StreamEvent::ContentBlockDelta { delta, .. } => {
-                                                    //tracing::debug!("解析到Anthropic格式的内容: {}", delta.text);
-                                                    content_buffer.push_str(&delta.text);
-                                                }
-                                                StreamEvent::MessageStop => {
-                                                    tracing::debug!("收到消息结束事件");
-                                                    stream_ended = true;
-                                                }
-                                                _ => {
-                                                    tracing::debug!("收到其他类型事件: {:?}", event);
-                                                }
-                                            }
-                                            yield Ok(event);
-                                        }
-                                        Err(e) => {
-                                            // 检查错误是否是因为不完整的JSON
-                                            let err_msg = e.to_string();
-                                            if err_msg.contains("EOF while parsing") || err_msg.contains("unexpected end of input") {
-                                                // 这是不完整的JSON，只记录调试信息，不返回错误
-                                                tracing::debug!("收到不完整的JSON数据，跳过处理: {}", json_str);
-                                                continue;
-                                            }
-                                            
-                                            // 只记录关键错误，不记录所有解析失败
-                                            if !json_str.contains("ping") && !json_str.contains("HEARTBEAT") {
-                                                tracing::error!("解析事件JSON失败: {} - {}", e, json_str);
-                                            }
-                                            // 不要为所有解析错误生成错误事件
-                                            if json_str != "[DONE]" && !json_str.contains("HEARTBEAT") {
-                                                yield Err(ApiError::Internal {
-                                                    message: format!("Failed to parse event JSON: {}", e),
-                                                });
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    //tracing::debug!("跳过非data事件: {}", raw_event);
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
+            loop {
+                // 按行解析，跨数据块的行与多字节字符会先缓存到下一个块到达
+                let (batch, eof) = match stream.next().await {
+                    Some(Ok(chunk)) => (lines.push(&chunk), false),
+                    Some(Err(e)) => {
                         tracing::error!("读取数据块时出错: {}", e);
                         yield Err(ApiError::AnthropicError { 
                             message: format!("Stream error: {}", e),
@@ -862,13 +944,68 @@ impl AnthropicClient {
                         });
                         return;
                     }
+                    None => (lines.finish(), true),
+                };
+
+                for line in batch {
+                    let line = repairer.line(&line);
+                    match parse::claude_stream_line(&line, usage_fields) {
+                        Ok(ClaudeLine::Skip) => {}
+                        Ok(ClaudeLine::Done) => {
+                            tracing::debug!("接收到OpenAI格式的流结束标记");
+                            stream_ended = true;
+                            yield Ok(StreamEvent::MessageStop);
+                        }
+                        Ok(ClaudeLine::Events(events)) => {
+                            for event in events {
+                                match &event {
+                                    StreamEvent::ContentBlockDelta { delta, .. } => {
+                                        content_buffer.push_str(&delta.text);
+                                        meter.text(&delta.text);
+                                    }
+                                    StreamEvent::MessageDelta { usage: Some(usage), .. } => {
+                                        meter.usage(usage.output_tokens);
+                                    }
+                                    StreamEvent::MessageStop => {
+                                        tracing::debug!("收到消息结束事件");
+                                        stream_ended = true;
+                                    }
+                                    _ => {
+                                        tracing::debug!("收到其他类型事件: {:?}", event);
+                                    }
+                                }
+                                yield Ok(event);
+                                if stream_ended {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(ParseError::Incomplete(_)) => {
+                            // 这是不完整的JSON，只记录调试信息，不返回错误
+                            tracing::debug!("收到不完整的JSON数据，跳过处理: {}", line);
+                        }
+                        Err(e) => {
+                            if !line.contains("HEARTBEAT") {
+                                if !line.contains("ping") {
+                                    tracing::error!("解析事件JSON失败: {} - {}", e, line);
+                                }
+                                yield Err(ApiError::Internal {
+                                    message: format!("Failed to parse event JSON: {}", e),
+                                });
+                            }
+                        }
+                    }
+                    // 如果流已经结束，不再继续处理
+                    if stream_ended {
+                        break;
+                    }
                 }
 
-                // 如果流已经结束，不再继续处理
-                if stream_ended {
+                if stream_ended || eof {
                     break;
                 }
             }
+            tracing::debug!("Claude流结束，收到{}字节内容", content_buffer.len());
         })
     }
 }
@@ -883,196 +1020,7 @@ impl From<ContentBlock> for crate::models::response::ContentBlock {
     }
 }
 
-// 辅助函数，从非标准响应中提取内容
-fn extract_content_from_response(raw_response: &str) -> Result<Vec<ContentBlock>> {
-    // 尝试将响应解析为JSON对象
-    let json_value: serde_json::Value = serde_json::from_str(raw_response)
-        .map_err(|e| ApiError::AnthropicError {
-            message: format!("解析JSON失败: {}", e),
-            type_: "parse_error".to_string(),
-            param: None,
-            code: None
-        })?;
-    
-    // 尝试不同的路径提取内容
-    let content_text = if let Some(content) = json_value.get("content") {
-        // 支持数组格式内容
-        if content.is_array() {
-            let mut text = String::new();
-            for item in content.as_array().unwrap() {
-                if let Some(item_text) = item.get("text").and_then(|t| t.as_str()) {
-                    text.push_str(item_text);
-                }
-            }
-            text
-        } else if let Some(text) = content.as_str() {
-            // 直接字符串内容
-            text.to_string()
-        } else {
-            // 内容是其他格式
-            json_value.to_string()
-        }
-    } else if let Some(choices) = json_value.get("choices") {
-        // OpenAI格式响应
-        if let Some(choice) = choices.as_array().and_then(|arr| arr.first()) {
-            if let Some(message) = choice.get("message") {
-                if let Some(content) = message.get("content").and_then(|c| c.as_str()) {
-                    content.to_string()
-                } else {
-                    json_value.to_string()
-                }
-            } else {
-                json_value.to_string()
-            }
-        } else {
-            json_value.to_string()
-        }
-    } else {
-        // 找不到任何识别的内容格式
-        json_value.to_string()
-    };
-    
-    // 返回提取的内容
-    Ok(vec![ContentBlock {
-        content_type: "text".to_string(),
-        text: content_text,
-    }])
-}
-
-// 从响应中提取ID
-fn extract_id_from_response(raw_response: &str) -> Option<String> {
-    if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(raw_response) {
-        if let Some(id) = json_value.get("id").and_then(|id| id.as_str()) {
-            return Some(id.to_string());
-        }
-    }
-    None
-}
-
-// 从响应中提取模型名称
-fn extract_model_from_response(raw_response: &str) -> Option<String> {
-    if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(raw_response) {
-        if let Some(model) = json_value.get("model").and_then(|m| m.as_str()) {
-            return Some(model.to_string());
-        }
-    }
-    None
-}
-
-// 从响应中提取用量信息
-fn extract_usage_from_response(raw_response: &str) -> Option<Usage> {
-    if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(raw_response) {
-        if let Some(usage) = json_value.get("usage") {
-            if let Ok(usage_data) = serde_json::from_value::<Usage>(usage.clone()) {
-                return Some(usage_data);
-            }
-        }
-    }
-    None
-}
-
-// 添加解析Deepseek响应的函数
-fn parse_deepseek_response(raw_response: &str) -> Result<AnthropicResponse> {
-    // 尝试将响应解析为JSON对象
-    let json_value: serde_json::Value = serde_json::from_str(raw_response)
-        .map_err(|e| ApiError::AnthropicError {
-            message: format!("解析Deepseek响应JSON失败: {}", e),
-            type_: "parse_error".to_string(),
-            param: None,
-            code: None
-        })?;
-    
-    // 提取必要信息
-    let id = json_value.get("id")
-        .and_then(|v| v.as_str())
-        .unwrap_or("deepseek_generated_id")
-        .to_string();
-    
-    let default_model = get_claude_default_model();
-    let model = json_value.get("model")
-        .and_then(|v| v.as_str())
-        .unwrap_or(&default_model)
-        .to_string();
-    
-    // 提取内容
-    let content_text = if let Some(choices) = json_value.get("choices").and_then(|v| v.as_array()) {
-        if let Some(first_choice) = choices.first() {
-            if let Some(message) = first_choice.get("message") {
-                if let Some(content) = message.get("content").and_then(|c| c.as_str()) {
-                    content.to_string()
-                } else {
-                    "".to_string()
-                }
-            } else {
-                "".to_string()
-            }
-        } else {
-            "".to_string()
-        }
-    } else {
-        "".to_string()
-    };
-    
-    // 提取用量信息
-    let usage = if let Some(usage_obj) = json_value.get("usage") {
-        let prompt_tokens = usage_obj.get("prompt_tokens")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0) as u32;
-        
-        let completion_tokens = usage_obj.get("completion_tokens")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0) as u32;
-        
-        Usage {
-            input_tokens: prompt_tokens,
-            output_tokens: completion_tokens,
-            cache_creation_input_tokens: 0,
-            cache_read_input_tokens: 0,
-        }
-    } else {
-        Usage::default()
-    };
-    
-    // 提取停止原因
-    let stop_reason = if let Some(choices) = json_value.get("choices").and_then(|v| v.as_array()) {
-        if let Some(first_choice) = choices.first() {
-            first_choice.get("finish_reason")
-                .and_then(|v| v.as_str())
-                .map(|s| s.to_string())
-        } else {
-            None
-        }
-    } else {
-        None
-    };
-    
-    // 构造内容块
-    let content = vec![ContentBlock {
-        content_type: "text".to_string(),
-        text: content_text,
-    }];
-    
-    // 返回标准化的响应
-    Ok(AnthropicResponse {
-        id,
-        response_type: "message".to_string(),
-        role: "assistant".to_string(),
-        model,
-        content,
-        stop_reason,
-        stop_sequence: None,
-        usage,
-    })
-}
-
-// 判断是否应该使用OpenAI格式的API（基于.env文件中的配置）
+// 判断是否应该使用OpenAI格式的API（基于当前生效的上游服务配置）
 pub(crate) fn should_use_openai_format() -> bool {
-    let claude_openai_url = read_env_from_dotenv("CLAUDE_OPENAI_TYPE_API_URL");
-    let anthropic_url = read_env_from_dotenv("ANTHROPIC_API_URL");
-    
-    match (claude_openai_url, anthropic_url) {
-        (Some(openai_url), _) if !openai_url.trim().is_empty() => true,
-        (_, Some(anthro_url)) if !anthro_url.trim().is_empty() => false,
-        _ => true  // 默认使用OpenAI格式，如果.env文件中两者都为空
-    }
+    providers::current().use_openai_format()
 }
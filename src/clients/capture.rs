@@ -0,0 +1,57 @@
+//! Status and selected headers of a client's latest upstream response.
+//!
+//! Verbose non-streaming responses report, for each stage, what its
+//! upstream answered: the status and the headers worth keeping for support
+//! requests and debugging, namely request ids, rate limits and the model
+//! version. Other headers, such as cookies, are not kept.
+
+use reqwest::{header::HeaderMap, StatusCode};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Headers kept by name.
+const KEPT: &[&str] = &[
+    "x-request-id",
+    "request-id",
+    "x-ds-trace-id",
+    "retry-after",
+    "openai-model",
+    "openai-version",
+    "anthropic-version",
+    "x-model-version",
+];
+
+/// Prefixes of the rate-limit headers, which are all kept.
+const KEPT_PREFIXES: &[&str] = &["x-ratelimit-", "anthropic-ratelimit-"];
+
+/// Status and kept headers of one response.
+#[derive(Debug, Clone)]
+pub struct ResponseMeta {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+}
+
+/// Status and kept headers of a client's latest response.
+#[derive(Debug, Clone, Default)]
+pub struct LastResponse(Arc<Mutex<Option<ResponseMeta>>>);
+
+impl LastResponse {
+    pub fn get(&self) -> Option<ResponseMeta> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Records the status and kept headers of a response.
+pub(crate) fn observe(status: StatusCode, headers: &HeaderMap, slot: &LastResponse) {
+    let headers = headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            KEPT.contains(&name) || KEPT_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    *slot.0.lock().unwrap() = Some(ResponseMeta { status: status.as_u16(), headers });
+}
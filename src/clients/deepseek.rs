@@ -15,10 +15,11 @@
 //! # Examples
 //!
 //! ```no_run
-//! use crate::{
+//! use deepclaude::{
 //!     clients::DeepSeekClient,
-//!     models::{ApiConfig, Message},
+//!     models::request::{ApiConfig, Message, Role},
 //! };
+//! use futures::StreamExt;
 //!
 //! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
 //! // Initialize the client
@@ -26,7 +27,7 @@
 //!
 //! // Prepare messages and configuration
 //! let messages = vec![Message {
-//!     role: "user".to_string(),
+//!     role: Role::User,
 //!     content: "Hello, how are you?".to_string(),
 //! }];
 //!
@@ -57,6 +58,7 @@
 use crate::{
     error::{ApiError, Result},
     models::request::{ApiConfig, Message},
+    pacing, payloads,
 };
 use futures::Stream;
 use reqwest::{header::HeaderMap, Client};
@@ -64,16 +66,25 @@ use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, pin::Pin};
 use futures::StreamExt;
 use serde_json;
-use std::env;
+use super::{
+    capture::{self, LastResponse, ResponseMeta},
+    hosts::{self, HostProfile, HostRoute, WireApi},
+    openai::OpenAIResponsesClient,
+    parse::{sse_line, LineBuffer, SseLine},
+    repair::Repairer,
+    providers,
+    quota::{self, LastQuota, Quota},
+    stats::{LastStream, Meter, StreamTiming},
+};
 
-// 从环境变量中读取DeepSeek API URL，如果未设置则使用默认值
+// 从当前生效的上游服务配置中读取DeepSeek API URL
 pub(crate) fn get_deepseek_api_url() -> String {
-    env::var("DEEPSEEK_OPENAI_TYPE_API_URL").unwrap_or_else(|_| String::from("https://ark.cn-beijing.volces.com/api/v3/chat/completions"))
+    providers::current().deepseek_api_url.clone()
 }
 
-// 从环境变量中读取DeepSeek模型名称，如果未设置则使用默认值
+// 从当前生效的上游服务配置中读取DeepSeek模型名称
 pub(crate) fn get_deepseek_default_model() -> String {
-    env::var("DEEPSEEK_DEFAULT_MODEL").unwrap_or_else(|_| String::from("deepseek-r1-250120"))
+    providers::current().deepseek_model.clone()
 }
 
 // 为了向后兼容，保留这些常量，但它们现在使用函数获取值
@@ -99,6 +110,10 @@ pub const DEEPSEEK_DEFAULT_MODEL: &str = "deepseek-r1-250120";
 pub struct DeepSeekClient {
     pub(crate) client: Client,
     api_token: String,
+    route: Option<HostRoute>,
+    last_stream: LastStream,
+    last_quota: LastQuota,
+    last_response: LastResponse,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -126,7 +141,7 @@ pub struct Choice {
 pub struct AssistantMessage {
     pub role: String,
     pub content: Option<String>,
-    #[serde(rename = "reasoning_content")]
+    #[serde(rename = "reasoning_content", alias = "reasoning")]
     pub reasoning_content: Option<String>,
 }
 
@@ -143,7 +158,7 @@ pub struct StreamChoice {
 pub struct StreamDelta {
     pub role: Option<String>,
     pub content: Option<String>,
-    #[serde(rename = "reasoning_content", default)]
+    #[serde(rename = "reasoning_content", alias = "reasoning", default)]
     pub reasoning_content: Option<String>,
 }
 
@@ -161,7 +176,7 @@ pub struct StreamResponse {
     pub system_fingerprint: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct DeepSeekUsage {
     #[serde(rename = "prompt_tokens")]
     pub input_tokens: u32,
@@ -182,13 +197,13 @@ pub struct DeepSeekUsage {
     pub output_details: CompletionTokenDetails,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct TokenDetails {
     #[serde(rename = "cached_tokens")]
     pub cached: u32,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct CompletionTokenDetails {
     #[serde(rename = "reasoning_tokens")]
     pub reasoning: u32,
@@ -207,6 +222,56 @@ impl DeepSeekClient {
         Self {
             client: Client::new(),
             api_token,
+            route: None,
+            last_stream: LastStream::default(),
+            last_quota: LastQuota::default(),
+            last_response: LastResponse::default(),
+        }
+    }
+
+    /// Returns the time to first token and throughput of the latest stream.
+    pub fn stream_timing(&self) -> Option<StreamTiming> {
+        self.last_stream.timing()
+    }
+
+    /// Returns the upstream quota reported by the latest response.
+    pub fn quota(&self) -> Option<Quota> {
+        self.last_quota.get()
+    }
+
+    /// Returns the status and selected headers of the latest non-streaming response.
+    pub fn last_response(&self) -> Option<ResponseMeta> {
+        self.last_response.get()
+    }
+
+    /// Sends requests to a third-party R1 host instead of the DeepSeek endpoint.
+    pub fn with_route(mut self, route: Option<HostRoute>) -> Self {
+        self.route = route;
+        self
+    }
+
+    fn api_url(&self) -> String {
+        match &self.route {
+            Some(route) => route.api_url.clone(),
+            None => get_deepseek_api_url(),
+        }
+    }
+
+    /// Returns a Responses API client if the route targets an o-series model.
+    fn responses_client(&self) -> Result<Option<(OpenAIResponsesClient, String)>> {
+        let Some(route) = self.route.as_ref().filter(|r| r.profile.wire == WireApi::Responses) else {
+            return Ok(None);
+        };
+        let api_token = route.require_api_key()?;
+        let client = OpenAIResponsesClient::new(self.client.clone(), route.api_url.clone(), api_token, route.profile);
+        Ok(Some((client, route.model.clone())))
+    }
+
+    /// The host profile for the endpoint this client sends to, if it is a known host.
+    fn profile(&self) -> Option<&'static HostProfile> {
+        match &self.route {
+            Some(route) => Some(route.profile),
+            None => hosts::detect(&self.api_url()),
         }
     }
 
@@ -227,9 +292,14 @@ impl DeepSeekClient {
     /// - Content-Type or Accept headers cannot be constructed
     pub(crate) fn build_headers(&self, custom_headers: Option<&HashMap<String, String>>) -> Result<HeaderMap> {
         let mut headers = HeaderMap::new();
+        // 使用第三方服务商时只使用其专属密钥及其认证方式
+        let authorization = match &self.route {
+            Some(route) => route.authorization()?,
+            None => format!("Bearer {}", self.api_token),
+        };
         headers.insert(
             "Authorization",
-            format!("Bearer {}", self.api_token)
+            authorization
                 .parse()
                 .map_err(|e| ApiError::Internal { 
                     message: format!("Invalid API token: {}", e) 
@@ -253,7 +323,7 @@ impl DeepSeekClient {
         );
 
         if let Some(custom) = custom_headers {
-            headers.extend(super::build_headers(custom)?);
+            super::merge_headers(&mut headers, custom)?;
         }
 
         Ok(headers)
@@ -297,6 +367,20 @@ impl DeepSeekClient {
                     map.insert(key, value);
                 }
             }
+
+            // 部分服务商只有显式要求时才在流的最后返回用量
+            if stream && self.profile().is_some_and(|p| p.stream_usage_opt_in) {
+                map.entry("stream_options".to_string())
+                    .or_insert(serde_json::json!({ "include_usage": true }));
+            }
+
+            // 模型别名替换为服务商的模型id，并加上服务商要求的参数
+            if let Some(route) = &self.route {
+                map.insert("model".to_string(), serde_json::json!(route.model));
+                for (key, value) in route.profile.extra_params() {
+                    map.entry(key.to_string()).or_insert(value);
+                }
+            }
             request_value = serde_json::Value::Object(map);
         }
 
@@ -330,12 +414,17 @@ impl DeepSeekClient {
         messages: Vec<Message>,
         config: &ApiConfig,
     ) -> Result<DeepSeekResponse> {
+        if let Some((client, model)) = self.responses_client()? {
+            return client.chat(messages, &model, config).await;
+        }
+
         let headers = self.build_headers(Some(&config.headers))?;
         let request = self.build_request(messages, false, config);
 
+        payloads::record("deepseek", &self.api_url(), &request);
         let response = self
             .client
-            .post(get_deepseek_api_url())
+            .post(self.api_url())
             .headers(headers)
             .json(&request)
             .send()
@@ -347,11 +436,23 @@ impl DeepSeekClient {
                 code: None
             })?;
 
+        quota::observe("deepseek", &self.api_url(), response.headers(), &self.last_quota);
+        pacing::observe("deepseek", response.status(), response.headers());
+        capture::observe(response.status(), response.headers(), &self.last_response);
+        if let Some(route) = &self.route {
+            route.log_limits(response.headers());
+        }
+
         if !response.status().is_success() {
+            let status = response.status();
+            let headers = response.headers().clone();
             let error = response
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
+            if let Some(profile) = self.profile() {
+                return Err(profile.translate_error(status, &headers, &error));
+            }
             return Err(ApiError::DeepSeekError { 
                 message: error,
                 type_: "api_error".to_string(),
@@ -370,12 +471,19 @@ impl DeepSeekClient {
         tracing::debug!("Raw DeepSeek response start");
         // tracing::debug!("Raw DeepSeek response: {}", raw_response);
 
-        serde_json::from_str(&raw_response).map_err(|e| ApiError::DeepSeekError { 
+        let mut response: DeepSeekResponse = serde_json::from_str(&raw_response).map_err(|e| ApiError::DeepSeekError { 
             message: format!("Failed to parse response: {} | Raw: {}", e, raw_response),
             type_: "parse_error".to_string(),
             param: None,
             code: None
-        })
+        })?;
+
+        if let Some(route) = &self.route {
+            for choice in &mut response.choices {
+                route.normalize_message(&mut choice.message);
+            }
+        }
+        Ok(response)
     }
 
     /// Sends a streaming chat request to the DeepSeek API.
@@ -402,9 +510,20 @@ impl DeepSeekClient {
         messages: Vec<Message>,
         config: &ApiConfig,
     ) -> Pin<Box<dyn Stream<Item = Result<StreamResponse>> + Send>> {
+        match self.responses_client() {
+            Ok(Some((client, model))) => return client.chat_stream(messages, &model, config),
+            Ok(None) => {}
+            Err(e) => return Box::pin(futures::stream::once(async move { Err(e) })),
+        }
+
         let request = self.build_request(messages, true, config);
         let client = self.client.clone();
-        let headers = match self.build_headers(None) {
+        let api_url = self.api_url();
+        let route = self.route.clone();
+        let profile = self.profile();
+        let last_stream = self.last_stream.clone();
+        let last_quota = self.last_quota.clone();
+        let headers = match self.build_headers(Some(&config.headers)) {
             Ok(h) => h,
             Err(e) => {
                 return Box::pin(futures::stream::once(async move {
@@ -414,8 +533,10 @@ impl DeepSeekClient {
         };
 
         Box::pin(async_stream::stream! {
+            let meter = Meter::start("deepseek", &api_url, &last_stream);
+            payloads::record("deepseek", &api_url, &request);
             let response = match client
-                .post(get_deepseek_api_url())
+                .post(&api_url)
                 .headers(headers)
                 .json(&request)
                 .send()
@@ -435,31 +556,44 @@ impl DeepSeekClient {
 
             let status = response.status();
             tracing::debug!("DeepSeek流式响应状态码: {}", status);
+            quota::observe("deepseek", &api_url, response.headers(), &last_quota);
+            pacing::observe("deepseek", status, response.headers());
+            if let Some(route) = &route {
+                route.log_limits(response.headers());
+            }
 
             if !status.is_success() {
+                let headers = response.headers().clone();
                 let error = response
                     .text()
                     .await
                     .unwrap_or_else(|_| "未知错误".to_string());
                 tracing::error!("DeepSeek API返回错误: {}", error);
-                yield Err(ApiError::DeepSeekError { 
-                    message: error,
-                    type_: "api_error".to_string(),
-                    param: None,
-                    code: None
+                yield Err(match profile {
+                    Some(profile) => profile.translate_error(status, &headers, &error),
+                    None => ApiError::DeepSeekError { 
+                        message: error,
+                        type_: "api_error".to_string(),
+                        param: None,
+                        code: None
+                    },
                 });
                 return;
             }
 
             let mut stream = response.bytes_stream();
-            let mut data = String::new();
+            let mut lines = LineBuffer::default();
+            let repairer = Repairer::new(&api_url);
             let mut content_buffer = String::new();
             let mut reasoning_buffer = String::new();
+            let mut in_think = false;
             
-            while let Some(chunk_result) = stream.next().await {
-                let chunk = match chunk_result {
-                    Ok(c) => c,
-                    Err(e) => {
+            let mut eof = false;
+            while !eof {
+                // 按行解析，跨数据块的行与多字节字符会先缓存到下一个块到达
+                let batch = match stream.next().await {
+                    Some(Ok(chunk)) => lines.push(&chunk),
+                    Some(Err(e)) => {
                         yield Err(ApiError::DeepSeekError { 
                             message: format!("流处理错误: {}", e),
                             type_: "stream_error".to_string(),
@@ -468,87 +602,96 @@ impl DeepSeekClient {
                         });
                         return;
                     }
+                    // 上游最后一行可能没有换行符
+                    None => {
+                        eof = true;
+                        lines.finish()
+                    }
                 };
-                
-                let chunk_str = String::from_utf8_lossy(&chunk);
-                //tracing::debug!("收到DeepSeek原始数据块: {}", chunk_str);
-                data.push_str(&chunk_str);
-
-                let mut start = 0;
-                while let Some(end) = data[start..].find("\n\n") {
-                    let end = start + end;
-                    let line = &data[start..end].trim();
-                    start = end + 2;
+
+                for line in batch {
+                    let line = repairer.line(&line);
+                    let json_data = match sse_line(&line) {
+                        SseLine::Data(data) => data.trim(),
+                        SseLine::Comment(comment) => {
+                            // 生成间隙的保活注释，只计数不转发
+                            tracing::trace!("DeepSeek保活注释: {}", comment);
+                            meter.keep_alive();
+                            continue;
+                        }
+                        SseLine::Blank | SseLine::Field => continue,
+                    };
                     
-                    if line.starts_with("data: ") {
-                        let json_data = &line["data: ".len()..];
+                    if json_data == "[DONE]" {
+                        tracing::debug!("DeepSeek流结束，内容状态: content={}, reasoning={}", 
+                            !content_buffer.is_empty(), !reasoning_buffer.is_empty());
                         
-                        if json_data == "[DONE]" {
-                            tracing::debug!("DeepSeek流结束，内容状态: content={}, reasoning={}", 
-                                !content_buffer.is_empty(), !reasoning_buffer.is_empty());
-                            
-                            // 不再在此发送任何内容，完全由handlers.rs负责处理
-                            // 这样可以防止重复发送
-                            
-                            break;
-                        }
+                        // 不再在此发送任何内容，完全由handlers.rs负责处理
+                        // 这样可以防止重复发送
                         
-                        match serde_json::from_str::<StreamResponse>(json_data) {
-                            Ok(mut response) => {
-                                if let Some(choice) = response.choices.first_mut() {
-                                    // 处理推理内容
-                                    if let Some(reasoning) = &choice.delta.reasoning_content {
-                                        if !reasoning.is_empty() {
-                                            reasoning_buffer.push_str(reasoning);
-                                            //tracing::debug!("收集到推理内容: {}", reasoning);
-                                        }
-                                    }
-                                    
-                                    // 处理普通内容
-                                    if let Some(content) = &choice.delta.content {
-                                        if !content.is_empty() {
-                                            content_buffer.push_str(content);
-                                            //tracing::debug!("收集到普通内容: {}", content);
-                                        }
+                        return;
+                    }
+                    
+                    match serde_json::from_str::<StreamResponse>(json_data) {
+                        Ok(mut response) => {
+                            if let Some(choice) = response.choices.first_mut() {
+                                if let Some(route) = &route {
+                                    route.normalize_delta(&mut choice.delta, &mut in_think);
+                                }
+
+                                // 处理推理内容
+                                if let Some(reasoning) = &choice.delta.reasoning_content {
+                                    if !reasoning.is_empty() {
+                                        reasoning_buffer.push_str(reasoning);
+                                        meter.text(reasoning);
+                                        //tracing::debug!("收集到推理内容: {}", reasoning);
                                     }
                                 }
                                 
-                                // 只转发推理内容的流事件，不转发普通内容的流事件
-                                // 这样可以避免普通内容被输出两次
-                                if response.choices.first().and_then(|c| c.delta.reasoning_content.as_ref()).is_some() {
-                                    yield Ok(response);
-                                } else if response.choices.first().and_then(|c| c.delta.role.as_ref()).is_some() {
-                                    // 仍然需要传递角色信息
-                                    yield Ok(response);
+                                // 处理普通内容
+                                if let Some(content) = &choice.delta.content {
+                                    if !content.is_empty() {
+                                        content_buffer.push_str(content);
+                                        meter.text(content);
+                                        //tracing::debug!("收集到普通内容: {}", content);
+                                    }
                                 }
-                                // 不传递普通内容的delta，因为我们会在流结束时一次性发送所有内容
                             }
-                            Err(e) => {
-                                tracing::warn!("解析StreamResponse失败: {}", e);
-                                
-                                // 尝试解析为通用JSON
-                                if let Ok(value) = serde_json::from_str::<serde_json::Value>(json_data) {
-                                    if let Some(error) = value.get("error") {
-                                        yield Err(ApiError::DeepSeekError {
-                                            message: error["message"].as_str().unwrap_or("未知错误").to_string(),
-                                            type_: error["type"].as_str().unwrap_or("unknown").to_string(),
-                                            param: error["param"].as_str().map(|s| s.to_string()),
-                                            code: error["code"].as_str().map(|s| s.to_string()),
-                                        });
-                                        return;
-                                    }
+                            
+                            if let Some(usage) = &response.usage {
+                                meter.usage(usage.output_tokens);
+                            }
+
+                            // 转发推理内容和普通内容的流事件，是否发给客户端由handlers.rs按模式决定
+                            let delta = response.choices.first().map(|c| &c.delta);
+                            if delta.is_some_and(|d| d.reasoning_content.is_some() || d.content.is_some()) {
+                                yield Ok(response);
+                            } else if response.choices.first().and_then(|c| c.delta.role.as_ref()).is_some() {
+                                // 仍然需要传递角色信息
+                                yield Ok(response);
+                            } else if response.usage.is_some() {
+                                // 传递只包含用量的最后一个数据块
+                                yield Ok(response);
+                            }
+                        }
+                        Err(e) => {
+                            tracing::warn!("解析StreamResponse失败: {}", e);
+                            
+                            // 尝试解析为通用JSON
+                            if let Ok(value) = serde_json::from_str::<serde_json::Value>(json_data) {
+                                if let Some(error) = value.get("error") {
+                                    yield Err(ApiError::DeepSeekError {
+                                        message: error["message"].as_str().unwrap_or("未知错误").to_string(),
+                                        type_: error["type"].as_str().unwrap_or("unknown").to_string(),
+                                        param: error["param"].as_str().map(|s| s.to_string()),
+                                        code: error["code"].as_str().map(|s| s.to_string()),
+                                    });
+                                    return;
                                 }
                             }
                         }
                     }
                 }
-                
-                // 更新未处理数据
-                if start < data.len() {
-                    data = data[start..].to_string();
-                } else {
-                    data.clear();
-                }
             }
         })
     }
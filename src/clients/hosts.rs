@@ -0,0 +1,586 @@
+//! Third-party hosts serving DeepSeek R1 for the reasoning stage.
+//!
+//! R1 is available from several OpenAI-compatible hosts that differ in how
+//! they return the reasoning trace and which rate-limit headers they send.
+//! A [`HostProfile`] captures those differences, and `[model_aliases]` in
+//! `config.toml` maps a model name used in requests to a host and the
+//! host's own model id:
+//!
+//! ```toml
+//! [model_aliases.r1-groq]
+//! host = "groq"
+//! model = "deepseek-r1-distill-llama-70b"
+//! ```
+//!
+//! Requests whose DeepSeek-stage model is an alias are sent to that host;
+//! all other requests keep using the configured DeepSeek endpoint.
+//!
+//! OpenAI o-series models (`host = "openai"`, e.g. `o4-mini`) are reached
+//! through the Responses API; see [`super::openai`].
+//!
+//! OpenRouter (`host = "openrouter"`) can serve either stage. Besides
+//! aliases, any model in its catalog can be named directly as
+//! `openrouter/<model id>`; see [`super::openrouter`] for the catalog.
+//!
+//! Volcengine Ark (`host = "ark"`, models named by endpoint id `ep-xxxx`)
+//! is also recognised automatically when it is the configured DeepSeek
+//! endpoint, so its errors and streamed usage are handled without an alias.
+//!
+//! DashScope (`host = "dashscope"`, Qwen), Zhipu (`host = "zhipu"`, GLM)
+//! and Moonshot (`host = "moonshot"`, Kimi) are meant for the Claude stage:
+//! an alias used as `anthropic_config.body.model` sends the answer to that
+//! host instead, with its authentication, its usage fields and the
+//! parameters that keep its models from thinking a second time.
+
+use super::deepseek::{AssistantMessage, StreamDelta};
+use crate::{config::ModelAlias, error::ApiError, secrets};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use reqwest::{header::HeaderMap, StatusCode};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+/// How a host returns the reasoning trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReasoningFormat {
+    /// A separate `reasoning_content` field, as in DeepSeek's own API.
+    ReasoningContent,
+    /// A separate `reasoning` field.
+    Reasoning,
+    /// Inline in `content`, wrapped in `<think>...</think>`.
+    ThinkTags,
+}
+
+/// API a host is reached through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireApi {
+    /// OpenAI-compatible `/chat/completions`.
+    ChatCompletions,
+    /// OpenAI `/responses`, used by the o-series reasoning models.
+    Responses,
+}
+
+/// Shape of a host's error responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// `{"error": {"message", "type", "code"}}` as in the OpenAI API.
+    OpenAI,
+    /// Ark's variant, with dotted codes such as `RateLimitExceeded.EndpointRPMExceeded`.
+    Ark,
+}
+
+/// How a host authenticates requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthScheme {
+    /// The key as a bearer token.
+    Bearer,
+    /// Zhipu's `<id>.<secret>` keys, signed into a short-lived HS256 JWT
+    /// sent as the bearer token. Keys without a secret part are sent as is.
+    ZhipuJwt,
+}
+
+/// Where a host reports token usage, as JSON pointers.
+#[derive(Debug)]
+pub struct UsageFields {
+    /// The usage object of a streamed chunk.
+    pub stream: &'static str,
+    /// Prompt tokens, including cached ones, within the usage object.
+    pub input: &'static str,
+    pub output: &'static str,
+    /// Prompt tokens served from the host's cache.
+    pub cached: &'static str,
+}
+
+/// Usage fields of the OpenAI chat completions API.
+pub const OPENAI_USAGE: UsageFields = UsageFields {
+    stream: "/usage",
+    input: "/prompt_tokens",
+    output: "/completion_tokens",
+    cached: "/prompt_tokens_details/cached_tokens",
+};
+
+/// Request and response conventions of one host.
+#[derive(Debug)]
+pub struct HostProfile {
+    pub name: &'static str,
+    pub wire: WireApi,
+    pub api_url: &'static str,
+    /// Environment variable holding the host's API key.
+    pub key_env: &'static str,
+    pub reasoning: ReasoningFormat,
+    /// Extra body parameters the host needs to return reasoning separately.
+    ///
+    /// Values are JSON literals; anything that does not parse is sent as a string.
+    pub extra_params: &'static [(&'static str, &'static str)],
+    /// Extra body parameters sent when the host answers the Claude stage,
+    /// in the same form as `extra_params`.
+    pub responder_params: &'static [(&'static str, &'static str)],
+    pub auth: AuthScheme,
+    pub usage: UsageFields,
+    pub remaining_requests_header: &'static str,
+    pub remaining_tokens_header: &'static str,
+    pub error_format: ErrorFormat,
+    /// Whether streamed responses only report usage when asked via `stream_options`.
+    pub stream_usage_opt_in: bool,
+}
+
+const PROFILES: &[HostProfile] = &[
+    HostProfile {
+        name: "fireworks",
+        wire: WireApi::ChatCompletions,
+        api_url: "https://api.fireworks.ai/inference/v1/chat/completions",
+        key_env: "FIREWORKS_API_KEY",
+        reasoning: ReasoningFormat::ThinkTags,
+        extra_params: &[],
+        responder_params: &[],
+        auth: AuthScheme::Bearer,
+        usage: OPENAI_USAGE,
+        remaining_requests_header: "x-ratelimit-remaining-requests",
+        remaining_tokens_header: "x-ratelimit-remaining-tokens-generated",
+        error_format: ErrorFormat::OpenAI,
+        stream_usage_opt_in: false,
+    },
+    HostProfile {
+        name: "together",
+        wire: WireApi::ChatCompletions,
+        api_url: "https://api.together.xyz/v1/chat/completions",
+        key_env: "TOGETHER_API_KEY",
+        reasoning: ReasoningFormat::ThinkTags,
+        extra_params: &[],
+        responder_params: &[],
+        auth: AuthScheme::Bearer,
+        usage: OPENAI_USAGE,
+        remaining_requests_header: "x-ratelimit-remaining",
+        remaining_tokens_header: "x-ratelimit-remaining-tokens",
+        error_format: ErrorFormat::OpenAI,
+        stream_usage_opt_in: false,
+    },
+    HostProfile {
+        name: "groq",
+        wire: WireApi::ChatCompletions,
+        api_url: "https://api.groq.com/openai/v1/chat/completions",
+        key_env: "GROQ_API_KEY",
+        reasoning: ReasoningFormat::Reasoning,
+        extra_params: &[("reasoning_format", "parsed")],
+        responder_params: &[],
+        auth: AuthScheme::Bearer,
+        usage: OPENAI_USAGE,
+        remaining_requests_header: "x-ratelimit-remaining-requests",
+        remaining_tokens_header: "x-ratelimit-remaining-tokens",
+        error_format: ErrorFormat::OpenAI,
+        stream_usage_opt_in: false,
+    },
+    HostProfile {
+        name: "siliconflow",
+        wire: WireApi::ChatCompletions,
+        api_url: "https://api.siliconflow.cn/v1/chat/completions",
+        key_env: "SILICONFLOW_API_KEY",
+        reasoning: ReasoningFormat::ReasoningContent,
+        extra_params: &[],
+        responder_params: &[],
+        auth: AuthScheme::Bearer,
+        usage: OPENAI_USAGE,
+        remaining_requests_header: "x-ratelimit-remaining-requests",
+        remaining_tokens_header: "x-ratelimit-remaining-tokens",
+        error_format: ErrorFormat::OpenAI,
+        stream_usage_opt_in: false,
+    },
+    // OpenRouter默认不返回推理内容，需要显式要求
+    HostProfile {
+        name: "openrouter",
+        wire: WireApi::ChatCompletions,
+        api_url: "https://openrouter.ai/api/v1/chat/completions",
+        key_env: "OPENROUTER_API_KEY",
+        reasoning: ReasoningFormat::Reasoning,
+        extra_params: &[("include_reasoning", "true")],
+        responder_params: &[],
+        auth: AuthScheme::Bearer,
+        usage: OPENAI_USAGE,
+        remaining_requests_header: "x-ratelimit-remaining",
+        remaining_tokens_header: "x-ratelimit-remaining-tokens",
+        error_format: ErrorFormat::OpenAI,
+        stream_usage_opt_in: true,
+    },
+    // Ark的模型名使用推理接入点id（ep-xxxx），也接受部分公开模型id
+    HostProfile {
+        name: "ark",
+        wire: WireApi::ChatCompletions,
+        api_url: "https://ark.cn-beijing.volces.com/api/v3/chat/completions",
+        key_env: "ARK_API_KEY",
+        reasoning: ReasoningFormat::ReasoningContent,
+        extra_params: &[],
+        responder_params: &[],
+        auth: AuthScheme::Bearer,
+        usage: OPENAI_USAGE,
+        remaining_requests_header: "x-ratelimit-remaining-requests",
+        remaining_tokens_header: "x-ratelimit-remaining-tokens",
+        error_format: ErrorFormat::Ark,
+        stream_usage_opt_in: true,
+    },
+    // o系列推理模型，通过Responses API获取推理摘要
+    HostProfile {
+        name: "openai",
+        wire: WireApi::Responses,
+        api_url: "https://api.openai.com/v1/responses",
+        key_env: "OPENAI_API_KEY",
+        reasoning: ReasoningFormat::ReasoningContent,
+        extra_params: &[],
+        responder_params: &[],
+        auth: AuthScheme::Bearer,
+        usage: OPENAI_USAGE,
+        remaining_requests_header: "x-ratelimit-remaining-requests",
+        remaining_tokens_header: "x-ratelimit-remaining-tokens",
+        error_format: ErrorFormat::OpenAI,
+        stream_usage_opt_in: false,
+    },
+    // 通义千问的OpenAI兼容模式；Qwen3模型默认开启思考，作为回答模型时关闭
+    HostProfile {
+        name: "dashscope",
+        wire: WireApi::ChatCompletions,
+        api_url: "https://dashscope.aliyuncs.com/compatible-mode/v1/chat/completions",
+        key_env: "DASHSCOPE_API_KEY",
+        reasoning: ReasoningFormat::ReasoningContent,
+        extra_params: &[],
+        responder_params: &[("enable_thinking", "false")],
+        auth: AuthScheme::Bearer,
+        usage: OPENAI_USAGE,
+        remaining_requests_header: "x-ratelimit-remaining-requests",
+        remaining_tokens_header: "x-ratelimit-remaining-tokens",
+        error_format: ErrorFormat::OpenAI,
+        stream_usage_opt_in: true,
+    },
+    // 智谱GLM的密钥为id.secret，需签发JWT；流的最后一块自带用量
+    HostProfile {
+        name: "zhipu",
+        wire: WireApi::ChatCompletions,
+        api_url: "https://open.bigmodel.cn/api/paas/v4/chat/completions",
+        key_env: "ZHIPUAI_API_KEY",
+        reasoning: ReasoningFormat::ReasoningContent,
+        extra_params: &[],
+        responder_params: &[("thinking", r#"{"type": "disabled"}"#)],
+        auth: AuthScheme::ZhipuJwt,
+        usage: OPENAI_USAGE,
+        remaining_requests_header: "x-ratelimit-remaining-requests",
+        remaining_tokens_header: "x-ratelimit-remaining-tokens",
+        error_format: ErrorFormat::OpenAI,
+        stream_usage_opt_in: false,
+    },
+    // Kimi在流的完成块的choice中返回用量，缓存命中数在usage.cached_tokens
+    HostProfile {
+        name: "moonshot",
+        wire: WireApi::ChatCompletions,
+        api_url: "https://api.moonshot.cn/v1/chat/completions",
+        key_env: "MOONSHOT_API_KEY",
+        reasoning: ReasoningFormat::ReasoningContent,
+        extra_params: &[],
+        responder_params: &[],
+        auth: AuthScheme::Bearer,
+        usage: UsageFields {
+            stream: "/choices/0/usage",
+            input: "/prompt_tokens",
+            output: "/completion_tokens",
+            cached: "/cached_tokens",
+        },
+        remaining_requests_header: "x-ratelimit-remaining-requests",
+        remaining_tokens_header: "x-ratelimit-remaining-tokens",
+        error_format: ErrorFormat::OpenAI,
+        stream_usage_opt_in: false,
+    },
+];
+
+/// Recognises a host from its endpoint URL.
+pub fn detect(api_url: &str) -> Option<&'static HostProfile> {
+    if api_url.contains(".volces.com/") {
+        return profile("ark");
+    }
+    if api_url.contains("openrouter.ai/") {
+        return profile("openrouter");
+    }
+    None
+}
+
+/// Looks up a built-in host profile by name.
+pub fn profile(name: &str) -> Option<&'static HostProfile> {
+    PROFILES.iter().find(|profile| profile.name == name)
+}
+
+impl HostProfile {
+    /// Returns the extra body parameters as JSON values.
+    pub fn extra_params(&self) -> impl Iterator<Item = (&'static str, serde_json::Value)> {
+        json_params(self.extra_params)
+    }
+
+    /// Returns the extra body parameters of the Claude stage as JSON values.
+    pub fn responder_params(&self) -> impl Iterator<Item = (&'static str, serde_json::Value)> {
+        json_params(self.responder_params)
+    }
+
+    /// Translates an error response into an `ApiError`.
+    pub fn translate_error(&self, status: StatusCode, headers: &HeaderMap, body: &str) -> ApiError {
+        let parsed: Option<serde_json::Value> = serde_json::from_str(body).ok();
+        let error = parsed.as_ref().map(|v| &v["error"]).filter(|e| e.is_object());
+        let Some(error) = error else {
+            return ApiError::DeepSeekError {
+                message: body.to_string(),
+                type_: "api_error".to_string(),
+                param: None,
+                code: Some(status.as_u16().to_string()),
+            };
+        };
+
+        let message = error["message"].as_str().unwrap_or(body).to_string();
+        let code = error["code"].as_str().map(String::from);
+        let param = error["param"].as_str().filter(|p| !p.is_empty()).map(String::from);
+
+        match self.error_format {
+            ErrorFormat::OpenAI => ApiError::DeepSeekError {
+                message,
+                type_: error["type"].as_str().unwrap_or("api_error").to_string(),
+                param,
+                code,
+            },
+            ErrorFormat::Ark => {
+                let code_str = code.as_deref().unwrap_or_default();
+                let type_ = match code_str.split('.').next().unwrap_or_default() {
+                    "AuthenticationError" | "InvalidAccountStatus" => "authentication_error",
+                    "AccessDenied" | "AccountOverdueError" => "permission_denied",
+                    "InvalidEndpointOrModel" | "ModelNotOpen" => "model_not_found",
+                    "RateLimitExceeded" | "QuotaExceeded" => "rate_limit_exceeded",
+                    "ServerOverloaded" => "overloaded",
+                    "SensitiveContentDetected" => "content_filter",
+                    "MissingParameter" | "InvalidParameter" => "invalid_request",
+                    _ => "api_error",
+                };
+                let mut message = if type_ == "model_not_found" {
+                    format!("{}（请确认模型名为Ark推理接入点id，如ep-xxxx，且该接入点已开通）", message)
+                } else {
+                    message
+                };
+                // 附带请求id便于在火山引擎控制台排查
+                if let Some(request_id) = headers.get("x-request-id").and_then(|v| v.to_str().ok()) {
+                    message = format!("{} (request id: {})", message, request_id);
+                }
+                // 限流和过载返回429/503并带上Retry-After，客户端可以稍后重试
+                let retry_after_secs = headers
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse().ok())
+                    .unwrap_or(1);
+                match type_ {
+                    "rate_limit_exceeded" => {
+                        tracing::warn!("{}限流: {}", self.name, message);
+                        return ApiError::RateLimited {
+                            provider: self.name.to_string(),
+                            retry_after_secs,
+                        };
+                    }
+                    "overloaded" => return ApiError::Overloaded { message, retry_after_secs },
+                    _ => {}
+                }
+                ApiError::DeepSeekError {
+                    message,
+                    type_: type_.to_string(),
+                    param,
+                    code,
+                }
+            }
+        }
+    }
+}
+
+fn json_params(
+    params: &'static [(&'static str, &'static str)],
+) -> impl Iterator<Item = (&'static str, serde_json::Value)> {
+    params.iter().map(|(key, value)| {
+        let value = serde_json::from_str(value).unwrap_or_else(|_| serde_json::json!(value));
+        (*key, value)
+    })
+}
+
+/// Seconds a signed Zhipu token stays valid.
+const ZHIPU_TOKEN_TTL_SECS: i64 = 3600;
+
+/// Signs a Zhipu `<id>.<secret>` key into a JWT.
+fn zhipu_token(key: &str) -> String {
+    let Some((id, secret)) = key.split_once('.') else {
+        return key.to_string();
+    };
+    let now = chrono::Utc::now().timestamp_millis();
+    let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","sign_type":"SIGN"}"#);
+    let claims = serde_json::json!({
+        "api_key": id,
+        "exp": now + ZHIPU_TOKEN_TTL_SECS * 1000,
+        "timestamp": now,
+    });
+    let signing_input = format!("{}.{}", header, URL_SAFE_NO_PAD.encode(claims.to_string()));
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(signing_input.as_bytes());
+    format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+}
+
+/// Names of the built-in host profiles.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    PROFILES.iter().map(|p| p.name)
+}
+
+/// Checks that every alias names a known host and has a key for it, its
+/// own `api_key` or the host's environment variable.
+///
+/// # Errors
+///
+/// Returns an error naming the first alias with an unknown host or without
+/// a key.
+pub fn validate(aliases: &HashMap<String, ModelAlias>) -> anyhow::Result<()> {
+    for (alias, config) in aliases {
+        let Some(profile) = profile(&config.host) else {
+            let known = names().collect::<Vec<_>>().join(", ");
+            anyhow::bail!("模型别名'{}'使用了未知的服务商'{}'，可选: {}", alias, config.host, known);
+        };
+        let has_key = config.api_key.as_deref().is_some_and(|key| !key.is_empty())
+            || std::env::var(profile.key_env).is_ok_and(|key| !key.is_empty());
+        if !has_key {
+            anyhow::bail!(
+                "模型别名'{}'路由到{}，但没有设置api_key，也没有设置环境变量{}",
+                alias,
+                profile.name,
+                profile.key_env
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Model name prefix that routes a request straight to an OpenRouter model.
+pub const OPENROUTER_PREFIX: &str = "openrouter/";
+
+/// A resolved model alias: where and how to send a reasoning request.
+#[derive(Debug, Clone)]
+pub struct HostRoute {
+    pub profile: &'static HostProfile,
+    pub model: String,
+    pub api_url: String,
+    api_key: Option<String>,
+}
+
+impl HostRoute {
+    /// Resolves the route for a stage's model, if it is an alias or an
+    /// `openrouter/<model id>` name.
+    pub fn for_model(aliases: &HashMap<String, ModelAlias>, model: Option<&str>) -> Option<Self> {
+        let model = model?;
+        if let Some(model_id) = model.strip_prefix(OPENROUTER_PREFIX).filter(|_| !aliases.contains_key(model)) {
+            let profile = profile("openrouter")?;
+            return Some(Self {
+                profile,
+                model: model_id.to_string(),
+                api_url: profile.api_url.to_string(),
+                api_key: None,
+            });
+        }
+
+        let alias = aliases.get(model)?;
+        let profile = profile(&alias.host)?;
+        Some(Self {
+            profile,
+            model: alias.model.clone(),
+            api_url: alias.api_url.clone().unwrap_or_else(|| profile.api_url.to_string()),
+            api_key: alias.api_key.clone(),
+        })
+    }
+
+    /// Returns the host API key, falling back to the host's environment variable.
+    pub fn api_key(&self) -> Option<String> {
+        self.api_key
+            .clone()
+            .or_else(|| std::env::var(self.profile.key_env).ok())
+            .filter(|key| !key.is_empty())
+            .and_then(|key| secrets::expose(&key))
+    }
+
+    /// Returns the host API key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Internal` if the host has no key. The caller's or
+    /// the server's DeepSeek and Anthropic keys are never sent to another
+    /// host in its place.
+    pub fn require_api_key(&self) -> Result<String, ApiError> {
+        self.api_key().ok_or_else(|| ApiError::Internal {
+            message: format!(
+                "服务商{}未配置API密钥，请在[model_aliases]中设置api_key或设置环境变量{}",
+                self.profile.name, self.profile.key_env
+            ),
+        })
+    }
+
+    /// Returns the `Authorization` header value for the host.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Internal` if the host has no key.
+    pub fn authorization(&self) -> Result<String, ApiError> {
+        let key = self.require_api_key()?;
+        Ok(match self.profile.auth {
+            AuthScheme::Bearer => format!("Bearer {}", key),
+            AuthScheme::ZhipuJwt => format!("Bearer {}", zhipu_token(&key)),
+        })
+    }
+
+    /// Logs the host's remaining rate limit, warning when it is exhausted.
+    pub fn log_limits(&self, headers: &HeaderMap) {
+        let read = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(String::from);
+        let requests = read(self.profile.remaining_requests_header);
+        let tokens = read(self.profile.remaining_tokens_header);
+        if requests.as_deref() == Some("0") || tokens.as_deref() == Some("0") {
+            tracing::warn!("{}的速率限制已耗尽: 剩余请求={:?}, 剩余tokens={:?}", self.profile.name, requests, tokens);
+        } else {
+            tracing::debug!("{}剩余速率限制: 请求={:?}, tokens={:?}", self.profile.name, requests, tokens);
+        }
+    }
+
+    /// Moves an inline `<think>` block out of a complete message's content.
+    pub fn normalize_message(&self, message: &mut AssistantMessage) {
+        if self.profile.reasoning != ReasoningFormat::ThinkTags || message.reasoning_content.is_some() {
+            return;
+        }
+        let Some(content) = message.content.take() else {
+            return;
+        };
+        match content.trim_start().strip_prefix("<think>").and_then(|rest| rest.split_once("</think>")) {
+            Some((reasoning, answer)) => {
+                message.reasoning_content = Some(reasoning.trim().to_string());
+                message.content = Some(answer.trim_start().to_string());
+            }
+            None => message.content = Some(content),
+        }
+    }
+
+    /// Moves streamed `<think>` content into the reasoning field.
+    ///
+    /// `in_think` carries the parser state across deltas.
+    pub fn normalize_delta(&self, delta: &mut StreamDelta, in_think: &mut bool) {
+        if self.profile.reasoning != ReasoningFormat::ThinkTags {
+            return;
+        }
+        let Some(mut content) = delta.content.take() else {
+            return;
+        };
+
+        if let Some(start) = content.find("<think>") {
+            *in_think = true;
+            content = content[start + "<think>".len()..].to_string();
+        }
+        if !*in_think {
+            delta.content = Some(content);
+            return;
+        }
+
+        match content.split_once("</think>") {
+            Some((reasoning, answer)) => {
+                *in_think = false;
+                delta.reasoning_content = Some(reasoning.to_string());
+                delta.content = Some(answer.trim_start().to_string()).filter(|s| !s.is_empty());
+            }
+            None => delta.reasoning_content = Some(content),
+        }
+    }
+}
@@ -2,13 +2,31 @@
 //!
 //! This module contains client implementations for different AI model providers:
 //! - `anthropic`: Client for Anthropic's Claude models
+//! - `capture`: Status and selected headers of the latest upstream response
 //! - `deepseek`: Client for DeepSeek's reasoning models
+//! - `hosts`: Profiles for third-party hosts serving DeepSeek R1
+//! - `openai`: Responses API client for OpenAI o-series reasoning models
+//! - `openrouter`: OpenRouter model catalog and per-model pricing
+//! - `parse`: Total parsers of upstream bodies and event streams
+//! - `providers`: Live upstream URLs, models and keys shared by both clients
+//! - `quota`: Remaining upstream quota from the rate-limit headers
+//! - `repair`: Repair of damaged stream lines from flaky gateways
+//! - `stats`: Time to first token and throughput of the streams per provider
 //!
 //! Each client handles authentication, request building, and response parsing
 //! specific to its provider's API.
 
 pub mod anthropic;
+pub mod capture;
 pub mod deepseek;
+pub mod hosts;
+pub mod openai;
+pub mod openrouter;
+pub mod parse;
+pub mod providers;
+pub mod quota;
+pub mod repair;
+pub mod stats;
 
 pub use anthropic::AnthropicClient;
 pub use deepseek::DeepSeekClient;
@@ -17,41 +35,29 @@ use crate::error::Result;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use std::collections::HashMap;
 
-/// Converts a HashMap of string headers to a reqwest HeaderMap.
+/// Adds a request's stage headers to the headers a client built.
 ///
-/// This function is used internally by clients to convert user-provided
-/// header maps into the format required by reqwest.
-///
-/// # Arguments
-///
-/// * `headers` - A HashMap containing header names and values as strings
-///
-/// # Returns
-///
-/// * `Result<HeaderMap>` - The converted HeaderMap on success, or an error if
-///   any header name or value is invalid
+/// A header the client already set, such as `Authorization`, `x-api-key`
+/// or `content-type`, is kept and the request's value is dropped with a
+/// warning, so that request headers cannot replace upstream credentials.
 ///
 /// # Errors
 ///
-/// Returns `ApiError::BadRequest` if:
-/// - A header name contains invalid characters
-/// - A header value contains invalid characters
-pub(crate) fn build_headers(headers: &HashMap<String, String>) -> Result<HeaderMap> {
-    let mut header_map = HeaderMap::new();
-    
-    for (key, value) in headers {
-        let header_name = HeaderName::from_bytes(key.as_bytes())
-            .map_err(|e| crate::error::ApiError::BadRequest { 
-                message: format!("Invalid header name: {}", e) 
-            })?;
-            
-        let header_value = HeaderValue::from_str(value)
-            .map_err(|e| crate::error::ApiError::BadRequest { 
-                message: format!("Invalid header value: {}", e) 
-            })?;
-            
-        header_map.insert(header_name, header_value);
+/// Returns `ApiError::BadRequest` if a header name or value contains
+/// invalid characters.
+pub(crate) fn merge_headers(headers: &mut HeaderMap, extra: &HashMap<String, String>) -> Result<()> {
+    for (key, value) in extra {
+        let name = HeaderName::from_bytes(key.as_bytes()).map_err(|e| crate::error::ApiError::BadRequest {
+            message: format!("Invalid header name: {}", e),
+        })?;
+        let value = HeaderValue::from_str(value).map_err(|e| crate::error::ApiError::BadRequest {
+            message: format!("Invalid header value: {}", e),
+        })?;
+        if headers.contains_key(&name) {
+            tracing::warn!("忽略请求头{}：不能覆盖网关设置的上游请求头", name);
+            continue;
+        }
+        headers.insert(name, value);
     }
-    
-    Ok(header_map)
+    Ok(())
 }
@@ -0,0 +1,299 @@
+//! OpenAI Responses API client for o-series reasoning models.
+//!
+//! Lets o3-mini / o4-mini and similar models serve as the reasoning stage.
+//! The prompt is sent with high reasoning effort and a reasoning summary is
+//! requested. Responses are converted into the DeepSeek response types, with
+//! the summary as `reasoning_content`, so the rest of the pipeline injects
+//! it into Claude exactly like an R1 trace.
+//!
+//! When the account does not receive reasoning summaries, the model's final
+//! answer is used as the reasoning trace instead.
+
+use super::{
+    deepseek::{
+        AssistantMessage, Choice, CompletionTokenDetails, DeepSeekResponse, DeepSeekUsage, StreamChoice,
+        StreamDelta, StreamResponse, TokenDetails,
+    },
+    hosts::HostProfile,
+    parse::{sse_data, LineBuffer},
+};
+use crate::{
+    error::{ApiError, Result},
+    models::request::{ApiConfig, Message, Role},
+    payloads,
+};
+use futures::{Stream, StreamExt};
+use reqwest::Client;
+use std::pin::Pin;
+
+const DEFAULT_REASONING_EFFORT: &str = "high";
+
+/// Client for the `/v1/responses` endpoint.
+#[derive(Debug)]
+pub struct OpenAIResponsesClient {
+    client: Client,
+    api_url: String,
+    api_token: String,
+    profile: &'static HostProfile,
+}
+
+impl OpenAIResponsesClient {
+    pub fn new(client: Client, api_url: String, api_token: String, profile: &'static HostProfile) -> Self {
+        Self {
+            client,
+            api_url,
+            api_token,
+            profile,
+        }
+    }
+
+    /// Builds a Responses API request from chat messages.
+    ///
+    /// System messages become `instructions`; `max_tokens` maps to
+    /// `max_output_tokens` and `reasoning_effort` to `reasoning.effort`.
+    /// Sampling parameters are dropped since reasoning models reject them.
+    fn build_request(&self, messages: Vec<Message>, stream: bool, model: &str, config: &ApiConfig) -> serde_json::Value {
+        let instructions = messages
+            .iter()
+            .filter(|m| m.role == Role::System)
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let input = messages
+            .iter()
+            .filter(|m| m.role != Role::System)
+            .map(|m| serde_json::json!({ "role": m.role, "content": m.content }))
+            .collect::<Vec<_>>();
+
+        let effort = config
+            .body
+            .get("reasoning_effort")
+            .and_then(|v| v.as_str())
+            .unwrap_or(DEFAULT_REASONING_EFFORT);
+
+        let mut request = serde_json::json!({
+            "model": model,
+            "input": input,
+            "stream": stream,
+            "store": false,
+            "reasoning": { "effort": effort, "summary": "auto" },
+        });
+        if !instructions.is_empty() {
+            request["instructions"] = serde_json::json!(instructions);
+        }
+        if let Some(max_tokens) = config.body.get("max_output_tokens").or_else(|| config.body.get("max_tokens")) {
+            request["max_output_tokens"] = max_tokens.clone();
+        }
+        request
+    }
+
+    async fn send(&self, request: &serde_json::Value) -> Result<reqwest::Response> {
+        payloads::record("openai", &self.api_url, request);
+        let response = self
+            .client
+            .post(&self.api_url)
+            .bearer_auth(&self.api_token)
+            .json(request)
+            .send()
+            .await
+            .map_err(|e| ApiError::DeepSeekError {
+                message: format!("Request failed: {}", e),
+                type_: "request_failed".to_string(),
+                param: None,
+                code: None,
+            })?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(self.profile.translate_error(status, &headers, &body));
+        }
+        Ok(response)
+    }
+
+    /// Sends a non-streaming request.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::DeepSeekError` if the request fails or the
+    /// response cannot be parsed.
+    pub async fn chat(&self, messages: Vec<Message>, model: &str, config: &ApiConfig) -> Result<DeepSeekResponse> {
+        let request = self.build_request(messages, false, model, config);
+        let body: serde_json::Value = self
+            .send(&request)
+            .await?
+            .json()
+            .await
+            .map_err(|e| ApiError::DeepSeekError {
+                message: format!("Failed to parse response: {}", e),
+                type_: "parse_error".to_string(),
+                param: None,
+                code: None,
+            })?;
+
+        let mut summary = String::new();
+        let mut answer = String::new();
+        for item in body["output"].as_array().into_iter().flatten() {
+            match item["type"].as_str() {
+                Some("reasoning") => {
+                    for part in item["summary"].as_array().into_iter().flatten() {
+                        if let Some(text) = part["text"].as_str() {
+                            summary.push_str(text);
+                        }
+                    }
+                }
+                Some("message") => {
+                    for part in item["content"].as_array().into_iter().flatten() {
+                        if let Some(text) = part["text"].as_str() {
+                            answer.push_str(text);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // 没有推理摘要时，使用最终回答作为推理草稿
+        let (reasoning, content) = if summary.is_empty() {
+            (answer, None)
+        } else {
+            (summary, Some(answer))
+        };
+
+        Ok(DeepSeekResponse {
+            id: body["id"].as_str().unwrap_or_default().to_string(),
+            object: "chat.completion".to_string(),
+            created: body["created_at"].as_i64().unwrap_or_default(),
+            model: body["model"].as_str().unwrap_or(model).to_string(),
+            choices: vec![Choice {
+                index: 0,
+                message: AssistantMessage {
+                    role: "assistant".to_string(),
+                    content,
+                    reasoning_content: Some(reasoning),
+                },
+                logprobs: None,
+                finish_reason: Some("stop".to_string()),
+            }],
+            usage: parse_usage(&body["usage"]),
+            system_fingerprint: None,
+        })
+    }
+
+    /// Sends a streaming request, yielding DeepSeek-style chunks.
+    pub fn chat_stream(
+        &self,
+        messages: Vec<Message>,
+        model: &str,
+        config: &ApiConfig,
+    ) -> Pin<Box<dyn Stream<Item = Result<StreamResponse>> + Send>> {
+        let request = self.build_request(messages, true, model, config);
+        let client = Self::new(self.client.clone(), self.api_url.clone(), self.api_token.clone(), self.profile);
+        let model = model.to_string();
+
+        Box::pin(async_stream::stream! {
+            let response = match client.send(&request).await {
+                Ok(response) => response,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+
+            let mut stream = response.bytes_stream();
+            let mut lines = LineBuffer::default();
+            let mut saw_summary = false;
+
+            let mut eof = false;
+            while !eof {
+                let batch = match stream.next().await {
+                    Some(Ok(chunk)) => lines.push(&chunk),
+                    Some(Err(e)) => {
+                        yield Err(ApiError::DeepSeekError {
+                            message: format!("流处理错误: {}", e),
+                            type_: "stream_error".to_string(),
+                            param: None,
+                            code: None,
+                        });
+                        return;
+                    }
+                    None => {
+                        eof = true;
+                        lines.finish()
+                    }
+                };
+                for line in batch {
+                    let Some(payload) = sse_data(&line) else {
+                        continue;
+                    };
+                    let Ok(value) = serde_json::from_str::<serde_json::Value>(payload) else {
+                        continue;
+                    };
+
+                    let delta = value["delta"].as_str().unwrap_or_default().to_string();
+                    let (delta, usage) = match value["type"].as_str() {
+                        Some("response.reasoning_summary_text.delta") => {
+                            saw_summary = true;
+                            (StreamDelta { role: None, content: None, reasoning_content: Some(delta) }, None)
+                        }
+                        // 没有推理摘要时，把回答当作推理草稿流式输出
+                        Some("response.output_text.delta") if !saw_summary => {
+                            (StreamDelta { role: None, content: None, reasoning_content: Some(delta) }, None)
+                        }
+                        Some("response.output_text.delta") => {
+                            (StreamDelta { role: None, content: Some(delta), reasoning_content: None }, None)
+                        }
+                        Some("response.completed") => {
+                            let usage = parse_usage(&value["response"]["usage"]);
+                            (StreamDelta { role: None, content: None, reasoning_content: None }, Some(usage))
+                        }
+                        Some("response.failed") | Some("error") => {
+                            let error = if value["error"].is_object() { &value["error"] } else { &value["response"]["error"] };
+                            yield Err(ApiError::DeepSeekError {
+                                message: error["message"].as_str().unwrap_or("未知错误").to_string(),
+                                type_: "api_error".to_string(),
+                                param: None,
+                                code: error["code"].as_str().map(String::from),
+                            });
+                            return;
+                        }
+                        _ => continue,
+                    };
+
+                    let choices = if usage.is_some() {
+                        Vec::new()
+                    } else {
+                        vec![StreamChoice { index: 0, delta, logprobs: None, finish_reason: None }]
+                    };
+                    yield Ok(StreamResponse {
+                        id: String::new(),
+                        object: "chat.completion.chunk".to_string(),
+                        created: chrono::Utc::now().timestamp(),
+                        model: model.clone(),
+                        choices,
+                        usage,
+                        service_tier: String::new(),
+                        system_fingerprint: String::new(),
+                    });
+                }
+            }
+        })
+    }
+}
+
+/// Converts Responses API usage into the DeepSeek usage shape.
+fn parse_usage(usage: &serde_json::Value) -> DeepSeekUsage {
+    let count = |value: &serde_json::Value| value.as_u64().unwrap_or_default() as u32;
+    DeepSeekUsage {
+        input_tokens: count(&usage["input_tokens"]),
+        output_tokens: count(&usage["output_tokens"]),
+        total_tokens: count(&usage["total_tokens"]),
+        input_details: TokenDetails {
+            cached: count(&usage["input_tokens_details"]["cached_tokens"]),
+        },
+        output_details: CompletionTokenDetails {
+            reasoning: count(&usage["output_tokens_details"]["reasoning_tokens"]),
+        },
+    }
+}
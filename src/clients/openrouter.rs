@@ -0,0 +1,141 @@
+//! OpenRouter model catalog.
+//!
+//! OpenRouter publishes its models with per-token prices at `/models`. The
+//! list is fetched in the background and kept as an immutable snapshot that
+//! feeds the `/v1/models` catalog and the cost of OpenRouter-routed stages.
+//! Until the first fetch succeeds the catalog is empty and costs fall back
+//! to the `[pricing]` table.
+
+use super::hosts;
+use crate::{config::{Config, OpenRouterConfig}, secrets};
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+static CATALOG: Lazy<ArcSwap<Vec<CatalogModel>>> = Lazy::new(|| ArcSwap::from_pointee(Vec::new()));
+
+/// One model offered by OpenRouter.
+#[derive(Debug, Clone, Serialize)]
+pub struct CatalogModel {
+    pub id: String,
+    pub name: String,
+    pub context_length: Option<u64>,
+    pub pricing: Option<ModelPrice>,
+}
+
+/// Prices in dollars per million tokens.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl ModelPrice {
+    /// Cost in dollars of one call.
+    pub fn cost(&self, input_tokens: u32, output_tokens: u32) -> f64 {
+        (input_tokens as f64 / 1_000_000.0) * self.input + (output_tokens as f64 / 1_000_000.0) * self.output
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelsResponse {
+    data: Vec<RawModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawModel {
+    id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    context_length: Option<u64>,
+    #[serde(default)]
+    pricing: Option<RawPricing>,
+}
+
+/// OpenRouter quotes prices as decimal strings in dollars per token.
+#[derive(Debug, Deserialize)]
+struct RawPricing {
+    prompt: String,
+    completion: String,
+}
+
+impl From<RawModel> for CatalogModel {
+    fn from(raw: RawModel) -> Self {
+        let pricing = raw.pricing.and_then(|p| {
+            Some(ModelPrice {
+                input: p.prompt.parse::<f64>().ok()? * 1_000_000.0,
+                output: p.completion.parse::<f64>().ok()? * 1_000_000.0,
+            })
+        });
+        Self {
+            name: if raw.name.is_empty() { raw.id.clone() } else { raw.name },
+            id: raw.id,
+            context_length: raw.context_length,
+            pricing,
+        }
+    }
+}
+
+/// Returns the most recently fetched catalog.
+pub fn models() -> Arc<Vec<CatalogModel>> {
+    CATALOG.load_full()
+}
+
+/// Looks up the price of an OpenRouter model id.
+pub fn price(model: &str) -> Option<ModelPrice> {
+    models().iter().find(|m| m.id == model).and_then(|m| m.pricing)
+}
+
+/// Fetches the catalog and swaps it in, returning the number of models.
+pub async fn refresh(client: &Client, config: &OpenRouterConfig) -> anyhow::Result<usize> {
+    let response: ModelsResponse = client
+        .get(&config.models_url)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let models: Vec<CatalogModel> = response.data.into_iter().map(CatalogModel::from).collect();
+    let count = models.len();
+    CATALOG.store(Arc::new(models));
+    Ok(count)
+}
+
+/// Returns true if an OpenRouter key is configured anywhere.
+fn configured(config: &Config) -> bool {
+    let profile_key = hosts::profile("openrouter")
+        .and_then(|profile| std::env::var(profile.key_env).ok())
+        .and_then(|key| secrets::expose(&key))
+        .is_some_and(|key| !key.is_empty());
+    profile_key
+        || config
+            .model_aliases
+            .values()
+            .any(|alias| alias.host == "openrouter" && alias.api_key.is_some())
+}
+
+/// Starts fetching the catalog in the background if OpenRouter is in use.
+pub fn spawn_refresh(config: &Config) {
+    if !configured(config) {
+        return;
+    }
+    let config = config.openrouter.clone();
+    tokio::spawn(async move {
+        let client = Client::new();
+        loop {
+            match refresh(&client, &config).await {
+                Ok(count) => tracing::info!("已获取OpenRouter模型目录，共{}个模型", count),
+                Err(e) => tracing::warn!("获取OpenRouter模型目录失败: {}", e),
+            }
+            if config.refresh_secs == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(config.refresh_secs)).await;
+        }
+    });
+}
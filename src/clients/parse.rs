@@ -0,0 +1,350 @@
+//! Parsers of upstream response bodies and event streams.
+//!
+//! Everything here takes arbitrary input from the network: the parsers
+//! never panic, never slice at fixed offsets, and report malformed input
+//! as a [`ParseError`] for the clients to turn into an `ApiError`. They are
+//! exercised by the `cargo fuzz` targets under `fuzz/`.
+
+use super::{
+    anthropic::{get_claude_default_model, AnthropicResponse, ContentBlock, ContentDelta, MessageDelta, StreamEvent, Usage},
+    hosts::UsageFields,
+};
+use std::borrow::Cow;
+use thiserror::Error;
+
+/// Error of parsing an upstream body or stream line.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    /// The JSON ended early, such as a line cut off by the upstream.
+    #[error("incomplete JSON: {0}")]
+    Incomplete(serde_json::Error),
+    /// The input is not JSON, or not of the expected shape.
+    #[error("invalid JSON: {0}")]
+    Json(serde_json::Error),
+}
+
+impl From<serde_json::Error> for ParseError {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_eof() {
+            ParseError::Incomplete(e)
+        } else {
+            ParseError::Json(e)
+        }
+    }
+}
+
+/// Splits a byte stream into lines.
+///
+/// Network chunks can end in the middle of a line or of a UTF-8 sequence,
+/// so bytes are held back until their line is complete and decoded only
+/// then. Lines are returned without the line ending.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Adds a chunk and returns the lines it completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+        let Some(last) = self.pending.iter().rposition(|&byte| byte == b'\n') else {
+            return Vec::new();
+        };
+        let rest = self.pending.split_off(last + 1);
+        let complete = std::mem::replace(&mut self.pending, rest);
+        complete[..last].split(|&byte| byte == b'\n').map(decode).collect()
+    }
+
+    /// Returns the last line if the stream did not end with a line ending.
+    pub fn finish(&mut self) -> Vec<String> {
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+        vec![decode(&std::mem::take(&mut self.pending))]
+    }
+}
+
+fn decode(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
+
+/// A line of an SSE stream.
+#[derive(Debug, PartialEq)]
+pub enum SseLine<'a> {
+    /// The payload of a `data:` line.
+    Data(&'a str),
+    /// A `:` comment, which upstreams send as keep-alives while generating.
+    Comment(&'a str),
+    /// The blank line ending an event.
+    Blank,
+    /// Any other field, such as `event:` or `id:`.
+    Field,
+}
+
+/// Classifies a line of an SSE stream.
+pub fn sse_line(line: &str) -> SseLine<'_> {
+    let line = line.trim_end();
+    if line.is_empty() {
+        return SseLine::Blank;
+    }
+    if let Some(comment) = line.strip_prefix(':') {
+        return SseLine::Comment(comment.trim_start());
+    }
+    match line.strip_prefix("data:") {
+        Some(data) => SseLine::Data(data.strip_prefix(' ').unwrap_or(data)),
+        None => SseLine::Field,
+    }
+}
+
+/// The payload of an SSE `data:` line, or `None` for any other line.
+pub fn sse_data(line: &str) -> Option<&str> {
+    match sse_line(line) {
+        SseLine::Data(data) => Some(data),
+        _ => None,
+    }
+}
+
+/// Damage [`repair_line`] fixes in an SSE line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Repair {
+    /// A byte order mark before the line or its payload.
+    Bom,
+    /// The `data:` prefix repeated, as in `data: data: {...}`.
+    DuplicateDataPrefix,
+    /// A comma before the `}` or `]` closing a JSON object or array.
+    TrailingComma,
+}
+
+impl Repair {
+    /// Name of the repair in logs and metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Repair::Bom => "bom",
+            Repair::DuplicateDataPrefix => "duplicate_data_prefix",
+            Repair::TrailingComma => "trailing_comma",
+        }
+    }
+}
+
+/// Fixes the damage some third-party gateways do to SSE lines: byte order
+/// marks, a repeated `data:` prefix and trailing commas in the JSON payload.
+///
+/// Returns the line borrowed and no repairs when nothing needed fixing.
+pub fn repair_line(line: &str) -> (Cow<'_, str>, Vec<Repair>) {
+    let mut repairs = Vec::new();
+    let unmarked = line.trim_start_matches('\u{feff}');
+    if unmarked.len() != line.len() {
+        repairs.push(Repair::Bom);
+    }
+    let Some(data) = unmarked.strip_prefix("data:") else {
+        return match repairs.is_empty() {
+            true => (Cow::Borrowed(line), repairs),
+            false => (Cow::Owned(unmarked.to_string()), repairs),
+        };
+    };
+
+    let mut data = data.trim_start();
+    let unmarked = data.trim_start_matches('\u{feff}');
+    if unmarked.len() != data.len() && !repairs.contains(&Repair::Bom) {
+        repairs.push(Repair::Bom);
+    }
+    data = unmarked;
+    let mut duplicated = false;
+    while let Some(inner) = data.strip_prefix("data:") {
+        data = inner.trim_start();
+        duplicated = true;
+    }
+    if duplicated {
+        repairs.push(Repair::DuplicateDataPrefix);
+    }
+    let without_commas = strip_trailing_commas(data);
+    if without_commas.is_some() {
+        repairs.push(Repair::TrailingComma);
+    }
+
+    if repairs.is_empty() {
+        return (Cow::Borrowed(line), repairs);
+    }
+    let data = without_commas.as_deref().unwrap_or(data);
+    (Cow::Owned(format!("data: {}", data)), repairs)
+}
+
+/// Removes commas directly before a closing `}` or `]`, outside strings.
+///
+/// Returns `None` if there were none; such a comma is never valid JSON, so
+/// removing it cannot change a valid payload.
+fn strip_trailing_commas(json: &str) -> Option<String> {
+    let mut out = String::with_capacity(json.len());
+    let mut removed = false;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in json.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' {
+            let next = json[i + 1..].trim_start().chars().next();
+            if matches!(next, Some('}' | ']')) {
+                removed = true;
+                continue;
+            }
+        }
+        out.push(c);
+    }
+    removed.then_some(out)
+}
+
+/// A line of a Claude stream, in either the Anthropic or the OpenAI format.
+#[derive(Debug)]
+pub enum ClaudeLine {
+    /// A line without events: a blank line, an `event:` line, a comment or
+    /// an OpenAI chunk without content.
+    Skip,
+    /// The OpenAI format's `[DONE]` marker.
+    Done,
+    Events(Vec<StreamEvent>),
+}
+
+/// Parses a line of a Claude stream.
+///
+/// OpenAI-format chunks are translated to Anthropic events: a content delta
+/// to `content_block_delta`, usage found where `fields` says to `message_delta` and
+/// a finish reason to `message_stop`.
+///
+/// # Errors
+///
+/// Returns a [`ParseError`] if a `data:` line is not a known event.
+pub fn claude_stream_line(line: &str, fields: &UsageFields) -> Result<ClaudeLine, ParseError> {
+    let Some(data) = sse_data(line) else {
+        return Ok(ClaudeLine::Skip);
+    };
+    let data = data.trim();
+    if data.is_empty() {
+        return Ok(ClaudeLine::Skip);
+    }
+    if data == "[DONE]" {
+        return Ok(ClaudeLine::Done);
+    }
+
+    let value: serde_json::Value = serde_json::from_str(data)?;
+    let Some(choices) = value.get("choices").and_then(|choices| choices.as_array()) else {
+        return Ok(ClaudeLine::Events(vec![serde_json::from_value(value)?]));
+    };
+    let choice = choices.first();
+    let mut events = Vec::new();
+    let content = choice.and_then(|choice| choice.pointer("/delta/content")).and_then(|content| content.as_str());
+    if let Some(text) = content.filter(|text| !text.is_empty()) {
+        events.push(StreamEvent::ContentBlockDelta {
+            index: 0,
+            delta: ContentDelta {
+                delta_type: "text".to_string(),
+                text: text.to_string(),
+            },
+        });
+    }
+    // 完成块的delta通常为空对象，完成原因与内容分开判断
+    let finish_reason = choice.and_then(|choice| choice.get("finish_reason")).filter(|reason| !reason.is_null());
+    let usage = value.pointer(fields.stream).filter(|usage| usage.is_object());
+    if usage.is_some() || finish_reason.is_some() {
+        events.push(StreamEvent::MessageDelta {
+            delta: MessageDelta {
+                stop_reason: finish_reason.and_then(|reason| reason.as_str()).map(str::to_string),
+                stop_sequence: None,
+            },
+            usage: usage.map(|usage| Usage::from_value(usage, fields)),
+        });
+    }
+    if finish_reason.is_some() {
+        events.push(StreamEvent::MessageStop);
+    }
+    Ok(ClaudeLine::Events(events))
+}
+
+/// Extracts the answer from a Claude response that does not deserialize
+/// as an [`AnthropicResponse`].
+///
+/// Looks for an Anthropic `content` array or string, then for an OpenAI
+/// `choices[0].message.content`, and falls back to the whole body.
+///
+/// # Errors
+///
+/// Returns a [`ParseError`] if the body is not JSON.
+pub fn extract_content_from_response(raw_response: &str) -> Result<Vec<ContentBlock>, ParseError> {
+    let json_value: serde_json::Value = serde_json::from_str(raw_response)?;
+
+    let content_text = if let Some(content) = json_value.get("content") {
+        if let Some(items) = content.as_array() {
+            // 支持数组格式内容
+            items.iter().filter_map(|item| item.get("text").and_then(|t| t.as_str())).collect()
+        } else if let Some(text) = content.as_str() {
+            text.to_string()
+        } else {
+            json_value.to_string()
+        }
+    } else if let Some(content) = json_value.pointer("/choices/0/message/content").and_then(|c| c.as_str()) {
+        // OpenAI格式响应
+        content.to_string()
+    } else {
+        // 找不到任何识别的内容格式
+        json_value.to_string()
+    };
+
+    Ok(vec![ContentBlock {
+        content_type: "text".to_string(),
+        text: content_text,
+        extra: Default::default(),
+    }])
+}
+
+/// Parses an OpenAI-format chat completion into an [`AnthropicResponse`],
+/// reading its `usage` object with `fields`.
+///
+/// Missing fields fall back to defaults, so any JSON body parses.
+///
+/// # Errors
+///
+/// Returns a [`ParseError`] if the body is not JSON.
+pub fn parse_deepseek_response(raw_response: &str, fields: &UsageFields) -> Result<AnthropicResponse, ParseError> {
+    let json_value: serde_json::Value = serde_json::from_str(raw_response)?;
+    let text = |pointer: &str| json_value.pointer(pointer).and_then(|v| v.as_str()).map(str::to_string);
+
+    Ok(AnthropicResponse {
+        id: text("/id").unwrap_or_else(|| "deepseek_generated_id".to_string()),
+        response_type: "message".to_string(),
+        role: "assistant".to_string(),
+        model: text("/model").unwrap_or_else(get_claude_default_model),
+        content: vec![ContentBlock {
+            content_type: "text".to_string(),
+            text: text("/choices/0/message/content").unwrap_or_default(),
+            extra: Default::default(),
+        }],
+        stop_reason: text("/choices/0/finish_reason"),
+        stop_sequence: None,
+        usage: json_value.get("usage").map(|usage| Usage::from_value(usage, fields)).unwrap_or_default(),
+    })
+}
+
+/// The `id` of a JSON body.
+pub fn extract_id_from_response(raw_response: &str) -> Option<String> {
+    let json_value: serde_json::Value = serde_json::from_str(raw_response).ok()?;
+    json_value.get("id")?.as_str().map(str::to_string)
+}
+
+/// The `model` of a JSON body.
+pub fn extract_model_from_response(raw_response: &str) -> Option<String> {
+    let json_value: serde_json::Value = serde_json::from_str(raw_response).ok()?;
+    json_value.get("model")?.as_str().map(str::to_string)
+}
+
+/// The `usage` of a JSON body, in the Anthropic or the OpenAI format.
+pub fn extract_usage_from_response(raw_response: &str, fields: &UsageFields) -> Option<Usage> {
+    let json_value: serde_json::Value = serde_json::from_str(raw_response).ok()?;
+    Some(Usage::from_value(json_value.get("usage")?, fields))
+}
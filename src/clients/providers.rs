@@ -0,0 +1,303 @@
+//! Live upstream provider settings.
+//!
+//! Provider URLs, default models and keys are resolved from the `.env` file
+//! (falling back to the process environment and built-in defaults) into an
+//! immutable [`ProviderSettings`] snapshot. Clients read the current snapshot
+//! for every request, and [`reload`] atomically swaps in a freshly resolved
+//! one, so in-flight requests keep the settings they started with.
+//!
+//! A request can select a named `[providers.<name>]` profile, which is
+//! overlaid on the `.env` settings for that request only: [`with_profile`]
+//! makes [`current`] return the overlay within the request's task, and
+//! [`inherit`] carries it into the tasks the request spawns.
+
+use crate::{config::ProviderProfile, paths, secrets};
+use arc_swap::ArcSwap;
+use futures::future::Either;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+const DEFAULT_DEEPSEEK_API_URL: &str = "https://ark.cn-beijing.volces.com/api/v3/chat/completions";
+const DEFAULT_DEEPSEEK_MODEL: &str = "deepseek-r1-250120";
+const DEFAULT_ANTHROPIC_API_URL: &str = "https://api.gptsapi.net/v1/messages";
+const DEFAULT_CLAUDE_OPENAI_TYPE_API_URL: &str = "https://api.claude-Plus.top/v1/chat/completions";
+const DEFAULT_CLAUDE_MODEL: &str = "wild-3-7-sonnet-20250219";
+
+static CURRENT: Lazy<ArcSwap<ProviderSettings>> = Lazy::new(|| ArcSwap::from_pointee(ProviderSettings::resolve()));
+
+tokio::task_local! {
+    /// Settings of the profile selected by the request being served.
+    static SELECTED: Arc<ProviderSettings>;
+}
+
+/// Snapshot of everything needed to reach the upstream providers.
+///
+/// Keys are kept in their configured form and may be secret references;
+/// use [`secrets::expose`] before sending them upstream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProviderSettings {
+    pub deepseek_api_url: String,
+    pub deepseek_model: String,
+    pub deepseek_api_key: Option<String>,
+    pub anthropic_api_url: String,
+    pub claude_openai_type_api_url: String,
+    pub claude_model: String,
+    pub anthropic_api_key: Option<String>,
+    /// The `[providers]` profile overlaid on the `.env` settings, if any.
+    pub profile: Option<String>,
+    /// Raw URL settings, used to decide between the OpenAI and Anthropic formats.
+    raw_anthropic_api_url: Option<String>,
+    raw_claude_openai_type_api_url: Option<String>,
+}
+
+/// Result of a reload attempt.
+#[derive(Debug, Serialize)]
+pub struct ReloadOutcome {
+    pub applied: bool,
+    pub changes: HashMap<&'static str, (String, String)>,
+    pub warnings: Vec<String>,
+}
+
+/// Returns the provider settings currently in effect: those of the
+/// request's profile, or else the `.env` settings.
+pub fn current() -> Arc<ProviderSettings> {
+    SELECTED.try_with(Arc::clone).unwrap_or_else(|_| CURRENT.load_full())
+}
+
+/// Runs `f` with the named profile overlaid on the `.env` settings.
+pub async fn with_profile<F: Future>(name: &str, profile: &ProviderProfile, f: F) -> F::Output {
+    let settings = CURRENT.load().overlay(name, profile);
+    SELECTED.scope(Arc::new(settings), f).await
+}
+
+/// Carries the request's profile, if it selected one, into a future that
+/// is about to be spawned.
+pub fn inherit<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    match SELECTED.try_with(Arc::clone) {
+        Ok(settings) => Either::Left(SELECTED.scope(settings, f)),
+        Err(_) => Either::Right(f),
+    }
+}
+
+/// Checks that every profile, overlaid on the `.env` settings, can be used
+/// to reach the providers.
+///
+/// # Errors
+///
+/// Returns an error naming the profile and its first problem.
+pub fn validate_profiles(profiles: &HashMap<String, ProviderProfile>) -> anyhow::Result<()> {
+    let base = CURRENT.load();
+    let mut aliases = HashMap::new();
+    for (name, profile) in profiles {
+        base.overlay(name, profile)
+            .validate()
+            .map_err(|errors| anyhow::anyhow!("上游账户配置'{}'无效: {}", name, errors.join("; ")))?;
+        for alias in &profile.aliases {
+            if let Some(other) = aliases.insert(alias.as_str(), name.as_str()) {
+                anyhow::bail!("模型名'{}'同时属于上游账户配置'{}'和'{}'", alias, other, name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Re-resolves the provider settings and swaps them in.
+///
+/// Secret references among the new keys are resolved before the swap.
+///
+/// # Arguments
+///
+/// * `dry_run` - Validate and report the changes without applying them
+///
+/// # Errors
+///
+/// Returns the list of validation errors if the new settings are invalid;
+/// the current settings are left untouched in that case.
+pub async fn reload(dry_run: bool, secrets_config: &crate::config::SecretsConfig) -> Result<ReloadOutcome, Vec<String>> {
+    let next = ProviderSettings::resolve();
+    next.validate()?;
+
+    let references: Vec<String> = [&next.deepseek_api_key, &next.anthropic_api_key]
+        .into_iter()
+        .flatten()
+        .filter(|value| secrets::is_reference(value))
+        .cloned()
+        .collect();
+    if !dry_run && !references.is_empty() {
+        secrets::resolve_all(&references, secrets_config)
+            .await
+            .map_err(|e| vec![e.to_string()])?;
+    }
+
+    let previous = current();
+    let outcome = ReloadOutcome {
+        applied: !dry_run,
+        changes: previous.diff(&next),
+        warnings: next.warnings(),
+    };
+
+    if !dry_run {
+        CURRENT.store(Arc::new(next));
+        tracing::info!("上游服务配置已重新加载，变更项: {}", outcome.changes.len());
+    }
+
+    Ok(outcome)
+}
+
+impl ProviderSettings {
+    /// Resolves settings from `.env`, then the process environment, then defaults.
+    pub fn resolve() -> Self {
+        let dotenv = read_dotenv();
+        let lookup = |key: &str| dotenv.get(key).cloned().or_else(|| std::env::var(key).ok());
+
+        Self {
+            deepseek_api_url: lookup("DEEPSEEK_OPENAI_TYPE_API_URL").unwrap_or_else(|| DEFAULT_DEEPSEEK_API_URL.to_string()),
+            deepseek_model: lookup("DEEPSEEK_DEFAULT_MODEL").unwrap_or_else(|| DEFAULT_DEEPSEEK_MODEL.to_string()),
+            deepseek_api_key: lookup("DEEPSEEK_API_KEY"),
+            anthropic_api_url: lookup("ANTHROPIC_API_URL").unwrap_or_else(|| DEFAULT_ANTHROPIC_API_URL.to_string()),
+            claude_openai_type_api_url: lookup("CLAUDE_OPENAI_TYPE_API_URL")
+                .unwrap_or_else(|| DEFAULT_CLAUDE_OPENAI_TYPE_API_URL.to_string()),
+            claude_model: lookup("CLAUDE_DEFAULT_MODEL").unwrap_or_else(|| DEFAULT_CLAUDE_MODEL.to_string()),
+            anthropic_api_key: lookup("ANTHROPIC_API_KEY"),
+            profile: None,
+            raw_anthropic_api_url: lookup("ANTHROPIC_API_URL"),
+            raw_claude_openai_type_api_url: lookup("CLAUDE_OPENAI_TYPE_API_URL"),
+        }
+    }
+
+    /// These settings with a profile's fields in place of their own.
+    ///
+    /// A profile that sets either Claude URL also decides the Claude API
+    /// format by its own URLs.
+    fn overlay(&self, name: &str, profile: &ProviderProfile) -> Self {
+        let pick = |value: &Option<String>, base: &String| value.clone().unwrap_or_else(|| base.clone());
+        let (raw_anthropic_api_url, raw_claude_openai_type_api_url) =
+            if profile.anthropic_api_url.is_some() || profile.claude_openai_type_api_url.is_some() {
+                (profile.anthropic_api_url.clone(), profile.claude_openai_type_api_url.clone())
+            } else {
+                (self.raw_anthropic_api_url.clone(), self.raw_claude_openai_type_api_url.clone())
+            };
+        Self {
+            deepseek_api_url: pick(&profile.deepseek_api_url, &self.deepseek_api_url),
+            deepseek_model: pick(&profile.deepseek_model, &self.deepseek_model),
+            deepseek_api_key: profile.deepseek_api_key.clone().or_else(|| self.deepseek_api_key.clone()),
+            anthropic_api_url: pick(&profile.anthropic_api_url, &self.anthropic_api_url),
+            claude_openai_type_api_url: pick(&profile.claude_openai_type_api_url, &self.claude_openai_type_api_url),
+            claude_model: pick(&profile.claude_model, &self.claude_model),
+            anthropic_api_key: profile.anthropic_api_key.clone().or_else(|| self.anthropic_api_key.clone()),
+            profile: Some(name.to_string()),
+            raw_anthropic_api_url,
+            raw_claude_openai_type_api_url,
+        }
+    }
+
+    /// Whether Claude is reached through an OpenAI-format gateway.
+    ///
+    /// The OpenAI format wins when its URL is set, and is also the default
+    /// when neither URL is configured.
+    pub fn use_openai_format(&self) -> bool {
+        let set = |value: &Option<String>| value.as_deref().is_some_and(|v| !v.trim().is_empty());
+        set(&self.raw_claude_openai_type_api_url) || !set(&self.raw_anthropic_api_url)
+    }
+
+    /// Checks that the settings can be used to reach the providers.
+    fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        let mut urls = vec![("DEEPSEEK_OPENAI_TYPE_API_URL", &self.deepseek_api_url)];
+        if self.use_openai_format() {
+            urls.push(("CLAUDE_OPENAI_TYPE_API_URL", &self.claude_openai_type_api_url));
+        } else {
+            urls.push(("ANTHROPIC_API_URL", &self.anthropic_api_url));
+        }
+        for (name, url) in urls {
+            match reqwest::Url::parse(url.trim()) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                _ => errors.push(format!("{}不是有效的http(s)地址: {}", name, url)),
+            }
+        }
+
+        for (name, model) in [("DEEPSEEK_DEFAULT_MODEL", &self.deepseek_model), ("CLAUDE_DEFAULT_MODEL", &self.claude_model)] {
+            if model.trim().is_empty() {
+                errors.push(format!("{}不能为空", name));
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Non-fatal problems worth reporting to the operator.
+    fn warnings(&self) -> Vec<String> {
+        [("DEEPSEEK_API_KEY", &self.deepseek_api_key), ("ANTHROPIC_API_KEY", &self.anthropic_api_key)]
+            .into_iter()
+            .filter(|(_, key)| key.as_deref().is_none_or(|k| k.trim().is_empty()))
+            .map(|(name, _)| format!("未配置{}，请求必须通过请求头提供密钥", name))
+            .collect()
+    }
+
+    /// Lists changed fields as (old, new) pairs, with keys masked.
+    fn diff(&self, next: &Self) -> HashMap<&'static str, (String, String)> {
+        let mut changes = HashMap::new();
+
+        let values = [
+            ("DEEPSEEK_OPENAI_TYPE_API_URL", &self.deepseek_api_url, &next.deepseek_api_url),
+            ("DEEPSEEK_DEFAULT_MODEL", &self.deepseek_model, &next.deepseek_model),
+            ("ANTHROPIC_API_URL", &self.anthropic_api_url, &next.anthropic_api_url),
+            ("CLAUDE_OPENAI_TYPE_API_URL", &self.claude_openai_type_api_url, &next.claude_openai_type_api_url),
+            ("CLAUDE_DEFAULT_MODEL", &self.claude_model, &next.claude_model),
+        ];
+        for (name, old, new) in values {
+            if old != new {
+                changes.insert(name, (old.clone(), new.clone()));
+            }
+        }
+
+        let keys = [
+            ("DEEPSEEK_API_KEY", &self.deepseek_api_key, &next.deepseek_api_key),
+            ("ANTHROPIC_API_KEY", &self.anthropic_api_key, &next.anthropic_api_key),
+        ];
+        for (name, old, new) in keys {
+            if old != new {
+                changes.insert(name, (mask(old), mask(new)));
+            }
+        }
+
+        changes
+    }
+}
+
+/// Masks a key for display, keeping only its last four characters.
+fn mask(key: &Option<String>) -> String {
+    match key.as_deref() {
+        None => "<unset>".to_string(),
+        Some(k) if secrets::is_reference(k) => k.to_string(),
+        Some(k) => {
+            let tail: String = k.chars().rev().take(4).collect::<Vec<_>>().into_iter().rev().collect();
+            format!("****{}", tail)
+        }
+    }
+}
+
+/// Reads `KEY=value` pairs from the `.env` file in the config directory.
+fn read_dotenv() -> HashMap<String, String> {
+    let env_path = paths::env_file();
+    let content = match std::fs::read_to_string(&env_path) {
+        Ok(content) => content,
+        Err(e) => {
+            tracing::error!("无法读取.env文件{:?}: {}", env_path, e);
+            tracing::error!("请在项目根目录创建.env文件并设置必要的配置项");
+            return HashMap::new();
+        }
+    };
+
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            (key.trim().to_string(), value.trim().trim_matches('"').trim_matches('\'').to_string())
+        })
+        .collect()
+}
@@ -0,0 +1,117 @@
+//! Remaining upstream quota reported in rate-limit headers.
+//!
+//! Both clients read the rate-limit headers of every upstream response:
+//! `x-ratelimit-{remaining,limit}-<resource>` as sent by OpenAI-compatible
+//! hosts, and `anthropic-ratelimit-<resource>-{remaining,limit}` as sent by
+//! Anthropic. The latest values per stage, provider and resource are
+//! exported as gauges at `GET /metrics`, and a client keeps those of its
+//! latest response for verbose responses.
+
+use once_cell::sync::Lazy;
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+/// Latest quota per stage and provider.
+static QUOTAS: Lazy<Mutex<BTreeMap<(String, String), Quota>>> = Lazy::new(Default::default);
+
+/// Remaining and total quota of one resource, such as requests or tokens.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Limit {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<f64>,
+}
+
+/// Quota per resource reported by one response.
+pub type Quota = BTreeMap<String, Limit>;
+
+/// Quota reported by a client's latest response.
+#[derive(Debug, Clone, Default)]
+pub struct LastQuota(Arc<Mutex<Option<Quota>>>);
+
+impl LastQuota {
+    pub fn get(&self) -> Option<Quota> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Parses the rate-limit headers of a response.
+pub(crate) fn parse(headers: &HeaderMap) -> Quota {
+    let mut quota = Quota::new();
+    for (name, value) in headers {
+        let Some(value) = value.to_str().ok().and_then(|value| value.trim().parse::<f64>().ok()) else {
+            continue;
+        };
+        let name = name.as_str();
+        // x-ratelimit-remaining-tokens / anthropic-ratelimit-tokens-remaining
+        let parsed = if let Some(rest) = name.strip_prefix("x-ratelimit-") {
+            rest.split_once('-')
+        } else if let Some(rest) = name.strip_prefix("anthropic-ratelimit-") {
+            rest.rsplit_once('-').map(|(resource, kind)| (kind, resource))
+        } else {
+            None
+        };
+        let Some((kind, resource)) = parsed else {
+            continue;
+        };
+        let limit = quota.entry(resource.to_string()).or_default();
+        match kind {
+            "remaining" => limit.remaining = Some(value),
+            "limit" => limit.limit = Some(value),
+            _ => {}
+        }
+    }
+    quota.retain(|_, limit| limit.remaining.is_some() || limit.limit.is_some());
+    quota
+}
+
+/// Records the quota reported by a response from `api_url`.
+pub(crate) fn observe(stage: &str, api_url: &str, headers: &HeaderMap, slot: &LastQuota) {
+    let quota = parse(headers);
+    if quota.is_empty() {
+        return;
+    }
+    let provider = reqwest::Url::parse(api_url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_else(|| api_url.to_string());
+    *slot.0.lock().unwrap() = Some(quota.clone());
+    QUOTAS.lock().unwrap().insert((stage.to_string(), provider), quota);
+}
+
+/// Renders the quota gauges in the Prometheus text format.
+pub fn render() -> String {
+    let quotas = QUOTAS.lock().unwrap().clone();
+    let mut out = String::new();
+    let mut metric = |name: &str, help: &str, value: fn(&Limit) -> Option<f64>| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        for ((stage, provider), quota) in &quotas {
+            for (resource, limit) in quota {
+                if let Some(value) = value(limit) {
+                    let _ = writeln!(
+                        out,
+                        "{}{{stage=\"{}\",provider=\"{}\",resource=\"{}\"}} {}",
+                        name, stage, provider, resource, value
+                    );
+                }
+            }
+        }
+    };
+    metric(
+        "deepclaude_upstream_quota_remaining",
+        "Remaining upstream quota per resource from the latest rate-limit headers.",
+        |limit| limit.remaining,
+    );
+    metric(
+        "deepclaude_upstream_quota_limit",
+        "Upstream quota per resource from the latest rate-limit headers.",
+        |limit| limit.limit,
+    );
+    out
+}
@@ -0,0 +1,79 @@
+//! Repair of damaged stream lines from flaky gateways.
+//!
+//! Some third-party gateways emit SSE lines with a byte order mark, a
+//! repeated `data:` prefix or trailing commas in the JSON, which would
+//! otherwise fail to parse and drop their tokens. With `[streams]
+//! repair_json` on (the default), both clients pass each stream line
+//! through [`parse::repair_line`] first. Every repair is counted per
+//! upstream host and kind, exported at `GET /metrics`, so a gateway that
+//! needs them shows up instead of silently losing text.
+
+use super::parse;
+use once_cell::sync::Lazy;
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Repairs per upstream host and kind.
+static REPAIRS: Lazy<Mutex<BTreeMap<(String, &'static str), u64>>> = Lazy::new(Default::default);
+
+/// Turns the repair of stream lines on or off.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Repairs the lines of one upstream stream.
+pub(crate) struct Repairer {
+    provider: String,
+}
+
+impl Repairer {
+    pub(crate) fn new(api_url: &str) -> Self {
+        let provider = reqwest::Url::parse(api_url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from))
+            .unwrap_or_else(|| api_url.to_string());
+        Self { provider }
+    }
+
+    /// Returns the line with its damage fixed, counting each repair.
+    pub(crate) fn line<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Cow::Borrowed(line);
+        }
+        let (repaired, repairs) = parse::repair_line(line);
+        if !repairs.is_empty() {
+            tracing::debug!("修复了{}的流数据行: {:?}", self.provider, repairs);
+            let mut counts = REPAIRS.lock().unwrap();
+            for repair in repairs {
+                *counts.entry((self.provider.clone(), repair.name())).or_default() += 1;
+            }
+        }
+        repaired
+    }
+}
+
+/// Renders the repair counters in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP deepclaude_stream_repairs_total Damaged upstream stream lines repaired, per provider and kind.\n# TYPE deepclaude_stream_repairs_total counter"
+    );
+    for ((provider, kind), count) in REPAIRS.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "deepclaude_stream_repairs_total{{provider=\"{}\",kind=\"{}\"}} {}",
+            provider, kind, count
+        );
+    }
+    out
+}
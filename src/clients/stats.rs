@@ -0,0 +1,314 @@
+//! Streaming performance of the upstream providers.
+//!
+//! Both clients meter their streams: time to first token from sending the
+//! request, and output tokens per second from the first token to the last.
+//! Output tokens are taken from the upstream usage when it reports them and
+//! estimated from the streamed text otherwise. Finished streams update
+//! smoothed per-provider gauges, keyed by stage and upstream host, which are
+//! exported at `GET /metrics`. A client also keeps the timing of its latest
+//! stream for request traces.
+//!
+//! Each stage call is also recorded per model, streaming and non-streaming
+//! apart: the latency until the first token when streaming and until the
+//! full response otherwise, or a failure. The rolling median latency and
+//! error rate over the latest calls drive the choice among pool members,
+//! and are reported with the times of the last success and failure at
+//! `GET /status` (see [`crate::status`]).
+
+use crate::ratelimit;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Weight of a new stream in the smoothed gauges.
+const SMOOTHING: f64 = 0.3;
+/// Number of recent calls kept per model.
+const CALL_WINDOW: usize = 50;
+
+static PROVIDERS: Lazy<Mutex<BTreeMap<(String, String), ProviderStats>>> = Lazy::new(Default::default);
+/// Recent calls to a model.
+#[derive(Debug, Default)]
+struct CallWindow {
+    /// Latencies of the latest calls, `None` for failures.
+    latencies: VecDeque<Option<Duration>>,
+    last_success: Option<DateTime<Utc>>,
+    last_failure: Option<DateTime<Utc>>,
+    consecutive_failures: usize,
+}
+
+/// Recent calls per model and streaming flag.
+static CALLS: Lazy<Mutex<BTreeMap<(String, bool), CallWindow>>> = Lazy::new(Default::default);
+
+/// Smoothed streaming performance of one provider.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProviderStats {
+    pub ttft_seconds: f64,
+    pub tokens_per_second: f64,
+    pub streams: u64,
+}
+
+impl ProviderStats {
+    fn update(&mut self, timing: &StreamTiming) {
+        let ttft = timing.ttft_ms as f64 / 1000.0;
+        if self.streams == 0 {
+            self.ttft_seconds = ttft;
+            self.tokens_per_second = timing.tokens_per_second;
+        } else {
+            self.ttft_seconds += SMOOTHING * (ttft - self.ttft_seconds);
+            self.tokens_per_second += SMOOTHING * (timing.tokens_per_second - self.tokens_per_second);
+        }
+        self.streams += 1;
+    }
+}
+
+/// Timing of one stream.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StreamTiming {
+    pub provider: String,
+    pub ttft_ms: u64,
+    pub output_tokens: u32,
+    pub tokens_per_second: f64,
+    /// Keep-alive comments the upstream sent.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub keep_alives: u32,
+}
+
+fn is_zero(count: &u32) -> bool {
+    *count == 0
+}
+
+#[derive(Debug)]
+struct Progress {
+    started: Instant,
+    first_token: Option<Instant>,
+    last_token: Instant,
+    estimated_tokens: u32,
+    reported_tokens: Option<u32>,
+    keep_alives: u32,
+}
+
+/// Progress of a client's latest stream, shared with the stream it started.
+#[derive(Debug, Clone, Default)]
+pub struct LastStream(Arc<Mutex<Option<(String, Progress)>>>);
+
+impl LastStream {
+    /// Returns the timing of the stream, `None` before its first token.
+    pub fn timing(&self) -> Option<StreamTiming> {
+        let guard = self.0.lock().unwrap();
+        let (provider, progress) = guard.as_ref()?;
+        let first_token = progress.first_token?;
+        let output_tokens = progress.reported_tokens.unwrap_or(progress.estimated_tokens);
+        let generating = progress.last_token.duration_since(first_token).as_secs_f64();
+        Some(StreamTiming {
+            provider: provider.clone(),
+            ttft_ms: first_token.duration_since(progress.started).as_millis() as u64,
+            output_tokens,
+            // 只有一个数据块时无法计算速率
+            tokens_per_second: if generating > 0.0 { output_tokens as f64 / generating } else { 0.0 },
+            keep_alives: progress.keep_alives,
+        })
+    }
+}
+
+/// Meters one stream; the gauges are updated when it is dropped.
+pub(crate) struct Meter {
+    stage: &'static str,
+    slot: LastStream,
+}
+
+impl Meter {
+    /// Starts metering a stream to `api_url`, just before the request is sent.
+    pub(crate) fn start(stage: &'static str, api_url: &str, slot: &LastStream) -> Self {
+        let provider = reqwest::Url::parse(api_url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from))
+            .unwrap_or_else(|| api_url.to_string());
+        let now = Instant::now();
+        *slot.0.lock().unwrap() = Some((
+            provider,
+            Progress {
+                started: now,
+                first_token: None,
+                last_token: now,
+                estimated_tokens: 0,
+                reported_tokens: None,
+                keep_alives: 0,
+            },
+        ));
+        Self {
+            stage,
+            slot: slot.clone(),
+        }
+    }
+
+    /// Records streamed output text.
+    pub(crate) fn text(&self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if let Some((_, progress)) = self.slot.0.lock().unwrap().as_mut() {
+            let now = Instant::now();
+            progress.first_token.get_or_insert(now);
+            progress.last_token = now;
+            progress.estimated_tokens += ratelimit::estimate_tokens(text);
+        }
+    }
+
+    /// Records a keep-alive comment.
+    pub(crate) fn keep_alive(&self) {
+        if let Some((_, progress)) = self.slot.0.lock().unwrap().as_mut() {
+            progress.keep_alives += 1;
+        }
+    }
+
+    /// Records the output token count reported by the upstream.
+    pub(crate) fn usage(&self, output_tokens: u32) {
+        if let Some((_, progress)) = self.slot.0.lock().unwrap().as_mut() {
+            progress.reported_tokens = Some(output_tokens);
+        }
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        let Some(timing) = self.slot.timing() else {
+            return;
+        };
+        tracing::debug!(
+            "{}流式统计 provider={} ttft={}ms tokens/s={:.1} keep_alives={}",
+            self.stage,
+            timing.provider,
+            timing.ttft_ms,
+            timing.tokens_per_second,
+            timing.keep_alives
+        );
+        PROVIDERS
+            .lock()
+            .unwrap()
+            .entry((self.stage.to_string(), timing.provider.clone()))
+            .or_default()
+            .update(&timing);
+    }
+}
+
+/// Rolling health of one model over its recent calls.
+#[derive(Debug, Clone, Default)]
+pub struct CallStats {
+    /// Median latency of the successful calls, `None` without any.
+    pub p50: Option<Duration>,
+    pub error_rate: f64,
+    pub calls: usize,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    /// Failures since the last successful call.
+    pub consecutive_failures: usize,
+}
+
+/// Records a stage call to a model; `latency` is `None` if the call failed.
+pub fn record_call(model: &str, streaming: bool, latency: Option<Duration>) {
+    let mut calls = CALLS.lock().unwrap();
+    let window = calls.entry((model.to_string(), streaming)).or_default();
+    if window.latencies.len() == CALL_WINDOW {
+        window.latencies.pop_front();
+    }
+    window.latencies.push_back(latency);
+    if latency.is_some() {
+        window.last_success = Some(Utc::now());
+        window.consecutive_failures = 0;
+    } else {
+        window.last_failure = Some(Utc::now());
+        window.consecutive_failures += 1;
+    }
+}
+
+/// Returns the rolling health of a model.
+pub fn call_stats(model: &str, streaming: bool) -> CallStats {
+    CALLS
+        .lock()
+        .unwrap()
+        .get(&(model.to_string(), streaming))
+        .map(summarize)
+        .unwrap_or_default()
+}
+
+fn summarize(window: &CallWindow) -> CallStats {
+    let calls = window.latencies.len();
+    let mut latencies: Vec<Duration> = window.latencies.iter().flatten().copied().collect();
+    latencies.sort();
+    CallStats {
+        p50: latencies.get(latencies.len().saturating_sub(1) / 2).copied(),
+        error_rate: if calls == 0 { 0.0 } else { (calls - latencies.len()) as f64 / calls as f64 },
+        calls,
+        last_success: window.last_success,
+        last_failure: window.last_failure,
+        consecutive_failures: window.consecutive_failures,
+    }
+}
+
+/// Returns the rolling health of every model called so far, keyed by model
+/// and streaming flag.
+pub fn all_call_stats() -> BTreeMap<(String, bool), CallStats> {
+    CALLS.lock().unwrap().iter().map(|(key, window)| (key.clone(), summarize(window))).collect()
+}
+
+/// Returns the smoothed stats of every provider, keyed by stage and host.
+pub fn snapshot() -> BTreeMap<(String, String), ProviderStats> {
+    PROVIDERS.lock().unwrap().clone()
+}
+
+/// Renders the gauges in the Prometheus text format.
+pub fn render() -> String {
+    let providers = snapshot();
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: fn(&ProviderStats) -> f64| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for ((stage, provider), stats) in &providers {
+            let _ = writeln!(out, "{}{{stage=\"{}\",provider=\"{}\"}} {}", name, stage, provider, value(stats));
+        }
+    };
+    metric(
+        "deepclaude_upstream_ttft_seconds",
+        "gauge",
+        "Smoothed time to first streamed token per provider.",
+        |stats| stats.ttft_seconds,
+    );
+    metric(
+        "deepclaude_upstream_tokens_per_second",
+        "gauge",
+        "Smoothed streamed output tokens per second per provider.",
+        |stats| stats.tokens_per_second,
+    );
+    metric(
+        "deepclaude_upstream_streams_total",
+        "counter",
+        "Streams measured per provider.",
+        |stats| stats.streams as f64,
+    );
+
+    let calls = all_call_stats();
+    let mut call_metric = |name: &str, help: &str, value: fn(&CallStats) -> Option<f64>| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        for ((model, streaming), stats) in &calls {
+            if let Some(value) = value(stats) {
+                let _ = writeln!(out, "{}{{model=\"{}\",streaming=\"{}\"}} {}", name, model, streaming, value);
+            }
+        }
+    };
+    call_metric(
+        "deepclaude_model_latency_p50_seconds",
+        "Median latency of the recent calls per model.",
+        |stats| stats.p50.map(|p50| p50.as_secs_f64()),
+    );
+    call_metric(
+        "deepclaude_model_error_rate",
+        "Share of the recent calls per model that failed.",
+        |stats| Some(stats.error_rate),
+    );
+    out
+}
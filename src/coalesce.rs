@@ -0,0 +1,264 @@
+//! Coalescing of duplicate chat requests.
+//!
+//! Clients that time out or lose their connection often retry several times
+//! in quick succession, and every retry would pay for its own DeepSeek and
+//! Claude calls. With `[coalesce] enabled`, a chat request identical to one
+//! that started less than `window_secs` ago (same body, and same headers
+//! apart from per-attempt ones such as `X-Request-Id`, so the same
+//! credentials) joins that run instead of starting its own. A run is driven
+//! by its own task and its response is recorded as it is produced, so every
+//! caller gets it replayed from the start, streams included, and a run
+//! carries on when its first caller goes away. Only that first request is
+//! charged and recorded.
+//!
+//! A run that failed is not joined once it has finished, so a retry after
+//! an error gets a fresh attempt. Joined responses carry
+//! `X-DeepClaude-Coalesced: true`, requests sending
+//! `X-DeepClaude-No-Coalesce` always get a run of their own, and the number
+//! of joined requests is exported at `GET /metrics`.
+
+use crate::{
+    config::CoalesceConfig,
+    error::{ApiError, Result},
+    models::request::ApiRequest,
+};
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tracing::Instrument;
+
+/// Request header that opts out of coalescing.
+pub const OPT_OUT_HEADER: &str = "x-deepclaude-no-coalesce";
+
+/// Response header marking a response shared with an earlier request.
+pub const COALESCED_HEADER: &str = "x-deepclaude-coalesced";
+
+/// Headers that differ between attempts of the same request.
+const PER_ATTEMPT_HEADERS: &[&str] = &["x-request-id", "traceparent", "tracestate", "x-stainless-retry-count"];
+
+/// A run's response, recorded as it is produced.
+#[derive(Default)]
+struct Recorded {
+    head: Option<std::result::Result<(StatusCode, HeaderMap), ApiError>>,
+    chunks: Vec<Bytes>,
+    done: bool,
+}
+
+/// One pipeline run shared by identical requests.
+struct Run {
+    started: Instant,
+    recorded: Mutex<Recorded>,
+    changed: Notify,
+}
+
+impl Run {
+    fn update(&self, change: impl FnOnce(&mut Recorded)) {
+        change(&mut self.recorded.lock().unwrap());
+        self.changed.notify_waiters();
+    }
+
+    fn succeeded(&self) -> bool {
+        let recorded = self.recorded.lock().unwrap();
+        matches!(&recorded.head, Some(Ok((status, _))) if status.is_success())
+    }
+
+    /// Whether a new request may still join the run.
+    fn joinable(&self, window: Duration) -> bool {
+        let done = self.recorded.lock().unwrap().done;
+        self.started.elapsed() < window && (!done || self.succeeded())
+    }
+
+    /// The run's response, replayed from the start.
+    async fn response(self: Arc<Self>) -> Result<Response> {
+        let head = loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            if let Some(head) = self.recorded.lock().unwrap().head.clone() {
+                break head;
+            }
+            changed.await;
+        };
+        let (status, headers) = head?;
+
+        let body = async_stream::stream! {
+            let mut next = 0;
+            loop {
+                // 先登记通知再读取，避免错过读取之后追加的内容
+                let changed = self.changed.notified();
+                tokio::pin!(changed);
+                changed.as_mut().enable();
+                let (chunks, done) = {
+                    let recorded = self.recorded.lock().unwrap();
+                    (recorded.chunks[next..].to_vec(), recorded.done)
+                };
+                next += chunks.len();
+                for chunk in chunks {
+                    yield Ok::<_, std::convert::Infallible>(chunk);
+                }
+                if done {
+                    break;
+                }
+                changed.await;
+            }
+        };
+        let mut response = Body::from_stream(body).into_response();
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        Ok(response)
+    }
+}
+
+/// Shares pipeline runs between identical requests.
+pub struct Coalescer {
+    /// `[coalesce] enabled`, switchable at runtime.
+    enabled: AtomicBool,
+    window: Duration,
+    runs: Arc<Mutex<HashMap<String, Arc<Run>>>>,
+    joined: AtomicU64,
+}
+
+impl Coalescer {
+    pub fn new(config: &CoalesceConfig) -> Self {
+        Self {
+            enabled: AtomicBool::new(config.enabled),
+            window: Duration::from_secs(config.window_secs),
+            runs: Default::default(),
+            joined: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turns coalescing on or off for later requests.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The key of a request, `None` if it is not coalesced.
+    pub fn key(&self, request: &ApiRequest, headers: &HeaderMap) -> Option<String> {
+        if !self.enabled() || headers.contains_key(OPT_OUT_HEADER) {
+            return None;
+        }
+        let mut kept: BTreeMap<&str, Vec<&[u8]>> = BTreeMap::new();
+        for (name, value) in headers {
+            if !PER_ATTEMPT_HEADERS.contains(&name.as_str()) {
+                kept.entry(name.as_str()).or_default().push(value.as_bytes());
+            }
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(serde_json::to_value(request).ok()?.to_string());
+        for (name, values) in kept {
+            hasher.update(name);
+            for value in values {
+                hasher.update(b"\0");
+                hasher.update(value);
+            }
+            hasher.update(b"\n");
+        }
+        Some(hex::encode(hasher.finalize()))
+    }
+
+    /// Serves a request, joining the run of an identical earlier request if
+    /// one is still joinable and starting a run otherwise.
+    pub async fn run<F>(&self, key: Option<String>, serve: F) -> Result<Response>
+    where
+        F: Future<Output = Result<Response>> + Send + 'static,
+    {
+        let Some(key) = key else {
+            return serve.await;
+        };
+        let window = self.window;
+        let (run, joined) = {
+            let mut runs = self.runs.lock().unwrap();
+            runs.retain(|_, run| run.joinable(window));
+            match runs.get(&key) {
+                Some(run) => (run.clone(), true),
+                None => {
+                    let run = Arc::new(Run {
+                        started: Instant::now(),
+                        recorded: Default::default(),
+                        changed: Notify::new(),
+                    });
+                    runs.insert(key.clone(), run.clone());
+                    (run, false)
+                }
+            }
+        };
+        if joined {
+            self.joined.fetch_add(1, Ordering::Relaxed);
+            tracing::info!("相同的请求已在处理，合并到进行中的运行");
+        } else {
+            self.start(key, run.clone(), serve, window);
+        }
+        let mut response = run.response().await?;
+        if joined {
+            response.headers_mut().insert(COALESCED_HEADER, HeaderValue::from_static("true"));
+        }
+        Ok(response)
+    }
+
+    /// Drives a run in its own task, recording its response.
+    fn start<F>(&self, key: String, run: Arc<Run>, serve: F, window: Duration)
+    where
+        F: Future<Output = Result<Response>> + Send + 'static,
+    {
+        let runs = self.runs.clone();
+        // 运行由第一个请求发起，沿用它的span
+        let span = tracing::Span::current();
+        tokio::spawn(
+            async move {
+                match serve.await {
+                    Ok(response) => {
+                        let (parts, body) = response.into_parts();
+                        run.update(|recorded| recorded.head = Some(Ok((parts.status, parts.headers))));
+                        let mut body = body.into_data_stream();
+                        while let Some(Ok(chunk)) = body.next().await {
+                            run.update(|recorded| recorded.chunks.push(chunk));
+                        }
+                    }
+                    Err(e) => run.update(|recorded| recorded.head = Some(Err(e))),
+                }
+                run.update(|recorded| recorded.done = true);
+
+                // 成功的运行在窗口内仍可加入，之后释放记录的响应
+                if run.succeeded() {
+                    tokio::time::sleep_until((run.started + window).into()).await;
+                }
+                let mut runs = runs.lock().unwrap();
+                if runs.get(&key).is_some_and(|current| Arc::ptr_eq(current, &run)) {
+                    runs.remove(&key);
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Renders the coalescing counter in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP deepclaude_coalesced_requests_total Chat requests served by joining an identical earlier run.\n# TYPE deepclaude_coalesced_requests_total counter\ndeepclaude_coalesced_requests_total {}",
+            self.joined.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
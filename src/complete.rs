@@ -0,0 +1,103 @@
+//! Plain-text completions for shell scripts.
+//!
+//! - `POST /debug/complete` - answer one prompt as plain text
+//!
+//! The body is `{"prompt": "...", "mode": "normal"}`, with `mode` and
+//! `system` optional. The prompt is sent as a single user message through
+//! `POST /v1/chat/completions`, so keys, presets, hooks and the response
+//! cache apply as usual, and the answer comes back as `text/plain`: the
+//! reasoning, a `---` divider line, then the answer. Errors keep their
+//! status code and are reported as an `error: <message>` line, so
+//! `curl -sf` and health checks can rely on the status alone. The endpoint
+//! only exists with `[debug] complete` enabled.
+
+use crate::{
+    error::{ApiError, Result},
+    handlers::{self, AppState},
+    models::{request::ApiRequest, response::OpenAICompatibleResponse},
+};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// Line between the reasoning and the answer.
+const DIVIDER: &str = "---";
+
+/// Body of `POST /debug/complete`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CompleteRequest {
+    pub prompt: String,
+    #[serde(default)]
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub system: Option<String>,
+}
+
+/// Answers one prompt as plain text.
+///
+/// # Errors
+///
+/// Returns `ApiError::NotFound` unless `[debug] complete` is enabled. Errors
+/// of the chat pipeline are returned as plain text with their status code.
+#[utoipa::path(
+    post,
+    path = "/debug/complete",
+    tag = "debug",
+    request_body = CompleteRequest,
+    responses(
+        (status = 200, description = "The reasoning, a `---` line and the answer", body = String, content_type = "text/plain"),
+        (status = 404, description = "`[debug] complete` is disabled", body = String, content_type = "text/plain"),
+    )
+)]
+pub async fn complete(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<CompleteRequest>,
+) -> Result<Response> {
+    if !state.config.debug.complete {
+        return Err(ApiError::NotFound {
+            message: "The complete endpoint is disabled; set [debug] complete to enable it".to_string(),
+        });
+    }
+
+    let request: ApiRequest = serde_json::from_value(json!({
+        "stream": false,
+        "mode": body.mode,
+        "system": body.system,
+        "messages": [{ "role": "user", "content": body.prompt }],
+    }))
+    .map_err(|e| ApiError::BadRequest { message: e.to_string() })?;
+
+    let response = match handlers::handle_chat(State(state), headers, Json(request)).await {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    };
+    let (mut parts, body) = response.into_parts();
+    let bytes = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| ApiError::Internal { message: format!("读取回答失败: {}", e) })?;
+
+    let text = if parts.status.is_success() {
+        let response: OpenAICompatibleResponse = serde_json::from_slice(&bytes)
+            .map_err(|e| ApiError::Internal { message: format!("解析回答失败: {}", e) })?;
+        let message = response.choices.into_iter().next().map(|choice| choice.message);
+        let reasoning = message.as_ref().and_then(|m| m.reasoning_content.clone()).unwrap_or_default();
+        let answer = message.map(|m| m.content).unwrap_or_default();
+        format!("{}\n{}\n{}\n", reasoning.trim(), DIVIDER, answer.trim())
+    } else {
+        let error: serde_json::Value = serde_json::from_slice(&bytes).unwrap_or_default();
+        let message = error["error"]["message"].as_str().unwrap_or_else(|| parts.status.as_str());
+        format!("error: {}\n", message)
+    };
+
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, text.into()))
+}
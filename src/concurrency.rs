@@ -0,0 +1,203 @@
+//! Limit on chat requests in flight, with priority tiers.
+//!
+//! With `[concurrency] max_in_flight` set, at most that many chat requests
+//! run at once; the others queue for up to `max_wait_ms` and are then
+//! rejected with `ApiError::Overloaded`. A virtual key is either
+//! `interactive` (the default) or `batch`. A freed slot goes to the queued
+//! interactive requests before any batch request, so daytime users are not
+//! stuck behind a nightly batch job, and `max_batch_in_flight` keeps slots
+//! free for them while batch jobs run. Within a tier the slot goes to the
+//! key with the fewest requests in flight, the earliest queued first, so one
+//! busy key cannot take every slot. A streaming request holds its slot until
+//! its response body is done.
+
+use crate::{
+    config::ConcurrencyConfig,
+    error::{ApiError, Result},
+    keys::{Priority, VirtualKey},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+/// A request waiting for a slot.
+struct Waiter {
+    ticket: u64,
+    priority: Priority,
+    key: String,
+    grant: oneshot::Sender<Permit>,
+}
+
+#[derive(Default)]
+struct Slots {
+    in_flight: usize,
+    batch_in_flight: usize,
+    per_key: HashMap<String, usize>,
+    queue: Vec<Waiter>,
+    next_ticket: u64,
+}
+
+impl Slots {
+    fn can_run(&self, config: &ConcurrencyConfig, priority: Priority) -> bool {
+        self.in_flight < config.max_in_flight
+            && (priority == Priority::Interactive
+                || config.max_batch_in_flight == 0
+                || self.batch_in_flight < config.max_batch_in_flight)
+    }
+
+    fn take(&mut self, priority: Priority, key: &str) {
+        self.in_flight += 1;
+        if priority == Priority::Batch {
+            self.batch_in_flight += 1;
+        }
+        *self.per_key.entry(key.to_string()).or_default() += 1;
+    }
+
+    fn give_back(&mut self, priority: Priority, key: &str) {
+        self.in_flight -= 1;
+        if priority == Priority::Batch {
+            self.batch_in_flight -= 1;
+        }
+        if let Some(count) = self.per_key.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_key.remove(key);
+            }
+        }
+    }
+}
+
+struct Shared {
+    config: ConcurrencyConfig,
+    slots: Mutex<Slots>,
+}
+
+impl Shared {
+    /// Hands free slots to queued requests, interactive ones first.
+    ///
+    /// Returns the permits of requests that gave up meanwhile, to be
+    /// dropped once the lock is released.
+    fn dispatch(self: &Arc<Self>, slots: &mut Slots) -> Vec<Permit> {
+        let mut abandoned = Vec::new();
+        loop {
+            let next = slots
+                .queue
+                .iter()
+                .enumerate()
+                .filter(|(_, waiter)| slots.can_run(&self.config, waiter.priority))
+                .min_by_key(|(_, waiter)| {
+                    let running = slots.per_key.get(&waiter.key).copied().unwrap_or(0);
+                    (waiter.priority, running, waiter.ticket)
+                })
+                .map(|(index, _)| index);
+            let Some(index) = next else {
+                return abandoned;
+            };
+            let waiter = slots.queue.remove(index);
+            // 等待方已放弃（客户端断开）时跳过
+            if waiter.grant.is_closed() {
+                continue;
+            }
+            slots.take(waiter.priority, &waiter.key);
+            let permit = Permit {
+                shared: self.clone(),
+                priority: waiter.priority,
+                key: waiter.key,
+            };
+            if let Err(permit) = waiter.grant.send(permit) {
+                abandoned.push(permit);
+            }
+        }
+    }
+}
+
+/// A slot held by a running request, given back when dropped.
+pub struct Permit {
+    shared: Arc<Shared>,
+    priority: Priority,
+    key: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let abandoned = {
+            let mut slots = self.shared.slots.lock().unwrap();
+            slots.give_back(self.priority, &self.key);
+            self.shared.dispatch(&mut slots)
+        };
+        drop(abandoned);
+    }
+}
+
+/// Slots of the chat requests in flight.
+pub struct ConcurrencyLimiter {
+    shared: Arc<Shared>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: &ConcurrencyConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                config: config.clone(),
+                slots: Mutex::new(Slots::default()),
+            }),
+        }
+    }
+
+    /// Takes a slot for a request made with `key`, queueing if none is free.
+    ///
+    /// Returns `None` if no limit is configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Overloaded` if no slot frees up within
+    /// `max_wait_ms`.
+    pub async fn acquire(&self, key: Option<&VirtualKey>) -> Result<Option<Permit>> {
+        let config = &self.shared.config;
+        if config.max_in_flight == 0 {
+            return Ok(None);
+        }
+        let priority = key.map(|key| key.priority).unwrap_or_default();
+        let name = key.map(|key| key.name.clone()).unwrap_or_default();
+
+        let (ticket, mut granted) = {
+            let mut slots = self.shared.slots.lock().unwrap();
+            let waiting = slots.queue.iter().any(|waiter| slots.can_run(config, waiter.priority));
+            if slots.can_run(config, priority) && !waiting {
+                slots.take(priority, &name);
+                return Ok(Some(Permit {
+                    shared: self.shared.clone(),
+                    priority,
+                    key: name,
+                }));
+            }
+            let (grant, granted) = oneshot::channel();
+            let ticket = slots.next_ticket;
+            slots.next_ticket += 1;
+            slots.queue.push(Waiter { ticket, priority, key: name, grant });
+            (ticket, granted)
+        };
+
+        tracing::debug!("并发请求数已满，{:?}请求排队等待", priority);
+        let wait = Duration::from_millis(config.max_wait_ms);
+        if let Ok(Ok(permit)) = tokio::time::timeout(wait, &mut granted).await {
+            return Ok(Some(permit));
+        }
+        {
+            let mut slots = self.shared.slots.lock().unwrap();
+            slots.queue.retain(|waiter| waiter.ticket != ticket);
+        }
+        // 超时的同时已分到槽位
+        if let Ok(permit) = granted.try_recv() {
+            return Ok(Some(permit));
+        }
+        tracing::warn!("等待并发槽位超时，拒绝{:?}请求", priority);
+        Err(ApiError::Overloaded {
+            message: "Too many requests in flight".to_string(),
+            retry_after_secs: wait.as_secs().max(1),
+        })
+    }
+}
@@ -4,10 +4,11 @@
 //! and environment variables. It includes pricing configurations for different
 //! AI model providers and server settings.
 
+use crate::{keys::VirtualKey, paths};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::path::PathBuf;
+use utoipa::ToSchema;
 
 /// Root configuration structure containing all application settings.
 ///
@@ -16,8 +17,119 @@ use std::path::PathBuf;
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub server: ServerConfig,
+    #[serde(default)]
     pub auth: AuthConfig,
     pub pricing: PricingConfig,
+    #[serde(default)]
+    pub rate_limits: HashMap<String, ProviderBudget>,
+    #[serde(default)]
+    pub presets: HashMap<String, Preset>,
+    #[serde(default)]
+    pub keys: Vec<VirtualKey>,
+    #[serde(default)]
+    pub secrets: SecretsConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub canary: CanaryConfig,
+    #[serde(default)]
+    pub shadow: ShadowConfig,
+    #[serde(default)]
+    pub deadletter: DeadLetterConfig,
+    #[serde(default)]
+    pub model_aliases: HashMap<String, ModelAlias>,
+    #[serde(default)]
+    pub model_pools: HashMap<String, ModelPool>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub openrouter: OpenRouterConfig,
+    #[serde(default)]
+    pub mcp: McpConfig,
+    #[serde(default)]
+    pub rag: RagConfig,
+    #[serde(default)]
+    pub files: FilesConfig,
+    #[serde(default)]
+    pub postprocess: PostprocessConfig,
+    #[serde(default)]
+    pub edit_blocks: EditBlocksConfig,
+    #[serde(default)]
+    pub language: LanguageConfig,
+    #[serde(default)]
+    pub system_prompt: SystemPromptConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
+    pub templates: TemplatesConfig,
+    #[serde(default)]
+    pub traces: TracesConfig,
+    #[serde(default)]
+    pub streams: StreamsConfig,
+    #[serde(default)]
+    pub usage: UsageConfig,
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantConfig>,
+    #[serde(default)]
+    pub reports: ReportsConfig,
+    #[serde(default)]
+    pub spend_alerts: SpendAlertsConfig,
+    #[serde(default)]
+    pub request_log: RequestLogConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub debug: DebugConfig,
+    #[serde(default)]
+    pub consensus: ConsensusConfig,
+    #[serde(default)]
+    pub refine: RefineConfig,
+    /// Output envelopes by Claude stage model or alias.
+    #[serde(default)]
+    pub envelopes: HashMap<String, EnvelopeConfig>,
+    #[serde(default)]
+    pub deepseek: DeepSeekConfig,
+    #[serde(default)]
+    pub passthrough: PassthroughConfig,
+    /// Named upstream account profiles.
+    #[serde(default)]
+    pub providers: HashMap<String, ProviderProfile>,
+    /// Upstream stop reasons mapped to OpenAI `finish_reason` values, in
+    /// addition to or overriding the built-in mapping.
+    #[serde(default)]
+    pub finish_reasons: HashMap<String, String>,
+    #[serde(default)]
+    pub response: ResponseConfig,
+    #[serde(default)]
+    pub prompt_cache: PromptCacheConfig,
+    #[serde(default)]
+    pub reasoning_cache: ReasoningCacheConfig,
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
+    #[serde(default)]
+    pub pacing: PacingConfig,
+    #[serde(default)]
+    pub soft_limit: SoftLimitConfig,
+    #[serde(default)]
+    pub affinity: AffinityConfig,
+    #[serde(default)]
+    pub shedding: SheddingConfig,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub grpc: GrpcConfig,
+    #[serde(default)]
+    pub coalesce: CoalesceConfig,
+    #[serde(default)]
+    pub abuse: AbuseConfig,
+    #[serde(default)]
+    pub signing: SigningConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
 }
 
 /// Server-specific configuration settings.
@@ -28,6 +140,19 @@ pub struct Config {
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// Answer `stream: true` requests with JSON when the client accepts only
+    /// `application/json`. Off by default since common SDKs send that
+    /// `Accept` header on streaming requests too.
+    #[serde(default)]
+    pub strict_accept: bool,
+    /// Addresses to listen on instead of `host` and the port: `host:port`
+    /// pairs, or `unix:<path>` for a Unix domain socket.
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// Directory runtime files are written under, relative to the config
+    /// directory; empty for the config directory itself.
+    #[serde(default)]
+    pub data_dir: String,
 }
 
 /// Pricing configuration for all supported AI models.
@@ -38,6 +163,11 @@ pub struct ServerConfig {
 pub struct PricingConfig {
     pub deepseek: DeepSeekPricing,
     pub anthropic: AnthropicPricing,
+    /// Prices of Claude models reached through an OpenAI-format gateway,
+    /// by the model name the gateway reports; these take precedence over
+    /// the Anthropic prices for such calls.
+    #[serde(default)]
+    pub gateways: HashMap<String, ModelPricing>,
 }
 
 /// DeepSeek-specific pricing configuration.
@@ -70,15 +200,1354 @@ pub struct AnthropicPricing {
 pub struct ModelPricing {
     pub input_price: f64,             // per million tokens
     pub output_price: f64,            // per million tokens
+    #[serde(default)]
     pub cache_write_price: f64,       // per million tokens
+    #[serde(default)]
     pub cache_read_price: f64,        // per million tokens
 }
 
+/// Client-side request budget for a single upstream provider.
+///
+/// Budgets are keyed by provider name (`deepseek`, `anthropic`) in the
+/// `[rate_limits]` table. A value of `0` disables the corresponding limit.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ProviderBudget {
+    #[serde(default)]
+    pub rpm: u32,                     // requests per minute
+    #[serde(default)]
+    pub tpm: u32,                     // tokens per minute
+    #[serde(default)]
+    pub max_wait_ms: u64,             // how long to queue before rejecting
+}
+
+/// Settings for resolving `vault://` and `aws-sm://` key references.
+///
+/// Credentials are always taken from the environment (`VAULT_TOKEN`,
+/// `AWS_ACCESS_KEY_ID`, ...), never from the config file.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SecretsConfig {
+    pub refresh_secs: u64,
+    pub vault_addr: Option<String>,
+    pub aws_region: Option<String>,
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        Self {
+            refresh_secs: 300,
+            vault_addr: None,
+            aws_region: None,
+        }
+    }
+}
+
+/// Named bundle of generation parameters selectable via the `preset` request field.
+///
+/// Values set here are used only when the request does not provide them itself.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct Preset {
+    pub mode: Option<String>,
+    pub deepseek: StageParams,
+    pub anthropic: StageParams,
+}
+
+/// Sampling parameters applied to a single pipeline stage.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct StageParams {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u32>,
+}
+
+/// Alternate pipeline configuration served to a share of traffic.
+///
+/// Requests are assigned to the canary with probability `percent`/100 and
+/// tagged with `name`; all other requests are tagged `stable`. Overrides
+/// only apply where the request does not choose a mode or model itself.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CanaryConfig {
+    pub percent: u8,
+    pub name: String,
+    pub mode: Option<String>,
+    pub deepseek_model: Option<String>,
+    pub anthropic_model: Option<String>,
+}
+
+impl Default for CanaryConfig {
+    fn default() -> Self {
+        Self {
+            percent: 0,
+            name: "canary".to_string(),
+            mode: None,
+            deepseek_model: None,
+            anthropic_model: None,
+        }
+    }
+}
+
+/// Background mirroring of sampled requests to an alternate responder.
+///
+/// Disabled while `percent` is `0` or `anthropic_model` is empty.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ShadowConfig {
+    pub percent: u8,
+    pub anthropic_model: String,
+    pub max_concurrent: usize,
+    pub output_path: String,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self {
+            percent: 0,
+            anthropic_model: String::new(),
+            max_concurrent: 4,
+            output_path: "shadow/records.jsonl".to_string(),
+        }
+    }
+}
+
+/// A model name that routes a stage to a third-party host.
+///
+/// `host` names a built-in profile: `fireworks`, `together`, `groq`,
+/// `siliconflow`, `ark` or `openai` for the reasoning stage, `dashscope`,
+/// `zhipu` or `moonshot` for the Claude stage, and `openrouter` for either.
+/// The key defaults to the host's own environment variable, e.g.
+/// `GROQ_API_KEY`, and may be a secret reference.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ModelAlias {
+    pub host: String,
+    pub model: String,
+    #[serde(default)]
+    pub api_url: Option<String>,
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+/// Interchangeable models for one stage, chosen by recent latency and errors.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ModelPool {
+    /// Model names or aliases, in order of preference when they perform alike.
+    pub members: Vec<String>,
+    /// Members failing more of their recent calls than this are avoided.
+    pub max_error_rate: f64,
+    /// Seconds a conversation keeps the member it was given.
+    pub sticky_secs: u64,
+}
+
+impl Default for ModelPool {
+    fn default() -> Self {
+        Self {
+            members: Vec::new(),
+            max_error_rate: 0.2,
+            sticky_secs: 3600,
+        }
+    }
+}
+
+/// A request rule, see [`crate::rules`].
+///
+/// The rule matches when every condition set in `when` holds; its actions
+/// then apply in the order listed here, and `reject` ends the request.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct Rule {
+    /// Name used in logs and in the rejection message.
+    pub name: String,
+    pub when: RuleMatch,
+    /// Model (or alias) the reasoning stage is switched to.
+    pub deepseek_model: Option<String>,
+    /// Model (or alias) the Claude stage is switched to.
+    pub anthropic_model: Option<String>,
+    /// Mode the request is forced into.
+    pub mode: Option<String>,
+    /// Text prepended to the system prompt.
+    pub system: Option<String>,
+    /// Rejects the request with 403 and this message.
+    pub reject: Option<String>,
+}
+
+/// Conditions of a `[[rules]]` entry; unset conditions match any request.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct RuleMatch {
+    /// Model the client named.
+    pub model: Option<String>,
+    /// Name of the virtual key the request was made with.
+    pub key: Option<String>,
+    /// A request header, `name` to require it or `name: value` to require its value.
+    pub header: Option<String>,
+    /// Regex searched in the system prompt and messages.
+    pub prompt: Option<String>,
+}
+
+/// Discovery of the OpenRouter model catalog.
+///
+/// The catalog is fetched whenever an OpenRouter key is configured, either
+/// as `OPENROUTER_API_KEY` or on an alias with `host = "openrouter"`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct OpenRouterConfig {
+    pub models_url: String,
+    /// Seconds between catalog refreshes; 0 fetches it once at startup.
+    pub refresh_secs: u64,
+}
+
+impl Default for OpenRouterConfig {
+    fn default() -> Self {
+        Self {
+            models_url: "https://openrouter.ai/api/v1/models".to_string(),
+            refresh_secs: 3600,
+        }
+    }
+}
+
+/// MCP servers whose tools are offered to Claude.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct McpConfig {
+    /// Maximum number of tool-calling rounds before the answer is returned as is.
+    pub max_rounds: u32,
+    /// Seconds to wait for a server to answer one request.
+    pub timeout_secs: u64,
+    pub servers: HashMap<String, McpServerConfig>,
+}
+
+impl Default for McpConfig {
+    fn default() -> Self {
+        Self {
+            max_rounds: 5,
+            timeout_secs: 60,
+            servers: HashMap::new(),
+        }
+    }
+}
+
+/// An MCP server launched as a child process and spoken to over stdio.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct McpServerConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+/// Optional retrieval stage that adds vector store context to requests.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RagConfig {
+    pub enabled: bool,
+    /// Number of chunks retrieved per request.
+    pub top_k: usize,
+    /// Chunks scoring below this similarity are dropped.
+    pub min_score: f32,
+    pub embedding: EmbeddingConfig,
+    pub store: VectorStoreConfig,
+}
+
+impl Default for RagConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            top_k: 4,
+            min_score: 0.0,
+            embedding: EmbeddingConfig::default(),
+            store: VectorStoreConfig::default(),
+        }
+    }
+}
+
+/// OpenAI-compatible embeddings endpoint used to embed queries.
+///
+/// `api_key` falls back to `EMBEDDING_API_KEY` and may be a secret reference.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct EmbeddingConfig {
+    pub url: String,
+    pub model: String,
+    pub api_key: String,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            url: "https://api.openai.com/v1/embeddings".to_string(),
+            model: "text-embedding-3-small".to_string(),
+            api_key: String::new(),
+        }
+    }
+}
+
+/// Vector store holding the document chunks.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum VectorStoreConfig {
+    /// A Qdrant collection whose points carry `text` and `source` payload fields.
+    Qdrant {
+        url: String,
+        collection: String,
+        #[serde(default)]
+        api_key: String,
+    },
+    /// A Postgres table with `content`, `source` and `embedding vector` columns.
+    Pgvector { url: String, table: String },
+}
+
+impl Default for VectorStoreConfig {
+    fn default() -> Self {
+        Self::Qdrant {
+            url: "http://localhost:6333".to_string(),
+            collection: "documents".to_string(),
+            api_key: String::new(),
+        }
+    }
+}
+
+/// Documents uploaded through `/v1/files`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct FilesConfig {
+    pub dir: String,
+    pub max_upload_bytes: usize,
+    /// Target size of the chunks extracted text is split into, in characters.
+    pub chunk_chars: usize,
+    /// Files up to this many characters in total are added to a request
+    /// whole; larger ones are narrowed down to the chunks that fit.
+    pub max_context_chars: usize,
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        Self {
+            dir: "files".to_string(),
+            max_upload_bytes: 10 * 1024 * 1024,
+            chunk_chars: 2000,
+            max_context_chars: 24000,
+        }
+    }
+}
+
+/// Transforms applied to the final answer before it is returned.
+///
+/// The transforms for a request are taken from `models` if its Claude stage
+/// model (or alias) has an entry, otherwise from `modes` for its mode,
+/// otherwise from `default`. They run in the order listed.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct PostprocessConfig {
+    pub default: Vec<Transform>,
+    pub modes: HashMap<String, Vec<Transform>>,
+    pub models: HashMap<String, Vec<Transform>>,
+}
+
+/// One post-processing step.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Transform {
+    /// Removes markdown code fence lines, keeping the code inside them.
+    StripCodeFences,
+    /// Trims leading and trailing whitespace.
+    Trim,
+    /// Removes `<thinking>` blocks that leaked into the answer.
+    StripThinking,
+    /// Truncates the answer to at most `chars` characters.
+    MaxLength { chars: usize },
+    /// Replaces every match of `pattern` with `replacement` (`$1` refers to groups).
+    RegexReplace { pattern: String, replacement: String },
+}
+
+/// Checking of the SEARCH/REPLACE blocks full mode answers are written in.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct EditBlocksConfig {
+    /// Asks Claude once to repair malformed blocks before answering.
+    pub validate: bool,
+}
+
+/// Deployment-wide base system prompt, merged with the request's own.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct SystemPromptConfig {
+    /// Base prompt; empty for none.
+    pub base: String,
+    pub strategy: SystemPromptStrategy,
+    /// Stages the system prompt is sent to, by mode; unlisted modes send it to both.
+    pub forward: HashMap<String, SystemPromptForward>,
+}
+
+impl SystemPromptConfig {
+    /// Stages a mode sends the system prompt to.
+    pub fn forward_for(&self, mode: &str) -> SystemPromptForward {
+        self.forward.get(mode).copied().unwrap_or_default()
+    }
+}
+
+/// Stages the system prompt is sent to.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemPromptForward {
+    #[default]
+    Both,
+    /// Only the DeepSeek stage.
+    Deepseek,
+    /// Only the Claude stage.
+    Claude,
+}
+
+impl SystemPromptForward {
+    pub fn to_deepseek(self) -> bool {
+        self != Self::Claude
+    }
+
+    pub fn to_claude(self) -> bool {
+        self != Self::Deepseek
+    }
+}
+
+/// How the base system prompt is merged with a request's system prompt.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemPromptStrategy {
+    /// The base prompt comes first.
+    #[default]
+    Prepend,
+    /// The base prompt comes after the request's.
+    Append,
+    /// The base prompt replaces the request's.
+    Override,
+}
+
+/// Language answers are written in.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LanguageConfig {
+    /// Language code or name, such as `zh` or `English`; empty for none.
+    /// Defaults to `zh`, as answers were always written in Chinese before
+    /// this setting existed.
+    pub target: String,
+    /// Checks the answer's script and asks Claude once to answer again in
+    /// the target language if it does not match. Off by default, since
+    /// streamed answers are held until the check passes.
+    pub verify: bool,
+}
+
+impl Default for LanguageConfig {
+    fn default() -> Self {
+        Self {
+            target: "zh".to_string(),
+            verify: false,
+        }
+    }
+}
+
+/// rhai scripts run at the request and response hook points.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct HooksConfig {
+    /// Script paths, run in order at each hook point they define.
+    pub scripts: Vec<String>,
+    /// Maximum number of operations one hook call may take.
+    pub max_operations: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            scripts: Vec::new(),
+            max_operations: 100_000,
+        }
+    }
+}
+
+/// Custom variables for `{{name}}` placeholders in system prompts.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct TemplatesConfig {
+    pub variables: HashMap<String, String>,
+}
+
+/// Storage of the pipeline traces of verbose requests.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TracesConfig {
+    pub dir: String,
+    /// Traces older than this are removed when a new one is saved.
+    pub retention_hours: u64,
+}
+
+impl Default for TracesConfig {
+    fn default() -> Self {
+        Self {
+            dir: "traces".to_string(),
+            retention_hours: 24,
+        }
+    }
+}
+
+/// Supervision of streaming responses.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct StreamsConfig {
+    /// Seconds shutdown waits for running streams before cancelling them.
+    pub shutdown_grace_secs: u64,
+    /// Events buffered per stream between the upstream and the client.
+    pub channel_capacity: usize,
+    pub slow_consumer: SlowConsumer,
+    /// Age in milliseconds at which merged text deltas are sent, 0 for no
+    /// age limit.
+    pub coalesce_ms: u64,
+    /// Length in characters at which merged text deltas are sent, 0 for no
+    /// length limit. With neither limit every delta is sent as it arrives.
+    pub coalesce_chars: usize,
+    /// Adds Azure-style `content_filter_results` to the final chunk, for
+    /// clients that require the field.
+    pub content_filter_results: bool,
+    /// Seconds after which a running stream is ended with a `timeout`
+    /// finish chunk, 0 for no limit.
+    pub max_stream_duration_secs: u64,
+    /// Repairs damaged upstream stream lines instead of skipping them.
+    pub repair_json: bool,
+    /// How answer text is flushed to clients without a compat profile.
+    pub flush: FlushMode,
+    /// Longest time, in milliseconds, `clause` flushing holds answer text.
+    pub flush_ms: u64,
+    /// Holds answer text inside a markdown code block or inline code span
+    /// until it closes.
+    pub markdown_safe: bool,
+    /// Length in characters up to which `markdown_safe` holds answer text.
+    pub markdown_max_chars: usize,
+    /// Client compatibility profiles by name.
+    pub profiles: BTreeMap<String, CompatProfile>,
+}
+
+impl Default for StreamsConfig {
+    fn default() -> Self {
+        Self {
+            shutdown_grace_secs: 30,
+            channel_capacity: 100,
+            slow_consumer: SlowConsumer::Pause,
+            coalesce_ms: 0,
+            coalesce_chars: 0,
+            content_filter_results: false,
+            max_stream_duration_secs: 0,
+            repair_json: true,
+            flush: FlushMode::Delta,
+            flush_ms: 300,
+            markdown_safe: false,
+            markdown_max_chars: 4000,
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+/// How a stream's answer text is flushed to the client.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlushMode {
+    /// Send text as it arrives, subject to the coalescing limits.
+    Delta,
+    /// Hold answer text until a clause or sentence ends, or for `flush_ms`.
+    Clause,
+}
+
+/// Stream settings for the clients that need them, see
+/// [`crate::streams`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CompatProfile {
+    /// Text the client's `User-Agent` contains; when empty, clients select
+    /// the profile by name with `X-DeepClaude-Compat`.
+    pub user_agent: String,
+    pub flush: FlushMode,
+    pub flush_ms: u64,
+    pub markdown_safe: bool,
+}
+
+impl Default for CompatProfile {
+    fn default() -> Self {
+        Self {
+            user_agent: String::new(),
+            flush: FlushMode::Clause,
+            flush_ms: 300,
+            markdown_safe: false,
+        }
+    }
+}
+
+/// Per-request usage records.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct UsageConfig {
+    pub enabled: bool,
+    pub dir: String,
+    /// Records that may wait for the writer before new ones are dropped.
+    pub queue_size: usize,
+    /// Most records written in one batch.
+    pub batch_size: usize,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: "usage".to_string(),
+            queue_size: 10000,
+            batch_size: 100,
+        }
+    }
+}
+
+/// Limits of one organization, under `[tenants.<id>]`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct TenantConfig {
+    /// Spend in dollars after which the tenant's requests are refused until
+    /// the next month.
+    pub monthly_budget_usd: Option<f64>,
+    /// How many keys the tenant's owners may issue through `POST /v1/keys`;
+    /// 0 turns self-service off.
+    pub max_keys: usize,
+}
+
+/// Scheduled daily cost summary.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ReportsConfig {
+    pub enabled: bool,
+    /// Five-field cron expression the summary is sent on.
+    pub schedule: String,
+    /// Offset from UTC, in hours, of the schedule and of day boundaries.
+    pub utc_offset_hours: i32,
+    /// URL the summary is posted to, such as a Slack incoming webhook; may
+    /// be a secret reference.
+    pub webhook_url: String,
+    /// Number of keys and models listed by cost.
+    pub top: usize,
+    /// Multiple of its 7-day daily average above which a key's or model's
+    /// cost is reported as an anomaly.
+    pub anomaly_factor: f64,
+}
+
+impl Default for ReportsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            schedule: "0 9 * * *".to_string(),
+            utc_offset_hours: 8,
+            webhook_url: String::new(),
+            top: 5,
+            anomaly_factor: 2.0,
+        }
+    }
+}
+
+/// Alerts on keys whose hourly spend runs far above their baseline.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SpendAlertsConfig {
+    pub enabled: bool,
+    /// Multiple of the baseline hourly cost above which a key is alerted.
+    pub multiple: f64,
+    /// Hours the baseline is averaged over.
+    pub baseline_hours: u32,
+    /// Hours a key must have been seen before it can be alerted.
+    pub min_history_hours: u32,
+    /// Spend in the hour below which no alert is raised.
+    pub min_spend_usd: f64,
+    /// URL alerts are posted to, such as a Slack incoming webhook; may be a
+    /// secret reference. Empty to only log and count them.
+    pub webhook_url: String,
+}
+
+impl Default for SpendAlertsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            multiple: 5.0,
+            baseline_hours: 24,
+            min_history_hours: 6,
+            min_spend_usd: 1.0,
+            webhook_url: String::new(),
+        }
+    }
+}
+
+/// Policy of the structured request log.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RequestLogConfig {
+    pub enabled: bool,
+    /// Percentage of requests logged with their full request and answer.
+    pub sample_percent: f64,
+    /// Request body fields replaced by `[REDACTED]` wherever they occur.
+    pub redact_fields: Vec<String>,
+    /// Log users and keys as salted hashes rather than by name.
+    pub hash_users: bool,
+    /// Salt mixed into the hashes.
+    pub hash_salt: String,
+}
+
+impl Default for RequestLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sample_percent: 0.0,
+            redact_fields: vec!["headers".to_string(), "api_key".to_string(), "authorization".to_string()],
+            hash_users: true,
+            hash_salt: String::new(),
+        }
+    }
+}
+
+/// Cache of non-streaming answers.
 #[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    /// Seconds an answer is kept.
+    pub ttl_secs: u64,
+    pub max_entries: usize,
+    /// Also answer requests whose latest user message is similar, by
+    /// embedding, to that of a cached request.
+    pub semantic: bool,
+    /// Cosine similarity at or above which two user messages count as the
+    /// same question.
+    pub similarity_threshold: f32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: 3600,
+            max_entries: 1000,
+            semantic: false,
+            similarity_threshold: 0.95,
+        }
+    }
+}
+
+/// Where usage records, cached responses and virtual keys are kept.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Connection string of the `postgres` backend, which may be a secret
+    /// reference, or database file of the `sqlite` backend.
+    pub url: String,
+    /// File of the keys issued through `POST /v1/keys` with the `file`
+    /// backend.
+    pub keys_file: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::default(),
+            url: String::new(),
+            keys_file: "issued_keys.json".to_string(),
+        }
+    }
+}
+
+/// Storage implementation, see [`crate::storage`].
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    File,
+    Memory,
+    Sqlite,
+    Postgres,
+}
+
+/// Debugging endpoints, all off by default.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct DebugConfig {
+    /// Serve `POST /debug/echo-completions`, see [`crate::echo`].
+    pub echo: bool,
+    /// Serve `POST /debug/complete`, see [`crate::complete`].
+    pub complete: bool,
+    /// Serve `GET /debug/tasks`, see [`crate::diagnostics`]; also set by
+    /// the `--diagnostics` flag.
+    pub tasks: bool,
+    /// Start with the `payload_capture` flag on, see [`crate::payloads`].
+    pub payloads: bool,
+}
+
+/// Defaults of consensus requests, see [`crate::consensus`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ConsensusConfig {
+    /// The two responder models of a request that does not name its own.
+    pub models: Vec<String>,
+    pub strategy: ConsensusStrategy,
+    /// Model that picks or merges the answers under the `judge` strategy.
+    pub judge_model: String,
+    /// Instructions for the judge; empty uses the built-in rubric.
+    pub judge_prompt: String,
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> Self {
+        Self {
+            models: Vec::new(),
+            strategy: ConsensusStrategy::Choices,
+            judge_model: "claude-3-5-haiku-20241022".to_string(),
+            judge_prompt: String::new(),
+        }
+    }
+}
+
+/// How the two answers of a consensus request are returned.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsensusStrategy {
+    /// Both answers, as two choices.
+    #[default]
+    Choices,
+    /// One answer, picked or merged by the judge model.
+    Judge,
+}
+
+/// Self-critique pass of non-streaming answers, see [`crate::refine`].
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct RefineConfig {
+    /// Refines requests that do not set `refine` themselves.
+    pub enabled: bool,
+    /// What the critique checks the draft for; empty uses the built-in rubric.
+    pub rubric: String,
+}
+
+/// A fixed JSON shape for a responder's answers, see [`crate::envelope`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct EnvelopeConfig {
+    /// JSON Schema the answer must match.
+    pub schema: serde_json::Value,
+    /// Also sets `response_format` to a JSON object on hosts that take it.
+    pub json_mode: bool,
+}
+
+impl Default for EnvelopeConfig {
+    fn default() -> Self {
+        Self {
+            schema: serde_json::json!({ "type": "object" }),
+            json_mode: true,
+        }
+    }
+}
+
+/// Reasoning stage parameters, see [`DeepSeekOptions`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct DeepSeekConfig {
+    /// Model id the `r1` shorthand stands for; empty uses the provider's model.
+    pub r1_model: String,
+    /// Model id the `v3` shorthand stands for.
+    pub v3_model: String,
+    /// Options of requests that leave them unset.
+    pub defaults: DeepSeekOptions,
+}
+
+impl Default for DeepSeekConfig {
+    fn default() -> Self {
+        Self {
+            r1_model: String::new(),
+            v3_model: "deepseek-v3-250324".to_string(),
+            defaults: DeepSeekOptions::default(),
+        }
+    }
+}
+
+/// Typed parameters of the DeepSeek stage.
+///
+/// Set per request as `deepseek`, or as defaults in `[deepseek.defaults]`.
+/// They are written into the stage body: request options take precedence
+/// over presets and defaults, and one that disagrees with the same field in
+/// `deepseek_config.body` is rejected rather than silently dropped.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeepSeekOptions {
+    /// `r1`, `v3`, or a model id or alias.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+}
+
+/// Inbound request headers forwarded to each stage's upstream.
+///
+/// Names are matched case-insensitively. A forwarded header never replaces
+/// one the client sets itself, such as authentication or content type.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PassthroughConfig {
+    pub deepseek: Vec<String>,
+    pub anthropic: Vec<String>,
+}
+
+impl Default for PassthroughConfig {
+    fn default() -> Self {
+        let common = ["x-title", "http-referer", "traceparent", "tracestate"];
+        Self {
+            deepseek: common.iter().map(|name| name.to_string()).collect(),
+            anthropic: std::iter::once("anthropic-beta")
+                .chain(common)
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// A named set of upstream accounts, see [`crate::clients::providers`].
+///
+/// Fields mirror the `.env` settings of the same name in lower case; unset
+/// ones keep the `.env` value. Keys may be secret references.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProviderProfile {
+    pub deepseek_api_url: Option<String>,
+    pub deepseek_model: Option<String>,
+    pub deepseek_api_key: Option<String>,
+    pub anthropic_api_url: Option<String>,
+    pub claude_openai_type_api_url: Option<String>,
+    pub claude_model: Option<String>,
+    pub anthropic_api_key: Option<String>,
+    /// Stage model names that select this profile; the stage then uses the
+    /// profile's model.
+    pub aliases: Vec<String>,
+}
+
+/// Fields of chat responses.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ResponseConfig {
+    pub model_name: ModelName,
+}
+
+/// Which model name responses report in their `model` field.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelName {
+    /// `<DeepSeek model>_<Claude model>`.
+    #[default]
+    Combined,
+    /// The request's own `model`, or the combined name if it has none.
+    Requested,
+    /// The model that wrote the answer.
+    Responder,
+    /// The reasoner's model on reasoning chunks and the responder's on the
+    /// rest; non-streaming responses report the responder.
+    Stage,
+}
+
+/// Prompt caching of session requests, see [`crate::sessions`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PromptCacheConfig {
+    pub enabled: bool,
+    /// Shortest system prompt, in characters, worth caching; Anthropic does
+    /// not cache prefixes under about 1024 tokens.
+    pub min_prefix_chars: usize,
+    /// Seconds a cached prefix stays warm without being read.
+    pub ttl_secs: u64,
+}
+
+impl Default for PromptCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_prefix_chars: 4096,
+            ttl_secs: 300,
+        }
+    }
+}
+
+/// Reuse of DeepSeek reasoning across retries, see [`crate::reasoning`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ReasoningCacheConfig {
+    pub enabled: bool,
+    /// Seconds a captured reasoning can be reused.
+    pub ttl_secs: u64,
+}
+
+impl Default for ReasoningCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: 600,
+        }
+    }
+}
+
+/// Limit on chat requests in flight, see [`crate::concurrency`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ConcurrencyConfig {
+    /// Most chat requests running at once; `0` means unlimited.
+    pub max_in_flight: usize,
+    /// Most requests of `batch` keys running at once; `0` means up to
+    /// `max_in_flight`.
+    pub max_batch_in_flight: usize,
+    /// How long a request may wait for a slot before it is rejected.
+    pub max_wait_ms: u64,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 0,
+            max_batch_in_flight: 0,
+            max_wait_ms: 30_000,
+        }
+    }
+}
+
+/// Pacing of batch requests, see [`crate::pacing`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PacingConfig {
+    pub enabled: bool,
+    /// Requests per minute assumed until an upstream reports its limit.
+    pub requests_per_minute: f64,
+    /// Share of an upstream's remaining quota left to interactive requests.
+    pub reserve: f64,
+    /// How long a batch request may wait for its turn before it is rejected.
+    pub max_wait_ms: u64,
+}
+
+impl Default for PacingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            requests_per_minute: 60.0,
+            reserve: 0.2,
+            max_wait_ms: 600_000,
+        }
+    }
+}
+
+/// Early warning before a streamed answer hits its limit, see
+/// [`crate::softlimit`].
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct SoftLimitConfig {
+    /// Answer tokens after which the warning chunk is sent; `0` disables it.
+    pub output_tokens: u32,
+    /// Whether the Claude stage is asked to conclude within the limit.
+    pub wrap_up: bool,
+}
+
+/// Load balancer routing hints, see [`crate::affinity`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AffinityConfig {
+    pub enabled: bool,
+    /// Name of the cookie carrying the affinity key.
+    pub cookie: String,
+    /// Seconds the cookie is kept.
+    pub max_age_secs: u64,
+}
+
+impl Default for AffinityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cookie: "deepclaude_affinity".to_string(),
+            max_age_secs: 3600,
+        }
+    }
+}
+
+/// Shedding new streams under memory or descriptor pressure, see
+/// [`crate::shedding`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SheddingConfig {
+    /// Resident memory in MiB above which new streams are rejected; `0`
+    /// disables the check.
+    pub max_rss_mb: u64,
+    /// Open file descriptors (connections included) above which new
+    /// streams are rejected; `0` disables the check.
+    pub max_open_fds: u64,
+    /// `Retry-After` of the rejections.
+    pub retry_after_secs: u64,
+}
+
+impl Default for SheddingConfig {
+    fn default() -> Self {
+        Self {
+            max_rss_mb: 0,
+            max_open_fds: 0,
+            retry_after_secs: 10,
+        }
+    }
+}
+
+/// Raw passthrough routes to the upstream APIs, see [`crate::proxy`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// Serve `/deepseek/v1/*`.
+    pub deepseek: bool,
+    /// Serve `/anthropic/v1/*`.
+    pub anthropic: bool,
+    /// Base URL the DeepSeek route forwards to, without `/v1`.
+    pub deepseek_url: String,
+    /// Base URL the Anthropic route forwards to, without `/v1`.
+    pub anthropic_url: String,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            deepseek: false,
+            anthropic: false,
+            deepseek_url: "https://api.deepseek.com".to_string(),
+            anthropic_url: "https://api.anthropic.com".to_string(),
+        }
+    }
+}
+
+/// gRPC interface of the chat pipeline, see [`crate::grpc`].
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct GrpcConfig {
+    /// Address to serve gRPC on, e.g. `127.0.0.1:50051`; empty disables it.
+    pub listen: String,
+}
+
+/// Coalescing of duplicate chat requests, see [`crate::coalesce`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CoalesceConfig {
+    pub enabled: bool,
+    /// How long after a run starts identical requests join it.
+    pub window_secs: u64,
+}
+
+impl Default for CoalesceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: 5,
+        }
+    }
+}
+
+/// Per-IP throttling and bans, see [`crate::abuse`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AbuseConfig {
+    pub enabled: bool,
+    /// Requests an address may make per minute; `0` disables throttling.
+    pub requests_per_minute: u32,
+    /// Unauthenticated requests within `failure_window_secs` that get an
+    /// address banned; `0` disables automatic bans.
+    pub max_auth_failures: u32,
+    pub failure_window_secs: u64,
+    /// How long automatic bans last.
+    pub ban_secs: u64,
+    /// Take the client address from `X-Forwarded-For`, for deployments
+    /// behind a reverse proxy.
+    pub trust_forwarded_for: bool,
+    /// Addresses of the reverse proxies whose `X-Forwarded-For` is trusted.
+    pub trusted_proxies: Vec<String>,
+    /// Addresses never throttled or banned.
+    pub allow: Vec<String>,
+}
+
+impl Default for AbuseConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            requests_per_minute: 600,
+            max_auth_failures: 10,
+            failure_window_secs: 300,
+            ban_secs: 900,
+            trust_forwarded_for: false,
+            trusted_proxies: Vec::new(),
+            allow: Vec::new(),
+        }
+    }
+}
+
+/// HMAC signatures on webhooks and administrative calls, see
+/// [`crate::signing`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SigningConfig {
+    /// Secret webhook posts are signed with, or a secret reference; empty
+    /// leaves them unsigned.
+    pub webhook_secret: String,
+    /// Secret administrative calls must be signed with, or a secret
+    /// reference; empty accepts them unsigned.
+    pub admin_secret: String,
+    /// How far a signed timestamp may be from the server's clock.
+    pub max_skew_secs: u64,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self {
+            webhook_secret: String::new(),
+            admin_secret: String::new(),
+            max_skew_secs: 300,
+        }
+    }
+}
+
+/// Log of administrative actions, see [`crate::audit`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// File the log is appended to.
+    pub path: String,
+    /// Whether records are encrypted with the master key.
+    pub encrypt: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "audit.log".to_string(),
+            encrypt: false,
+        }
+    }
+}
+
+/// Scheduled purge of old data, see [`crate::retention`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    pub enabled: bool,
+    /// Five-field cron expression, in UTC, the purge runs on.
+    pub schedule: String,
+    /// Days usage records are kept; 0 keeps them.
+    pub usage_days: u32,
+    /// Days audit records are kept; 0 keeps them.
+    pub audit_days: u32,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            schedule: "30 3 * * *".to_string(),
+            usage_days: 0,
+            audit_days: 0,
+        }
+    }
+}
+
+/// How a stream reacts to a client reading slower than the upstream writes.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumer {
+    /// Stop reading from the upstream until the client catches up.
+    Pause,
+    /// Keep reading and merge the text deltas the client has not taken yet.
+    Coalesce,
+}
+
+/// Capture of failed requests for later replay.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct DeadLetterConfig {
+    pub enabled: bool,
+    pub dir: String,
+    /// Entries kept at most, the oldest removed first; `0` keeps them all.
+    pub max_entries: usize,
+    /// Days an entry is kept; `0` keeps it until it is replayed.
+    pub max_age_days: u32,
+}
+
+impl Default for DeadLetterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: "deadletter".to_string(),
+            max_entries: 1000,
+            max_age_days: 7,
+        }
+    }
+}
+
+/// Access to the `/admin` endpoints.
+///
+/// The admin API is disabled while `token` is empty and no `tokens` are
+/// configured. The token may also be given as `ADMIN_TOKEN` in the
+/// environment, or as a secret reference; it has the owner role.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub token: String,
+    /// Named admin tokens, each limited to a role.
+    pub tokens: Vec<AdminToken>,
+}
+
+/// A named admin token, see [`crate::admin`].
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AdminToken {
+    pub name: String,
+    /// The token itself, or a secret reference.
+    pub token: String,
+    pub role: AdminRole,
+}
+
+/// What an admin token may do; each role includes the ones before it.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    /// Reads dashboards: usage, streams, dead letters, flags.
+    Viewer,
+    /// Also acts on the running service: reloads, replays, flags.
+    Operator,
+    /// Also edits `.env` and manages keys.
+    Owner,
+}
+
+impl AdminRole {
+    pub fn name(self) -> &'static str {
+        match self {
+            AdminRole::Viewer => "viewer",
+            AdminRole::Operator => "operator",
+            AdminRole::Owner => "owner",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
 pub struct AuthConfig {
     pub api_key: String,
     pub deepseek_api_key: String,
     pub anthropic_api_key: String,
+    /// Treats the `Authorization` bearer token only as a virtual key: it is
+    /// never used as the DeepSeek key, which comes from the virtual key or
+    /// the server's own configuration instead.
+    pub managed_keys: bool,
 }
 
 impl Config {
@@ -98,35 +1567,27 @@ impl Config {
     /// - The TOML content cannot be parsed
     /// - The parsed content doesn't match the expected structure
     pub fn load() -> anyhow::Result<Self> {
-        // 尝试多个可能的位置来加载.env文件
-        let possible_env_paths = vec![
-            PathBuf::from(".env"),
-            PathBuf::from("../.env"),
-            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".env"),
-        ];
-
-        let mut env_loaded = false;
-        for path in possible_env_paths {
-            if path.exists() {
-                dotenv::from_path(path.as_path()).ok();
-                env_loaded = true;
-                break;
-            }
-        }
-
-        if !env_loaded {
-            eprintln!("警告: 无法找到.env文件，将使用默认环境变量");
+        // 从配置目录加载.env文件
+        let env_path = paths::env_file();
+        if dotenv::from_path(&env_path).is_err() {
+            eprintln!("警告: 无法找到.env文件{}，将使用默认环境变量", env_path.display());
         }
         
         // 尝试从配置文件加载
         let config_result = config::Config::builder()
-            .add_source(config::File::from(Path::new("config.toml")))
+            .add_source(config::File::from(paths::config_file()))
             .build()
-            .and_then(|config| config.try_deserialize());
+            .and_then(|config| config.try_deserialize::<Config>());
 
         // 如果配置文件加载失败，使用环境变量
         match config_result {
-            Ok(config) => Ok(config),
+            Ok(mut config) => {
+                config.auth.fill_from_env();
+                if config.admin.token.is_empty() {
+                    config.admin.token = env::var("ADMIN_TOKEN").unwrap_or_default();
+                }
+                Ok(config)
+            }
             Err(_) => Ok(Config {
                 server: ServerConfig {
                     host: "127.0.0.1".to_string(),
@@ -134,16 +1595,111 @@ impl Config {
                         .unwrap_or_else(|_| "8000".to_string())
                         .parse()
                         .unwrap_or(8000),
+                    strict_accept: false,
+                    listen: Vec::new(),
+                    data_dir: String::new(),
                 },
                 auth: AuthConfig {
                     api_key: env::var("API_KEY").unwrap_or_default(),
                     deepseek_api_key: env::var("DEEPSEEK_API_KEY").unwrap_or_default(),
                     anthropic_api_key: env::var("ANTHROPIC_API_KEY").unwrap_or_default(),
+                    managed_keys: false,
                 },
                 pricing: PricingConfig::default(),
+                rate_limits: HashMap::new(),
+                presets: HashMap::new(),
+                keys: Vec::new(),
+                secrets: SecretsConfig::default(),
+                admin: AdminConfig {
+                    token: env::var("ADMIN_TOKEN").unwrap_or_default(),
+                    tokens: Vec::new(),
+                },
+                canary: CanaryConfig::default(),
+                shadow: ShadowConfig::default(),
+                deadletter: DeadLetterConfig::default(),
+                model_aliases: HashMap::new(),
+                model_pools: HashMap::new(),
+                rules: Vec::new(),
+                openrouter: OpenRouterConfig::default(),
+                mcp: McpConfig::default(),
+                rag: RagConfig::default(),
+                files: FilesConfig::default(),
+                postprocess: PostprocessConfig::default(),
+                edit_blocks: EditBlocksConfig::default(),
+                language: LanguageConfig::default(),
+                system_prompt: SystemPromptConfig::default(),
+                hooks: HooksConfig::default(),
+                templates: TemplatesConfig::default(),
+                traces: TracesConfig::default(),
+                streams: StreamsConfig::default(),
+                usage: UsageConfig::default(),
+                tenants: HashMap::new(),
+                reports: ReportsConfig::default(),
+                spend_alerts: SpendAlertsConfig::default(),
+                request_log: RequestLogConfig::default(),
+                cache: CacheConfig::default(),
+                storage: StorageConfig::default(),
+                debug: DebugConfig::default(),
+                consensus: ConsensusConfig::default(),
+                refine: RefineConfig::default(),
+                envelopes: HashMap::new(),
+                deepseek: DeepSeekConfig::default(),
+                passthrough: PassthroughConfig::default(),
+                providers: HashMap::new(),
+                finish_reasons: HashMap::new(),
+                response: ResponseConfig::default(),
+                prompt_cache: PromptCacheConfig::default(),
+                reasoning_cache: ReasoningCacheConfig::default(),
+                concurrency: ConcurrencyConfig::default(),
+                pacing: PacingConfig::default(),
+                soft_limit: SoftLimitConfig::default(),
+                affinity: AffinityConfig::default(),
+                shedding: SheddingConfig::default(),
+                proxy: ProxyConfig::default(),
+                grpc: GrpcConfig::default(),
+                coalesce: CoalesceConfig::default(),
+                abuse: AbuseConfig::default(),
+                signing: SigningConfig::default(),
+                audit: AuditConfig::default(),
+                retention: RetentionConfig::default(),
             })
         }
     }
+
+    /// Resolves the relative file locations: runtime files against the data
+    /// directory and hook scripts against the config directory.
+    pub fn resolve_paths(&mut self) {
+        let data_dir = paths::data_dir(&self.server.data_dir);
+        for path in [
+            &mut self.traces.dir,
+            &mut self.files.dir,
+            &mut self.deadletter.dir,
+            &mut self.shadow.output_path,
+            &mut self.usage.dir,
+            &mut self.storage.keys_file,
+            &mut self.audit.path,
+        ] {
+            *path = paths::resolve(&data_dir, path).to_string_lossy().into_owned();
+        }
+        for script in &mut self.hooks.scripts {
+            *script = paths::resolve(paths::config_dir(), script).to_string_lossy().into_owned();
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Fills any keys missing from the config file with values from the environment.
+    fn fill_from_env(&mut self) {
+        if self.api_key.is_empty() {
+            self.api_key = env::var("API_KEY").unwrap_or_default();
+        }
+        if self.deepseek_api_key.is_empty() {
+            self.deepseek_api_key = env::var("DEEPSEEK_API_KEY").unwrap_or_default();
+        }
+        if self.anthropic_api_key.is_empty() {
+            self.anthropic_api_key = env::var("ANTHROPIC_API_KEY").unwrap_or_default();
+        }
+    }
 }
 
 // 为 AnthropicPricing 实现 Default trait
@@ -182,13 +1738,70 @@ impl Default for Config {
             server: ServerConfig {
                 host: "127.0.0.1".to_string(),
                 port: 3000,
+                strict_accept: false,
+                listen: Vec::new(),
+                data_dir: String::new(),
             },
             pricing: PricingConfig::default(),
             auth: AuthConfig {
                 api_key: "".to_string(),
                 deepseek_api_key: "".to_string(),
                 anthropic_api_key: "".to_string(),
-            }
+                managed_keys: false,
+            },
+            rate_limits: HashMap::new(),
+            presets: HashMap::new(),
+            keys: Vec::new(),
+            secrets: SecretsConfig::default(),
+            admin: AdminConfig::default(),
+            canary: CanaryConfig::default(),
+            shadow: ShadowConfig::default(),
+            deadletter: DeadLetterConfig::default(),
+            model_aliases: HashMap::new(),
+            model_pools: HashMap::new(),
+            rules: Vec::new(),
+            openrouter: OpenRouterConfig::default(),
+            mcp: McpConfig::default(),
+            rag: RagConfig::default(),
+            files: FilesConfig::default(),
+            postprocess: PostprocessConfig::default(),
+            edit_blocks: EditBlocksConfig::default(),
+            language: LanguageConfig::default(),
+            system_prompt: SystemPromptConfig::default(),
+            hooks: HooksConfig::default(),
+            templates: TemplatesConfig::default(),
+            traces: TracesConfig::default(),
+            streams: StreamsConfig::default(),
+            usage: UsageConfig::default(),
+            tenants: HashMap::new(),
+            reports: ReportsConfig::default(),
+            spend_alerts: SpendAlertsConfig::default(),
+            request_log: RequestLogConfig::default(),
+            cache: CacheConfig::default(),
+            storage: StorageConfig::default(),
+            debug: DebugConfig::default(),
+            consensus: ConsensusConfig::default(),
+            refine: RefineConfig::default(),
+            envelopes: HashMap::new(),
+            deepseek: DeepSeekConfig::default(),
+            passthrough: PassthroughConfig::default(),
+            providers: HashMap::new(),
+            finish_reasons: HashMap::new(),
+            response: ResponseConfig::default(),
+            prompt_cache: PromptCacheConfig::default(),
+            reasoning_cache: ReasoningCacheConfig::default(),
+            concurrency: ConcurrencyConfig::default(),
+            pacing: PacingConfig::default(),
+            soft_limit: SoftLimitConfig::default(),
+            affinity: AffinityConfig::default(),
+            shedding: SheddingConfig::default(),
+            proxy: ProxyConfig::default(),
+            grpc: GrpcConfig::default(),
+            coalesce: CoalesceConfig::default(),
+            abuse: AbuseConfig::default(),
+            signing: SigningConfig::default(),
+            audit: AuditConfig::default(),
+            retention: RetentionConfig::default(),
         }
     }
 }
@@ -0,0 +1,257 @@
+//! Dual-responder consensus mode.
+//!
+//! A non-streaming request with a `consensus` field sends the same
+//! reasoning-augmented prompt to two responder models in parallel. With the
+//! `choices` strategy both answers are returned, as choices 0 and 1; with
+//! `judge` a lightweight model compares them against the question and the
+//! reasoning and either picks one or merges them into a single answer.
+//!
+//! ```json
+//! {
+//!   "messages": [{ "role": "user", "content": "..." }],
+//!   "consensus": { "models": ["claude-3-5-sonnet-20241022", "glm"], "strategy": "judge" }
+//! }
+//! ```
+//!
+//! A failed responder leaves the other's answer as the only one, and a
+//! failed judge falls back to the first answer; the request fails only when
+//! both responders do. Tokens and cost of all calls are summed into the
+//! request's responder usage.
+
+use crate::{
+    clients::{
+        anthropic::{AnthropicResponse, ContentBlock, Usage},
+        hosts::WireApi,
+        stats,
+        AnthropicClient,
+    },
+    config::{ConsensusConfig, ConsensusStrategy},
+    error::{ApiError, Result},
+    handlers::{self, AppState},
+    models::{
+        request::{ApiConfig, ApiRequest, Message, Role},
+        response::ConsensusSummary,
+    },
+};
+use futures::future;
+
+/// Instructions of the judge when `[consensus] judge_prompt` is empty.
+const JUDGE_RUBRIC: &str = "You compare two answers to the same question, both written from the same reasoning. \
+Judge them on correctness first, then completeness, then clarity. \
+If one answer is better, reply with only its letter, A or B. \
+If each gets something right that the other misses, reply with a single answer that combines their correct parts, \
+written as a direct answer to the question, and nothing else.";
+
+/// Answers of the responder stage of a consensus request.
+#[derive(Debug)]
+pub struct Consensus {
+    /// The answers to return, in choice order.
+    pub answers: Vec<String>,
+    /// Tokens of every responder and judge call.
+    pub usage: Usage,
+    /// Cost of every responder and judge call.
+    pub cost: f64,
+    pub summary: ConsensusSummary,
+}
+
+impl Consensus {
+    /// The stage's result as a single responder response: the first
+    /// answer, the summed usage and the models joined with `+`.
+    pub fn response(&self) -> AnthropicResponse {
+        AnthropicResponse {
+            id: uuid::Uuid::new_v4().to_string(),
+            response_type: "message".to_string(),
+            role: "assistant".to_string(),
+            model: self.summary.models.join("+"),
+            content: vec![ContentBlock {
+                content_type: "text".to_string(),
+                text: self.answers.first().cloned().unwrap_or_default(),
+                extra: Default::default(),
+            }],
+            stop_reason: Some("end_turn".to_string()),
+            stop_sequence: None,
+            usage: self.usage.clone(),
+        }
+    }
+}
+
+/// Fills a consensus request's missing models and strategy from the
+/// `[consensus]` defaults.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` unless the request ends up with exactly
+/// two models.
+pub fn resolve(request: &mut ApiRequest, config: &ConsensusConfig) -> Result<()> {
+    let Some(consensus) = request.consensus.as_mut() else {
+        return Ok(());
+    };
+    if consensus.models.is_empty() {
+        consensus.models = config.models.clone();
+    }
+    consensus.strategy.get_or_insert(config.strategy);
+    if consensus.models.len() != 2 {
+        return Err(ApiError::BadRequest {
+            message: format!("consensus needs exactly two models, got {}", consensus.models.len()),
+        });
+    }
+    Ok(())
+}
+
+/// Runs the responder stage of a resolved consensus request.
+///
+/// `messages` and `system` are the prompt a single responder would get,
+/// with the reasoning already added; `reasoning` is shown to the judge.
+///
+/// # Errors
+///
+/// Returns the first responder's error if both responders fail.
+pub async fn run(
+    state: &AppState,
+    token: &str,
+    request: &ApiRequest,
+    messages: Vec<Message>,
+    system: Option<String>,
+    reasoning: &str,
+) -> Result<Consensus> {
+    let consensus = request.consensus.clone().unwrap_or_default();
+    let strategy = consensus.strategy.unwrap_or(state.config.consensus.strategy);
+    let calls = consensus.models.iter().map(|model| {
+        let config = with_model(&request.anthropic_config, model);
+        let messages = messages.clone();
+        let system = system.clone();
+        async move { respond(state, token, &config, messages, system).await }
+    });
+    let results = future::join_all(calls).await;
+
+    let mut usage = Usage::default();
+    let mut cost = 0.0;
+    let mut answers = Vec::new();
+    let mut failed = Vec::new();
+    let mut first_error = None;
+    for (model, result) in consensus.models.iter().zip(results) {
+        match result {
+            Ok((answer, call_usage, call_cost)) => {
+                usage.add(&call_usage);
+                cost += call_cost;
+                answers.push(answer);
+            }
+            Err(e) => {
+                tracing::warn!("共识模式中{}回答失败: {}", model, e);
+                failed.push(model.clone());
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if answers.is_empty() {
+        return Err(first_error.unwrap_or_else(|| ApiError::BadRequest {
+            message: "consensus needs exactly two models".to_string(),
+        }));
+    }
+
+    let mut verdict = None;
+    if strategy == ConsensusStrategy::Judge && answers.len() == 2 {
+        let question = request
+            .messages
+            .iter()
+            .rev()
+            .find(|message| message.role == Role::User)
+            .map_or("", |message| message.content.as_str());
+        match judge(state, token, &request.anthropic_config, question, reasoning, &answers).await {
+            Ok((reply, call_usage, call_cost)) => {
+                usage.add(&call_usage);
+                cost += call_cost;
+                let (answer, picked) = pick(reply, &answers);
+                verdict = picked.map(str::to_string);
+                answers = vec![answer];
+            }
+            Err(e) => {
+                tracing::warn!("共识裁判失败，使用第一个回答: {}", e);
+                answers.truncate(1);
+            }
+        }
+    } else if strategy == ConsensusStrategy::Judge {
+        tracing::info!("共识模式只有一个回答，跳过裁判");
+    }
+
+    Ok(Consensus {
+        answers,
+        usage,
+        cost,
+        summary: ConsensusSummary {
+            strategy,
+            models: consensus.models,
+            verdict,
+            failed,
+        },
+    })
+}
+
+/// The responder config of the request with its model replaced.
+fn with_model(config: &ApiConfig, model: &str) -> ApiConfig {
+    let mut config = config.clone();
+    if !config.body.is_object() {
+        config.body = serde_json::json!({});
+    }
+    config.body["model"] = serde_json::Value::from(model);
+    config
+}
+
+/// Calls one responder, returning its answer, usage and cost.
+async fn respond(
+    state: &AppState,
+    token: &str,
+    config: &ApiConfig,
+    messages: Vec<Message>,
+    system: Option<String>,
+) -> Result<(String, Usage, f64)> {
+    let route = handlers::stage_route(state, config).filter(|route| route.profile.wire == WireApi::ChatCompletions);
+    let client = AnthropicClient::new(token.to_string()).with_route(route.clone());
+    let model = config.body.get("model").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+
+    let started = std::time::Instant::now();
+    let response = state.mcp.chat(&client, messages, system, config).await;
+    stats::record_call(&model, false, response.as_ref().ok().map(|_| started.elapsed()));
+    let response = response?;
+
+    let cost = handlers::responder_cost(state, route.as_ref(), &response);
+    Ok((response.text().trim_start().to_string(), response.usage, cost))
+}
+
+/// Asks the judge model to pick or merge two answers.
+async fn judge(
+    state: &AppState,
+    token: &str,
+    config: &ApiConfig,
+    question: &str,
+    reasoning: &str,
+    answers: &[String],
+) -> Result<(String, Usage, f64)> {
+    let settings = &state.config.consensus;
+    let rubric = if settings.judge_prompt.trim().is_empty() {
+        JUDGE_RUBRIC
+    } else {
+        settings.judge_prompt.as_str()
+    };
+    let prompt = format!(
+        "<question>\n{}\n</question>\n\n<reasoning>\n{}\n</reasoning>\n\n<answer_a>\n{}\n</answer_a>\n\n<answer_b>\n{}\n</answer_b>",
+        question, reasoning, answers[0], answers[1]
+    );
+    let messages = vec![Message {
+        role: Role::User,
+        content: prompt,
+    }];
+    respond(state, token, &with_model(config, &settings.judge_model), messages, Some(rubric.to_string())).await
+}
+
+/// Reads the judge's reply: a lone letter picks that answer, anything else
+/// is the merged answer. An empty reply is no verdict and keeps the first.
+fn pick(reply: String, answers: &[String]) -> (String, Option<&'static str>) {
+    let letter = reply.trim().trim_end_matches('.').to_ascii_uppercase();
+    match letter.as_str() {
+        "A" => (answers[0].clone(), Some("a")),
+        "B" => (answers[1].clone(), Some("b")),
+        "" => (answers[0].clone(), None),
+        _ => (reply.trim().to_string(), Some("merged")),
+    }
+}
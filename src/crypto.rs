@@ -0,0 +1,109 @@
+//! Encryption of upstream API keys at rest.
+//!
+//! Upstream keys stored in the key store are written as
+//! `enc:v1:<base64(nonce || ciphertext)>` using AES-256-GCM with a master key
+//! taken from `DEEPCLAUDE_MASTER_KEY`. The master key may itself be a
+//! `vault://` or `aws-sm://` reference so it never has to live on disk.
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+/// Environment variable holding the master key.
+pub const MASTER_KEY_ENV: &str = "DEEPCLAUDE_MASTER_KEY";
+
+/// Returns true if the value is an encrypted key store entry.
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/// AES-256-GCM key used to encrypt and decrypt key store entries, and to
+/// sign with HMAC-SHA256.
+pub struct MasterKey {
+    cipher: Aes256Gcm,
+    /// HMAC key derived from the master key, so the cipher key is never
+    /// used for anything else.
+    mac: Vec<u8>,
+}
+
+impl MasterKey {
+    /// Builds a master key from its configured representation.
+    ///
+    /// A base64 value decoding to exactly 32 bytes is used as the raw key;
+    /// anything else is treated as a passphrase and hashed with SHA-256.
+    pub fn from_material(material: &str) -> Self {
+        let bytes = match STANDARD.decode(material.trim()) {
+            Ok(raw) if raw.len() == 32 => raw,
+            _ => Sha256::digest(material.trim().as_bytes()).to_vec(),
+        };
+        let mac = Sha256::new().chain_update(b"deepclaude.mac.").chain_update(&bytes).finalize().to_vec();
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)),
+            mac,
+        }
+    }
+
+    /// Reads the master key from the environment variable `var`.
+    ///
+    /// Returns `None` if the variable is unset or its secret reference
+    /// has not been resolved.
+    pub fn from_env(var: &str) -> Option<Self> {
+        // 不使用utils::get_env_var，避免在调试日志中输出主密钥
+        let value = std::env::var(var).unwrap_or_default();
+        if value.is_empty() {
+            return None;
+        }
+        crate::secrets::expose(&value).map(|material| Self::from_material(&material))
+    }
+
+    /// Encrypts a plaintext key into its `enc:v1:` representation.
+    pub fn encrypt(&self, plaintext: &str) -> anyhow::Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow::anyhow!("加密失败"))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend(ciphertext);
+        Ok(format!("{}{}", PREFIX, STANDARD.encode(payload)))
+    }
+
+    /// Decrypts an `enc:v1:` value back into the plaintext key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value is malformed or was encrypted
+    /// with a different master key.
+    pub fn decrypt(&self, value: &str) -> anyhow::Result<String> {
+        let encoded = value
+            .strip_prefix(PREFIX)
+            .ok_or_else(|| anyhow::anyhow!("不是加密的密钥值"))?;
+        let payload = STANDARD.decode(encoded)?;
+        if payload.len() <= NONCE_LEN {
+            anyhow::bail!("加密的密钥值过短");
+        }
+
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("解密失败，主密钥可能不匹配"))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    /// Returns the hex HMAC-SHA256 of `message` under a key derived from
+    /// the master key.
+    pub fn mac(&self, message: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.mac).expect("HMAC accepts keys of any length");
+        mac.update(message.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
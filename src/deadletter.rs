@@ -0,0 +1,207 @@
+//! Dead-letter capture of failed chat requests.
+//!
+//! With `[deadletter] enabled`, requests that fail because an upstream
+//! provider errored are written as received, with their per-stage headers
+//! stripped, as one JSON file per request under `[deadletter] dir`. They
+//! can be listed and replayed through the admin API once the upstream
+//! recovers; a successful replay removes the entry. Entries older than
+//! `max_age_days` are removed, and past `max_entries` the oldest are.
+//!
+//! A replay runs the whole pipeline again with the virtual key the request
+//! was made with, so its restrictions, presets, hooks and budgets apply. A
+//! request made with the caller's own upstream keys is not replayed, since
+//! those keys are not kept.
+//!
+//! Only failures surfaced before a response starts are captured. A stream
+//! that fails part-way has already delivered output to the client and is
+//! not replayable.
+//!
+//! Entries record the tenant the request acted for, so that erasing a
+//! tenant (see [`crate::retention`]) removes its entries too.
+
+use crate::{config::DeadLetterConfig, error::ApiError, models::request::ApiRequest};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A failed request waiting to be replayed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeadLetter {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub last_error: String,
+    pub attempts: u32,
+    /// Name of the virtual key the request was made with, if any.
+    pub key_name: Option<String>,
+    /// Tenant the request acted for, if any.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Whether the request, made without a virtual key, ran on the server's
+    /// own upstream keys rather than upstream keys sent by the caller.
+    #[serde(default)]
+    pub server_keys: bool,
+    pub request: ApiRequest,
+}
+
+/// File-backed dead-letter store.
+#[derive(Debug)]
+pub struct DeadLetterStore {
+    enabled: bool,
+    dir: PathBuf,
+    max_entries: usize,
+    max_age_days: u32,
+}
+
+impl DeadLetterStore {
+    /// Creates the store for the `[deadletter]` settings.
+    pub fn new(config: &DeadLetterConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            dir: PathBuf::from(&config.dir),
+            max_entries: config.max_entries,
+            max_age_days: config.max_age_days,
+        }
+    }
+
+    /// Returns true if the error is an upstream failure worth replaying later.
+    pub fn is_replayable(error: &ApiError) -> bool {
+        matches!(
+            error,
+            ApiError::DeepSeekError { .. }
+                | ApiError::AnthropicError { .. }
+                | ApiError::Internal { .. }
+                | ApiError::Other { .. }
+        )
+    }
+
+    /// Persists a failed request and returns its dead-letter id.
+    ///
+    /// Per-stage headers are dropped since they may carry credentials.
+    pub fn capture(
+        &self,
+        request: &ApiRequest,
+        key_name: Option<String>,
+        tenant: Option<String>,
+        server_keys: bool,
+        error: &ApiError,
+    ) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+
+        let mut request = request.clone();
+        request.deepseek_config.headers.clear();
+        request.anthropic_config.headers.clear();
+
+        let entry = DeadLetter {
+            id: uuid::Uuid::new_v4().to_string(),
+            created_at: Utc::now(),
+            last_error: error.to_string(),
+            attempts: 1,
+            key_name,
+            tenant,
+            server_keys,
+            request,
+        };
+        match self.save(&entry) {
+            Ok(()) => {
+                tracing::warn!("请求失败，已写入死信队列: {}", entry.id);
+                if let Err(e) = self.prune() {
+                    tracing::error!("清理死信队列失败: {}", e);
+                }
+                Some(entry.id)
+            }
+            Err(e) => {
+                tracing::error!("写入死信队列失败: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Lists all entries, oldest first.
+    pub fn list(&self) -> anyhow::Result<Vec<DeadLetter>> {
+        let mut entries = Vec::new();
+        if !self.dir.exists() {
+            return Ok(entries);
+        }
+        for file in std::fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                entries.push(serde_json::from_str(&std::fs::read_to_string(&path)?)?);
+            }
+        }
+        entries.sort_by_key(|entry: &DeadLetter| entry.created_at);
+        Ok(entries)
+    }
+
+    /// Loads an entry by id.
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<DeadLetter>> {
+        let Some(path) = self.path(id).filter(|path| path.exists()) else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_str(&std::fs::read_to_string(path)?)?))
+    }
+
+    /// Writes an entry, replacing any previous version.
+    pub fn save(&self, entry: &DeadLetter) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.path(&entry.id).ok_or_else(|| anyhow::anyhow!("无效的死信id: {}", entry.id))?;
+        std::fs::write(path, serde_json::to_string_pretty(entry)?)?;
+        Ok(())
+    }
+
+    /// Removes the entries older than `max_age_days` and, past
+    /// `max_entries`, the oldest ones, returning how many.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store cannot be read or an entry removed.
+    pub fn prune(&self) -> anyhow::Result<u64> {
+        let entries = self.list()?;
+        let cutoff = Utc::now() - chrono::Duration::days(i64::from(self.max_age_days));
+        let expired = entries
+            .iter()
+            .take_while(|entry| self.max_age_days > 0 && entry.created_at < cutoff)
+            .count();
+        let excess = match self.max_entries {
+            0 => 0,
+            max => entries.len().saturating_sub(max),
+        };
+        let removed = expired.max(excess);
+        for entry in &entries[..removed] {
+            self.remove(&entry.id)?;
+        }
+        Ok(removed as u64)
+    }
+
+    /// Removes the entries of a tenant, returning how many.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store cannot be read or an entry removed.
+    pub fn erase(&self, tenant: &str) -> anyhow::Result<u64> {
+        let mut removed = 0;
+        for entry in self.list()? {
+            if entry.tenant.as_deref() == Some(tenant) {
+                self.remove(&entry.id)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Deletes an entry.
+    pub fn remove(&self, id: &str) -> anyhow::Result<()> {
+        let path = self.path(id).ok_or_else(|| anyhow::anyhow!("无效的死信id: {}", id))?;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        // id来自URL路径，只允许uuid字符以防止路径穿越
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return None;
+        }
+        Some(self.dir.join(format!("{}.json", id)))
+    }
+}
@@ -0,0 +1,75 @@
+//! Runtime diagnostics for stuck streams.
+//!
+//! - `GET /debug/tasks` - list the live pipeline tasks and runtime load
+//!
+//! Lists every streaming task in the [`crate::streams`] registry, oldest
+//! first, with its age and how long it has been in its current stage, so a
+//! stream stuck waiting on an upstream stands out by its `state_age_ms`.
+//! The tokio runtime's worker count, live task count and global queue depth
+//! come alongside: a queue that keeps growing points at blocked workers
+//! rather than a slow upstream. The endpoint only exists when the server
+//! runs with `--diagnostics` or `[debug] tasks` enabled, and needs the
+//! admin token since it names the virtual keys of the requests.
+
+use crate::{
+    admin,
+    config::AdminRole,
+    error::{ApiError, Result},
+    handlers::AppState,
+};
+use axum::{extract::State, http::HeaderMap, Json};
+use serde_json::json;
+use std::sync::Arc;
+
+/// Lists the live pipeline tasks with their ages.
+///
+/// # Errors
+///
+/// Returns `ApiError::NotFound` unless diagnostics are enabled, and the
+/// admin API's errors for a missing or wrong token.
+#[utoipa::path(
+    get,
+    path = "/debug/tasks",
+    tag = "debug",
+    responses(
+        (status = 200, description = "The live pipeline tasks and runtime load", body = Object),
+        (status = 404, description = "Diagnostics are disabled", body = crate::error::ErrorResponse),
+    )
+)]
+pub async fn list_tasks(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Json<serde_json::Value>> {
+    if !state.config.debug.tasks {
+        return Err(ApiError::NotFound {
+            message: "Diagnostics are disabled; start with --diagnostics or set [debug] tasks".to_string(),
+        });
+    }
+    admin::require_admin(&state, &headers, AdminRole::Viewer)?;
+
+    let now = chrono::Utc::now();
+    let tasks: Vec<_> = state
+        .streams
+        .list()
+        .into_iter()
+        .map(|info| {
+            json!({
+                "id": info.id,
+                "kind": "stream",
+                "mode": info.mode,
+                "key_name": info.key_name,
+                "state": info.state,
+                "started_at": info.started_at,
+                "age_ms": (now - info.started_at).num_milliseconds(),
+                "state_age_ms": (now - info.state_since).num_milliseconds(),
+            })
+        })
+        .collect();
+    let metrics = tokio::runtime::Handle::current().metrics();
+
+    Ok(Json(json!({
+        "runtime": {
+            "workers": metrics.num_workers(),
+            "alive_tasks": metrics.num_alive_tasks(),
+            "global_queue_depth": metrics.global_queue_depth(),
+        },
+        "tasks": tasks,
+    })))
+}
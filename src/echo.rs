@@ -0,0 +1,160 @@
+//! Synthetic completions for load-testing the proxy.
+//!
+//! - `POST /debug/echo-completions` - stream a generated completion
+//!
+//! The endpoint answers with a chat completion stream made up by a local
+//! generator instead of an upstream, so the proxy's own streaming path can
+//! be load-tested without any upstream spend. The stream runs through the
+//! same machinery as `POST /v1/chat/completions`: it is registered with the
+//! stream registry (and so shows in `GET /admin/streams`, can be cancelled
+//! and is drained on shutdown), its deltas go through the event sink with
+//! the `[streams]` coalescing and slow consumer settings, and its chunks
+//! have the same shape as a real stream's.
+//!
+//! The body sets the shape of the stream: `reasoning_tokens` reasoning
+//! deltas followed by `tokens` answer deltas, one word each, at
+//! `tokens_per_sec` (0 for as fast as possible). Any other fields, such as
+//! `messages`, are ignored. The endpoint only exists with `[debug] echo`
+//! enabled, which is meant for development configurations.
+
+use crate::{
+    clients::deepseek::get_deepseek_default_model,
+    error::{ApiError, ErrorResponse, Result, SseResponse},
+    handlers::{self, AppState},
+    streams::{Delta, EventSink, StreamInfo, StreamState},
+};
+use axum::{extract::State, http::HeaderMap, response::sse::Event, Json};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use std::{convert::Infallible, sync::Arc, time::Duration};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use utoipa::ToSchema;
+
+/// Words the generated text is made of.
+const WORDS: [&str; 8] = ["lorem", "ipsum", "dolor", "sit", "amet", "consectetur", "adipiscing", "elit"];
+
+/// Body of `POST /debug/echo-completions`.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct EchoRequest {
+    pub reasoning_tokens: usize,
+    pub tokens: usize,
+    /// Deltas sent per second; 0 sends them as fast as the client reads.
+    pub tokens_per_sec: f64,
+}
+
+impl Default for EchoRequest {
+    fn default() -> Self {
+        Self {
+            reasoning_tokens: 0,
+            tokens: 256,
+            tokens_per_sec: 50.0,
+        }
+    }
+}
+
+/// Streams a generated completion.
+///
+/// # Errors
+///
+/// Returns `ApiError::NotFound` unless `[debug] echo` is enabled.
+#[utoipa::path(
+    post,
+    path = "/debug/echo-completions",
+    tag = "debug",
+    request_body = EchoRequest,
+    responses(
+        (status = 200, description = "Generated `chat.completion.chunk` events", body = String, content_type = "text/event-stream"),
+        (status = 404, description = "`[debug] echo` is disabled", body = ErrorResponse),
+    )
+)]
+pub async fn echo_completions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<EchoRequest>,
+) -> Result<SseResponse> {
+    if !state.config.debug.echo {
+        return Err(ApiError::NotFound {
+            message: "The echo endpoint is disabled; set [debug] echo to enable it".to_string(),
+        });
+    }
+
+    let streams_config = &state.config.streams;
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::result::Result<Event, Infallible>>(
+        streams_config.channel_capacity.max(1),
+    );
+    let stream_id = uuid::Uuid::new_v4().to_string();
+    let created = Utc::now().timestamp();
+    let chunk_id = stream_id.clone();
+    let model = get_deepseek_default_model();
+    let chunk_model = model.clone();
+    let mut sink = EventSink::new(
+        tx.clone(),
+        streams_config,
+        Box::new(move |kind, text| handlers::delta_event(&chunk_id, created, &chunk_model, kind, text)),
+    );
+
+    let info = StreamInfo {
+        id: stream_id.clone(),
+        started_at: Utc::now(),
+        state: StreamState::Reasoning,
+        state_since: Utc::now(),
+        model: model.clone(),
+        mode: "echo".to_string(),
+        key_name: state.key_store.lookup(&headers).map(|key| key.name.clone()),
+    };
+    let registry = state.clone();
+    registry.streams.spawn(info, tx, async move {
+        let chunk = |delta: serde_json::Value, finish_reason: Option<&str>| {
+            json!({
+                "id": stream_id,
+                "object": "chat.completion.chunk",
+                "created": created,
+                "model": model,
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+            })
+        };
+        if sink.send(chunk(json!({ "role": "assistant" }), None).to_string()).await.is_err() {
+            return;
+        }
+
+        let interval = (request.tokens_per_sec > 0.0).then(|| Duration::from_secs_f64(1.0 / request.tokens_per_sec));
+        let started = Instant::now();
+        let total = request.reasoning_tokens + request.tokens;
+        for index in 0..total {
+            // 按目标速率排定每个增量的发送时间，不累积睡眠误差
+            if let Some(interval) = interval {
+                tokio::time::sleep_until(started + interval.mul_f64(index as f64)).await;
+            }
+            let kind = if index < request.reasoning_tokens {
+                Delta::Reasoning
+            } else {
+                Delta::Content
+            };
+            if index == request.reasoning_tokens {
+                state.streams.set_state(&stream_id, StreamState::Responding);
+            }
+            let word = WORDS[index % WORDS.len()];
+            let text = if index + 1 < total { format!("{} ", word) } else { word.to_string() };
+            if sink.delta(kind, &text).await.is_err() {
+                tracing::debug!("回显流{}的客户端已断开", stream_id);
+                return;
+            }
+        }
+
+        state.streams.set_state(&stream_id, StreamState::Finishing);
+        let mut finish = chunk(json!({}), Some("stop"));
+        finish["usage"] = json!({
+            "prompt_tokens": 0,
+            "completion_tokens": total,
+            "total_tokens": total,
+        });
+        if sink.send(finish.to_string()).await.is_ok() {
+            let _ = sink.send("[DONE]").await;
+        }
+    }, || {});
+
+    Ok(SseResponse::new(ReceiverStream::new(rx)))
+}
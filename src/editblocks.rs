@@ -0,0 +1,101 @@
+//! Validation of SEARCH/REPLACE edit blocks in full mode answers.
+//!
+//! Full mode asks Claude for aider-style edit blocks:
+//!
+//! ```text
+//! src/main.rs
+//! <<<<<<< SEARCH
+//! old lines
+//! =======
+//! new lines
+//! >>>>>>> REPLACE
+//! ```
+//!
+//! Editor tooling silently drops blocks it cannot parse. When
+//! `[edit_blocks] validate` is set, the answer is checked and, if any block
+//! is malformed, Claude is asked once to resend it with the problems listed.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Marker {
+    Search,
+    Divider,
+    Replace,
+}
+
+fn marker(line: &str) -> Option<Marker> {
+    let line = line.trim_end();
+    let run = |c: char, rest: &str| {
+        let count = line.chars().take_while(|&x| x == c).count();
+        (5..=9).contains(&count) && line[count..].trim() == rest
+    };
+    if run('<', "SEARCH") {
+        Some(Marker::Search)
+    } else if run('=', "") {
+        Some(Marker::Divider)
+    } else if run('>', "REPLACE") {
+        Some(Marker::Replace)
+    } else {
+        None
+    }
+}
+
+/// Lists the syntax problems of the edit blocks in an answer, empty if
+/// they are all well-formed or there are none.
+pub fn problems(text: &str) -> Vec<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut problems = Vec::new();
+    // 当前块的起始行号和是否已经过分隔线
+    let mut open: Option<(usize, bool)> = None;
+
+    for (i, line) in lines.iter().enumerate() {
+        let number = i + 1;
+        match (marker(line), open) {
+            (Some(Marker::Search), None) => {
+                // 块前应有文件路径（跳过代码围栏行），紧跟上一个块时沿用其文件
+                let path = lines[..i]
+                    .iter()
+                    .rev()
+                    .map(|l| l.trim())
+                    .find(|l| !l.is_empty() && !l.starts_with("```"));
+                if path.is_none() {
+                    problems.push(format!("the block at line {} has no file path before it", number));
+                }
+                open = Some((number, false));
+            }
+            (Some(Marker::Search), Some((start, _))) => {
+                problems.push(format!("the block at line {} is missing its >>>>>>> REPLACE line", start));
+                open = Some((number, false));
+            }
+            (Some(Marker::Divider), Some((start, false))) => open = Some((start, true)),
+            // 只有SEARCH部分里的=======是分隔线，替换内容和块外的（如markdown标题下划线）都是正文
+            (Some(Marker::Divider), _) => {}
+            (Some(Marker::Replace), Some((_, true))) => open = None,
+            (Some(Marker::Replace), Some((start, false))) => {
+                problems.push(format!("the block at line {} is missing its ======= divider", start));
+                open = None;
+            }
+            (Some(Marker::Replace), None) => {
+                problems.push(format!("line {} has >>>>>>> REPLACE without a matching <<<<<<< SEARCH", number));
+            }
+            (None, _) => {}
+        }
+    }
+    if let Some((start, _)) = open {
+        problems.push(format!("the block at line {} is not closed with >>>>>>> REPLACE", start));
+    }
+
+    let fences = lines.iter().filter(|l| l.trim_start().starts_with("```")).count();
+    if fences % 2 == 1 {
+        problems.push("a code fence is opened but never closed".to_string());
+    }
+    problems
+}
+
+/// Follow-up message asking Claude to fix the listed problems.
+pub fn feedback(problems: &[String]) -> String {
+    format!(
+        "Your SEARCH/REPLACE blocks are malformed:\n- {}\n\nReply again with your complete answer, \
+         writing every edit as a valid SEARCH/REPLACE block.",
+        problems.join("\n- ")
+    )
+}
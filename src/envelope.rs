@@ -0,0 +1,150 @@
+//! Output envelopes for agent frameworks.
+//!
+//! An `[envelopes.<model>]` entry fixes the shape of the answers of a Claude
+//! stage model or alias to a JSON Schema, such as
+//! `{"answer": ..., "citations": [...], "confidence": ...}`, so that agent
+//! frameworks can parse the pipeline's output. The schema is added to the
+//! responder's system prompt and, for hosts routed through an alias, JSON
+//! mode is requested with `response_format`.
+//!
+//! The answer is validated with the other answer checks: one that does not
+//! match is sent back to the responder once with the validation errors. An
+//! answer still not matching after that fails the request, rather than
+//! handing a caller output it cannot parse. Streamed answers are buffered
+//! and sent as one chunk once validated.
+
+use crate::{
+    config::EnvelopeConfig,
+    error::{ApiError, Result},
+    models::request::ApiConfig,
+};
+use jsonschema::Validator;
+use std::{collections::HashMap, sync::Arc};
+
+/// A compiled envelope.
+pub struct Envelope {
+    schema: String,
+    validator: Validator,
+    json_mode: bool,
+}
+
+impl std::fmt::Debug for Envelope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Envelope").field("schema", &self.schema).field("json_mode", &self.json_mode).finish()
+    }
+}
+
+/// Compiled `[envelopes]`, by Claude stage model or alias.
+#[derive(Debug, Default)]
+pub struct Envelopes {
+    models: HashMap<String, Arc<Envelope>>,
+}
+
+impl Envelopes {
+    /// Compiles the configured schemas.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the model if a schema is not a valid JSON Schema.
+    pub fn new(config: &HashMap<String, EnvelopeConfig>) -> anyhow::Result<Self> {
+        let models = config
+            .iter()
+            .map(|(model, envelope)| {
+                let validator = jsonschema::validator_for(&envelope.schema)
+                    .map_err(|e| anyhow::anyhow!("模型{}的输出信封schema无效: {}", model, e))?;
+                let envelope = Envelope {
+                    schema: envelope.schema.to_string(),
+                    validator,
+                    json_mode: envelope.json_mode,
+                };
+                Ok((model.clone(), Arc::new(envelope)))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { models })
+    }
+
+    /// The envelope of a Claude stage model, if it has one.
+    pub fn get(&self, model: &str) -> Option<Arc<Envelope>> {
+        self.models.get(model).cloned()
+    }
+}
+
+impl Envelope {
+    /// Adds the envelope instruction to a system prompt.
+    pub fn instruct(&self, system: Option<String>) -> Option<String> {
+        let instruction = format!(
+            "Reply with only a JSON object that matches this JSON Schema, without code fences or any text around it:\n{}",
+            self.schema
+        );
+        Some(match system {
+            Some(system) => format!("{}\n\n{}", system, instruction),
+            None => instruction,
+        })
+    }
+
+    /// Asks a routed host for a JSON object answer, unless JSON mode is off
+    /// or the request sets its own `response_format`.
+    pub fn request_json(&self, config: &mut ApiConfig) {
+        if !self.json_mode {
+            return;
+        }
+        if !config.body.is_object() {
+            config.body = serde_json::json!({});
+        }
+        if let Some(body) = config.body.as_object_mut() {
+            body.entry("response_format").or_insert_with(|| serde_json::json!({ "type": "json_object" }));
+        }
+    }
+
+    /// Returns what is wrong with an answer, or nothing if it matches.
+    pub fn problems(&self, answer: &str) -> Vec<String> {
+        let value: serde_json::Value = match serde_json::from_str(unfence(answer)) {
+            Ok(value) => value,
+            Err(e) => return vec![format!("the answer is not valid JSON: {}", e)],
+        };
+        self.validator
+            .iter_errors(&value)
+            .map(|error| match error.instance_path.to_string() {
+                path if path.is_empty() => error.to_string(),
+                path => format!("{}: {}", path, error),
+            })
+            .collect()
+    }
+
+    /// Builds the revision request for an answer with problems.
+    pub fn feedback(&self, problems: &[String]) -> String {
+        format!(
+            "Your answer does not match the required JSON Schema:\n- {}\n\nReply again with only a JSON object that matches the schema.",
+            problems.join("\n- ")
+        )
+    }
+
+    /// Returns the answer's JSON without surrounding whitespace or code fence.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::AnthropicError` if the answer does not match.
+    pub fn enforce(&self, answer: &str) -> Result<String> {
+        let problems = self.problems(answer);
+        if !problems.is_empty() {
+            tracing::warn!("回答不符合输出信封: {}", problems.join("; "));
+            return Err(ApiError::AnthropicError {
+                message: format!("The answer does not match the output envelope: {}", problems.join("; ")),
+                type_: "invalid_envelope".to_string(),
+                param: None,
+                code: None,
+            });
+        }
+        Ok(unfence(answer).to_string())
+    }
+}
+
+/// Strips whitespace and a Markdown code fence around an answer.
+fn unfence(answer: &str) -> &str {
+    let answer = answer.trim();
+    let Some(inner) = answer.strip_prefix("```").and_then(|rest| rest.strip_suffix("```")) else {
+        return answer;
+    };
+    // 去掉开头围栏后的语言标记，如```json
+    inner.split_once('\n').map_or(inner, |(_, body)| body).trim()
+}
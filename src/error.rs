@@ -7,20 +7,21 @@
 //! - Type aliases for common Result types
 
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response, sse::Event},
     Json,
 };
 use serde::{Deserialize, Serialize};
 use std::convert::Infallible;
 use thiserror::Error;
+use utoipa::ToSchema;
 use tokio_stream::wrappers::ReceiverStream;
 
 /// Response structure for API errors.
 ///
 /// This structure provides a consistent format for error responses
 /// returned by the API endpoints.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct ErrorResponse {
     pub error: ErrorDetails,
 }
@@ -32,7 +33,7 @@ pub struct ErrorResponse {
 /// - The type of error that occurred
 /// - Optional parameter that caused the error
 /// - Optional error code for more specific error handling
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct ErrorDetails {
     pub message: String,
     #[serde(rename = "type")]
@@ -79,6 +80,44 @@ pub enum ApiError {
         code: Option<String>,
     },
 
+    #[error("Not found: {message}")]
+    NotFound {
+        message: String,
+    },
+
+    #[error("Unauthorized: {message}")]
+    Unauthorized {
+        message: String,
+    },
+
+    #[error("Forbidden: {message}")]
+    Forbidden {
+        message: String,
+    },
+
+    #[error("Conflict: {message}")]
+    Conflict {
+        message: String,
+    },
+
+    #[error("Rate limit budget exhausted for {provider}")]
+    RateLimited {
+        provider: String,
+        retry_after_secs: u64,
+    },
+
+    #[error("Monthly budget of organization {tenant} exhausted")]
+    BudgetExceeded {
+        tenant: String,
+        budget_usd: f64,
+    },
+
+    #[error("{message}")]
+    Overloaded {
+        message: String,
+        retry_after_secs: u64,
+    },
+
     #[error("Internal server error: {message}")]
     Internal {
         message: String,
@@ -90,93 +129,164 @@ pub enum ApiError {
     },
 }
 
-/// Implements conversion of API errors into HTTP responses.
-///
-/// Maps each error variant to an appropriate HTTP status code and
-/// formats the error details into a consistent JSON response structure.
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        let (status, error_response) = match &self {
+impl ApiError {
+    /// The status code and error details the error is reported with.
+    ///
+    /// Streams that fail after their response has started report the same
+    /// details in their final chunk.
+    pub fn details(&self) -> (StatusCode, ErrorDetails) {
+        match self {
             ApiError::BadRequest { message } => (
                 StatusCode::BAD_REQUEST,
-                ErrorResponse {
-                    error: ErrorDetails {
-                        message: message.clone(),
-                        type_: "bad_request".to_string(),
-                        param: None,
-                        code: None,
-                    },
+                ErrorDetails {
+                    message: message.clone(),
+                    type_: "bad_request".to_string(),
+                    param: None,
+                    code: None,
                 },
             ),
             ApiError::MissingHeader { header } => (
                 StatusCode::BAD_REQUEST,
-                ErrorResponse {
-                    error: ErrorDetails {
-                        message: format!("Missing required header: {}", header),
-                        type_: "missing_header".to_string(),
-                        param: Some(header.clone()),
-                        code: None,
-                    },
+                ErrorDetails {
+                    message: format!("Missing required header: {}", header),
+                    type_: "missing_header".to_string(),
+                    param: Some(header.clone()),
+                    code: None,
                 },
             ),
             ApiError::InvalidSystemPrompt => (
                 StatusCode::BAD_REQUEST,
-                ErrorResponse {
-                    error: ErrorDetails {
-                        message: "System prompt can only be provided once, either in root or messages array".to_string(),
-                        type_: "invalid_system_prompt".to_string(),
-                        param: None,
-                        code: None,
-                    },
+                ErrorDetails {
+                    message: "System prompt can only be provided once, either in root or messages array".to_string(),
+                    type_: "invalid_system_prompt".to_string(),
+                    param: None,
+                    code: None,
                 },
             ),
             ApiError::DeepSeekError { message, type_, param, code } => (
                 StatusCode::BAD_REQUEST,
-                ErrorResponse {
-                    error: ErrorDetails {
-                        message: format!("DeepSeek API Error: {}", message),
-                        type_: format!("deepseek_{}", type_),
-                        param: param.clone(),
-                        code: code.clone(),
-                    },
+                ErrorDetails {
+                    message: format!("DeepSeek API Error: {}", message),
+                    type_: format!("deepseek_{}", type_),
+                    param: param.clone(),
+                    code: code.clone(),
                 },
             ),
             ApiError::AnthropicError { message, type_, param, code } => (
                 StatusCode::BAD_REQUEST,
-                ErrorResponse {
-                    error: ErrorDetails {
-                        message: format!("Anthropic API Error: {}", message),
-                        type_: format!("anthropic_{}", type_),
-                        param: param.clone(),
-                        code: code.clone(),
-                    },
+                ErrorDetails {
+                    message: format!("Anthropic API Error: {}", message),
+                    type_: format!("anthropic_{}", type_),
+                    param: param.clone(),
+                    code: code.clone(),
+                },
+            ),
+            ApiError::NotFound { message } => (
+                StatusCode::NOT_FOUND,
+                ErrorDetails {
+                    message: message.clone(),
+                    type_: "not_found".to_string(),
+                    param: None,
+                    code: None,
+                },
+            ),
+            ApiError::Unauthorized { message } => (
+                StatusCode::UNAUTHORIZED,
+                ErrorDetails {
+                    message: message.clone(),
+                    type_: "authentication_error".to_string(),
+                    param: None,
+                    code: None,
+                },
+            ),
+            ApiError::Forbidden { message } => (
+                StatusCode::FORBIDDEN,
+                ErrorDetails {
+                    message: message.clone(),
+                    type_: "permission_denied".to_string(),
+                    param: None,
+                    code: None,
+                },
+            ),
+            ApiError::Conflict { message } => (
+                StatusCode::CONFLICT,
+                ErrorDetails {
+                    message: message.clone(),
+                    type_: "conflict".to_string(),
+                    param: None,
+                    code: None,
+                },
+            ),
+            ApiError::RateLimited { provider, retry_after_secs } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorDetails {
+                    message: format!(
+                        "Rate limit for {} is exhausted, retry in {}s",
+                        provider, retry_after_secs
+                    ),
+                    type_: "rate_limit_exceeded".to_string(),
+                    param: Some(provider.clone()),
+                    code: None,
+                },
+            ),
+            ApiError::BudgetExceeded { tenant, budget_usd } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorDetails {
+                    message: format!(
+                        "Organization '{}' has used its monthly budget of ${:.2}",
+                        tenant, budget_usd
+                    ),
+                    type_: "insufficient_quota".to_string(),
+                    param: None,
+                    code: None,
+                },
+            ),
+            ApiError::Overloaded { message, retry_after_secs } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorDetails {
+                    message: format!("{}, retry in {}s", message, retry_after_secs),
+                    type_: "overloaded".to_string(),
+                    param: None,
+                    code: None,
                 },
             ),
             ApiError::Internal { message } => (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                ErrorResponse {
-                    error: ErrorDetails {
-                        message: message.clone(),
-                        type_: "internal_error".to_string(),
-                        param: None,
-                        code: None,
-                    },
+                ErrorDetails {
+                    message: message.clone(),
+                    type_: "internal_error".to_string(),
+                    param: None,
+                    code: None,
                 },
             ),
             ApiError::Other { message } => (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                ErrorResponse {
-                    error: ErrorDetails {
-                        message: format!("Internal server error: {}", message),
-                        type_: "internal_error".to_string(),
-                        param: None,
-                        code: None,
-                    },
+                ErrorDetails {
+                    message: format!("Internal server error: {}", message),
+                    type_: "internal_error".to_string(),
+                    param: None,
+                    code: None,
                 },
             ),
-        };
+        }
+    }
+}
 
-        (status, Json(error_response)).into_response()
+/// Implements conversion of API errors into HTTP responses.
+///
+/// Maps each error variant to an appropriate HTTP status code and
+/// formats the error details into a consistent JSON response structure.
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, error) = self.details();
+        let mut response = (status, Json(ErrorResponse { error })).into_response();
+        if let ApiError::RateLimited { retry_after_secs, .. } | ApiError::Overloaded { retry_after_secs, .. } = &self {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(*retry_after_secs),
+            );
+        }
+        response
     }
 }
 
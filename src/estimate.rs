@@ -0,0 +1,134 @@
+//! Cost preview of a prospective chat request.
+//!
+//! - `POST /v1/estimate` - project the cost range of a chat request
+//!
+//! The body is a chat request as sent to `POST /v1/chat/completions`; it
+//! is resolved the same way (preset and DeepSeek defaults) and its prompt
+//! tokenized with the same estimate the rate limiter uses, but nothing is
+//! sent upstream. For each stage the response gives the model, the input
+//! tokens, the cost of that input read from the provider's cache and
+//! written uncached, and the cost of the stage's full `max_tokens` of
+//! output. The Claude stage's input also carries the reasoning, so its
+//! upper bound includes the DeepSeek stage's full output. The total range
+//! runs from every input cached with no output to every input uncached with
+//! every stage writing its limit. Prices come from `[pricing]`, or the
+//! OpenRouter catalog for models routed there, which has no cache price.
+
+use crate::{
+    error::{ApiError, Result},
+    handlers::{self, AppState},
+    models::request::{ApiConfig, ApiRequest},
+    ratelimit,
+};
+use axum::{extract::State, Json};
+use serde_json::json;
+use std::sync::Arc;
+
+/// Output limit of a stage whose request sets no `max_tokens`.
+const DEFAULT_MAX_TOKENS: u32 = 8192;
+
+fn max_tokens(config: &ApiConfig, default: u32) -> u32 {
+    config
+        .body
+        .get("max_tokens")
+        .and_then(|v| v.as_u64())
+        .map_or(default, |max| max.min(u32::MAX as u64) as u32)
+}
+
+/// Projects the cost range of a chat request.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` for an unknown preset or invalid DeepSeek
+/// options.
+#[utoipa::path(
+    post,
+    path = "/v1/estimate",
+    tag = "usage",
+    request_body = ApiRequest,
+    responses(
+        (status = 200, description = "Projected tokens and cost range per stage", body = Object),
+        (status = 400, description = "Invalid request", body = crate::error::ErrorResponse),
+    )
+)]
+pub async fn estimate(
+    State(state): State<Arc<AppState>>,
+    Json(mut request): Json<ApiRequest>,
+) -> Result<Json<serde_json::Value>> {
+    let config = &state.config;
+    request.apply_deepseek_options(&config.deepseek)?;
+    if let Some(name) = &request.preset {
+        let preset = config.presets.get(name).cloned().ok_or_else(|| ApiError::BadRequest {
+            message: format!("Unknown preset: {}", name),
+        })?;
+        request.apply_preset(&preset);
+    }
+    request.apply_deepseek_defaults(&config.deepseek);
+
+    let mode = request.mode.clone().unwrap_or_else(|| state.flags.default_mode());
+    let models = handlers::stage_models(&request);
+    let prompt_tokens = ratelimit::estimate_messages_tokens(&request.get_messages_with_system(&config.system_prompt, &mode, None));
+
+    // DeepSeek阶段的输入只有对话本身
+    let deepseek_output = max_tokens(&request.deepseek_config, DEFAULT_MAX_TOKENS);
+    let deepseek_route = handlers::stage_route(&state, &request.deepseek_config);
+    let (deepseek_cached, deepseek_uncached, deepseek_output_cost) =
+        match handlers::routed_cost(deepseek_route.as_ref(), prompt_tokens, 0) {
+            Some(input) => {
+                let output = handlers::routed_cost(deepseek_route.as_ref(), 0, deepseek_output).unwrap_or_default();
+                (input, input, output)
+            }
+            None => (
+                handlers::calculate_deepseek_cost(prompt_tokens, 0, 0, prompt_tokens, config),
+                handlers::calculate_deepseek_cost(prompt_tokens, 0, 0, 0, config),
+                handlers::calculate_deepseek_cost(0, deepseek_output, 0, 0, config),
+            ),
+        };
+
+    // Claude阶段的输入还包括DeepSeek的输出，最多为其max_tokens
+    let claude_default = if models[1].contains("claude-3-opus") { 4096 } else { DEFAULT_MAX_TOKENS };
+    let anthropic_output = max_tokens(&request.anthropic_config, claude_default);
+    let anthropic_input = prompt_tokens.saturating_add(deepseek_output);
+    let anthropic_route = handlers::stage_route(&state, &request.anthropic_config);
+    let (anthropic_cached, anthropic_uncached, anthropic_output_cost) =
+        match handlers::routed_cost(anthropic_route.as_ref(), prompt_tokens, 0) {
+            Some(input) => {
+                let max_input = handlers::routed_cost(anthropic_route.as_ref(), anthropic_input, 0).unwrap_or_default();
+                let output = handlers::routed_cost(anthropic_route.as_ref(), 0, anthropic_output).unwrap_or_default();
+                (input, max_input, output)
+            }
+            None => (
+                handlers::calculate_anthropic_cost(&models[1], 0, 0, 0, prompt_tokens, config),
+                handlers::calculate_anthropic_cost(&models[1], anthropic_input, 0, 0, 0, config),
+                handlers::calculate_anthropic_cost(&models[1], 0, anthropic_output, 0, 0, config),
+            ),
+        };
+
+    Ok(Json(json!({
+        "object": "cost_estimate",
+        "mode": mode,
+        "prompt_tokens": prompt_tokens,
+        "stages": [
+            {
+                "stage": "deepseek",
+                "model": models[0],
+                "input_tokens": { "min": prompt_tokens, "max": prompt_tokens },
+                "max_output_tokens": deepseek_output,
+                "input_cost_usd": { "cached": deepseek_cached, "uncached": deepseek_uncached },
+                "max_output_cost_usd": deepseek_output_cost,
+            },
+            {
+                "stage": "anthropic",
+                "model": models[1],
+                "input_tokens": { "min": prompt_tokens, "max": anthropic_input },
+                "max_output_tokens": anthropic_output,
+                "input_cost_usd": { "cached": anthropic_cached, "uncached": anthropic_uncached },
+                "max_output_cost_usd": anthropic_output_cost,
+            },
+        ],
+        "cost_usd": {
+            "min": deepseek_cached + anthropic_cached,
+            "max": deepseek_uncached + deepseek_output_cost + anthropic_uncached + anthropic_output_cost,
+        },
+    })))
+}
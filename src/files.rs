@@ -0,0 +1,464 @@
+//! Uploaded documents that chat requests can reference by id.
+//!
+//! - `POST /v1/files` - upload a text, markdown or PDF file (multipart field `file`)
+//! - `GET /v1/files/{id}` - file metadata
+//! - `DELETE /v1/files/{id}` - delete a file
+//!
+//! Text is extracted and split into chunks at upload time and stored as one
+//! JSON file per upload under `[files] dir`. Chat requests list files in
+//! `file_ids`; files that fit in `[files] max_context_chars` are added to the
+//! context whole. Larger ones are narrowed down to the chunks most similar to
+//! the latest user message when the retrieval stage is enabled (chunks are
+//! embedded on upload), and to their leading chunks otherwise.
+//!
+//! A file belongs to the virtual key it was uploaded with, or to that key's
+//! tenant for a tenant-bound key, the way usage is scoped. Only its owner
+//! can read its metadata, delete it or reference it in a chat request;
+//! anyone else gets a 404. Files uploaded without a key are shared by the
+//! callers without one. Erasing a tenant (see [`crate::retention`]) deletes
+//! its files, and the tenant export includes them.
+
+use crate::{
+    config::FilesConfig,
+    error::{ApiError, ErrorResponse, Result},
+    handlers::AppState,
+    keys::VirtualKey,
+    models::request::{ApiRequest, Role},
+    rag::{self, Chunk, Retriever},
+    tenants::Scope,
+};
+use axum::{
+    extract::{Multipart, Path, State},
+    http::HeaderMap,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{path::PathBuf, sync::Arc};
+
+const ID_PREFIX: &str = "file-";
+
+/// An uploaded file and its extracted text.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StoredFile {
+    pub id: String,
+    pub filename: String,
+    pub bytes: usize,
+    pub created_at: DateTime<Utc>,
+    /// Name of the virtual key the file was uploaded with, if any.
+    #[serde(default)]
+    pub key_name: Option<String>,
+    /// Tenant the file was uploaded for.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub chunks: Vec<FileChunk>,
+}
+
+/// A piece of a file's text, with its embedding if one was computed.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileChunk {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub embedding: Vec<f32>,
+}
+
+impl StoredFile {
+    /// Whether the caller with `key` owns the file: a tenant-bound key owns
+    /// its tenant's files, another key the files uploaded with it, and a
+    /// caller without a key the files uploaded without one.
+    pub fn owned_by(&self, key: Option<&VirtualKey>) -> bool {
+        match key {
+            Some(key) => match &key.tenant {
+                Some(tenant) => self.tenant.as_ref() == Some(tenant),
+                None => self.key_name.as_ref() == Some(&key.name),
+            },
+            None => self.key_name.is_none(),
+        }
+    }
+
+    /// The file's text, its chunks joined.
+    pub fn text(&self) -> String {
+        self.chunks.iter().map(|chunk| chunk.text.as_str()).collect::<Vec<_>>().join("\n\n")
+    }
+
+    fn chars(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.text.chars().count()).sum()
+    }
+
+    /// Metadata returned by the API, in the OpenAI file object shape.
+    pub fn describe(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "object": "file",
+            "filename": self.filename,
+            "bytes": self.bytes,
+            "created_at": self.created_at.timestamp(),
+            "chunks": self.chunks.len(),
+            "characters": self.chars(),
+        })
+    }
+}
+
+/// File-backed store of uploaded documents.
+#[derive(Debug)]
+pub struct FileStore {
+    config: FilesConfig,
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Creates the store for the `[files]` settings.
+    pub fn new(config: &FilesConfig) -> Self {
+        Self {
+            config: config.clone(),
+            dir: PathBuf::from(&config.dir),
+        }
+    }
+
+    /// Largest upload accepted, in bytes.
+    pub fn max_upload_bytes(&self) -> usize {
+        self.config.max_upload_bytes
+    }
+
+    /// Loads a file by id.
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<StoredFile>> {
+        let Some(path) = self.path(id).filter(|path| path.exists()) else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_str(&std::fs::read_to_string(path)?)?))
+    }
+
+    /// Loads a file by id if the caller with `key` owns it.
+    pub fn get_owned(&self, id: &str, key: Option<&VirtualKey>) -> anyhow::Result<Option<StoredFile>> {
+        Ok(self.get(id)?.filter(|file| file.owned_by(key)))
+    }
+
+    /// The files of a tenant, oldest first.
+    pub fn of_tenant(&self, tenant: &str) -> anyhow::Result<Vec<StoredFile>> {
+        let mut files: Vec<_> = self.all()?.into_iter().filter(|file| file.tenant.as_deref() == Some(tenant)).collect();
+        files.sort_by_key(|file| file.created_at);
+        Ok(files)
+    }
+
+    /// Deletes the files of a tenant, returning how many.
+    pub fn erase(&self, tenant: &str) -> anyhow::Result<u64> {
+        let mut removed = 0;
+        for file in self.of_tenant(tenant)? {
+            if self.remove(&file.id)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn all(&self) -> anyhow::Result<Vec<StoredFile>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            // 无法解析的文件不属于任何租户
+            if let Ok(file) = serde_json::from_str::<StoredFile>(&std::fs::read_to_string(&path)?) {
+                files.push(file);
+            }
+        }
+        Ok(files)
+    }
+
+    fn save(&self, file: &StoredFile) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.path(&file.id).ok_or_else(|| anyhow::anyhow!("无效的文件id: {}", file.id))?;
+        std::fs::write(path, serde_json::to_string(file)?)?;
+        Ok(())
+    }
+
+    /// Deletes a file, returning false if it does not exist.
+    pub fn remove(&self, id: &str) -> anyhow::Result<bool> {
+        match self.path(id).filter(|path| path.exists()) {
+            Some(path) => {
+                std::fs::remove_file(path)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        // id来自URL路径和请求体，只允许uuid字符以防止路径穿越
+        let hex = id.strip_prefix(ID_PREFIX)?;
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(self.dir.join(format!("{}.json", id)))
+    }
+
+    /// Adds the files a request made with `key` references to its context.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::NotFound` if a referenced file does not exist or
+    /// the caller does not own it.
+    pub async fn attach(&self, retriever: &Retriever, request: &mut ApiRequest, key: Option<&VirtualKey>) -> Result<()> {
+        if request.file_ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut files = Vec::new();
+        for id in &request.file_ids {
+            let file = self.get_owned(id, key)?.ok_or_else(|| ApiError::NotFound {
+                message: format!("File '{}' not found", id),
+            })?;
+            files.push(file);
+        }
+
+        let budget = self.config.max_context_chars;
+        let total: usize = files.iter().map(StoredFile::chars).sum();
+        let mut candidates: Vec<(Chunk, Vec<f32>)> = files
+            .into_iter()
+            .flat_map(|file| {
+                let filename = file.filename;
+                file.chunks.into_iter().enumerate().map(move |(i, chunk)| {
+                    let context = Chunk {
+                        text: chunk.text,
+                        source: format!("{}#{}", filename, i + 1),
+                        score: 1.0,
+                    };
+                    (context, chunk.embedding)
+                })
+            })
+            .collect();
+
+        if total > budget {
+            // 文件超出上下文预算时，优先保留与问题最相关的片段
+            let query = request.messages.iter().rev().find(|m| m.role == Role::User);
+            let ranked = retriever.enabled() && candidates.iter().all(|(_, embedding)| !embedding.is_empty());
+            if let (Some(query), true) = (query, ranked) {
+                match retriever.embed(&query.content).await {
+                    Ok(vector) => {
+                        for (chunk, embedding) in candidates.iter_mut() {
+                            chunk.score = rag::cosine_similarity(&vector, embedding);
+                        }
+                        candidates.sort_by(|a, b| b.0.score.total_cmp(&a.0.score));
+                    }
+                    Err(e) => tracing::warn!("嵌入问题失败，按顺序截取文件内容: {}", e),
+                }
+            } else {
+                tracing::warn!("引用的文件共{}字符，超出上下文预算{}，按顺序截取", total, budget);
+            }
+        }
+
+        let mut used = 0;
+        let mut chunks = Vec::new();
+        for (chunk, _) in candidates {
+            let chars = chunk.text.chars().count();
+            if used + chars > budget {
+                continue;
+            }
+            used += chars;
+            chunks.push(chunk);
+        }
+        rag::add_chunks(request, chunks);
+        Ok(())
+    }
+}
+
+/// Extracts the text of an upload based on its name and content type.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` for unsupported types, text that is not
+/// UTF-8, or PDFs the text cannot be extracted from.
+async fn extract_text(filename: &str, content_type: Option<&str>, data: Vec<u8>) -> Result<String> {
+    let extension = filename.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase()).unwrap_or_default();
+    let is_pdf = extension == "pdf" || content_type == Some("application/pdf");
+    if is_pdf {
+        // pdf-extract遇到损坏的文件可能panic，放到阻塞线程中隔离
+        return tokio::task::spawn_blocking(move || pdf_extract::extract_text_from_mem(&data))
+            .await
+            .map_err(|_| ApiError::BadRequest {
+                message: "Failed to extract text from PDF".to_string(),
+            })?
+            .map_err(|e| ApiError::BadRequest {
+                message: format!("Failed to extract text from PDF: {}", e),
+            });
+    }
+
+    let is_text = matches!(extension.as_str(), "txt" | "md" | "markdown" | "text")
+        || content_type.is_some_and(|t| t.starts_with("text/"));
+    if !is_text {
+        return Err(ApiError::BadRequest {
+            message: format!("Unsupported file type: {}", filename),
+        });
+    }
+    String::from_utf8(data).map_err(|_| ApiError::BadRequest {
+        message: "Text files must be UTF-8".to_string(),
+    })
+}
+
+/// Splits text into chunks of about `size` characters, at paragraph
+/// boundaries where possible.
+fn chunk_text(text: &str, size: usize) -> Vec<String> {
+    let size = size.max(1);
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        if !current.is_empty() && current.chars().count() + paragraph.chars().count() + 2 > size {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if paragraph.chars().count() > size {
+            // 超长段落按字符数硬切分
+            let chars: Vec<char> = paragraph.chars().collect();
+            for piece in chars.chunks(size) {
+                chunks.push(piece.iter().collect());
+            }
+            continue;
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(paragraph);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Uploads a document, owned by the caller's virtual key.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` if the `file` field is missing, the file
+/// is too large or of an unsupported type, or contains no text, and
+/// `ApiError::Unauthorized` without a virtual key where one is required.
+#[utoipa::path(
+    post,
+    path = "/v1/files",
+    tag = "files",
+    request_body(content = String, content_type = "multipart/form-data", description = "The document in a `file` field"),
+    responses(
+        (status = 200, description = "The stored file", body = Object),
+        (status = 400, description = "Missing, oversized or unreadable file", body = ErrorResponse),
+        (status = 401, description = "No virtual key", body = ErrorResponse),
+    )
+)]
+pub async fn upload_file(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Json<serde_json::Value>> {
+    let key = state.virtual_key(&headers)?;
+    let scope = Scope::resolve(&headers, key.as_deref())?;
+    let mut upload = None;
+    while let Some(field) = multipart.next_field().await.map_err(|e| ApiError::BadRequest {
+        message: format!("Invalid multipart body: {}", e.body_text()),
+    })? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let filename = field.file_name().unwrap_or("upload.txt").to_string();
+        let content_type = field.content_type().map(String::from);
+        let data = field.bytes().await.map_err(|e| ApiError::BadRequest {
+            message: format!("Failed to read upload: {}", e.body_text()),
+        })?;
+        upload = Some((filename, content_type, data));
+        break;
+    }
+    let (filename, content_type, data) = upload.ok_or_else(|| ApiError::BadRequest {
+        message: "Missing multipart field 'file'".to_string(),
+    })?;
+
+    let bytes = data.len();
+    let text = extract_text(&filename, content_type.as_deref(), data.to_vec()).await?;
+    let texts = chunk_text(&text, state.files.config.chunk_chars);
+    if texts.is_empty() {
+        return Err(ApiError::BadRequest {
+            message: "No text could be extracted from the file".to_string(),
+        });
+    }
+
+    // 启用检索时在上传时计算嵌入，供超长文件按相关度截取
+    let mut embeddings = Vec::new();
+    if state.retriever.enabled() {
+        match state.retriever.embed_batch(&texts).await {
+            Ok(vectors) => embeddings = vectors,
+            Err(e) => tracing::warn!("计算文件嵌入失败，将按顺序截取: {}", e),
+        }
+    }
+    let mut embeddings = embeddings.into_iter();
+
+    let file = StoredFile {
+        id: format!("{}{}", ID_PREFIX, uuid::Uuid::new_v4().simple()),
+        filename,
+        bytes,
+        created_at: Utc::now(),
+        key_name: key.map(|key| key.name.clone()),
+        tenant: scope.tenant,
+        chunks: texts
+            .into_iter()
+            .map(|text| FileChunk {
+                text,
+                embedding: embeddings.next().unwrap_or_default(),
+            })
+            .collect(),
+    };
+    state.files.save(&file)?;
+    tracing::info!("已保存上传文件{}，共{}个片段", file.id, file.chunks.len());
+
+    Ok(Json(file.describe()))
+}
+
+/// Returns a file's metadata.
+#[utoipa::path(
+    get,
+    path = "/v1/files/{id}",
+    tag = "files",
+    params(("id" = String, Path, description = "File id")),
+    responses(
+        (status = 200, description = "The file's metadata", body = Object),
+        (status = 404, description = "No such file of the caller's", body = ErrorResponse),
+    )
+)]
+pub async fn get_file(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>> {
+    let key = state.virtual_key(&headers)?;
+    let file = state.files.get_owned(&id, key.as_deref())?.ok_or_else(|| ApiError::NotFound {
+        message: format!("File '{}' not found", id),
+    })?;
+    Ok(Json(file.describe()))
+}
+
+/// Deletes a file.
+#[utoipa::path(
+    delete,
+    path = "/v1/files/{id}",
+    tag = "files",
+    params(("id" = String, Path, description = "File id")),
+    responses(
+        (status = 200, description = "The file was deleted", body = Object),
+        (status = 404, description = "No such file of the caller's", body = ErrorResponse),
+    )
+)]
+pub async fn delete_file(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>> {
+    let key = state.virtual_key(&headers)?;
+    // 其他调用方的文件按不存在处理
+    if state.files.get_owned(&id, key.as_deref())?.is_none() || !state.files.remove(&id)? {
+        return Err(ApiError::NotFound {
+            message: format!("File '{}' not found", id),
+        });
+    }
+    Ok(Json(json!({ "id": id, "object": "file", "deleted": true })))
+}
@@ -0,0 +1,150 @@
+//! Runtime feature flags.
+//!
+//! `GET /admin/flags` shows, and `PUT /admin/flags` changes, the settings
+//! that can be flipped without a restart: the default mode of requests that
+//! name none (otherwise `MODE` in `.env`), the response cache, reasoning
+//! reuse, request coalescing and upstream payload capture. Changes apply to requests that start
+//! afterwards and last until the process exits; the config file still
+//! decides the values a restart starts with. Every change is logged and
+//! kept in an audit trail of the last `AUDIT_ENTRIES`, recording who made
+//! it, when, and each flag's old and new value.
+
+use crate::{
+    error::{ApiError, Result},
+    handlers::AppState,
+    utils,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, RwLock},
+};
+use utoipa::ToSchema;
+
+/// How many changes the audit trail keeps.
+const AUDIT_ENTRIES: usize = 200;
+
+/// Modes a default can be set to.
+const MODES: &[&str] = &["normal", "full"];
+
+/// The flags in effect.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct FlagValues {
+    /// Mode of requests that name none.
+    pub default_mode: String,
+    pub response_cache: bool,
+    pub reasoning_cache: bool,
+    pub coalescing: bool,
+    /// Keep the bodies sent upstream, see [`crate::payloads`].
+    pub payload_capture: bool,
+}
+
+/// A change to some of the flags; omitted flags keep their value.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlagUpdate {
+    /// A mode, or an empty string to go back to `MODE` in `.env`.
+    pub default_mode: Option<String>,
+    pub response_cache: Option<bool>,
+    pub reasoning_cache: Option<bool>,
+    pub coalescing: Option<bool>,
+    pub payload_capture: Option<bool>,
+}
+
+/// One flag change of the audit trail.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FlagChange {
+    pub at: DateTime<Utc>,
+    pub changed_by: String,
+    pub flag: String,
+    pub from: Value,
+    pub to: Value,
+}
+
+/// Flags that live outside their subsystems, and the audit trail.
+#[derive(Default)]
+pub struct Flags {
+    default_mode: RwLock<Option<String>>,
+    audit: Mutex<VecDeque<FlagChange>>,
+}
+
+impl Flags {
+    /// The mode of requests that name none.
+    pub fn default_mode(&self) -> String {
+        self.default_mode.read().unwrap().clone().unwrap_or_else(utils::get_mode)
+    }
+
+    /// The changes made so far, oldest first.
+    pub fn audit(&self) -> Vec<FlagChange> {
+        self.audit.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// The flags in effect.
+pub fn current(state: &AppState) -> FlagValues {
+    FlagValues {
+        default_mode: state.flags.default_mode(),
+        response_cache: state.cache.enabled(),
+        reasoning_cache: state.reasoning.enabled(),
+        coalescing: state.coalescer.enabled(),
+        payload_capture: state.payloads.enabled(),
+    }
+}
+
+/// Applies a change and records it in the audit trail.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` for an unknown mode; nothing is changed in
+/// that case.
+pub fn apply(state: &AppState, update: FlagUpdate, changed_by: &str) -> Result<FlagValues> {
+    if let Some(mode) = update.default_mode.as_deref().filter(|mode| !mode.is_empty()) {
+        if !MODES.contains(&mode) {
+            return Err(ApiError::BadRequest {
+                message: format!("Unknown mode '{}'; expected one of: {}", mode, MODES.join(", ")),
+            });
+        }
+    }
+
+    let before = current(state);
+    if let Some(mode) = update.default_mode {
+        *state.flags.default_mode.write().unwrap() = (!mode.is_empty()).then_some(mode);
+    }
+    if let Some(enabled) = update.response_cache {
+        state.cache.set_enabled(enabled);
+    }
+    if let Some(enabled) = update.reasoning_cache {
+        state.reasoning.set_enabled(enabled);
+    }
+    if let Some(enabled) = update.coalescing {
+        state.coalescer.set_enabled(enabled);
+    }
+    if let Some(enabled) = update.payload_capture {
+        state.payloads.set_enabled(enabled);
+    }
+    let after = current(state);
+
+    let (old, new) = (json!(before), json!(after));
+    let mut audit = state.flags.audit.lock().unwrap();
+    for (flag, to) in new.as_object().into_iter().flatten() {
+        let from = &old[flag];
+        if from == to {
+            continue;
+        }
+        tracing::info!("{}将运行时开关{}从{}改为{}", changed_by, flag, from, to);
+        state.audit.record(changed_by, "flags.update", json!({ "flag": flag, "from": from, "to": to }));
+        audit.push_back(FlagChange {
+            at: Utc::now(),
+            changed_by: changed_by.to_string(),
+            flag: flag.clone(),
+            from: from.clone(),
+            to: to.clone(),
+        });
+        if audit.len() > AUDIT_ENTRIES {
+            audit.pop_front();
+        }
+    }
+    Ok(after)
+}
@@ -0,0 +1,347 @@
+//! Gemini-compatible API surface.
+//!
+//! - `POST /v1beta/models/{model}:generateContent` - answer one request
+//! - `POST /v1beta/models/{model}:streamGenerateContent` - stream the answer
+//!
+//! Tools that only speak the Google API shape can use the pipeline through
+//! these routes. The request's `contents` become the chat messages (role
+//! `model` is the assistant), `systemInstruction` the system prompt, and
+//! `generationConfig` the Claude stage's sampling parameters; the model in
+//! the path is echoed back like an OpenAI request's `model`. The request
+//! then goes through `POST /v1/chat/completions`, so keys, presets, hooks
+//! and the response cache apply as usual. The key may come in
+//! `x-goog-api-key` or the `key` query parameter instead of
+//! `Authorization`.
+//!
+//! The answer is a `GenerateContentResponse` whose single candidate holds
+//! the reasoning as a part with `thought: true` followed by the answer.
+//! Streams are sent as SSE with `alt=sse`, and as a JSON array of responses
+//! otherwise, one response per chunk of the pipeline's stream. Errors keep
+//! their status code and are reported in Google's error shape.
+
+use crate::{
+    clients::parse::{sse_data, LineBuffer},
+    error::{ApiError, Result},
+    handlers::{self, AppState},
+    models::{request::ApiRequest, response::OpenAICompatibleResponse},
+};
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::Arc};
+
+/// A message of a Gemini request.
+#[derive(Debug, Deserialize)]
+pub struct Content {
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub parts: Vec<Part>,
+}
+
+/// A part of a Gemini message; only text parts are supported.
+#[derive(Debug, Deserialize)]
+pub struct Part {
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+/// Sampling parameters of a Gemini request.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GenerationConfig {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u32>,
+    pub max_output_tokens: Option<u32>,
+    pub stop_sequences: Vec<String>,
+}
+
+/// Body of `generateContent` and `streamGenerateContent`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentRequest {
+    pub contents: Vec<Content>,
+    #[serde(default, alias = "system_instruction")]
+    pub system_instruction: Option<Content>,
+    #[serde(default, alias = "generation_config")]
+    pub generation_config: Option<GenerationConfig>,
+}
+
+/// Answers `generateContent` and `streamGenerateContent`.
+///
+/// Errors are returned in Google's error shape with their status code.
+pub async fn generate_content(
+    state: State<Arc<AppState>>,
+    model_method: Path<String>,
+    query: Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Json<GenerateContentRequest>,
+) -> Response {
+    match serve(state, model_method, query, headers, body).await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => google_error(response).await,
+        Err(e) => google_error(e.into_response()).await,
+    }
+}
+
+/// Serves a Gemini request through the chat pipeline.
+///
+/// # Errors
+///
+/// Returns `ApiError::NotFound` for a method other than the two above, and
+/// `ApiError::BadRequest` for a part that is not text.
+async fn serve(
+    State(state): State<Arc<AppState>>,
+    Path(model_method): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    mut headers: HeaderMap,
+    Json(body): Json<GenerateContentRequest>,
+) -> Result<Response> {
+    let (model, stream) = match model_method.rsplit_once(':') {
+        Some((model, "generateContent")) => (model.to_string(), false),
+        Some((model, "streamGenerateContent")) => (model.to_string(), true),
+        _ => {
+            return Err(ApiError::NotFound {
+                message: format!("Unknown method: {}", model_method),
+            })
+        }
+    };
+
+    // Google客户端用x-goog-api-key或key参数传递密钥
+    if !headers.contains_key(header::AUTHORIZATION) {
+        let key = headers
+            .get("x-goog-api-key")
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| query.get("key").map(String::as_str))
+            .and_then(|key| HeaderValue::from_str(&format!("Bearer {}", key)).ok());
+        if let Some(key) = key {
+            headers.insert(header::AUTHORIZATION, key);
+        }
+    }
+
+    let request = chat_request(body, &model, stream)?;
+    let response = handlers::handle_chat(State(state), headers, Json(request)).await?;
+    if !stream {
+        let (parts, body) = response.into_parts();
+        let bytes = axum::body::to_bytes(body, usize::MAX)
+            .await
+            .map_err(|e| ApiError::Internal { message: format!("读取回答失败: {}", e) })?;
+        let response: OpenAICompatibleResponse = serde_json::from_slice(&bytes)
+            .map_err(|e| ApiError::Internal { message: format!("解析回答失败: {}", e) })?;
+        let mut answer = Json(from_completion(&response)).into_response();
+        answer.headers_mut().extend(parts.headers.into_iter().filter_map(|(name, value)| {
+            name.filter(|name| *name != header::CONTENT_TYPE && *name != header::CONTENT_LENGTH)
+                .map(|name| (name, value))
+        }));
+        return Ok(answer);
+    }
+
+    let sse = query.get("alt").is_some_and(|alt| alt == "sse");
+    let (mut parts, body) = response.into_parts();
+    let mut chunks = body.into_data_stream();
+    let translated = async_stream::stream! {
+        let mut lines = LineBuffer::default();
+        let mut first = true;
+        if !sse {
+            yield Ok::<_, std::convert::Infallible>("[".to_string());
+        }
+        'chunks: while let Some(chunk) = chunks.next().await {
+            let Ok(chunk) = chunk else { break };
+            for line in lines.push(&chunk) {
+                let Some(data) = sse_data(&line) else { continue };
+                if data == "[DONE]" {
+                    break 'chunks;
+                }
+                let Ok(chunk) = serde_json::from_str::<Value>(data) else { continue };
+                let Some(event) = from_chunk(&chunk) else { continue };
+                yield Ok(match (sse, first) {
+                    (true, _) => format!("data: {}\n\n", event),
+                    (false, true) => event.to_string(),
+                    (false, false) => format!(",\n{}", event),
+                });
+                first = false;
+            }
+        }
+        if !sse {
+            yield Ok("]".to_string());
+        }
+    };
+    let content_type = if sse { "text/event-stream" } else { "application/json" };
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, Body::from_stream(translated)))
+}
+
+/// Builds the chat request of a Gemini request.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` for a part that is not text or an unknown role.
+fn chat_request(body: GenerateContentRequest, model: &str, stream: bool) -> Result<ApiRequest> {
+    let text = |content: &Content| -> Result<String> {
+        content
+            .parts
+            .iter()
+            .map(|part| {
+                part.text.clone().ok_or_else(|| ApiError::BadRequest {
+                    message: "Only text parts are supported".to_string(),
+                })
+            })
+            .collect()
+    };
+    let messages = body
+        .contents
+        .iter()
+        .map(|content| {
+            let role = match content.role.as_deref() {
+                None | Some("user") => "user",
+                Some("model") => "assistant",
+                Some(role) => {
+                    return Err(ApiError::BadRequest {
+                        message: format!("Unknown role: {}", role),
+                    })
+                }
+            };
+            Ok(json!({ "role": role, "content": text(content)? }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let system = body.system_instruction.as_ref().map(text).transpose()?;
+
+    let mut params = serde_json::Map::new();
+    if let Some(config) = body.generation_config {
+        let fields = [
+            ("temperature", config.temperature.map(Value::from)),
+            ("top_p", config.top_p.map(Value::from)),
+            ("top_k", config.top_k.map(Value::from)),
+            ("max_tokens", config.max_output_tokens.map(Value::from)),
+            ("stop_sequences", (!config.stop_sequences.is_empty()).then(|| json!(config.stop_sequences))),
+        ];
+        params.extend(fields.into_iter().filter_map(|(name, value)| Some((name.to_string(), value?))));
+    }
+
+    serde_json::from_value(json!({
+        "model": model,
+        "stream": stream,
+        "system": system,
+        "messages": messages,
+        "anthropic_config": { "headers": {}, "body": params },
+    }))
+    .map_err(|e| ApiError::BadRequest { message: e.to_string() })
+}
+
+/// Gemini finish reason of an OpenAI `finish_reason`.
+fn finish_reason(reason: &str) -> &'static str {
+    match reason {
+        "stop" => "STOP",
+        "length" => "MAX_TOKENS",
+        "content_filter" => "SAFETY",
+        _ => "OTHER",
+    }
+}
+
+/// Gemini usage of an OpenAI `usage` object.
+fn usage_metadata(usage: &Value) -> Value {
+    json!({
+        "promptTokenCount": usage["prompt_tokens"],
+        "candidatesTokenCount": usage["completion_tokens"],
+        "totalTokenCount": usage["total_tokens"],
+    })
+}
+
+/// Translates a non-streaming answer.
+fn from_completion(response: &OpenAICompatibleResponse) -> Value {
+    let candidates: Vec<Value> = response
+        .choices
+        .iter()
+        .map(|choice| {
+            let mut parts = Vec::new();
+            if let Some(reasoning) = choice.message.reasoning_content.as_ref().filter(|r| !r.is_empty()) {
+                parts.push(json!({ "text": reasoning, "thought": true }));
+            }
+            parts.push(json!({ "text": choice.message.content }));
+            json!({
+                "index": choice.index,
+                "content": { "role": "model", "parts": parts },
+                "finishReason": finish_reason(&choice.finish_reason),
+            })
+        })
+        .collect();
+    json!({
+        "candidates": candidates,
+        "usageMetadata": usage_metadata(&json!(response.usage)),
+        "modelVersion": response.model,
+        "responseId": response.id,
+    })
+}
+
+/// Translates a chunk of the pipeline's stream, or `None` for a chunk
+/// that carries nothing, such as the role.
+fn from_chunk(chunk: &Value) -> Option<Value> {
+    if let Some(error) = chunk.get("error") {
+        return Some(json!({ "error": error }));
+    }
+    let choice = &chunk["choices"][0];
+    let mut parts = Vec::new();
+    if let Some(reasoning) = choice["delta"]["reasoning_content"].as_str().filter(|r| !r.is_empty()) {
+        parts.push(json!({ "text": reasoning, "thought": true }));
+    }
+    if let Some(content) = choice["delta"]["content"].as_str().filter(|c| !c.is_empty()) {
+        parts.push(json!({ "text": content }));
+    }
+    let finish = choice["finish_reason"].as_str();
+    if parts.is_empty() && finish.is_none() {
+        return None;
+    }
+
+    let mut candidate = json!({ "index": 0, "content": { "role": "model", "parts": parts } });
+    if let Some(finish) = finish {
+        candidate["finishReason"] = json!(finish_reason(finish));
+    }
+    let mut event = json!({ "candidates": [candidate], "modelVersion": chunk["model"], "responseId": chunk["id"] });
+    if let Some(usage) = chunk.get("usage").filter(|usage| usage.is_object()) {
+        event["usageMetadata"] = usage_metadata(usage);
+    }
+    Some(event)
+}
+
+/// Rewrites an error response of the chat pipeline in Google's shape.
+async fn google_error(response: Response) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+    let error: Value = serde_json::from_slice(&bytes).unwrap_or_default();
+    let status = parts.status;
+    let message = error["error"]["message"]
+        .as_str()
+        .unwrap_or_else(|| status.canonical_reason().unwrap_or_default());
+    let body = json!({
+        "error": {
+            "code": status.as_u16(),
+            "message": message,
+            "status": google_status(status),
+        }
+    });
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Response::from_parts(parts, Body::from(body.to_string()))
+}
+
+/// Google RPC status name of an HTTP status.
+fn google_status(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        400 => "INVALID_ARGUMENT",
+        401 => "UNAUTHENTICATED",
+        403 => "PERMISSION_DENIED",
+        404 => "NOT_FOUND",
+        429 => "RESOURCE_EXHAUSTED",
+        503 => "UNAVAILABLE",
+        _ => "INTERNAL",
+    }
+}
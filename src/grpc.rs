@@ -0,0 +1,276 @@
+//! gRPC interface of the chat pipeline.
+//!
+//! With `[grpc] listen` set, the `deepclaude.v1.DeepClaude` service of
+//! `proto/deepclaude.proto` is served on that address next to the HTTP
+//! server, for services that prefer protobuf contracts and HTTP/2
+//! multiplexing over parsing SSE. Its `Chat` RPC mirrors
+//! `POST /v1/chat/completions` with `stream: true`: the request goes
+//! through the same handler, so keys, presets, hooks, limits and usage
+//! records apply as usual, and each chunk of the stream becomes a
+//! `ChatEvent` carrying a reasoning or answer delta, the finish reason and
+//! usage, or a mid-stream error.
+//!
+//! Credentials and other headers are read from the request metadata under
+//! their HTTP names. An error raised before the stream starts is returned
+//! as the call's status, with the code matching its HTTP status.
+//!
+//! The messages below are kept in step with the `.proto` file by hand, so
+//! the build needs no `protoc`; `build.rs` generates the service code.
+
+use crate::{
+    clients::parse::{sse_data, LineBuffer},
+    error::ApiError,
+    handlers::{self, AppState},
+    models::request::ApiRequest,
+};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use futures::{Stream, StreamExt};
+use serde_json::{json, Value};
+use std::{future::Future, pin::Pin, sync::Arc};
+use tonic::{Request, Response, Status};
+
+/// Service code generated by `build.rs`.
+#[allow(clippy::all)]
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/deepclaude.v1.DeepClaude.rs"));
+}
+
+pub use proto::{deep_claude_client::DeepClaudeClient, deep_claude_server::DeepClaudeServer};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ChatRequest {
+    #[prost(string, tag = "1")]
+    pub model: String,
+    #[prost(string, tag = "2")]
+    pub mode: String,
+    #[prost(string, tag = "3")]
+    pub system: String,
+    #[prost(message, repeated, tag = "4")]
+    pub messages: Vec<Message>,
+    #[prost(string, tag = "5")]
+    pub preset: String,
+    #[prost(string, tag = "6")]
+    pub user: String,
+    #[prost(string, tag = "7")]
+    pub deepseek_body_json: String,
+    #[prost(string, tag = "8")]
+    pub anthropic_body_json: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Message {
+    #[prost(string, tag = "1")]
+    pub role: String,
+    #[prost(string, tag = "2")]
+    pub content: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ChatEvent {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub model: String,
+    #[prost(oneof = "chat_event::Event", tags = "3, 4, 5")]
+    pub event: Option<chat_event::Event>,
+}
+
+pub mod chat_event {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Event {
+        #[prost(message, tag = "3")]
+        Delta(super::Delta),
+        #[prost(message, tag = "4")]
+        Finish(super::Finish),
+        #[prost(message, tag = "5")]
+        Error(super::Error),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Delta {
+    #[prost(enumeration = "delta::Kind", tag = "1")]
+    pub kind: i32,
+    #[prost(string, tag = "2")]
+    pub text: String,
+}
+
+pub mod delta {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        Reasoning = 0,
+        Content = 1,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Finish {
+    #[prost(string, tag = "1")]
+    pub finish_reason: String,
+    #[prost(message, optional, tag = "2")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Usage {
+    #[prost(uint32, tag = "1")]
+    pub prompt_tokens: u32,
+    #[prost(uint32, tag = "2")]
+    pub completion_tokens: u32,
+    #[prost(uint32, tag = "3")]
+    pub total_tokens: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Error {
+    #[prost(string, tag = "1")]
+    pub message: String,
+    #[prost(string, tag = "2")]
+    pub r#type: String,
+}
+
+/// The gRPC service over the application state.
+pub struct ChatService {
+    state: Arc<AppState>,
+}
+
+/// Builds the gRPC server of the chat pipeline.
+pub fn server(state: Arc<AppState>) -> DeepClaudeServer<ChatService> {
+    DeepClaudeServer::new(ChatService { state })
+}
+
+/// Serves the gRPC interface on `listener` until `shutdown` resolves.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    state: Arc<AppState>,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    tonic::transport::Server::builder()
+        .add_service(server(state))
+        .serve_with_incoming_shutdown(tokio_stream::wrappers::TcpListenerStream::new(listener), shutdown)
+        .await?;
+    Ok(())
+}
+
+type ChatStream = Pin<Box<dyn Stream<Item = std::result::Result<ChatEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl proto::deep_claude_server::DeepClaude for ChatService {
+    type ChatStream = ChatStream;
+
+    async fn chat(&self, request: Request<ChatRequest>) -> std::result::Result<Response<ChatStream>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let request = chat_request(request.into_inner()).map_err(status)?;
+        let response = handlers::handle_chat(State(self.state.clone()), headers, Json(request))
+            .await
+            .map_err(status)?;
+
+        let mut chunks = response.into_body().into_data_stream();
+        let events = async_stream::stream! {
+            let mut lines = LineBuffer::default();
+            'chunks: while let Some(chunk) = chunks.next().await {
+                let Ok(chunk) = chunk else { break };
+                for line in lines.push(&chunk) {
+                    let Some(data) = sse_data(&line) else { continue };
+                    if data == "[DONE]" {
+                        break 'chunks;
+                    }
+                    let Ok(chunk) = serde_json::from_str::<Value>(data) else { continue };
+                    for event in from_chunk(&chunk) {
+                        yield Ok(event);
+                    }
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+/// Builds the chat request of a gRPC request.
+fn chat_request(request: ChatRequest) -> crate::error::Result<ApiRequest> {
+    let body = |json: &str| -> crate::error::Result<Value> {
+        if json.trim().is_empty() {
+            return Ok(json!({}));
+        }
+        serde_json::from_str(json).map_err(|e| ApiError::BadRequest {
+            message: format!("Invalid stage body JSON: {}", e),
+        })
+    };
+    let non_empty = |value: String| (!value.is_empty()).then_some(value);
+    let messages: Vec<Value> = request
+        .messages
+        .iter()
+        .map(|message| json!({ "role": message.role, "content": message.content }))
+        .collect();
+    serde_json::from_value(json!({
+        "stream": true,
+        "model": non_empty(request.model),
+        "mode": non_empty(request.mode),
+        "system": non_empty(request.system),
+        "preset": non_empty(request.preset),
+        "user": non_empty(request.user),
+        "messages": messages,
+        "deepseek_config": { "headers": {}, "body": body(&request.deepseek_body_json)? },
+        "anthropic_config": { "headers": {}, "body": body(&request.anthropic_body_json)? },
+    }))
+    .map_err(|e| ApiError::BadRequest { message: e.to_string() })
+}
+
+/// Translates a chunk of the pipeline's stream into events.
+fn from_chunk(chunk: &Value) -> Vec<ChatEvent> {
+    let id = chunk["id"].as_str().unwrap_or_default().to_string();
+    let model = chunk["model"].as_str().unwrap_or_default().to_string();
+    let event = |event| ChatEvent {
+        id: id.clone(),
+        model: model.clone(),
+        event: Some(event),
+    };
+    if let Some(error) = chunk.get("error") {
+        return vec![event(chat_event::Event::Error(Error {
+            message: error["message"].as_str().unwrap_or_default().to_string(),
+            r#type: error["type"].as_str().unwrap_or_default().to_string(),
+        }))];
+    }
+
+    let choice = &chunk["choices"][0];
+    let mut events = Vec::new();
+    for (field, kind) in [("reasoning_content", delta::Kind::Reasoning), ("content", delta::Kind::Content)] {
+        if let Some(text) = choice["delta"][field].as_str().filter(|text| !text.is_empty()) {
+            events.push(event(chat_event::Event::Delta(Delta {
+                kind: kind as i32,
+                text: text.to_string(),
+            })));
+        }
+    }
+    if let Some(finish_reason) = choice["finish_reason"].as_str() {
+        let usage = chunk.get("usage").filter(|usage| usage.is_object()).map(|usage| {
+            let count = |field: &str| usage[field].as_u64().unwrap_or(0) as u32;
+            Usage {
+                prompt_tokens: count("prompt_tokens"),
+                completion_tokens: count("completion_tokens"),
+                total_tokens: count("total_tokens"),
+            }
+        });
+        events.push(event(chat_event::Event::Finish(Finish {
+            finish_reason: finish_reason.to_string(),
+            usage,
+        })));
+    }
+    events
+}
+
+/// The gRPC status of an error, with the code matching its HTTP status.
+fn status(error: ApiError) -> Status {
+    let message = error.to_string();
+    let code = match error.into_response().status() {
+        StatusCode::BAD_REQUEST => tonic::Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => tonic::Code::Unauthenticated,
+        StatusCode::FORBIDDEN => tonic::Code::PermissionDenied,
+        StatusCode::NOT_FOUND => tonic::Code::NotFound,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::PAYMENT_REQUIRED => tonic::Code::ResourceExhausted,
+        StatusCode::SERVICE_UNAVAILABLE => tonic::Code::Unavailable,
+        _ => tonic::Code::Internal,
+    };
+    Status::new(code, message)
+}
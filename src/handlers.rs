@@ -5,22 +5,71 @@
 //! responses. It coordinates between different AI models and handles
 //! usage tracking and cost calculations.
 use crate::{
-    clients::{AnthropicClient, DeepSeekClient},
-    config::Config,
-    error::{ApiError, Result, SseResponse},
+    abuse::IpGuard,
+    admin,
+    affinity,
+    audit::AuditLog,
+    concurrency::ConcurrencyLimiter,
+    cache::{Lookup, ResponseCache},
+    coalesce::Coalescer,
+    clients::{
+        anthropic::AnthropicResponse,
+        capture::ResponseMeta,
+        hosts::{self, HostRoute, WireApi},
+        openrouter, providers, quota, repair, stats, AnthropicClient, DeepSeekClient,
+    },
+    config::{AdminRole, CanaryConfig, Config, ModelName, ProviderProfile},
+    consensus,
+    deadletter::DeadLetterStore,
+    editblocks,
+    envelope::{Envelope, Envelopes},
+    language::TargetLanguage,
+    error::{ApiError, ErrorResponse, Result, SseResponse},
+    files::FileStore,
+    flags::Flags,
+    hooks::{HookPoint, Hooks},
+    keys::{KeyStore, VirtualKey},
+    mcp::Mcp,
+    pacing::Pacer,
+    paths,
+    payloads::PayloadStore,
+    pools::ModelPools,
+    postprocess::{Pipeline, PostProcessor},
+    proxy::Proxy,
+    rag::Retriever,
+    ratelimit::{self, RateLimiter},
+    refine,
+    reqlog::RequestLog,
+    rules::Rules,
+    secrets,
+    reasoning::{self, ReasoningCache},
+    sessions::{self, SessionStore},
+    softlimit::SoftLimit,
+    shadow::{self, Shadow, ShadowRequest},
+    shedding::LoadShedder,
+    signing,
+    spend::SpendMonitor,
+    storage::Storage,
+    streams::{self, Delta, EventSink, StreamInfo, StreamRegistry, StreamState},
+    template::Variables,
+    tenants::{self, Scope},
+    traces::{Trace, TraceStore},
+    usage::{UsageLedger, UsageRecord},
 };
 use crate::models::{
-    request::{ApiRequest, Role},
+    request::{ApiConfig, ApiRequest, Role},
     response::{
-        ApiResponse, AnthropicUsage, Choice, ContentBlock, CombinedUsage,
-        DeepSeekUsage, ExternalApiResponse, Message as ResponseMessage,
-        OpenAICompatibleResponse, Usage,
+        finish_reason, ApiResponse, AnthropicUsage, Choice, ContentBlock, CombinedUsage,
+        DeepSeekUsage, ExternalApiResponse, Message as ResponseMessage, FINISH_REASONS,
+        OpenAICompatibleResponse, PassUsage, UpstreamResponses, Usage,
     },
 };
-use crate::clients::anthropic::StreamEvent;
+use crate::clients::anthropic::{StreamEvent, Usage as ClaudeUsage};
 use crate::models::request::Message;
 use axum::{
+    body::Body,
     extract::State,
+    http::{header, HeaderValue},
     response::{sse::Event, IntoResponse, Json},
     Json as AxumJson,
 };
@@ -29,12 +78,17 @@ use futures::StreamExt;
 use std::{sync::Arc, collections::HashMap};
 use tokio_stream::wrappers::ReceiverStream;
 use crate::clients::deepseek::get_deepseek_default_model;
-use dotenv::dotenv;
 use std::fs;
 use std::io::Write;
 use serde::Deserialize;
 use serde_json::json;
-use crate::utils;
+use utoipa::ToSchema;
+
+/// Response header naming the pipeline variant that served the request.
+const VARIANT_HEADER: &str = "x-deepclaude-variant";
+
+/// Request header selecting a `[providers]` profile.
+const PROFILE_HEADER: &str = "x-provider-profile";
 
 /// Application state shared across request handlers.
 ///
@@ -42,10 +96,149 @@ use crate::utils;
 /// to all request handlers.
 pub struct AppState {
     pub config: Config,
+    pub rate_limiter: RateLimiter,
+    pub key_store: KeyStore,
+    pub shadow: Shadow,
+    pub dead_letters: DeadLetterStore,
+    pub mcp: Mcp,
+    pub retriever: Retriever,
+    pub files: FileStore,
+    pub postprocessor: PostProcessor,
+    pub hooks: Hooks,
+    pub traces: TraceStore,
+    pub pools: ModelPools,
+    pub streams: StreamRegistry,
+    pub usage: UsageLedger,
+    pub spend: SpendMonitor,
+    pub request_log: RequestLog,
+    pub cache: ResponseCache,
+    pub envelopes: Envelopes,
+    pub sessions: SessionStore,
+    pub reasoning: ReasoningCache,
+    pub concurrency: ConcurrencyLimiter,
+    pub pacer: Pacer,
+    pub shedder: LoadShedder,
+    pub proxy: Proxy,
+    pub rules: Rules,
+    pub coalescer: Coalescer,
+    pub flags: Flags,
+    pub ip_guard: IpGuard,
+    pub payloads: PayloadStore,
+    pub audit: AuditLog,
 }
 impl AppState {
-    pub fn new(config: Config) -> Self {
-        AppState { config }
+    pub async fn new(config: Config) -> anyhow::Result<Self> {
+        let storage = Storage::open(&config).await?;
+        let rate_limiter = RateLimiter::new(&config.rate_limits);
+        let key_store = KeyStore::load(&storage.keys.load().await?)?.with_backend(storage.keys.clone());
+        hosts::validate(&config.model_aliases)?;
+        providers::validate_profiles(&config.providers)?;
+        config
+            .deepseek
+            .defaults
+            .params(&config.deepseek)
+            .map_err(|e| anyhow::anyhow!("[deepseek.defaults]配置无效: {}", e))?;
+        if let Some((reason, value)) = config
+            .finish_reasons
+            .iter()
+            .find(|(_, value)| !FINISH_REASONS.contains(&value.as_str()))
+        {
+            anyhow::bail!("[finish_reasons]中{}的值{}无效，可选: {}", reason, value, FINISH_REASONS.join(", "));
+        }
+        let shadow = Shadow::new(&config.shadow);
+        let dead_letters = DeadLetterStore::new(&config.deadletter);
+        let mcp = Mcp::new(&config.mcp);
+        let retriever = Retriever::new(&config.rag)?;
+        let files = FileStore::new(&config.files);
+        let postprocessor = PostProcessor::new(&config.postprocess)?;
+        let hooks = Hooks::new(&config.hooks)?;
+        let traces = TraceStore::new(&config.traces);
+        let pools = ModelPools::new(&config.model_pools)?;
+        let usage = UsageLedger::new(&config.usage, storage.usage);
+        let spend = SpendMonitor::new(&config.spend_alerts, &usage).await;
+        let request_log = RequestLog::new(&config.request_log);
+        let reasoning = ReasoningCache::new(&config.reasoning_cache, storage.cache.clone());
+        let sessions = SessionStore::new(&config.prompt_cache, storage.cache.clone());
+        let cache = ResponseCache::new(&config.cache, storage.cache);
+        let envelopes = Envelopes::new(&config.envelopes)?;
+        let concurrency = ConcurrencyLimiter::new(&config.concurrency);
+        let pacer = Pacer::new(&config.pacing);
+        let shedder = LoadShedder::new(&config.shedding);
+        let streams = StreamRegistry::new(&config.streams);
+        repair::set_enabled(config.streams.repair_json);
+        signing::set_webhook_secret(&config.signing.webhook_secret);
+        let proxy = Proxy::new(&config.proxy);
+        let rules = Rules::new(&config.rules)?;
+        let coalescer = Coalescer::new(&config.coalesce);
+        let ip_guard = IpGuard::new(&config.abuse)?;
+        let payloads = PayloadStore::new(config.debug.payloads);
+        let audit = AuditLog::new(&config.audit)?;
+        Ok(AppState {
+            config,
+            rate_limiter,
+            key_store,
+            shadow,
+            dead_letters,
+            mcp,
+            retriever,
+            files,
+            postprocessor,
+            hooks,
+            traces,
+            pools,
+            streams,
+            usage,
+            spend,
+            request_log,
+            cache,
+            envelopes,
+            sessions,
+            reasoning,
+            concurrency,
+            pacer,
+            shedder,
+            proxy,
+            rules,
+            coalescer,
+            flags: Flags::default(),
+            ip_guard,
+            payloads,
+            audit,
+        })
+    }
+
+    /// Records a finished request's usage, adds its cost to the key's spend
+    /// and writes it to the request log.
+    fn record_usage(&self, record: &UsageRecord, request: &ApiRequest, answer: &str) {
+        self.usage.record(record);
+        self.spend.observe(record);
+        self.request_log.log(record, request, answer);
+    }
+
+    /// Records the spend of an attempt whose answer failed. It has no
+    /// answer for the request log.
+    fn record_failed_attempt(&self, record: UsageRecord) {
+        tracing::warn!("第{}次尝试失败，浪费{:.6}美元", record.attempts, record.wasted_cost_usd);
+        self.usage.record(&record);
+        self.spend.observe(&record);
+    }
+
+    /// The virtual key a request carries.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Unauthorized` if the request carries none while
+    /// one is required: with `[auth] managed_keys`, or once `[[keys]]` are
+    /// configured, so a caller cannot skip its key's restrictions by
+    /// leaving the key out.
+    pub(crate) fn virtual_key(&self, headers: &axum::http::HeaderMap) -> Result<Option<Arc<VirtualKey>>> {
+        let key = self.key_store.lookup(headers);
+        if key.is_none() && (self.config.auth.managed_keys || !self.config.keys.is_empty()) {
+            return Err(ApiError::Unauthorized {
+                message: "Authorization must carry a DeepClaude virtual key".to_string(),
+            });
+        }
+        Ok(key)
     }
 }
 /// Extracts API tokens from request headers.
@@ -65,47 +258,12 @@ impl AppState {
 /// 从.env文件中获取API tokens
 #[allow(dead_code)]
 fn get_env_api_tokens() -> Option<(String, String)> {
-    // 获取当前目录
-    let current_dir = std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from("."));
-    let env_path = current_dir.join(".env");
-    
-    // 确保.env文件存在
-    if !env_path.exists() {
-        tracing::error!(".env文件不存在: {:?}", env_path);
-        return None;
-    }
-    
-    // 读取.env文件内容
-    let content = match std::fs::read_to_string(&env_path) {
-        Ok(content) => content,
-        Err(e) => {
-            tracing::debug!("读取.env文件失败: {}", e);
-            return None;
-        }
-    };
-    
-    // 解析.env文件中的环境变量
-    let mut deepseek_key = None;
-    let mut anthropic_key = None;
-    
-    for line in content.lines() {
-        let line = line.trim();
-        if line.is_empty() || line.starts_with('#') {
-            continue;
-        }
-        
-        if let Some(pos) = line.find('=') {
-            let key = line[..pos].trim();
-            let value = line[pos + 1..].trim();
-            
-            if key == "DEEPSEEK_API_KEY" {
-                deepseek_key = Some(value.to_string());
-            } else if key == "ANTHROPIC_API_KEY" {
-                anthropic_key = Some(value.to_string());
-            }
-        }
-    }
-    
+    let settings = providers::current();
+
+    // 密钥可以是vault://或aws-sm://引用
+    let deepseek_key = settings.deepseek_api_key.as_deref().and_then(secrets::expose);
+    let anthropic_key = settings.anthropic_api_key.as_deref().and_then(secrets::expose);
+
     match (deepseek_key, anthropic_key) {
         (Some(d), Some(a)) => Some((d, a)),
         _ => {
@@ -173,6 +331,62 @@ fn extract_api_tokens(headers: &axum::http::HeaderMap) -> Result<(String, String
     })
 }
 
+/// Resolves the upstream tokens for a request.
+///
+/// Upstream keys stored on the caller's virtual key take precedence over
+/// the keys of the request's provider profile. Without a virtual key the
+/// request headers and then the `.env` file follow. A virtual key itself
+/// is never sent upstream: the DeepSeek key then comes from the server,
+/// the Claude key from `X-Anthropic-API-Token` or the server.
+///
+/// # Errors
+///
+/// Returns `ApiError::Unauthorized` if a virtual key is required and
+/// missing, and `ApiError::Internal` if a request with a virtual key has no
+/// upstream key for a stage.
+fn resolve_upstream_tokens(state: &AppState, headers: &axum::http::HeaderMap) -> Result<(String, String)> {
+    let key = state.virtual_key(headers)?;
+    let settings = providers::current();
+    let profile = settings.profile.as_ref().and_then(|name| state.config.providers.get(name));
+    let server_key = |key: &Option<String>| key.as_deref().and_then(secrets::expose);
+    let pinned = (
+        key.as_ref()
+            .and_then(|k| k.deepseek_api_key.clone())
+            .or_else(|| profile.and_then(|p| server_key(&p.deepseek_api_key))),
+        key.as_ref()
+            .and_then(|k| k.anthropic_api_key.clone())
+            .or_else(|| profile.and_then(|p| server_key(&p.anthropic_api_key))),
+    );
+    if key.is_none() {
+        if let (Some(deepseek), Some(anthropic)) = pinned {
+            return Ok((deepseek, anthropic));
+        }
+        let (deepseek, anthropic) = extract_api_tokens(headers)?;
+        return Ok((pinned.0.unwrap_or(deepseek), pinned.1.unwrap_or(anthropic)));
+    }
+
+    // 虚拟密钥本身不作为DeepSeek密钥发往上游
+    let deepseek = pinned
+        .0
+        .or_else(|| server_key(&settings.deepseek_api_key))
+        .ok_or_else(|| ApiError::Internal {
+            message: "未配置DeepSeek上游密钥：请在虚拟密钥或.env中设置DEEPSEEK_API_KEY".to_string(),
+        })?;
+    let anthropic = pinned
+        .1
+        .or_else(|| {
+            headers
+                .get("X-Anthropic-API-Token")
+                .and_then(|h| h.to_str().ok())
+                .map(String::from)
+        })
+        .or_else(|| server_key(&settings.anthropic_api_key))
+        .ok_or_else(|| ApiError::Internal {
+            message: "未配置Claude上游密钥：请在虚拟密钥或.env中设置ANTHROPIC_API_KEY".to_string(),
+        })?;
+    Ok((deepseek, anthropic))
+}
+
 /// Calculates the cost of DeepSeek API usage.
 ///
 /// # Arguments
@@ -186,7 +400,7 @@ fn extract_api_tokens(headers: &axum::http::HeaderMap) -> Result<(String, String
 /// # Returns
 ///
 /// The total cost in dollars for the API usage
-fn calculate_deepseek_cost(
+pub(crate) fn calculate_deepseek_cost(
     input_tokens: u32,
     output_tokens: u32,
     _reasoning_tokens: u32,
@@ -214,7 +428,7 @@ fn calculate_deepseek_cost(
 /// # Returns
 ///
 /// The total cost in dollars for the API usage
-fn calculate_anthropic_cost(
+pub(crate) fn calculate_anthropic_cost(
     model: &str,
     input_tokens: u32,
     output_tokens: u32,
@@ -222,7 +436,15 @@ fn calculate_anthropic_cost(
     cache_read_tokens: u32,
     config: &Config,
 ) -> f64 {
-    let pricing = if model.contains("claude-3-5-sonnet") {
+    // 经OpenAI格式网关调用时优先使用网关的价格
+    let gateway = config
+        .pricing
+        .gateways
+        .get(model)
+        .filter(|_| crate::clients::anthropic::should_use_openai_format());
+    let pricing = if let Some(pricing) = gateway {
+        pricing
+    } else if model.contains("claude-3-5-sonnet") {
         &config.pricing.anthropic.claude_3_sonnet
     } else if model.contains("claude-3-5-haiku") {
         &config.pricing.anthropic.claude_3_haiku
@@ -240,6 +462,29 @@ fn calculate_anthropic_cost(
     input_cost + output_cost + cache_write_cost + cache_read_cost
 }
 
+/// Calculates the cost of a responder call, from the catalog price of a
+/// routed model or the configured Anthropic pricing.
+pub(crate) fn responder_cost(state: &AppState, route: Option<&HostRoute>, response: &AnthropicResponse) -> f64 {
+    routed_cost(route, response.usage.input_tokens, response.usage.output_tokens).unwrap_or_else(|| {
+        calculate_anthropic_cost(
+            &response.model,
+            response.usage.input_tokens,
+            response.usage.output_tokens,
+            response.usage.cache_creation_input_tokens,
+            response.usage.cache_read_input_tokens,
+            &state.config,
+        )
+    })
+}
+
+/// Calculates the cost of a stage routed to OpenRouter from its catalog price.
+///
+/// Returns `None` for other hosts, or while the model's price is unknown.
+pub(crate) fn routed_cost(route: Option<&HostRoute>, input_tokens: u32, output_tokens: u32) -> Option<f64> {
+    let route = route.filter(|route| route.profile.name == "openrouter")?;
+    openrouter::price(&route.model).map(|price| price.cost(input_tokens, output_tokens))
+}
+
 /// Formats a cost value as a dollar amount string.
 ///
 /// # Arguments
@@ -253,13 +498,202 @@ pub(crate) fn format_cost(cost: f64) -> String {
     format!("${:.2}", cost)
 }
 
-/// 获取MODE环境变量，决定DeepSeek和Claude之间的交互模式
+/// 获取请求未指定时的模式，运行时开关优先于MODE环境变量，决定DeepSeek和Claude之间的交互模式
 /// 
 /// 返回值:
 /// - "normal": 只将DeepSeek的推理内容传递给Claude（默认）
 /// - "full": 将DeepSeek的最终结果都传递给Claude
-fn get_mode() -> String {
-    utils::get_mode()
+fn get_mode(state: &AppState) -> String {
+    state.flags.default_mode()
+}
+
+/// Resolves the host route for a stage's model, if it is an alias or an
+/// OpenRouter model.
+pub(crate) fn stage_route(state: &AppState, config: &ApiConfig) -> Option<HostRoute> {
+    HostRoute::for_model(&state.config.model_aliases, config.body.get("model").and_then(|v| v.as_str()))
+}
+
+/// Builds the final chunk of a stream.
+///
+/// `content_filter_results` is only added with `[streams]
+/// content_filter_results`, for clients that require the field. No category
+/// is moderated on its own, so all of them report whether a `post_response`
+/// hook withheld the answer.
+fn finish_chunk(
+    state: &AppState,
+    (id, created, model): (&str, i64, &str),
+    finish_reason: &str,
+    completion_chars: u32,
+) -> serde_json::Value {
+    let mut chunk = json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "delta": {},
+            "finish_reason": finish_reason,
+        }],
+        "system_fingerprint": "",
+        "usage": {
+            "prompt_tokens": 0,
+            "completion_tokens": completion_chars,
+            "total_tokens": completion_chars
+        }
+    });
+    if state.config.streams.content_filter_results {
+        let filtered = json!({ "filtered": finish_reason == "content_filter" });
+        chunk["choices"][0]["content_filter_results"] = json!({
+            "hate": filtered,
+            "self_harm": filtered,
+            "sexual": filtered,
+            "violence": filtered,
+        });
+    }
+    chunk
+}
+
+/// Returns the upstream quota reported to each stage's client.
+fn stage_quota(deepseek: &DeepSeekClient, anthropic: &AnthropicClient) -> serde_json::Value {
+    json!({ "deepseek": deepseek.quota(), "anthropic": anthropic.quota() })
+}
+
+/// Combines a stage's captured status and headers with its response body.
+fn external_response(meta: ResponseMeta, body: serde_json::Value) -> ExternalApiResponse {
+    ExternalApiResponse { status: meta.status, headers: meta.headers, body }
+}
+
+/// Returns the models each pipeline stage will use for this request.
+pub(crate) fn stage_models(request: &ApiRequest) -> Vec<String> {
+    let deepseek_model = request.deepseek_config.body.get("model")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .unwrap_or_else(get_deepseek_default_model);
+    let anthropic_model = request.anthropic_config.body.get("model")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .unwrap_or_else(crate::clients::anthropic::get_claude_default_model);
+    let mut models = vec![deepseek_model, anthropic_model];
+    // 共识请求的两个回答模型同样需要密钥授权
+    if let Some(consensus) = &request.consensus {
+        models.extend(consensus.models.iter().cloned());
+    }
+    models
+}
+
+/// Returns the `model` reported to the client, see `[response] model_name`.
+fn response_model(state: &AppState, request: &ApiRequest, responder: &str) -> String {
+    match (state.config.response.model_name, &request.model) {
+        (ModelName::Requested, Some(model)) => model.clone(),
+        (ModelName::Responder | ModelName::Stage, _) => responder.to_string(),
+        _ => format!("{}_{}", get_deepseek_default_model(), responder),
+    }
+}
+
+/// Returns the model name of a stream's reasoning chunks.
+fn reasoning_model(state: &AppState, request: &ApiRequest, models: &[String]) -> String {
+    match state.config.response.model_name {
+        ModelName::Stage => models[0].clone(),
+        _ => response_model(state, request, &models[1]),
+    }
+}
+
+/// Returns the post-processing pipeline for a request's final answer.
+fn answer_pipeline<'a>(state: &'a AppState, request: &ApiRequest, mode: &str) -> Option<&'a Pipeline> {
+    let models = stage_models(request);
+    state.postprocessor.pipeline(mode, &models[1])
+}
+
+/// Builds the template variables for a request's system prompts.
+fn template_variables(state: &AppState, headers: &axum::http::HeaderMap, request: &ApiRequest, mode: &str) -> Variables {
+    let key_name = state.key_store.lookup(headers).map(|key| key.name.clone());
+    let user_name = request.user.clone().or(key_name);
+    Variables::new(&state.config.templates, mode, user_name.as_deref())
+}
+
+/// Adds the language instruction to a system prompt.
+fn with_language(system: Option<String>, language: Option<&TargetLanguage>) -> Option<String> {
+    let Some(language) = language else {
+        return system;
+    };
+    Some(match system {
+        Some(system) => format!("{}\n\n{}", system, language.instruction()),
+        None => language.instruction(),
+    })
+}
+
+/// Returns the output envelope of the request's Claude stage model, and
+/// asks a routed single responder for JSON mode.
+fn answer_envelope(state: &AppState, request: &mut ApiRequest, routed: bool) -> Option<Arc<Envelope>> {
+    let envelope = state.envelopes.get(&stage_models(request)[1])?;
+    if routed && request.consensus.is_none() {
+        envelope.request_json(&mut request.anthropic_config);
+    }
+    Some(envelope)
+}
+
+/// Checks run on the final answer before it is returned.
+struct AnswerChecks {
+    edit_blocks: bool,
+    language: Option<TargetLanguage>,
+    envelope: Option<Arc<Envelope>>,
+}
+
+impl AnswerChecks {
+    fn new(state: &AppState, mode: &str, language: Option<TargetLanguage>, envelope: Option<Arc<Envelope>>) -> Self {
+        Self {
+            edit_blocks: mode == "full" && state.config.edit_blocks.validate,
+            language: language.filter(TargetLanguage::verifiable),
+            envelope,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.edit_blocks || self.language.is_some() || self.envelope.is_some()
+    }
+
+    /// Runs the checks and, if any fails, asks Claude once to revise the
+    /// answer. Returns the revised response, or `None` if the answer passed
+    /// or the revision failed.
+    async fn revise(
+        &self,
+        client: &AnthropicClient,
+        (messages, system): (Vec<Message>, Option<String>),
+        config: &ApiConfig,
+        answer: &str,
+    ) -> Option<AnthropicResponse> {
+        let mut feedback = Vec::new();
+        if self.edit_blocks {
+            let problems = editblocks::problems(answer);
+            if !problems.is_empty() {
+                tracing::warn!("SEARCH/REPLACE块格式错误，请求Claude修复: {}", problems.join("; "));
+                feedback.push(editblocks::feedback(&problems));
+            }
+        }
+        if let Some(language) = self.language.as_ref().filter(|language| !language.matches(answer)) {
+            tracing::warn!("回答不是{}，请求Claude重新回答", language.name());
+            feedback.push(language.feedback());
+        }
+        if let Some(envelope) = &self.envelope {
+            let problems = envelope.problems(answer);
+            if !problems.is_empty() {
+                tracing::warn!("回答不符合输出信封，请求Claude修改: {}", problems.join("; "));
+                feedback.push(envelope.feedback(&problems));
+            }
+        }
+        if feedback.is_empty() {
+            return None;
+        }
+
+        match client.revise(messages, system, config, answer, &feedback.join("\n\n")).await {
+            Ok(response) => Some(response),
+            Err(e) => {
+                tracing::warn!("请求Claude修改回答失败，返回原回答: {}", e);
+                None
+            }
+        }
+    }
 }
 
 /// Main handler for chat requests.
@@ -276,18 +710,301 @@ fn get_mode() -> String {
 /// # Returns
 ///
 /// * `Result<Response>` - The API response or an error
+#[utoipa::path(
+    post,
+    path = "/v1/chat/completions",
+    tag = "chat",
+    request_body = ApiRequest,
+    responses(
+        (status = 200, description = "The answer, or `chat.completion.chunk` events when `stream` is set",
+            content(
+                (OpenAICompatibleResponse = "application/json"),
+                (String = "text/event-stream"),
+            )),
+        (status = 400, description = "Invalid request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorResponse),
+        (status = 429, description = "Rate or spend limit reached", body = ErrorResponse),
+        (status = 503, description = "No request slot freed up in time", body = ErrorResponse),
+    )
+)]
 pub async fn handle_chat(
     state: State<Arc<AppState>>,
     headers: axum::http::HeaderMap,
     Json(request): Json<ApiRequest>,
 ) -> Result<axum::response::Response> {
+    // 短时间内的相同请求合并到同一次运行
+    let key = state.coalescer.key(&request, &headers);
+    let request_id = crate::request_id(&headers).unwrap_or_default().to_string();
+    // 记录请求的租户，以便删除租户数据时一并删除其捕获的请求体
+    let tenant = Scope::resolve(&headers, state.key_store.lookup(&headers).as_deref())
+        .ok()
+        .and_then(|scope| scope.tenant);
+    let captured = state.clone();
+    let serve = async move {
+        captured.payloads.capture(&request_id, tenant, run_chat(captured.clone(), headers, request)).await
+    };
+    state.coalescer.run(key, serve).await
+}
+
+/// Runs a chat request through the pipeline, capturing it in the
+/// dead-letter store if an upstream fails.
+async fn run_chat(
+    state: State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    request: ApiRequest,
+) -> Result<axum::response::Response> {
+    let received = state.config.deadletter.enabled.then(|| request.clone());
+    let key = state.key_store.lookup(&headers);
+    let key_name = key.as_ref().map(|key| key.name.clone());
+    let tenant = Scope::resolve(&headers, key.as_deref()).ok().and_then(|scope| scope.tenant);
+    // 没有虚拟密钥、也没有自带上游密钥的请求使用服务器的密钥，重放时可以沿用
+    let server_keys = key_name.is_none()
+        && !headers.contains_key(header::AUTHORIZATION)
+        && !headers.contains_key("X-Anthropic-API-Token");
+    let dead_letters = state.clone();
+
+    // 上游失败的请求按收到时的样子写入死信队列，待上游恢复后重放
+    process_chat(state, headers, request).await.inspect_err(|e| {
+        if let Some(received) = received.filter(|_| DeadLetterStore::is_replayable(e)) {
+            dead_letters.dead_letters.capture(&received, key_name, tenant, server_keys, e);
+        }
+    })
+}
+
+/// Runs a chat request through the pipeline: hooks, rules, presets, the
+/// virtual key's restrictions, budgets and both stages.
+pub(crate) async fn process_chat(
+    state: State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    mut request: ApiRequest,
+) -> Result<axum::response::Response> {
+    // 转发白名单内的入站请求头
+    request.forward_headers(&state.config.passthrough, &headers);
+
+    // 用户脚本可以修改或否决请求
+    state.hooks.pre_request(&mut request, &headers)?;
+
+    // 请求的DeepSeek参数优先于预设和配置默认值
+    request.apply_deepseek_options(&state.config.deepseek)?;
+
+    // 配置的规则可以改写模型和模式、注入系统提示或拒绝请求
+    state.rules.apply(&mut request, &headers, state.key_store.lookup(&headers).as_deref())?;
+
+    // 请求选择的上游账户配置只在本请求内生效
+    match select_provider_profile(&state, &headers, &mut request)? {
+        Some((name, profile)) => {
+            providers::with_profile(&name, &profile, serve_chat(state, headers, request)).await
+        }
+        None => serve_chat(state, headers, request).await,
+    }
+}
+
+/// Picks the `[providers]` profile of a request, from the
+/// `X-Provider-Profile` header or a stage model that is one of a profile's
+/// aliases. A stage named by an alias falls back to the profile's model.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` for an unknown profile, or if the header
+/// and the stage models select different profiles.
+fn select_provider_profile(
+    state: &AppState,
+    headers: &axum::http::HeaderMap,
+    request: &mut ApiRequest,
+) -> Result<Option<(String, ProviderProfile)>> {
+    let mut selected = headers
+        .get(PROFILE_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .map(|name| name.trim().to_string());
+    if let Some(name) = &selected {
+        if !state.config.providers.contains_key(name) {
+            return Err(ApiError::BadRequest {
+                message: format!("Unknown provider profile: {}", name),
+            });
+        }
+    }
+    for config in [&mut request.deepseek_config, &mut request.anthropic_config] {
+        let Some(model) = config.body.get("model").and_then(|v| v.as_str()) else {
+            continue;
+        };
+        let Some((name, _)) = state.config.providers.iter().find(|(_, p)| p.aliases.iter().any(|a| a == model)) else {
+            continue;
+        };
+        if selected.as_ref().is_some_and(|selected| selected != name) {
+            return Err(ApiError::BadRequest {
+                message: format!("Model {} belongs to provider profile {}, but the request selects another", model, name),
+            });
+        }
+        selected = Some(name.clone());
+        if let Some(body) = config.body.as_object_mut() {
+            body.remove("model");
+        }
+    }
+    Ok(selected.map(|name| {
+        let profile = state.config.providers[&name].clone();
+        (name, profile)
+    }))
+}
+
+/// Serves a chat request once its provider profile is in effect.
+async fn serve_chat(
+    state: State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    mut request: ApiRequest,
+) -> Result<axum::response::Response> {
+    // 应用请求指定的参数预设
+    if let Some(name) = &request.preset {
+        let preset = state.config.presets.get(name).cloned().ok_or_else(|| ApiError::BadRequest {
+            message: format!("Unknown preset: {}", name),
+        })?;
+        request.apply_preset(&preset);
+    }
+
+    // 按比例将部分流量分配到金丝雀配置
+    let stable_request = request.clone();
+    if in_canary(&state.config.canary) {
+        request.apply_canary(&state.config.canary);
+    }
+
+    // 回退到稳定配置时同样要补全默认值
+    let complete = |request: &mut ApiRequest| -> Result<()> {
+        request.apply_deepseek_defaults(&state.config.deepseek);
+        // 共识请求缺省的模型和策略取自配置
+        consensus::resolve(request, &state.config.consensus)
+    };
+    complete(&mut request)?;
+
+    // 校验虚拟密钥的模型、模式和max_tokens限制
+    if let Some(key) = state.virtual_key(&headers)? {
+        let mode = request.mode.clone().unwrap_or_else(|| get_mode(&state));
+        let models = stage_models(&request);
+        if let Err(e) = key.authorize(&mut request, &mode, &models) {
+            // 金丝雀配置不被该密钥允许时回退到稳定配置
+            if request.variant.is_none() {
+                return Err(e);
+            }
+            request = stable_request;
+            complete(&mut request)?;
+            let mode = request.mode.clone().unwrap_or_else(|| get_mode(&state));
+            let models = stage_models(&request);
+            key.authorize(&mut request, &mode, &models)?;
+        }
+    }
+
+    // 按组织和项目归属请求，并检查组织的月度预算
+    request.scope = Scope::resolve(&headers, state.key_store.lookup(&headers).as_deref())?;
+    tenants::check_budget(&state.config.tenants, &request.scope, &state.usage).await?;
+
+    // 内存或文件描述符超限时拒绝新的流式请求，已有的流继续完成
     if request.stream {
-        let stream_response = chat_stream(state, headers, Json(request)).await?;
-        Ok(stream_response.into_response())
+        state.shedder.admit_stream()?;
+    }
+
+    // 并发已满时排队，交互式密钥的请求优先于批处理密钥
+    let permit = state.concurrency.acquire(state.key_store.lookup(&headers).as_deref()).await?;
+    // 批处理密钥的请求按上游报告的剩余配额放慢提交
+    state.pacer.acquire(state.key_store.lookup(&headers).as_deref()).await?;
+
+    // 模型池按近期延迟和错误率选择成员
+    state.pools.apply(&mut request);
+
+    // 引用的上传文件先加入上下文，编号排在检索结果之前
+    state.files.attach(&state.retriever, &mut request, state.key_store.lookup(&headers).as_deref()).await?;
+
+    // 检索相关文档片段作为两个阶段的上下文，密钥可以关闭检索
+    if state.key_store.lookup(&headers).is_none_or(|key| key.retrieval) {
+        state.retriever.augment(&mut request).await;
+    }
+
+    negotiate_stream(&headers, &mut request, state.config.server.strict_accept);
+    request.forward_user();
+
+    let variant = HeaderValue::from_str(request.variant_tag()).unwrap_or(HeaderValue::from_static("stable"));
+    let affinity = state.config.affinity.enabled.then(|| affinity::key(&headers, &request));
+    let result = if request.stream {
+        chat_stream(state.clone(), headers, Json(request)).await.map(|sse| {
+            // 禁止缓存，并关闭nginx等反向代理对事件流的缓冲
+            let mut response = sse.into_response();
+            response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+            response.headers_mut().insert("x-accel-buffering", HeaderValue::from_static("no"));
+            // 流式请求直到响应体结束才归还并发槽位
+            match permit {
+                Some(permit) => response.map(|body| {
+                    Body::from_stream(body.into_data_stream().inspect(move |_| {
+                        let _held = &permit;
+                    }))
+                }),
+                None => response,
+            }
+        })
     } else {
-        let json_response = chat(state, headers, Json(request)).await?;
-        Ok(json_response.into_response())
+        // 非流式请求先查响应缓存，密钥可以关闭缓存
+        let lookup = if state.key_store.lookup(&headers).is_none_or(|key| key.cache) {
+            state.cache.lookup(&request, &state.retriever).await
+        } else {
+            None
+        };
+        let result = match lookup {
+            Some(Lookup { hit: Some(mut response), .. }) => {
+                // 缓存的回答按本次请求回显模型名
+                if let (ModelName::Requested, Some(model)) = (state.config.response.model_name, &request.model) {
+                    response.model = model.clone();
+                }
+                Ok(Json(response))
+            }
+            lookup => {
+                let result = chat(state.clone(), headers, Json(request)).await;
+                if let (Some(lookup), Ok(Json(response))) = (lookup, &result) {
+                    state.cache.store(lookup, response).await;
+                }
+                result
+            }
+        };
+        result.and_then(|Json(mut response)| {
+            let hook_headers = state.hooks.post_response(&mut response.choices[0].message.content)?;
+            let mut response = Json(response).into_response();
+            response.headers_mut().extend(hook_headers);
+            Ok(response)
+        })
+    };
+
+    let mut response = result?;
+    response.headers_mut().insert(VARIANT_HEADER, variant);
+    if let Some(affinity) = &affinity {
+        affinity::apply(&state.config.affinity, affinity, response.headers_mut());
     }
+    Ok(response)
+}
+
+/// Picks streaming or JSON from the `Accept` header.
+///
+/// A client accepting `text/event-stream` but not JSON gets a stream even
+/// without `stream: true`. With `strict_accept`, a client accepting JSON but
+/// not event streams gets a JSON response to a `stream: true` request.
+fn negotiate_stream(headers: &axum::http::HeaderMap, request: &mut ApiRequest, strict_accept: bool) {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return;
+    };
+    let accepts = |media_type: &str| {
+        accept.split(',').any(|range| {
+            let range = range.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+            range == media_type || range == "*/*"
+        })
+    };
+    let event_stream = accepts("text/event-stream");
+    let json = accepts("application/json");
+    if !request.stream && event_stream && !json {
+        tracing::debug!("客户端只接受text/event-stream，改为流式响应");
+        request.stream = true;
+    } else if request.stream && json && !event_stream && strict_accept {
+        tracing::debug!("客户端只接受application/json，改为非流式响应");
+        request.stream = false;
+    }
+}
+
+/// Decides whether a request is routed to the canary configuration.
+fn in_canary(canary: &CanaryConfig) -> bool {
+    canary.percent > 0 && (uuid::Uuid::new_v4().as_u128() % 100) < canary.percent as u128
 }
 
 /// Handler for non-streaming chat requests.
@@ -307,7 +1024,7 @@ pub async fn handle_chat(
 pub(crate) async fn chat(
     State(state): State<Arc<AppState>>,
     headers: axum::http::HeaderMap,
-    Json(request): Json<ApiRequest>,
+    Json(mut request): Json<ApiRequest>,
 ) -> Result<Json<OpenAICompatibleResponse>> {
     // Validate system prompt
     if !request.validate_system_prompt() {
@@ -315,28 +1032,33 @@ pub(crate) async fn chat(
     }
 
     // Extract API tokens
-    let (deepseek_token, anthropic_token) = extract_api_tokens(&headers)?;
+    let (deepseek_token, anthropic_token) = resolve_upstream_tokens(&state, &headers)?;
 
     // Initialize clients
-    let deepseek_client = DeepSeekClient::new(deepseek_token);
-    let anthropic_client = AnthropicClient::new(anthropic_token);
+    let deepseek_route = stage_route(&state, &request.deepseek_config);
+    let anthropic_route = stage_route(&state, &request.anthropic_config)
+        .filter(|route| route.profile.wire == WireApi::ChatCompletions);
+    let envelope = answer_envelope(&state, &mut request, anthropic_route.is_some());
+    let deepseek_client = DeepSeekClient::new(deepseek_token).with_route(deepseek_route.clone());
+    let anthropic_client = AnthropicClient::new(anthropic_token.clone()).with_route(anthropic_route.clone());
 
-    // 获取当前模式
-    let mode = get_mode();
+    // 获取当前模式，请求中指定的模式优先
+    let mode = request.mode.clone().unwrap_or_else(|| get_mode(&state));
+    let language = TargetLanguage::resolve(request.target_language.as_deref(), &state.config.language);
     
     // 获取系统提示和消息
-    let messages = if mode == "full" {
+    let mut messages = if mode == "full" {
         // full模式下使用带有特定系统提示的消息
-        request.get_messages_with_system()
+        request.get_messages_with_system(&state.config.system_prompt, &mode, language.as_ref().map(TargetLanguage::instruction).as_deref())
     } else {
         // normal模式下只使用原始消息
         let mut messages = Vec::new();
         
-        // 添加系统消息（如果有）
-        if let Some(system) = &request.system {
+        // 添加系统消息（如果有），与部署的基础系统提示合并
+        if let Some(system) = request.deepseek_system_prompt(&state.config.system_prompt, &mode) {
             messages.push(Message {
                 role: Role::System,
-                content: system.clone(),
+                content: system,
             });
         }
         
@@ -346,25 +1068,85 @@ pub(crate) async fn chat(
         messages
     };
 
+    // 渲染系统提示中的模板变量
+    let variables = template_variables(&state, &headers, &request, &mode);
+    let models = stage_models(&request);
+    let deepseek_variables = variables.with_model(&models[0]);
+    for message in messages.iter_mut().filter(|m| m.role == Role::System) {
+        message.content = deepseek_variables.render(&message.content);
+    }
+
+    // verbose请求记录各阶段的输入输出，供之后下载
+    let key_name = state.key_store.lookup(&headers).map(|key| key.name.clone());
+    let mut trace = request
+        .verbose
+        .then(|| Trace::new(&mode, request.variant_tag(), key_name.as_deref(), request.scope.tenant.as_deref()));
+
+    // 在调用上游之前检查本地速率预算
+    let estimated_tokens = ratelimit::estimate_messages_tokens(&messages);
+    state.rate_limiter.acquire_all(&["deepseek", "anthropic"], estimated_tokens).await?;
+    // 客户端以同一请求id重试时计为同一请求的又一次尝试
+    let attempt = state.usage.attempt(crate::request_id(&headers).unwrap_or_default());
+
     // Call DeepSeek API
-    let deepseek_response = deepseek_client.chat(messages.clone(), &request.deepseek_config).await?;
+    if let Some(trace) = trace.as_mut() {
+        trace.begin("deepseek", &models[0], json!({ "messages": messages, "body": request.deepseek_config.body }));
+    }
+    // 重试或重发同一条消息时复用已有的推理
+    let reasoning_key = state.reasoning.key(&request, &models[0], &mode, &messages);
+    let reused = match &reasoning_key {
+        Some(key) => state.reasoning.get(key).await,
+        None => None,
+    };
+    let reasoning_reused = reused.is_some();
+    let deepseek_response = match reused {
+        Some(response) => {
+            tracing::info!("复用缓存的DeepSeek推理");
+            response
+        }
+        None => {
+            let deepseek_started = std::time::Instant::now();
+            let deepseek_response = deepseek_client.chat(messages.clone(), &request.deepseek_config).await;
+            stats::record_call(&models[0], false, deepseek_response.as_ref().ok().map(|_| deepseek_started.elapsed()));
+            let deepseek_response = match deepseek_response {
+                Ok(response) => response,
+                Err(e) => {
+                    // 上游调用失败时两个阶段都没有产出，退还占用的速率预算
+                    state.rate_limiter.settle("deepseek", estimated_tokens, 0);
+                    state.rate_limiter.settle("anthropic", estimated_tokens, 0);
+                    return Err(e);
+                }
+            };
+            if let Some(key) = &reasoning_key {
+                state.reasoning.put(key, &deepseek_response).await;
+            }
+            deepseek_response
+        }
+    };
+    if let Some(trace) = trace.as_mut() {
+        trace.end(json!(deepseek_response));
+    }
+    state.rate_limiter.settle("deepseek", estimated_tokens, deepseek_response.usage.total_tokens);
+    let deepseek_cost = routed_cost(
+        deepseek_route.as_ref(),
+        deepseek_response.usage.input_tokens,
+        deepseek_response.usage.output_tokens,
+    )
+    .unwrap_or_else(|| calculate_deepseek_cost(
+        deepseek_response.usage.input_tokens,
+        deepseek_response.usage.output_tokens,
+        deepseek_response.usage.output_details.reasoning,
+        deepseek_response.usage.input_details.cached,
+        &state.config,
+    ));
     
-    // Store response metadata
-    let _deepseek_status: u16 = 200;
-    let _deepseek_headers: HashMap<String, String> = HashMap::new(); // Headers not available when using high-level chat method
+    // verbose请求返回上游的状态码和部分响应头，复用缓存推理时没有上游响应
+    let deepseek_upstream = deepseek_client
+        .last_response()
+        .filter(|_| request.verbose)
+        .map(|meta| external_response(meta, json!(deepseek_response)));
 
     // Extract reasoning content and wrap in thinking tags
-    let reasoning_content = deepseek_response
-        .choices
-        .first()
-        .and_then(|c| c.message.reasoning_content.as_ref())
-        .ok_or_else(|| ApiError::DeepSeekError { 
-            message: "No reasoning content in response".to_string(),
-            type_: "missing_content".to_string(),
-            param: None,
-            code: None
-        })?;
-
     // 获取DeepSeek的普通内容
     let empty_string = String::new();
     let normal_content = deepseek_response
@@ -373,6 +1155,24 @@ pub(crate) async fn chat(
         .and_then(|c| c.message.content.as_ref())
         .unwrap_or(&empty_string);
 
+    // v3等非推理模型没有推理内容，full模式下只需要其回答
+    let reasoning_content = match deepseek_response
+        .choices
+        .first()
+        .and_then(|c| c.message.reasoning_content.as_ref())
+    {
+        Some(reasoning) => reasoning,
+        None if mode == "full" && !normal_content.trim().is_empty() => &empty_string,
+        None => {
+            return Err(ApiError::DeepSeekError {
+                message: "No reasoning content in response".to_string(),
+                type_: "missing_content".to_string(),
+                param: None,
+                code: None,
+            })
+        }
+    };
+
     // 检查内容是否存在
     let has_normal_content = !normal_content.trim().is_empty();
     
@@ -385,6 +1185,7 @@ pub(crate) async fn chat(
     };
 
     // Add thinking content to messages for Anthropic
+    let history_len = messages.len();
     let mut anthropic_messages = messages;
     
     // 添加调试日志
@@ -422,42 +1223,175 @@ All changes to files must use this *SEARCH/REPLACE block* format.
 ONLY EVER RETURN CODE IN A *SEARCH/REPLACE BLOCK*!";
 
         // 结合用户的系统提示词（如果有的话）
-        Some(match request.get_system_prompt() {
+        Some(match request.claude_system_prompt(&state.config.system_prompt, &mode) {
             Some(user_system) => format!("{}\n\n{}", claude_system_prompt, user_system),
             None => claude_system_prompt.to_string(),
         })
     } else {
         // normal模式下，保持原来的系统提示词
-        request.get_system_prompt().map(String::from)
+        request.claude_system_prompt(&state.config.system_prompt, &mode)
     };
+    let claude_variables = variables.with_model(&models[1]);
+    let combined_system_prompt = combined_system_prompt.map(|system| claude_variables.render(&system));
+    let mut combined_system_prompt = with_language(combined_system_prompt, language.as_ref());
+    if let Some(envelope) = &envelope {
+        combined_system_prompt = envelope.instruct(combined_system_prompt);
+    }
+    if let Some(trace) = trace.as_mut() {
+        trace.injected_thinking = anthropic_messages.get(history_len).map(|m| m.content.clone());
+    }
+    state.hooks.pre_responder(&mut anthropic_messages, &mut combined_system_prompt, reasoning_content, &mode)?;
+
+    // 会话请求把系统提示词作为缓存前缀发送，并记录缓存冷热
+    let prompt_cache = if anthropic_route.is_none() && !crate::clients::anthropic::should_use_openai_format() {
+        let session = sessions::session(&headers);
+        state.sessions.touch(request.scope.tenant.as_deref(), session.as_deref(), &models[1], combined_system_prompt.as_deref()).await
+    } else {
+        None
+    };
+    let anthropic_client = anthropic_client.with_prompt_cache(prompt_cache.is_some());
+
+    // 被采样的请求在响应完成后镜像到影子模型，共识请求不镜像也不校验
+    let single = request.consensus.is_none();
+    let shadow_input = (single && state.shadow.sampled())
+        .then(|| (anthropic_messages.clone(), combined_system_prompt.clone()));
+    let checks = AnswerChecks::new(&state, &mode, language, envelope.clone());
+    let check_input = (single && checks.enabled())
+        .then(|| (anthropic_messages.clone(), combined_system_prompt.clone()));
+    let refine_input = (single && refine::requested(&state, &request))
+        .then(|| (anthropic_messages.clone(), combined_system_prompt.clone()));
 
     // Call Anthropic API
-    let anthropic_response = anthropic_client.chat(
-        anthropic_messages,
-        combined_system_prompt,
-        &request.anthropic_config
-    ).await?;
-    
-    // Store response metadata
-    let _anthropic_status: u16 = 200;
-    let _anthropic_headers: HashMap<String, String> = HashMap::new(); // Headers not available when using high-level chat method
+    if let Some(trace) = trace.as_mut() {
+        trace.begin("anthropic", &models[1], json!({
+            "messages": anthropic_messages,
+            "system": combined_system_prompt,
+            "body": request.anthropic_config.body,
+        }));
+    }
+    let answered = if single {
+        let anthropic_started = std::time::Instant::now();
+        let anthropic_response = state.mcp.chat(
+            &anthropic_client,
+            anthropic_messages,
+            combined_system_prompt,
+            &request.anthropic_config
+        ).await;
+        stats::record_call(&models[1], false, anthropic_response.as_ref().ok().map(|_| anthropic_started.elapsed()));
+        anthropic_response.map(|response| (response, None))
+    } else {
+        // 共识模式：两个回答模型并行回答，按策略返回两个回答或由裁判选出一个
+        consensus::run(
+            &state,
+            &anthropic_token,
+            &request,
+            anthropic_messages,
+            combined_system_prompt,
+            reasoning_content,
+        )
+        .await
+        .map(|consensus| (consensus.response(), Some(consensus)))
+    };
+    let (mut anthropic_response, consensus) = match answered {
+        Ok(answered) => answered,
+        Err(e) => {
+            state.rate_limiter.settle("anthropic", estimated_tokens, 0);
+            // 回答失败时这次推理的花费白白浪费，记录下来供重试的请求对照
+            if !reasoning_reused {
+                state.record_failed_attempt(UsageRecord {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    timestamp: Utc::now(),
+                    key_name: state.key_store.lookup(&headers).map(|key| key.name.clone()),
+                    user: request.user.clone(),
+                    tenant: request.scope.tenant.clone(),
+                    project: request.scope.project.clone(),
+                    mode: mode.clone(),
+                    variant: request.variant_tag().to_string(),
+                    deepseek_model: models[0].clone(),
+                    anthropic_model: models[1].clone(),
+                    deepseek_input_tokens: deepseek_response.usage.input_tokens,
+                    deepseek_output_tokens: deepseek_response.usage.output_tokens,
+                    cost_usd: deepseek_cost,
+                    request_id: crate::request_id(&headers).map(String::from),
+                    attempts: attempt,
+                    wasted_cost_usd: deepseek_cost,
+                    ..Default::default()
+                });
+            }
+            return Err(e);
+        }
+    };
+    if let Some(trace) = trace.as_mut() {
+        trace.end(json!(anthropic_response));
+    }
+    // 在校验和自我批评再次调用之前记录回答的上游响应，共识模式没有单一的上游响应
+    let anthropic_upstream = anthropic_client
+        .last_response()
+        .filter(|_| request.verbose && single)
+        .map(|meta| external_response(meta, json!(anthropic_response)));
 
-    // Calculate usage costs
-    let deepseek_cost = calculate_deepseek_cost(
-        deepseek_response.usage.input_tokens,
-        deepseek_response.usage.output_tokens,
-        deepseek_response.usage.output_details.reasoning,
-        deepseek_response.usage.input_details.cached,
-        &state.config,
+    // 校验SEARCH/REPLACE块和回答语言，不通过时请Claude修改一次
+    if let Some(input) = check_input {
+        let answer = anthropic_response.text();
+        if let Some(trace) = trace.as_mut() {
+            trace.begin("checks", &models[1], json!({ "answer": answer }));
+        }
+        let revised = checks.revise(&anthropic_client, input, &request.anthropic_config, &answer).await;
+        if let Some(trace) = trace.as_mut() {
+            trace.end(json!(revised));
+        }
+        if let Some(revised) = revised {
+            anthropic_response.usage.add(&revised.usage);
+            anthropic_response.content = revised.content;
+        }
+    }
+
+    // 请求要求时让Claude对照推理自我批评并修改一次，用量单独记录
+    let mut refinement = None;
+    if let Some(input) = refine_input {
+        let draft = anthropic_response.text();
+        if let Some(trace) = trace.as_mut() {
+            trace.begin("refine", &models[1], json!({ "draft": draft }));
+        }
+        let mut refined = refine::refine(
+            &state,
+            &anthropic_client,
+            anthropic_route.as_ref(),
+            input,
+            &request.anthropic_config,
+            reasoning_content,
+            &draft,
+        ).await;
+        if let Some(trace) = trace.as_mut() {
+            trace.end(json!({ "content": refined.content, "usage": refined.usage }));
+        }
+        if let Some(content) = refined.content.take() {
+            anthropic_response.content = content;
+        }
+        refinement = Some(refined);
+    }
+    let refine_usage = refinement.as_ref().map(|refinement| refinement.usage.clone()).unwrap_or_default();
+    let refine_cost = refinement.as_ref().map_or(0.0, |refinement| refinement.cost);
+
+    state.rate_limiter.settle(
+        "anthropic",
+        estimated_tokens,
+        anthropic_response.usage.input_tokens + anthropic_response.usage.output_tokens
+            + refine_usage.input_tokens + refine_usage.output_tokens,
     );
 
-    let anthropic_cost = calculate_anthropic_cost(
-        &anthropic_response.model,
-        anthropic_response.usage.input_tokens,
-        anthropic_response.usage.output_tokens,
-        anthropic_response.usage.cache_creation_input_tokens,
-        anthropic_response.usage.cache_read_input_tokens,
-        &state.config,
+    // Calculate usage costs
+    let anthropic_cost = match &consensus {
+        Some(consensus) => consensus.cost,
+        None => responder_cost(&state, anthropic_route.as_ref(), &anthropic_response),
+    };
+
+    tracing::info!(
+        "用量记录 variant={} deepseek_tokens={} anthropic_tokens={} cost={}",
+        request.variant_tag(),
+        deepseek_response.usage.total_tokens,
+        anthropic_response.usage.input_tokens + anthropic_response.usage.output_tokens,
+        format_cost(deepseek_cost + anthropic_cost + refine_cost),
     );
 
     // Combine thinking content with Anthropic's response
@@ -484,7 +1418,7 @@ ONLY EVER RETURN CODE IN A *SEARCH/REPLACE BLOCK*!";
     
     // Add Anthropic's response blocks with claude prefix
     let claude_content = anthropic_response.content.clone().into_iter()
-        .map(|block| ContentBlock::from_anthropic(block))
+        .map(ContentBlock::from_anthropic)
         .collect::<Vec<_>>();
     
     content.extend(claude_content);
@@ -494,18 +1428,10 @@ ONLY EVER RETURN CODE IN A *SEARCH/REPLACE BLOCK*!";
         created: Utc::now(),
         content: vec![ContentBlock {
             content_type: "text".to_string(),
-            text: content.iter().fold(String::new(), |acc, c| acc + &c.text),
+            text: content.iter().fold(String::new(), |acc, c| acc + c.text.as_str()),
         }],
-        deepseek_response: request.verbose.then(|| ExternalApiResponse {
-            status: 200,
-            headers: HashMap::new(),
-            body: serde_json::Value::Null,
-        }),
-        anthropic_response: request.verbose.then(|| ExternalApiResponse {
-            status: 200,
-            headers: HashMap::new(),
-            body: serde_json::to_value(&anthropic_response).unwrap_or_default(),
-        }),
+        deepseek_response: deepseek_upstream.clone(),
+        anthropic_response: anthropic_upstream.clone(),
         combined_usage: CombinedUsage {
             total_cost: format_cost(deepseek_cost + anthropic_cost),
             deepseek_usage: DeepSeekUsage::default(),
@@ -520,47 +1446,226 @@ ONLY EVER RETURN CODE IN A *SEARCH/REPLACE BLOCK*!";
         },
     };
 
+    // 只包含Claude的响应，并按配置做后处理
+    let answer = anthropic_response.content.into_iter()
+        .map(|block| ContentBlock::from_anthropic(block).text)
+        .collect::<Vec<_>>()
+        .join("")
+        .trim_start() // 去掉开头的所有空白字符，包括换行符
+        .to_string();
+    let pipeline = answer_pipeline(&state, &request, &mode);
+    let postprocess = |answer: String| match pipeline {
+        Some(pipeline) => pipeline.apply(&answer),
+        None => answer,
+    };
+    let mut answer = postprocess(answer);
+    // 共识模式的choices策略把第二个回答作为第二个选项
+    let mut alternatives = consensus
+        .as_ref()
+        .map(|consensus| consensus.answers.iter().skip(1).cloned().map(&postprocess).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if let Some(envelope) = &envelope {
+        answer = envelope.enforce(&answer)?;
+        alternatives = alternatives.iter().map(|answer| envelope.enforce(answer)).collect::<Result<_>>()?;
+    }
+
     // 获取北京时间戳
     let beijing_timestamp = (Utc::now() + Duration::hours(8)).timestamp();
 
+    let reasoning_content = if mode == "full" && has_normal_content {
+        // full模式下只使用原始回答部分作为reasoning_content
+        format!("deepseek原始回答:{}", normal_content)
+    } else {
+        // normal模式下使用完整的reasoning_content
+        reasoning_content.clone()
+    };
+    let finish_reason = finish_reason(anthropic_response.stop_reason.as_deref(), &state.config.finish_reasons);
+    let stop_reason = anthropic_response.stop_reason.clone().filter(|_| request.verbose);
+    let choice = |index: i32, content: String| Choice {
+        index,
+        message: ResponseMessage {
+            role: "assistant".to_string(),
+            content,
+            reasoning_content: Some(reasoning_content.clone()),
+        },
+        finish_reason: finish_reason.clone(),
+        stop_reason: stop_reason.clone(),
+    };
+    // 只包含Claude的响应，不包含thinking标签中的内容
+    let mut choices = vec![choice(0, answer)];
+    choices.extend(alternatives.into_iter().zip(1..).map(|(answer, index)| choice(index, answer)));
+
     // 修改返回部分
     let response = OpenAICompatibleResponse {
         id: uuid::Uuid::new_v4().to_string(),
         object: "chat.completion".to_string(),
         created: beijing_timestamp,
-        model: format!("{}_{}", get_deepseek_default_model(), anthropic_response.model),
-        choices: vec![Choice {
-            index: 0,
-            message: ResponseMessage {
-                role: "assistant".to_string(),
-                // 只包含Claude的响应，不包含thinking标签中的内容
-                content: anthropic_response.content.into_iter()
-                    .map(|block| ContentBlock::from_anthropic(block).text)
-                    .collect::<Vec<_>>()
-                    .join("")
-                    .trim_start() // 去掉开头的所有空白字符，包括换行符
-                    .to_string(),
-                reasoning_content: if mode == "full" && has_normal_content {
-                    // full模式下只使用原始回答部分作为reasoning_content
-                    Some(format!("deepseek原始回答:{}", normal_content))
-                } else {
-                    // normal模式下使用完整的reasoning_content
-                    Some(reasoning_content.clone())
-                },
-            },
-            finish_reason: "stop".to_string(),
-        }],
+        model: response_model(&state, &request, &anthropic_response.model),
+        choices,
         usage: Usage {
-            prompt_tokens: anthropic_response.usage.input_tokens,
-            completion_tokens: anthropic_response.usage.output_tokens,
-            total_tokens: anthropic_response.usage.input_tokens + anthropic_response.usage.output_tokens,
+            prompt_tokens: anthropic_response.usage.input_tokens + refine_usage.input_tokens,
+            completion_tokens: anthropic_response.usage.output_tokens + refine_usage.output_tokens,
+            total_tokens: anthropic_response.usage.input_tokens + anthropic_response.usage.output_tokens
+                + refine_usage.input_tokens + refine_usage.output_tokens,
+            refine: refinement.is_some().then(|| PassUsage {
+                prompt_tokens: refine_usage.input_tokens,
+                completion_tokens: refine_usage.output_tokens,
+                total_tokens: refine_usage.input_tokens + refine_usage.output_tokens,
+            }),
         },
+        sources: request.sources.clone(),
+        quota: request.verbose.then(|| stage_quota(&deepseek_client, &anthropic_client)),
+        upstream: request.verbose.then_some(UpstreamResponses {
+            deepseek: deepseek_upstream,
+            anthropic: anthropic_upstream,
+        }),
+        cached: false,
+        consensus: consensus.map(|consensus| consensus.summary),
     };
 
+    if let Some(mut trace) = trace {
+        trace.answer = response.choices[0].message.content.clone();
+        state.traces.save(&trace.finish(&response.id));
+    }
+
+    state.record_usage(&UsageRecord {
+        id: response.id.clone(),
+        timestamp: Utc::now(),
+        key_name: state.key_store.lookup(&headers).map(|key| key.name.clone()),
+        user: request.user.clone(),
+        tenant: request.scope.tenant.clone(),
+        project: request.scope.project.clone(),
+        mode: mode.clone(),
+        variant: request.variant_tag().to_string(),
+        stream: false,
+        deepseek_model: models[0].clone(),
+        anthropic_model: anthropic_response.model.clone(),
+        deepseek_input_tokens: deepseek_response.usage.input_tokens,
+        deepseek_output_tokens: deepseek_response.usage.output_tokens,
+        anthropic_input_tokens: anthropic_response.usage.input_tokens,
+        anthropic_output_tokens: anthropic_response.usage.output_tokens,
+        refine_input_tokens: refine_usage.input_tokens,
+        refine_output_tokens: refine_usage.output_tokens,
+        refine_cost_usd: refine_cost,
+        cost_usd: deepseek_cost + anthropic_cost + refine_cost,
+        estimated: false,
+        prompt_cache,
+        request_id: crate::request_id(&headers).map(String::from),
+        attempts: attempt,
+        wasted_cost_usd: 0.0,
+    }, &request, &response.choices[0].message.content);
+
+    if let Some((messages, system)) = shadow_input {
+        shadow::mirror(&state, anthropic_token, ShadowRequest {
+            id: response.id.clone(),
+            tenant: request.scope.tenant.clone(),
+            variant: request.variant_tag().to_string(),
+            messages,
+            system,
+            config: request.anthropic_config.clone(),
+            primary_model: anthropic_response.model.clone(),
+            primary_output: response.choices[0].message.content.clone(),
+        });
+    }
+
     // 直接返回OpenAI兼容格式，不要转换为ApiResponse
     Ok(Json(response))
 }
 
+/// Builds the stream chunk carrying a piece of the reasoning or the answer.
+///
+/// Only the fields the chunk format requires are sent: the role goes with
+/// the first chunk of the stream and usage with the last.
+pub(crate) fn delta_event(id: &str, created: i64, model: &str, kind: Delta, text: &str) -> String {
+    let delta = match kind {
+        Delta::Reasoning => json!({ "reasoning_content": text }),
+        Delta::Content => json!({ "content": text }),
+    };
+    json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "delta": delta,
+            "finish_reason": null
+        }]
+    }).to_string()
+}
+
+/// Usage a streaming request has run up so far, for the stream registry
+/// to record if the stream is cut short.
+struct StreamMeter {
+    /// The request's usage record without tokens or cost, taken by
+    /// whichever records the usage first.
+    record: Option<UsageRecord>,
+    estimated_tokens: u32,
+    deepseek_route: Option<HostRoute>,
+    anthropic_route: Option<HostRoute>,
+    anthropic_model: String,
+    /// DeepSeek input and output tokens and cost, once that stage settled.
+    deepseek: Option<(u32, u32, f64)>,
+    /// Estimated reasoning tokens received so far.
+    reasoning_tokens: u32,
+    /// Whether the Claude stage has started.
+    responding: bool,
+    /// Estimated answer tokens received so far.
+    answer_tokens: u32,
+    /// Claude usage reported in the stream so far.
+    reported: Option<ClaudeUsage>,
+}
+
+impl StreamMeter {
+    /// Settles the rate budget of a stream cut short and records the usage
+    /// it ran up, unless its task already recorded it.
+    fn cut_short(&mut self, state: &AppState) {
+        let Some(mut record) = self.record.take() else {
+            return;
+        };
+        let estimated = self.estimated_tokens;
+        let (deepseek_input_tokens, deepseek_output_tokens, deepseek_cost) = match self.deepseek {
+            Some(deepseek) => deepseek,
+            None => {
+                state.rate_limiter.settle("deepseek", estimated, estimated + self.reasoning_tokens);
+                let cost = routed_cost(self.deepseek_route.as_ref(), estimated, self.reasoning_tokens)
+                    .unwrap_or_else(|| calculate_deepseek_cost(estimated, self.reasoning_tokens, 0, 0, &state.config));
+                (estimated, self.reasoning_tokens, cost)
+            }
+        };
+        // 回答阶段未开始时没有Claude用量，占用的预算全部退还
+        let (usage, anthropic_cost) = if self.responding {
+            let mut usage = self.reported.clone().unwrap_or_default();
+            usage.input_tokens = usage.input_tokens.max(estimated);
+            usage.output_tokens = usage.output_tokens.max(self.answer_tokens);
+            let cost = routed_cost(self.anthropic_route.as_ref(), usage.input_tokens, usage.output_tokens).unwrap_or_else(|| {
+                calculate_anthropic_cost(
+                    &self.anthropic_model,
+                    usage.input_tokens,
+                    usage.output_tokens,
+                    usage.cache_creation_input_tokens,
+                    usage.cache_read_input_tokens,
+                    &state.config,
+                )
+            });
+            (usage, cost)
+        } else {
+            (ClaudeUsage::default(), 0.0)
+        };
+        state.rate_limiter.settle("anthropic", estimated, usage.input_tokens + usage.output_tokens);
+        record.timestamp = Utc::now();
+        record.deepseek_input_tokens = deepseek_input_tokens;
+        record.deepseek_output_tokens = deepseek_output_tokens;
+        record.anthropic_input_tokens = usage.input_tokens;
+        record.anthropic_output_tokens = usage.output_tokens;
+        record.cost_usd = deepseek_cost + anthropic_cost;
+        record.estimated = true;
+        tracing::info!("流{}提前结束，记录已产生的用量{:.6}美元", record.id, record.cost_usd);
+        state.usage.record(&record);
+        state.spend.observe(&record);
+    }
+}
+
 /// Handler for streaming chat requests.
 ///
 /// Processes the request through both AI models sequentially,
@@ -576,38 +1681,53 @@ ONLY EVER RETURN CODE IN A *SEARCH/REPLACE BLOCK*!";
 ///
 /// * `Result<SseResponse>` - A stream of Server-Sent Events or an error
 pub(crate) async fn chat_stream(
-    State(_state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     headers: axum::http::HeaderMap,
-    Json(request): Json<ApiRequest>,
+    Json(mut request): Json<ApiRequest>,
 ) -> Result<SseResponse> {
     // 验证系统提示
     if !request.validate_system_prompt() {
         return Err(ApiError::InvalidSystemPrompt);
     }
+    if request.consensus.is_some() {
+        return Err(ApiError::BadRequest {
+            message: "consensus requests are not streamed; send stream: false".to_string(),
+        });
+    }
+    if request.refine == Some(true) {
+        return Err(ApiError::BadRequest {
+            message: "refined requests are not streamed; send stream: false".to_string(),
+        });
+    }
 
     // 提取API令牌
-    let (deepseek_token, anthropic_token) = extract_api_tokens(&headers)?;
+    let (deepseek_token, anthropic_token) = resolve_upstream_tokens(&state, &headers)?;
 
     // 初始化客户端
-    let deepseek_client = DeepSeekClient::new(deepseek_token);
-    let anthropic_client = AnthropicClient::new(anthropic_token);
+    let deepseek_route = stage_route(&state, &request.deepseek_config);
+    let anthropic_route = stage_route(&state, &request.anthropic_config)
+        .filter(|route| route.profile.wire == WireApi::ChatCompletions);
+    let envelope = answer_envelope(&state, &mut request, anthropic_route.is_some());
+    let deepseek_client = DeepSeekClient::new(deepseek_token).with_route(deepseek_route.clone());
+    let anthropic_client = AnthropicClient::new(anthropic_token.clone()).with_route(anthropic_route.clone());
 
-    // 获取当前模式
-    let mode = get_mode();
+    // 获取当前模式，请求中指定的模式优先
+    let mode = request.mode.clone().unwrap_or_else(|| get_mode(&state));
+    let language = TargetLanguage::resolve(request.target_language.as_deref(), &state.config.language);
 
     // 获取系统提示和消息
-    let messages = if mode == "full" {
+    let mut messages = if mode == "full" {
         // full模式下使用带有特定系统提示的消息
-        request.get_messages_with_system()
+        request.get_messages_with_system(&state.config.system_prompt, &mode, language.as_ref().map(TargetLanguage::instruction).as_deref())
     } else {
         // normal模式下只使用原始消息
         let mut messages = Vec::new();
         
-        // 添加系统消息（如果有）
-        if let Some(system) = &request.system {
+        // 添加系统消息（如果有），与部署的基础系统提示合并
+        if let Some(system) = request.deepseek_system_prompt(&state.config.system_prompt, &mode) {
             messages.push(Message {
                 role: Role::System,
-                content: system.clone(),
+                content: system,
             });
         }
         
@@ -617,27 +1737,134 @@ pub(crate) async fn chat_stream(
         messages
     };
 
-    // 创建通道，使用正确的类型
-    let (tx, rx) = tokio::sync::mpsc::channel::<std::result::Result<Event, std::convert::Infallible>>(100);
+    // 渲染系统提示中的模板变量
+    let variables = template_variables(&state, &headers, &request, &mode);
+    let models = stage_models(&request);
+    let deepseek_variables = variables.with_model(&models[0]);
+    for message in messages.iter_mut().filter(|m| m.role == Role::System) {
+        message.content = deepseek_variables.render(&message.content);
+    }
+
+    // verbose请求记录各阶段的输入输出，供之后下载
+    let key_name = state.key_store.lookup(&headers).map(|key| key.name.clone());
+    let mut trace = request
+        .verbose
+        .then(|| Trace::new(&mode, request.variant_tag(), key_name.as_deref(), request.scope.tenant.as_deref()));
+
+    // 在启动流之前检查本地速率预算
+    let estimated_tokens = ratelimit::estimate_messages_tokens(&messages);
+    state.rate_limiter.acquire_all(&["deepseek", "anthropic"], estimated_tokens).await?;
+    // 客户端以同一请求id重试时计为同一请求的又一次尝试
+    let request_id = crate::request_id(&headers).map(String::from);
+    let attempt = state.usage.attempt(request_id.as_deref().unwrap_or_default());
+
+    // 创建通道，客户端读取过慢时按配置的策略处理
+    let streams_config = &state.config.streams;
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::result::Result<Event, std::convert::Infallible>>(
+        streams_config.channel_capacity.max(1),
+    );
     let stream = ReceiverStream::new(rx);
+    let stream_id = uuid::Uuid::new_v4().to_string();
+    let created = chrono::Utc::now().timestamp();
+    let chunk_id = stream_id.clone();
+    let model = response_model(&state, &request, &models[1]);
+    // 推理块可以按配置标注推理模型，其余的块标注回答模型
+    let reasoner = reasoning_model(&state, &request, &models);
+    let (chunk_model, chunk_reasoner) = (model.clone(), reasoner.clone());
+    let mut sink = EventSink::new(
+        tx.clone(),
+        streams_config,
+        Box::new(move |kind, text| {
+            let model = match kind {
+                Delta::Reasoning => &chunk_reasoner,
+                Delta::Content => &chunk_model,
+            };
+            delta_event(&chunk_id, created, model, kind, text)
+        }),
+    )
+    .with_flushing(streams::flushing(streams_config, &headers));
 
-    // 启动异步任务处理流式响应
-    tokio::spawn(async move {
+    // 在受监管的任务中处理流式响应
+    let info = StreamInfo {
+        id: stream_id.clone(),
+        started_at: Utc::now(),
+        state: StreamState::Reasoning,
+        state_since: Utc::now(),
+        model: model.clone(),
+        mode: mode.clone(),
+        key_name: state.key_store.lookup(&headers).map(|key| key.name.clone()),
+    };
+    let usage_key_name = info.key_name.clone();
+    // 流被取消或异常退出时，由监管任务按已产生的用量记录并结算速率预算
+    let meter = Arc::new(std::sync::Mutex::new(StreamMeter {
+        record: Some(UsageRecord {
+            id: stream_id.clone(),
+            timestamp: Utc::now(),
+            key_name: usage_key_name.clone(),
+            user: request.user.clone(),
+            tenant: request.scope.tenant.clone(),
+            project: request.scope.project.clone(),
+            mode: mode.clone(),
+            variant: request.variant_tag().to_string(),
+            stream: true,
+            deepseek_model: models[0].clone(),
+            anthropic_model: models[1].clone(),
+            request_id: request_id.clone(),
+            attempts: attempt,
+            ..Default::default()
+        }),
+        estimated_tokens,
+        deepseek_route: deepseek_route.clone(),
+        anthropic_route: anthropic_route.clone(),
+        anthropic_model: request
+            .anthropic_config
+            .body
+            .get("model")
+            .and_then(|v| v.as_str())
+            .map(String::from)
+            .unwrap_or_else(crate::clients::anthropic::get_claude_default_model),
+        deepseek: None,
+        reasoning_tokens: 0,
+        responding: false,
+        answer_tokens: 0,
+        reported: None,
+    }));
+    let settle = {
+        let (state, meter) = (state.clone(), meter.clone());
+        move || meter.lock().unwrap_or_else(std::sync::PoisonError::into_inner).cut_short(&state)
+    };
+    let session = sessions::session(&headers);
+    let registry = state.clone();
+    registry.streams.spawn(info, tx.clone(), async move {
         // 首先获取 DeepSeek 的推理内容
-        let mut deepseek_stream = deepseek_client.chat_stream(messages.clone(), &request.deepseek_config);
+        if let Some(trace) = trace.as_mut() {
+            trace.begin("deepseek", &models[0], json!({ "messages": messages, "body": request.deepseek_config.body }));
+        }
+        // 重试或重发同一条消息时复用已有的推理
+        let reasoning_key = state.reasoning.key(&request, &models[0], &mode, &messages);
+        let reused = match &reasoning_key {
+            Some(key) => state.reasoning.get(key).await,
+            None => None,
+        };
+        let reasoning_reused = reused.is_some();
+        let mut deepseek_stream = match reused {
+            Some(response) => {
+                tracing::info!("复用缓存的DeepSeek推理");
+                Box::pin(futures::stream::iter([Ok(reasoning::replay(response))]))
+            }
+            None => deepseek_client.chat_stream(messages.clone(), &request.deepseek_config),
+        };
         let mut reasoning_content = String::new();
         let mut normal_content = String::new();
-        let stream_id = uuid::Uuid::new_v4().to_string();
-        let created = chrono::Utc::now().timestamp();
         let heartbeat_interval = Duration::seconds(15);
         let mut last_event_time = Utc::now();
         
-        // 发送角色事件
-        let role_event = serde_json::json!({
+        // 发送角色事件，检索到的来源随第一个事件返回
+        let mut role_event = serde_json::json!({
             "id": stream_id,
             "object": "chat.completion.chunk",
             "created": created,
-            "model": get_deepseek_default_model(),
+            "model": reasoner,
             "choices": [{
                 "index": 0,
                 "delta": {
@@ -645,9 +1872,13 @@ pub(crate) async fn chat_stream(
                 },
                 "finish_reason": null
             }]
-        }).to_string();
+        });
+        if !request.sources.is_empty() {
+            role_event["sources"] = json!(request.sources);
+        }
+        let role_event = role_event.to_string();
         
-        if let Err(e) = tx.send(Ok(Event::default().data(role_event))).await {
+        if let Err(e) = sink.send(role_event).await {
             tracing::error!("发送角色事件失败: {}", e);
             return;
         }
@@ -656,119 +1887,86 @@ pub(crate) async fn chat_stream(
         tracing::info!("流处理 - 发送角色事件成功");
         
         // 流式输出 DeepSeek 的推理内容
+        let mut deepseek_total_tokens = None;
+        let mut deepseek_tokens = None;
         while let Some(result) = deepseek_stream.next().await {
-            if let Ok(response) = result {
-                if let Some(choice) = response.choices.first() {
-                    // 处理推理内容
-                    if let Some(reasoning) = &choice.delta.reasoning_content {
-                        if !reasoning.is_empty() {
-                            // 记录已经处理过的推理内容，避免重复
-                            reasoning_content.push_str(reasoning);
-                            
-                            // 只在normal模式下发送推理内容事件，或者full模式且内容中包含"deepseek原始回答:"
-                            let should_send = mode != "full" || reasoning.contains("deepseek原始回答:");
-                            
-                            if should_send {
-                                // 在full模式下，如果内容包含"deepseek原始回答:"前缀，则只发送该前缀后面的内容
-                                let content_to_send = if mode == "full" && reasoning.contains("deepseek原始回答:") {
-                                    // 只发送"deepseek原始回答:"及之后的内容
-                                    if let Some(idx) = reasoning.find("deepseek原始回答:") {
-                                        &reasoning[idx..]
-                                    } else {
-                                        reasoning
-                                    }
+            let response = match result {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::error!("DeepSeek流处理错误: {}", e);
+                    if !reasoning_reused {
+                        stats::record_call(&models[0], true, None);
+                    }
+                    // 推理中途失败，不再把不完整的推理交给Claude，按已产生的用量记录
+                    meter.lock().unwrap().cut_short(&state);
+                    let error_event = streams::error_chunk((&stream_id, created, &model), &e.details().1);
+                    if let Err(e) = sink.send(error_event).await {
+                        tracing::error!("发送流错误事件失败: {}", e);
+                    }
+                    if let Err(e) = sink.send("[DONE]").await {
+                        tracing::error!("发送DONE标记失败: {}", e);
+                    }
+                    return;
+                }
+            };
+            if let Some(usage) = &response.usage {
+                deepseek_total_tokens = Some(usage.total_tokens);
+                deepseek_tokens = Some((usage.input_tokens, usage.output_tokens));
+            }
+            if let Some(choice) = response.choices.first() {
+                // 处理推理内容
+                if let Some(reasoning) = &choice.delta.reasoning_content {
+                    if !reasoning.is_empty() {
+                        // 记录已经处理过的推理内容，避免重复
+                        reasoning_content.push_str(reasoning);
+                        meter.lock().unwrap().reasoning_tokens += ratelimit::estimate_tokens(reasoning);
+                        
+                        // 只在normal模式下发送推理内容事件，或者full模式且内容中包含"deepseek原始回答:"
+                        let should_send = mode != "full" || reasoning.contains("deepseek原始回答:");
+                        
+                        if should_send {
+                            // 在full模式下，如果内容包含"deepseek原始回答:"前缀，则只发送该前缀后面的内容
+                            let content_to_send = if mode == "full" && reasoning.contains("deepseek原始回答:") {
+                                // 只发送"deepseek原始回答:"及之后的内容
+                                if let Some(idx) = reasoning.find("deepseek原始回答:") {
+                                    &reasoning[idx..]
                                 } else {
                                     reasoning
-                                };
-                            
-                                // 发送推理内容事件（流式）
-                                let reasoning_event = serde_json::json!({
-                                    "id": uuid::Uuid::new_v4().to_string(),
-                                    "object": "chat.completion.chunk",
-                                    "created": chrono::Utc::now().timestamp(),
-                                    "model": get_deepseek_default_model(),
-                                    "choices": [{
-                                        "index": 0,
-                                        "delta": {
-                                            "content": null,
-                                            "reasoning_content": content_to_send,
-                                            "role": "assistant"
-                                        },
-                                        "finish_reason": null,
-                                        "content_filter_results": {
-                                            "hate": {"filtered": false},
-                                            "self_harm": {"filtered": false},
-                                            "sexual": {"filtered": false},
-                                            "violence": {"filtered": false}
-                                        }
-                                    }],
-                                    "system_fingerprint": "",
-                                    "usage": {
-                                        "prompt_tokens": response.usage.as_ref().map_or(0, |u| u.input_tokens),
-                                        "completion_tokens": response.usage.as_ref().map_or(0, |u| u.output_tokens),
-                                        "total_tokens": response.usage.as_ref().map_or(0, |u| u.total_tokens)
-                                    }
-                                }).to_string();
-                                
-                                if let Err(e) = tx.send(Ok(Event::default().data(reasoning_event))).await {
-                                    tracing::error!("发送推理内容事件失败: {}", e);
-                                    return;
                                 }
-                                last_event_time = Utc::now();
+                            } else {
+                                reasoning
+                            };
+                        
+                            if let Err(e) = sink.delta(Delta::Reasoning, content_to_send).await {
+                                tracing::error!("发送推理内容事件失败: {}", e);
+                                return;
                             }
+                            last_event_time = Utc::now();
                         }
                     }
-                    
-                    // 处理普通内容
-                    if let Some(content) = &choice.delta.content {
-                        if !content.is_empty() {
-                            // 记录普通内容
-                            let is_first_content = normal_content.is_empty();
-                            normal_content.push_str(content);
-                            
-                            // 在full模式下流式发送普通内容
-                            if mode == "full" {
-                                // 发送普通内容作为推理内容的一部分（流式）
-                                let normal_as_reasoning_event = serde_json::json!({
-                                    "id": uuid::Uuid::new_v4().to_string(),
-                                    "object": "chat.completion.chunk",
-                                    "created": chrono::Utc::now().timestamp(),
-                                    "model": get_deepseek_default_model(),
-                                    "choices": [{
-                                        "index": 0,
-                                        "delta": {
-                                            "content": null,
-                                            "reasoning_content": if is_first_content {
-                                                // 首次出现普通内容时，添加前缀
-                                                format!("deepseek原始回答:{}", content)
-                                            } else {
-                                                // 后续的普通内容直接发送
-                                                content.to_string()
-                                            },
-                                            "role": "assistant"
-                                        },
-                                        "finish_reason": null,
-                                        "content_filter_results": {
-                                            "hate": {"filtered": false},
-                                            "self_harm": {"filtered": false},
-                                            "sexual": {"filtered": false},
-                                            "violence": {"filtered": false}
-                                        }
-                                    }],
-                                    "system_fingerprint": "",
-                                    "usage": {
-                                        "prompt_tokens": response.usage.as_ref().map_or(0, |u| u.input_tokens),
-                                        "completion_tokens": content.chars().count() as u32,
-                                        "total_tokens": response.usage.as_ref().map_or(0, |u| u.total_tokens)
-                                    }
-                                }).to_string();
-                                
-                                if let Err(e) = tx.send(Ok(Event::default().data(normal_as_reasoning_event))).await {
-                                    tracing::error!("发送普通内容流事件失败: {}", e);
-                                    return;
-                                }
-                                last_event_time = Utc::now();
+                }
+                
+                // 处理普通内容
+                if let Some(content) = &choice.delta.content {
+                    if !content.is_empty() {
+                        // 记录普通内容
+                        let is_first_content = normal_content.is_empty();
+                        normal_content.push_str(content);
+                        meter.lock().unwrap().reasoning_tokens += ratelimit::estimate_tokens(content);
+                        
+                        // 在full模式下流式发送普通内容
+                        if mode == "full" {
+                            // 发送普通内容作为推理内容的一部分（流式），首次出现时添加前缀
+                            let text = if is_first_content {
+                                format!("deepseek原始回答:{}", content)
+                            } else {
+                                content.to_string()
+                            };
+                            if let Err(e) = sink.delta(Delta::Reasoning, &text).await {
+                                tracing::error!("发送普通内容流事件失败: {}", e);
+                                return;
                             }
+                            last_event_time = Utc::now();
                         }
                     }
                 }
@@ -777,6 +1975,35 @@ pub(crate) async fn chat_stream(
 
         // 添加调试日志
         tracing::info!("流处理 - 当前模式: {}, DeepSeek流处理完成", mode);
+
+        // 上游未返回用量时按内容估算
+        let deepseek_actual_tokens = deepseek_total_tokens.unwrap_or_else(|| {
+            estimated_tokens
+                + ratelimit::estimate_tokens(&reasoning_content)
+                + ratelimit::estimate_tokens(&normal_content)
+        });
+        state.rate_limiter.settle("deepseek", estimated_tokens, deepseek_actual_tokens);
+        let (deepseek_input_tokens, deepseek_output_tokens) = deepseek_tokens
+            .unwrap_or((estimated_tokens, deepseek_actual_tokens.saturating_sub(estimated_tokens)));
+        let deepseek_cost = routed_cost(deepseek_route.as_ref(), deepseek_input_tokens, deepseek_output_tokens)
+            .unwrap_or_else(|| calculate_deepseek_cost(deepseek_input_tokens, deepseek_output_tokens, 0, 0, &state.config));
+        meter.lock().unwrap().deepseek = Some((deepseek_input_tokens, deepseek_output_tokens, deepseek_cost));
+        if let Some(trace) = trace.as_mut() {
+            trace.end(json!({
+                "reasoning_content": reasoning_content,
+                "content": normal_content,
+                "total_tokens": deepseek_total_tokens,
+            }));
+            trace.stream_timing(deepseek_client.stream_timing());
+        }
+        if !reasoning_reused {
+            let deepseek_ttft = deepseek_client.stream_timing().map(|timing| std::time::Duration::from_millis(timing.ttft_ms));
+            stats::record_call(&models[0], true, deepseek_ttft);
+            if let Some(key) = &reasoning_key {
+                let response = reasoning::collected(&models[0], &reasoning_content, &normal_content);
+                state.reasoning.put(key, &response).await;
+            }
+        }
         
         // 将推理内容添加到消息中
         let mut anthropic_messages = messages.clone();
@@ -814,27 +2041,97 @@ You NEVER leave comments describing code without implementing it!
 You always COMPLETELY IMPLEMENT the needed code!
 Describe each change with a *SEARCH/REPLACE block* per the examples below.
 All changes to files must use this *SEARCH/REPLACE block* format.
-ONLY EVER RETURN CODE IN A *SEARCH/REPLACE BLOCK*!
-Always reply to the user in chinese.";
+ONLY EVER RETURN CODE IN A *SEARCH/REPLACE BLOCK*!";
 
             // 结合用户的系统提示词（如果有的话）
-            Some(match request.get_system_prompt() {
+            Some(match request.claude_system_prompt(&state.config.system_prompt, &mode) {
                 Some(user_system) => format!("{}\n\n{}", claude_system_prompt, user_system),
                 None => claude_system_prompt.to_string(),
             })
         } else {
             // normal模式下，保持原来的系统提示词
-            request.get_system_prompt().map(String::from)
+            request.claude_system_prompt(&state.config.system_prompt, &mode)
         };
+        let claude_variables = variables.with_model(&models[1]);
+        let combined_system_prompt = combined_system_prompt.map(|system| claude_variables.render(&system));
+        let mut combined_system_prompt = with_language(combined_system_prompt, language.as_ref());
+        if let Some(envelope) = &envelope {
+            combined_system_prompt = envelope.instruct(combined_system_prompt);
+        }
+        let mut soft_limit = SoftLimit::new(&state.config.soft_limit);
+        if let Some(soft_limit) = &soft_limit {
+            combined_system_prompt = soft_limit.instruct(combined_system_prompt);
+        }
+        if let Some(trace) = trace.as_mut() {
+            trace.injected_thinking = anthropic_messages.get(messages.len()).map(|m| m.content.clone());
+        }
+        if let Err(e) =
+            state.hooks.pre_responder(&mut anthropic_messages, &mut combined_system_prompt, &reasoning_content, &mode)
+        {
+            let error_event = streams::error_chunk((&stream_id, created, &model), &e.details().1);
+            if let Err(e) = sink.send(error_event).await {
+                tracing::error!("发送钩子错误事件失败: {}", e);
+            }
+            if let Err(e) = sink.send("[DONE]").await {
+                tracing::error!("发送DONE标记失败: {}", e);
+            }
+            return;
+        }
+
+        // 会话请求把系统提示词作为缓存前缀发送，并记录缓存冷热
+        let prompt_cache = if anthropic_route.is_none() && !crate::clients::anthropic::should_use_openai_format() {
+            state.sessions.touch(request.scope.tenant.as_deref(), session.as_deref(), &models[1], combined_system_prompt.as_deref()).await
+        } else {
+            None
+        };
+        let anthropic_client = anthropic_client.with_prompt_cache(prompt_cache.is_some());
+
+        // 被采样的请求在响应完成后镜像到影子模型
+        let shadow_input = state
+            .shadow
+            .sampled()
+            .then(|| (anthropic_messages.clone(), combined_system_prompt.clone()));
+        let checks = AnswerChecks::new(&state, &mode, language, envelope.clone());
+        let mut check_input = checks.enabled().then(|| (anthropic_messages.clone(), combined_system_prompt.clone()));
 
         // 获取 Anthropic 的流式响应
-        let mut anthropic_stream = anthropic_client.chat_stream(
+        if let Some(trace) = trace.as_mut() {
+            trace.begin("anthropic", &models[1], json!({
+                "messages": anthropic_messages,
+                "system": combined_system_prompt,
+                "body": request.anthropic_config.body,
+            }));
+        }
+        state.streams.set_state(&stream_id, StreamState::Responding);
+        meter.lock().unwrap().responding = true;
+        // 推理结束、回答开始，客户端可以从“思考中”切换到“回答中”
+        let responder_event = json!({
+            "id": stream_id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{ "index": 0, "delta": {}, "finish_reason": null }],
+            "deepclaude_event": "responder_started",
+        });
+        if let Err(e) = sink.send(responder_event.to_string()).await {
+            tracing::error!("发送回答开始事件失败: {}", e);
+            return;
+        }
+        let anthropic_started = std::time::Instant::now();
+        let mut anthropic_stream = state.mcp.chat_stream(
+            &anthropic_client,
             anthropic_messages,
             combined_system_prompt,
             &request.anthropic_config
         );
 
         let mut content_buffer = String::new();
+        let mut stop_reason: Option<String> = None;
+        // 上游在流中报告的Claude用量，两种格式都会累计到这里
+        let mut reported_usage: Option<ClaudeUsage> = None;
+        let pipeline = answer_pipeline(&state, &request, &mode);
+        // 需要后处理或校验时缓冲回答，等Claude完成后整体发送
+        let buffered = pipeline.is_some() || check_input.is_some() || state.hooks.has(HookPoint::PostResponse);
         
         // 获取模型信息
         let default_model = crate::clients::anthropic::get_claude_default_model();
@@ -854,7 +2151,24 @@ Always reply to the user in chinese.";
         tracing::info!("使用API类型: {}, 模型: {}", api_type, model_str);
 
         // 处理 Anthropic 的流式响应
-        while let Some(result) = anthropic_stream.next().await {
+        loop {
+            // 上游停顿时，暂存的回答到了分句等待时间也要发出
+            let next = match sink.held_until() {
+                Some(until) => match tokio::time::timeout_at(until, anthropic_stream.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        if let Err(e) = sink.release_held() {
+                            tracing::error!("发送暂存的回答失败: {}", e);
+                            break;
+                        }
+                        continue;
+                    }
+                },
+                None => anthropic_stream.next().await,
+            };
+            let Some(result) = next else {
+                break;
+            };
             match result {
                 Ok(response) => {
                     // 检查是否需要发送心跳
@@ -865,7 +2179,7 @@ Always reply to the user in chinese.";
                             "id": uuid::Uuid::new_v4().to_string(),
                             "object": "chat.completion.chunk",
                             "created": chrono::Utc::now().timestamp(),
-                            "model": get_deepseek_default_model(),
+                            "model": model,
                             "choices": [{
                                 "index": 0,
                                 "delta": {},
@@ -874,7 +2188,8 @@ Always reply to the user in chinese.";
                             "heartbeat": true
                         }).to_string();
                         
-                        if let Err(e) = tx.send(Ok(Event::default().data(heartbeat_event))).await {
+                        // 通道已满时客户端还有未读事件，跳过心跳
+                        if let Err(e) = sink.heartbeat(heartbeat_event) {
                             tracing::error!("发送心跳失败: {}", e);
                             break;
                         }
@@ -883,82 +2198,144 @@ Always reply to the user in chinese.";
 
                     // 处理 Anthropic 的响应内容
                     match response {
-                        StreamEvent::ContentBlockDelta { delta, .. } => {
-                            if !delta.text.is_empty() {
-                                // 添加到内容缓冲区
-                                content_buffer.push_str(&delta.text);
-                                
-                                // 直接发送内容，不添加前缀
-                                let content_to_send = delta.text.to_string();
-                                
-                                // 发送普通内容事件
-                                let content_event = serde_json::json!({
-                                    "id": uuid::Uuid::new_v4().to_string(),
+                        StreamEvent::ContentBlockDelta { delta, .. } if !delta.text.is_empty() => {
+                            // 添加到内容缓冲区
+                            content_buffer.push_str(&delta.text);
+                            meter.lock().unwrap().answer_tokens += ratelimit::estimate_tokens(&delta.text);
+
+                            // 回答超过软限制时提醒一次，客户端可以提前告知用户
+                            if let Some(warning) = soft_limit.as_mut().and_then(|limit| limit.check(&delta.text)) {
+                                let warning_event = json!({
+                                    "id": stream_id,
                                     "object": "chat.completion.chunk",
-                                    "created": chrono::Utc::now().timestamp(),
-                                    "model": get_deepseek_default_model(),
-                                    "choices": [{
-                                        "index": 0,
-                                        "delta": {
-                                            "content": content_to_send,
-                                            "reasoning_content": null,
-                                            "role": "assistant"
-                                        },
-                                        "finish_reason": null,
-                                        "content_filter_results": {
-                                            "hate": {"filtered": false},
-                                            "self_harm": {"filtered": false},
-                                            "sexual": {"filtered": false},
-                                            "violence": {"filtered": false}
-                                        }
-                                    }],
-                                    "system_fingerprint": "",
-                                    "usage": {
-                                        "prompt_tokens": 0,
-                                        "completion_tokens": content_to_send.chars().count() as u32,
-                                        "total_tokens": content_to_send.chars().count() as u32
-                                    }
-                                }).to_string();
-                                
-                                if let Err(e) = tx.send(Ok(Event::default().data(content_event))).await {
-                                    tracing::error!("发送内容事件失败: {}", e);
+                                    "created": created,
+                                    "model": model,
+                                    "choices": [{ "index": 0, "delta": {}, "finish_reason": null }],
+                                    "soft_limit": warning,
+                                });
+                                if let Err(e) = sink.send(warning_event.to_string()).await {
+                                    tracing::error!("发送软限制提醒失败: {}", e);
                                     break;
                                 }
-                                last_event_time = now;
+                            }
+
+                            if buffered {
+                                continue;
+                            }
+                            
+                            // 发送普通内容事件
+                            if let Err(e) = sink.delta(Delta::Content, &delta.text).await {
+                                tracing::error!("发送内容事件失败: {}", e);
+                                break;
+                            }
+                            last_event_time = now;
+                        }
+                        StreamEvent::MessageStart { message } => {
+                            reported_usage.get_or_insert_with(Default::default).update(&message.usage);
+                            meter.lock().unwrap().reported = reported_usage.clone();
+                        }
+                        StreamEvent::MessageDelta { delta, usage } => {
+                            if let Some(usage) = &usage {
+                                reported_usage.get_or_insert_with(Default::default).update(usage);
+                                meter.lock().unwrap().reported = reported_usage.clone();
+                            }
+                            if delta.stop_reason.is_some() {
+                                stop_reason = delta.stop_reason;
                             }
                         }
                         StreamEvent::MessageStop => {
-                            // 发送完成事件
-                            let finish_event = serde_json::json!({
-                                "id": stream_id,
-                                "object": "chat.completion.chunk",
-                                "created": created,
-                                "model": get_deepseek_default_model(),
-                                "choices": [{
-                                    "index": 0,
-                                    "delta": {},
-                                    "finish_reason": "stop",
-                                    "content_filter_results": {
-                                        "hate": {"filtered": false},
-                                        "self_harm": {"filtered": false},
-                                        "sexual": {"filtered": false},
-                                        "violence": {"filtered": false}
+                            state.streams.set_state(&stream_id, StreamState::Finishing);
+                            if let Some(trace) = trace.as_mut() {
+                                trace.end(json!({ "content": content_buffer }));
+                                trace.stream_timing(anthropic_client.stream_timing());
+                            }
+                            if let Some(input) = check_input.take() {
+                                if let Some(trace) = trace.as_mut() {
+                                    trace.begin("checks", &models[1], json!({ "answer": content_buffer }));
+                                }
+                                let revised = checks
+                                    .revise(&anthropic_client, input, &request.anthropic_config, &content_buffer)
+                                    .await;
+                                if let Some(trace) = trace.as_mut() {
+                                    trace.end(json!(revised));
+                                }
+                                if let Some(revised) = revised {
+                                    content_buffer = revised.text();
+                                }
+                            }
+                            if let Some(pipeline) = pipeline {
+                                content_buffer = pipeline.apply(&content_buffer);
+                            }
+                            if let Some(envelope) = &envelope {
+                                match envelope.enforce(&content_buffer) {
+                                    Ok(answer) => content_buffer = answer,
+                                    Err(e) => {
+                                        let error_event = streams::error_chunk((&stream_id, created, &model), &e.details().1);
+                                        if let Err(e) = sink.send(error_event).await {
+                                            tracing::error!("发送信封错误事件失败: {}", e);
+                                        }
+                                        if let Err(e) = sink.send("[DONE]").await {
+                                            tracing::error!("发送DONE标记失败: {}", e);
+                                        }
+                                        break;
+                                    }
+                                }
+                            }
+                            if let Some(trace) = trace.as_mut() {
+                                trace.answer = content_buffer.clone();
+                            }
+                            // 流式响应头已发送，钩子设置的响应头只对非流式响应生效
+                            match state.hooks.post_response(&mut content_buffer) {
+                                Ok(_) => {}
+                                // 钩子否决时回答尚未发送，以content_filter结束流
+                                Err(ApiError::Forbidden { message }) => {
+                                    tracing::info!("流{}的回答被post_response钩子拦截: {}", stream_id, message);
+                                    let finish_event = finish_chunk(&state, (&stream_id, created, &model), "content_filter", 0);
+                                    if let Err(e) = sink.send(finish_event.to_string()).await {
+                                        tracing::error!("发送完成事件失败: {}", e);
+                                    }
+                                    if let Err(e) = sink.send("[DONE]").await {
+                                        tracing::error!("发送DONE标记失败: {}", e);
                                     }
-                                }],
-                                "system_fingerprint": "",
-                                "usage": {
-                                    "prompt_tokens": 0,
-                                    "completion_tokens": content_buffer.chars().count() as u32,
-                                    "total_tokens": content_buffer.chars().count() as u32
+                                    break;
                                 }
-                            }).to_string();
+                                Err(e) => {
+                                    let error_event = streams::error_chunk((&stream_id, created, &model), &e.details().1);
+                                    if let Err(e) = sink.send(error_event).await {
+                                        tracing::error!("发送钩子错误事件失败: {}", e);
+                                    }
+                                    if let Err(e) = sink.send("[DONE]").await {
+                                        tracing::error!("发送DONE标记失败: {}", e);
+                                    }
+                                    break;
+                                }
+                            }
+                            if buffered && !content_buffer.is_empty() {
+                                if let Err(e) = sink.delta(Delta::Content, &content_buffer).await {
+                                    tracing::error!("发送内容事件失败: {}", e);
+                                    break;
+                                }
+                            }
+
+                            // 发送完成事件
+                            let mut finish_event = finish_chunk(
+                                &state,
+                                (&stream_id, created, &model),
+                                &finish_reason(stop_reason.as_deref(), &state.config.finish_reasons),
+                                content_buffer.chars().count() as u32,
+                            );
+                            if request.verbose {
+                                finish_event["quota"] = stage_quota(&deepseek_client, &anthropic_client);
+                                finish_event["choices"][0]["stop_reason"] = json!(stop_reason);
+                            }
+                            let finish_event = finish_event.to_string();
                             
-                            if let Err(e) = tx.send(Ok(Event::default().data(finish_event))).await {
+                            if let Err(e) = sink.send(finish_event).await {
                                 tracing::error!("发送完成事件失败: {}", e);
                             }
                             
                             // 发送 [DONE] 标记作为特殊的 SSE 事件
-                            if let Err(e) = tx.send(Ok(Event::default().data("[DONE]"))).await {
+                            if let Err(e) = sink.send("[DONE]").await {
                                 tracing::error!("发送DONE标记失败: {}", e);
                             }
                             break;
@@ -977,45 +2354,168 @@ Always reply to the user in chinese.";
                 
                     // 其他错误正常处理
                     tracing::error!("流处理错误: {}", e);
-                    let error_message = format!("Internal server error: {}", e);
                     
-                    // 发送错误事件
-                    if let Err(e) = tx.send(Ok(Event::default().data(format!(r#"data: {{"error": "{error_message}"}}"#)))).await {
+                    // 回答没有产出，退还为它占用的速率预算
+                    state.rate_limiter.settle("anthropic", estimated_tokens, 0);
+                    meter.lock().unwrap().record = None;
+
+                    // 以finish_reason为error的结束块和[DONE]结束流，客户端SDK才能正常结束迭代
+                    let error_event = streams::error_chunk((&stream_id, created, &model), &e.details().1);
+                    if let Err(e) = sink.send(error_event).await {
                         tracing::error!("发送流错误事件失败: {}", e);
                     }
-                    
+                    if let Err(e) = sink.send("[DONE]").await {
+                        tracing::error!("发送DONE标记失败: {}", e);
+                    }
+
+                    // 回答失败时这次推理的花费白白浪费，记录下来供重试的请求对照
+                    if !reasoning_reused {
+                        state.record_failed_attempt(UsageRecord {
+                            id: stream_id.clone(),
+                            timestamp: Utc::now(),
+                            key_name: usage_key_name,
+                            user: request.user.clone(),
+                            tenant: request.scope.tenant.clone(),
+                            project: request.scope.project.clone(),
+                            mode: mode.clone(),
+                            variant: request.variant_tag().to_string(),
+                            stream: true,
+                            deepseek_model: models[0].clone(),
+                            anthropic_model: models[1].clone(),
+                            deepseek_input_tokens,
+                            deepseek_output_tokens,
+                            cost_usd: deepseek_cost,
+                            estimated: deepseek_tokens.is_none(),
+                            request_id,
+                            attempts: attempt,
+                            wasted_cost_usd: deepseek_cost,
+                            ..Default::default()
+                        });
+                    }
                     return;
                 }
             }
         }
         
+        let anthropic_actual_tokens = estimated_tokens + ratelimit::estimate_tokens(&content_buffer);
+        meter.lock().unwrap().record = None;
+        state.rate_limiter.settle("anthropic", estimated_tokens, anthropic_actual_tokens);
+        tracing::info!(
+            "用量记录 variant={} deepseek_tokens={} anthropic_tokens≈{}",
+            request.variant_tag(),
+            deepseek_actual_tokens,
+            anthropic_actual_tokens,
+        );
+
         // 确保所有流都已关闭
         drop(anthropic_stream);
-    });
+
+        // 使用MCP工具时没有逐token的流，以整体完成时间计
+        let anthropic_ttft = anthropic_client
+            .stream_timing()
+            .map(|timing| std::time::Duration::from_millis(timing.ttft_ms))
+            .or_else(|| (!content_buffer.is_empty()).then(|| anthropic_started.elapsed()));
+        stats::record_call(&models[1], true, anthropic_ttft);
+
+        if let Some(trace) = trace {
+            state.traces.save(&trace.finish(&stream_id));
+        }
+
+        // 上游未在流中报告用量时，Claude用量按内容估算
+        let estimated = deepseek_tokens.is_none() || reported_usage.is_none();
+        let anthropic_usage = reported_usage.unwrap_or_else(|| ClaudeUsage {
+            input_tokens: estimated_tokens,
+            output_tokens: ratelimit::estimate_tokens(&content_buffer),
+            ..Default::default()
+        });
+        let anthropic_input_tokens = anthropic_usage.input_tokens;
+        let anthropic_output_tokens = anthropic_usage.output_tokens;
+        let anthropic_cost = routed_cost(anthropic_route.as_ref(), anthropic_input_tokens, anthropic_output_tokens)
+            .unwrap_or_else(|| calculate_anthropic_cost(
+                model_str,
+                anthropic_input_tokens,
+                anthropic_output_tokens,
+                anthropic_usage.cache_creation_input_tokens,
+                anthropic_usage.cache_read_input_tokens,
+                &state.config,
+            ));
+        state.record_usage(&UsageRecord {
+            id: stream_id.clone(),
+            timestamp: Utc::now(),
+            key_name: usage_key_name,
+            user: request.user.clone(),
+            tenant: request.scope.tenant.clone(),
+            project: request.scope.project.clone(),
+            mode: mode.clone(),
+            variant: request.variant_tag().to_string(),
+            stream: true,
+            deepseek_model: models[0].clone(),
+            anthropic_model: models[1].clone(),
+            deepseek_input_tokens,
+            deepseek_output_tokens,
+            anthropic_input_tokens,
+            anthropic_output_tokens,
+            refine_input_tokens: 0,
+            refine_output_tokens: 0,
+            refine_cost_usd: 0.0,
+            cost_usd: deepseek_cost + anthropic_cost,
+            estimated,
+            prompt_cache,
+            request_id,
+            attempts: attempt,
+            wasted_cost_usd: 0.0,
+        }, &request, &content_buffer);
+
+        if let Some((messages, system)) = shadow_input {
+            shadow::mirror(&state, anthropic_token, ShadowRequest {
+                id: stream_id,
+                tenant: request.scope.tenant.clone(),
+                variant: request.variant_tag().to_string(),
+                messages,
+                system,
+                config: request.anthropic_config.clone(),
+                primary_model: model_str.to_string(),
+                primary_output: content_buffer,
+            });
+        }
+    }, settle);
 
     Ok(SseResponse::new(stream))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct EnvUpdateRequest {
     pub variables: HashMap<String, String>,
 }
 
 /// 更新.env文件中的环境变量
+#[utoipa::path(
+    post,
+    path = "/v1/env/update",
+    tag = "operations",
+    request_body = EnvUpdateRequest,
+    responses((status = 200, description = "The variables were written to .env", body = Object))
+)]
 pub async fn update_env_variables(
+    State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
     AxumJson(payload): AxumJson<EnvUpdateRequest>,
 ) -> Result<AxumJson<serde_json::Value>> {
-    let current_dir = std::env::current_dir().map_err(|e| ApiError::Internal {
-        message: format!("无法获取当前目录: {}", e),
-    })?;
-
-    let env_path = current_dir.join(".env");
+    // 配置了任何管理员令牌后，只有owner可以修改.env
+    let actor = if admin::admin_configured(&state) {
+        admin::require_admin(&state, &headers, AdminRole::Owner)?.name
+    } else {
+        admin::actor(&headers)
+    };
+    let env_path = paths::env_file();
     
     // 读取现有的.env文件内容
-    let mut env_content = match fs::read_to_string(&env_path) {
-        Ok(content) => content,
-        Err(_) => String::new(), // 如果文件不存在，创建一个新的
-    };
+    // 如果文件不存在，创建一个新的
+    let mut env_content = fs::read_to_string(&env_path).unwrap_or_default();
+
+    // 审计记录只保存变量名，值可能是密钥
+    let mut names: Vec<_> = payload.variables.keys().cloned().collect();
+    names.sort();
 
     // 更新环境变量
     for (key, value) in payload.variables {
@@ -1047,6 +2547,13 @@ pub async fn update_env_variables(
         message: format!("无法写入.env文件: {}", e),
     })?;
 
+    state.audit.record(&actor, "env.update", json!({ "variables": names }));
+
+    // 让新的上游配置立即对后续请求生效
+    if let Err(errors) = providers::reload(false, &state.config.secrets).await {
+        tracing::warn!("环境变量已写入，但上游服务配置未重新加载: {}", errors.join("; "));
+    }
+
     Ok(AxumJson(json!({
         "status": "success",
         "message": "环境变量已更新"
@@ -1054,12 +2561,21 @@ pub async fn update_env_variables(
 }
 
 /// 获取.env文件中的所有环境变量
-pub async fn get_env_variables() -> Result<AxumJson<serde_json::Value>> {
-    let current_dir = std::env::current_dir().map_err(|e| ApiError::Internal {
-        message: format!("无法获取当前目录: {}", e),
-    })?;
-
-    let env_path = current_dir.join(".env");
+#[utoipa::path(
+    get,
+    path = "/v1/env/variables",
+    tag = "operations",
+    responses((status = 200, description = "The variables in .env", body = Object))
+)]
+pub async fn get_env_variables(
+    State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+) -> Result<AxumJson<serde_json::Value>> {
+    // 读取的变量中包含上游密钥，与修改一样需要owner
+    if admin::admin_configured(&state) {
+        admin::require_admin(&state, &headers, AdminRole::Owner)?;
+    }
+    let env_path = paths::env_file();
     
     // 读取.env文件内容
     let env_content = fs::read_to_string(&env_path).map_err(|e| ApiError::Internal {
@@ -1084,3 +2600,77 @@ pub async fn get_env_variables() -> Result<AxumJson<serde_json::Value>> {
         "variables": variables
     })))
 }
+
+/// Exports the upstream streaming gauges, stream repairs, remaining upstream quota,
+/// per-key spend, coalesced requests and batch pacing in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses((status = 200, description = "Prometheus metrics", body = String, content_type = "text/plain"))
+)]
+pub async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let body = format!(
+        "{}{}{}{}{}{}{}{}{}",
+        stats::render(),
+        quota::render(),
+        repair::render(),
+        state.spend.render(),
+        state.usage.render(),
+        state.shedder.render(),
+        state.coalescer.render(),
+        state.ip_guard.render(),
+        state.pacer.render()
+    );
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// Lists the models requests can name, in the OpenAI `/v1/models` format.
+///
+/// Includes the default models of both stages, every `[model_aliases]`
+/// and `[model_pools]` entry, and the OpenRouter catalog as `openrouter/<model id>` with its
+/// per-million-token prices.
+#[utoipa::path(
+    get,
+    path = "/v1/models",
+    tag = "chat",
+    responses((status = 200, description = "The model list", body = Object))
+)]
+pub async fn list_models(State(state): State<Arc<AppState>>) -> AxumJson<serde_json::Value> {
+    let settings = providers::current();
+    let mut data = vec![
+        json!({ "id": settings.deepseek_model, "object": "model", "owned_by": "deepseek" }),
+        json!({ "id": settings.claude_model, "object": "model", "owned_by": "anthropic" }),
+    ];
+
+    let mut aliases: Vec<_> = state.config.model_aliases.iter().collect();
+    aliases.sort_by_key(|(name, _)| name.as_str());
+    for (name, alias) in aliases {
+        let mut entry = json!({ "id": name, "object": "model", "owned_by": alias.host });
+        if alias.host == "openrouter" {
+            if let Some(price) = openrouter::price(&alias.model) {
+                entry["pricing"] = json!(price);
+            }
+        }
+        data.push(entry);
+    }
+
+    let mut pools: Vec<_> = state.pools.names().collect();
+    pools.sort();
+    for name in pools {
+        data.push(json!({ "id": name, "object": "model", "owned_by": "pool" }));
+    }
+
+    for model in openrouter::models().iter() {
+        data.push(json!({
+            "id": format!("{}{}", hosts::OPENROUTER_PREFIX, model.id),
+            "object": "model",
+            "owned_by": "openrouter",
+            "name": model.name,
+            "context_length": model.context_length,
+            "pricing": model.pricing,
+        }));
+    }
+
+    AxumJson(json!({ "object": "list", "data": data }))
+}
@@ -0,0 +1,205 @@
+//! Scriptable request and response hooks.
+//!
+//! The rhai scripts listed in `[hooks] scripts` are compiled at startup. A
+//! script takes part in a hook point by defining a function of that name,
+//! which works on the context bound to `this`:
+//!
+//! - `pre_request()` - before presets, keys and retrieval are applied.
+//!   `this.request` is the chat request and may be changed, including the
+//!   upstream headers in `this.request.deepseek_config.headers` and
+//!   `this.request.anthropic_config.headers`. `this.headers` holds the
+//!   incoming request headers.
+//! - `pre_responder()` - before the Claude stage. `this.messages` and
+//!   `this.system` may be changed; `this.reasoning` and `this.mode` are
+//!   informational.
+//! - `post_response()` - on the final answer. `this.content` may be changed
+//!   and `this.headers` entries are added to the response (not applied to
+//!   streaming responses, whose headers are already sent).
+//!
+//! A script vetoes the request by throwing, e.g. `throw "not allowed"`,
+//! which answers 403 with the thrown message. A streaming answer vetoed by
+//! `post_response` instead ends with `finish_reason: "content_filter"`, as
+//! its status is already sent. Scripts run in order, each seeing the
+//! previous one's changes.
+
+use crate::{
+    config::HooksConfig,
+    error::{ApiError, Result},
+    models::request::{ApiRequest, Message},
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde_json::json;
+use std::path::PathBuf;
+
+/// A point in the request lifecycle where scripts run.
+#[derive(Debug, Clone, Copy)]
+pub enum HookPoint {
+    PreRequest,
+    PreResponder,
+    PostResponse,
+}
+
+impl HookPoint {
+    fn function(self) -> &'static str {
+        match self {
+            HookPoint::PreRequest => "pre_request",
+            HookPoint::PreResponder => "pre_responder",
+            HookPoint::PostResponse => "post_response",
+        }
+    }
+}
+
+struct Script {
+    path: String,
+    ast: AST,
+}
+
+/// The compiled hook scripts.
+pub struct Hooks {
+    engine: Engine,
+    scripts: Vec<Script>,
+}
+
+impl Hooks {
+    /// Compiles the scripts listed in `[hooks]`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a script cannot be read or does not compile.
+    pub fn new(config: &HooksConfig) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+
+        let scripts = config
+            .scripts
+            .iter()
+            .map(|path| {
+                let ast = engine
+                    .compile_file(PathBuf::from(path))
+                    .map_err(|e| anyhow::anyhow!("无法编译钩子脚本{}: {}", path, e))?;
+                Ok(Script { path: path.clone(), ast })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if !scripts.is_empty() {
+            tracing::info!("已加载{}个钩子脚本", scripts.len());
+        }
+        Ok(Self { engine, scripts })
+    }
+
+    /// Returns true if any script defines the hook point.
+    pub fn has(&self, point: HookPoint) -> bool {
+        self.scripts.iter().any(|script| defines(script, point))
+    }
+
+    /// Runs the scripts defining `point` on a context, returning it with
+    /// their changes.
+    fn run(&self, point: HookPoint, context: serde_json::Value) -> Result<serde_json::Value> {
+        let mut context = rhai::serde::to_dynamic(&context).map_err(|e| internal(point, "context", e))?;
+        for script in self.scripts.iter().filter(|script| defines(script, point)) {
+            let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut context);
+            // 返回值不使用，脚本通过this修改上下文
+            let _ = self
+                .engine
+                .call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &script.ast, point.function(), ())
+                .map_err(|e| match e.unwrap_inner() {
+                    // 脚本throw表示否决请求
+                    EvalAltResult::ErrorRuntime(value, _) => {
+                        tracing::info!("钩子脚本{}否决了请求: {}", script.path, value);
+                        ApiError::Forbidden {
+                            message: value.to_string(),
+                        }
+                    }
+                    _ => internal(point, &script.path, e),
+                })?;
+        }
+        rhai::serde::from_dynamic(&context).map_err(|e| internal(point, "context", e))
+    }
+
+    /// Runs the `pre_request` hooks on an incoming request.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Forbidden` if a script vetoes the request and
+    /// `ApiError::Internal` if a script fails or breaks the request shape.
+    pub fn pre_request(&self, request: &mut ApiRequest, headers: &HeaderMap) -> Result<()> {
+        if !self.has(HookPoint::PreRequest) {
+            return Ok(());
+        }
+        let headers: serde_json::Map<String, serde_json::Value> = headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), json!(value.to_str().ok()?))))
+            .collect();
+        let context = self.run(HookPoint::PreRequest, json!({ "request": request, "headers": headers }))?;
+        *request = serde_json::from_value(context["request"].clone()).map_err(|e| ApiError::Internal {
+            message: format!("pre_request hook returned an invalid request: {}", e),
+        })?;
+        Ok(())
+    }
+
+    /// Runs the `pre_responder` hooks on the Claude stage input.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Forbidden` if a script vetoes the request and
+    /// `ApiError::Internal` if a script fails.
+    pub fn pre_responder(
+        &self,
+        messages: &mut Vec<Message>,
+        system: &mut Option<String>,
+        reasoning: &str,
+        mode: &str,
+    ) -> Result<()> {
+        if !self.has(HookPoint::PreResponder) {
+            return Ok(());
+        }
+        let context = self.run(
+            HookPoint::PreResponder,
+            json!({ "messages": messages, "system": system, "reasoning": reasoning, "mode": mode }),
+        )?;
+        *messages = serde_json::from_value(context["messages"].clone()).map_err(|e| ApiError::Internal {
+            message: format!("pre_responder hook returned invalid messages: {}", e),
+        })?;
+        *system = context["system"].as_str().map(String::from);
+        Ok(())
+    }
+
+    /// Runs the `post_response` hooks on the final answer, returning the
+    /// headers they add.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Forbidden` if a script vetoes the response and
+    /// `ApiError::Internal` if a script fails.
+    pub fn post_response(&self, content: &mut String) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        if !self.has(HookPoint::PostResponse) {
+            return Ok(headers);
+        }
+        let context = self.run(HookPoint::PostResponse, json!({ "content": content, "headers": {} }))?;
+        if let Some(text) = context["content"].as_str() {
+            *content = text.to_string();
+        }
+        for (name, value) in context["headers"].as_object().into_iter().flatten() {
+            let value = value.as_str().map(String::from).unwrap_or_else(|| value.to_string());
+            match (HeaderName::try_from(name.as_str()), HeaderValue::try_from(value)) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => tracing::warn!("忽略钩子脚本设置的无效响应头: {}", name),
+            }
+        }
+        Ok(headers)
+    }
+}
+
+fn defines(script: &Script, point: HookPoint) -> bool {
+    script.ast.iter_functions().any(|f| f.name == point.function() && f.params.is_empty())
+}
+
+fn internal(point: HookPoint, source: &str, e: impl std::fmt::Display) -> ApiError {
+    tracing::error!("钩子{}执行失败({}): {}", point.function(), source, e);
+    ApiError::Internal {
+        message: format!("{} hook failed: {}", point.function(), e),
+    }
+}
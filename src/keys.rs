@@ -0,0 +1,541 @@
+//! Virtual API keys issued by this deployment.
+//!
+//! Virtual keys are presented by clients as `Authorization: Bearer <key>`
+//! and carry per-key restrictions (allowed models, modes and a max_tokens
+//! ceiling) that are enforced before a request reaches any upstream provider.
+//!
+//! A key may also carry its own upstream provider keys. These are stored
+//! encrypted (see [`crate::crypto`]) and only decrypted in memory.
+//!
+//! Tenant owners can issue and revoke keys of their own tenant:
+//!
+//! - `POST /v1/keys` - issue a key
+//! - `GET /v1/keys` - list the keys issued for the caller's tenant
+//! - `DELETE /v1/keys/{id}` - revoke an issued key
+//!
+//! These take a virtual key with `manage_keys = true` that is bound to a
+//! tenant. An issued key belongs to that tenant and can never do more than
+//! the key that issued it: its models, modes, projects and `max_tokens`
+//! must lie within the issuer's, which it inherits by default, and it
+//! cannot issue keys itself. Key names are unique across configured and
+//! issued keys, since usage and dead letters are attributed by name, so
+//! issuing a key under a taken name is refused with a 409. A tenant holds at most `[tenants.<id>]
+//! max_keys` issued keys; the default of 0 turns self-service off for it.
+//! Issued keys carry no upstream provider keys of their own, and are kept
+//! by the `[storage]` backend so they survive restarts, except with the
+//! `memory` backend.
+
+use crate::{
+    crypto::{self, MasterKey},
+    error::{ApiError, ErrorResponse, Result},
+    handlers::AppState,
+    models::request::ApiRequest,
+    storage::KeyBackend,
+};
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use utoipa::ToSchema;
+
+/// A single virtual key and its restrictions.
+///
+/// Empty allowlists mean "no restriction".
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VirtualKey {
+    pub key: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub allowed_models: Vec<String>,
+    #[serde(default)]
+    pub allowed_modes: Vec<String>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub deepseek_api_key: Option<String>,
+    #[serde(default)]
+    pub anthropic_api_key: Option<String>,
+    /// Whether requests made with this key go through the retrieval stage.
+    #[serde(default = "default_retrieval")]
+    pub retrieval: bool,
+    /// Whether requests made with this key may be answered from the
+    /// response cache.
+    #[serde(default = "default_cache")]
+    pub cache: bool,
+    /// Organization the key is bound to, see [`crate::tenants`].
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Projects the key may act for; empty means any.
+    #[serde(default)]
+    pub projects: Vec<String>,
+    /// Scheduling tier of the key's requests, see [`crate::concurrency`].
+    #[serde(default)]
+    pub priority: Priority,
+    /// Whether the key may issue and revoke keys of its tenant.
+    #[serde(default)]
+    pub manage_keys: bool,
+    /// Id of a key issued through `POST /v1/keys`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Name of the key that issued this one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issued_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// Scheduling tier of a key's requests when the server is saturated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Requests a user is waiting for; served first.
+    #[default]
+    Interactive,
+    /// Background jobs; only served when no interactive request is queued.
+    Batch,
+}
+
+fn default_retrieval() -> bool {
+    true
+}
+
+fn default_cache() -> bool {
+    true
+}
+
+/// Lookup table of all configured and issued virtual keys.
+#[derive(Default)]
+pub struct KeyStore {
+    keys: RwLock<HashMap<String, Arc<VirtualKey>>>,
+    /// Where issued keys are kept.
+    backend: Option<Arc<dyn KeyBackend>>,
+    /// Held while a key is issued, so that concurrent issues cannot both
+    /// pass a tenant's `max_keys`.
+    issuing: tokio::sync::Mutex<()>,
+}
+
+impl KeyStore {
+    /// Builds the key store from the `[[keys]]` entries in the config.
+    ///
+    /// Encrypted upstream keys are decrypted with the master key from
+    /// `DEEPCLAUDE_MASTER_KEY`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if two keys share a name, if an encrypted upstream
+    /// key is present but no master key is configured, or if decryption
+    /// fails.
+    pub fn load(keys: &[VirtualKey]) -> anyhow::Result<Self> {
+        let master = MasterKey::from_env(crypto::MASTER_KEY_ENV);
+        let mut decrypted = HashMap::new();
+
+        for key in keys {
+            // 用量和死信按密钥名称归属，名称必须唯一
+            if decrypted.values().any(|other: &Arc<VirtualKey>| other.name == key.name) {
+                anyhow::bail!("密钥名称'{}'重复，每个密钥的名称必须唯一", key.name);
+            }
+            let mut key = key.clone();
+            for upstream in [&mut key.deepseek_api_key, &mut key.anthropic_api_key].into_iter().flatten() {
+                if crypto::is_encrypted(upstream) {
+                    let master = master.as_ref().ok_or_else(|| {
+                        anyhow::anyhow!("密钥'{}'包含加密的上游密钥，但未设置{}", key.name, crypto::MASTER_KEY_ENV)
+                    })?;
+                    *upstream = master
+                        .decrypt(upstream)
+                        .map_err(|e| anyhow::anyhow!("无法解密密钥'{}'的上游密钥: {}", key.name, e))?;
+                } else {
+                    tracing::warn!("密钥'{}'的上游密钥以明文存储，建议使用`deepclaude keys encrypt`加密", key.name);
+                }
+            }
+            decrypted.insert(key.key.clone(), Arc::new(key));
+        }
+
+        Ok(Self {
+            keys: RwLock::new(decrypted),
+            backend: None,
+            issuing: Default::default(),
+        })
+    }
+
+    /// Keeps keys issued from now on in `backend`.
+    pub fn with_backend(mut self, backend: Arc<dyn KeyBackend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Finds the virtual key matching the request's bearer token, if any.
+    pub fn lookup(&self, headers: &axum::http::HeaderMap) -> Option<Arc<VirtualKey>> {
+        let token = headers
+            .get("Authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))?;
+        self.keys.read().unwrap().get(token).cloned()
+    }
+
+    /// Finds a virtual key by its configured name.
+    pub fn find_by_name(&self, name: &str) -> Option<Arc<VirtualKey>> {
+        self.keys.read().unwrap().values().find(|key| key.name == name).cloned()
+    }
+
+    /// The keys bound to a tenant, configured and issued, by name.
+    pub fn of_tenant(&self, tenant: &str) -> Vec<Arc<VirtualKey>> {
+        let mut keys: Vec<_> = self
+            .keys
+            .read()
+            .unwrap()
+            .values()
+            .filter(|key| key.tenant.as_deref() == Some(tenant))
+            .cloned()
+            .collect();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        keys
+    }
+
+    /// The keys issued for a tenant, oldest first.
+    pub fn issued(&self, tenant: &str) -> Vec<Arc<VirtualKey>> {
+        let mut keys: Vec<_> = self
+            .keys
+            .read()
+            .unwrap()
+            .values()
+            .filter(|key| key.id.is_some() && key.tenant.as_deref() == Some(tenant))
+            .cloned()
+            .collect();
+        keys.sort_by_key(|key| key.created_at);
+        keys
+    }
+
+    /// Stores and activates an issued key, if its name is not taken by
+    /// another key and its tenant holds fewer than `max_keys` issued keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Conflict` if a configured or issued key already has
+    /// its name, `ApiError::Forbidden` if the tenant's quota is used up, or
+    /// `ApiError::Internal` if the key cannot be stored.
+    async fn issue(&self, key: VirtualKey, max_keys: usize) -> Result<()> {
+        // 计数和写入在同一把锁内完成，避免并发签发超出配额
+        let _issuing = self.issuing.lock().await;
+        // 用量和死信按密钥名称归属，重名会让两个密钥互相看到对方的数据
+        if self.find_by_name(&key.name).is_some() {
+            return Err(ApiError::Conflict {
+                message: format!("A key named '{}' already exists", key.name),
+            });
+        }
+        let tenant = key.tenant.as_deref().unwrap_or_default();
+        if self.issued(tenant).len() >= max_keys {
+            return Err(ApiError::Forbidden {
+                message: format!("Organization '{}' may hold at most {} issued keys", tenant, max_keys),
+            });
+        }
+        if let Some(backend) = &self.backend {
+            backend.insert(&key).await.map_err(|e| ApiError::Internal {
+                message: format!("无法保存密钥: {}", e),
+            })?;
+        }
+        self.keys.write().unwrap().insert(key.key.clone(), Arc::new(key));
+        Ok(())
+    }
+
+    /// Deactivates and removes an issued key.
+    async fn revoke(&self, id: &str) -> anyhow::Result<()> {
+        if let Some(backend) = &self.backend {
+            backend.remove(id).await?;
+        }
+        self.keys.write().unwrap().retain(|_, key| key.id.as_deref() != Some(id));
+        Ok(())
+    }
+}
+
+impl VirtualKey {
+    /// Checks the request against this key's restrictions.
+    ///
+    /// Stage `max_tokens` values that the request leaves unset are capped at
+    /// the key's ceiling so the upstream defaults cannot exceed it.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The chat request, after presets have been applied
+    /// * `mode` - The effective pipeline mode for the request
+    /// * `models` - The effective models used by the request
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Forbidden` if the request uses a model or mode the
+    /// key is not allowed to use, or asks for more tokens than its ceiling.
+    pub fn authorize(&self, request: &mut ApiRequest, mode: &str, models: &[String]) -> Result<()> {
+        if !self.allowed_modes.is_empty() && !self.allowed_modes.iter().any(|m| m == mode) {
+            return Err(ApiError::Forbidden {
+                message: format!("Key '{}' is not allowed to use mode '{}'", self.name, mode),
+            });
+        }
+
+        if !self.allowed_models.is_empty() {
+            if let Some(model) = models.iter().find(|model| !self.allows_model(model)) {
+                return Err(ApiError::Forbidden {
+                    message: format!("Key '{}' is not allowed to use model '{}'", self.name, model),
+                });
+            }
+        }
+
+        if let Some(ceiling) = self.max_tokens {
+            for config in [&mut request.deepseek_config, &mut request.anthropic_config] {
+                if !config.body.is_object() {
+                    config.body = serde_json::json!({});
+                }
+                let body = config.body.as_object_mut().expect("body was just set to an object");
+                match body.get("max_tokens").and_then(|v| v.as_u64()) {
+                    Some(requested) if requested > ceiling as u64 => {
+                        return Err(ApiError::Forbidden {
+                            message: format!(
+                                "Key '{}' is limited to max_tokens={}, requested {}",
+                                self.name, ceiling, requested
+                            ),
+                        });
+                    }
+                    Some(_) => {}
+                    None => {
+                        body.insert("max_tokens".to_string(), serde_json::json!(ceiling));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks a raw passthrough call, see [`crate::proxy`]: a restricted
+    /// mode list must include `passthrough`, and the call's model must be
+    /// allowed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Forbidden` if the key may not make the call.
+    pub fn authorize_passthrough(&self, model: Option<&str>) -> Result<()> {
+        if !self.allowed_modes.is_empty() && !self.allowed_modes.iter().any(|m| m == "passthrough") {
+            return Err(ApiError::Forbidden {
+                message: format!("Key '{}' is not allowed to use passthrough routes", self.name),
+            });
+        }
+        if !self.allowed_models.is_empty() && !model.is_some_and(|model| self.allows_model(model)) {
+            return Err(ApiError::Forbidden {
+                message: format!("Key '{}' is not allowed to use model '{}'", self.name, model.unwrap_or_default()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Matches a model against the allowlist; a trailing `*` matches any suffix.
+    fn allows_model(&self, model: &str) -> bool {
+        self.allowed_models.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => model.starts_with(prefix),
+            None => model == pattern,
+        })
+    }
+}
+
+/// A key to issue through `POST /v1/keys`; omitted restrictions are the
+/// issuer's.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct IssueKeyRequest {
+    pub name: String,
+    pub allowed_models: Option<Vec<String>>,
+    pub allowed_modes: Option<Vec<String>>,
+    pub max_tokens: Option<u32>,
+    pub projects: Option<Vec<String>>,
+}
+
+/// The caller's key if it may manage keys, with its tenant.
+///
+/// # Errors
+///
+/// Returns `ApiError::Unauthorized` without a virtual key, or
+/// `ApiError::Forbidden` if the key may not manage keys or has no tenant.
+fn key_manager(state: &AppState, headers: &HeaderMap) -> Result<(Arc<VirtualKey>, String)> {
+    let key = state.key_store.lookup(headers).ok_or_else(|| ApiError::Unauthorized {
+        message: "Managing keys requires a virtual key".to_string(),
+    })?;
+    let tenant = match &key.tenant {
+        Some(tenant) if key.manage_keys => tenant.clone(),
+        _ => {
+            return Err(ApiError::Forbidden {
+                message: format!("Key '{}' may not manage keys", key.name),
+            })
+        }
+    };
+    Ok((key, tenant))
+}
+
+/// A key as listed to its tenant, without the secret.
+pub(crate) fn listed(key: &VirtualKey) -> serde_json::Value {
+    json!({
+        "id": key.id,
+        "name": key.name,
+        "key_prefix": key.key.chars().take(8).collect::<String>(),
+        "allowed_models": key.allowed_models,
+        "allowed_modes": key.allowed_modes,
+        "max_tokens": key.max_tokens,
+        "projects": key.projects,
+        "issued_by": key.issued_by,
+        "created_at": key.created_at,
+    })
+}
+
+/// Takes a requested restriction list if it lies within the issuer's; an
+/// empty list means no restriction.
+fn narrowed(requested: Option<Vec<String>>, issuer: &[String], what: &str) -> Result<Vec<String>> {
+    let Some(requested) = requested else {
+        return Ok(issuer.to_vec());
+    };
+    if !issuer.is_empty() {
+        if requested.is_empty() {
+            return Err(ApiError::Forbidden {
+                message: format!("Issued keys must be limited to the issuer's {}", what),
+            });
+        }
+        if let Some(extra) = requested.iter().find(|item| !issuer.contains(item)) {
+            return Err(ApiError::Forbidden {
+                message: format!("The issuer may not grant {} '{}'", what, extra),
+            });
+        }
+    }
+    Ok(requested)
+}
+
+/// Issues a key for the caller's tenant.
+///
+/// The secret is only returned by this call.
+///
+/// # Errors
+///
+/// Returns `ApiError::Forbidden` if the caller may not manage keys, asks for
+/// more than its own key allows, or the tenant's `max_keys` are in use,
+/// `ApiError::Conflict` if the name is taken by another key, and
+/// `ApiError::BadRequest` without a name.
+#[utoipa::path(
+    post,
+    path = "/v1/keys",
+    tag = "keys",
+    request_body = IssueKeyRequest,
+    responses(
+        (status = 200, description = "The issued key, with its secret", body = Object),
+        (status = 403, description = "Not allowed, or the tenant's quota is used up", body = ErrorResponse),
+        (status = 409, description = "A key with this name already exists", body = ErrorResponse),
+    )
+)]
+pub async fn issue_key(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<IssueKeyRequest>,
+) -> Result<Json<serde_json::Value>> {
+    let (issuer, tenant) = key_manager(&state, &headers)?;
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::BadRequest {
+            message: "A key needs a name".to_string(),
+        });
+    }
+    let max_keys = state.config.tenants.get(&tenant).map_or(0, |config| config.max_keys);
+    let max_tokens = match (request.max_tokens, issuer.max_tokens) {
+        (Some(requested), Some(ceiling)) if requested > ceiling => {
+            return Err(ApiError::Forbidden {
+                message: format!("The issuer is limited to max_tokens={}", ceiling),
+            });
+        }
+        (requested, ceiling) => requested.or(ceiling),
+    };
+
+    let id = format!("key_{}", uuid::Uuid::new_v4().simple());
+    let key = VirtualKey {
+        key: format!("dc-{}", uuid::Uuid::new_v4().simple()),
+        name: name.to_string(),
+        allowed_models: narrowed(request.allowed_models, &issuer.allowed_models, "model")?,
+        allowed_modes: narrowed(request.allowed_modes, &issuer.allowed_modes, "mode")?,
+        max_tokens,
+        deepseek_api_key: None,
+        anthropic_api_key: None,
+        retrieval: issuer.retrieval,
+        cache: issuer.cache,
+        tenant: Some(tenant),
+        projects: narrowed(request.projects, &issuer.projects, "project")?,
+        priority: issuer.priority,
+        manage_keys: false,
+        id: Some(id),
+        issued_by: Some(issuer.name.clone()),
+        created_at: Some(Utc::now()),
+    };
+    state.key_store.issue(key.clone(), max_keys).await?;
+    tracing::info!("密钥{}为组织{:?}签发了密钥{}", issuer.name, key.tenant, key.name);
+    state.audit.record(&issuer.name, "keys.issue", json!({ "id": key.id, "name": key.name, "tenant": key.tenant }));
+
+    let mut issued = listed(&key);
+    issued["key"] = json!(key.key);
+    Ok(Json(issued))
+}
+
+/// Lists the keys issued for the caller's tenant, without their secrets.
+///
+/// # Errors
+///
+/// Returns `ApiError::Forbidden` if the caller may not manage keys.
+#[utoipa::path(
+    get,
+    path = "/v1/keys",
+    tag = "keys",
+    responses((status = 200, description = "The tenant's issued keys", body = Object))
+)]
+pub async fn list_keys(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Json<serde_json::Value>> {
+    let (_, tenant) = key_manager(&state, &headers)?;
+    let max_keys = state.config.tenants.get(&tenant).map_or(0, |config| config.max_keys);
+    let keys: Vec<_> = state.key_store.issued(&tenant).iter().map(|key| listed(key)).collect();
+    Ok(Json(json!({
+        "object": "list",
+        "data": keys,
+        "max_keys": max_keys,
+    })))
+}
+
+/// Revokes a key issued for the caller's tenant.
+///
+/// # Errors
+///
+/// Returns `ApiError::Forbidden` if the caller may not manage keys, and
+/// `ApiError::NotFound` if the tenant has no issued key with that id.
+#[utoipa::path(
+    delete,
+    path = "/v1/keys/{id}",
+    tag = "keys",
+    params(("id" = String, Path, description = "Id of the issued key")),
+    responses(
+        (status = 200, description = "The key was revoked", body = Object),
+        (status = 404, description = "No such issued key", body = ErrorResponse),
+    )
+)]
+pub async fn revoke_key(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>> {
+    let (manager, tenant) = key_manager(&state, &headers)?;
+    if !state.key_store.issued(&tenant).iter().any(|key| key.id.as_deref() == Some(id.as_str())) {
+        return Err(ApiError::NotFound {
+            message: format!("Key '{}' not found", id),
+        });
+    }
+    state.key_store.revoke(&id).await.map_err(|e| ApiError::Internal {
+        message: format!("无法删除密钥: {}", e),
+    })?;
+    tracing::info!("密钥{}撤销了组织{}的密钥{}", manager.name, tenant, id);
+    state.audit.record(&manager.name, "keys.revoke", json!({ "id": id, "tenant": tenant }));
+    Ok(Json(json!({ "id": id, "deleted": true })))
+}
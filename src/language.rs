@@ -0,0 +1,131 @@
+//! Output language enforcement.
+//!
+//! A target language from the request's `target_language` or
+//! `[language] target` (Chinese unless configured otherwise) adds an
+//! instruction to Claude's system prompt, and in full mode to DeepSeek's,
+//! whose answer is shown as the reasoning. With `[language] verify`, for
+//! languages with a known script the final answer is also checked by
+//! counting letters per script, outside code fences, and Claude is asked
+//! once to answer again if the answer is in another language. Languages
+//! written in Latin script can only be told apart from non-Latin ones.
+
+use crate::config::LanguageConfig;
+
+/// Answers with fewer letters than this are not checked.
+const MIN_LETTERS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Script {
+    Latin,
+    Han,
+    Kana,
+    Hangul,
+    Cyrillic,
+    Arabic,
+}
+
+/// Known languages: codes and names, display name, script and the share of
+/// letters that must be in that script.
+const LANGUAGES: &[(&[&str], &str, Script, f32)] = &[
+    (&["zh", "chinese", "中文"], "Chinese", Script::Han, 0.15),
+    (&["ja", "japanese", "日本語"], "Japanese", Script::Kana, 0.05),
+    (&["ko", "korean", "한국어"], "Korean", Script::Hangul, 0.15),
+    (&["ru", "russian"], "Russian", Script::Cyrillic, 0.5),
+    (&["uk", "ukrainian"], "Ukrainian", Script::Cyrillic, 0.5),
+    (&["ar", "arabic"], "Arabic", Script::Arabic, 0.5),
+    (&["en", "english"], "English", Script::Latin, 0.7),
+    (&["fr", "french"], "French", Script::Latin, 0.7),
+    (&["de", "german"], "German", Script::Latin, 0.7),
+    (&["es", "spanish"], "Spanish", Script::Latin, 0.7),
+];
+
+fn script(c: char) -> Option<Script> {
+    match c {
+        'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => Some(Script::Latin),
+        '\u{3040}'..='\u{30FF}' => Some(Script::Kana),
+        '\u{4E00}'..='\u{9FFF}' | '\u{3400}'..='\u{4DBF}' => Some(Script::Han),
+        '\u{AC00}'..='\u{D7AF}' | '\u{1100}'..='\u{11FF}' => Some(Script::Hangul),
+        '\u{0400}'..='\u{04FF}' => Some(Script::Cyrillic),
+        '\u{0600}'..='\u{06FF}' => Some(Script::Arabic),
+        _ => None,
+    }
+}
+
+/// The language answers should be written in.
+#[derive(Debug, Clone)]
+pub struct TargetLanguage {
+    name: String,
+    check: Option<(Script, f32)>,
+}
+
+impl TargetLanguage {
+    /// Picks the request's language, falling back to `[language] target`.
+    /// An empty value turns enforcement off.
+    pub fn resolve(requested: Option<&str>, config: &LanguageConfig) -> Option<Self> {
+        let value = requested.unwrap_or(&config.target).trim();
+        if value.is_empty() {
+            return None;
+        }
+        // zh-CN、en_US等地区变体按主语言处理
+        let key = value.to_lowercase();
+        let primary = key.split(['-', '_']).next().unwrap_or_default();
+        let known = LANGUAGES.iter().find(|(names, ..)| names.contains(&key.as_str()) || names.contains(&primary));
+        Some(match known {
+            Some((_, name, script, share)) => Self {
+                name: name.to_string(),
+                check: config.verify.then_some((*script, *share)),
+            },
+            None => Self {
+                name: value.to_string(),
+                check: None,
+            },
+        })
+    }
+
+    /// Display name of the language.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns true if answers can be checked for the language.
+    pub fn verifiable(&self) -> bool {
+        self.check.is_some()
+    }
+
+    /// System prompt instruction for the language.
+    pub fn instruction(&self) -> String {
+        format!("Always reply to the user in {}.", self.name)
+    }
+
+    /// Returns false if the answer is clearly not in the language.
+    pub fn matches(&self, text: &str) -> bool {
+        let Some((target, share)) = self.check else {
+            return true;
+        };
+
+        let mut in_fence = false;
+        let (mut total, mut matching) = (0usize, 0usize);
+        for line in text.lines() {
+            if line.trim_start().starts_with("```") {
+                in_fence = !in_fence;
+                continue;
+            }
+            if in_fence {
+                continue;
+            }
+            for script in line.chars().filter_map(script) {
+                total += 1;
+                if script == target {
+                    matching += 1;
+                }
+            }
+        }
+        // 日文混用汉字，只要求假名达到较低比例，中文则几乎没有假名
+        total < MIN_LETTERS || matching as f32 / total as f32 >= share
+    }
+
+    /// Follow-up message asking Claude to answer in the language.
+    pub fn feedback(&self) -> String {
+        format!("Your answer is not in {0}. Reply again with your complete answer written in {0}.", self.name)
+    }
+}
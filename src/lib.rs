@@ -0,0 +1,173 @@
+//! DeepClaude - A high-performance LLM inference API and Chat UI that integrates DeepSeek R1's CoT reasoning traces with Anthropic Claude models.
+//!
+//! The server binary is a thin wrapper around this library, which holds
+//! the request handling and the stateful subsystems. [`router`] builds the
+//! HTTP application over an [`AppState`](handlers::AppState), which lets
+//! the integration tests under `tests/` drive the real routes against
+//! mocked upstreams.
+
+pub mod abuse;
+pub mod admin;
+pub mod affinity;
+pub mod audit;
+pub mod bench;
+pub mod cache;
+pub mod capabilities;
+pub mod cli;
+pub mod clients;
+pub mod coalesce;
+pub mod complete;
+pub mod config;
+pub mod concurrency;
+pub mod consensus;
+pub mod crypto;
+pub mod deadletter;
+pub mod diagnostics;
+pub mod echo;
+pub mod editblocks;
+pub mod envelope;
+pub mod error;
+pub mod estimate;
+pub mod files;
+pub mod flags;
+pub mod gemini;
+pub mod grpc;
+pub mod handlers;
+pub mod hooks;
+pub mod keys;
+pub mod language;
+pub mod logs;
+pub mod mcp;
+pub mod models;
+pub mod openapi;
+pub mod pacing;
+pub mod paths;
+pub mod payloads;
+pub mod pools;
+pub mod postprocess;
+pub mod proxy;
+pub mod rag;
+pub mod ratelimit;
+pub mod reasoning;
+pub mod refine;
+pub mod repl;
+pub mod reports;
+pub mod reqlog;
+pub mod retention;
+pub mod rules;
+pub mod schedule;
+pub mod secrets;
+pub mod sessions;
+pub mod shadow;
+pub mod shedding;
+pub mod signing;
+pub mod softlimit;
+pub mod spend;
+pub mod status;
+pub mod storage;
+pub mod streams;
+pub mod systemd;
+pub mod template;
+pub mod tenants;
+pub mod traces;
+pub mod usage;
+pub mod utils;
+
+use axum::{
+    extract::DefaultBodyLimit,
+    http::HeaderValue,
+    middleware,
+    routing::{any, delete, get, post, put},
+    Router,
+};
+use handlers::AppState;
+use std::sync::Arc;
+use tower_http::{
+    cors::{Any, CorsLayer},
+    trace::TraceLayer,
+};
+
+/// Header identifying a request in logs and captured payloads.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The `X-Request-Id` of a request.
+pub fn request_id(headers: &axum::http::HeaderMap) -> Option<&str> {
+    headers.get(REQUEST_ID_HEADER).and_then(|value| value.to_str().ok())
+}
+
+/// Gives every request an `X-Request-Id`, the client's or a generated one,
+/// and returns it on the response.
+async fn assign_request_id(mut request: axum::extract::Request, next: middleware::Next) -> axum::response::Response {
+    let request_id = match request.headers().get(REQUEST_ID_HEADER) {
+        Some(value) => value.clone(),
+        None => {
+            let generated = HeaderValue::from_str(&uuid::Uuid::new_v4().to_string()).expect("UUIDs are valid header values");
+            request.headers_mut().insert(REQUEST_ID_HEADER, generated.clone());
+            generated
+        }
+    };
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    response
+}
+
+/// The span of an HTTP request, carrying its `X-Request-Id` so its log
+/// entries can be told apart.
+fn request_span(request: &axum::extract::Request) -> tracing::Span {
+    let request_id = request_id(request.headers()).unwrap_or_default();
+    tracing::info_span!("request", method = %request.method(), uri = %request.uri(), request_id = %request_id)
+}
+
+/// Builds the application's routes over its state.
+pub fn router(state: Arc<AppState>) -> Router {
+    // Set up CORS
+    let cors = CorsLayer::new()
+        .allow_methods(Any)
+        .allow_headers(Any)
+        .allow_origin(Any);
+
+    Router::new()
+        .route("/v1/chat/completions", post(handlers::handle_chat))
+        .route("/v1/chat/completions/{id}/cancel", post(streams::cancel_stream))
+        .route("/v1/models", get(handlers::list_models))
+        .route("/v1beta/models/{model_method}", post(gemini::generate_content))
+        .route("/v1/capabilities", get(capabilities::capabilities))
+        .route("/metrics", get(handlers::metrics))
+        .route("/status", get(status::status))
+        .route(
+            "/v1/files",
+            post(files::upload_file).layer(DefaultBodyLimit::max(state.files.max_upload_bytes())),
+        )
+        .route("/v1/files/{id}", get(files::get_file).delete(files::delete_file))
+        .route("/v1/keys", get(keys::list_keys).post(keys::issue_key))
+        .route("/v1/keys/{id}", delete(keys::revoke_key))
+        .route("/v1/traces/{request_id}", get(traces::get_trace))
+        .route("/v1/estimate", post(estimate::estimate))
+        .route("/v1/usage", get(usage::usage_report))
+        .route("/v1/usage/export", get(usage::export_usage))
+        .route("/v1/env/update", post(handlers::update_env_variables))
+        .route("/v1/env/variables", get(handlers::get_env_variables))
+        .route("/admin/providers/reload", post(admin::reload_providers))
+        .route("/admin/deadletter", get(admin::list_dead_letters))
+        .route("/admin/deadletter/{id}/replay", post(admin::replay_dead_letter))
+        .route("/admin/streams", get(admin::list_streams))
+        .route("/admin/flags", get(admin::get_flags).put(admin::put_flags))
+        .route("/admin/bans", get(admin::list_bans))
+        .route("/admin/logs/stream", get(logs::stream_logs))
+        .route("/admin/requests/{id}/payloads", get(payloads::get_payloads))
+        .route("/admin/bans/{ip}", put(admin::put_ban).delete(admin::delete_ban))
+        .route("/admin/purge", post(retention::purge))
+        .route("/admin/tenants/{id}/export", get(tenants::export_tenant))
+        .route("/deepseek/v1/{*path}", any(proxy::deepseek))
+        .route("/anthropic/v1/{*path}", any(proxy::anthropic))
+        .route("/debug/echo-completions", post(echo::echo_completions))
+        .route("/debug/complete", post(complete::complete))
+        .route("/debug/tasks", get(diagnostics::list_tasks))
+        .merge(openapi::routes())
+        .layer(middleware::from_fn_with_state(state.clone(), signing::verify_admin))
+        .layer(middleware::from_fn_with_state(state.clone(), abuse::guard))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(middleware::from_fn(assign_request_id))
+        .layer(cors)
+        .with_state(state)
+}
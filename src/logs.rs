@@ -0,0 +1,259 @@
+//! Live log tail for operators.
+//!
+//! - `GET /admin/logs/stream` - follow the log over server-sent events
+//!
+//! Every log event the server writes is also kept in memory, the last
+//! `BACKLOG` of them, and handed to the open tails. Each SSE event carries
+//! one entry as JSON: time, level, target, message, the other fields of the
+//! event, and the request it belongs to. `?level=warn` keeps that level and
+//! the more severe ones; `?request_id=` keeps the entries of one request,
+//! matching either its `X-Request-Id` (generated when the client sends
+//! none) or the id of its stream as listed at `GET /admin/streams`. A tail
+//! starts with the last `?tail=` matching entries (100 by default) and then
+//! follows new ones. A tail that falls behind skips entries and gets a
+//! `lagged` event saying how many. Needs an admin token with the viewer
+//! role.
+
+use crate::{
+    admin,
+    config::AdminRole,
+    error::{ApiError, Result},
+    handlers::AppState,
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::Infallible,
+    fmt::Write,
+    sync::{Arc, LazyLock, Mutex},
+};
+use tokio::sync::broadcast;
+use tracing::{
+    field::{Field, Visit},
+    span, Event as TracingEvent, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use utoipa::IntoParams;
+
+/// How many entries are kept for new tails.
+const BACKLOG: usize = 1000;
+
+/// How many entries a slow tail may fall behind before it skips some.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Span fields naming the request an entry belongs to.
+const REQUEST_FIELDS: &[&str] = &["request_id", "stream_id"];
+
+/// One log event.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    pub at: DateTime<Utc>,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    /// The event's other fields and those of the spans it happened in.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_id: Option<String>,
+}
+
+struct LogHub {
+    backlog: Mutex<VecDeque<LogEntry>>,
+    sender: broadcast::Sender<LogEntry>,
+}
+
+static HUB: LazyLock<LogHub> = LazyLock::new(|| LogHub {
+    backlog: Mutex::new(VecDeque::with_capacity(BACKLOG)),
+    sender: broadcast::channel(CHANNEL_CAPACITY).0,
+});
+
+/// Collects the fields of an event or span as text.
+#[derive(Default)]
+struct Fields(BTreeMap<String, String>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let mut text = String::new();
+        let _ = write!(text, "{:?}", value);
+        self.0.insert(field.name().to_string(), text);
+    }
+}
+
+/// The tracing layer feeding the log tail.
+pub struct LogLayer;
+
+/// The layer to add to the subscriber so the log can be tailed.
+pub fn layer() -> LogLayer {
+    LogLayer
+}
+
+impl<S> Layer<S> for LogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<Fields>() {
+            values.record(fields);
+        }
+    }
+
+    fn on_event(&self, event: &TracingEvent<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        // 外层span的字段先写入，内层和事件自身的同名字段覆盖它们
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<Fields>() {
+                    fields.0.extend(span_fields.0.iter().map(|(name, value)| (name.clone(), value.clone())));
+                }
+            }
+        }
+        event.record(&mut fields);
+
+        let mut fields = fields.0;
+        let metadata = event.metadata();
+        let entry = LogEntry {
+            at: Utc::now(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: fields.remove("message").unwrap_or_default(),
+            request_id: fields.remove(REQUEST_FIELDS[0]),
+            stream_id: fields.remove(REQUEST_FIELDS[1]),
+            fields,
+        };
+        let mut backlog = HUB.backlog.lock().unwrap();
+        if backlog.len() == BACKLOG {
+            backlog.pop_front();
+        }
+        backlog.push_back(entry.clone());
+        // 在锁内发送，见stream_logs；没有人在跟踪日志时发送失败，忽略即可
+        let _ = HUB.sender.send(entry);
+    }
+}
+
+/// Query parameters of `GET /admin/logs/stream`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct LogFilter {
+    /// Least severe level to include: trace, debug, info, warn or error.
+    pub level: Option<String>,
+    /// Request id or stream id whose entries to include.
+    pub request_id: Option<String>,
+    /// How many past entries to send first.
+    pub tail: usize,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            level: None,
+            request_id: None,
+            tail: 100,
+        }
+    }
+}
+
+/// A parsed `LogFilter`.
+struct Matcher {
+    level: Option<Level>,
+    request_id: Option<String>,
+}
+
+impl Matcher {
+    fn matches(&self, entry: &LogEntry) -> bool {
+        // Level的顺序中越详细越大，error最小
+        let level_ok = self.level.is_none_or(|least| entry.level.parse::<Level>().is_ok_and(|level| level <= least));
+        let request_ok = self.request_id.as_ref().is_none_or(|id| {
+            entry.request_id.as_ref() == Some(id) || entry.stream_id.as_ref() == Some(id)
+        });
+        level_ok && request_ok
+    }
+}
+
+fn event(entry: &LogEntry) -> Event {
+    Event::default().data(serde_json::to_string(entry).unwrap_or_default())
+}
+
+/// Follows the log over server-sent events.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` for an unknown level.
+#[utoipa::path(
+    get,
+    path = "/admin/logs/stream",
+    tag = "admin",
+    params(LogFilter),
+    responses(
+        (status = 200, description = "One event per log entry", body = String, content_type = "text/event-stream"),
+        (status = 400, description = "Unknown level", body = crate::error::ErrorResponse),
+    )
+)]
+pub async fn stream_logs(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(filter): Query<LogFilter>,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>> {
+    admin::require_admin(&state, &headers, AdminRole::Viewer)?;
+    let level = match &filter.level {
+        Some(level) => Some(level.parse::<Level>().map_err(|_| ApiError::BadRequest {
+            message: format!("Unknown level '{}'; expected trace, debug, info, warn or error", level),
+        })?),
+        None => None,
+    };
+    let matcher = Matcher {
+        level,
+        request_id: filter.request_id,
+    };
+
+    // 持有积压队列的锁时订阅，写入方也在锁内发送，条目既不会丢失也不会重复
+    let (mut receiver, backlog) = {
+        let backlog = HUB.backlog.lock().unwrap();
+        let receiver = HUB.sender.subscribe();
+        let matching: Vec<_> = backlog.iter().filter(|entry| matcher.matches(entry)).cloned().collect();
+        (receiver, matching[matching.len().saturating_sub(filter.tail)..].to_vec())
+    };
+    let stream = async_stream::stream! {
+        for entry in &backlog {
+            yield Ok(event(entry));
+        }
+        loop {
+            match receiver.recv().await {
+                Ok(entry) if matcher.matches(&entry) => yield Ok(event(&entry)),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    yield Ok(Event::default().event("lagged").data(skipped.to_string()));
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    };
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
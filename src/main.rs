@@ -10,23 +10,14 @@
 //! The API requires authentication tokens for both services and
 //! supports custom configuration through a TOML config file.
 
-mod clients;
-mod config;
-mod error;
-mod handlers;
-mod models;
-mod utils;
-
-use crate::{config::Config, handlers::AppState};
-use axum::routing::{post, get, Router};
-use std::{net::SocketAddr, sync::Arc};
-use tower_http::{
-    cors::{Any, CorsLayer},
-    trace::TraceLayer,
-};
-use tracing_subscriber::fmt::time::FormatTime;
+
+use axum::Router;
+use deepclaude::{cli, clients, config::Config, crypto, handlers::AppState, paths, reports, retention, secrets, systemd, utils};
+use clap::Parser;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::sync::watch;
+use tracing_subscriber::{fmt::time::FormatTime, layer::SubscriberExt, util::SubscriberInitExt};
 use chrono::Utc;
-use dotenv::dotenv;
 
 /// Application entry point.
 ///
@@ -45,6 +36,9 @@ use dotenv::dotenv;
 /// - Server encounters a fatal error while running
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = cli::Cli::parse();
+    paths::init(cli.config_dir.clone());
+
     // 自定义时间格式化器，使用北京时间
     struct BeijingTime;
 
@@ -67,37 +61,75 @@ async fn main() -> anyhow::Result<()> {
     let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| "deepclaude=debug,tower_http=debug".into());
 
-    tracing_subscriber::fmt()
-        .with_env_filter(env_filter)
-        .event_format(format)
+    // 日志同时交给/admin/logs/stream
+    tracing_subscriber::registry()
+        .with(env_filter)
+        .with(tracing_subscriber::fmt::layer().event_format(format))
+        .with(deepclaude::logs::layer())
         .init();
 
+    // 运行子命令后直接退出
+    if let Some(command) = cli.command {
+        return cli::run(command).await;
+    }
+
     // Load configuration
-    let config = Config::load().unwrap_or_else(|_| {
+    let mut config = Config::load().unwrap_or_else(|_| {
         tracing::warn!("Failed to load config.toml, using default configuration");
         Config::default()
     });
+    config.resolve_paths();
+    if cli.diagnostics {
+        config.debug.tasks = true;
+        tracing::info!("已启用诊断模式，可通过/debug/tasks查看运行中的任务");
+    }
 
-    // Create application state
-    let state = Arc::new(AppState::new(config.clone()));
+    // 解析配置和.env中的外部密钥引用
+    let secret_refs: Vec<String> = [
+        config.auth.deepseek_api_key.clone(),
+        config.auth.anthropic_api_key.clone(),
+        utils::get_env_var("DEEPSEEK_API_KEY", ""),
+        utils::get_env_var("ANTHROPIC_API_KEY", ""),
+        std::env::var(crypto::MASTER_KEY_ENV).unwrap_or_default(),
+        config.admin.token.clone(),
+        config.rag.embedding.api_key.clone(),
+        utils::get_env_var("EMBEDDING_API_KEY", ""),
+        config.reports.webhook_url.clone(),
+        config.spend_alerts.webhook_url.clone(),
+        config.storage.url.clone(),
+        config.signing.webhook_secret.clone(),
+        config.signing.admin_secret.clone(),
+    ]
+    .into_iter()
+    .chain(config.admin.tokens.iter().map(|admin| admin.token.clone()))
+    .chain(
+        config
+            .providers
+            .values()
+            .flat_map(|profile| [profile.deepseek_api_key.clone(), profile.anthropic_api_key.clone()])
+            .flatten(),
+    )
+    .filter(|value| secrets::is_reference(value))
+    .collect();
+    if !secret_refs.is_empty() {
+        secrets::resolve_all(&secret_refs, &config.secrets).await?;
+        tracing::info!("已解析{}个外部密钥引用", secret_refs.len());
+        secrets::spawn_refresh(secret_refs, config.secrets.clone());
+    }
 
-    // Set up CORS
-    let cors = CorsLayer::new()
-        .allow_methods(Any)
-        .allow_headers(Any)
-        .allow_origin(Any);
+    // 使用OpenRouter时在后台获取其模型目录和价格
+    clients::openrouter::spawn_refresh(&config);
+
+    // Create application state
+    let state = Arc::new(AppState::new(config.clone()).await?);
+    reports::spawn(state.clone())?;
+    retention::spawn(state.clone())?;
 
     // Build router
-    let app = Router::new()
-        .route("/v1/chat/completions", post(handlers::handle_chat))
-        .route("/v1/env/update", post(handlers::update_env_variables))
-        .route("/v1/env/variables", get(handlers::get_env_variables))
-        .layer(TraceLayer::new_for_http())
-        .layer(cors)
-        .with_state(state);
+    let app = deepclaude::router(state.clone());
 
     // 加载环境变量
-    dotenv().ok();
+    dotenv::from_path(paths::env_file()).ok();
     
     // 设置默认值
     if std::env::var("PORT").is_err() {
@@ -118,19 +150,157 @@ async fn main() -> anyhow::Result<()> {
         tracing::info!("已启用普通模式，仅DeepSeek的推理内容将传递给Claude");
     }
 
-    // Get host and port from config
-    let addr: SocketAddr = format!("{}:{}", config.server.host, port)
-        .parse()
-        .expect("Invalid host/port configuration");
-
-    tracing::info!("Starting server on {}", addr);
+    // 未配置监听地址时使用host和端口
+    let listen = if config.server.listen.is_empty() {
+        vec![format!("{}:{}", config.server.host, port)]
+    } else {
+        config.server.listen.clone()
+    };
+    let mut listeners = Vec::new();
+    for address in &listen {
+        listeners.push(Listen::parse(address)?.bind().await?);
+    }
+    let grpc = if config.grpc.listen.is_empty() {
+        None
+    } else {
+        let listener = tokio::net::TcpListener::bind(&config.grpc.listen).await?;
+        tracing::info!("Starting gRPC server on {}", config.grpc.listen);
+        Some(listener)
+    };
+    systemd::ready();
+    systemd::spawn_watchdog();
 
     // Start server
-    axum::serve(
-        tokio::net::TcpListener::bind(&addr).await?,
-        app.into_make_service(),
+    let grace = std::time::Duration::from_secs(config.streams.shutdown_grace_secs);
+    let (stop, stopped) = watch::channel(false);
+    let usage_state = state.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        // 停止接受新连接，已开始的流式响应在宽限期内继续完成
+        tracing::info!("正在关闭，等待{}个流式响应完成", state.streams.list().len());
+        systemd::stopping();
+        let _ = stop.send(true);
+        state.streams.drain(grace).await;
+    });
+    let grpc_state = usage_state.clone();
+    let mut grpc_stopped = stopped.clone();
+    let grpc = async move {
+        let Some(listener) = grpc else { return Ok(()) };
+        let shutdown = async move {
+            let _ = grpc_stopped.wait_for(|stopped| *stopped).await;
+        };
+        deepclaude::grpc::serve(listener, grpc_state, shutdown).await
+    };
+    futures::future::try_join(
+        futures::future::try_join_all(listeners.into_iter().map(|listener| serve(listener, app.clone(), stopped.clone()))),
+        grpc,
     )
     .await?;
+    // 退出前写完队列中的用量记录
+    usage_state.usage.flush().await;
+
+    Ok(())
+}
+
+/// An address the server listens on.
+enum Listen {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Listen {
+    /// Parses a `host:port` pair or a `unix:<path>` socket.
+    fn parse(address: &str) -> anyhow::Result<Self> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(Self::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            anyhow::bail!("当前平台不支持Unix套接字: {}", path);
+        }
+        address
+            .parse()
+            .map(Self::Tcp)
+            .map_err(|_| anyhow::anyhow!("无效的监听地址: {}", address))
+    }
+
+    /// Binds the address, before any listener starts serving.
+    async fn bind(self) -> anyhow::Result<Bound> {
+        match self {
+            Self::Tcp(addr) => {
+                let listener = tokio::net::TcpListener::bind(addr).await?;
+                tracing::info!("Starting server on {}", addr);
+                Ok(Bound::Tcp(listener))
+            }
+            #[cfg(unix)]
+            Self::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
 
+                // 删除上次运行遗留的套接字文件，其他类型的文件不动
+                let stale = std::fs::metadata(&path).is_ok_and(|meta| meta.file_type().is_socket());
+                if stale {
+                    std::fs::remove_file(&path)?;
+                }
+                let listener = tokio::net::UnixListener::bind(&path)
+                    .map_err(|e| anyhow::anyhow!("无法绑定Unix套接字{}: {}", path.display(), e))?;
+                tracing::info!("Starting server on unix:{}", path.display());
+                Ok(Bound::Unix(listener, path))
+            }
+        }
+    }
+}
+
+/// A bound listener.
+enum Bound {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+/// Serves the app on one listener until shutdown is signalled.
+async fn serve(listener: Bound, app: Router, mut stopped: watch::Receiver<bool>) -> anyhow::Result<()> {
+    let shutdown = async move {
+        let _ = stopped.wait_for(|stopped| *stopped).await;
+    };
+    match listener {
+        Bound::Tcp(listener) => {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown)
+                .await?;
+        }
+        #[cfg(unix)]
+        Bound::Unix(listener, path) => {
+            axum::serve(listener, app.into_make_service())
+                .with_graceful_shutdown(shutdown)
+                .await?;
+            let _ = std::fs::remove_file(&path);
+        }
+    }
     Ok(())
 }
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("无法监听SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -0,0 +1,442 @@
+//! MCP (Model Context Protocol) tool integration.
+//!
+//! Servers configured under `[mcp.servers]` are launched as child processes
+//! and spoken to with newline-delimited JSON-RPC over stdio. Their tools are
+//! offered to Claude as Anthropic tools named `<server>__<tool>`. When Claude
+//! answers with tool calls, the calls are executed here and their results
+//! are sent back as a new turn, until Claude gives a final answer. After
+//! `[mcp] max_rounds` rounds of tool calls Claude is asked to answer without
+//! calling more tools.
+//!
+//! Tools are only offered when the responder uses the Anthropic Messages
+//! API; requests to OpenAI-format endpoints or routed hosts run without them.
+//! With tools offered, a streaming request receives the final answer as a
+//! single chunk once all tool rounds are done.
+//!
+//! Servers are started on first use and restarted after they exit or stop
+//! answering.
+
+use crate::{
+    clients::anthropic::{AnthropicClient, AnthropicResponse, ContentDelta, MessageDelta, StreamEvent, Usage},
+    config::{McpConfig, McpServerConfig},
+    error::{ApiError, Result},
+    models::request::{ApiConfig, Message},
+};
+use futures::{Stream, StreamExt};
+use serde_json::{json, Value};
+use std::{collections::HashMap, io, pin::Pin, process::Stdio, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::Mutex,
+};
+
+const PROTOCOL_VERSION: &str = "2025-03-26";
+
+/// Separates the server name from the tool name in the names Claude sees.
+const NAME_SEPARATOR: &str = "__";
+
+/// Result of a JSON-RPC call: the result, or the error message.
+type RpcResult = std::result::Result<Value, String>;
+
+/// The configured MCP servers and their tools.
+pub struct Mcp {
+    max_rounds: u32,
+    servers: HashMap<String, McpServer>,
+    tools: Mutex<Option<Arc<Vec<Tool>>>>,
+}
+
+/// A tool offered by one of the servers.
+#[derive(Debug)]
+struct Tool {
+    server: String,
+    name: String,
+    /// Anthropic tool definition, with the qualified name.
+    definition: Value,
+}
+
+struct McpServer {
+    name: String,
+    config: McpServerConfig,
+    timeout: Duration,
+    connection: Mutex<Option<Connection>>,
+}
+
+struct Connection {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    next_id: u64,
+}
+
+impl Connection {
+    /// Launches a server and performs the initialization handshake.
+    async fn start(config: &McpServerConfig) -> io::Result<Self> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or_else(|| io::Error::other("无法获取MCP服务器的stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| io::Error::other("无法获取MCP服务器的stdout"))?;
+
+        let mut connection = Self {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+            next_id: 0,
+        };
+        let params = json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {},
+            "clientInfo": { "name": "deepclaude", "version": env!("CARGO_PKG_VERSION") },
+        });
+        connection.call("initialize", params).await?.map_err(io::Error::other)?;
+        connection
+            .send(&json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))
+            .await?;
+        Ok(connection)
+    }
+
+    async fn send(&mut self, message: &Value) -> io::Result<()> {
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+        self.stdin.write_all(line.as_bytes()).await?;
+        self.stdin.flush().await
+    }
+
+    /// Sends a request and waits for its response.
+    ///
+    /// Requests the server makes in the meantime are answered: `ping` with
+    /// an empty result, anything else as unsupported.
+    async fn call(&mut self, method: &str, params: Value) -> io::Result<RpcResult> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await?;
+
+        loop {
+            let line = self
+                .stdout
+                .next_line()
+                .await?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "MCP服务器已退出"))?;
+            // 跳过服务器输出的非JSON日志行
+            let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                continue;
+            };
+
+            if let Some(method) = message.get("method").and_then(|m| m.as_str()) {
+                if let Some(request_id) = message.get("id") {
+                    let reply = if method == "ping" {
+                        json!({ "jsonrpc": "2.0", "id": request_id, "result": {} })
+                    } else {
+                        json!({
+                            "jsonrpc": "2.0",
+                            "id": request_id,
+                            "error": { "code": -32601, "message": "Method not found" },
+                        })
+                    };
+                    self.send(&reply).await?;
+                }
+                continue;
+            }
+
+            if message["id"].as_u64() != Some(id) {
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                return Ok(Err(error["message"].as_str().unwrap_or("未知错误").to_string()));
+            }
+            return Ok(Ok(message["result"].clone()));
+        }
+    }
+}
+
+impl McpServer {
+    /// Sends a request, starting the server first if it is not running.
+    ///
+    /// A server that fails or times out is stopped and restarted on the
+    /// next request.
+    async fn request(&self, method: &str, params: Value) -> anyhow::Result<Value> {
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            let started = tokio::time::timeout(self.timeout, Connection::start(&self.config)).await;
+            *connection = Some(started.map_err(|_| anyhow::anyhow!("启动超时"))??);
+            tracing::info!("MCP服务器{}已启动", self.name);
+        }
+
+        let active = connection.as_mut().expect("连接已建立");
+        match tokio::time::timeout(self.timeout, active.call(method, params)).await {
+            Ok(Ok(result)) => result.map_err(|message| anyhow::anyhow!(message)),
+            Ok(Err(e)) => {
+                *connection = None;
+                Err(e.into())
+            }
+            Err(_) => {
+                *connection = None;
+                anyhow::bail!("{}请求超时", method)
+            }
+        }
+    }
+
+    /// Lists every tool the server offers, following pagination.
+    async fn list_tools(&self) -> anyhow::Result<Vec<Tool>> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let result = self.request("tools/list", params).await?;
+            for tool in result["tools"].as_array().into_iter().flatten() {
+                let Some(name) = tool["name"].as_str() else {
+                    continue;
+                };
+                let schema = match &tool["inputSchema"] {
+                    Value::Null => json!({ "type": "object" }),
+                    schema => schema.clone(),
+                };
+                tools.push(Tool {
+                    server: self.name.clone(),
+                    name: name.to_string(),
+                    definition: json!({
+                        "name": format!("{}{}{}", self.name, NAME_SEPARATOR, name),
+                        "description": tool["description"].as_str().unwrap_or_default(),
+                        "input_schema": schema,
+                    }),
+                });
+            }
+            cursor = result["nextCursor"].as_str().map(String::from);
+            if cursor.is_none() {
+                return Ok(tools);
+            }
+        }
+    }
+}
+
+impl Mcp {
+    /// Prepares the servers of the `[mcp]` settings without starting them.
+    pub fn new(config: &McpConfig) -> Self {
+        let servers = config
+            .servers
+            .iter()
+            .map(|(name, server)| {
+                let server = McpServer {
+                    name: name.clone(),
+                    config: server.clone(),
+                    timeout: Duration::from_secs(config.timeout_secs),
+                    connection: Mutex::new(None),
+                };
+                (name.clone(), server)
+            })
+            .collect();
+        Self {
+            max_rounds: config.max_rounds,
+            servers,
+            tools: Mutex::new(None),
+        }
+    }
+
+    /// Returns the tools of all servers.
+    ///
+    /// The list is cached once every server has answered; servers that fail
+    /// are retried on the next request.
+    async fn tools(&self) -> Arc<Vec<Tool>> {
+        let mut cached = self.tools.lock().await;
+        if let Some(tools) = cached.as_ref() {
+            return tools.clone();
+        }
+
+        let mut tools = Vec::new();
+        let mut complete = true;
+        for server in self.servers.values() {
+            match server.list_tools().await {
+                Ok(listed) => tools.extend(listed),
+                Err(e) => {
+                    tracing::warn!("获取MCP服务器{}的工具列表失败: {}", server.name, e);
+                    complete = false;
+                }
+            }
+        }
+
+        let tools = Arc::new(tools);
+        if complete {
+            tracing::info!("已加载{}个MCP工具", tools.len());
+            *cached = Some(tools.clone());
+        }
+        tools
+    }
+
+    /// Returns the tools to offer for a request, or `None` if it runs without tools.
+    async fn offered_tools(&self, client: &AnthropicClient, config: &ApiConfig) -> Option<Arc<Vec<Tool>>> {
+        if self.servers.is_empty() || self.max_rounds == 0 || !client.supports_tools(config) {
+            return None;
+        }
+        Some(self.tools().await).filter(|tools| !tools.is_empty())
+    }
+
+    /// Runs one tool call, returning the result text and whether it failed.
+    async fn call_tool(&self, tools: &[Tool], qualified_name: &str, input: Value) -> (String, bool) {
+        let Some(tool) = tools.iter().find(|tool| tool.definition["name"] == qualified_name) else {
+            return (format!("Unknown tool: {}", qualified_name), true);
+        };
+        let Some(server) = self.servers.get(&tool.server) else {
+            return (format!("Unknown MCP server: {}", tool.server), true);
+        };
+
+        tracing::info!("调用MCP工具: {}", qualified_name);
+        let params = json!({ "name": tool.name, "arguments": input });
+        match server.request("tools/call", params).await {
+            Ok(result) => {
+                let text = result["content"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|part| match part["text"].as_str() {
+                        Some(text) => text.to_string(),
+                        None => part.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                (text, result["isError"].as_bool().unwrap_or(false))
+            }
+            Err(e) => {
+                tracing::warn!("MCP工具{}调用失败: {}", qualified_name, e);
+                (format!("Tool call failed: {}", e), true)
+            }
+        }
+    }
+
+    /// Sends a non-streaming responder request with the MCP tools available.
+    ///
+    /// Usage in the returned response covers every round.
+    ///
+    /// # Errors
+    ///
+    /// Returns the responder's error if any round fails. Failed tool calls
+    /// are reported to Claude rather than failing the request.
+    pub async fn chat(
+        &self,
+        client: &AnthropicClient,
+        messages: Vec<Message>,
+        system: Option<String>,
+        config: &ApiConfig,
+    ) -> Result<AnthropicResponse> {
+        let Some(tools) = self.offered_tools(client, config).await else {
+            return client.chat(messages, system, config).await;
+        };
+        self.run(client, &tools, messages, system, config).await
+    }
+
+    async fn run(
+        &self,
+        client: &AnthropicClient,
+        tools: &[Tool],
+        messages: Vec<Message>,
+        system: Option<String>,
+        config: &ApiConfig,
+    ) -> Result<AnthropicResponse> {
+        let mut request = client.build_request(messages, system, false, config);
+        request.set_tools(tools.iter().map(|tool| tool.definition.clone()).collect());
+
+        let mut usage = Usage::default();
+        for round in 0..=self.max_rounds {
+            // 最后一轮不再允许调用工具，要求直接给出回答
+            if round == self.max_rounds {
+                request.set_tool_choice(json!({ "type": "none" }));
+            }
+
+            let mut response = client.send(&request, config).await?;
+            usage.add(&response.usage);
+
+            let calls: Vec<_> = response
+                .content
+                .iter()
+                .filter(|block| block.content_type == "tool_use")
+                .cloned()
+                .collect();
+            if response.stop_reason.as_deref() != Some("tool_use") || calls.is_empty() {
+                response.usage = usage;
+                return Ok(response);
+            }
+
+            let mut results = Vec::new();
+            for call in calls {
+                let name = call.extra.get("name").and_then(|v| v.as_str()).unwrap_or_default();
+                let input = call.extra.get("input").cloned().unwrap_or_else(|| json!({}));
+                let (text, is_error) = self.call_tool(tools, name, input).await;
+                results.push(json!({
+                    "type": "tool_result",
+                    "tool_use_id": call.extra.get("id"),
+                    "content": text,
+                    "is_error": is_error,
+                }));
+            }
+
+            // 空的文本块会被拒绝，回传时去掉
+            let assistant_content: Vec<_> = response
+                .content
+                .into_iter()
+                .filter(|block| block.content_type != "text" || !block.text.is_empty())
+                .collect();
+            request.push_turn("assistant", json!(assistant_content));
+            request.push_turn("user", Value::Array(results));
+        }
+
+        Err(ApiError::AnthropicError {
+            message: "工具调用未能产生最终回答".to_string(),
+            type_: "tool_error".to_string(),
+            param: None,
+            code: None,
+        })
+    }
+
+    /// Streams a responder request with the MCP tools available.
+    ///
+    /// Without tools to offer this is the client's own stream. Otherwise the
+    /// tool rounds run first and the final answer is sent as one chunk.
+    pub fn chat_stream<'a>(
+        &'a self,
+        client: &'a AnthropicClient,
+        messages: Vec<Message>,
+        system: Option<String>,
+        config: &'a ApiConfig,
+    ) -> Pin<Box<dyn Stream<Item = Result<StreamEvent>> + Send + 'a>> {
+        Box::pin(async_stream::stream! {
+            let Some(tools) = self.offered_tools(client, config).await else {
+                let mut stream = client.chat_stream(messages, system, config);
+                while let Some(event) = stream.next().await {
+                    yield event;
+                }
+                return;
+            };
+
+            match self.run(client, &tools, messages, system, config).await {
+                Ok(response) => {
+                    let text = response.content.into_iter().map(|block| block.text).collect::<String>();
+                    yield Ok(StreamEvent::ContentBlockDelta {
+                        index: 0,
+                        delta: ContentDelta {
+                            delta_type: "text_delta".to_string(),
+                            text,
+                        },
+                    });
+                    yield Ok(StreamEvent::MessageDelta {
+                        delta: MessageDelta {
+                            stop_reason: response.stop_reason,
+                            stop_sequence: response.stop_sequence,
+                        },
+                        usage: None,
+                    });
+                    yield Ok(StreamEvent::MessageStop);
+                }
+                Err(e) => yield Err(e),
+            }
+        })
+    }
+}
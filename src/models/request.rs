@@ -3,20 +3,44 @@
 //! This module defines the structures used to represent incoming API requests,
 //! including chat messages, configuration options, and request parameters.
 
+use crate::config::{
+    CanaryConfig, ConsensusStrategy, DeepSeekConfig, DeepSeekOptions, PassthroughConfig, Preset, StageParams,
+    SystemPromptConfig, SystemPromptStrategy,
+};
+use crate::error::{ApiError, Result};
+use crate::models::response::Source;
+use crate::tenants::Scope;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use utoipa::ToSchema;
 
 /// Primary request structure for chat API endpoints.
 ///
 /// This structure represents a complete chat request, including messages,
 /// system prompts, and configuration options for both DeepSeek and Anthropic APIs.
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ApiRequest {
     #[serde(default)]
     pub stream: bool,
+
+    /// Model the client named, echoed back with `[response] model_name =
+    /// "requested"`; the stages' models are set in their configs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
     
+    /// Records a trace of the stages, see `GET /v1/traces/{request_id}`,
+    /// and reports the upstream quota with the answer. Non-streaming answers
+    /// also carry each stage's upstream status, selected headers and body.
     #[serde(default)]
     pub verbose: bool,
+
+    /// Overrides the `MODE` setting (`normal` or `full`) for this request.
+    #[serde(default)]
+    pub mode: Option<String>,
+
+    /// Name of a generation preset from `config.toml`.
+    #[serde(default)]
+    pub preset: Option<String>,
     
     pub system: Option<String>,
     pub messages: Vec<Message>,
@@ -26,13 +50,62 @@ pub struct ApiRequest {
     
     #[serde(default)]
     pub anthropic_config: ApiConfig,
+
+    /// End-user identifier, available to system prompts as `{{user_name}}`.
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// Language the answer must be written in, overriding `[language] target`.
+    /// An empty string turns enforcement off.
+    #[serde(default)]
+    pub target_language: Option<String>,
+
+    /// Uploaded files (see `POST /v1/files`) to add to the context.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub file_ids: Vec<String>,
+
+    /// Sends the Claude stage to two responders, see [`crate::consensus`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consensus: Option<ConsensusRequest>,
+
+    /// Has Claude critique and revise its draft once, see [`crate::refine`];
+    /// unset follows `[refine] enabled`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refine: Option<bool>,
+
+    /// Model and sampling parameters of the DeepSeek stage; see
+    /// [`ApiRequest::apply_deepseek_options`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deepseek: Option<DeepSeekOptions>,
+
+    /// Canary variant this request was assigned to, `None` for stable traffic.
+    #[serde(skip)]
+    pub variant: Option<String>,
+
+    /// Retrieved chunks added to the system prompt by the retrieval stage.
+    #[serde(skip)]
+    pub sources: Vec<Source>,
+
+    /// Organization and project the request is accounted to.
+    #[serde(skip)]
+    pub scope: Scope,
+}
+
+/// Responders and strategy of a consensus request; left out fields take
+/// their `[consensus]` defaults.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct ConsensusRequest {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<ConsensusStrategy>,
 }
 
 /// A single message in a chat conversation.
 ///
 /// Represents one message in the conversation history, including
 /// its role (system, user, or assistant) and content.
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct Message {
     pub role: Role,
     pub content: String,
@@ -42,7 +115,7 @@ pub struct Message {
 ///
 /// Each message must be associated with one of these roles to
 /// properly structure the conversation flow.
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, ToSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     System,
@@ -54,7 +127,7 @@ pub enum Role {
 ///
 /// Contains headers and body parameters that will be passed
 /// to the external AI model APIs.
-#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
 pub struct ApiConfig {
     #[serde(default)]
     pub headers: HashMap<String, String>,
@@ -63,7 +136,200 @@ pub struct ApiConfig {
     pub body: serde_json::Value,
 }
 
+impl ApiConfig {
+    /// Fills body parameters from `params` that the caller did not set explicitly.
+    pub fn apply_defaults(&mut self, params: &StageParams) {
+        let defaults = [
+            ("temperature", params.temperature.map(serde_json::Value::from)),
+            ("top_p", params.top_p.map(serde_json::Value::from)),
+            ("max_tokens", params.max_tokens.map(serde_json::Value::from)),
+        ];
+        for (key, value) in defaults {
+            if let Some(value) = value {
+                self.set_default(key, value);
+            }
+        }
+    }
+
+    /// Sets a body parameter unless the caller already set it.
+    pub fn set_default(&mut self, key: &str, value: serde_json::Value) {
+        if !self.body.is_object() {
+            self.body = serde_json::Value::Object(serde_json::Map::new());
+        }
+        if let Some(body) = self.body.as_object_mut() {
+            body.entry(key).or_insert(value);
+        }
+    }
+}
+
+impl DeepSeekOptions {
+    /// Body parameters of the options that are set, with `r1` and `v3`
+    /// resolved to the configured model ids.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BadRequest` for an option out of its range.
+    pub fn params(&self, config: &DeepSeekConfig) -> Result<Vec<(&'static str, serde_json::Value)>> {
+        let ranges = [
+            ("temperature", self.temperature, 0.0, 2.0),
+            ("top_p", self.top_p, 0.0, 1.0),
+            ("presence_penalty", self.presence_penalty, -2.0, 2.0),
+            ("frequency_penalty", self.frequency_penalty, -2.0, 2.0),
+        ];
+        let mut params = Vec::new();
+        for (key, value, min, max) in ranges {
+            let Some(value) = value else { continue };
+            if !(min..=max).contains(&value) {
+                return Err(ApiError::BadRequest {
+                    message: format!("deepseek.{} must be between {} and {}", key, min, max),
+                });
+            }
+            params.push((key, serde_json::Value::from(value)));
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens == 0 {
+                return Err(ApiError::BadRequest {
+                    message: "deepseek.max_tokens must be greater than 0".to_string(),
+                });
+            }
+            params.push(("max_tokens", serde_json::Value::from(max_tokens)));
+        }
+        let model = match self.model.as_deref() {
+            Some("r1") if !config.r1_model.is_empty() => Some(config.r1_model.as_str()),
+            // 未配置r1模型时交给服务商的默认模型
+            Some("r1") => None,
+            Some("v3") => Some(config.v3_model.as_str()),
+            model => model,
+        };
+        if let Some(model) = model {
+            params.push(("model", serde_json::Value::from(model)));
+        }
+        Ok(params)
+    }
+}
+
 impl ApiRequest {
+    /// Writes the request's `deepseek` options into the DeepSeek stage body.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::BadRequest` if an option is out of range, if the
+    /// body sets the same parameter to a different value, or if the body
+    /// sets `stream` or `messages`, which the gateway controls.
+    pub fn apply_deepseek_options(&mut self, config: &DeepSeekConfig) -> Result<()> {
+        for key in ["stream", "messages"] {
+            if self.deepseek_config.body.get(key).is_some() {
+                return Err(ApiError::BadRequest {
+                    message: format!("deepseek_config.body cannot set `{}`", key),
+                });
+            }
+        }
+        let Some(options) = &self.deepseek else {
+            return Ok(());
+        };
+        for (key, value) in options.params(config)? {
+            match self.deepseek_config.body.get(key) {
+                Some(existing) if *existing != value => {
+                    return Err(ApiError::BadRequest {
+                        message: format!(
+                            "deepseek.{} conflicts with deepseek_config.body.{}; set it in one place",
+                            key, key
+                        ),
+                    });
+                }
+                _ => self.deepseek_config.set_default(key, value),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills the DeepSeek stage parameters left unset by the request, its
+    /// preset and its canary from `[deepseek.defaults]`, which are checked
+    /// when the server starts.
+    pub fn apply_deepseek_defaults(&mut self, config: &DeepSeekConfig) {
+        for (key, value) in config.defaults.params(config).unwrap_or_default() {
+            self.deepseek_config.set_default(key, value);
+        }
+    }
+
+    /// Copies the allowlisted inbound headers into each stage's headers,
+    /// keeping any the request body sets for the stage itself.
+    pub fn forward_headers(&mut self, passthrough: &PassthroughConfig, inbound: &axum::http::HeaderMap) {
+        for (config, names) in [
+            (&mut self.deepseek_config, &passthrough.deepseek),
+            (&mut self.anthropic_config, &passthrough.anthropic),
+        ] {
+            for name in names {
+                let name = name.to_ascii_lowercase();
+                let Some(value) = inbound.get(name.as_str()).and_then(|value| value.to_str().ok()) else {
+                    continue;
+                };
+                if !config.headers.keys().any(|key| key.eq_ignore_ascii_case(&name)) {
+                    config.headers.insert(name, value.to_string());
+                }
+            }
+        }
+    }
+
+    /// Applies a generation preset, keeping any values set by the request itself.
+    pub fn apply_preset(&mut self, preset: &Preset) {
+        if self.mode.is_none() {
+            self.mode = preset.mode.clone();
+        }
+        self.deepseek_config.apply_defaults(&preset.deepseek);
+        self.anthropic_config.apply_defaults(&preset.anthropic);
+    }
+
+    /// Switches the request to the canary configuration.
+    ///
+    /// Only the mode and models the request leaves unset are overridden.
+    pub fn apply_canary(&mut self, canary: &CanaryConfig) {
+        if self.mode.is_none() {
+            self.mode = canary.mode.clone();
+        }
+        for (config, model) in [
+            (&mut self.deepseek_config, &canary.deepseek_model),
+            (&mut self.anthropic_config, &canary.anthropic_model),
+        ] {
+            if let Some(model) = model {
+                config.set_default("model", serde_json::Value::from(model.as_str()));
+            }
+        }
+        self.variant = Some(canary.name.clone());
+    }
+
+    /// Prepends context to the system prompt, wherever the request put it,
+    /// and records the sources it was drawn from.
+    pub fn add_context(&mut self, context: &str, sources: Vec<Source>) {
+        if let Some(system) = &mut self.system {
+            *system = format!("{}\n\n{}", context, system);
+        } else if let Some(message) = self.messages.iter_mut().find(|m| m.role == Role::System) {
+            message.content = format!("{}\n\n{}", context, message.content);
+        } else {
+            self.system = Some(context.to_string());
+        }
+        self.sources.extend(sources);
+    }
+
+    /// Returns the variant tag used in usage records and metrics.
+    pub fn variant_tag(&self) -> &str {
+        self.variant.as_deref().unwrap_or("stable")
+    }
+
+    /// Passes the end user to the Claude stage as the OpenAI `user` field,
+    /// unless the stage body already names one.
+    pub fn forward_user(&mut self) {
+        if let Some(user) = &self.user {
+            let body = &mut self.anthropic_config.body;
+            if body.is_null() {
+                *body = serde_json::json!({});
+            }
+            if body.is_object() && body.get("user").is_none() {
+                body["user"] = serde_json::json!(user);
+            }
+        }
+    }
+
     /// Validates that system prompts are not duplicated.
     ///
     /// Checks that a system prompt is not provided in both the root level
@@ -82,16 +348,19 @@ impl ApiRequest {
     /// Returns messages with the system prompt in the correct position.
     ///
     /// Ensures the system prompt (if present) is the first message,
-    /// followed by the conversation messages in order.
+    /// followed by the conversation messages in order. The system prompt is
+    /// merged with the deployment's base prompt and left out if the mode
+    /// does not forward it to DeepSeek, and a language instruction, if
+    /// given, is added to it.
     ///
     /// # Returns
     ///
     /// * `Vec<Message>` - Messages with system prompt correctly positioned
-    pub fn get_messages_with_system(&self) -> Vec<Message> {
+    pub fn get_messages_with_system(&self, config: &SystemPromptConfig, mode: &str, language: Option<&str>) -> Vec<Message> {
         let mut messages = Vec::new();
 
         // Add system message first
-        if let Some(system) = &self.system {
+        if let Some(system) = self.deepseek_system_prompt(config, mode) {
             // 为 DeepSeek R1 添加特定的系统提示词
             let deepseek_system_prompt = format!("Act as an expert architect engineer and provide direction to your editor engineer.
 Study the change request and the current code.
@@ -102,8 +371,6 @@ Just show the changes needed.
 
 DO NOT show the entire updated function/file/etc!
 
-Always reply to the user in chinese.
-
 {}", system);
             
             messages.push(Message {
@@ -119,9 +386,7 @@ The editor engineer will rely solely on your instructions, so make them unambigu
 Explain all needed code changes clearly and completely, but concisely.
 Just show the changes needed.
 
-DO NOT show the entire updated function/file/etc!
-
-Always reply to the user in chinese.";
+DO NOT show the entire updated function/file/etc!";
             
             messages.push(Message {
                 role: Role::System,
@@ -129,6 +394,11 @@ Always reply to the user in chinese.";
             });
         }
 
+        if let Some(language) = language {
+            messages[0].content.push_str("\n\n");
+            messages[0].content.push_str(language);
+        }
+
         // Add remaining messages
         messages.extend(self.messages.iter().filter(|msg| !matches!(msg.role, Role::System)).cloned());
 
@@ -151,4 +421,28 @@ Always reply to the user in chinese.";
                 .map(|msg| msg.content.as_str())
         })
     }
+
+    /// Returns the system prompt merged with the deployment's base prompt
+    /// per its strategy.
+    pub fn merged_system_prompt(&self, base: &SystemPromptConfig) -> Option<String> {
+        let system = self.get_system_prompt();
+        if base.base.is_empty() {
+            return system.map(String::from);
+        }
+        Some(match (base.strategy, system) {
+            (SystemPromptStrategy::Override, _) | (_, None) => base.base.clone(),
+            (SystemPromptStrategy::Prepend, Some(system)) => format!("{}\n\n{}", base.base, system),
+            (SystemPromptStrategy::Append, Some(system)) => format!("{}\n\n{}", system, base.base),
+        })
+    }
+
+    /// The merged system prompt, if the mode forwards it to DeepSeek.
+    pub fn deepseek_system_prompt(&self, config: &SystemPromptConfig, mode: &str) -> Option<String> {
+        self.merged_system_prompt(config).filter(|_| config.forward_for(mode).to_deepseek())
+    }
+
+    /// The merged system prompt, if the mode forwards it to Claude.
+    pub fn claude_system_prompt(&self, config: &SystemPromptConfig, mode: &str) -> Option<String> {
+        self.merged_system_prompt(config).filter(|_| config.forward_for(mode).to_claude())
+    }
 }
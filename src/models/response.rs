@@ -3,9 +3,11 @@
 //! This module defines the structures used to represent API responses,
 //! including chat completions, usage statistics, and streaming events.
 
+use crate::config::ConsensusStrategy;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use utoipa::ToSchema;
 //use crate::handlers::format_cost;
 
 /// Primary response structure for chat API endpoints.
@@ -37,17 +39,39 @@ pub struct ContentBlock {
     pub text: String,
 }
 
+/// A retrieved document chunk that was added to the request's context.
+///
+/// `index` is the number the chunk is cited by in the context, e.g. `[1]`.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct Source {
+    pub index: usize,
+    pub source: String,
+    pub score: f32,
+}
+
 /// Raw response from an external API.
 ///
 /// Contains the complete response details from an external API
 /// call, including status code, headers, and response body.
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct ExternalApiResponse {
     pub status: u16,
     pub headers: HashMap<String, String>,
     pub body: serde_json::Value,
 }
 
+/// Upstream responses of the pipeline stages.
+///
+/// A stage is left out when it made no upstream call of its own, such as
+/// reasoning reused from the cache or answers from a consensus.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct UpstreamResponses {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deepseek: Option<ExternalApiResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anthropic: Option<ExternalApiResponse>,
+}
+
 /// Combined usage statistics from both AI models.
 ///
 /// Aggregates token usage and cost information from both
@@ -95,6 +119,7 @@ pub struct AnthropicUsage {
 /// and usage statistics.
 #[derive(Debug, Serialize)]
 #[serde(tag = "type")]
+#[allow(dead_code)]
 pub enum StreamEvent {
     #[serde(rename = "start")]
     #[allow(dead_code)]
@@ -223,28 +248,69 @@ impl AnthropicUsage {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// The OpenAI `finish_reason` values.
+pub const FINISH_REASONS: &[&str] = &["stop", "length", "tool_calls", "content_filter", "function_call"];
+
+/// Maps an upstream stop reason to an OpenAI `finish_reason`.
+///
+/// Anthropic reasons (`end_turn`, `max_tokens`, `stop_sequence`,
+/// `tool_use`, `refusal`) and OpenAI-format ones are both understood, and
+/// `overrides` from `[finish_reasons]` take precedence. A missing or unknown
+/// reason is reported as `stop`.
+pub fn finish_reason(stop_reason: Option<&str>, overrides: &HashMap<String, String>) -> String {
+    let Some(reason) = stop_reason else {
+        return "stop".to_string();
+    };
+    if let Some(mapped) = overrides.get(reason) {
+        return mapped.clone();
+    }
+    match reason {
+        "max_tokens" | "length" | "model_context_window_exceeded" => "length",
+        "tool_use" | "tool_calls" => "tool_calls",
+        "function_call" => "function_call",
+        "refusal" | "content_filter" | "safety" => "content_filter",
+        _ => "stop",
+    }
+    .to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct Choice {
     pub index: i32,
     pub message: Message,
     pub finish_reason: String,
+    /// The upstream's own stop reason, for verbose requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[schema(as = ResponseMessage)]
 pub struct Message {
     pub role: String,
     pub content: String,
     pub reasoning_content: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    /// Tokens of the refinement pass, included in the totals above.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refine: Option<PassUsage>,
+}
+
+/// Tokens of one extra pass over the answer.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct PassUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct OpenAICompatibleResponse {
     pub id: String,
     pub object: String,
@@ -252,13 +318,44 @@ pub struct OpenAICompatibleResponse {
     pub model: String,
     pub choices: Vec<Choice>,
     pub usage: Usage,
+    /// Retrieved chunks the answer may draw on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<Source>,
+    /// Upstream quota reported by each stage, for verbose requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<serde_json::Value>,
+    /// Status, selected headers and body of each stage's upstream response,
+    /// for verbose requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<UpstreamResponses>,
+    /// Set when the answer was served from the response cache.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+    /// How the choices of a consensus request were produced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consensus: Option<ConsensusSummary>,
+}
+
+/// The responders of a consensus request and the judge's verdict.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ConsensusSummary {
+    pub strategy: ConsensusStrategy,
+    pub models: Vec<String>,
+    /// `a` or `b` for a picked answer, `merged` for a merged one; absent
+    /// when the judge was not asked or did not answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verdict: Option<String>,
+    /// Responders that failed; their answer is missing from the choices.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<String>,
 }
 
 // 在文件底部添加
 impl From<OpenAICompatibleResponse> for ApiResponse {
     fn from(response: OpenAICompatibleResponse) -> Self {
-        let content = response.choices[0].message.content.clone();
-        let reasoning = response.choices[0].message.reasoning_content.clone();
+        let message = response.choices.first().map(|choice| &choice.message);
+        let content = message.map(|message| message.content.clone()).unwrap_or_default();
+        let reasoning = message.and_then(|message| message.reasoning_content.clone());
         
         let mut content_blocks = Vec::new();
         
@@ -0,0 +1,149 @@
+//! API documentation for integrators.
+//!
+//! - `GET /openapi.json` - OpenAPI document of the public endpoints
+//! - `GET /docs` - Swagger UI over that document
+//!
+//! The document is generated from the handlers' `#[utoipa::path]`
+//! annotations and the request and response models, so it follows the code.
+//! Chat requests and responses are the OpenAI ones plus the DeepClaude
+//! extensions: `mode`, `verbose`, `preset`, the per-stage configs and the
+//! `reasoning_content` of the answer.
+
+use crate::{
+    abuse::{Ban, BanRequest},
+    admin, capabilities, complete,
+    config::{ConsensusStrategy, DeepSeekOptions},
+    diagnostics, echo,
+    error::{ErrorDetails, ErrorResponse},
+    estimate,
+    files,
+    flags::{FlagChange, FlagUpdate, FlagValues},
+    handlers,
+    keys::{self, IssueKeyRequest},
+    logs,
+    models::{
+        request::{ApiConfig, ApiRequest, ConsensusRequest, Message, Role},
+        response::{
+            Choice, ConsensusSummary, ExternalApiResponse, Message as ResponseMessage, OpenAICompatibleResponse,
+            PassUsage, Source, UpstreamResponses, Usage,
+        },
+    },
+    payloads,
+    retention::{self, PurgeRequest},
+    status, streams, tenants, traces, usage,
+};
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+/// The OpenAPI document of the public endpoints.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "DeepClaude",
+        description = "OpenAI-compatible chat completions that pair DeepSeek R1 reasoning with a Claude answer."
+    ),
+    paths(
+        handlers::handle_chat,
+        streams::cancel_stream,
+        handlers::list_models,
+        capabilities::capabilities,
+        traces::get_trace,
+        files::upload_file,
+        files::get_file,
+        files::delete_file,
+        keys::issue_key,
+        keys::list_keys,
+        keys::revoke_key,
+        estimate::estimate,
+        usage::usage_report,
+        usage::export_usage,
+        handlers::metrics,
+        status::status,
+        handlers::update_env_variables,
+        handlers::get_env_variables,
+        admin::reload_providers,
+        admin::list_dead_letters,
+        admin::replay_dead_letter,
+        admin::list_streams,
+        admin::get_flags,
+        admin::put_flags,
+        admin::list_bans,
+        admin::put_ban,
+        admin::delete_ban,
+        retention::purge,
+        tenants::export_tenant,
+        logs::stream_logs,
+        payloads::get_payloads,
+        echo::echo_completions,
+        complete::complete,
+        diagnostics::list_tasks,
+    ),
+    components(schemas(
+        ApiRequest,
+        Message,
+        Role,
+        ApiConfig,
+        DeepSeekOptions,
+        ConsensusRequest,
+        ConsensusStrategy,
+        OpenAICompatibleResponse,
+        Choice,
+        ResponseMessage,
+        Usage,
+        PassUsage,
+        Source,
+        ConsensusSummary,
+        UpstreamResponses,
+        ExternalApiResponse,
+        ErrorResponse,
+        ErrorDetails,
+        FlagValues,
+        FlagUpdate,
+        FlagChange,
+        Ban,
+        BanRequest,
+        IssueKeyRequest,
+        PurgeRequest,
+    )),
+    modifiers(&Credentials),
+    security(("bearer" = []), ("bearer" = [], "anthropic_token" = [])),
+    tags(
+        (name = "chat", description = "Chat completions and what they can use"),
+        (name = "files", description = "Documents added to the context with `file_ids`"),
+        (name = "keys", description = "Virtual keys issued by tenant owners"),
+        (name = "usage", description = "Token and cost accounting"),
+        (name = "operations", description = "Metrics and server settings"),
+        (name = "admin", description = "Endpoints that take the admin token"),
+        (name = "debug", description = "Endpoints enabled under `[debug]`"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Declares how requests authenticate.
+struct Credentials;
+
+impl Modify for Credentials {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        // 虚拟密钥、上游密钥或管理员令牌均作为Bearer令牌发送
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "anthropic_token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-Anthropic-API-Token",
+                "Claude key, when the bearer token is a DeepSeek key",
+            ))),
+        );
+    }
+}
+
+/// Routes serving the document and Swagger UI.
+pub fn routes() -> SwaggerUi {
+    SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi())
+}
@@ -0,0 +1,204 @@
+//! Pacing of batch requests by the upstreams' reported rate limits.
+//!
+//! With `[pacing] enabled`, the requests of `batch` keys (see
+//! [`crate::concurrency`]) take a token from a bucket per upstream,
+//! `deepseek` and `anthropic`, before they run, and wait for one for up to
+//! `max_wait_ms` before they are rejected with 429. Interactive requests
+//! are never paced. Each bucket holds one token and refills at a rate
+//! derived from the rate-limit headers of the upstream's latest response
+//! (see [`crate::clients::quota`]): the reported requests-per-minute limit,
+//! or `requests_per_minute` until one is reported, scaled down as the
+//! remaining share of the most used resource falls towards `reserve`, so
+//! that share is left to interactive requests. A 429 from the upstream
+//! stops batch requests until its `retry-after` has passed and halves the
+//! rate, which then grows back with every successful response. Batches
+//! thus run as fast as the upstream allows without setting off a storm of
+//! 429s. The current rates are exported at `GET /metrics`.
+
+use crate::{
+    clients::quota,
+    config::PacingConfig,
+    error::{ApiError, Result},
+    keys::{Priority, VirtualKey},
+};
+use once_cell::sync::Lazy;
+use reqwest::{header::HeaderMap, StatusCode};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Upstreams batch requests are paced for, as named in `[rate_limits]`.
+const UPSTREAMS: [&str; 2] = ["deepseek", "anthropic"];
+
+/// Smallest share of the full rate batch requests keep.
+const MIN_SHARE: f64 = 0.05;
+
+/// Share of the full rate regained with each successful response.
+const RECOVERY: f64 = 0.05;
+
+/// How long batch requests stop after a 429 without a `retry-after`.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// What the latest responses of an upstream said about its limits.
+#[derive(Debug)]
+struct Observed {
+    /// Reported requests per minute.
+    limit: Option<f64>,
+    /// Remaining share of the most used resource.
+    remaining: Option<f64>,
+    /// Share of the rate left after recent 429s.
+    backoff: f64,
+    retry_until: Option<Instant>,
+}
+
+impl Default for Observed {
+    fn default() -> Self {
+        Self {
+            limit: None,
+            remaining: None,
+            backoff: 1.0,
+            retry_until: None,
+        }
+    }
+}
+
+/// Latest observations per upstream, fed by both clients.
+static OBSERVED: Lazy<Mutex<HashMap<String, Observed>>> = Lazy::new(Default::default);
+
+/// Records what a response from `upstream` said about its rate limits.
+pub(crate) fn observe(upstream: &str, status: StatusCode, headers: &HeaderMap) {
+    let reported = quota::parse(headers);
+    let mut observed = OBSERVED.lock().unwrap();
+    let observed = observed.entry(upstream.to_string()).or_default();
+    if let Some(limit) = reported.get("requests").and_then(|requests| requests.limit) {
+        observed.limit = Some(limit);
+    }
+    let remaining = reported
+        .values()
+        .filter_map(|limit| Some(limit.remaining? / limit.limit.filter(|total| *total > 0.0)?))
+        .reduce(f64::min);
+    if remaining.is_some() {
+        observed.remaining = remaining;
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = headers
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+        observed.retry_until = Some(Instant::now() + retry_after);
+        observed.backoff = (observed.backoff / 2.0).max(MIN_SHARE);
+        tracing::warn!("{}返回429，批处理请求暂停{:?}，速率降为{:.0}%", upstream, retry_after, observed.backoff * 100.0);
+    } else if status.is_success() {
+        observed.backoff = (observed.backoff + RECOVERY).min(1.0);
+    }
+}
+
+/// Token bucket of one upstream.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Paces batch requests per upstream.
+pub struct Pacer {
+    config: PacingConfig,
+    buckets: Mutex<HashMap<&'static str, Bucket>>,
+}
+
+impl Pacer {
+    pub fn new(config: &PacingConfig) -> Self {
+        Self {
+            config: config.clone(),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Requests per second batch requests to `upstream` may start at, and
+    /// when they may start again after a 429.
+    fn rate(&self, upstream: &str) -> (f64, Option<Instant>) {
+        let observed = OBSERVED.lock().unwrap();
+        let Some(observed) = observed.get(upstream) else {
+            return (self.config.requests_per_minute / 60.0, None);
+        };
+        let limit = observed.limit.unwrap_or(self.config.requests_per_minute);
+        // 剩余份额高于预留部分时按比例使用，降到预留部分时只保留最低速率
+        let reserve = self.config.reserve.clamp(0.0, 0.99);
+        let headroom = observed.remaining.map_or(1.0, |remaining| (remaining - reserve) / (1.0 - reserve));
+        let share = (headroom.clamp(MIN_SHARE, 1.0) * observed.backoff).max(MIN_SHARE);
+        (limit / 60.0 * share, observed.retry_until)
+    }
+
+    /// Takes a token from the bucket of `upstream`, or returns how long
+    /// until one is available.
+    fn take(&self, upstream: &'static str) -> std::result::Result<(), Duration> {
+        let (rate, retry_until) = self.rate(upstream);
+        let now = Instant::now();
+        if let Some(until) = retry_until.filter(|until| *until > now) {
+            return Err(until - now);
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(upstream).or_insert(Bucket { tokens: 1.0, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(1.0);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+
+    /// Waits until a request made with `key` may be sent to the upstreams.
+    /// Only the requests of batch keys wait.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::RateLimited` if an upstream's bucket does not
+    /// refill within `max_wait_ms`.
+    pub async fn acquire(&self, key: Option<&VirtualKey>) -> Result<()> {
+        if !self.config.enabled || key.is_none_or(|key| key.priority != Priority::Batch) {
+            return Ok(());
+        }
+        let deadline = Instant::now() + Duration::from_millis(self.config.max_wait_ms);
+        for upstream in UPSTREAMS {
+            while let Err(wait) = self.take(upstream) {
+                if Instant::now().checked_add(wait).is_none_or(|ready| ready > deadline) {
+                    tracing::warn!("{}的批处理速率已用尽，拒绝请求", upstream);
+                    return Err(ApiError::RateLimited {
+                        provider: upstream.to_string(),
+                        retry_after_secs: wait.as_secs().clamp(1, 3600),
+                    });
+                }
+                tracing::debug!("批处理请求等待{}的速率{:?}", upstream, wait);
+                tokio::time::sleep(wait.max(Duration::from_millis(10))).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the batch rates in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        if !self.config.enabled {
+            return out;
+        }
+        let name = "deepclaude_batch_pace_requests_per_minute";
+        let _ = writeln!(
+            out,
+            "# HELP {} Rate batch requests are paced at per upstream.\n# TYPE {} gauge",
+            name, name
+        );
+        for upstream in UPSTREAMS {
+            let _ = writeln!(out, "{}{{provider=\"{}\"}} {}", name, upstream, self.rate(upstream).0 * 60.0);
+        }
+        out
+    }
+}
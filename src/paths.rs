@@ -0,0 +1,54 @@
+//! Locations of the configuration and runtime files.
+//!
+//! `.env` and `config.toml` are read from the config directory: the
+//! `--config-dir` argument, else `DEEPCLAUDE_HOME`, else the working
+//! directory. Files the server writes at runtime (traces, uploads, dead
+//! letters, shadow records) go under the data directory:
+//! `DEEPCLAUDE_DATA_DIR`, else `[server] data_dir`, else the config
+//! directory. Relative paths in the config are resolved against these
+//! directories instead of the working directory, so the server behaves the
+//! same whatever directory it is started from.
+
+use once_cell::sync::OnceCell;
+use std::path::{Path, PathBuf};
+
+static CONFIG_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// Sets the config directory from the command line, before anything reads
+/// the configuration.
+pub fn init(config_dir: Option<PathBuf>) {
+    if let Some(dir) = config_dir {
+        let _ = CONFIG_DIR.set(dir);
+    }
+}
+
+/// Returns the directory holding `.env` and `config.toml`.
+pub fn config_dir() -> &'static Path {
+    CONFIG_DIR.get_or_init(|| std::env::var_os("DEEPCLAUDE_HOME").map(PathBuf::from).unwrap_or_default())
+}
+
+/// Returns the path of the `.env` file.
+pub fn env_file() -> PathBuf {
+    config_dir().join(".env")
+}
+
+/// Returns the path of `config.toml`.
+pub fn config_file() -> PathBuf {
+    config_dir().join("config.toml")
+}
+
+/// Returns the directory runtime files are written under.
+pub fn data_dir(configured: &str) -> PathBuf {
+    let dir = std::env::var("DEEPCLAUDE_DATA_DIR").unwrap_or_else(|_| configured.to_string());
+    resolve(config_dir(), &dir)
+}
+
+/// Resolves a relative path against `base`; absolute paths are kept.
+pub fn resolve(base: &Path, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base.join(path)
+    }
+}
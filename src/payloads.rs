@@ -0,0 +1,189 @@
+//! Capture of the exact upstream request bodies, for debugging.
+//!
+//! - `GET /admin/requests/{id}/payloads` - the bodies sent upstream for a
+//!   chat request
+//!
+//! While the `payload_capture` runtime flag is on (see [`crate::flags`];
+//! `[debug] payloads` sets it at startup), every body a chat request sends
+//! to DeepSeek, Claude or another upstream is kept byte for byte, in the
+//! order sent, with the upstream and URL it went to. Headers, and so
+//! credentials, are not kept. Bodies are kept under the request's
+//! `X-Request-Id`, which is generated when the client sends none and is
+//! returned on every response. Streamed requests keep adding bodies until
+//! their stream ends. Only the last `MAX_REQUESTS` requests are kept, in
+//! memory, with the tenant they acted for so that erasing a tenant (see
+//! [`crate::retention`]) drops them. Needs an admin token with the operator role, since bodies hold
+//! the conversation.
+
+use crate::{
+    admin,
+    config::AdminRole,
+    error::{ApiError, ErrorResponse, Result},
+    handlers::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures::future::Either;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// How many requests' bodies are kept.
+const MAX_REQUESTS: usize = 500;
+
+/// One body sent upstream.
+#[derive(Debug, Clone, Serialize)]
+pub struct SentPayload {
+    pub at: DateTime<Utc>,
+    /// `deepseek`, `anthropic` or `openai`.
+    pub upstream: String,
+    pub url: String,
+    /// The body exactly as sent.
+    pub body: String,
+}
+
+type Payloads = Arc<Mutex<Vec<SentPayload>>>;
+
+/// The bodies of one request, with the tenant it acted for.
+struct Captured {
+    tenant: Option<String>,
+    payloads: Payloads,
+}
+
+tokio::task_local! {
+    /// Bodies sent by the request being served, while it is captured.
+    static CAPTURED: Payloads;
+}
+
+/// Records a body about to be sent upstream, if the request is captured.
+pub(crate) fn record(upstream: &str, url: &str, body: &impl Serialize) {
+    let _ = CAPTURED.try_with(|payloads| {
+        let body = serde_json::to_string(body).unwrap_or_default();
+        payloads.lock().unwrap().push(SentPayload {
+            at: Utc::now(),
+            upstream: upstream.to_string(),
+            url: url.to_string(),
+            body,
+        });
+    });
+}
+
+/// Carries the request's capture, if any, into a future that is about to
+/// be spawned.
+pub fn inherit<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    match CAPTURED.try_with(Arc::clone) {
+        Ok(payloads) => Either::Left(CAPTURED.scope(payloads, f)),
+        Err(_) => Either::Right(f),
+    }
+}
+
+/// The captured bodies of recent requests.
+#[derive(Default)]
+pub struct PayloadStore {
+    enabled: AtomicBool,
+    requests: Mutex<(VecDeque<String>, HashMap<String, Captured>)>,
+}
+
+impl PayloadStore {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+            requests: Default::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turns capture on or off for later requests.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Runs `f`, keeping the bodies it sends upstream under `request_id`,
+    /// for `tenant`, while capture is on.
+    pub async fn capture<F: Future>(&self, request_id: &str, tenant: Option<String>, f: F) -> F::Output {
+        if !self.enabled() {
+            return f.await;
+        }
+        let payloads = Payloads::default();
+        {
+            let mut requests = self.requests.lock().unwrap();
+            let (order, by_id) = &mut *requests;
+            let captured = Captured {
+                tenant,
+                payloads: payloads.clone(),
+            };
+            if by_id.insert(request_id.to_string(), captured).is_none() {
+                order.push_back(request_id.to_string());
+            }
+            while order.len() > MAX_REQUESTS {
+                if let Some(oldest) = order.pop_front() {
+                    by_id.remove(&oldest);
+                }
+            }
+        }
+        CAPTURED.scope(payloads, f).await
+    }
+
+    /// The bodies captured for a request, in the order sent.
+    pub fn get(&self, request_id: &str) -> Option<Vec<SentPayload>> {
+        let requests = self.requests.lock().unwrap();
+        requests.1.get(request_id).map(|captured| captured.payloads.lock().unwrap().clone())
+    }
+
+    /// Drops the bodies captured for a tenant's requests, returning for how
+    /// many requests.
+    pub fn erase(&self, tenant: &str) -> u64 {
+        let mut requests = self.requests.lock().unwrap();
+        let (order, by_id) = &mut *requests;
+        let before = by_id.len();
+        by_id.retain(|_, captured| captured.tenant.as_deref() != Some(tenant));
+        order.retain(|id| by_id.contains_key(id));
+        (before - by_id.len()) as u64
+    }
+}
+
+/// Returns the bodies a chat request sent upstream.
+///
+/// # Errors
+///
+/// Returns `ApiError::NotFound` if no bodies were captured for the request.
+#[utoipa::path(
+    get,
+    path = "/admin/requests/{id}/payloads",
+    tag = "admin",
+    params(("id" = String, Path, description = "X-Request-Id of the chat request")),
+    responses(
+        (status = 200, description = "The bodies sent upstream, in order", body = Object),
+        (status = 404, description = "Nothing was captured for the request", body = ErrorResponse),
+    )
+)]
+pub async fn get_payloads(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>> {
+    admin::require_admin(&state, &headers, AdminRole::Operator)?;
+
+    let payloads = state.payloads.get(&id).ok_or_else(|| ApiError::NotFound {
+        message: format!("No payloads were captured for request '{}'; turn on the payload_capture flag first", id),
+    })?;
+    Ok(Json(json!({
+        "status": "success",
+        "request_id": id,
+        "payloads": payloads,
+    })))
+}
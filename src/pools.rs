@@ -0,0 +1,122 @@
+//! Latency-based choice among interchangeable models.
+//!
+//! `[model_pools.<name>]` lists models or aliases that can serve the same
+//! stage, e.g. two Claude gateways. A request naming the pool as a stage's
+//! model gets the member with the best rolling median latency among those
+//! whose recent error rate is acceptable; members without calls yet are
+//! tried first. A conversation, identified by its user and first user
+//! message, keeps its member for `sticky_secs` unless that member turns
+//! unhealthy.
+
+use crate::{
+    clients::stats,
+    config::ModelPool,
+    models::request::{ApiRequest, Role},
+};
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Members need this many recent calls before their error rate counts.
+const MIN_CALLS: usize = 5;
+
+/// The configured pools and the members conversations are bound to.
+#[derive(Debug)]
+pub struct ModelPools {
+    pools: HashMap<String, ModelPool>,
+    sticky: Mutex<HashMap<u64, (String, Instant)>>,
+}
+
+impl ModelPools {
+    /// Checks the `[model_pools]` settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the first pool without members.
+    pub fn new(pools: &HashMap<String, ModelPool>) -> anyhow::Result<Self> {
+        if let Some((name, _)) = pools.iter().find(|(_, pool)| pool.members.is_empty()) {
+            anyhow::bail!("模型池'{}'没有成员", name);
+        }
+        Ok(Self {
+            pools: pools.clone(),
+            sticky: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the pool names, which requests can use as models.
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.pools.keys()
+    }
+
+    /// Replaces stage models naming a pool with the member to use.
+    pub fn apply(&self, request: &mut ApiRequest) {
+        if self.pools.is_empty() {
+            return;
+        }
+        let conversation = conversation_id(request);
+        let streaming = request.stream;
+        for config in [&mut request.deepseek_config, &mut request.anthropic_config] {
+            let Some((name, pool)) = config
+                .body
+                .get("model")
+                .and_then(|v| v.as_str())
+                .and_then(|model| self.pools.get_key_value(model))
+            else {
+                continue;
+            };
+            let member = self.pick(name, pool, streaming, conversation);
+            tracing::info!("模型池{}选择了{}", name, member);
+            config.body["model"] = serde_json::json!(member);
+        }
+    }
+
+    fn pick(&self, name: &str, pool: &ModelPool, streaming: bool, conversation: u64) -> String {
+        let mut hasher = DefaultHasher::new();
+        (name, conversation).hash(&mut hasher);
+        let key = hasher.finish();
+
+        let now = Instant::now();
+        let ttl = Duration::from_secs(pool.sticky_secs);
+        let mut sticky = self.sticky.lock().unwrap();
+        sticky.retain(|_, (_, at)| now.duration_since(*at) < ttl);
+        if let Some((member, at)) = sticky.get_mut(&key) {
+            if healthy(pool, &stats::call_stats(member, streaming)) {
+                *at = now;
+                return member.clone();
+            }
+        }
+
+        // 先避开错误率过高的成员，再比较中位延迟，没有记录的成员优先尝试
+        let member = pool
+            .members
+            .iter()
+            .min_by_key(|member| {
+                let stats = stats::call_stats(member, streaming);
+                (!healthy(pool, &stats), stats.p50.unwrap_or_default())
+            })
+            .cloned()
+            .unwrap_or_default();
+        sticky.insert(key, (member.clone(), now));
+        member
+    }
+}
+
+fn healthy(pool: &ModelPool, stats: &stats::CallStats) -> bool {
+    stats.calls < MIN_CALLS || stats.error_rate <= pool.max_error_rate
+}
+
+/// Identifies a conversation by its user and first user message.
+fn conversation_id(request: &ApiRequest) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request.user.hash(&mut hasher);
+    request
+        .messages
+        .iter()
+        .find(|message| message.role == Role::User)
+        .map(|message| message.content.as_str())
+        .hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,110 @@
+//! Post-processing of the final answer.
+//!
+//! The `[postprocess]` transforms are compiled once at startup and picked per
+//! request by Claude stage model, then mode. Non-streaming responses are
+//! transformed before they are returned. Streaming responses with transforms
+//! are buffered and sent as one chunk when Claude finishes, so both paths
+//! return the same text.
+
+use crate::config::{PostprocessConfig, Transform};
+use regex::Regex;
+use std::collections::HashMap;
+
+/// A compiled list of transforms.
+#[derive(Debug)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+}
+
+#[derive(Debug)]
+enum Step {
+    StripCodeFences,
+    Trim,
+    StripThinking(Regex),
+    MaxLength(usize),
+    RegexReplace(Regex, String),
+}
+
+impl Pipeline {
+    fn compile(transforms: &[Transform]) -> anyhow::Result<Option<Self>> {
+        if transforms.is_empty() {
+            return Ok(None);
+        }
+        let steps = transforms
+            .iter()
+            .map(|transform| {
+                Ok(match transform {
+                    Transform::StripCodeFences => Step::StripCodeFences,
+                    Transform::Trim => Step::Trim,
+                    // 未闭合的<thinking>一直删除到结尾
+                    Transform::StripThinking => Step::StripThinking(Regex::new(r"(?s)<thinking>.*?(</thinking>|$)")?),
+                    Transform::MaxLength { chars } => Step::MaxLength(*chars),
+                    Transform::RegexReplace { pattern, replacement } => Step::RegexReplace(
+                        Regex::new(pattern).map_err(|e| anyhow::anyhow!("无效的后处理正则 {}: {}", pattern, e))?,
+                        replacement.clone(),
+                    ),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Some(Self { steps }))
+    }
+
+    /// Runs the transforms over an answer.
+    pub fn apply(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for step in &self.steps {
+            text = match step {
+                Step::StripCodeFences => text
+                    .lines()
+                    .filter(|line| !line.trim_start().starts_with("```"))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Step::Trim => text.trim().to_string(),
+                Step::StripThinking(regex) => regex.replace_all(&text, "").into_owned(),
+                Step::MaxLength(chars) => text.chars().take(*chars).collect(),
+                Step::RegexReplace(regex, replacement) => regex.replace_all(&text, replacement.as_str()).into_owned(),
+            };
+        }
+        text
+    }
+}
+
+/// Compiled `[postprocess]` pipelines.
+#[derive(Debug, Default)]
+pub struct PostProcessor {
+    default: Option<Pipeline>,
+    modes: HashMap<String, Option<Pipeline>>,
+    models: HashMap<String, Option<Pipeline>>,
+}
+
+impl PostProcessor {
+    /// Compiles the `[postprocess]` settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a `regex_replace` pattern does not compile.
+    pub fn new(config: &PostprocessConfig) -> anyhow::Result<Self> {
+        let compile_all = |entries: &HashMap<String, Vec<Transform>>| {
+            entries
+                .iter()
+                .map(|(name, transforms)| Ok((name.clone(), Pipeline::compile(transforms)?)))
+                .collect::<anyhow::Result<HashMap<_, _>>>()
+        };
+        Ok(Self {
+            default: Pipeline::compile(&config.default)?,
+            modes: compile_all(&config.modes)?,
+            models: compile_all(&config.models)?,
+        })
+    }
+
+    /// Returns the pipeline for a request, `None` if it has no transforms.
+    ///
+    /// An empty list for a model or mode turns post-processing off for it.
+    pub fn pipeline(&self, mode: &str, model: &str) -> Option<&Pipeline> {
+        self.models
+            .get(model)
+            .or_else(|| self.modes.get(mode))
+            .unwrap_or(&self.default)
+            .as_ref()
+    }
+}
@@ -0,0 +1,428 @@
+//! Raw passthrough routes to the upstream APIs.
+//!
+//! - `/deepseek/v1/*` - forwarded to `[proxy] deepseek_url`
+//! - `/anthropic/v1/*` - forwarded to `[proxy] anthropic_url`
+//!
+//! Each route is off unless enabled under `[proxy]`. A call is forwarded
+//! as is, with any method, path, query and body, and no pipeline stage
+//! runs: teams making direct single-model calls still go through the
+//! gateway's keys, budgets and usage records. The caller's credentials are
+//! replaced by the upstream key: the one stored on the caller's virtual
+//! key, otherwise the caller's own upstream token when it carries no
+//! virtual key, otherwise the server's key from `.env`. Once virtual keys
+//! are configured, calls without one are refused. A
+//! virtual key's model allowlist applies to the body's `model`, and its
+//! mode allowlist must include `passthrough` if it is restricted.
+//!
+//! The response, streaming or not, is passed back unchanged. The token
+//! counts of a successful response are read from its `usage` objects as it
+//! goes through, and one usage record with mode `passthrough` is written
+//! when the body ends, priced like the stage of the same provider.
+
+use crate::{
+    clients::providers,
+    config::ProxyConfig,
+    error::{ApiError, Result},
+    handlers::{self, AppState},
+    secrets,
+    tenants::{self, Scope},
+    usage::UsageRecord,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, RawQuery, State},
+    http::{header, HeaderMap, HeaderName, Method},
+    response::Response,
+};
+use chrono::Utc;
+use futures::StreamExt;
+use reqwest::Client;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Request headers that are not forwarded: hop-by-hop headers, and the
+/// caller's credentials, which are replaced by the upstream key.
+const DROPPED_REQUEST_HEADERS: [&str; 8] = [
+    "host",
+    "connection",
+    "content-length",
+    "transfer-encoding",
+    "authorization",
+    "x-api-key",
+    "x-anthropic-api-token",
+    "x-provider-profile",
+];
+
+/// Response headers that are not passed back, since the body is re-framed.
+const DROPPED_RESPONSE_HEADERS: [&str; 3] = ["connection", "content-length", "transfer-encoding"];
+
+/// `anthropic-version` sent when the caller does not set one.
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Upstream API a passthrough route forwards to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Upstream {
+    DeepSeek,
+    Anthropic,
+}
+
+impl Upstream {
+    fn name(self) -> &'static str {
+        match self {
+            Upstream::DeepSeek => "deepseek",
+            Upstream::Anthropic => "anthropic",
+        }
+    }
+}
+
+/// The passthrough routes' settings and HTTP client.
+pub struct Proxy {
+    config: ProxyConfig,
+    client: Client,
+}
+
+impl Proxy {
+    pub fn new(config: &ProxyConfig) -> Self {
+        Self {
+            config: config.clone(),
+            client: Client::new(),
+        }
+    }
+
+    fn base_url(&self, upstream: Upstream) -> Option<&str> {
+        let (enabled, url) = match upstream {
+            Upstream::DeepSeek => (self.config.deepseek, &self.config.deepseek_url),
+            Upstream::Anthropic => (self.config.anthropic, &self.config.anthropic_url),
+        };
+        enabled.then(|| url.trim_end_matches('/'))
+    }
+}
+
+/// Forwards a call under `/deepseek/v1/`.
+///
+/// # Errors
+///
+/// See [`forward`].
+pub async fn deepseek(
+    state: State<Arc<AppState>>,
+    path: Path<String>,
+    query: RawQuery,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    forward(Upstream::DeepSeek, state, path, query, method, headers, body).await
+}
+
+/// Forwards a call under `/anthropic/v1/`.
+///
+/// # Errors
+///
+/// See [`forward`].
+pub async fn anthropic(
+    state: State<Arc<AppState>>,
+    path: Path<String>,
+    query: RawQuery,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    forward(Upstream::Anthropic, state, path, query, method, headers, body).await
+}
+
+/// Forwards a call to an upstream and meters the response.
+///
+/// # Errors
+///
+/// Returns `ApiError::NotFound` if the route is not enabled,
+/// `ApiError::Unauthorized` or `ApiError::Forbidden` if the caller's key
+/// may not make the call, `ApiError::BudgetExceeded` if its tenant's budget
+/// is spent, `ApiError::MissingHeader` if no upstream key is available, and
+/// the stage's upstream error if the upstream cannot be reached.
+async fn forward(
+    upstream: Upstream,
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    RawQuery(query): RawQuery,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    let Some(base_url) = state.proxy.base_url(upstream) else {
+        return Err(ApiError::NotFound {
+            message: format!("The /{}/v1 passthrough route is disabled; enable it under [proxy]", upstream.name()),
+        });
+    };
+    let key = state.virtual_key(&headers)?;
+    let key = key.as_deref();
+    let model = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|body| body.get("model").and_then(|model| model.as_str()).map(String::from));
+    if let Some(key) = key {
+        key.authorize_passthrough(model.as_deref())?;
+    }
+    let scope = Scope::resolve(&headers, key)?;
+    tenants::check_budget(&state.config.tenants, &scope, &state.usage).await?;
+    let upstream_key = upstream_key(&state, &headers, upstream)?;
+
+    let mut url = format!("{}/v1/{}", base_url, path);
+    if let Some(query) = query {
+        url = format!("{}?{}", url, query);
+    }
+    let mut forwarded = headers.clone();
+    for name in DROPPED_REQUEST_HEADERS {
+        forwarded.remove(name);
+    }
+    match upstream {
+        Upstream::DeepSeek => {
+            forwarded.insert(header::AUTHORIZATION, header_value(&format!("Bearer {}", upstream_key))?);
+        }
+        Upstream::Anthropic => {
+            forwarded.insert(HeaderName::from_static("x-api-key"), header_value(&upstream_key)?);
+            if !forwarded.contains_key("anthropic-version") {
+                forwarded.insert(HeaderName::from_static("anthropic-version"), header_value(ANTHROPIC_VERSION)?);
+            }
+        }
+    }
+
+    tracing::info!("透传{} {}请求到{}", method, upstream.name(), url);
+    let response = state
+        .proxy
+        .client
+        .request(method, &url)
+        .headers(forwarded)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| upstream_error(upstream, format!("Request failed: {}", e)))?;
+
+    let status = response.status();
+    let mut response_headers = response.headers().clone();
+    for name in DROPPED_RESPONSE_HEADERS {
+        response_headers.remove(name);
+    }
+    let event_stream = response_headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+
+    // 只为成功的响应记录用量，在响应体结束或客户端断开时写入
+    let mut meter = status.is_success().then(|| Meter {
+        state: state.clone(),
+        upstream,
+        record: UsageRecord {
+            id: uuid::Uuid::new_v4().to_string(),
+            key_name: key.map(|key| key.name.clone()),
+            tenant: scope.tenant,
+            project: scope.project,
+            mode: "passthrough".to_string(),
+            variant: "stable".to_string(),
+            stream: event_stream,
+            request_id: crate::request_id(&headers).map(String::from),
+            attempts: 1,
+            ..Default::default()
+        },
+        model: model.unwrap_or_default(),
+        event_stream,
+        pending: Vec::new(),
+        usage: Tokens::default(),
+    });
+    let mut chunks = response.bytes_stream();
+    let body = async_stream::stream! {
+        while let Some(chunk) = chunks.next().await {
+            if let (Some(meter), Ok(chunk)) = (meter.as_mut(), &chunk) {
+                meter.feed(chunk);
+            }
+            yield chunk;
+        }
+    };
+
+    let mut proxied = Response::new(Body::from_stream(body));
+    *proxied.status_mut() = status;
+    *proxied.headers_mut() = response_headers;
+    Ok(proxied)
+}
+
+/// Picks the upstream key of a passthrough call.
+///
+/// # Errors
+///
+/// Returns `ApiError::Unauthorized` without a virtual key when one is
+/// required, and `ApiError::MissingHeader` if neither the key, the caller
+/// nor the server provides an upstream key.
+fn upstream_key(state: &AppState, headers: &HeaderMap, upstream: Upstream) -> Result<String> {
+    let key = state.virtual_key(headers)?;
+    let key = key.as_deref();
+    let header = |name: &str| headers.get(name).and_then(|h| h.to_str().ok()).map(String::from);
+    let settings = providers::current();
+    let server_key = |key: &Option<String>| key.as_deref().and_then(secrets::expose);
+    let (pinned, caller, server) = match upstream {
+        Upstream::DeepSeek => (
+            key.and_then(|key| key.deepseek_api_key.clone()),
+            header("authorization").and_then(|h| h.strip_prefix("Bearer ").map(String::from)),
+            server_key(&settings.deepseek_api_key),
+        ),
+        Upstream::Anthropic => (
+            key.and_then(|key| key.anthropic_api_key.clone()),
+            header("x-api-key").or_else(|| header("x-anthropic-api-token")),
+            server_key(&settings.anthropic_api_key),
+        ),
+    };
+    // 带虚拟密钥时调用方的令牌就是虚拟密钥本身，不发往上游
+    let caller = caller.filter(|_| key.is_none());
+    pinned.or(caller).or(server).ok_or_else(|| ApiError::MissingHeader {
+        header: match upstream {
+            Upstream::DeepSeek => "Authorization".to_string(),
+            Upstream::Anthropic => "x-api-key".to_string(),
+        },
+    })
+}
+
+fn header_value(value: &str) -> Result<header::HeaderValue> {
+    header::HeaderValue::from_str(value).map_err(|e| ApiError::BadRequest {
+        message: format!("Invalid header value: {}", e),
+    })
+}
+
+fn upstream_error(upstream: Upstream, message: String) -> ApiError {
+    let type_ = "request_failed".to_string();
+    match upstream {
+        Upstream::DeepSeek => ApiError::DeepSeekError { message, type_, param: None, code: None },
+        Upstream::Anthropic => ApiError::AnthropicError { message, type_, param: None, code: None },
+    }
+}
+
+/// Token counts read from a response's `usage` objects.
+#[derive(Debug, Default)]
+struct Tokens {
+    input: u32,
+    output: u32,
+    cache_read: u32,
+    cache_write: u32,
+}
+
+impl Tokens {
+    /// Takes the counts of one `usage` object, in either API's field names.
+    ///
+    /// Stream events repeat or accumulate counts (Anthropic reports input
+    /// tokens in `message_start` and the running output in
+    /// `message_delta`), so the largest value seen is kept.
+    fn observe(&mut self, usage: &Value) {
+        let count = |fields: &[&str]| {
+            fields
+                .iter()
+                .filter_map(|field| usage.pointer(field).and_then(|v| v.as_u64()))
+                .max()
+                .unwrap_or(0) as u32
+        };
+        self.input = self.input.max(count(&["/prompt_tokens", "/input_tokens"]));
+        self.output = self.output.max(count(&["/completion_tokens", "/output_tokens"]));
+        self.cache_read = self.cache_read.max(count(&[
+            "/prompt_tokens_details/cached_tokens",
+            "/prompt_cache_hit_tokens",
+            "/cache_read_input_tokens",
+        ]));
+        self.cache_write = self.cache_write.max(count(&["/cache_creation_input_tokens"]));
+    }
+}
+
+/// Reads the usage of a response as it is passed back, and records it when
+/// dropped.
+struct Meter {
+    state: Arc<AppState>,
+    upstream: Upstream,
+    record: UsageRecord,
+    model: String,
+    event_stream: bool,
+    /// Bytes of an unfinished line, or the whole body of a JSON response.
+    pending: Vec<u8>,
+    usage: Tokens,
+}
+
+impl Meter {
+    fn feed(&mut self, chunk: &[u8]) {
+        self.pending.extend_from_slice(chunk);
+        if !self.event_stream {
+            return;
+        }
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            self.observe_line(&line);
+        }
+    }
+
+    fn observe_line(&mut self, line: &[u8]) {
+        let Some(data) = std::str::from_utf8(line).ok().and_then(|line| line.trim().strip_prefix("data:")) else {
+            return;
+        };
+        let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+            return;
+        };
+        self.observe_event(&event);
+    }
+
+    fn observe_event(&mut self, event: &Value) {
+        for usage in [event.get("usage"), event.pointer("/message/usage")].into_iter().flatten() {
+            self.usage.observe(usage);
+        }
+        if self.model.is_empty() {
+            let model = event.get("model").or_else(|| event.pointer("/message/model"));
+            if let Some(model) = model.and_then(|v| v.as_str()) {
+                self.model = model.to_string();
+            }
+        }
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        if self.event_stream {
+            self.observe_line(&pending);
+        } else if let Ok(body) = serde_json::from_slice::<Value>(&pending) {
+            self.observe_event(&body);
+        }
+
+        let config = &self.state.config;
+        let usage = &self.usage;
+        let record = &mut self.record;
+        record.timestamp = Utc::now();
+        match self.upstream {
+            Upstream::DeepSeek => {
+                record.deepseek_model = self.model.clone();
+                record.deepseek_input_tokens = usage.input;
+                record.deepseek_output_tokens = usage.output;
+                record.cost_usd = handlers::calculate_deepseek_cost(
+                    usage.input,
+                    usage.output,
+                    0,
+                    usage.cache_read.min(usage.input),
+                    config,
+                );
+            }
+            Upstream::Anthropic => {
+                record.anthropic_model = self.model.clone();
+                record.anthropic_input_tokens = usage.input;
+                record.anthropic_output_tokens = usage.output;
+                record.cost_usd = handlers::calculate_anthropic_cost(
+                    &self.model,
+                    usage.input,
+                    usage.output,
+                    usage.cache_write,
+                    usage.cache_read,
+                    config,
+                );
+            }
+        }
+        tracing::info!(
+            "透传用量 {} model={} input_tokens={} output_tokens={} cost={}",
+            self.upstream.name(),
+            self.model,
+            usage.input,
+            usage.output,
+            handlers::format_cost(record.cost_usd),
+        );
+        self.state.usage.record(record);
+        self.state.spend.observe(record);
+    }
+}
@@ -0,0 +1,251 @@
+//! Retrieval-augmented generation.
+//!
+//! When `[rag] enabled` is set, the latest user message is embedded through
+//! an OpenAI-compatible embeddings endpoint and the `top_k` closest chunks
+//! are fetched from the configured vector store. The chunks are numbered and
+//! prepended to the system prompt, so both stages see them, and are returned
+//! with the response as `sources`.
+//!
+//! Two stores are supported:
+//!
+//! - Qdrant: a collection whose point payloads carry `text` and `source`
+//! - pgvector: a table with `content text`, `source text` and
+//!   `embedding vector` columns, searched by cosine distance; needs a build
+//!   with the `postgres` feature
+//!
+//! Virtual keys can opt out with `retrieval = false`. Retrieval failures are
+//! logged and the request continues without context.
+
+use crate::{
+    config::{RagConfig, VectorStoreConfig},
+    models::{
+        request::{ApiRequest, Role},
+        response::Source,
+    },
+    secrets,
+};
+use reqwest::Client;
+use serde_json::json;
+use std::time::Duration;
+#[cfg(feature = "postgres")]
+use tokio::sync::Mutex;
+
+/// Environment variable holding the embeddings API key.
+const EMBEDDING_KEY_ENV: &str = "EMBEDDING_API_KEY";
+
+/// A chunk of document text to add to a request's context.
+#[derive(Debug)]
+pub struct Chunk {
+    pub text: String,
+    pub source: String,
+    pub score: f32,
+}
+
+/// The retrieval stage and its vector store connection.
+pub struct Retriever {
+    config: RagConfig,
+    client: Client,
+    #[cfg(feature = "postgres")]
+    postgres: Mutex<Option<tokio_postgres::Client>>,
+}
+
+impl Retriever {
+    /// Creates the retrieval stage for the `[rag]` settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pgvector table name is not a plain identifier,
+    /// or if retrieval from pgvector is enabled in a build without the
+    /// `postgres` feature.
+    pub fn new(config: &RagConfig) -> anyhow::Result<Self> {
+        if let VectorStoreConfig::Pgvector { table, .. } = &config.store {
+            if cfg!(not(feature = "postgres")) && config.enabled {
+                anyhow::bail!("当前构建未启用postgres特性，无法使用pgvector");
+            }
+            // 表名直接拼接进SQL，只允许普通标识符
+            if table.is_empty() || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                anyhow::bail!("无效的pgvector表名: {}", table);
+            }
+        }
+        Ok(Self {
+            config: config.clone(),
+            client: Client::new(),
+            #[cfg(feature = "postgres")]
+            postgres: Mutex::new(None),
+        })
+    }
+
+    /// Adds retrieved context to the request, if retrieval is enabled.
+    pub async fn augment(&self, request: &mut ApiRequest) {
+        if !self.config.enabled {
+            return;
+        }
+        let Some(query) = request.messages.iter().rev().find(|m| m.role == Role::User) else {
+            return;
+        };
+
+        let chunks = match self.retrieve(&query.content).await {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                tracing::warn!("检索上下文失败，继续处理请求: {}", e);
+                return;
+            }
+        };
+        tracing::debug!("已检索到{}个上下文片段", chunks.len());
+        add_chunks(request, chunks);
+    }
+
+    /// Returns true if the retrieval stage is enabled.
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    async fn retrieve(&self, query: &str) -> anyhow::Result<Vec<Chunk>> {
+        let vector = self.embed(query).await?;
+        let chunks = match &self.config.store {
+            VectorStoreConfig::Qdrant { url, collection, api_key } => {
+                self.search_qdrant(url, collection, api_key, &vector).await?
+            }
+            #[cfg(feature = "postgres")]
+            VectorStoreConfig::Pgvector { url, table } => self.search_pgvector(url, table, &vector).await?,
+            #[cfg(not(feature = "postgres"))]
+            VectorStoreConfig::Pgvector { .. } => anyhow::bail!("当前构建未启用postgres特性，无法使用pgvector"),
+        };
+        Ok(chunks.into_iter().filter(|chunk| chunk.score >= self.config.min_score).collect())
+    }
+
+    /// Embeds one text with the configured embeddings endpoint.
+    pub async fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        self.embed_batch(&[text.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("嵌入接口响应中没有embedding"))
+    }
+
+    /// Embeds several texts in one call, returning vectors in input order.
+    pub async fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let embedding = &self.config.embedding;
+        let configured = if embedding.api_key.is_empty() {
+            std::env::var(EMBEDDING_KEY_ENV).unwrap_or_default()
+        } else {
+            embedding.api_key.clone()
+        };
+        let api_key = secrets::expose(&configured).unwrap_or_default();
+
+        let mut request = self
+            .client
+            .post(&embedding.url)
+            .timeout(Duration::from_secs(30))
+            .json(&json!({ "model": embedding.model, "input": texts }));
+        if !api_key.is_empty() {
+            request = request.bearer_auth(api_key);
+        }
+        let body: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+
+        let mut data = body["data"].as_array().cloned().unwrap_or_default();
+        data.sort_by_key(|item| item["index"].as_u64().unwrap_or_default());
+        let vectors: Vec<Vec<f32>> = data
+            .iter()
+            .filter_map(|item| item["embedding"].as_array())
+            .map(|values| values.iter().filter_map(|v| v.as_f64()).map(|v| v as f32).collect())
+            .collect();
+        if vectors.len() != texts.len() {
+            anyhow::bail!("嵌入接口返回了{}个向量，应为{}个", vectors.len(), texts.len());
+        }
+        Ok(vectors)
+    }
+
+    async fn search_qdrant(&self, url: &str, collection: &str, api_key: &str, vector: &[f32]) -> anyhow::Result<Vec<Chunk>> {
+        let mut request = self
+            .client
+            .post(format!("{}/collections/{}/points/search", url.trim_end_matches('/'), collection))
+            .timeout(Duration::from_secs(30))
+            .json(&json!({ "vector": vector, "limit": self.config.top_k, "with_payload": true }));
+        if let Some(api_key) = secrets::expose(api_key).filter(|key| !key.is_empty()) {
+            request = request.header("api-key", api_key);
+        }
+        let body: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+
+        Ok(body["result"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|point| {
+                Some(Chunk {
+                    text: point["payload"]["text"].as_str()?.to_string(),
+                    source: point["payload"]["source"].as_str().unwrap_or("unknown").to_string(),
+                    score: point["score"].as_f64().unwrap_or_default() as f32,
+                })
+            })
+            .collect())
+    }
+
+    #[cfg(feature = "postgres")]
+    async fn search_pgvector(&self, url: &str, table: &str, vector: &[f32]) -> anyhow::Result<Vec<Chunk>> {
+        let mut connection = self.postgres.lock().await;
+        if connection.as_ref().is_none_or(|client| client.is_closed()) {
+            let (client, driver) = tokio_postgres::connect(url, tokio_postgres::NoTls).await?;
+            tokio::spawn(async move {
+                if let Err(e) = driver.await {
+                    tracing::warn!("pgvector连接已断开: {}", e);
+                }
+            });
+            *connection = Some(client);
+        }
+        let client = connection.as_ref().expect("连接已建立");
+
+        // pgvector接受'[1,2,3]'形式的文本，先按text传参再转换
+        let literal = format!(
+            "[{}]",
+            vector.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",")
+        );
+        let sql = format!(
+            "SELECT content, source, (1 - (embedding <=> $1::text::vector))::real AS score \
+             FROM {} ORDER BY embedding <=> $1::text::vector LIMIT $2",
+            table
+        );
+        let rows = client.query(&sql, &[&literal, &(self.config.top_k as i64)]).await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| Chunk {
+                text: row.get(0),
+                source: row.get::<_, Option<String>>(1).unwrap_or_else(|| "unknown".to_string()),
+                score: row.get(2),
+            })
+            .collect())
+    }
+}
+
+/// Numbers chunks after any already in the request's context and prepends
+/// them to its system prompt.
+pub fn add_chunks(request: &mut ApiRequest, chunks: Vec<Chunk>) {
+    if chunks.is_empty() {
+        return;
+    }
+
+    let mut context = String::from("Use the following context where it is relevant, and cite it by number, e.g. [1].");
+    let mut sources = Vec::new();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let index = request.sources.len() + i + 1;
+        context.push_str(&format!("\n\n[{}] (source: {})\n{}", index, chunk.source, chunk.text));
+        sources.push(Source {
+            index,
+            source: chunk.source,
+            score: chunk.score,
+        });
+    }
+    request.add_context(&context, sources);
+}
+
+/// Cosine similarity of two vectors, 0 if either is empty or zero.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denominator = norm(a) * norm(b);
+    if denominator == 0.0 {
+        0.0
+    } else {
+        dot / denominator
+    }
+}
@@ -0,0 +1,225 @@
+//! Client-side rate limit budgeting for upstream providers.
+//!
+//! Each upstream provider can be given a requests-per-minute and
+//! tokens-per-minute budget in `config.toml`. Requests are admitted against
+//! a sliding one-minute window; when the budget is exhausted the request is
+//! queued for up to `max_wait_ms` and then rejected locally with
+//! `ApiError::RateLimited`, instead of pushing the provider into long 429 bans.
+
+use crate::{
+    config::ProviderBudget,
+    error::{ApiError, Result},
+    models::request::Message,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Sliding window of admitted requests for one provider.
+#[derive(Debug, Default)]
+struct Window {
+    entries: VecDeque<(Instant, u32)>,
+    tokens: u64,
+}
+
+impl Window {
+    fn prune(&mut self, now: Instant) {
+        while let Some(&(at, tokens)) = self.entries.front() {
+            if now.duration_since(at) < WINDOW {
+                break;
+            }
+            self.entries.pop_front();
+            self.tokens -= tokens as u64;
+        }
+    }
+
+    fn push(&mut self, now: Instant, tokens: u32) {
+        self.entries.push_back((now, tokens));
+        self.tokens += tokens as u64;
+    }
+
+    /// Returns how long until the oldest entry leaves the window.
+    fn next_release(&self, now: Instant) -> Duration {
+        self.entries
+            .front()
+            .map(|&(at, _)| WINDOW.saturating_sub(now.duration_since(at)))
+            .unwrap_or_default()
+    }
+}
+
+/// Per-provider budget tracker shared across all requests.
+#[derive(Debug)]
+pub struct RateLimiter {
+    budgets: HashMap<String, ProviderBudget>,
+    windows: HashMap<String, Mutex<Window>>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter for the budgets configured under `[rate_limits]`.
+    pub fn new(budgets: &HashMap<String, ProviderBudget>) -> Self {
+        let windows = budgets
+            .keys()
+            .map(|name| (name.clone(), Mutex::new(Window::default())))
+            .collect();
+
+        Self {
+            budgets: budgets.clone(),
+            windows,
+        }
+    }
+
+    /// Reserves budget for one request to `provider`.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - The provider name as used in `[rate_limits]`
+    /// * `estimated_tokens` - Estimated token cost of the request
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::RateLimited` if the budget does not free up
+    /// within the provider's `max_wait_ms`.
+    pub async fn acquire(&self, provider: &str, estimated_tokens: u32) -> Result<()> {
+        self.reserve(provider, estimated_tokens).await.map(|_| ())
+    }
+
+    /// Reserves budget for one request to each of `providers`, in order.
+    ///
+    /// If any provider's budget does not free up in time, the reservations
+    /// already made are handed back before the error is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::RateLimited` for the first provider whose budget
+    /// does not free up within its `max_wait_ms`.
+    pub async fn acquire_all(&self, providers: &[&str], estimated_tokens: u32) -> Result<()> {
+        let mut reserved = Vec::with_capacity(providers.len());
+        for provider in providers {
+            match self.reserve(provider, estimated_tokens).await {
+                Ok(at) => reserved.push((*provider, at)),
+                Err(e) => {
+                    // 后一个上游被拒绝时退还前面已占用的预算
+                    for (provider, at) in reserved {
+                        self.refund(provider, at, estimated_tokens);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserves budget for one request to `provider`, returning when the
+    /// reservation was made, or `None` if the provider has no budget.
+    async fn reserve(&self, provider: &str, estimated_tokens: u32) -> Result<Option<Instant>> {
+        let (Some(budget), Some(window)) = (self.budgets.get(provider), self.windows.get(provider)) else {
+            return Ok(None);
+        };
+
+        let deadline = Instant::now() + Duration::from_millis(budget.max_wait_ms);
+
+        loop {
+            let wait = match Self::admit(budget, window, estimated_tokens) {
+                Ok(at) => return Ok(Some(at)),
+                Err(wait) => wait,
+            };
+
+            let now = Instant::now();
+            if now + wait > deadline {
+                tracing::warn!("{}的本地速率预算已耗尽，拒绝请求", provider);
+                return Err(ApiError::RateLimited {
+                    provider: provider.to_string(),
+                    retry_after_secs: wait.as_secs().max(1),
+                });
+            }
+
+            tracing::debug!("{}的本地速率预算不足，排队等待{:?}", provider, wait);
+            tokio::time::sleep(wait.max(Duration::from_millis(10))).await;
+        }
+    }
+
+    /// Reserves budget for one request to `provider` without waiting.
+    ///
+    /// Used for optional background traffic that should never queue
+    /// behind, or push out, user-facing requests.
+    pub fn try_acquire(&self, provider: &str, estimated_tokens: u32) -> bool {
+        match (self.budgets.get(provider), self.windows.get(provider)) {
+            (Some(budget), Some(window)) => Self::admit(budget, window, estimated_tokens).is_ok(),
+            _ => true,
+        }
+    }
+
+    /// Hands back a reservation made at `at`.
+    fn refund(&self, provider: &str, at: Option<Instant>, estimated_tokens: u32) {
+        let (Some(at), Some(window)) = (at, self.windows.get(provider)) else {
+            return;
+        };
+        let mut window = window.lock().unwrap();
+        if let Some(index) = window.entries.iter().position(|&entry| entry == (at, estimated_tokens)) {
+            window.entries.remove(index);
+            window.tokens -= estimated_tokens as u64;
+        }
+    }
+
+    /// Admits a request into the window, returning when, or returns how
+    /// long until space frees up.
+    fn admit(budget: &ProviderBudget, window: &Mutex<Window>, estimated_tokens: u32) -> std::result::Result<Instant, Duration> {
+        let mut window = window.lock().unwrap();
+        let now = Instant::now();
+        window.prune(now);
+
+        let rpm_ok = budget.rpm == 0 || (window.entries.len() as u32) < budget.rpm;
+        // 窗口为空时总是放行，避免单个大请求永远无法通过
+        let tpm_ok = budget.tpm == 0
+            || window.entries.is_empty()
+            || window.tokens + estimated_tokens as u64 <= budget.tpm as u64;
+
+        if rpm_ok && tpm_ok {
+            window.push(now, estimated_tokens);
+            Ok(now)
+        } else {
+            Err(window.next_release(now))
+        }
+    }
+
+    /// Records the difference between the estimated and actual token usage.
+    ///
+    /// An overrun is added to the window. An overestimate, down to a call
+    /// that failed and used nothing, is handed back by lowering the
+    /// reservation, which still counts as a request.
+    pub fn settle(&self, provider: &str, estimated_tokens: u32, actual_tokens: u32) {
+        let Some(window) = self.windows.get(provider) else {
+            return;
+        };
+        let mut window = window.lock().unwrap();
+        if actual_tokens > estimated_tokens {
+            window.push(Instant::now(), actual_tokens - estimated_tokens);
+            return;
+        }
+        // 上游调用失败或用量低于估算时退还多占的预算，已移出窗口的预约无需退还
+        if let Some(entry) = window.entries.iter_mut().rev().find(|(_, tokens)| *tokens == estimated_tokens) {
+            entry.1 = actual_tokens;
+            window.tokens -= (estimated_tokens - actual_tokens) as u64;
+        }
+    }
+}
+
+/// Roughly estimates the token count of a piece of text.
+///
+/// ASCII text averages about four characters per token while CJK
+/// characters are usually a token each.
+pub fn estimate_tokens(text: &str) -> u32 {
+    let (ascii, other) = text.chars().fold((0u32, 0u32), |(a, o), c| {
+        if c.is_ascii() { (a + 1, o) } else { (a, o + 1) }
+    });
+    ascii.div_ceil(4) + other
+}
+
+/// Estimates the prompt token count of a conversation.
+pub fn estimate_messages_tokens(messages: &[Message]) -> u32 {
+    messages.iter().map(|m| estimate_tokens(&m.content) + 4).sum()
+}
@@ -0,0 +1,162 @@
+//! Reuse of DeepSeek reasoning across retries.
+//!
+//! With `[reasoning_cache] enabled`, the output of a successful DeepSeek
+//! stage is kept for `ttl_secs` under a hash of its input: the messages
+//! sent to DeepSeek, its model and body parameters, the mode and the
+//! tenant. A request with the same input, typically a client retrying after
+//! the Claude stage failed or a user resending their last message, takes
+//! the kept reasoning instead of paying for a second reasoning pass; its
+//! DeepSeek stage then reports zero tokens. Unlike the response cache this
+//! applies to streaming requests too, and the Claude stage always runs.
+//! Entries are kept in the `[storage]` key-value cache under
+//! `reasoning:<hash>`.
+
+use crate::{
+    clients::deepseek::{
+        AssistantMessage, Choice, DeepSeekResponse, DeepSeekUsage, StreamChoice, StreamDelta, StreamResponse,
+    },
+    config::ReasoningCacheConfig,
+    models::request::{ApiRequest, Message},
+    storage::KvCache,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// DeepSeek outputs of recent conversations.
+pub struct ReasoningCache {
+    config: ReasoningCacheConfig,
+    /// `[reasoning_cache] enabled`, switchable at runtime.
+    enabled: AtomicBool,
+    store: Arc<dyn KvCache>,
+}
+
+impl ReasoningCache {
+    pub fn new(config: &ReasoningCacheConfig, store: Arc<dyn KvCache>) -> Self {
+        Self {
+            config: config.clone(),
+            enabled: AtomicBool::new(config.enabled),
+            store,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turns reuse on or off for later requests.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The key of a DeepSeek stage's input, `None` if reuse is disabled.
+    pub fn key(&self, request: &ApiRequest, model: &str, mode: &str, messages: &[Message]) -> Option<String> {
+        if !self.enabled() {
+            return None;
+        }
+        let mut body = request.deepseek_config.body.clone();
+        if let Some(body) = body.as_object_mut() {
+            body.remove("user");
+        }
+        let inputs = serde_json::json!({
+            "messages": messages,
+            "model": model,
+            "body": body,
+            "mode": mode,
+            "tenant": request.scope.tenant,
+        });
+        Some(format!("reasoning:{}", hex::encode(Sha256::digest(inputs.to_string().as_bytes()))))
+    }
+
+    /// The kept output for `key`, with its usage zeroed.
+    pub async fn get(&self, key: &str) -> Option<DeepSeekResponse> {
+        let value = match self.store.get(key).await {
+            Ok(value) => value?,
+            Err(e) => {
+                tracing::warn!("读取推理缓存失败: {}", e);
+                return None;
+            }
+        };
+        let mut response: DeepSeekResponse = serde_json::from_str(&value)
+            .inspect_err(|e| tracing::warn!("跳过无法解析的推理缓存: {}", e))
+            .ok()?;
+        // 复用的推理不再产生DeepSeek费用
+        response.usage = DeepSeekUsage::default();
+        Some(response)
+    }
+
+    /// Keeps a DeepSeek stage's output for later retries.
+    pub async fn put(&self, key: &str, response: &DeepSeekResponse) {
+        let Some(message) = response.choices.first().map(|choice| &choice.message) else {
+            return;
+        };
+        let empty = |text: &Option<String>| text.as_deref().is_none_or(|text| text.trim().is_empty());
+        if empty(&message.reasoning_content) && empty(&message.content) {
+            return;
+        }
+        let value = match serde_json::to_string(response) {
+            Ok(value) => value,
+            Err(e) => {
+                tracing::warn!("序列化推理缓存失败: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.store.put(key, value, Duration::from_secs(self.config.ttl_secs)).await {
+            tracing::warn!("写入推理缓存失败: {}", e);
+        }
+    }
+}
+
+/// The output of a streamed DeepSeek stage, to keep it like a non-streamed one.
+pub fn collected(model: &str, reasoning_content: &str, content: &str) -> DeepSeekResponse {
+    DeepSeekResponse {
+        id: uuid::Uuid::new_v4().to_string(),
+        object: "chat.completion".to_string(),
+        created: chrono::Utc::now().timestamp(),
+        model: model.to_string(),
+        choices: vec![Choice {
+            index: 0,
+            message: AssistantMessage {
+                role: "assistant".to_string(),
+                content: Some(content.to_string()),
+                reasoning_content: (!reasoning_content.is_empty()).then(|| reasoning_content.to_string()),
+            },
+            logprobs: None,
+            finish_reason: Some("stop".to_string()),
+        }],
+        usage: DeepSeekUsage::default(),
+        system_fingerprint: None,
+    }
+}
+
+/// A kept output replayed as the single chunk of a DeepSeek stream.
+pub fn replay(response: DeepSeekResponse) -> StreamResponse {
+    StreamResponse {
+        id: response.id,
+        object: "chat.completion.chunk".to_string(),
+        created: response.created,
+        model: response.model,
+        choices: response
+            .choices
+            .into_iter()
+            .map(|choice| StreamChoice {
+                index: choice.index,
+                delta: StreamDelta {
+                    role: Some(choice.message.role),
+                    content: choice.message.content,
+                    reasoning_content: choice.message.reasoning_content,
+                },
+                logprobs: None,
+                finish_reason: choice.finish_reason,
+            })
+            .collect(),
+        usage: Some(response.usage),
+        service_tier: String::new(),
+        system_fingerprint: response.system_fingerprint.unwrap_or_default(),
+    }
+}
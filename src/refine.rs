@@ -0,0 +1,109 @@
+//! Self-critique refinement.
+//!
+//! A non-streaming request with `refine: true` gets one extra pass over
+//! Claude's draft: Claude first critiques the draft against the DeepSeek
+//! reasoning and the `[refine] rubric`, then rewrites it once following the
+//! critique. A critique without findings ends the pass without a rewrite.
+//!
+//! The pass is best effort: if either call fails the draft is returned. Its
+//! tokens and cost are reported apart from the responder's, as
+//! `usage.refine` in the response and `refine_*` in the usage record.
+
+use crate::{
+    clients::{
+        anthropic::{AnthropicResponse, ContentBlock, Usage},
+        hosts::HostRoute,
+        AnthropicClient,
+    },
+    handlers::{self, AppState},
+    models::request::{ApiConfig, ApiRequest, Message},
+};
+
+/// What the critique checks when `[refine] rubric` is empty.
+const RUBRIC: &str = "- Is every claim correct, and consistent with the reasoning?\n\
+- Does the answer reach the conclusion the reasoning supports, or does it drop or contradict a step?\n\
+- Does it answer everything that was asked, without padding?";
+
+/// The critique's reply when the draft needs no changes.
+const NO_CHANGES: &str = "NO CHANGES";
+
+/// Result of a refinement pass.
+#[derive(Debug, Default)]
+pub struct Refinement {
+    /// The rewritten answer, or `None` to keep the draft.
+    pub content: Option<Vec<ContentBlock>>,
+    /// Tokens of the critique and the rewrite.
+    pub usage: Usage,
+    pub cost: f64,
+}
+
+/// Whether a request is refined: its own `refine`, or else `[refine] enabled`.
+pub fn requested(state: &AppState, request: &ApiRequest) -> bool {
+    request.refine.unwrap_or(state.config.refine.enabled)
+}
+
+/// Critiques the draft and rewrites it once.
+///
+/// `messages` and `system` are the input that produced `draft`. The draft
+/// is kept if the critique found nothing to change or a call failed; the
+/// usage of the calls made is reported either way.
+pub async fn refine(
+    state: &AppState,
+    client: &AnthropicClient,
+    route: Option<&HostRoute>,
+    (messages, system): (Vec<Message>, Option<String>),
+    config: &ApiConfig,
+    reasoning: &str,
+    draft: &str,
+) -> Refinement {
+    let rubric = match state.config.refine.rubric.trim() {
+        "" => RUBRIC,
+        rubric => rubric,
+    };
+    let critique_request = format!(
+        "Review your answer above before it is sent. Check it against this reasoning:\n\n\
+<reasoning>\n{}\n</reasoning>\n\nand against these criteria:\n\n{}\n\n\
+List each problem you find and how to fix it. If there is nothing to fix, reply with only {}.",
+        reasoning, rubric, NO_CHANGES
+    );
+
+    let mut refinement = Refinement::default();
+    let critique = match client
+        .revise(messages.clone(), system.clone(), config, draft, &critique_request)
+        .await
+    {
+        Ok(response) => {
+            refinement.add(state, route, &response);
+            response.text()
+        }
+        Err(e) => {
+            tracing::warn!("请求Claude自我批评失败，返回原回答: {}", e);
+            return refinement;
+        }
+    };
+    if critique.trim().trim_end_matches('.').eq_ignore_ascii_case(NO_CHANGES) {
+        tracing::debug!("自我批评没有发现问题，保留原回答");
+        return refinement;
+    }
+
+    let rewrite_request = format!(
+        "A review of your answer found these problems:\n\n{}\n\n\
+Rewrite your answer to fix them. Reply with only the revised answer.",
+        critique.trim()
+    );
+    match client.revise(messages, system, config, draft, &rewrite_request).await {
+        Ok(response) => {
+            refinement.add(state, route, &response);
+            refinement.content = Some(response.content);
+        }
+        Err(e) => tracing::warn!("请求Claude按批评修改失败，返回原回答: {}", e),
+    }
+    refinement
+}
+
+impl Refinement {
+    fn add(&mut self, state: &AppState, route: Option<&HostRoute>, response: &AnthropicResponse) {
+        self.usage.add(&response.usage);
+        self.cost += handlers::responder_cost(state, route, response);
+    }
+}
@@ -0,0 +1,242 @@
+//! Interactive chat client.
+//!
+//! `deepclaude chat` opens a REPL against a running server. Each line is
+//! sent as a streaming chat request carrying the conversation so far; the
+//! reasoning is printed dim and the answer in normal text as they arrive.
+//! Lines starting with `/` are commands:
+//!
+//! - `/mode [normal|full]` - show or set the pipeline mode
+//! - `/model [name|default]` - show or set the Claude stage model
+//! - `/save [path]` - write the conversation to a Markdown file
+//! - `/clear` - start a new conversation
+//! - `/help`, `/quit`
+
+use crate::clients::parse::{sse_line, LineBuffer, SseLine};
+use futures::StreamExt;
+use serde_json::json;
+use std::{io::Write, path::PathBuf};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+const HELP: &str = "/mode [normal|full]     显示或切换模式
+/model [名称|default]   显示或切换Claude阶段的模型
+/save [路径]            将对话保存为Markdown文件
+/clear                  开始新对话
+/quit                   退出";
+
+/// Settings of a chat session.
+#[derive(Debug, Clone)]
+pub struct ChatOptions {
+    /// Base URL of the server.
+    pub url: String,
+    /// Virtual key or upstream tokens sent as the bearer token.
+    pub key: Option<String>,
+    /// Claude key sent as `X-Anthropic-API-Token`, when the server has none.
+    pub anthropic_key: Option<String>,
+    /// Initial pipeline mode; the server's default when unset.
+    pub mode: Option<String>,
+    /// Initial Claude stage model; the server's default when unset.
+    pub model: Option<String>,
+    /// Dims the reasoning with ANSI escapes; otherwise a divider separates it.
+    pub color: bool,
+}
+
+/// One exchange of the conversation.
+#[derive(Debug)]
+struct Turn {
+    prompt: String,
+    reasoning: String,
+    answer: String,
+}
+
+/// Runs the REPL until `/quit` or the end of `input`.
+///
+/// # Errors
+///
+/// Returns an error if reading `input` or writing `output` fails. Failed
+/// requests are reported to `output` and leave the conversation unchanged.
+pub async fn run<R, W>(options: ChatOptions, input: R, output: &mut W) -> anyhow::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: Write,
+{
+    let client = reqwest::Client::new();
+    let mut mode = options.mode.clone();
+    let mut model = options.model.clone();
+    let mut turns: Vec<Turn> = Vec::new();
+    let mut lines = input.lines();
+
+    writeln!(output, "已连接{}，输入/help查看命令", options.url)?;
+    loop {
+        write!(output, "> ")?;
+        output.flush()?;
+        let Some(line) = lines.next_line().await? else {
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (command, argument) = line.split_once(' ').map_or((line, ""), |(c, a)| (c, a.trim()));
+        match command {
+            "/quit" | "/exit" => break,
+            "/help" => writeln!(output, "{}", HELP)?,
+            "/clear" => {
+                turns.clear();
+                writeln!(output, "已开始新对话")?;
+            }
+            "/mode" => match argument {
+                "" => writeln!(output, "当前模式: {}", mode.as_deref().unwrap_or("服务端默认"))?,
+                "normal" | "full" => {
+                    mode = Some(argument.to_string());
+                    writeln!(output, "模式已切换为{}", argument)?;
+                }
+                _ => writeln!(output, "未知模式: {}，可选normal或full", argument)?,
+            },
+            "/model" => match argument {
+                "" => writeln!(output, "当前模型: {}", model.as_deref().unwrap_or("服务端默认"))?,
+                "default" => {
+                    model = None;
+                    writeln!(output, "已恢复服务端默认模型")?;
+                }
+                _ => {
+                    model = Some(argument.to_string());
+                    writeln!(output, "模型已切换为{}", argument)?;
+                }
+            },
+            "/save" => {
+                let path = match argument {
+                    "" => PathBuf::from(format!("deepclaude-chat-{}.md", chrono::Local::now().format("%Y%m%d-%H%M%S"))),
+                    path => PathBuf::from(path),
+                };
+                match std::fs::write(&path, transcript(&turns)) {
+                    Ok(()) => writeln!(output, "已保存{}轮对话到{}", turns.len(), path.display())?,
+                    Err(e) => writeln!(output, "保存失败: {}", e)?,
+                }
+            }
+            command if command.starts_with('/') => writeln!(output, "未知命令: {}，输入/help查看命令", command)?,
+            _ => {
+                let mut messages = Vec::new();
+                for turn in &turns {
+                    messages.push(json!({ "role": "user", "content": turn.prompt }));
+                    messages.push(json!({ "role": "assistant", "content": turn.answer }));
+                }
+                messages.push(json!({ "role": "user", "content": line }));
+                let mut body = json!({ "stream": true, "messages": messages });
+                if let Some(mode) = &mode {
+                    body["mode"] = json!(mode);
+                }
+                if let Some(model) = &model {
+                    body["anthropic_config"] = json!({ "body": { "model": model } });
+                }
+
+                let mut turn = Turn {
+                    prompt: line.to_string(),
+                    reasoning: String::new(),
+                    answer: String::new(),
+                };
+                match send(&client, &options, &body, &mut turn, output).await {
+                    Ok(()) => turns.push(turn),
+                    Err(e) => writeln!(output, "请求失败: {}", e)?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Streams one request, printing its deltas as they arrive.
+async fn send<W: Write>(
+    client: &reqwest::Client,
+    options: &ChatOptions,
+    body: &serde_json::Value,
+    turn: &mut Turn,
+    output: &mut W,
+) -> anyhow::Result<()> {
+    let url = format!("{}/v1/chat/completions", options.url.trim_end_matches('/'));
+    let mut request = client.post(url).json(body);
+    if let Some(key) = &options.key {
+        request = request.bearer_auth(key);
+    }
+    if let Some(key) = &options.anthropic_key {
+        request = request.header("X-Anthropic-API-Token", key);
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        anyhow::bail!("{}: {}", response.status(), response.text().await.unwrap_or_default());
+    }
+
+    let mut lines = LineBuffer::default();
+    let mut stream = response.bytes_stream();
+    let mut eof = false;
+    let result = async {
+        while !eof {
+            let batch = match stream.next().await {
+                Some(chunk) => lines.push(&chunk?),
+                None => {
+                    eof = true;
+                    lines.finish()
+                }
+            };
+            for line in batch {
+                let SseLine::Data(data) = sse_line(&line) else {
+                    continue;
+                };
+                let Ok(value) = serde_json::from_str::<serde_json::Value>(data) else {
+                    continue;
+                };
+                if let Some(message) = value["error"]["message"].as_str() {
+                    anyhow::bail!("{}", message);
+                }
+                let delta = &value["choices"][0]["delta"];
+                if let Some(reasoning) = delta["reasoning_content"].as_str().filter(|r| !r.is_empty()) {
+                    if turn.reasoning.is_empty() && options.color {
+                        write!(output, "{}", DIM)?;
+                    }
+                    turn.reasoning.push_str(reasoning);
+                    write!(output, "{}", reasoning)?;
+                }
+                if let Some(answer) = delta["content"].as_str().filter(|a| !a.is_empty()) {
+                    if turn.answer.is_empty() && !turn.reasoning.is_empty() {
+                        if options.color {
+                            write!(output, "{}\n\n", RESET)?;
+                        } else {
+                            write!(output, "\n\n---\n\n")?;
+                        }
+                    }
+                    turn.answer.push_str(answer);
+                    write!(output, "{}", answer)?;
+                }
+                output.flush()?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    // 出错时也要恢复终端颜色
+    if options.color && turn.answer.is_empty() && !turn.reasoning.is_empty() {
+        write!(output, "{}", RESET)?;
+    }
+    writeln!(output)?;
+    result
+}
+
+/// Renders the conversation as Markdown, with the reasoning folded.
+fn transcript(turns: &[Turn]) -> String {
+    let mut markdown = String::new();
+    for turn in turns {
+        markdown.push_str(&format!("## User\n\n{}\n\n## Assistant\n\n", turn.prompt));
+        if !turn.reasoning.is_empty() {
+            markdown.push_str(&format!(
+                "<details>\n<summary>Reasoning</summary>\n\n{}\n\n</details>\n\n",
+                turn.reasoning.trim()
+            ));
+        }
+        markdown.push_str(&format!("{}\n\n", turn.answer.trim()));
+    }
+    markdown
+}
@@ -0,0 +1,221 @@
+//! Scheduled daily cost summary.
+//!
+//! On the `[reports] schedule` the previous day's usage records are summed
+//! and posted to `webhook_url`: totals, the keys and models that cost the
+//! most, and the keys and models whose cost was more than `anomaly_factor`
+//! times their daily average over the seven days before. The body carries a
+//! `text` field so a Slack incoming webhook can take it as is, and the
+//! figures under `summary` for other receivers. Days run from midnight at
+//! `utc_offset_hours`, the same offset the schedule is evaluated at.
+
+use crate::{
+    config::ReportsConfig,
+    handlers::AppState,
+    schedule::{self, Schedule},
+    secrets, signing,
+    usage::{UsageRecord, UsageTotals},
+};
+use chrono::{Duration, FixedOffset, NaiveDate, Utc};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc};
+
+/// Days the anomaly baseline is averaged over.
+const BASELINE_DAYS: i64 = 7;
+
+/// The cost summary of one day.
+#[derive(Debug, Serialize)]
+pub struct DailySummary {
+    pub date: NaiveDate,
+    pub total: UsageTotals,
+    pub top_keys: Vec<Entry>,
+    pub top_models: Vec<Entry>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+/// The usage of one key or model.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub name: String,
+    pub totals: UsageTotals,
+}
+
+/// A key or model that cost well above its recent average.
+#[derive(Debug, Serialize)]
+pub struct Anomaly {
+    /// `key` or `model`.
+    pub kind: &'static str,
+    pub name: String,
+    pub cost_usd: f64,
+    pub average_cost_usd: f64,
+}
+
+/// Starts the daily summary job if it is enabled.
+///
+/// # Errors
+///
+/// Returns an error if the schedule or offset is invalid.
+pub fn spawn(state: Arc<AppState>) -> anyhow::Result<()> {
+    let config = state.config.reports.clone();
+    if !config.enabled {
+        return Ok(());
+    }
+    if config.webhook_url.is_empty() {
+        anyhow::bail!("启用了每日费用汇总但没有配置webhook_url");
+    }
+    let schedule = Schedule::parse(&config.schedule)?;
+    let offset = offset(&config)?;
+    tracing::info!("每日费用汇总已启用，计划: {}", config.schedule);
+    schedule::spawn("daily_summary", schedule, offset, move || {
+        let state = state.clone();
+        let config = config.clone();
+        async move {
+            let yesterday = Utc::now().with_timezone(&offset).date_naive() - Duration::days(1);
+            match summarize(&state, &config, offset, yesterday).await {
+                Ok(summary) => post(&config, &summary).await,
+                Err(e) => tracing::error!("生成每日费用汇总失败: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn offset(config: &ReportsConfig) -> anyhow::Result<FixedOffset> {
+    FixedOffset::east_opt(config.utc_offset_hours * 3600)
+        .ok_or_else(|| anyhow::anyhow!("无效的utc_offset_hours: {}", config.utc_offset_hours))
+}
+
+/// Summarizes the usage of `date` against the days before it.
+async fn summarize(state: &AppState, config: &ReportsConfig, offset: FixedOffset, date: NaiveDate) -> anyhow::Result<DailySummary> {
+    let first = date - Duration::days(BASELINE_DAYS);
+    // 用量文件按UTC日期划分，多读一天以覆盖时区偏移
+    let records = state.usage.read(Some(first - Duration::days(1)), Some(date + Duration::days(1))).await?;
+    let day_of = |record: &UsageRecord| record.timestamp.with_timezone(&offset).date_naive();
+
+    let mut total = UsageTotals::default();
+    let mut keys: HashMap<String, UsageTotals> = HashMap::new();
+    let mut models: HashMap<String, UsageTotals> = HashMap::new();
+    let mut key_baseline: HashMap<String, f64> = HashMap::new();
+    let mut model_baseline: HashMap<String, f64> = HashMap::new();
+    for record in &records {
+        let day = day_of(record);
+        let key = record.key_name.clone().unwrap_or_default();
+        let model = format!("{}+{}", record.deepseek_model, record.anthropic_model);
+        if day == date {
+            total.add(record);
+            keys.entry(key).or_default().add(record);
+            models.entry(model).or_default().add(record);
+        } else if day >= first && day < date {
+            *key_baseline.entry(key).or_default() += record.cost_usd;
+            *model_baseline.entry(model).or_default() += record.cost_usd;
+        }
+    }
+
+    let mut anomalies = find_anomalies("key", &keys, &key_baseline, config.anomaly_factor);
+    anomalies.extend(find_anomalies("model", &models, &model_baseline, config.anomaly_factor));
+    Ok(DailySummary {
+        date,
+        total,
+        top_keys: top(keys, config.top),
+        top_models: top(models, config.top),
+        anomalies,
+    })
+}
+
+/// Returns the entries whose cost exceeds `factor` times their baseline
+/// daily average. Entries without earlier spend have no baseline and are
+/// not reported.
+fn find_anomalies(kind: &'static str, day: &HashMap<String, UsageTotals>, baseline: &HashMap<String, f64>, factor: f64) -> Vec<Anomaly> {
+    let mut anomalies: Vec<Anomaly> = day
+        .iter()
+        .filter_map(|(name, totals)| {
+            let average = baseline.get(name).copied().unwrap_or_default() / BASELINE_DAYS as f64;
+            (average > 0.0 && totals.cost_usd > average * factor).then(|| Anomaly {
+                kind,
+                name: name.clone(),
+                cost_usd: totals.cost_usd,
+                average_cost_usd: average,
+            })
+        })
+        .collect();
+    anomalies.sort_by(|a, b| (b.cost_usd / b.average_cost_usd).total_cmp(&(a.cost_usd / a.average_cost_usd)));
+    anomalies
+}
+
+/// Returns the `n` entries that cost the most.
+fn top(entries: HashMap<String, UsageTotals>, n: usize) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = entries.into_iter().map(|(name, totals)| Entry { name, totals }).collect();
+    entries.sort_by(|a, b| b.totals.cost_usd.total_cmp(&a.totals.cost_usd).then_with(|| a.name.cmp(&b.name)));
+    entries.truncate(n);
+    entries
+}
+
+/// Renders the summary as a Slack message.
+fn text(summary: &DailySummary) -> String {
+    let name = |name: &str| if name.is_empty() { "(none)".to_string() } else { name.to_string() };
+    let mut text = format!(
+        "*DeepClaude usage for {}*\n{} requests, {} input / {} output tokens, ${:.4}\n",
+        summary.date, summary.total.requests, summary.total.input_tokens, summary.total.output_tokens, summary.total.cost_usd
+    );
+    if !summary.top_keys.is_empty() {
+        text.push_str("\n*Top keys*\n");
+        for entry in &summary.top_keys {
+            text.push_str(&format!("• {}: ${:.4} ({} requests)\n", name(&entry.name), entry.totals.cost_usd, entry.totals.requests));
+        }
+    }
+    if !summary.top_models.is_empty() {
+        text.push_str("\n*Top models*\n");
+        for entry in &summary.top_models {
+            text.push_str(&format!("• {}: ${:.4} ({} requests)\n", entry.name, entry.totals.cost_usd, entry.totals.requests));
+        }
+    }
+    if !summary.anomalies.is_empty() {
+        text.push_str(&format!("\n*Anomalies vs {}-day average*\n", BASELINE_DAYS));
+        for anomaly in &summary.anomalies {
+            text.push_str(&format!(
+                "• {} {}: ${:.4} vs ${:.4}/day ({:.1}x)\n",
+                anomaly.kind,
+                name(&anomaly.name),
+                anomaly.cost_usd,
+                anomaly.average_cost_usd,
+                anomaly.cost_usd / anomaly.average_cost_usd
+            ));
+        }
+    }
+    text
+}
+
+/// Posts the summary to the webhook, logging failures.
+async fn post(config: &ReportsConfig, summary: &DailySummary) {
+    let Some(url) = secrets::expose(&config.webhook_url) else {
+        return;
+    };
+    let body = serde_json::json!({ "text": text(summary), "summary": summary });
+    match post_webhook(&url, &body).await {
+        Ok(()) => tracing::info!("已发送{}的费用汇总", summary.date),
+        Err(e) => tracing::error!("发送{}的费用汇总失败: {}", summary.date, e),
+    }
+}
+
+/// Posts a JSON body to a webhook, signed if `[signing] webhook_secret` is
+/// set.
+///
+/// # Errors
+///
+/// Returns an error if the request fails or the webhook answers with an
+/// error status.
+pub async fn post_webhook(url: &str, body: &serde_json::Value) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(body)?;
+    let mut request = reqwest::Client::new()
+        .post(url)
+        .timeout(std::time::Duration::from_secs(30))
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    for (name, value) in signing::webhook_headers(&body).into_iter().flatten() {
+        request = request.header(name, value);
+    }
+    request
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Structured request logging.
+//!
+//! With `[request_log]` enabled every finished chat request is logged as one
+//! JSON line under the `deepclaude::request_log` target: its id, key, user,
+//! tenant, models, token counts, cost and whether it was streamed. A
+//! `sample_percent` share of the requests, chosen by request id, is logged
+//! in full with the request body and the answer. Fields named in
+//! `redact_fields` are replaced wherever they occur in the logged body, and
+//! with `hash_users` the user and key names are replaced by a salted hash
+//! that still groups the lines of one user without naming them.
+
+use crate::{config::RequestLogConfig, models::request::ApiRequest, usage::UsageRecord};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// Value redacted fields are replaced by.
+const REDACTED: &str = "[REDACTED]";
+
+/// Writes request log lines according to the logging policy.
+pub struct RequestLog {
+    config: RequestLogConfig,
+}
+
+impl RequestLog {
+    pub fn new(config: &RequestLogConfig) -> Self {
+        Self { config: config.clone() }
+    }
+
+    /// Logs a finished request, in full if it is sampled.
+    pub fn log(&self, record: &UsageRecord, request: &ApiRequest, answer: &str) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut line = serde_json::json!({
+            "id": record.id,
+            "timestamp": record.timestamp,
+            "key": record.key_name.as_deref().map(|key| self.identifier(key)),
+            "user": record.user.as_deref().map(|user| self.identifier(user)),
+            "tenant": record.tenant,
+            "project": record.project,
+            "mode": record.mode,
+            "variant": record.variant,
+            "stream": record.stream,
+            "deepseek_model": record.deepseek_model,
+            "anthropic_model": record.anthropic_model,
+            "input_tokens": record.input_tokens(),
+            "output_tokens": record.output_tokens(),
+            "cost_usd": record.cost_usd,
+            "sampled": false,
+        });
+        if self.sampled(&record.id) {
+            let mut body = serde_json::to_value(request).unwrap_or_default();
+            self.scrub(&mut body);
+            line["sampled"] = Value::Bool(true);
+            line["request"] = body;
+            line["response"] = Value::String(answer.to_string());
+        }
+        tracing::info!(target: "deepclaude::request_log", "{}", line);
+    }
+
+    /// Returns true if the request falls in the sampled share, decided by
+    /// its id so that the decision is stable.
+    fn sampled(&self, id: &str) -> bool {
+        if self.config.sample_percent <= 0.0 {
+            return false;
+        }
+        let digest = Sha256::digest(id.as_bytes());
+        let bucket = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 10_000;
+        f64::from(bucket) < self.config.sample_percent * 100.0
+    }
+
+    /// Returns the logged form of a user or key name.
+    fn identifier(&self, name: &str) -> String {
+        if !self.config.hash_users {
+            return name.to_string();
+        }
+        let digest = Sha256::digest(format!("{}{}", self.config.hash_salt, name).as_bytes());
+        // 截取前16个十六进制字符，足以区分用户
+        hex::encode(digest)[..16].to_string()
+    }
+
+    /// Replaces the redacted fields and hashes the `user` fields anywhere in
+    /// a JSON value.
+    fn scrub(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (field, value) in map.iter_mut() {
+                    if self.config.redact_fields.iter().any(|redacted| redacted.eq_ignore_ascii_case(field)) {
+                        *value = Value::String(REDACTED.to_string());
+                    } else if let (true, Value::String(user)) = (field == "user", &*value) {
+                        *value = Value::String(self.identifier(user));
+                    } else {
+                        self.scrub(value);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.scrub(item)),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Data retention and erasure.
+//!
+//! - `POST /admin/purge` - purge expired data now, or erase a tenant's data
+//!
+//! With `[retention] enabled`, a job on `[retention] schedule` removes the
+//! usage records older than `usage_days` and the audit records older than
+//! `audit_days` (see [`crate::audit`]), traces past `[traces]
+//! retention_hours`, and dead letters past `[deadletter] max_age_days`.
+//! Session state already expires after `[prompt_cache] ttl_secs`. A setting
+//! of 0 days keeps that data.
+//!
+//! `POST /admin/purge` runs the same purge on demand, or, with a `tenant`,
+//! erases that tenant's usage records, traces, session state, dead letters,
+//! captured payloads, shadow records and uploaded files, for erasure
+//! requests under the GDPR. Cached responses and captured reasoning are only
+//! held under hashes for their short TTLs and expire on their own. Sampled
+//! request log lines go to the log output, which this server cannot
+//! rewrite, so an erasure is refused while `[request_log]` samples requests;
+//! remove the tenant's lines from the log sink (they carry the tenant) and
+//! stop sampling first. Erasing a tenant's usage records also clears its
+//! spend towards `monthly_budget_usd`. Every purge is recorded in the audit
+//! log, which keeps its records through an erasure so the erasure itself
+//! stays on record. Needs an admin token with the owner role.
+
+use crate::{
+    admin,
+    config::AdminRole,
+    error::{ApiError, Result},
+    handlers::AppState,
+    schedule::{self, Schedule},
+    storage::Purge,
+};
+use axum::{extract::State, http::HeaderMap, Json};
+use chrono::{Duration, FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// A purge requested through `POST /admin/purge`.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct PurgeRequest {
+    /// Tenant whose data is erased; without one, expired data is purged.
+    pub tenant: Option<String>,
+}
+
+/// How much of each kind of data a purge removed.
+#[derive(Debug, Default, Serialize)]
+pub struct Purged {
+    pub usage: u64,
+    pub audit: u64,
+    pub traces: u64,
+    pub sessions: u64,
+    pub dead_letters: u64,
+    pub payloads: u64,
+    pub shadow: u64,
+    pub files: u64,
+}
+
+/// Starts the scheduled purge, if enabled.
+///
+/// # Errors
+///
+/// Returns an error if the schedule cannot be parsed.
+pub fn spawn(state: Arc<AppState>) -> anyhow::Result<()> {
+    let config = &state.config.retention;
+    if !config.enabled {
+        return Ok(());
+    }
+    let schedule = Schedule::parse(&config.schedule)?;
+    tracing::info!("数据保留清理已启用，计划: {}", config.schedule);
+    schedule::spawn("retention_purge", schedule, FixedOffset::east_opt(0).unwrap(), move || {
+        let state = state.clone();
+        async move {
+            match expired(&state).await {
+                Ok(purged) => {
+                    tracing::info!("已清理过期数据: {:?}", purged);
+                    state.audit.record("retention", "retention.purge", json!(purged));
+                }
+                Err(e) => tracing::error!("清理过期数据失败: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Removes the data past its retention period.
+pub async fn expired(state: &AppState) -> anyhow::Result<Purged> {
+    let config = &state.config.retention;
+    let mut purged = Purged::default();
+    if config.usage_days > 0 {
+        let before = Utc::now().date_naive() - Duration::days(i64::from(config.usage_days));
+        purged.usage = state.usage.purge(&Purge::Before(before)).await?;
+    }
+    if config.audit_days > 0 {
+        purged.audit = state.audit.purge(Utc::now() - Duration::days(i64::from(config.audit_days)))?;
+    }
+    purged.traces = state.traces.prune()?;
+    purged.dead_letters = state.dead_letters.prune()?;
+    Ok(purged)
+}
+
+/// Removes the data of a tenant.
+///
+/// # Errors
+///
+/// Returns `ApiError::BadRequest` while `[request_log]` samples requests,
+/// since their logged bodies cannot be erased here.
+pub async fn erase(state: &AppState, tenant: &str) -> Result<Purged> {
+    let log = &state.config.request_log;
+    if log.enabled && log.sample_percent > 0.0 {
+        return Err(ApiError::BadRequest {
+            message: "Sampled request logs hold request bodies that cannot be erased here; \
+                      remove the tenant's lines from the log output and set [request_log] sample_percent = 0 first"
+                .to_string(),
+        });
+    }
+    Ok(Purged {
+        usage: state.usage.purge(&Purge::Tenant(tenant.to_string())).await?,
+        audit: 0,
+        traces: state.traces.erase(tenant)?,
+        sessions: state.sessions.erase(tenant).await?,
+        dead_letters: state.dead_letters.erase(tenant)?,
+        payloads: state.payloads.erase(tenant),
+        shadow: state.shadow.erase(tenant)?,
+        files: state.files.erase(tenant)?,
+    })
+}
+
+/// Purges expired data, or erases the data of a tenant.
+#[utoipa::path(
+    post,
+    path = "/admin/purge",
+    tag = "admin",
+    request_body = PurgeRequest,
+    responses((status = 200, description = "How much of each kind of data was removed", body = Object))
+)]
+pub async fn purge(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<PurgeRequest>,
+) -> Result<Json<serde_json::Value>> {
+    let admin = admin::require_admin(&state, &headers, AdminRole::Owner)?;
+
+    let purged = match &request.tenant {
+        Some(tenant) => erase(&state, tenant).await?,
+        None => expired(&state).await?,
+    };
+    tracing::info!("{}清理了数据: {:?}", admin.name, purged);
+    state.audit.record(
+        &admin.name,
+        "retention.purge",
+        json!({ "tenant": request.tenant, "purged": purged }),
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "tenant": request.tenant,
+        "purged": purged,
+    })))
+}
@@ -0,0 +1,125 @@
+//! Declarative request rules.
+//!
+//! `[[rules]]` entries are compiled once at startup and evaluated in order
+//! for every chat request, before presets, key checks and the pipeline. A
+//! rule matches on the model the client named, the virtual key, a header or
+//! a regex over the prompt, and can switch either stage's model (or alias),
+//! force the mode, prepend a system prompt or reject the request. Later
+//! rules see the changes of earlier ones, so a rule can override another.
+
+use crate::{
+    config::Rule,
+    error::{ApiError, Result},
+    keys::VirtualKey,
+    models::request::ApiRequest,
+};
+use axum::http::HeaderMap;
+use regex::Regex;
+
+/// A rule with its prompt regex compiled.
+#[derive(Debug)]
+struct Compiled {
+    rule: Rule,
+    prompt: Option<Regex>,
+}
+
+/// Compiled `[[rules]]`.
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<Compiled>,
+}
+
+impl Rules {
+    pub fn new(rules: &[Rule]) -> anyhow::Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let prompt = rule
+                    .when
+                    .prompt
+                    .as_deref()
+                    .map(|pattern| {
+                        Regex::new(pattern).map_err(|e| anyhow::anyhow!("规则{}的prompt正则无效 {}: {}", rule.name, pattern, e))
+                    })
+                    .transpose()?;
+                Ok(Compiled {
+                    rule: rule.clone(),
+                    prompt,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Applies the matching rules to a request.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Forbidden` if a matching rule rejects the request.
+    pub fn apply(&self, request: &mut ApiRequest, headers: &HeaderMap, key: Option<&VirtualKey>) -> Result<()> {
+        for compiled in &self.rules {
+            if !compiled.matches(request, headers, key) {
+                continue;
+            }
+            let rule = &compiled.rule;
+            tracing::debug!("请求匹配规则{}", rule.name);
+            if let Some(message) = &rule.reject {
+                return Err(ApiError::Forbidden {
+                    message: format!("{} (rule {})", message, rule.name),
+                });
+            }
+            for (config, model) in [
+                (&mut request.deepseek_config, &rule.deepseek_model),
+                (&mut request.anthropic_config, &rule.anthropic_model),
+            ] {
+                if let Some(model) = model {
+                    // 规则的模型覆盖请求自己指定的模型
+                    if let Some(body) = config.body.as_object_mut() {
+                        body.remove("model");
+                    }
+                    config.set_default("model", serde_json::Value::from(model.as_str()));
+                }
+            }
+            if let Some(mode) = &rule.mode {
+                request.mode = Some(mode.clone());
+            }
+            if let Some(system) = &rule.system {
+                request.add_context(system, Vec::new());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Compiled {
+    fn matches(&self, request: &ApiRequest, headers: &HeaderMap, key: Option<&VirtualKey>) -> bool {
+        let when = &self.rule.when;
+        if when.model.as_ref().is_some_and(|model| request.model.as_ref() != Some(model)) {
+            return false;
+        }
+        if when.key.as_ref().is_some_and(|name| key.is_none_or(|key| &key.name != name)) {
+            return false;
+        }
+        if let Some(header) = &when.header {
+            let (name, value) = match header.split_once(':') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (header.trim(), None),
+            };
+            let matched = headers
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .any(|v| value.is_none_or(|value| v.trim() == value));
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(prompt) = &self.prompt {
+            let matched = request.system.iter().chain(request.messages.iter().map(|m| &m.content)).any(|text| prompt.is_match(text));
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,135 @@
+//! Cron-style scheduling of background jobs.
+//!
+//! Schedules use the five cron fields `minute hour day-of-month month
+//! day-of-week`, each `*`, a number, a range `a-b`, a list `a,b` or a step
+//! `*/n` / `a-b/n`. Day-of-week runs from 0 (Sunday) to 6; 7 is also
+//! Sunday. As in cron, when both day fields are restricted a day matching
+//! either one fires. Times are evaluated at a fixed UTC offset.
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Timelike, Utc};
+use std::future::Future;
+
+/// A parsed cron expression.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl Schedule {
+    /// Parses a five-field cron expression.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the field that cannot be parsed.
+    pub fn parse(expression: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            anyhow::bail!("cron表达式需要5个字段: {}", expression);
+        };
+        let mut weekdays = field(weekday, 0, 7)?;
+        // 7和0都表示周日
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+        Ok(Self {
+            minutes: field(minute, 0, 59)?,
+            hours: field(hour, 0, 23)?,
+            days: field(day, 1, 31)?,
+            months: field(month, 1, 12)?,
+            weekdays,
+            days_restricted: day != "*",
+            weekdays_restricted: weekday != "*",
+        })
+    }
+
+    /// Returns the first matching minute after `after`.
+    pub fn next_after(&self, after: DateTime<FixedOffset>) -> Option<DateTime<FixedOffset>> {
+        let mut time = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        // 最坏情况下(如2月29日)需要跨越数年，按分钟逐步推进但整小时、整天地跳过
+        let limit = after + Duration::days(366 * 5);
+        while time <= limit {
+            if !self.months[time.month() as usize] || !self.matches_day(time) {
+                time = (time + Duration::days(1)).with_hour(0)?.with_minute(0)?;
+                continue;
+            }
+            if !self.hours[time.hour() as usize] {
+                time = (time + Duration::hours(1)).with_minute(0)?;
+                continue;
+            }
+            if self.minutes[time.minute() as usize] {
+                return Some(time);
+            }
+            time += Duration::minutes(1);
+        }
+        None
+    }
+
+    fn matches_day(&self, time: DateTime<FixedOffset>) -> bool {
+        let day = self.days[time.day() as usize];
+        let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            (true, false) => day,
+            (false, true) => weekday,
+            (false, false) => true,
+        }
+    }
+}
+
+/// Parses one cron field into a table indexed by value.
+fn field(spec: &str, min: u32, max: u32) -> anyhow::Result<Vec<bool>> {
+    let mut allowed = vec![false; max as usize + 1];
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0)),
+            None => (part, Some(1)),
+        };
+        let step = step.ok_or_else(|| anyhow::anyhow!("无效的cron步长: {}", part))?;
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (start.parse()?, end.parse()?),
+                None => {
+                    let value = range.parse()?;
+                    // 单个值加步长表示从该值到最大值
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            anyhow::bail!("cron字段超出范围{}-{}: {}", min, max, part);
+        }
+        for value in (start..=end).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+/// Runs `job` at every time the schedule matches, at the given UTC offset.
+pub fn spawn<F, Fut>(name: &'static str, schedule: Schedule, offset: FixedOffset, job: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    tokio::spawn(async move {
+        loop {
+            let now = Utc::now().with_timezone(&offset);
+            let Some(next) = schedule.next_after(now) else {
+                tracing::warn!("定时任务{}没有下一次执行时间，已停止", name);
+                return;
+            };
+            tracing::debug!("定时任务{}下次执行: {}", name, next);
+            let wait = (next - now).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+            job().await;
+        }
+    });
+}
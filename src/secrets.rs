@@ -0,0 +1,219 @@
+//! External secret references for provider API keys.
+//!
+//! Provider keys in `config.toml` or `.env` may be given as references
+//! instead of plaintext:
+//!
+//! - `vault://<kv-path>#<field>` - read from HashiCorp Vault (KV v1 or v2)
+//!   using `VAULT_ADDR` and `VAULT_TOKEN`
+//! - `aws-sm://<secret-id>[#<json-field>]` - read from AWS Secrets Manager
+//!   using the standard `AWS_*` credential environment variables
+//!
+//! References are resolved at startup and refreshed periodically; resolved
+//! values are only ever held in memory.
+
+use crate::config::SecretsConfig;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, sync::RwLock, time::Duration};
+
+const VAULT_SCHEME: &str = "vault://";
+const AWS_SM_SCHEME: &str = "aws-sm://";
+
+/// Resolved secret values keyed by their reference URI.
+static RESOLVED: Lazy<RwLock<HashMap<String, String>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Returns true if the value is a secret reference rather than a plaintext key.
+pub fn is_reference(value: &str) -> bool {
+    value.starts_with(VAULT_SCHEME) || value.starts_with(AWS_SM_SCHEME)
+}
+
+/// Returns the usable key for a configured value.
+///
+/// Plaintext values are returned unchanged; references are replaced by
+/// their resolved value, or `None` if they have not been resolved.
+pub fn expose(value: &str) -> Option<String> {
+    if !is_reference(value) {
+        return Some(value.to_string());
+    }
+    let resolved = RESOLVED.read().unwrap().get(value).cloned();
+    if resolved.is_none() {
+        tracing::error!("密钥引用尚未解析: {}", value);
+    }
+    resolved
+}
+
+/// Resolves every reference in `references` and stores the results.
+///
+/// # Errors
+///
+/// Returns an error naming the first reference that could not be resolved.
+/// References resolved before the failure are still stored.
+pub async fn resolve_all(references: &[String], config: &SecretsConfig) -> anyhow::Result<()> {
+    let client = Client::new();
+    for reference in references {
+        let value = resolve(&client, reference, config)
+            .await
+            .map_err(|e| anyhow::anyhow!("无法解析密钥引用 {}: {}", reference, e))?;
+        RESOLVED.write().unwrap().insert(reference.clone(), value);
+    }
+    Ok(())
+}
+
+/// Spawns a background task that re-resolves all references periodically.
+///
+/// Failed refreshes keep the previously resolved value.
+pub fn spawn_refresh(references: Vec<String>, config: SecretsConfig) {
+    if references.is_empty() || config.refresh_secs == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.refresh_secs));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match resolve_all(&references, &config).await {
+                Ok(()) => tracing::debug!("已刷新{}个密钥引用", references.len()),
+                Err(e) => tracing::warn!("刷新密钥引用失败，继续使用旧值: {}", e),
+            }
+        }
+    });
+}
+
+async fn resolve(client: &Client, reference: &str, config: &SecretsConfig) -> anyhow::Result<String> {
+    if let Some(rest) = reference.strip_prefix(VAULT_SCHEME) {
+        resolve_vault(client, rest, config).await
+    } else if let Some(rest) = reference.strip_prefix(AWS_SM_SCHEME) {
+        resolve_aws(client, rest, config).await
+    } else {
+        Ok(reference.to_string())
+    }
+}
+
+/// Splits `path#field` into its parts.
+fn split_fragment(rest: &str) -> (&str, Option<&str>) {
+    match rest.split_once('#') {
+        Some((path, field)) => (path, Some(field)),
+        None => (rest, None),
+    }
+}
+
+async fn resolve_vault(client: &Client, rest: &str, config: &SecretsConfig) -> anyhow::Result<String> {
+    let (path, field) = split_fragment(rest);
+    let field = field.ok_or_else(|| anyhow::anyhow!("vault引用缺少#字段名"))?;
+
+    let addr = config
+        .vault_addr
+        .clone()
+        .or_else(|| std::env::var("VAULT_ADDR").ok())
+        .ok_or_else(|| anyhow::anyhow!("未设置VAULT_ADDR"))?;
+    let token = std::env::var("VAULT_TOKEN").map_err(|_| anyhow::anyhow!("未设置VAULT_TOKEN"))?;
+
+    let body: serde_json::Value = client
+        .get(format!("{}/v1/{}", addr.trim_end_matches('/'), path.trim_start_matches('/')))
+        .header("X-Vault-Token", token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // KV v2 把数据嵌套在data.data中，KV v1直接放在data中
+    let data = &body["data"];
+    let data = if data["data"].is_object() { &data["data"] } else { data };
+    data[field]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| anyhow::anyhow!("vault返回中没有字段{}", field))
+}
+
+async fn resolve_aws(client: &Client, rest: &str, config: &SecretsConfig) -> anyhow::Result<String> {
+    let (secret_id, field) = split_fragment(rest);
+
+    let region = config
+        .aws_region
+        .clone()
+        .or_else(|| std::env::var("AWS_REGION").ok())
+        .or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+        .ok_or_else(|| anyhow::anyhow!("未设置AWS_REGION"))?;
+    let access_key = std::env::var("AWS_ACCESS_KEY_ID").map_err(|_| anyhow::anyhow!("未设置AWS_ACCESS_KEY_ID"))?;
+    let secret_key =
+        std::env::var("AWS_SECRET_ACCESS_KEY").map_err(|_| anyhow::anyhow!("未设置AWS_SECRET_ACCESS_KEY"))?;
+    let session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+
+    let host = format!("secretsmanager.{}.amazonaws.com", region);
+    let payload = serde_json::json!({ "SecretId": secret_id }).to_string();
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let target = "secretsmanager.GetSecretValue";
+
+    // AWS Signature Version 4
+    let mut signed_headers = vec![
+        ("content-type", "application/x-amz-json-1.1".to_string()),
+        ("host", host.clone()),
+        ("x-amz-date", amz_date.clone()),
+        ("x-amz-target", target.to_string()),
+    ];
+    if let Some(token) = &session_token {
+        signed_headers.push(("x-amz-security-token", token.clone()));
+        signed_headers.sort_by(|a, b| a.0.cmp(b.0));
+    }
+    let canonical_headers: String = signed_headers.iter().map(|(k, v)| format!("{}:{}\n", k, v)).collect();
+    let header_names = signed_headers.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(";");
+    let canonical_request = format!(
+        "POST\n/\n\n{}\n{}\n{}",
+        canonical_headers,
+        header_names,
+        hex::encode(Sha256::digest(payload.as_bytes()))
+    );
+    let scope = format!("{}/{}/secretsmanager/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let mut key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    for part in [region.as_str(), "secretsmanager", "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key, scope, header_names, signature
+    );
+
+    let mut request = client.post(format!("https://{}/", host)).body(payload);
+    for (name, value) in &signed_headers {
+        if *name != "host" {
+            request = request.header(*name, value);
+        }
+    }
+    let body: serde_json::Value = request
+        .header("Authorization", authorization)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let secret = body["SecretString"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("AWS返回中没有SecretString"))?;
+    match field {
+        Some(field) => serde_json::from_str::<serde_json::Value>(secret)?[field]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| anyhow::anyhow!("密钥JSON中没有字段{}", field)),
+        None => Ok(secret.to_string()),
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
@@ -0,0 +1,118 @@
+//! Prompt cache warming for multi-turn sessions.
+//!
+//! A client names its conversation with the `X-Session-Id` header. With
+//! `[prompt_cache] enabled`, the Claude stage of a session request marks its
+//! system prompt as a cached prefix (`cache_control: ephemeral`) once the
+//! prompt is at least `min_prefix_chars` long, so each turn after the first
+//! reads the prefix from Anthropic's cache instead of paying for it again.
+//!
+//! The store remembers, per session, which prefix was last sent, for
+//! `ttl_secs`, in the `[storage]` key-value cache under
+//! `session:<hex of the tenant>:<id>`, so replicas sharing a database agree on it and
+//! a tenant's sessions can be erased together. The first turn, a turn whose
+//! prefix changed (a new system prompt, model or config) and a turn after
+//! `ttl_secs` of silence write the cache and count as `cold`; the others
+//! count as `warm`. The status is recorded as `prompt_cache` in the usage
+//! records. Only the Anthropic Messages API supports cache markers, so
+//! OpenAI-format hosts are left alone.
+
+use crate::{config::PromptCacheConfig, storage::KvCache};
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, time::Duration};
+
+/// Request header naming the session a request belongs to.
+pub const SESSION_HEADER: &str = "x-session-id";
+
+/// The session a request names, if any.
+pub fn session(headers: &HeaderMap) -> Option<String> {
+    let session = headers.get(SESSION_HEADER)?.to_str().ok()?;
+    (!session.is_empty()).then(|| session.to_string())
+}
+
+/// Whether a request found its prefix in the prompt cache.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+    /// The prefix was sent within the TTL and is read from the cache.
+    Warm,
+    /// The prefix is written to the cache by this request.
+    Cold,
+}
+
+/// Prefixes recently sent by each session.
+pub struct SessionStore {
+    config: PromptCacheConfig,
+    store: Arc<dyn KvCache>,
+}
+
+impl SessionStore {
+    pub fn new(config: &PromptCacheConfig, store: Arc<dyn KvCache>) -> Self {
+        Self {
+            config: config.clone(),
+            store,
+        }
+    }
+
+    /// Records that a request of `session`, made for `tenant`, sends
+    /// `system` to `model`.
+    ///
+    /// Returns `None` if the request's prefix is not cached: caching is
+    /// disabled, the request names no session or the prompt is too short.
+    pub async fn touch(
+        &self,
+        tenant: Option<&str>,
+        session: Option<&str>,
+        model: &str,
+        system: Option<&str>,
+    ) -> Option<CacheStatus> {
+        if !self.config.enabled {
+            return None;
+        }
+        let session = session?;
+        let system = system.filter(|system| system.chars().count() >= self.config.min_prefix_chars)?;
+        let prefix = hex::encode(Sha256::digest(format!("{}\n{}", model, system).as_bytes()));
+
+        // 过期的会话不会再命中缓存，存储会自行清理
+        let key = format!("{}{}", tenant_prefix(tenant), session);
+        let status = match self.store.get(&key).await {
+            Ok(Some(sent)) if sent == prefix => CacheStatus::Warm,
+            Ok(_) => CacheStatus::Cold,
+            Err(e) => {
+                tracing::warn!("读取会话缓存状态失败: {}", e);
+                CacheStatus::Cold
+            }
+        };
+        // 每次读取都会刷新Anthropic端缓存的有效期
+        if let Err(e) = self.store.put(&key, prefix, Duration::from_secs(self.config.ttl_secs)).await {
+            tracing::warn!("写入会话缓存状态失败: {}", e);
+        }
+        Some(status)
+    }
+
+    /// The ids of a tenant's unexpired sessions.
+    pub async fn list(&self, tenant: &str) -> anyhow::Result<Vec<String>> {
+        let prefix = tenant_prefix(Some(tenant));
+        let mut sessions: Vec<String> = self
+            .store
+            .scan(&prefix)
+            .await?
+            .into_iter()
+            .filter_map(|(key, _)| key.strip_prefix(&prefix).map(str::to_string))
+            .collect();
+        sessions.sort();
+        Ok(sessions)
+    }
+
+    /// Forgets the sessions of a tenant, returning how many there were.
+    pub async fn erase(&self, tenant: &str) -> anyhow::Result<u64> {
+        self.store.remove_prefix(&tenant_prefix(Some(tenant))).await
+    }
+}
+
+/// Start of the cache keys of a tenant's sessions. The tenant is hex-encoded
+/// so no tenant's prefix is the start of another's.
+fn tenant_prefix(tenant: Option<&str>) -> String {
+    format!("session:{}:", hex::encode(tenant.unwrap_or_default()))
+}
@@ -0,0 +1,212 @@
+//! Shadow-traffic evaluation.
+//!
+//! A sampled share of requests is replayed against an alternate responder
+//! model in the background, after the user-visible response has been
+//! produced. The primary and shadow outputs are appended as JSON lines to
+//! `[shadow] output_path` for offline comparison. Each record carries the
+//! tenant of the request, so that erasing a tenant (see
+//! [`crate::retention`]) also removes its records from the file.
+//!
+//! Shadow calls are best effort: they run on a bounded task pool, never wait
+//! for rate limit budget, and are dropped rather than queued when either is
+//! exhausted.
+
+use crate::{
+    clients::{hosts::WireApi, providers, AnthropicClient},
+    config::ShadowConfig,
+    handlers::{self, AppState},
+    models::request::{ApiConfig, Message},
+    ratelimit,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::sync::Semaphore;
+
+/// Background mirroring pool shared across requests.
+#[derive(Debug)]
+pub struct Shadow {
+    config: ShadowConfig,
+    permits: Arc<Semaphore>,
+    output: Mutex<()>,
+}
+
+/// The responder call of a completed request, to be mirrored.
+#[derive(Debug)]
+pub struct ShadowRequest {
+    pub id: String,
+    pub tenant: Option<String>,
+    pub variant: String,
+    pub messages: Vec<Message>,
+    pub system: Option<String>,
+    pub config: ApiConfig,
+    pub primary_model: String,
+    pub primary_output: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ShadowRecord<'a> {
+    timestamp: DateTime<Utc>,
+    id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    variant: &'a str,
+    system: Option<&'a str>,
+    messages: &'a [Message],
+    primary: Output<'a>,
+    shadow: Output<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct Output<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u128>,
+}
+
+impl Shadow {
+    /// Creates the mirroring pool for the `[shadow]` settings.
+    pub fn new(config: &ShadowConfig) -> Self {
+        Self {
+            config: config.clone(),
+            permits: Arc::new(Semaphore::new(config.max_concurrent)),
+            output: Mutex::new(()),
+        }
+    }
+
+    /// Decides whether the current request should be mirrored.
+    pub fn sampled(&self) -> bool {
+        self.config.percent > 0
+            && !self.config.anthropic_model.is_empty()
+            && (uuid::Uuid::new_v4().as_u128() % 100) < self.config.percent as u128
+    }
+
+    /// Appends one comparison record to the output file.
+    fn record(&self, record: &ShadowRecord) -> anyhow::Result<()> {
+        let line = serde_json::to_string(record)?;
+        let path = Path::new(&self.config.output_path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // 串行写入，避免并发的影子任务交错写入同一行
+        let _guard = self.output.lock().unwrap();
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+
+    /// Removes a tenant's records from the output file, returning how many.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or rewritten.
+    pub fn erase(&self, tenant: &str) -> anyhow::Result<u64> {
+        let path = Path::new(&self.config.output_path);
+        let _guard = self.output.lock().unwrap();
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        let mut kept = String::with_capacity(content.len());
+        let mut removed = 0;
+        for line in content.lines() {
+            // 无法解析的行不属于任何租户，原样保留
+            let record = serde_json::from_str::<serde_json::Value>(line).ok();
+            if record.is_some_and(|record| record["tenant"].as_str() == Some(tenant)) {
+                removed += 1;
+            } else {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        if removed > 0 {
+            // 先写临时文件再替换，避免中途失败留下半个文件
+            let temp = path.with_extension("erase.tmp");
+            std::fs::write(&temp, kept)?;
+            std::fs::rename(&temp, path)?;
+        }
+        Ok(removed)
+    }
+}
+
+/// Mirrors a completed request to the shadow responder in the background.
+///
+/// Returns immediately; the request is dropped if the task pool is full or
+/// the Anthropic rate limit budget has no room for it.
+pub fn mirror(state: &Arc<AppState>, anthropic_token: String, request: ShadowRequest) {
+    let Ok(permit) = state.shadow.permits.clone().try_acquire_owned() else {
+        tracing::debug!("影子任务池已满，跳过请求{}", request.id);
+        return;
+    };
+
+    let estimated_tokens = ratelimit::estimate_messages_tokens(&request.messages);
+    if !state.rate_limiter.try_acquire("anthropic", estimated_tokens) {
+        tracing::debug!("Anthropic速率预算不足，跳过影子请求{}", request.id);
+        return;
+    }
+
+    let state = state.clone();
+    tokio::spawn(providers::inherit(async move {
+        let _permit = permit;
+        let shadow_model = state.shadow.config.anthropic_model.clone();
+
+        let mut config = request.config.clone();
+        if !config.body.is_object() {
+            config.body = serde_json::json!({});
+        }
+        config.body["model"] = serde_json::Value::from(shadow_model.as_str());
+
+        let started = Instant::now();
+        let route = handlers::stage_route(&state, &config)
+            .filter(|route| route.profile.wire == WireApi::ChatCompletions);
+        let client = AnthropicClient::new(anthropic_token).with_route(route);
+        let result = client.chat(request.messages.clone(), request.system.clone(), &config).await;
+        let latency_ms = started.elapsed().as_millis();
+
+        let (output, error) = match result {
+            Ok(response) => {
+                let actual = response.usage.input_tokens + response.usage.output_tokens;
+                state.rate_limiter.settle("anthropic", estimated_tokens, actual);
+                let text = response.content.into_iter().map(|block| block.text).collect::<String>();
+                (Some(text), None)
+            }
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        let record = ShadowRecord {
+            timestamp: Utc::now(),
+            id: &request.id,
+            tenant: request.tenant.as_deref(),
+            variant: &request.variant,
+            system: request.system.as_deref(),
+            messages: &request.messages,
+            primary: Output {
+                model: &request.primary_model,
+                output: Some(&request.primary_output),
+                error: None,
+                latency_ms: None,
+            },
+            shadow: Output {
+                model: &shadow_model,
+                output: output.as_deref(),
+                error,
+                latency_ms: Some(latency_ms),
+            },
+        };
+        if let Err(e) = state.shadow.record(&record) {
+            tracing::warn!("写入影子对比记录失败: {}", e);
+        }
+    }));
+}
@@ -0,0 +1,148 @@
+//! Shedding new streams under memory or descriptor pressure.
+//!
+//! A process that keeps accepting streams while its memory grows gets
+//! OOM-killed mid-generation, taking every running stream with it. With
+//! `[shedding] max_rss_mb` or `max_open_fds` set, the process's resident
+//! memory and open file descriptors (each connection holds one) are read
+//! from `/proc`, at most once per `SAMPLE_INTERVAL`. While either is above
+//! its limit, new streaming requests are rejected with
+//! `ApiError::Overloaded` and `Retry-After`, and the running streams finish
+//! normally. Non-streaming requests are short and still served. Where
+//! `/proc` is unavailable nothing is shed. Both figures are exported at
+//! `GET /metrics`.
+
+use crate::{
+    config::SheddingConfig,
+    error::{ApiError, Result},
+};
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long a usage sample is reused.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resource usage of the process, `None` where it cannot be read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub rss_bytes: Option<u64>,
+    pub open_fds: Option<u64>,
+}
+
+impl Usage {
+    /// Reads the current usage from `/proc/self`.
+    pub fn read() -> Self {
+        let rss_bytes = std::fs::read_to_string("/proc/self/status").ok().and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("VmRSS:"))
+                .and_then(|value| value.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+                .map(|kb| kb * 1024)
+        });
+        let open_fds = std::fs::read_dir("/proc/self/fd").ok().map(|entries| entries.count() as u64);
+        Self { rss_bytes, open_fds }
+    }
+}
+
+/// Decides whether new streams are accepted.
+pub struct LoadShedder {
+    config: SheddingConfig,
+    sample: Mutex<Option<(Instant, Usage)>>,
+    shedding: AtomicBool,
+}
+
+impl LoadShedder {
+    pub fn new(config: &SheddingConfig) -> Self {
+        Self {
+            config: config.clone(),
+            sample: Mutex::new(None),
+            shedding: AtomicBool::new(false),
+        }
+    }
+
+    /// The latest usage sample, read again once it is older than
+    /// `SAMPLE_INTERVAL`.
+    fn usage(&self) -> Usage {
+        let mut sample = self.sample.lock().unwrap();
+        match *sample {
+            Some((taken, usage)) if taken.elapsed() < SAMPLE_INTERVAL => usage,
+            _ => {
+                let usage = Usage::read();
+                *sample = Some((Instant::now(), usage));
+                usage
+            }
+        }
+    }
+
+    /// The limit the process is over, if any.
+    fn exceeded(&self, usage: Usage) -> Option<String> {
+        let max_rss = self.config.max_rss_mb * 1024 * 1024;
+        if let Some(rss) = usage.rss_bytes.filter(|&rss| max_rss > 0 && rss > max_rss) {
+            return Some(format!("memory usage {}MiB above {}MiB", rss / 1024 / 1024, self.config.max_rss_mb));
+        }
+        let max_fds = self.config.max_open_fds;
+        if let Some(fds) = usage.open_fds.filter(|&fds| max_fds > 0 && fds > max_fds) {
+            return Some(format!("{} open file descriptors above {}", fds, max_fds));
+        }
+        None
+    }
+
+    /// Admits a new streaming request.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Overloaded` while the process is over one of its
+    /// limits.
+    pub fn admit_stream(&self) -> Result<()> {
+        if self.config.max_rss_mb == 0 && self.config.max_open_fds == 0 {
+            return Ok(());
+        }
+        match self.exceeded(self.usage()) {
+            Some(reason) => {
+                if !self.shedding.swap(true, Ordering::Relaxed) {
+                    tracing::warn!("资源使用超限（{}），开始拒绝新的流式请求", reason);
+                }
+                Err(ApiError::Overloaded {
+                    message: "Server is low on resources and not accepting new streams".to_string(),
+                    retry_after_secs: self.config.retry_after_secs,
+                })
+            }
+            None => {
+                if self.shedding.swap(false, Ordering::Relaxed) {
+                    tracing::info!("资源使用恢复正常，重新接受流式请求");
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Renders the usage and shedding state in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let usage = self.usage();
+        let metrics = [
+            (
+                "deepclaude_process_resident_memory_bytes",
+                "Resident memory of the process.",
+                usage.rss_bytes,
+            ),
+            ("deepclaude_process_open_fds", "Open file descriptors of the process.", usage.open_fds),
+            (
+                "deepclaude_load_shedding",
+                "Whether new streams are being rejected for resource usage.",
+                Some(self.shedding.load(Ordering::Relaxed) as u64),
+            ),
+        ];
+        for (name, help, value) in metrics {
+            if let Some(value) = value {
+                let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,144 @@
+//! HMAC signatures on webhooks and administrative calls.
+//!
+//! With `[signing] webhook_secret` set, every webhook post (daily reports,
+//! spend alerts) carries two headers so receivers can check it came from
+//! this server and was not replayed:
+//!
+//! - `X-DeepClaude-Timestamp` - Unix time of the post, in seconds
+//! - `X-DeepClaude-Signature` - `sha256=` and the hex HMAC-SHA256 of
+//!   `<timestamp>.<body>` under the secret
+//!
+//! With `[signing] admin_secret` set, administrative calls that change
+//! something (every non-GET route under `/admin` and `POST /v1/env/update`)
+//! must carry the same headers, signed over
+//! `<timestamp>.<METHOD>.<path and query>.<body>` so a signature cannot be
+//! reused on another route. Calls with a missing or wrong signature, or a
+//! timestamp more than `max_skew_secs` away from the server's clock, are
+//! refused with 401 before the admin token is checked. Both secrets may be
+//! secret references.
+
+use crate::{
+    config::SigningConfig,
+    error::{ApiError, Result},
+    handlers::AppState,
+    secrets,
+};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{HeaderMap, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::{Arc, RwLock};
+
+/// Header carrying the Unix time a payload was signed at.
+pub const TIMESTAMP_HEADER: &str = "x-deepclaude-timestamp";
+
+/// Header carrying the signature of a payload.
+pub const SIGNATURE_HEADER: &str = "x-deepclaude-signature";
+
+/// Largest administrative body that is buffered for verification.
+const MAX_SIGNED_BODY: usize = 16 * 1024 * 1024;
+
+/// `[signing] webhook_secret`, used by webhook posts outside the app state.
+static WEBHOOK_SECRET: RwLock<String> = RwLock::new(String::new());
+
+/// Sets the secret webhook posts are signed with.
+pub fn set_webhook_secret(secret: &str) {
+    *WEBHOOK_SECRET.write().unwrap() = secret.to_string();
+}
+
+fn mac(secret: &str, timestamp: i64, payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload);
+    mac
+}
+
+/// The signature header value of `payload` signed at `timestamp`.
+pub fn signature(secret: &str, timestamp: i64, payload: &[u8]) -> String {
+    format!("sha256={}", hex::encode(mac(secret, timestamp, payload).finalize().into_bytes()))
+}
+
+/// The payload an administrative call is signed over, apart from the
+/// timestamp.
+pub fn admin_payload(method: &Method, path_and_query: &str, body: &[u8]) -> Vec<u8> {
+    let mut payload = format!("{}.{}.", method, path_and_query).into_bytes();
+    payload.extend_from_slice(body);
+    payload
+}
+
+/// Signature headers for a webhook body, if webhooks are signed.
+pub fn webhook_headers(body: &[u8]) -> Option<[(&'static str, String); 2]> {
+    let configured = WEBHOOK_SECRET.read().unwrap().clone();
+    let secret = secrets::expose(&configured).filter(|secret| !secret.is_empty())?;
+    let timestamp = chrono::Utc::now().timestamp();
+    Some([
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (SIGNATURE_HEADER, signature(&secret, timestamp, body)),
+    ])
+}
+
+/// Checks the signature headers of a payload.
+///
+/// # Errors
+///
+/// Returns `ApiError::Unauthorized` if a header is missing, the timestamp is
+/// too far from now or the signature does not match.
+pub fn verify(config: &SigningConfig, secret: &str, headers: &HeaderMap, payload: &[u8]) -> Result<()> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let unauthorized = |message: &str| ApiError::Unauthorized {
+        message: message.to_string(),
+    };
+    let timestamp: i64 = header(TIMESTAMP_HEADER)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| unauthorized("Administrative calls must be signed; X-DeepClaude-Timestamp is missing"))?;
+    let provided = header(SIGNATURE_HEADER)
+        .and_then(|value| value.strip_prefix("sha256="))
+        .and_then(|value| hex::decode(value).ok())
+        .ok_or_else(|| unauthorized("Administrative calls must be signed; X-DeepClaude-Signature is missing"))?;
+    if (chrono::Utc::now().timestamp() - timestamp).unsigned_abs() > config.max_skew_secs {
+        return Err(unauthorized("The request signature has expired"));
+    }
+
+    // verify_slice按常数时间比较
+    mac(secret, timestamp, payload).verify_slice(&provided).map_err(|_| unauthorized("Invalid request signature"))
+}
+
+/// Whether a call changes administrative state.
+fn administrative(method: &Method, path: &str) -> bool {
+    method != Method::GET && method != Method::HEAD && (path.starts_with("/admin/") || path == "/v1/env/update")
+}
+
+/// Middleware requiring signatures on administrative calls.
+pub async fn verify_admin(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let config = &state.config.signing;
+    let Some(secret) = secrets::expose(&config.admin_secret).filter(|secret| !secret.is_empty()) else {
+        return next.run(request).await;
+    };
+    if !administrative(request.method(), request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = match to_bytes(body, MAX_SIGNED_BODY).await {
+        Ok(body) => body,
+        Err(e) => {
+            return ApiError::BadRequest {
+                message: format!("Failed to read the request body: {}", e),
+            }
+            .into_response()
+        }
+    };
+    let path = parts.uri.path_and_query().map_or(parts.uri.path(), |path| path.as_str());
+    let payload = admin_payload(&parts.method, path, &body);
+    if let Err(e) = verify(config, &secret, &parts.headers, &payload) {
+        tracing::warn!("{} {}的签名校验失败: {}", parts.method, parts.uri.path(), e);
+        return e.into_response();
+    }
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
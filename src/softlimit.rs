@@ -0,0 +1,64 @@
+//! Early warning before a streamed answer hits its token limit.
+//!
+//! With `[soft_limit] output_tokens` set, a streaming request whose answer
+//! grows past that many tokens (estimated from its deltas) gets one extra
+//! chunk with an empty delta and a `soft_limit` field:
+//!
+//! ```json
+//! {"object": "chat.completion.chunk", "choices": [{"index": 0, "delta": {}, "finish_reason": null}],
+//!  "soft_limit": {"limit": 2048, "output_tokens": 2051}}
+//! ```
+//!
+//! so chat UIs can warn the user before `max_tokens` cuts the answer off.
+//! With `wrap_up` set, the Claude stage is also told the budget in its
+//! system prompt and asked to conclude once it is reached; a running
+//! generation cannot take new instructions, so the budget is given up front.
+
+use crate::{config::SoftLimitConfig, ratelimit};
+use serde_json::json;
+
+/// The soft limit of one streamed answer.
+pub struct SoftLimit {
+    limit: u32,
+    wrap_up: bool,
+    output_tokens: u32,
+    warned: bool,
+}
+
+impl SoftLimit {
+    /// The soft limit of a streaming request, `None` if disabled.
+    pub fn new(config: &SoftLimitConfig) -> Option<Self> {
+        (config.output_tokens > 0).then_some(Self {
+            limit: config.output_tokens,
+            wrap_up: config.wrap_up,
+            output_tokens: 0,
+            warned: false,
+        })
+    }
+
+    /// Adds the wrap-up instruction to the Claude stage's system prompt.
+    pub fn instruct(&self, system: Option<String>) -> Option<String> {
+        if !self.wrap_up {
+            return system;
+        }
+        let instruction = format!(
+            "Keep your answer within about {} tokens. If it runs longer, wrap up with a brief conclusion instead of starting new sections.",
+            self.limit
+        );
+        Some(match system {
+            Some(system) => format!("{}\n\n{}", system, instruction),
+            None => instruction,
+        })
+    }
+
+    /// Counts a delta of the answer and returns the `soft_limit` field of
+    /// the warning chunk the first time the answer crosses the limit.
+    pub fn check(&mut self, delta: &str) -> Option<serde_json::Value> {
+        self.output_tokens += ratelimit::estimate_tokens(delta);
+        if self.warned || self.output_tokens <= self.limit {
+            return None;
+        }
+        self.warned = true;
+        Some(json!({ "limit": self.limit, "output_tokens": self.output_tokens }))
+    }
+}
@@ -0,0 +1,219 @@
+//! Detection of runaway spend per key.
+//!
+//! The cost of every recorded request is summed per key and hour. A key's
+//! baseline is its average hourly cost over the `baseline_hours` before the
+//! current hour, counting only the hours since it was first seen. Once a
+//! key has `min_history_hours` of history, spend in the current hour above
+//! `multiple` times the baseline (and above `min_spend_usd`) raises an
+//! alert: a warning in the log, a counter at `GET /metrics` and, with a
+//! `webhook_url`, a JSON post carrying a Slack-compatible `text`. A key is
+//! alerted at most once per hour. The hours are seeded from the usage
+//! ledger at startup so a restart does not reset the baselines.
+
+use crate::{
+    config::SpendAlertsConfig,
+    reports, secrets,
+    usage::{UsageLedger, UsageRecord},
+};
+use chrono::{DateTime, Duration, Utc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::Mutex,
+};
+
+/// Key name the requests made without a virtual key are tracked under.
+const ANONYMOUS: &str = "anonymous";
+
+/// Hourly spend of one key.
+#[derive(Debug, Default)]
+struct KeySpend {
+    /// Hour the key was first seen, in hours since the epoch.
+    first_hour: i64,
+    /// Cost per hour since the epoch, within the baseline window.
+    hours: BTreeMap<i64, f64>,
+    alerted_hour: Option<i64>,
+    alerts: u64,
+}
+
+impl KeySpend {
+    /// Returns the average hourly cost before `hour`, `None` while the key
+    /// has too little history.
+    fn baseline(&self, hour: i64, config: &SpendAlertsConfig) -> Option<f64> {
+        let history = (hour - self.first_hour).min(config.baseline_hours as i64);
+        if history < config.min_history_hours.max(1) as i64 {
+            return None;
+        }
+        let total: f64 = self.hours.range(hour - history..hour).map(|(_, cost)| cost).sum();
+        Some(total / history as f64)
+    }
+}
+
+/// Tracks per-key hourly spend and raises alerts.
+pub struct SpendMonitor {
+    config: SpendAlertsConfig,
+    keys: Mutex<HashMap<String, KeySpend>>,
+}
+
+/// An hour of a key's spend above its baseline.
+struct Alert {
+    key: String,
+    spend_usd: f64,
+    baseline_usd: f64,
+}
+
+impl SpendMonitor {
+    /// Creates the monitor, seeding the baselines from the usage ledger.
+    pub async fn new(config: &SpendAlertsConfig, usage: &UsageLedger) -> Self {
+        let monitor = Self {
+            config: config.clone(),
+            keys: Mutex::new(HashMap::new()),
+        };
+        if !config.enabled {
+            return monitor;
+        }
+        let since = Utc::now() - Duration::hours(config.baseline_hours as i64 + 1);
+        match usage.read(Some(since.date_naive()), None).await {
+            Ok(records) => {
+                let mut keys = monitor.keys.lock().unwrap();
+                for record in records.iter().filter(|record| record.timestamp >= since) {
+                    add(&mut keys, key_of(record), record.timestamp, record.cost_usd);
+                }
+                tracing::info!("已从用量记录载入{}个密钥的花费基线", keys.len());
+            }
+            Err(e) => tracing::warn!("载入花费基线失败: {}", e),
+        }
+        monitor
+    }
+
+    /// Adds a recorded request to its key's spend, alerting if the key's
+    /// spend this hour has run away.
+    pub fn observe(&self, record: &UsageRecord) {
+        if !self.config.enabled {
+            return;
+        }
+        let key = key_of(record);
+        let hour = hour_of(record.timestamp);
+        let alert = {
+            let mut keys = self.keys.lock().unwrap();
+            let spend = add(&mut keys, key, record.timestamp, record.cost_usd);
+            // 清理基线窗口之外的小时
+            spend.hours.retain(|past, _| *past >= hour - self.config.baseline_hours as i64);
+            let current = spend.hours.get(&hour).copied().unwrap_or_default();
+            match spend.baseline(hour, &self.config) {
+                Some(baseline)
+                    if spend.alerted_hour != Some(hour)
+                        && current >= self.config.min_spend_usd
+                        && current > baseline * self.config.multiple =>
+                {
+                    spend.alerted_hour = Some(hour);
+                    spend.alerts += 1;
+                    Some(Alert {
+                        key: key.to_string(),
+                        spend_usd: current,
+                        baseline_usd: baseline,
+                    })
+                }
+                _ => None,
+            }
+        };
+        if let Some(alert) = alert {
+            self.alert(alert);
+        }
+    }
+
+    fn alert(&self, alert: Alert) {
+        tracing::warn!(
+            "密钥{}本小时花费${:.4}超过基线${:.4}/小时的{}倍",
+            alert.key,
+            alert.spend_usd,
+            alert.baseline_usd,
+            self.config.multiple
+        );
+        if self.config.webhook_url.is_empty() {
+            return;
+        }
+        let Some(url) = secrets::expose(&self.config.webhook_url) else {
+            return;
+        };
+        let body = serde_json::json!({
+            "text": format!(
+                "*DeepClaude spend alert*: key {} has spent ${:.4} this hour, {:.1}x its baseline of ${:.4}/hour",
+                alert.key,
+                alert.spend_usd,
+                alert.spend_usd / alert.baseline_usd.max(f64::EPSILON),
+                alert.baseline_usd
+            ),
+            "alert": {
+                "key": alert.key,
+                "spend_usd": alert.spend_usd,
+                "baseline_usd": alert.baseline_usd,
+                "multiple": self.config.multiple,
+            },
+        });
+        tokio::spawn(async move {
+            if let Err(e) = reports::post_webhook(&url, &body).await {
+                tracing::error!("发送花费告警失败: {}", e);
+            }
+        });
+    }
+
+    /// Renders the spend gauges and alert counters in the Prometheus text
+    /// format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        if !self.config.enabled {
+            return out;
+        }
+        let hour = hour_of(Utc::now());
+        let keys = self.keys.lock().unwrap();
+        let mut keys: Vec<_> = keys.iter().collect();
+        keys.sort_by_key(|(key, _)| key.as_str());
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn Fn(&KeySpend) -> Option<f64>| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (key, spend) in &keys {
+                if let Some(value) = value(spend) {
+                    let _ = writeln!(out, "{}{{key=\"{}\"}} {}", name, key, value);
+                }
+            }
+        };
+        metric(
+            "deepclaude_key_spend_usd",
+            "gauge",
+            "Cost recorded per key in the current hour.",
+            &|spend| Some(spend.hours.get(&hour).copied().unwrap_or_default()),
+        );
+        metric(
+            "deepclaude_key_spend_baseline_usd",
+            "gauge",
+            "Average hourly cost per key over the baseline window.",
+            &|spend| spend.baseline(hour, &self.config),
+        );
+        metric(
+            "deepclaude_spend_alerts_total",
+            "counter",
+            "Spend alerts raised per key.",
+            &|spend| Some(spend.alerts as f64),
+        );
+        out
+    }
+}
+
+fn key_of(record: &UsageRecord) -> &str {
+    record.key_name.as_deref().unwrap_or(ANONYMOUS)
+}
+
+fn hour_of(time: DateTime<Utc>) -> i64 {
+    time.timestamp().div_euclid(3600)
+}
+
+fn add<'a>(keys: &'a mut HashMap<String, KeySpend>, key: &str, time: DateTime<Utc>, cost: f64) -> &'a mut KeySpend {
+    let hour = hour_of(time);
+    let spend = keys.entry(key.to_string()).or_insert_with(|| KeySpend {
+        first_hour: hour,
+        ..KeySpend::default()
+    });
+    spend.first_hour = spend.first_hour.min(hour);
+    *spend.hours.entry(hour).or_default() += cost;
+    spend
+}
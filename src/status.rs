@@ -0,0 +1,95 @@
+//! Upstream health summary for status dashboards.
+//!
+//! - `GET /status` - health of the models and hosts called recently
+//!
+//! Each model the stages called since startup is listed once for streaming
+//! and once for non-streaming calls, with its state, the error rate and
+//! median latency over its latest calls (see [`crate::clients::stats`]),
+//! and when it last succeeded and failed. Requests are never blocked on
+//! this state; it only summarizes what the calls observed:
+//!
+//! - `down` after `DOWN_AFTER_FAILURES` failures in a row
+//! - `degraded` when at least `DEGRADED_ERROR_RATE` of the latest calls failed
+//! - `operational` otherwise
+//!
+//! The top-level `status` is the worst state of any model. The streaming
+//! speed of each upstream host, as exported at `GET /metrics`, is listed
+//! under `providers`. Like `/v1/capabilities`, the document is public and
+//! holds nothing secret, so it can be embedded in a dashboard.
+
+use crate::clients::stats::{self, CallStats};
+use axum::Json;
+use serde::Serialize;
+use serde_json::json;
+
+/// Failures in a row after which a model counts as down.
+const DOWN_AFTER_FAILURES: usize = 3;
+/// Share of failed recent calls from which a model counts as degraded.
+const DEGRADED_ERROR_RATE: f64 = 0.2;
+
+/// Health of a model, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum State {
+    Operational,
+    Degraded,
+    Down,
+}
+
+fn state(stats: &CallStats) -> State {
+    if stats.consecutive_failures >= DOWN_AFTER_FAILURES {
+        State::Down
+    } else if stats.error_rate >= DEGRADED_ERROR_RATE {
+        State::Degraded
+    } else {
+        State::Operational
+    }
+}
+
+/// Summarizes the health of the upstream models and hosts.
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "operations",
+    security(()),
+    responses((status = 200, description = "Per-model health and per-host streaming speed", body = Object))
+)]
+pub async fn status() -> Json<serde_json::Value> {
+    let calls = stats::all_call_stats();
+    let overall = calls.values().map(state).max().unwrap_or(State::Operational);
+    let models: Vec<_> = calls
+        .iter()
+        .map(|((model, streaming), stats)| {
+            json!({
+                "model": model,
+                "streaming": streaming,
+                "state": state(stats),
+                "calls": stats.calls,
+                "error_rate": stats.error_rate,
+                "p50_latency_ms": stats.p50.map(|p50| p50.as_millis() as u64),
+                "consecutive_failures": stats.consecutive_failures,
+                "last_success": stats.last_success,
+                "last_failure": stats.last_failure,
+            })
+        })
+        .collect();
+    let providers: Vec<_> = stats::snapshot()
+        .into_iter()
+        .map(|((stage, host), stats)| {
+            json!({
+                "stage": stage,
+                "host": host,
+                "ttft_seconds": stats.ttft_seconds,
+                "tokens_per_second": stats.tokens_per_second,
+                "streams": stats.streams,
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "status": overall,
+        "updated_at": chrono::Utc::now(),
+        "models": models,
+        "providers": providers,
+    }))
+}
@@ -0,0 +1,206 @@
+//! Usage records in JSON Lines files, one per month, and issued keys in a
+//! JSON file.
+
+use super::{KeyBackend, Purge, RecordStream, UsageSink};
+use crate::{keys::VirtualKey, usage::UsageRecord};
+use chrono::{Datelike, NaiveDate};
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+/// Usage records appended to `<dir>/YYYY-MM.jsonl`.
+#[derive(Debug)]
+pub struct FileUsage {
+    dir: Arc<PathBuf>,
+    /// Serializes appends so concurrent batches never interleave.
+    write: Arc<Mutex<()>>,
+}
+
+impl FileUsage {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: Arc::new(PathBuf::from(dir)),
+            write: Arc::new(Mutex::new(())),
+        }
+    }
+}
+
+impl UsageSink for FileUsage {
+    fn append<'a>(&'a self, records: &'a [UsageRecord]) -> BoxFuture<'a, anyhow::Result<()>> {
+        let dir = self.dir.clone();
+        let write = self.write.clone();
+        let records = records.to_vec();
+        // 在阻塞线程上写文件，每个月份的记录一次写入
+        let task = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let mut by_month: BTreeMap<(i32, u32), String> = BTreeMap::new();
+            for record in &records {
+                let lines = by_month.entry((record.timestamp.year(), record.timestamp.month())).or_default();
+                lines.push_str(&serde_json::to_string(record)?);
+                lines.push('\n');
+            }
+            std::fs::create_dir_all(dir.as_path())?;
+            let _guard = write.lock().unwrap();
+            for ((year, month), lines) in by_month {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(month_file(&dir, year, month))?
+                    .write_all(lines.as_bytes())?;
+            }
+            Ok(())
+        });
+        async move { task.await? }.boxed()
+    }
+
+    fn read(&self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> RecordStream {
+        let dir = self.dir.clone();
+        Box::pin(async_stream::try_stream! {
+            for (year, month) in months(&dir)? {
+                let first = NaiveDate::from_ymd_opt(year, month, 1).unwrap_or_default();
+                let last = first.checked_add_months(chrono::Months::new(1)).unwrap_or(first).pred_opt().unwrap_or(first);
+                if from.is_some_and(|from| last < from) || to.is_some_and(|to| first > to) {
+                    continue;
+                }
+                // 逐行读取，不把整月的记录放进内存
+                let file = tokio::fs::File::open(month_file(&dir, year, month)).await?;
+                let mut lines = BufReader::new(file).lines();
+                while let Some(line) = lines.next_line().await? {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let record: UsageRecord = match serde_json::from_str(&line) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            tracing::warn!("跳过无法解析的用量记录: {}", e);
+                            continue;
+                        }
+                    };
+                    let day = record.timestamp.date_naive();
+                    if from.is_none_or(|from| day >= from) && to.is_none_or(|to| day <= to) {
+                        yield record;
+                    }
+                }
+            }
+        })
+    }
+
+    fn purge<'a>(&'a self, purge: &'a Purge) -> BoxFuture<'a, anyhow::Result<u64>> {
+        let dir = self.dir.clone();
+        let write = self.write.clone();
+        let purge = purge.clone();
+        let task = tokio::task::spawn_blocking(move || -> anyhow::Result<u64> {
+            let _guard = write.lock().unwrap();
+            let mut removed = 0;
+            for (year, month) in months(&dir)? {
+                let first = NaiveDate::from_ymd_opt(year, month, 1).unwrap_or_default();
+                if matches!(purge, Purge::Before(date) if first >= date) {
+                    continue;
+                }
+                // 先写临时文件再替换，重写到一半时不会丢失保留的记录
+                let path = month_file(&dir, year, month);
+                let mut kept = String::new();
+                for line in std::fs::read_to_string(&path)?.lines() {
+                    let matched = serde_json::from_str::<UsageRecord>(line).is_ok_and(|record| purge.matches(&record));
+                    if matched {
+                        removed += 1;
+                    } else if !line.trim().is_empty() {
+                        kept.push_str(line);
+                        kept.push('\n');
+                    }
+                }
+                if kept.is_empty() {
+                    std::fs::remove_file(&path)?;
+                } else {
+                    let temporary = path.with_extension("tmp");
+                    std::fs::write(&temporary, kept)?;
+                    std::fs::rename(&temporary, &path)?;
+                }
+            }
+            Ok(removed)
+        });
+        async move { task.await? }.boxed()
+    }
+}
+
+/// Lists the months with a usage file, oldest first.
+fn months(dir: &Path) -> anyhow::Result<Vec<(i32, u32)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut months: Vec<(i32, u32)> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let (year, month) = name.strip_suffix(".jsonl")?.split_once('-')?;
+            Some((year.parse().ok()?, month.parse().ok()?))
+        })
+        .collect();
+    months.sort();
+    Ok(months)
+}
+
+fn month_file(dir: &Path, year: i32, month: u32) -> PathBuf {
+    dir.join(format!("{:04}-{:02}.jsonl", year, month))
+}
+
+/// The keys configured in `[[keys]]`, and the issued ones in a JSON file.
+#[derive(Debug)]
+pub struct FileKeys {
+    configured: Vec<VirtualKey>,
+    path: PathBuf,
+    /// Serializes rewrites of the file.
+    write: Mutex<()>,
+}
+
+impl FileKeys {
+    pub fn new(configured: Vec<VirtualKey>, path: &str) -> Self {
+        Self {
+            configured,
+            path: PathBuf::from(path),
+            write: Mutex::new(()),
+        }
+    }
+
+    fn issued(&self) -> anyhow::Result<Vec<VirtualKey>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(json) => Ok(serde_json::from_str(&json)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Rewrites the file with a change applied to the issued keys.
+    fn update(&self, change: impl FnOnce(&mut Vec<VirtualKey>)) -> anyhow::Result<()> {
+        let _guard = self.write.lock().unwrap();
+        let mut keys = self.issued()?;
+        change(&mut keys);
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // 先写临时文件再替换，写到一半时不会丢失已有的密钥
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_string_pretty(&keys)?)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+impl KeyBackend for FileKeys {
+    fn load(&self) -> BoxFuture<'_, anyhow::Result<Vec<VirtualKey>>> {
+        let keys = self.issued().map(|issued| self.configured.iter().cloned().chain(issued).collect());
+        futures::future::ready(keys).boxed()
+    }
+
+    fn insert<'a>(&'a self, key: &'a VirtualKey) -> BoxFuture<'a, anyhow::Result<()>> {
+        futures::future::ready(self.update(|keys| keys.push(key.clone()))).boxed()
+    }
+
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        futures::future::ready(self.update(|keys| keys.retain(|key| key.id.as_deref() != Some(id)))).boxed()
+    }
+}
@@ -0,0 +1,119 @@
+//! In-memory storage.
+
+use super::{KeyBackend, KvCache, Purge, RecordStream, UsageSink};
+use crate::{keys::VirtualKey, usage::UsageRecord};
+use chrono::NaiveDate;
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Usage records kept in memory.
+#[derive(Debug, Default)]
+pub struct MemoryUsage {
+    records: Mutex<Vec<UsageRecord>>,
+}
+
+impl UsageSink for MemoryUsage {
+    fn append<'a>(&'a self, records: &'a [UsageRecord]) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.records.lock().unwrap().extend_from_slice(records);
+        futures::future::ready(Ok(())).boxed()
+    }
+
+    fn read(&self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> RecordStream {
+        let records: Vec<_> = self
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| {
+                let day = record.timestamp.date_naive();
+                from.is_none_or(|from| day >= from) && to.is_none_or(|to| day <= to)
+            })
+            .cloned()
+            .map(Ok)
+            .collect();
+        Box::pin(futures::stream::iter(records))
+    }
+
+    fn purge<'a>(&'a self, purge: &'a Purge) -> BoxFuture<'a, anyhow::Result<u64>> {
+        let mut records = self.records.lock().unwrap();
+        let before = records.len();
+        records.retain(|record| !purge.matches(record));
+        futures::future::ready(Ok((before - records.len()) as u64)).boxed()
+    }
+}
+
+/// Cache entries kept in memory.
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    entries: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl KvCache for MemoryCache {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<Option<String>>> {
+        let value = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(value, _)| value.clone());
+        futures::future::ready(Ok(value)).boxed()
+    }
+
+    fn put<'a>(&'a self, key: &'a str, value: String, ttl: Duration) -> BoxFuture<'a, anyhow::Result<()>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (_, expires)| *expires > now);
+        entries.insert(key.to_string(), (value, now + ttl));
+        futures::future::ready(Ok(())).boxed()
+    }
+
+    fn scan<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<(String, String)>>> {
+        let now = Instant::now();
+        let entries = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, (_, expires))| key.starts_with(prefix) && *expires > now)
+            .map(|(key, (value, _))| (key.clone(), value.clone()))
+            .collect();
+        futures::future::ready(Ok(entries)).boxed()
+    }
+
+    fn remove_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<u64>> {
+        let now = Instant::now();
+        let mut removed = 0;
+        self.entries.lock().unwrap().retain(|key, (_, expires)| {
+            let matched = key.starts_with(prefix);
+            if matched && *expires > now {
+                removed += 1;
+            }
+            !matched
+        });
+        futures::future::ready(Ok(removed)).boxed()
+    }
+}
+
+/// The keys configured in `[[keys]]`; issued keys only live in the key
+/// store.
+#[derive(Debug)]
+pub struct StaticKeys(pub Vec<VirtualKey>);
+
+impl KeyBackend for StaticKeys {
+    fn load(&self) -> BoxFuture<'_, anyhow::Result<Vec<VirtualKey>>> {
+        futures::future::ready(Ok(self.0.clone())).boxed()
+    }
+
+    fn insert<'a>(&'a self, _key: &'a VirtualKey) -> BoxFuture<'a, anyhow::Result<()>> {
+        futures::future::ready(Ok(())).boxed()
+    }
+
+    fn remove<'a>(&'a self, _id: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        futures::future::ready(Ok(())).boxed()
+    }
+}
@@ -0,0 +1,150 @@
+//! Pluggable storage of the stateful subsystems.
+//!
+//! Usage records, cached responses, session and reasoning state, and
+//! virtual keys are kept behind the [`UsageSink`], [`KvCache`] and
+//! [`KeyBackend`] traits, so a small deployment needs no database while a
+//! larger one can share this state between replicas. `[storage] backend` picks the implementation:
+//!
+//! - `file` (default): usage records in a JSON Lines file per month under
+//!   `[usage] dir`, the cache in memory, and keys from `[[keys]]` plus the
+//!   issued ones in `[storage] keys_file`
+//! - `memory`: usage records, the cache and issued keys in memory, lost on
+//!   restart, and keys from `[[keys]]`
+//! - `sqlite`: usage records, the cache and further keys in a SQLite
+//!   database file at `[storage] url`, alongside the keys from `[[keys]]`;
+//!   only in builds with the `sqlite` cargo feature
+//! - `postgres`: usage records, the cache and further keys in PostgreSQL
+//!   tables at `[storage] url`, alongside the keys from `[[keys]]`; only
+//!   in builds with the `postgres` cargo feature
+
+pub mod file;
+pub mod memory;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use crate::{
+    config::{Config, StorageBackend},
+    keys::VirtualKey,
+    usage::UsageRecord,
+};
+#[cfg(feature = "postgres")]
+use crate::secrets;
+use chrono::NaiveDate;
+use futures::{future::BoxFuture, stream::BoxStream};
+use std::{sync::Arc, time::Duration};
+
+/// Usage records read from a sink, oldest first.
+pub type RecordStream = BoxStream<'static, anyhow::Result<UsageRecord>>;
+
+/// Which usage records a purge removes.
+#[derive(Debug, Clone)]
+pub enum Purge {
+    /// The records made before a date.
+    Before(NaiveDate),
+    /// Every record of a tenant.
+    Tenant(String),
+}
+
+impl Purge {
+    pub fn matches(&self, record: &UsageRecord) -> bool {
+        match self {
+            Purge::Before(date) => record.timestamp.date_naive() < *date,
+            Purge::Tenant(tenant) => record.tenant.as_ref() == Some(tenant),
+        }
+    }
+}
+
+/// Where usage records are kept.
+pub trait UsageSink: Send + Sync {
+    /// Appends a batch of records.
+    fn append<'a>(&'a self, records: &'a [UsageRecord]) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Streams the records made between two dates, inclusive.
+    fn read(&self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> RecordStream;
+
+    /// Removes the records matched by `purge`, returning how many.
+    fn purge<'a>(&'a self, purge: &'a Purge) -> BoxFuture<'a, anyhow::Result<u64>>;
+}
+
+/// A key-value store whose entries expire.
+pub trait KvCache: Send + Sync {
+    /// Returns the value of an unexpired entry.
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<Option<String>>>;
+
+    /// Stores a value for `ttl`, replacing any entry of the key.
+    fn put<'a>(&'a self, key: &'a str, value: String, ttl: Duration) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Returns the unexpired entries whose key starts with `prefix`.
+    fn scan<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<(String, String)>>>;
+
+    /// Removes the unexpired entries whose key starts with `prefix`,
+    /// returning how many.
+    fn remove_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<u64>>;
+}
+
+/// Where virtual keys are loaded from.
+pub trait KeyBackend: Send + Sync {
+    fn load(&self) -> BoxFuture<'_, anyhow::Result<Vec<VirtualKey>>>;
+
+    /// Keeps a key issued through `POST /v1/keys`.
+    fn insert<'a>(&'a self, key: &'a VirtualKey) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Removes an issued key by its id.
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// The storage of each subsystem.
+pub struct Storage {
+    pub usage: Arc<dyn UsageSink>,
+    pub cache: Arc<dyn KvCache>,
+    pub keys: Arc<dyn KeyBackend>,
+}
+
+impl Storage {
+    /// Opens the storage configured in `[storage]`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend cannot be reached, or is not built in.
+    pub async fn open(config: &Config) -> anyhow::Result<Self> {
+        match config.storage.backend {
+            StorageBackend::File => Ok(Self {
+                usage: Arc::new(file::FileUsage::new(&config.usage.dir)),
+                cache: Arc::new(memory::MemoryCache::default()),
+                keys: Arc::new(file::FileKeys::new(config.keys.clone(), &config.storage.keys_file)),
+            }),
+            StorageBackend::Memory => Ok(Self {
+                usage: Arc::new(memory::MemoryUsage::default()),
+                cache: Arc::new(memory::MemoryCache::default()),
+                keys: Arc::new(memory::StaticKeys(config.keys.clone())),
+            }),
+            #[cfg(feature = "sqlite")]
+            StorageBackend::Sqlite => {
+                let store = Arc::new(sqlite::Sqlite::open(&config.storage.url, config.keys.clone()).await?);
+                tracing::info!("已打开SQLite存储: {}", config.storage.url);
+                Ok(Self {
+                    usage: store.clone(),
+                    cache: store.clone(),
+                    keys: store,
+                })
+            }
+            #[cfg(not(feature = "sqlite"))]
+            StorageBackend::Sqlite => anyhow::bail!("当前构建未启用sqlite特性，无法使用SQLite存储"),
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres => {
+                let url = secrets::expose(&config.storage.url).unwrap_or_default();
+                let store = Arc::new(postgres::Postgres::connect(&url, config.keys.clone()).await?);
+                tracing::info!("已连接PostgreSQL存储");
+                Ok(Self {
+                    usage: store.clone(),
+                    cache: store.clone(),
+                    keys: store,
+                })
+            }
+            #[cfg(not(feature = "postgres"))]
+            StorageBackend::Postgres => anyhow::bail!("当前构建未启用postgres特性，无法使用PostgreSQL存储"),
+        }
+    }
+}
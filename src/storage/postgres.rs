@@ -0,0 +1,216 @@
+//! PostgreSQL storage.
+//!
+//! Tables are created on connect if missing:
+//!
+//! - `deepclaude_usage (id text primary key, created_at timestamptz, record jsonb)`
+//! - `deepclaude_cache (key text primary key, value text, expires_at timestamptz)`
+//! - `deepclaude_keys (key jsonb)`, one virtual key per row in the
+//!   `[[keys]]` format, loaded in addition to the configured keys; issued
+//!   keys are added here
+
+use super::{KeyBackend, KvCache, Purge, RecordStream, UsageSink};
+use crate::{keys::VirtualKey, usage::UsageRecord};
+use chrono::NaiveDate;
+use futures::{future::BoxFuture, FutureExt, TryStreamExt};
+use std::{sync::Arc, time::Duration};
+use tokio_postgres::{Client, NoTls};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS deepclaude_usage (
+    id text PRIMARY KEY,
+    created_at timestamptz NOT NULL,
+    record jsonb NOT NULL
+);
+CREATE INDEX IF NOT EXISTS deepclaude_usage_created_at ON deepclaude_usage (created_at);
+CREATE TABLE IF NOT EXISTS deepclaude_cache (
+    key text PRIMARY KEY,
+    value text NOT NULL,
+    expires_at timestamptz NOT NULL
+);
+CREATE TABLE IF NOT EXISTS deepclaude_keys (
+    key jsonb NOT NULL
+);
+";
+
+/// A PostgreSQL connection holding usage, cache and keys.
+pub struct Postgres {
+    client: Arc<Client>,
+    configured_keys: Vec<VirtualKey>,
+}
+
+impl Postgres {
+    /// Connects and creates the tables.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database cannot be reached or the tables
+    /// cannot be created.
+    pub async fn connect(url: &str, configured_keys: Vec<VirtualKey>) -> anyhow::Result<Self> {
+        let (client, driver) = tokio_postgres::connect(url, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = driver.await {
+                tracing::error!("PostgreSQL存储连接已断开: {}", e);
+            }
+        });
+        client.batch_execute(SCHEMA).await?;
+        Ok(Self {
+            client: Arc::new(client),
+            configured_keys,
+        })
+    }
+}
+
+impl UsageSink for Postgres {
+    fn append<'a>(&'a self, records: &'a [UsageRecord]) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            // 整批记录作为一个JSON数组在一条语句中插入
+            let json = serde_json::to_string(records)?;
+            self.client
+                .execute(
+                    "INSERT INTO deepclaude_usage (id, created_at, record) \
+                     SELECT record->>'id', (record->>'timestamp')::timestamptz, record \
+                     FROM jsonb_array_elements($1::text::jsonb) AS record ON CONFLICT (id) DO NOTHING",
+                    &[&json],
+                )
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn read(&self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> RecordStream {
+        let client = self.client.clone();
+        let from = from.map(|date| date.to_string());
+        let to = to.map(|date| date.to_string());
+        Box::pin(async_stream::try_stream! {
+            let rows = client
+                .query_raw(
+                    "SELECT record::text FROM deepclaude_usage \
+                     WHERE ($1::text IS NULL OR (created_at AT TIME ZONE 'UTC')::date >= $1::text::date) \
+                     AND ($2::text IS NULL OR (created_at AT TIME ZONE 'UTC')::date <= $2::text::date) \
+                     ORDER BY created_at",
+                    [from, to],
+                )
+                .await?;
+            futures::pin_mut!(rows);
+            while let Some(row) = rows.try_next().await? {
+                let json: String = row.get(0);
+                yield serde_json::from_str::<UsageRecord>(&json)?;
+            }
+        })
+    }
+
+    fn purge<'a>(&'a self, purge: &'a Purge) -> BoxFuture<'a, anyhow::Result<u64>> {
+        async move {
+            let removed = match purge {
+                Purge::Before(date) => {
+                    self.client
+                        .execute(
+                            "DELETE FROM deepclaude_usage WHERE (created_at AT TIME ZONE 'UTC')::date < $1::text::date",
+                            &[&date.to_string()],
+                        )
+                        .await?
+                }
+                Purge::Tenant(tenant) => {
+                    self.client
+                        .execute("DELETE FROM deepclaude_usage WHERE record->>'tenant' = $1", &[tenant])
+                        .await?
+                }
+            };
+            Ok(removed)
+        }
+        .boxed()
+    }
+}
+
+impl KvCache for Postgres {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<Option<String>>> {
+        async move {
+            let row = self
+                .client
+                .query_opt("SELECT value FROM deepclaude_cache WHERE key = $1 AND expires_at > now()", &[&key])
+                .await?;
+            Ok(row.map(|row| row.get(0)))
+        }
+        .boxed()
+    }
+
+    fn put<'a>(&'a self, key: &'a str, value: String, ttl: Duration) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let ttl = format!("{} seconds", ttl.as_secs());
+            self.client
+                .execute(
+                    "INSERT INTO deepclaude_cache (key, value, expires_at) VALUES ($1, $2, now() + $3::text::interval) \
+                     ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+                    &[&key, &value, &ttl],
+                )
+                .await?;
+            // 顺带清理过期条目
+            self.client.execute("DELETE FROM deepclaude_cache WHERE expires_at <= now()", &[]).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn scan<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<(String, String)>>> {
+        async move {
+            let rows = self
+                .client
+                .query(
+                    "SELECT key, value FROM deepclaude_cache WHERE starts_with(key, $1) AND expires_at > now() ORDER BY key",
+                    &[&prefix],
+                )
+                .await?;
+            Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+        }
+        .boxed()
+    }
+
+    fn remove_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<u64>> {
+        async move {
+            // 前缀按字面匹配，不解释LIKE通配符；过期条目由put顺带清理
+            let removed = self
+                .client
+                .execute(
+                    "DELETE FROM deepclaude_cache WHERE starts_with(key, $1) AND expires_at > now()",
+                    &[&prefix],
+                )
+                .await?;
+            Ok(removed)
+        }
+        .boxed()
+    }
+}
+
+impl KeyBackend for Postgres {
+    fn load(&self) -> BoxFuture<'_, anyhow::Result<Vec<VirtualKey>>> {
+        async move {
+            let mut keys = self.configured_keys.clone();
+            for row in self.client.query("SELECT key::text FROM deepclaude_keys", &[]).await? {
+                let json: String = row.get(0);
+                keys.push(serde_json::from_str(&json)?);
+            }
+            Ok(keys)
+        }
+        .boxed()
+    }
+
+    fn insert<'a>(&'a self, key: &'a VirtualKey) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let json = serde_json::to_string(key)?;
+            self.client
+                .execute("INSERT INTO deepclaude_keys (key) VALUES ($1::text::jsonb)", &[&json])
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            self.client.execute("DELETE FROM deepclaude_keys WHERE key->>'id' = $1", &[&id]).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
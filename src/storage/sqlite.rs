@@ -0,0 +1,265 @@
+//! SQLite storage.
+//!
+//! Tables are created on open if missing, in the database file at
+//! `[storage] url`:
+//!
+//! - `deepclaude_usage (id text primary key, created_at text, record text)`,
+//!   `created_at` in RFC 3339 UTC so that it sorts by time
+//! - `deepclaude_cache (key text primary key, value text, expires_at integer)`,
+//!   `expires_at` in Unix milliseconds
+//! - `deepclaude_keys (key text)`, one virtual key per row in the `[[keys]]`
+//!   format, loaded in addition to the configured keys; issued keys are
+//!   added here
+//!
+//! SQLite calls block, so they run on the blocking thread pool, one at a
+//! time on a single connection.
+
+use super::{KeyBackend, KvCache, Purge, RecordStream, UsageSink};
+use crate::{keys::VirtualKey, usage::UsageRecord};
+use chrono::{NaiveDate, SecondsFormat, Utc};
+use futures::{future::BoxFuture, FutureExt};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio_stream::wrappers::ReceiverStream;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS deepclaude_usage (
+    id text PRIMARY KEY,
+    created_at text NOT NULL,
+    record text NOT NULL
+);
+CREATE INDEX IF NOT EXISTS deepclaude_usage_created_at ON deepclaude_usage (created_at);
+CREATE TABLE IF NOT EXISTS deepclaude_cache (
+    key text PRIMARY KEY,
+    value text NOT NULL,
+    expires_at integer NOT NULL
+);
+CREATE TABLE IF NOT EXISTS deepclaude_keys (
+    key text NOT NULL
+);
+";
+
+/// A SQLite database holding usage, cache and keys.
+pub struct Sqlite {
+    connection: Arc<Mutex<Connection>>,
+    configured_keys: Vec<VirtualKey>,
+}
+
+impl Sqlite {
+    /// Opens the database file, creating it and the tables if missing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened or the tables cannot be
+    /// created.
+    pub async fn open(path: &str, configured_keys: Vec<VirtualKey>) -> anyhow::Result<Self> {
+        let path = path.to_string();
+        let connection = tokio::task::spawn_blocking(move || -> anyhow::Result<Connection> {
+            let connection = Connection::open(path)?;
+            // 多个副本共用一个文件时，写锁冲突等待而不是立即失败
+            connection.busy_timeout(Duration::from_secs(5))?;
+            connection.execute_batch(SCHEMA)?;
+            Ok(connection)
+        })
+        .await??;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            configured_keys,
+        })
+    }
+
+    /// Runs `call` on the connection on the blocking thread pool.
+    fn with<T, F>(&self, call: F) -> BoxFuture<'static, anyhow::Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        async move {
+            tokio::task::spawn_blocking(move || call(&mut connection.lock().unwrap_or_else(PoisonError::into_inner))).await?
+        }
+        .boxed()
+    }
+}
+
+fn now_ms() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+/// SQL matching keys that start with `?1` literally, without LIKE wildcards.
+const PREFIX: &str = "substr(key, 1, length(?1)) = ?1";
+
+impl UsageSink for Sqlite {
+    fn append<'a>(&'a self, records: &'a [UsageRecord]) -> BoxFuture<'a, anyhow::Result<()>> {
+        let rows = records
+            .iter()
+            .map(|record| {
+                let created_at = record.timestamp.to_rfc3339_opts(SecondsFormat::Micros, true);
+                Ok((record.id.clone(), created_at, serde_json::to_string(record)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>();
+        async move {
+            let rows = rows?;
+            self.with(move |connection| {
+                // 整批记录在一个事务中插入
+                let transaction = connection.transaction()?;
+                {
+                    let mut insert = transaction.prepare(
+                        "INSERT INTO deepclaude_usage (id, created_at, record) VALUES (?1, ?2, ?3) \
+                         ON CONFLICT (id) DO NOTHING",
+                    )?;
+                    for (id, created_at, record) in &rows {
+                        insert.execute(params![id, created_at, record])?;
+                    }
+                }
+                transaction.commit()?;
+                Ok(())
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn read(&self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> RecordStream {
+        let connection = self.connection.clone();
+        let from = from.map(|date| date.to_string());
+        let to = to.map(|date| date.to_string());
+        // 在阻塞线程中逐行读取，经通道交给流，不把全部记录读入内存
+        let (tx, rx) = tokio::sync::mpsc::channel::<anyhow::Result<UsageRecord>>(64);
+        tokio::task::spawn_blocking(move || {
+            let result = (|| -> anyhow::Result<()> {
+                let connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut select = connection.prepare(
+                    "SELECT record FROM deepclaude_usage \
+                     WHERE (?1 IS NULL OR substr(created_at, 1, 10) >= ?1) \
+                     AND (?2 IS NULL OR substr(created_at, 1, 10) <= ?2) \
+                     ORDER BY created_at",
+                )?;
+                let mut rows = select.query(params![from, to])?;
+                while let Some(row) = rows.next()? {
+                    let json: String = row.get(0)?;
+                    let record = serde_json::from_str::<UsageRecord>(&json).map_err(anyhow::Error::from);
+                    if tx.blocking_send(record).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            })();
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(e));
+            }
+        });
+        Box::pin(ReceiverStream::new(rx))
+    }
+
+    fn purge<'a>(&'a self, purge: &'a Purge) -> BoxFuture<'a, anyhow::Result<u64>> {
+        let purge = purge.clone();
+        self.with(move |connection| {
+            let removed = match purge {
+                Purge::Before(date) => connection.execute(
+                    "DELETE FROM deepclaude_usage WHERE substr(created_at, 1, 10) < ?1",
+                    params![date.to_string()],
+                )?,
+                Purge::Tenant(tenant) => connection.execute(
+                    "DELETE FROM deepclaude_usage WHERE json_extract(record, '$.tenant') = ?1",
+                    params![tenant],
+                )?,
+            };
+            Ok(removed as u64)
+        })
+    }
+}
+
+impl KvCache for Sqlite {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, anyhow::Result<Option<String>>> {
+        let key = key.to_string();
+        self.with(move |connection| {
+            let value = connection
+                .query_row(
+                    "SELECT value FROM deepclaude_cache WHERE key = ?1 AND expires_at > ?2",
+                    params![key, now_ms()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(value)
+        })
+    }
+
+    fn put<'a>(&'a self, key: &'a str, value: String, ttl: Duration) -> BoxFuture<'a, anyhow::Result<()>> {
+        let key = key.to_string();
+        self.with(move |connection| {
+            let now = now_ms();
+            connection.execute(
+                "INSERT INTO deepclaude_cache (key, value, expires_at) VALUES (?1, ?2, ?3) \
+                 ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+                params![key, value, now + ttl.as_millis() as i64],
+            )?;
+            // 顺带清理过期条目
+            connection.execute("DELETE FROM deepclaude_cache WHERE expires_at <= ?1", params![now])?;
+            Ok(())
+        })
+    }
+
+    fn scan<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<(String, String)>>> {
+        let prefix = prefix.to_string();
+        self.with(move |connection| {
+            let mut select = connection.prepare(&format!(
+                "SELECT key, value FROM deepclaude_cache WHERE {} AND expires_at > ?2 ORDER BY key",
+                PREFIX
+            ))?;
+            let entries = select
+                .query_map(params![prefix, now_ms()], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(entries)
+        })
+    }
+
+    fn remove_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<u64>> {
+        let prefix = prefix.to_string();
+        self.with(move |connection| {
+            // 过期条目由put顺带清理
+            let removed = connection.execute(
+                &format!("DELETE FROM deepclaude_cache WHERE {} AND expires_at > ?2", PREFIX),
+                params![prefix, now_ms()],
+            )?;
+            Ok(removed as u64)
+        })
+    }
+}
+
+impl KeyBackend for Sqlite {
+    fn load(&self) -> BoxFuture<'_, anyhow::Result<Vec<VirtualKey>>> {
+        let mut keys = self.configured_keys.clone();
+        self.with(move |connection| {
+            let mut select = connection.prepare("SELECT key FROM deepclaude_keys")?;
+            for json in select.query_map([], |row| row.get::<_, String>(0))? {
+                keys.push(serde_json::from_str(&json?)?);
+            }
+            Ok(keys)
+        })
+    }
+
+    fn insert<'a>(&'a self, key: &'a VirtualKey) -> BoxFuture<'a, anyhow::Result<()>> {
+        let json = serde_json::to_string(key);
+        async move {
+            let json = json?;
+            self.with(move |connection| {
+                connection.execute("INSERT INTO deepclaude_keys (key) VALUES (?1)", params![json])?;
+                Ok(())
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        let id = id.to_string();
+        self.with(move |connection| {
+            connection.execute("DELETE FROM deepclaude_keys WHERE json_extract(key, '$.id') = ?1", params![id])?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,726 @@
+//! Supervised streaming tasks.
+//!
+//! - `POST /v1/chat/completions/{id}/cancel` - cancel a streaming response,
+//!   with the virtual key that opened it or an operator admin token
+//!
+//! Every streaming response runs as a task registered under its stream id
+//! (the `id` of its chunks) with its start time and pipeline stage. A
+//! supervisor awaits each task: a task that panics or is cancelled ends its
+//! stream with an error chunk instead of silently closing it, and is removed
+//! from the registry either way. The usage a cancelled or panicked stream
+//! ran up so far is still recorded and settled with the rate limiter. The
+//! registry backs the cancellation
+//! endpoint, the `GET /admin/streams` live view, `GET /debug/tasks` and
+//! graceful shutdown, which waits up to `[streams] shutdown_grace_secs` for
+//! running streams before cancelling them.
+//!
+//! With `[streams] max_stream_duration_secs` set, the supervisor also ends a
+//! stream that runs longer: the task is cancelled, the usage it ran up is
+//! recorded, and the client gets a final chunk with `finish_reason:
+//! "timeout"` and `[DONE]`, so a responder that holds its connection open
+//! without finishing cannot hold the client forever, or run unbilled.
+//!
+//! A stream that fails once its response has started, whether its task
+//! panics or an upstream or hook fails, ends with an error chunk: a final
+//! chunk with `finish_reason: "error"` and the OpenAI `error` object, then
+//! `[DONE]`, so SDK stream iterators finish and raise the error instead of
+//! choking on a bare error payload or waiting for more.
+//!
+//! Events reach the client through a channel of `[streams] channel_capacity`
+//! events. When it is full the client is reading slower than the upstream
+//! writes: heartbeats are dropped, since the client has data waiting anyway,
+//! and `[streams] slow_consumer` decides between pausing upstream reads and
+//! merging the pending text deltas into one event.
+//!
+//! Text deltas can also be merged on purpose to cut per-event overhead: with
+//! `[streams] coalesce_ms` or `coalesce_chars` set, consecutive deltas of the
+//! same kind are held until the merged text reaches that age or length. The
+//! limits are checked as deltas arrive, and held text is always sent before
+//! any other event.
+//!
+//! Some chat UIs render CJK text badly when it arrives a few characters at a
+//! time. With `[streams] flush = "clause"`, answer text is held until a
+//! clause or sentence ends (at CJK punctuation, a line break, or ASCII
+//! punctuation before a space) and sent up to that point, or sent whole
+//! once it has been held for `flush_ms`, even if the upstream has stalled
+//! and no further delta arrives. Reasoning deltas are not held.
+//!
+//! With `[streams] markdown_safe`, answer text is also never split inside a
+//! fenced code block or an inline code span, which several UIs render
+//! broken until it closes: text from the opening fence or backticks on is
+//! held until they close, or until `markdown_max_chars` are held.
+//!
+//! Compat profiles under `[streams.profiles.<name>]` set `flush`, `flush_ms`
+//! and `markdown_safe` for particular clients: the profile named by the
+//! `X-DeepClaude-Compat` header, or else the first, by name, whose
+//! `user_agent` the request's `User-Agent` contains.
+
+use crate::{
+    admin,
+    clients::providers,
+    config::{AdminRole, FlushMode, SlowConsumer, StreamsConfig},
+    error::{ApiError, ErrorDetails, ErrorResponse, Result},
+    handlers::AppState,
+    payloads,
+};
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap},
+    response::sse::Event,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::mpsc::{error::TrySendError, Sender},
+    task::AbortHandle,
+};
+use tracing::Instrument;
+
+/// Request header naming the compat profile of a stream.
+pub const COMPAT_HEADER: &str = "x-deepclaude-compat";
+
+/// Punctuation ending a clause or sentence of CJK text.
+const CJK_CLAUSE_ENDS: [char; 8] = ['。', '！', '？', '；', '，', '、', '：', '…'];
+
+/// Closing marks sent with the punctuation before them.
+const CLOSING_MARKS: [char; 8] = ['”', '’', '」', '』', '）', '》', ')', '"'];
+
+/// Builds the final chunk of a stream that failed after its response
+/// started, reporting `error` with `finish_reason: "error"`.
+pub fn error_chunk((id, created, model): (&str, i64, &str), error: &ErrorDetails) -> String {
+    json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [{ "index": 0, "delta": {}, "finish_reason": "error" }],
+        "error": error,
+    })
+    .to_string()
+}
+
+/// How a stream's answer text is held back.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Flushing {
+    /// How long answer text may be held for its clause to end.
+    pub clause: Option<Duration>,
+    /// Length, in characters, up to which answer text is held for a code
+    /// block or span to close.
+    pub code_cap: Option<usize>,
+}
+
+impl Flushing {
+    fn holds(&self) -> bool {
+        self.clause.is_some() || self.code_cap.is_some()
+    }
+}
+
+/// How the request's compat profile, or else `[streams]`, holds back
+/// answer text.
+pub fn flushing(config: &StreamsConfig, headers: &HeaderMap) -> Flushing {
+    let named = headers
+        .get(COMPAT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|name| config.profiles.get(name));
+    let user_agent = headers.get(header::USER_AGENT).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    let matched = || {
+        config
+            .profiles
+            .values()
+            .find(|profile| !profile.user_agent.is_empty() && user_agent.contains(&profile.user_agent))
+    };
+    let (flush, flush_ms, markdown_safe) = match named.or_else(matched) {
+        Some(profile) => (profile.flush, profile.flush_ms, profile.markdown_safe),
+        None => (config.flush, config.flush_ms, config.markdown_safe),
+    };
+    Flushing {
+        clause: (flush == FlushMode::Clause).then(|| Duration::from_millis(flush_ms)),
+        code_cap: markdown_safe.then_some(config.markdown_max_chars.max(1)),
+    }
+}
+
+/// Byte offsets just past each clause end in `text`.
+fn clause_ends(text: &str) -> Vec<usize> {
+    let mut ends = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        // 英文标点后跟空白才算结束，避免切开3.14这样的数字
+        let ends_clause = CJK_CLAUSE_ENDS.contains(&c)
+            || c == '\n'
+            || (matches!(c, '.' | '!' | '?' | ';' | ':' | ',') && chars.peek().is_some_and(|(_, next)| next.is_whitespace()));
+        if !ends_clause {
+            continue;
+        }
+        let mut end = i + c.len_utf8();
+        while let Some((j, mark)) = chars.next_if(|(_, mark)| CLOSING_MARKS.contains(mark)) {
+            end = j + mark.len_utf8();
+        }
+        ends.push(end);
+    }
+    ends
+}
+
+/// Where the answer text sent so far stands relative to markdown code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Code {
+    /// Character and length of the open code fence.
+    fence: Option<(char, usize)>,
+    /// Length of the backtick run that opened an inline code span.
+    span: Option<usize>,
+    line_start: bool,
+}
+
+impl Default for Code {
+    fn default() -> Self {
+        Self {
+            fence: None,
+            span: None,
+            line_start: true,
+        }
+    }
+}
+
+impl Code {
+    fn outside(&self) -> bool {
+        self.fence.is_none() && self.span.is_none()
+    }
+
+    /// Reads `text` on from this state. Returns the byte offsets at which
+    /// the text read so far is outside any code, and the state at the end.
+    /// Reading stops early where the rest may still turn into a fence or a
+    /// longer backtick run.
+    fn scan(mut self, text: &str) -> (Vec<usize>, Code) {
+        let mut outside = Vec::new();
+        let mut i = 0;
+        while i < text.len() {
+            let rest = &text[i..];
+            if self.line_start {
+                let after = rest.trim_start_matches(' ');
+                let indent = rest.len() - after.len();
+                let line = rest.find('\n');
+                if line.is_none() && after.is_empty() {
+                    break;
+                }
+                if let Some(marker) = after.chars().next().filter(|c| indent <= 3 && matches!(c, '`' | '~')) {
+                    let run = after.len() - after.trim_start_matches(marker).len();
+                    match line {
+                        // 行还没结束，无法判断是否是代码块围栏
+                        None if run >= 3 || run == after.len() => break,
+                        Some(line) if run >= 3 => {
+                            let info = rest[indent + run..line].trim();
+                            let fence_line = match self.fence {
+                                None => self.span.is_none() && !(marker == '`' && info.contains('`')),
+                                Some(_) => true,
+                            };
+                            if fence_line {
+                                match self.fence {
+                                    None => self.fence = Some((marker, run)),
+                                    Some((open, length)) if open == marker && run >= length && info.is_empty() => {
+                                        self.fence = None
+                                    }
+                                    Some(_) => {}
+                                }
+                                i += line + 1;
+                                if self.outside() {
+                                    outside.push(i);
+                                }
+                                continue;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                self.line_start = false;
+            }
+            let c = rest.chars().next().unwrap_or_default();
+            if c == '\n' {
+                self.line_start = true;
+            }
+            if self.fence.is_some() {
+                i += c.len_utf8();
+                continue;
+            }
+            if c == '`' {
+                let run = rest.len() - rest.trim_start_matches('`').len();
+                if run == rest.len() {
+                    // 反引号可能还没发完
+                    break;
+                }
+                self.span = match self.span {
+                    None => Some(run),
+                    Some(open) if open == run => None,
+                    open => open,
+                };
+                i += run;
+            } else {
+                i += c.len_utf8();
+            }
+            if self.outside() {
+                outside.push(i);
+            }
+        }
+        (outside, self)
+    }
+}
+
+/// Channel carrying a stream's events to the client.
+pub type EventSender = Sender<std::result::Result<Event, Infallible>>;
+
+/// Builds the event carrying a text delta.
+pub type ChunkBuilder = Box<dyn Fn(Delta, &str) -> String + Send>;
+
+/// Pipeline stage a stream is in.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamState {
+    /// Streaming the DeepSeek reasoning.
+    Reasoning,
+    /// Streaming Claude's answer.
+    Responding,
+    /// Checking, post-processing and sending the final answer.
+    Finishing,
+}
+
+/// A running stream as shown by `GET /admin/streams`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamInfo {
+    pub id: String,
+    pub started_at: DateTime<Utc>,
+    pub state: StreamState,
+    /// When the stream entered its current stage.
+    pub state_since: DateTime<Utc>,
+    /// Model named in the stream's chunks.
+    pub model: String,
+    pub mode: String,
+    /// Name of the virtual key the request was made with, if any.
+    pub key_name: Option<String>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: StreamInfo,
+    abort: AbortHandle,
+}
+
+/// Registry of the running streaming tasks.
+#[derive(Debug, Default)]
+pub struct StreamRegistry {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    max_duration: Option<Duration>,
+}
+
+impl StreamRegistry {
+    pub fn new(config: &StreamsConfig) -> Self {
+        Self {
+            entries: Arc::default(),
+            max_duration: (config.max_stream_duration_secs > 0)
+                .then(|| Duration::from_secs(config.max_stream_duration_secs)),
+        }
+    }
+
+    /// Runs a streaming task under supervision.
+    ///
+    /// `tx` is the stream's event channel, used to report a panic or
+    /// cancellation to the client. `settle` runs once the task has ended,
+    /// however it ended, before any final chunk is sent: a stream records
+    /// there the usage it ran up if its task did not get to.
+    pub fn spawn<F, S>(&self, info: StreamInfo, tx: EventSender, task: F, settle: S)
+    where
+        F: Future<Output = ()> + Send + 'static,
+        S: FnOnce() + Send + 'static,
+    {
+        let id = info.id.clone();
+        let chunk = (info.id.clone(), info.started_at.timestamp(), info.model.clone());
+        let timeout_chunk = json!({
+            "id": info.id,
+            "object": "chat.completion.chunk",
+            "created": info.started_at.timestamp(),
+            "model": info.model,
+            "choices": [{ "index": 0, "delta": {}, "finish_reason": "timeout" }],
+        });
+        // 流在自己的任务中运行，沿用请求的span并记下流id，日志才能按请求筛选
+        let span = tracing::info_span!("stream", stream_id = %id);
+        let mut handle = tokio::spawn(payloads::inherit(providers::inherit(task)).instrument(span));
+        self.entries.lock().unwrap().insert(
+            id.clone(),
+            Entry {
+                info,
+                abort: handle.abort_handle(),
+            },
+        );
+
+        let entries = self.entries.clone();
+        let max_duration = self.max_duration;
+        tokio::spawn(async move {
+            let outcome = match max_duration {
+                Some(limit) => match tokio::time::timeout(limit, &mut handle).await {
+                    Ok(outcome) => Some(outcome),
+                    Err(_) => {
+                        handle.abort();
+                        let _ = (&mut handle).await;
+                        None
+                    }
+                },
+                None => Some(handle.await),
+            };
+            entries.lock().unwrap().remove(&id);
+            if !matches!(outcome, Some(Ok(()))) {
+                // 超时终止、取消或异常退出的流没来得及记录用量，按已产生的用量记录
+                settle();
+            }
+            let (message, type_) = match outcome {
+                None => {
+                    tracing::warn!("流式响应{}超过最长时长，已终止", id);
+                    let _ = tx.send(Ok(Event::default().data(timeout_chunk.to_string()))).await;
+                    let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+                    return;
+                }
+                Some(Ok(())) => return,
+                Some(Err(e)) if e.is_cancelled() => {
+                    tracing::info!("流式响应{}已取消", id);
+                    ("Stream cancelled", "cancelled")
+                }
+                Some(Err(e)) => {
+                    tracing::error!("流式任务{}异常退出: {}", id, e);
+                    ("Internal server error", "internal_error")
+                }
+            };
+            let error = ErrorDetails {
+                message: message.to_string(),
+                type_: type_.to_string(),
+                param: None,
+                code: None,
+            };
+            let _ = tx.send(Ok(Event::default().data(error_chunk((&chunk.0, chunk.1, &chunk.2), &error)))).await;
+            let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+        });
+    }
+
+    /// Updates the stage shown for a stream.
+    pub fn set_state(&self, id: &str, state: StreamState) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(id) {
+            entry.info.state = state;
+            entry.info.state_since = Utc::now();
+        }
+    }
+
+    /// Lists the running streams, oldest first.
+    pub fn list(&self) -> Vec<StreamInfo> {
+        let mut streams: Vec<_> = self.entries.lock().unwrap().values().map(|entry| entry.info.clone()).collect();
+        streams.sort_by_key(|info| info.started_at);
+        streams
+    }
+
+    /// Cancels a stream; returns false if it is not running, or if
+    /// `key_name` is given and the stream was not opened with that key.
+    pub fn cancel(&self, id: &str, key_name: Option<&str>) -> bool {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(id) {
+            Some(entry) if key_name.is_none_or(|name| entry.info.key_name.as_deref() == Some(name)) => {
+                entry.abort.abort();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Waits up to `grace` for the running streams to finish, then cancels
+    /// the rest.
+    pub async fn drain(&self, grace: Duration) {
+        let deadline = Instant::now() + grace;
+        while !self.entries.lock().unwrap().is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let entries = self.entries.lock().unwrap();
+        if !entries.is_empty() {
+            tracing::warn!("关闭等待超时，取消{}个未完成的流式响应", entries.len());
+            for entry in entries.values() {
+                entry.abort.abort();
+            }
+        }
+    }
+}
+
+/// Kind of streamed text a delta event carries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delta {
+    Reasoning,
+    Content,
+}
+
+/// The client has gone away.
+#[derive(Debug, thiserror::Error)]
+#[error("客户端已断开连接")]
+pub struct Closed;
+
+/// Text deltas merged into one event.
+struct Pending {
+    kind: Delta,
+    text: String,
+    since: Instant,
+}
+
+/// Sends a stream's events, merging text deltas and applying the slow
+/// consumer policy.
+pub struct EventSink {
+    tx: EventSender,
+    policy: SlowConsumer,
+    max_age: Option<Duration>,
+    max_chars: Option<usize>,
+    flushing: Flushing,
+    /// Where the answer text sent so far stands relative to markdown code.
+    code: Code,
+    build: ChunkBuilder,
+    /// Text held back for merging or not yet taken by a slow client.
+    pending: Option<Pending>,
+}
+
+impl EventSink {
+    pub fn new(tx: EventSender, config: &StreamsConfig, build: ChunkBuilder) -> Self {
+        Self {
+            tx,
+            policy: config.slow_consumer,
+            max_age: (config.coalesce_ms > 0).then(|| Duration::from_millis(config.coalesce_ms)),
+            max_chars: (config.coalesce_chars > 0).then_some(config.coalesce_chars),
+            flushing: Flushing::default(),
+            code: Code::default(),
+            build,
+            pending: None,
+        }
+    }
+
+    /// Holds answer text back as `flushing` says.
+    pub fn with_flushing(mut self, flushing: Flushing) -> Self {
+        self.flushing = flushing;
+        self
+    }
+
+    /// Sends an event, after any pending text.
+    pub async fn send(&mut self, data: impl Into<String>) -> std::result::Result<(), Closed> {
+        self.flush().await?;
+        self.tx.send(Ok(Event::default().data(data.into()))).await.map_err(|_| Closed)
+    }
+
+    /// Sends a text delta, or holds it for merging.
+    pub async fn delta(&mut self, kind: Delta, text: &str) -> std::result::Result<(), Closed> {
+        match &mut self.pending {
+            Some(pending) if pending.kind == kind => pending.text.push_str(text),
+            _ => {
+                // 类型不同的增量不能合并，先等待发出之前的内容
+                self.flush().await?;
+                self.pending = Some(Pending {
+                    kind,
+                    text: text.to_string(),
+                    since: Instant::now(),
+                });
+            }
+        }
+        if kind == Delta::Content && self.flushing.holds() {
+            if self.flushing.clause.is_none() && !self.due() {
+                return Ok(());
+            }
+            return self.send_held().await;
+        }
+        if !self.due() {
+            return Ok(());
+        }
+        match self.policy {
+            SlowConsumer::Pause => self.flush().await,
+            SlowConsumer::Coalesce => self.try_flush(),
+        }
+    }
+
+    /// Sends the held answer text up to the last point that is outside
+    /// markdown code and, until the text has been held for the clause wait,
+    /// ends a clause. Text past the code cap is sent whole.
+    async fn send_held(&mut self) -> std::result::Result<(), Closed> {
+        let Some(rest) = self.split_held() else {
+            return Ok(());
+        };
+        match self.policy {
+            SlowConsumer::Pause => self.flush().await?,
+            SlowConsumer::Coalesce => self.try_flush()?,
+        }
+        self.hold(rest);
+        Ok(())
+    }
+
+    /// Cuts the held answer text where [`Self::send_held`] may send it,
+    /// returning the text past the cut, or `None` if none may be sent yet.
+    fn split_held(&mut self) -> Option<String> {
+        let pending = self.pending.as_mut()?;
+        let text = &pending.text;
+        let capped = self.flushing.code_cap.is_some_and(|cap| text.chars().count() >= cap);
+        let clause = !capped && self.flushing.clause.is_some_and(|wait| pending.since.elapsed() < wait);
+        let ends = if clause { clause_ends(text) } else { Vec::new() };
+        let end = match self.flushing.code_cap {
+            // 超过上限时不再等待代码块结束
+            Some(_) if !capped => {
+                let (outside, _) = self.code.scan(text);
+                outside.into_iter().rev().find(|point| !clause || ends.contains(point)).unwrap_or(0)
+            }
+            _ if clause => ends.last().copied().unwrap_or(0),
+            _ => text.len(),
+        };
+        if end == 0 {
+            return None;
+        }
+        Some(pending.text.split_off(end))
+    }
+
+    /// Holds answer text that could not be sent yet.
+    fn hold(&mut self, rest: String) {
+        if !rest.is_empty() {
+            match &mut self.pending {
+                // 客户端还没取走的文本与剩余部分合并
+                Some(pending) => pending.text.push_str(&rest),
+                None => {
+                    self.pending = Some(Pending {
+                        kind: Delta::Content,
+                        text: rest,
+                        since: Instant::now(),
+                    })
+                }
+            }
+        }
+    }
+
+    /// Follows the markdown code of answer text that was sent.
+    fn sent(&mut self, pending: &Pending) {
+        if pending.kind == Delta::Content && self.flushing.code_cap.is_some() {
+            self.code = self.code.scan(&pending.text).1;
+        }
+    }
+
+    /// Whether the held text has reached a merging limit.
+    fn due(&self) -> bool {
+        let Some(pending) = &self.pending else {
+            return false;
+        };
+        if self.max_age.is_none() && self.max_chars.is_none() {
+            return true;
+        }
+        self.max_age.is_some_and(|age| pending.since.elapsed() >= age)
+            || self.max_chars.is_some_and(|chars| pending.text.chars().count() >= chars)
+    }
+
+    /// When answer text held for its clause to end is due to be sent
+    /// anyway, if that is still ahead.
+    pub fn held_until(&self) -> Option<tokio::time::Instant> {
+        let wait = self.flushing.clause?;
+        let pending = self.pending.as_ref().filter(|pending| pending.kind == Delta::Content)?;
+        let until = pending.since + wait;
+        (until > Instant::now()).then(|| until.into())
+    }
+
+    /// Sends the answer text held past the clause wait without waiting for
+    /// the next delta, returning whether any was sent.
+    pub fn release_held(&mut self) -> std::result::Result<bool, Closed> {
+        let waited = self.pending.as_ref().is_some_and(|pending| {
+            pending.kind == Delta::Content && self.flushing.clause.is_some_and(|wait| pending.since.elapsed() >= wait)
+        });
+        let Some(rest) = waited.then(|| self.split_held()).flatten() else {
+            return Ok(false);
+        };
+        self.try_flush()?;
+        self.hold(rest);
+        Ok(true)
+    }
+
+    /// Sends a heartbeat unless the client has events waiting, first
+    /// releasing answer text held past the clause wait.
+    pub fn heartbeat(&mut self, data: String) -> std::result::Result<(), Closed> {
+        if self.release_held()? {
+            return Ok(());
+        }
+        // 按分句或代码块暂存、还没到时间的回答等下一个增量再发出
+        let held = self.flushing.holds() && self.pending.as_ref().is_some_and(|pending| pending.kind == Delta::Content);
+        if !held {
+            self.try_flush()?;
+            if self.pending.is_some() {
+                return Ok(());
+            }
+        }
+        match self.tx.try_send(Ok(Event::default().data(data))) {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Closed(_)) => Err(Closed),
+        }
+    }
+
+    fn try_flush(&mut self) -> std::result::Result<(), Closed> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+        match self.tx.try_send(Ok(Event::default().data((self.build)(pending.kind, &pending.text)))) {
+            Ok(()) => {
+                self.sent(&pending);
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                self.pending = Some(pending);
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(Closed),
+        }
+    }
+
+    async fn flush(&mut self) -> std::result::Result<(), Closed> {
+        if let Some(pending) = self.pending.take() {
+            let data = (self.build)(pending.kind, &pending.text);
+            self.tx.send(Ok(Event::default().data(data))).await.map_err(|_| Closed)?;
+            self.sent(&pending);
+        }
+        Ok(())
+    }
+}
+
+/// Cancels a streaming response by its stream id.
+///
+/// A virtual key can cancel the streams opened with it; without one the
+/// caller needs an operator admin token.
+///
+/// # Errors
+///
+/// Returns `ApiError::NotFound` if no stream with the id is running, or none
+/// opened with the caller's key, and `ApiError::Unauthorized` without a
+/// virtual key or admin token.
+#[utoipa::path(
+    post,
+    path = "/v1/chat/completions/{id}/cancel",
+    tag = "chat",
+    params(("id" = String, Path, description = "Stream id, the `id` of its chunks")),
+    responses(
+        (status = 200, description = "The stream was cancelled", body = Object),
+        (status = 401, description = "Neither a virtual key nor an admin token", body = ErrorResponse),
+        (status = 404, description = "No such stream is running", body = ErrorResponse),
+    )
+)]
+pub async fn cancel_stream(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>> {
+    let key_name = match state.key_store.lookup(&headers) {
+        Some(key) => Some(key.name.clone()),
+        None => {
+            admin::require_admin(&state, &headers, AdminRole::Operator)?;
+            None
+        }
+    };
+    // 其他密钥开启的流按不存在处理，不透露流id是否有效
+    if !state.streams.cancel(&id, key_name.as_deref()) {
+        return Err(ApiError::NotFound {
+            message: format!("Stream '{}' is not running", id),
+        });
+    }
+    Ok(Json(json!({ "id": id, "object": "chat.completion.cancel", "cancelled": true })))
+}
@@ -0,0 +1,75 @@
+//! systemd service notifications.
+//!
+//! When started by systemd with `Type=notify`, the server reports `READY=1`
+//! once every listener is bound and `STOPPING=1` when shutdown begins. With
+//! `WatchdogSec=` set, a task pings the watchdog at half the interval for as
+//! long as the runtime keeps scheduling it, so systemd restarts an instance
+//! whose runtime is wedged. Without `NOTIFY_SOCKET` nothing is sent.
+
+use std::time::Duration;
+
+/// Reports that the server is accepting connections.
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Reports that the server is shutting down.
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Pings the watchdog in the background if systemd expects it.
+pub fn spawn_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    tracing::info!("已启用systemd看门狗，间隔{:?}", interval);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval / 2);
+        loop {
+            ticker.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// Returns the watchdog interval systemd set for this process.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // WATCHDOG_PID指向其他进程时看门狗不属于本进程
+    if let Some(pid) = std::env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) {
+        if pid != std::process::id() {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = (|| -> std::io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        // 以@开头的是Linux抽象命名空间套接字
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.strip_prefix('@') {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        socket.send_to(state.as_bytes(), &path)?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        tracing::warn!("向systemd发送{}失败: {}", state, e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
@@ -0,0 +1,57 @@
+//! Variables in system prompts.
+//!
+//! `{{name}}` placeholders in the system prompt each stage receives, including
+//! the built-in full mode prompts, are replaced at request time. Built-in
+//! variables are `date`, `time` and `datetime` (Beijing time), `mode`,
+//! `model` (the model of the stage being prompted) and `user_name` (the
+//! request's `user`, or the virtual key name). `[templates] variables` adds
+//! custom ones. Unknown placeholders are left as they are.
+
+use crate::config::TemplatesConfig;
+use chrono::{Duration, Utc};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashMap;
+
+static PLACEHOLDER: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{\{\s*([A-Za-z0-9_]+)\s*\}\}").expect("有效的正则"));
+
+/// Values of the template variables for one request.
+#[derive(Debug, Clone)]
+pub struct Variables {
+    values: HashMap<String, String>,
+}
+
+impl Variables {
+    /// Collects the custom and built-in variables; built-ins take precedence.
+    pub fn new(config: &TemplatesConfig, mode: &str, user_name: Option<&str>) -> Self {
+        let now = Utc::now() + Duration::hours(8);
+        let mut values = config.variables.clone();
+        values.extend([
+            ("date".to_string(), now.format("%Y-%m-%d").to_string()),
+            ("time".to_string(), now.format("%H:%M").to_string()),
+            ("datetime".to_string(), now.format("%Y-%m-%d %H:%M:%S").to_string()),
+            ("mode".to_string(), mode.to_string()),
+            ("user_name".to_string(), user_name.unwrap_or_default().to_string()),
+        ]);
+        Self { values }
+    }
+
+    /// Returns the variables with `model` set for one stage.
+    pub fn with_model(&self, model: &str) -> Self {
+        let mut variables = self.clone();
+        variables.values.insert("model".to_string(), model.to_string());
+        variables
+    }
+
+    /// Replaces the known placeholders in a text.
+    pub fn render(&self, text: &str) -> String {
+        PLACEHOLDER
+            .replace_all(text, |captures: &regex::Captures| {
+                self.values
+                    .get(&captures[1])
+                    .cloned()
+                    .unwrap_or_else(|| captures[0].to_string())
+            })
+            .into_owned()
+    }
+}
@@ -0,0 +1,213 @@
+//! Organization and project scoping of requests.
+//!
+//! - `GET /admin/tenants/{id}/export` - everything kept about a tenant
+//!
+//! A request names its tenant with an `OpenAI-Organization` or `X-Tenant-Id`
+//! header and its project with `OpenAI-Project` or `X-Project-Id`. A
+//! virtual key bound to a tenant (`tenant` in `[[keys]]`) can only be used
+//! for that tenant and, if it lists `projects`, for those projects; requests
+//! made with it are scoped to its tenant even without the header. A key
+//! without a tenant may name any tenant. Requests without a virtual key
+//! cannot be attributed to a tenant, since anyone could send the headers:
+//! they are ignored, so such requests are charged to no tenant's budget.
+//!
+//! Usage records carry the tenant and project, and the usage report of a
+//! tenant-bound key covers its whole tenant. A tenant with a
+//! `[tenants.<id>] monthly_budget_usd` is refused further requests once its
+//! recorded spend for the current month reaches the budget.
+//!
+//! The export answers data-portability requests without access to the
+//! storage. It is a JSON Lines archive: a header line, then one line per
+//! key bound to the tenant (without its secret), live session, audit record
+//! naming the tenant, trace, uploaded file (its metadata and text) and usage
+//! record, each with a `type` of `export`, `key`, `session`, `audit`,
+//! `trace`, `file` or `usage`. Usage records are streamed, so the archive
+//! can cover years of them. Needs an admin token with the owner role, like
+//! erasure (see [`crate::retention`]).
+
+use crate::{
+    admin,
+    config::{AdminRole, TenantConfig},
+    error::{ApiError, Result},
+    handlers::AppState,
+    keys::{self, VirtualKey},
+    usage::UsageLedger,
+};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use futures::StreamExt;
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc};
+use tokio_stream::wrappers::ReceiverStream;
+
+const TENANT_HEADERS: [&str; 2] = ["openai-organization", "x-tenant-id"];
+const PROJECT_HEADERS: [&str; 2] = ["openai-project", "x-project-id"];
+
+/// The tenant and project a request is accounted to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scope {
+    pub tenant: Option<String>,
+    pub project: Option<String>,
+}
+
+impl Scope {
+    /// Resolves the scope of a request from its headers and virtual key.
+    ///
+    /// Without a key the headers are ignored and the request has no scope.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ApiError::Forbidden` if the headers name a tenant or project
+    /// the key is not bound to.
+    pub fn resolve(headers: &HeaderMap, key: Option<&VirtualKey>) -> Result<Self> {
+        let tenant = header(headers, &TENANT_HEADERS);
+        let project = header(headers, &PROJECT_HEADERS);
+        let Some(key) = key else {
+            // 没有虚拟密钥的请求不能证明自己属于哪个租户，忽略这些请求头
+            if tenant.is_some() || project.is_some() {
+                tracing::debug!("请求没有虚拟密钥，忽略其组织和项目请求头");
+            }
+            return Ok(Self::default());
+        };
+
+        let tenant = match (&key.tenant, tenant) {
+            (Some(bound), Some(requested)) if *bound != requested => {
+                return Err(ApiError::Forbidden {
+                    message: format!("Key '{}' is not allowed to act for organization '{}'", key.name, requested),
+                });
+            }
+            (Some(bound), _) => Some(bound.clone()),
+            (None, requested) => requested,
+        };
+        if let Some(project) = &project {
+            if !key.projects.is_empty() && !key.projects.contains(project) {
+                return Err(ApiError::Forbidden {
+                    message: format!("Key '{}' is not allowed to act for project '{}'", key.name, project),
+                });
+            }
+        }
+        Ok(Self { tenant, project })
+    }
+}
+
+fn header(headers: &HeaderMap, names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| headers.get(*name))
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+}
+
+/// Checks a tenant's monthly budget.
+///
+/// # Errors
+///
+/// Returns `ApiError::BudgetExceeded` if the tenant has spent its budget for
+/// the current month.
+pub async fn check_budget(tenants: &HashMap<String, TenantConfig>, scope: &Scope, usage: &UsageLedger) -> Result<()> {
+    let Some(tenant) = &scope.tenant else {
+        return Ok(());
+    };
+    let Some(budget) = tenants.get(tenant).and_then(|config| config.monthly_budget_usd) else {
+        return Ok(());
+    };
+    let spent = usage.tenant_spend(tenant).await;
+    if spent >= budget {
+        tracing::warn!("租户{}本月花费${:.2}已达到预算${:.2}", tenant, spent, budget);
+        return Err(ApiError::BudgetExceeded {
+            tenant: tenant.clone(),
+            budget_usd: budget,
+        });
+    }
+    Ok(())
+}
+
+/// Exports everything kept about a tenant as a JSON Lines archive.
+///
+/// # Errors
+///
+/// Returns `ApiError::Internal` if the sessions, audit log, traces or files
+/// cannot be read.
+#[utoipa::path(
+    get,
+    path = "/admin/tenants/{id}/export",
+    tag = "admin",
+    params(("id" = String, Path, description = "Tenant id")),
+    responses((status = 200, description = "The tenant's data, one JSON object per line",
+        content((String = "application/x-ndjson"))))
+)]
+pub async fn export_tenant(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+) -> Result<Response> {
+    let admin = admin::require_admin(&state, &headers, AdminRole::Owner)?;
+
+    // 用量之外的数据量较小，先全部读出，读取失败时还能返回错误状态
+    let mut lines = vec![json!({ "type": "export", "tenant": tenant, "exported_at": Utc::now() })];
+    for key in state.key_store.of_tenant(&tenant) {
+        let mut listed = keys::listed(&key);
+        listed["type"] = json!("key");
+        lines.push(listed);
+    }
+    for session in state.sessions.list(&tenant).await? {
+        lines.push(json!({ "type": "session", "id": session }));
+    }
+    for record in state.audit.records()? {
+        if record.detail.get("tenant").and_then(|value| value.as_str()) == Some(tenant.as_str()) {
+            lines.push(json!({ "type": "audit", "record": record }));
+        }
+    }
+    for trace in state.traces.of_tenant(&tenant)? {
+        lines.push(json!({ "type": "trace", "trace": trace }));
+    }
+    for file in state.files.of_tenant(&tenant)? {
+        lines.push(json!({ "type": "file", "file": file.describe(), "text": file.text() }));
+    }
+    tracing::info!("{}导出了租户{}的数据", admin.name, tenant);
+    state.audit.record(&admin.name, "tenants.export", json!({ "tenant": tenant }));
+
+    state.usage.flush().await;
+    let mut records = state.usage.stream(None, None);
+    let (tx, rx) = tokio::sync::mpsc::channel::<anyhow::Result<String>>(64);
+    let owner = tenant.clone();
+    tokio::spawn(async move {
+        for line in lines {
+            if tx.send(Ok(format!("{}\n", line))).await.is_err() {
+                return;
+            }
+        }
+        while let Some(record) = records.next().await {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    break;
+                }
+            };
+            if record.tenant.as_deref() != Some(owner.as_str()) {
+                continue;
+            }
+            let line = json!({ "type": "usage", "record": record });
+            if tx.send(Ok(format!("{}\n", line))).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // 文件名只保留安全字符
+    let name: String = tenant.chars().filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')).collect();
+    let disposition = format!("attachment; filename=\"tenant-{}.jsonl\"", name);
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson".to_string()), (header::CONTENT_DISPOSITION, disposition)],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
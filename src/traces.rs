@@ -0,0 +1,269 @@
+//! Pipeline traces of verbose requests.
+//!
+//! - `GET /v1/traces/{request_id}` - download the trace of a request
+//!
+//! Chat requests made with `verbose: true` record what each stage was sent
+//! and what the upstream returned, the thinking injected into the Claude
+//! stage, the final answer and timings, including time to first token and
+//! throughput of the streamed stages. The trace is stored as one JSON file
+//! under `[traces] dir`, named by the response id (the stream id for
+//! streaming responses). Traces older than `[traces] retention_hours` are
+//! removed when a new one is saved and by the scheduled purge (see
+//! [`crate::retention`]), which can also erase the traces of a tenant.
+//! Per-stage headers are not recorded since they may carry credentials.
+//!
+//! A trace holds the whole conversation, so it is served like usage is
+//! scoped: to the virtual key the request was made with, to the keys of
+//! its tenant, or with an operator admin token. Anyone else gets a 404.
+
+use crate::{
+    admin,
+    clients::stats::StreamTiming,
+    config::{AdminRole, TracesConfig},
+    error::{ApiError, ErrorResponse, Result},
+    handlers::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The recorded pipeline of one request.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Trace {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub mode: String,
+    pub variant: String,
+    /// Name of the virtual key the request was made with, if any.
+    #[serde(default)]
+    pub key_name: Option<String>,
+    /// Tenant the request was accounted to.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub stages: Vec<Stage>,
+    /// Assistant message carrying DeepSeek's output into the Claude stage.
+    pub injected_thinking: Option<String>,
+    /// The final answer after checks and post-processing, before
+    /// `post_response` hooks.
+    pub answer: String,
+    pub total_ms: u64,
+    #[serde(skip, default = "Instant::now")]
+    started: Instant,
+}
+
+/// One upstream call of a trace.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Stage {
+    pub name: String,
+    pub model: String,
+    /// Offset from the start of the request.
+    pub started_ms: u64,
+    pub duration_ms: u64,
+    pub input: serde_json::Value,
+    pub output: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamTiming>,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl Trace {
+    pub fn new(mode: &str, variant: &str, key_name: Option<&str>, tenant: Option<&str>) -> Self {
+        Self {
+            id: String::new(),
+            created_at: Utc::now(),
+            mode: mode.to_string(),
+            variant: variant.to_string(),
+            key_name: key_name.map(str::to_string),
+            tenant: tenant.map(str::to_string),
+            stages: Vec::new(),
+            injected_thinking: None,
+            answer: String::new(),
+            total_ms: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Starts a stage with the input sent upstream.
+    pub fn begin(&mut self, name: &str, model: &str, input: serde_json::Value) {
+        self.stages.push(Stage {
+            name: name.to_string(),
+            model: model.to_string(),
+            started_ms: self.started.elapsed().as_millis() as u64,
+            duration_ms: 0,
+            input,
+            output: serde_json::Value::Null,
+            stream: None,
+            started: Some(Instant::now()),
+        });
+    }
+
+    /// Finishes the latest stage with what the upstream returned.
+    pub fn end(&mut self, output: serde_json::Value) {
+        if let Some(stage) = self.stages.last_mut() {
+            stage.duration_ms = stage.started.map_or(0, |started| started.elapsed().as_millis() as u64);
+            stage.output = output;
+        }
+    }
+
+    /// Adds the stream timing of the latest stage.
+    pub fn stream_timing(&mut self, timing: Option<StreamTiming>) {
+        if let Some(stage) = self.stages.last_mut() {
+            stage.stream = timing;
+        }
+    }
+
+    /// Completes the trace under the response id.
+    pub fn finish(mut self, id: &str) -> Self {
+        self.id = id.to_string();
+        self.total_ms = self.started.elapsed().as_millis() as u64;
+        self
+    }
+}
+
+/// File-backed trace store.
+#[derive(Debug)]
+pub struct TraceStore {
+    dir: PathBuf,
+    retention: Duration,
+}
+
+impl TraceStore {
+    /// Creates the store for the `[traces]` settings.
+    pub fn new(config: &TracesConfig) -> Self {
+        Self {
+            dir: PathBuf::from(&config.dir),
+            retention: Duration::from_secs(config.retention_hours * 3600),
+        }
+    }
+
+    /// Persists a finished trace. Failures are logged, not returned, since
+    /// the response has already been produced.
+    pub fn save(&self, trace: &Trace) {
+        let result = (|| -> anyhow::Result<()> {
+            std::fs::create_dir_all(&self.dir)?;
+            self.prune()?;
+            let path = self.path(&trace.id).ok_or_else(|| anyhow::anyhow!("无效的追踪id: {}", trace.id))?;
+            std::fs::write(path, serde_json::to_string_pretty(trace)?)?;
+            Ok(())
+        })();
+        match result {
+            Ok(()) => tracing::debug!("已保存请求追踪: {}", trace.id),
+            Err(e) => tracing::error!("保存请求追踪失败: {}", e),
+        }
+    }
+
+    /// Loads a trace by request id.
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<Trace>> {
+        let Some(path) = self.path(id).filter(|path| path.exists()) else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_str(&std::fs::read_to_string(path)?)?))
+    }
+
+    /// The traces of a tenant, oldest first.
+    pub fn of_tenant(&self, tenant: &str) -> anyhow::Result<Vec<Trace>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut traces = Vec::new();
+        for file in std::fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            // 无法解析的追踪不属于任何租户
+            if let Ok(trace) = serde_json::from_str::<Trace>(&std::fs::read_to_string(&path)?) {
+                if trace.tenant.as_deref() == Some(tenant) {
+                    traces.push(trace);
+                }
+            }
+        }
+        traces.sort_by_key(|trace| trace.created_at);
+        Ok(traces)
+    }
+
+    /// Removes traces past the retention period, returning how many.
+    pub fn prune(&self) -> anyhow::Result<u64> {
+        let now = SystemTime::now();
+        self.remove(|path| Ok(now.duration_since(std::fs::metadata(path)?.modified()?).unwrap_or_default() > self.retention))
+    }
+
+    /// Removes the traces of a tenant, returning how many.
+    pub fn erase(&self, tenant: &str) -> anyhow::Result<u64> {
+        self.remove(|path| {
+            // 无法解析的追踪不属于任何租户
+            let trace = serde_json::from_str::<Trace>(&std::fs::read_to_string(path)?);
+            Ok(trace.is_ok_and(|trace| trace.tenant.as_deref() == Some(tenant)))
+        })
+    }
+
+    /// Removes the traces `matches` picks.
+    fn remove(&self, matches: impl Fn(&std::path::Path) -> anyhow::Result<bool>) -> anyhow::Result<u64> {
+        if !self.dir.exists() {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        for file in std::fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().is_some_and(|ext| ext == "json") && matches(&path)? {
+                std::fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        // id来自URL路径，只允许uuid字符以防止路径穿越
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return None;
+        }
+        Some(self.dir.join(format!("{}.json", id)))
+    }
+}
+
+/// Downloads the trace of a verbose request.
+///
+/// # Errors
+///
+/// Returns `ApiError::NotFound` if no trace is stored under the id, or the
+/// caller has neither its key, a key of its tenant nor an operator admin
+/// token.
+#[utoipa::path(
+    get,
+    path = "/v1/traces/{request_id}",
+    tag = "chat",
+    params(("request_id" = String, Path, description = "Request id of a verbose request")),
+    responses(
+        (status = 200, description = "The trace", body = Object),
+        (status = 404, description = "No such trace", body = ErrorResponse),
+    )
+)]
+pub async fn get_trace(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(request_id): Path<String>,
+) -> Result<Json<Trace>> {
+    // 和用量一样按密钥和租户限定，其他调用方按不存在处理，不透露请求id是否有效
+    let visible = |trace: &Trace| match state.key_store.lookup(&headers) {
+        Some(key) => match &key.tenant {
+            Some(tenant) => trace.tenant.as_ref() == Some(tenant),
+            None => trace.key_name.as_ref() == Some(&key.name),
+        },
+        None => admin::require_admin(&state, &headers, AdminRole::Operator).is_ok(),
+    };
+    let trace = state.traces.get(&request_id)?.filter(visible).ok_or_else(|| ApiError::NotFound {
+        message: format!("Trace '{}' not found", request_id),
+    })?;
+    Ok(Json(trace))
+}
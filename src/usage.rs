@@ -0,0 +1,607 @@
+//! Usage records of chat requests.
+//!
+//! - `GET /v1/usage` - token and cost totals, optionally grouped
+//! - `GET /v1/usage/export` - download the records of a month as CSV or
+//!   JSON Lines
+//!
+//! Every completed chat request appends one record with its virtual key,
+//! end user (the OpenAI `user` field), tenant and project (see
+//! [`crate::tenants`]), models, tokens and cost to the configured storage
+//! (see [`crate::storage`]), by default a JSON Lines file per month under
+//! `[usage] dir`. Token counts of streaming responses are
+//! estimated where the upstream does not report them. Records are queued
+//! to a writer task that appends them in batches of up to `batch_size`, so
+//! requests never wait on the storage; when more than `queue_size` records
+//! are waiting, further records are dropped with a warning and counted at
+//! `GET /metrics`. The queue is flushed on shutdown.
+//!
+//! Records carry the request's `X-Request-Id`. A client that retries a
+//! request under the same id (the last `MAX_ATTEMPT_IDS` ids are
+//! remembered) gets one record per attempt that spent tokens: an attempt
+//! whose answer failed after DeepSeek reasoned is recorded with its
+//! reasoning cost as both `cost_usd` and `wasted_cost_usd`, and the
+//! attempt that succeeds carries `attempts`, the number of attempts the
+//! request took. Reports total `wasted_cost_usd`, which is what failing
+//! upstreams and gateways cost.
+//!
+//! The report is available with the admin token, covering every record or
+//! the `tenant` asked for, or with a virtual key, covering its tenant's
+//! records if it is bound to one and its own records otherwise. It can be
+//! grouped by `user`, `key`, `tenant`, `project`, `model`, `variant` or
+//! `day` and limited to a date range with `from` and `to` (inclusive,
+//! `YYYY-MM-DD`). The export streams the raw records of one `month`
+//! (`YYYY-MM`, the current month by default) that the caller may see, in
+//! the `format` asked for (`csv` by default, or `jsonl`).
+
+use crate::{
+    admin,
+    config::{AdminRole, UsageConfig},
+    error::{ApiError, ErrorResponse, Result},
+    handlers::AppState,
+    sessions::CacheStatus,
+    storage::{Purge, RecordStream, UsageSink},
+};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use futures::{StreamExt, TryStreamExt};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use utoipa::IntoParams;
+
+/// How many request ids are remembered for counting retries.
+const MAX_ATTEMPT_IDS: usize = 10_000;
+
+/// Usage of one chat request.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UsageRecord {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    /// Name of the virtual key the request was made with, if any.
+    pub key_name: Option<String>,
+    /// End-user identifier sent by the client.
+    pub user: Option<String>,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub project: Option<String>,
+    pub mode: String,
+    pub variant: String,
+    pub stream: bool,
+    pub deepseek_model: String,
+    pub anthropic_model: String,
+    pub deepseek_input_tokens: u32,
+    pub deepseek_output_tokens: u32,
+    pub anthropic_input_tokens: u32,
+    pub anthropic_output_tokens: u32,
+    /// Tokens of the refinement pass, not included in the Anthropic tokens.
+    #[serde(default)]
+    pub refine_input_tokens: u32,
+    #[serde(default)]
+    pub refine_output_tokens: u32,
+    /// Cost of the refinement pass, included in `cost_usd`.
+    #[serde(default)]
+    pub refine_cost_usd: f64,
+    pub cost_usd: f64,
+    /// Whether token counts were estimated instead of reported upstream.
+    pub estimated: bool,
+    /// Prompt cache status of a session request, see [`crate::sessions`].
+    #[serde(default)]
+    pub prompt_cache: Option<CacheStatus>,
+    /// `X-Request-Id` of the request.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// Which attempt at the request under its `X-Request-Id` this was, 1
+    /// for the first.
+    #[serde(default = "first_attempt")]
+    pub attempts: u32,
+    /// Cost of an attempt whose answer failed, included in `cost_usd`.
+    #[serde(default)]
+    pub wasted_cost_usd: f64,
+}
+
+fn first_attempt() -> u32 {
+    1
+}
+
+impl UsageRecord {
+    /// Input tokens of every stage and pass.
+    pub fn input_tokens(&self) -> u64 {
+        u64::from(self.deepseek_input_tokens) + u64::from(self.anthropic_input_tokens) + u64::from(self.refine_input_tokens)
+    }
+
+    /// Output tokens of every stage and pass.
+    pub fn output_tokens(&self) -> u64 {
+        u64::from(self.deepseek_output_tokens) + u64::from(self.anthropic_output_tokens) + u64::from(self.refine_output_tokens)
+    }
+}
+
+/// Usage ledger over the configured [`UsageSink`].
+pub struct UsageLedger {
+    enabled: bool,
+    sink: Arc<dyn UsageSink>,
+    queue: mpsc::Sender<Queued>,
+    counters: Arc<WriterCounters>,
+    /// Spend per tenant in the month it was loaded for.
+    spend: Mutex<Option<MonthSpend>>,
+    /// Attempts per recent request id, oldest id first.
+    attempts: Mutex<(VecDeque<String>, HashMap<String, u32>)>,
+}
+
+/// Work queued to the writer task.
+enum Queued {
+    Record(Box<UsageRecord>),
+    /// Answered once everything queued before it is written.
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Debug, Default)]
+struct WriterCounters {
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+#[derive(Debug)]
+struct MonthSpend {
+    month: (i32, u32),
+    by_tenant: HashMap<String, f64>,
+}
+
+impl UsageLedger {
+    /// Creates the ledger for the `[usage]` settings and starts its writer
+    /// task.
+    pub fn new(config: &UsageConfig, sink: Arc<dyn UsageSink>) -> Self {
+        let (queue, writes) = mpsc::channel(config.queue_size.max(1));
+        let counters = Arc::new(WriterCounters::default());
+        tokio::spawn(write(sink.clone(), writes, config.batch_size.max(1), counters.clone()));
+        Self {
+            enabled: config.enabled,
+            sink,
+            queue,
+            counters,
+            spend: Mutex::new(None),
+            attempts: Default::default(),
+        }
+    }
+
+    /// Counts an attempt at the request with this id and returns its
+    /// number, 1 for the first.
+    pub fn attempt(&self, request_id: &str) -> u32 {
+        if !self.enabled {
+            return 1;
+        }
+        let mut attempts = self.attempts.lock().unwrap();
+        let (order, by_id) = &mut *attempts;
+        let count = by_id.entry(request_id.to_string()).or_insert_with(|| {
+            order.push_back(request_id.to_string());
+            0
+        });
+        *count += 1;
+        let count = *count;
+        while order.len() > MAX_ATTEMPT_IDS {
+            if let Some(oldest) = order.pop_front() {
+                by_id.remove(&oldest);
+            }
+        }
+        count
+    }
+
+    /// Queues a record for the writer task. A record that does not fit in
+    /// the queue is dropped, and failures are logged, not returned, since
+    /// the response has already been produced.
+    pub fn record(&self, record: &UsageRecord) {
+        if !self.enabled {
+            return;
+        }
+        if self.queue.try_send(Queued::Record(Box::new(record.clone()))).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("用量写入队列已满，丢弃记录{}", record.id);
+        }
+
+        if let (Some(tenant), Some(spend)) = (&record.tenant, self.spend.lock().unwrap().as_mut()) {
+            if spend.month == (record.timestamp.year(), record.timestamp.month()) {
+                *spend.by_tenant.entry(tenant.clone()).or_default() += record.cost_usd;
+            }
+        }
+    }
+
+    /// Returns a tenant's recorded spend in the current month.
+    pub async fn tenant_spend(&self, tenant: &str) -> f64 {
+        let now = Utc::now();
+        let month = (now.year(), now.month());
+        let loaded = self.spend.lock().unwrap().as_ref().is_some_and(|spend| spend.month == month);
+        if !loaded {
+            // 每月首次查询时从用量记录汇总，之后随记录累加
+            let first = NaiveDate::from_ymd_opt(month.0, month.1, 1);
+            let mut by_tenant = HashMap::new();
+            match self.read(first, None).await {
+                Ok(records) => {
+                    for record in records {
+                        if let Some(tenant) = record.tenant {
+                            *by_tenant.entry(tenant).or_default() += record.cost_usd;
+                        }
+                    }
+                }
+                Err(e) => tracing::error!("读取用量记录失败: {}", e),
+            }
+            *self.spend.lock().unwrap() = Some(MonthSpend { month, by_tenant });
+        }
+        self.spend
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|spend| spend.by_tenant.get(tenant))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Reads the records made between two dates, inclusive.
+    pub async fn read(&self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> anyhow::Result<Vec<UsageRecord>> {
+        self.sink.read(from, to).try_collect().await
+    }
+
+    /// Removes the records matched by `purge`, after writing those queued,
+    /// and returns how many were removed.
+    pub async fn purge(&self, purge: &Purge) -> anyhow::Result<u64> {
+        self.flush().await;
+        let removed = self.sink.purge(purge).await?;
+        // 删除的记录可能属于本月，下次查询时重新汇总
+        *self.spend.lock().unwrap() = None;
+        Ok(removed)
+    }
+
+    /// Streams the records made between two dates, inclusive.
+    pub fn stream(&self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> RecordStream {
+        self.sink.read(from, to)
+    }
+
+    /// Waits until the records queued so far are written.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.queue.send(Queued::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    /// Renders the writer queue's gauge and counters in the Prometheus text
+    /// format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        if !self.enabled {
+            return out;
+        }
+        let metrics = [
+            (
+                "deepclaude_usage_queue_depth",
+                "gauge",
+                "Usage records waiting to be written.",
+                (self.queue.max_capacity() - self.queue.capacity()) as u64,
+            ),
+            (
+                "deepclaude_usage_dropped_total",
+                "counter",
+                "Usage records dropped because the write queue was full.",
+                self.counters.dropped.load(Ordering::Relaxed),
+            ),
+            (
+                "deepclaude_usage_write_failures_total",
+                "counter",
+                "Usage records the storage failed to write.",
+                self.counters.failed.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+        }
+        out
+    }
+}
+
+/// Writes queued records to the sink, taking up to `batch_size` of them at
+/// a time.
+async fn write(sink: Arc<dyn UsageSink>, mut writes: mpsc::Receiver<Queued>, batch_size: usize, counters: Arc<WriterCounters>) {
+    let mut batch = Vec::with_capacity(batch_size);
+    while writes.recv_many(&mut batch, batch_size).await > 0 {
+        let mut records = Vec::new();
+        let mut flushed = Vec::new();
+        for queued in batch.drain(..) {
+            match queued {
+                Queued::Record(record) => records.push(*record),
+                Queued::Flush(done) => flushed.push(done),
+            }
+        }
+        if !records.is_empty() {
+            if let Err(e) = sink.append(&records).await {
+                counters.failed.fetch_add(records.len() as u64, Ordering::Relaxed);
+                tracing::error!("写入{}条用量记录失败: {}", records.len(), e);
+            }
+        }
+        for done in flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Query parameters for `GET /v1/usage`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UsageQuery {
+    pub group_by: Option<String>,
+    /// Limits an admin report to one tenant.
+    pub tenant: Option<String>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+/// Totals of a set of usage records.
+#[derive(Debug, Default, Serialize)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost_usd: f64,
+    /// Cost of failed attempts, included in `cost_usd`.
+    pub wasted_cost_usd: f64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, record: &UsageRecord) {
+        self.requests += 1;
+        self.input_tokens += record.input_tokens();
+        self.output_tokens += record.output_tokens();
+        self.cost_usd += record.cost_usd;
+        self.wasted_cost_usd += record.wasted_cost_usd;
+    }
+}
+
+/// Fields the report can be grouped by.
+const GROUPS: [&str; 7] = ["user", "key", "tenant", "project", "model", "variant", "day"];
+
+/// Returns the value of the field a record is grouped by.
+fn group_of(record: &UsageRecord, group_by: &str) -> String {
+    let value = match group_by {
+        "user" => record.user.clone(),
+        "key" => record.key_name.clone(),
+        "tenant" => record.tenant.clone(),
+        "project" => record.project.clone(),
+        "model" => Some(format!("{}+{}", record.deepseek_model, record.anthropic_model)),
+        "variant" => Some(record.variant.clone()),
+        "day" => Some(record.timestamp.date_naive().to_string()),
+        _ => None,
+    };
+    value.unwrap_or_default()
+}
+
+/// Decides which records a caller may see.
+type Visibility = Box<dyn Fn(&UsageRecord) -> bool + Send>;
+
+/// Returns the records visible to the caller: every record, or those of
+/// `tenant`, with the admin token, and those of its tenant or itself with a
+/// virtual key.
+///
+/// # Errors
+///
+/// Returns `ApiError::Unauthorized` without the admin token or a virtual key.
+fn visibility(state: &AppState, headers: &HeaderMap, tenant: Option<String>) -> Result<Visibility> {
+    // 虚拟密钥只能查看所属租户或自己的用量
+    Ok(match state.key_store.lookup(headers) {
+        Some(key) => match key.tenant.clone() {
+            Some(tenant) => Box::new(move |record| record.tenant.as_ref() == Some(&tenant)),
+            None => {
+                let name = key.name.clone();
+                Box::new(move |record| record.key_name.as_ref() == Some(&name))
+            }
+        },
+        None => {
+            admin::require_admin(state, headers, AdminRole::Viewer)?;
+            Box::new(move |record| tenant.is_none() || record.tenant == tenant)
+        }
+    })
+}
+
+/// Reports usage totals.
+///
+/// # Errors
+///
+/// Returns `ApiError::Unauthorized` without the admin token or a virtual key,
+/// and `ApiError::BadRequest` for an unknown `group_by`.
+#[utoipa::path(
+    get,
+    path = "/v1/usage",
+    tag = "usage",
+    params(UsageQuery),
+    responses(
+        (status = 200, description = "The usage totals", body = Object),
+        (status = 400, description = "Unknown `group_by`", body = ErrorResponse),
+        (status = 401, description = "Neither the admin token nor a virtual key", body = ErrorResponse),
+    )
+)]
+pub async fn usage_report(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<serde_json::Value>> {
+    let visible = visibility(&state, &headers, query.tenant.clone())?;
+    if let Some(group_by) = query.group_by.as_deref() {
+        if !GROUPS.contains(&group_by) {
+            return Err(ApiError::BadRequest {
+                message: format!("Unknown group_by '{}'; use one of {}", group_by, GROUPS.join(", ")),
+            });
+        }
+    }
+
+    let records = state.usage.read(query.from, query.to).await?;
+    let mut total = UsageTotals::default();
+    let mut groups: BTreeMap<String, UsageTotals> = BTreeMap::new();
+    for record in records.iter().filter(|record| visible(record)) {
+        total.add(record);
+        if let Some(group_by) = query.group_by.as_deref() {
+            groups.entry(group_of(record, group_by)).or_default().add(record);
+        }
+    }
+
+    let groups: Vec<_> = groups
+        .into_iter()
+        .map(|(group, totals)| serde_json::json!({ "group": group, "totals": totals }))
+        .collect();
+    Ok(Json(serde_json::json!({
+        "object": "usage.report",
+        "group_by": query.group_by,
+        "from": query.from,
+        "to": query.to,
+        "total": total,
+        "groups": groups,
+    })))
+}
+
+/// Query parameters for `GET /v1/usage/export`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    pub format: Option<String>,
+    pub month: Option<String>,
+    /// Limits an admin export to one tenant.
+    pub tenant: Option<String>,
+}
+
+/// Columns of the CSV export.
+const CSV_COLUMNS: &str = "id,timestamp,key_name,user,tenant,project,mode,variant,stream,deepseek_model,anthropic_model,\
+deepseek_input_tokens,deepseek_output_tokens,anthropic_input_tokens,anthropic_output_tokens,\
+refine_input_tokens,refine_output_tokens,refine_cost_usd,cost_usd,estimated,prompt_cache,\
+request_id,attempts,wasted_cost_usd";
+
+/// Formats a record as a CSV row.
+fn csv_row(record: &UsageRecord) -> String {
+    let field = |value: &str| {
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    };
+    let optional = |value: &Option<String>| field(value.as_deref().unwrap_or_default());
+    [
+        field(&record.id),
+        record.timestamp.to_rfc3339(),
+        optional(&record.key_name),
+        optional(&record.user),
+        optional(&record.tenant),
+        optional(&record.project),
+        field(&record.mode),
+        field(&record.variant),
+        record.stream.to_string(),
+        field(&record.deepseek_model),
+        field(&record.anthropic_model),
+        record.deepseek_input_tokens.to_string(),
+        record.deepseek_output_tokens.to_string(),
+        record.anthropic_input_tokens.to_string(),
+        record.anthropic_output_tokens.to_string(),
+        record.refine_input_tokens.to_string(),
+        record.refine_output_tokens.to_string(),
+        record.refine_cost_usd.to_string(),
+        record.cost_usd.to_string(),
+        record.estimated.to_string(),
+        match record.prompt_cache {
+            Some(CacheStatus::Warm) => "warm".to_string(),
+            Some(CacheStatus::Cold) => "cold".to_string(),
+            None => String::new(),
+        },
+        optional(&record.request_id),
+        record.attempts.to_string(),
+        record.wasted_cost_usd.to_string(),
+    ]
+    .join(",")
+}
+
+/// Streams the usage records of a month.
+///
+/// # Errors
+///
+/// Returns `ApiError::Unauthorized` without the admin token or a virtual key,
+/// and `ApiError::BadRequest` for an unknown format or a malformed month.
+#[utoipa::path(
+    get,
+    path = "/v1/usage/export",
+    tag = "usage",
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "The usage records of the month",
+            content((String = "text/csv"), (String = "application/x-ndjson"))),
+        (status = 400, description = "Unknown format or malformed month", body = ErrorResponse),
+        (status = 401, description = "Neither the admin token nor a virtual key", body = ErrorResponse),
+    )
+)]
+pub async fn export_usage(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response> {
+    let visible = visibility(&state, &headers, query.tenant.clone())?;
+    let csv = match query.format.as_deref().unwrap_or("csv") {
+        "csv" => true,
+        "jsonl" => false,
+        other => {
+            return Err(ApiError::BadRequest {
+                message: format!("Unknown format '{}'; use csv or jsonl", other),
+            });
+        }
+    };
+    let now = Utc::now();
+    let (year, month) = match query.month.as_deref() {
+        None => (now.year(), now.month()),
+        Some(month) => NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+            .map(|date| (date.year(), date.month()))
+            .map_err(|_| ApiError::BadRequest {
+                message: format!("Invalid month '{}'; use YYYY-MM", month),
+            })?,
+    };
+
+    // 逐条读取并过滤，不把整月的记录放进内存
+    let first = NaiveDate::from_ymd_opt(year, month, 1);
+    let last = first.and_then(|first| first.checked_add_months(chrono::Months::new(1))).and_then(|next| next.pred_opt());
+    let mut records = state.usage.stream(first, last);
+    let (tx, rx) = tokio::sync::mpsc::channel::<anyhow::Result<String>>(64);
+    tokio::spawn(async move {
+        if csv && tx.send(Ok(format!("{}\n", CSV_COLUMNS))).await.is_err() {
+            return;
+        }
+        while let Some(record) = records.next().await {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    break;
+                }
+            };
+            if !visible(&record) {
+                continue;
+            }
+            let row = if csv { csv_row(&record) } else { serde_json::to_string(&record).unwrap_or_default() };
+            if tx.send(Ok(format!("{}\n", row))).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let (content_type, extension) = if csv { ("text/csv; charset=utf-8", "csv") } else { ("application/x-ndjson", "jsonl") };
+    let disposition = format!("attachment; filename=\"usage-{:04}-{:02}.{}\"", year, month, extension);
+    Ok((
+        [(header::CONTENT_TYPE, content_type.to_string()), (header::CONTENT_DISPOSITION, disposition)],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
@@ -10,16 +10,8 @@
 pub fn get_mode() -> String {
     tracing::debug!("尝试从.env文件读取MODE变量");
     
-    // 从.env文件读取
-    let current_dir = match std::env::current_dir() {
-        Ok(dir) => dir,
-        Err(e) => {
-            tracing::error!("无法获取当前目录: {}", e);
-            return "normal".to_string(); // 如果无法获取当前目录，返回默认值
-        }
-    };
-    
-    let env_path = current_dir.join(".env");
+    // 从配置目录的.env文件读取
+    let env_path = crate::paths::env_file();
     tracing::debug!("尝试从.env文件读取: {:?}", env_path);
     
     // 读取.env文件内容
@@ -72,16 +64,8 @@ pub fn get_env_var(key: &str, default: &str) -> String {
     
     tracing::debug!("系统环境变量中未找到{}，尝试从.env文件读取", key);
     
-    // 如果环境变量中没有，尝试从.env文件读取
-    let current_dir = match std::env::current_dir() {
-        Ok(dir) => dir,
-        Err(e) => {
-            tracing::error!("无法获取当前目录: {}", e);
-            return default.to_string(); // 如果无法获取当前目录，返回默认值
-        }
-    };
-    
-    let env_path = current_dir.join(".env");
+    // 如果环境变量中没有，尝试从配置目录的.env文件读取
+    let env_path = crate::paths::env_file();
     
     // 读取.env文件内容
     let env_content = match std::fs::read_to_string(&env_path) {
@@ -0,0 +1,3140 @@
+//! End-to-end tests of `POST /v1/chat/completions`.
+//!
+//! Each test serves the real router with the DeepSeek and Claude upstreams
+//! replaced by wiremock servers, see [`common::Harness`].
+
+mod common;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use common::{sse_data, ClaudeApi, Harness};
+use deepclaude::{
+    audit,
+    clients::{hosts, providers},
+    config::{EnvelopeConfig, ModelAlias, StorageBackend, StreamsConfig},
+    crypto::{self, MasterKey},
+    editblocks,
+    error::ApiError,
+    ratelimit::RateLimiter,
+    repl::{self, ChatOptions},
+    signing,
+    streams::{Delta, EventSink, Flushing},
+};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use wiremock::{
+    matchers::{body_partial_json, body_string_contains, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const REASONING: &str = "First, think it through. ";
+const DEEPSEEK_ANSWER: &str = "DeepSeek says 42.";
+const CLAUDE_ANSWER: &str = "The answer is 42.";
+
+fn request(mode: &str, stream: bool) -> Value {
+    json!({
+        "model": "deepclaude",
+        "mode": mode,
+        "stream": stream,
+        "messages": [{ "role": "user", "content": "What is six times seven?" }],
+        "deepseek_config": { "headers": {}, "body": {} },
+        "anthropic_config": { "headers": {}, "body": {} },
+    })
+}
+
+/// DeepSeek's answer to a non-streaming request.
+fn deepseek_completion() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "id": "ds-1",
+        "object": "chat.completion",
+        "created": 1,
+        "model": "deepseek-reasoner",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": DEEPSEEK_ANSWER, "reasoning_content": REASONING },
+            "finish_reason": "stop",
+        }],
+        "usage": { "prompt_tokens": 10, "completion_tokens": 20, "total_tokens": 30 },
+    }))
+}
+
+/// DeepSeek's answer to a streaming request, the reasoning split in words.
+fn deepseek_stream() -> ResponseTemplate {
+    let mut body = String::new();
+    let mut chunk = |delta: Value| {
+        let chunk = json!({
+            "id": "ds-1",
+            "object": "chat.completion.chunk",
+            "created": 1,
+            "model": "deepseek-reasoner",
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": null }],
+        });
+        body.push_str(&format!("data: {}\n\n", chunk));
+    };
+    for word in REASONING.split_inclusive(' ') {
+        chunk(json!({ "reasoning_content": word }));
+    }
+    chunk(json!({ "content": DEEPSEEK_ANSWER }));
+    body.push_str("data: [DONE]\n\n");
+    ResponseTemplate::new(200).insert_header("content-type", "text/event-stream").set_body_string(body)
+}
+
+/// Claude's answer to a non-streaming request.
+fn claude_message() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(claude_message_body())
+}
+
+fn claude_message_body() -> Value {
+    json!({
+        "id": "msg-1",
+        "type": "message",
+        "role": "assistant",
+        "model": "claude-3-7-sonnet-20250219",
+        "content": [{ "type": "text", "text": CLAUDE_ANSWER }],
+        "stop_reason": "end_turn",
+        "stop_sequence": null,
+        "usage": { "input_tokens": 12, "output_tokens": 6 },
+    })
+}
+
+/// Claude's answer to a streaming request, in two text deltas.
+fn claude_stream() -> ResponseTemplate {
+    let (first, second) = CLAUDE_ANSWER.split_at(8);
+    let events = [
+        json!({ "type": "message_start", "message": {
+            "id": "msg-1", "type": "message", "role": "assistant", "model": "claude-3-7-sonnet-20250219",
+            "content": [], "usage": { "input_tokens": 12, "output_tokens": 1 },
+        }}),
+        json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } }),
+        json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": first } }),
+        json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": second } }),
+        json!({ "type": "content_block_stop", "index": 0 }),
+        json!({ "type": "message_delta", "delta": { "stop_reason": "end_turn" }, "usage": { "output_tokens": 6 } }),
+        json!({ "type": "message_stop" }),
+    ];
+    let body: String = events
+        .iter()
+        .map(|event| format!("event: {}\ndata: {}\n\n", event["type"].as_str().unwrap(), event))
+        .collect();
+    ResponseTemplate::new(200).set_body_raw(body, "text/event-stream")
+}
+
+/// Claude's streamed answer, sent in the given text deltas.
+fn claude_stream_of(deltas: &[&str]) -> ResponseTemplate {
+    let mut events = vec![
+        json!({ "type": "message_start", "message": {
+            "id": "msg-1", "type": "message", "role": "assistant", "model": "claude-3-7-sonnet-20250219",
+            "content": [], "usage": { "input_tokens": 12, "output_tokens": 1 },
+        }}),
+        json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } }),
+    ];
+    for text in deltas {
+        events.push(json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": text } }));
+    }
+    events.push(json!({ "type": "content_block_stop", "index": 0 }));
+    events.push(json!({ "type": "message_delta", "delta": { "stop_reason": "end_turn" }, "usage": { "output_tokens": 6 } }));
+    events.push(json!({ "type": "message_stop" }));
+    let body: String = events
+        .iter()
+        .map(|event| format!("event: {}\ndata: {}\n\n", event["type"].as_str().unwrap(), event))
+        .collect();
+    ResponseTemplate::new(200).set_body_raw(body, "text/event-stream")
+}
+
+async fn mount_upstreams(harness: &Harness) {
+    mount_deepseek(harness).await;
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({ "stream": true })))
+        .respond_with(claude_stream())
+        .mount(&harness.claude)
+        .await;
+    Mock::given(method("POST")).respond_with(claude_message()).mount(&harness.claude).await;
+}
+
+/// Mounts DeepSeek's answers only, for tests with their own Claude upstream.
+async fn mount_deepseek(harness: &Harness) {
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({ "stream": true })))
+        .respond_with(deepseek_stream())
+        .mount(&harness.deepseek)
+        .await;
+    Mock::given(method("POST")).respond_with(deepseek_completion()).mount(&harness.deepseek).await;
+}
+
+/// Reasoning and answer text of a stream's chunks, with the chunks parsed.
+fn stream_text(events: &[String]) -> (Vec<Value>, String, String) {
+    let chunks: Vec<Value> = events[..events.len() - 1]
+        .iter()
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    let text = |field: &str| -> String {
+        chunks
+            .iter()
+            .filter_map(|chunk| chunk["choices"][0]["delta"][field].as_str())
+            .collect()
+    };
+    let (reasoning, content) = (text("reasoning_content"), text("content"));
+    (chunks, reasoning, content)
+}
+
+#[tokio::test]
+async fn non_streaming_normal_mode_combines_both_stages() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    mount_upstreams(&harness).await;
+
+    let response = harness.chat(request("normal", false)).await;
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["object"], "chat.completion");
+    assert_eq!(body["choices"][0]["message"]["content"], CLAUDE_ANSWER);
+    assert_eq!(body["choices"][0]["message"]["reasoning_content"], REASONING);
+
+    // Claude收到DeepSeek的推理而不是其回答
+    let claude = harness.claude_requests().await;
+    assert_eq!(claude.len(), 1);
+    let sent = claude[0].to_string();
+    assert!(sent.contains(REASONING.trim()));
+    assert!(!sent.contains(DEEPSEEK_ANSWER));
+}
+
+#[tokio::test]
+async fn non_streaming_full_mode_passes_deepseek_answer_to_claude() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    mount_upstreams(&harness).await;
+
+    let response = harness.chat(request("full", false)).await;
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["choices"][0]["message"]["content"], CLAUDE_ANSWER);
+
+    let claude = harness.claude_requests().await;
+    assert_eq!(claude.len(), 1);
+    assert!(claude[0].to_string().contains(DEEPSEEK_ANSWER));
+}
+
+#[tokio::test]
+async fn streaming_normal_mode_frames_reasoning_then_answer() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    mount_upstreams(&harness).await;
+
+    let response = harness.chat(request("normal", true)).await;
+    assert_eq!(response.status(), 200);
+    assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/event-stream"));
+    let events = sse_data(&response.text().await.unwrap());
+
+    assert_eq!(events.last().map(String::as_str), Some("[DONE]"));
+    let (chunks, reasoning, content) = stream_text(&events);
+    assert!(chunks.iter().all(|chunk| chunk["object"] == "chat.completion.chunk"));
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    assert_eq!(reasoning, REASONING);
+    assert_eq!(content, CLAUDE_ANSWER);
+
+    // 所有推理增量都在回答增量之前
+    let first_content = chunks.iter().position(|chunk| chunk["choices"][0]["delta"]["content"].is_string()).unwrap();
+    let last_reasoning = chunks
+        .iter()
+        .rposition(|chunk| chunk["choices"][0]["delta"]["reasoning_content"].is_string())
+        .unwrap();
+    assert!(last_reasoning < first_content);
+
+    let finish = chunks.last().unwrap();
+    assert_eq!(finish["choices"][0]["finish_reason"], "stop");
+    assert!(chunks.iter().all(|chunk| chunk["id"] == finish["id"]));
+}
+
+#[tokio::test]
+async fn streaming_full_mode_streams_deepseek_answer_and_claude_answer() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    mount_upstreams(&harness).await;
+
+    let response = harness.chat(request("full", true)).await;
+    assert_eq!(response.status(), 200);
+    let events = sse_data(&response.text().await.unwrap());
+
+    assert_eq!(events.last().map(String::as_str), Some("[DONE]"));
+    let (chunks, reasoning, content) = stream_text(&events);
+    // full模式只转发DeepSeek的回答，不转发其推理
+    assert_eq!(reasoning, format!("deepseek原始回答:{}", DEEPSEEK_ANSWER));
+    assert_eq!(content, CLAUDE_ANSWER);
+    assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "stop");
+
+    let claude = harness.claude_requests().await;
+    assert_eq!(claude.len(), 1);
+    assert!(claude[0].to_string().contains(DEEPSEEK_ANSWER));
+}
+
+#[tokio::test]
+async fn typed_deepseek_options_reach_the_reasoning_stage() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.deepseek.defaults.max_tokens = Some(4096);
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    let mut body = request("normal", false);
+    body["deepseek"] = json!({ "model": "v3", "temperature": 0.3, "presence_penalty": 0.5 });
+    assert_eq!(harness.chat(body).await.status(), 200);
+    let requests = harness.deepseek.received_requests().await.unwrap();
+    let sent: Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(sent["model"], "deepseek-v3-250324");
+    assert_eq!(sent["temperature"], 0.3);
+    assert_eq!(sent["presence_penalty"], 0.5);
+    assert_eq!(sent["max_tokens"], 4096);
+
+    // 与请求体中同一字段冲突或越界的选项被拒绝
+    let mut body = request("normal", false);
+    body["deepseek"] = json!({ "temperature": 0.3 });
+    body["deepseek_config"]["body"] = json!({ "temperature": 0.9 });
+    assert_eq!(harness.chat(body).await.status(), 400);
+    let mut body = request("normal", false);
+    body["deepseek"] = json!({ "temperature": 3.0 });
+    assert_eq!(harness.chat(body).await.status(), 400);
+    assert_eq!(harness.deepseek.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn a_canary_fallback_keeps_the_deepseek_defaults() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.deepseek.defaults.max_tokens = Some(4096);
+        config.canary = serde_json::from_value(json!({ "percent": 100, "anthropic_model": "claude-canary" })).unwrap();
+        config.keys = vec![serde_json::from_value(json!({
+            "key": "sk-stable",
+            "name": "stable",
+            "allowed_models": ["deepseek*", "claude-3*", "wild-*"],
+            "deepseek_api_key": "ds-upstream",
+            "anthropic_api_key": "claude-upstream",
+        }))
+        .unwrap()];
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    // 密钥不允许金丝雀模型，请求回退到稳定配置，默认值照样补全
+    let response = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("sk-stable")
+        .json(&request("normal", false))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200, "{}", response.text().await.unwrap());
+    let requests = harness.deepseek.received_requests().await.unwrap();
+    let sent: Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(sent["max_tokens"], 4096);
+    assert_ne!(harness.claude_requests().await[0]["model"], "claude-canary");
+}
+
+#[tokio::test]
+async fn allowlisted_headers_are_forwarded_without_replacing_credentials() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    mount_upstreams(&harness).await;
+
+    let mut body = request("normal", false);
+    body["anthropic_config"]["headers"] = json!({ "x-api-key": "stolen", "x-custom": "kept" });
+    let response = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("deepseek-token")
+        .header("X-Anthropic-API-Token", "claude-token")
+        .header("anthropic-beta", "prompt-caching-2024-07-31")
+        .header("traceparent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+        .header("x-not-listed", "dropped")
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let claude = &harness.claude.received_requests().await.unwrap()[0];
+    let header = |name: &str| claude.headers.get(name).map(|value| value.to_str().unwrap().to_string());
+    assert_eq!(header("anthropic-beta").as_deref(), Some("prompt-caching-2024-07-31"));
+    assert!(header("traceparent").is_some());
+    assert_eq!(header("x-custom").as_deref(), Some("kept"));
+    assert_eq!(header("x-api-key").as_deref(), Some("claude-token"));
+    assert_eq!(header("x-not-listed"), None);
+
+    // DeepSeek只收到自己白名单内的请求头
+    let deepseek = &harness.deepseek.received_requests().await.unwrap()[0];
+    assert!(deepseek.headers.get("traceparent").is_some());
+    assert!(deepseek.headers.get("anthropic-beta").is_none());
+}
+
+#[tokio::test]
+async fn managed_keys_never_forward_the_bearer_token_upstream() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.auth.managed_keys = true;
+        config.keys = vec![serde_json::from_value(json!({
+            "key": "sk-virtual",
+            "name": "managed",
+            "deepseek_api_key": "ds-upstream",
+        }))
+        .unwrap()];
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    // 不是虚拟密钥的Bearer令牌被拒绝，而不是当作DeepSeek密钥
+    assert_eq!(harness.chat(request("normal", false)).await.status(), 401);
+    assert!(harness.deepseek.received_requests().await.unwrap().is_empty());
+
+    let response = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("sk-virtual")
+        .header("X-Anthropic-API-Token", "claude-token")
+        .json(&request("normal", false))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let deepseek = &harness.deepseek.received_requests().await.unwrap()[0];
+    assert_eq!(deepseek.headers.get("authorization").unwrap(), "Bearer ds-upstream");
+}
+
+#[tokio::test]
+async fn provider_profile_sends_both_selection_paths_to_its_account() {
+    let backup = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({ "stream": true })))
+        .respond_with(deepseek_stream())
+        .mount(&backup)
+        .await;
+    Mock::given(method("POST")).respond_with(deepseek_completion()).mount(&backup).await;
+    let backup_url = format!("{}/chat/completions", backup.uri());
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.providers.insert(
+            "backup".to_string(),
+            serde_json::from_value(json!({
+                "deepseek_api_url": backup_url,
+                "deepseek_model": "deepseek-r1-backup",
+                "deepseek_api_key": "ds-backup",
+                "aliases": ["r1-backup"],
+            }))
+            .unwrap(),
+        );
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    let response = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("deepseek-token")
+        .header("X-Anthropic-API-Token", "claude-token")
+        .header("X-Provider-Profile", "backup")
+        .json(&request("normal", false))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // 流式请求在独立任务中运行，同样使用别名选择的账户
+    let mut body = request("normal", true);
+    body["deepseek_config"]["body"] = json!({ "model": "r1-backup" });
+    let response = harness.chat(body).await;
+    assert_eq!(response.status(), 200);
+    let (_, reasoning, _) = stream_text(&sse_data(&response.text().await.unwrap()));
+    assert_eq!(reasoning, REASONING);
+
+    let requests = backup.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 2);
+    for request in &requests {
+        assert_eq!(request.headers.get("authorization").unwrap(), "Bearer ds-backup");
+        let sent: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(sent["model"], "deepseek-r1-backup");
+    }
+    assert!(harness.deepseek.received_requests().await.unwrap().is_empty());
+
+    let mut body = request("normal", false);
+    body["deepseek_config"]["body"] = json!({ "model": "r1-backup" });
+    let response = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("deepseek-token")
+        .header("X-Provider-Profile", "missing")
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn chat_repl_streams_answers_and_saves_the_conversation() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    mount_upstreams(&harness).await;
+
+    let path = std::env::temp_dir().join(format!("deepclaude-repl-{}.md", std::process::id()));
+    let input = format!("/mode normal\nWhat is six times seven?\n/save {}\n/quit\n", path.display());
+    let options = ChatOptions {
+        url: harness.url.clone(),
+        key: Some("deepseek-token".to_string()),
+        anthropic_key: Some("claude-token".to_string()),
+        mode: None,
+        model: None,
+        color: false,
+    };
+    let mut output = Vec::new();
+    repl::run(options, input.as_bytes(), &mut output).await.unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains(&format!("{}\n\n---\n\n", REASONING)), "{}", output);
+    assert!(output.contains(CLAUDE_ANSWER));
+    let saved = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(saved.starts_with("## User\n\nWhat is six times seven?"));
+    assert!(saved.contains(REASONING.trim()));
+    assert!(saved.trim_end().ends_with(CLAUDE_ANSWER));
+
+    assert!(output.contains("模式已切换为normal"));
+}
+
+#[tokio::test]
+async fn debug_complete_answers_in_plain_text() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| config.debug.complete = true).await;
+    mount_upstreams(&harness).await;
+    let complete = |body: Value| {
+        harness
+            .client
+            .post(format!("{}/debug/complete", harness.url))
+            .bearer_auth("deepseek-token")
+            .header("X-Anthropic-API-Token", "claude-token")
+            .json(&body)
+            .send()
+    };
+
+    let response = complete(json!({ "prompt": "What is six times seven?", "mode": "normal" })).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/plain"));
+    assert_eq!(
+        response.text().await.unwrap(),
+        format!("{}\n---\n{}\n", REASONING.trim(), CLAUDE_ANSWER)
+    );
+
+    // 错误保留状态码，正文为一行纯文本
+    let response = harness
+        .client
+        .post(format!("{}/debug/complete", harness.url))
+        .json(&json!({ "prompt": "hi" }))
+        .send()
+        .await
+        .unwrap();
+    assert!(!response.status().is_success());
+    assert!(response.text().await.unwrap().starts_with("error: "));
+}
+
+#[tokio::test]
+async fn openapi_document_describes_the_chat_extensions() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+
+    let document: Value = harness
+        .client
+        .get(format!("{}/openapi.json", harness.url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(document["openapi"].as_str().unwrap().starts_with("3."));
+    assert_eq!(
+        document["paths"]["/v1/chat/completions"]["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/ApiRequest"
+    );
+    let schemas = &document["components"]["schemas"];
+    for field in ["mode", "verbose", "deepseek"] {
+        assert!(schemas["ApiRequest"]["properties"][field].is_object(), "ApiRequest.{} missing", field);
+    }
+    assert!(schemas["ResponseMessage"]["properties"]["reasoning_content"].is_object());
+    assert!(schemas["ApiRequest"]["properties"]["scope"].is_null());
+
+    let docs = harness.client.get(format!("{}/docs/", harness.url)).send().await.unwrap();
+    assert_eq!(docs.status(), 200);
+    assert!(docs.text().await.unwrap().contains("swagger"));
+}
+
+#[tokio::test]
+async fn finish_reason_follows_the_upstream_stop_reason() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({ "stream": true })))
+        .respond_with(deepseek_stream())
+        .mount(&harness.deepseek)
+        .await;
+    Mock::given(method("POST")).respond_with(deepseek_completion()).mount(&harness.deepseek).await;
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({ "stream": true })))
+        .respond_with(claude_stream())
+        .mount(&harness.claude)
+        .await;
+    let mut truncated = claude_message_body();
+    truncated["stop_reason"] = json!("max_tokens");
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(truncated))
+        .mount(&harness.claude)
+        .await;
+
+    let mut body = request("normal", false);
+    body["verbose"] = json!(true);
+    let response: Value = harness.chat(body).await.json().await.unwrap();
+    assert_eq!(response["choices"][0]["finish_reason"], "length");
+    assert_eq!(response["choices"][0]["stop_reason"], "max_tokens");
+
+    // 非verbose请求不带上游的原始原因
+    let response: Value = harness.chat(request("normal", false)).await.json().await.unwrap();
+    assert_eq!(response["choices"][0]["finish_reason"], "length");
+    assert!(response["choices"][0].get("stop_reason").is_none());
+
+    let mut body = request("normal", true);
+    body["verbose"] = json!(true);
+    let events = sse_data(&harness.chat(body).await.text().await.unwrap());
+    let (chunks, _, _) = stream_text(&events);
+    let finish = &chunks.last().unwrap()["choices"][0];
+    assert_eq!(finish["finish_reason"], "stop");
+    assert_eq!(finish["stop_reason"], "end_turn");
+}
+
+#[tokio::test]
+async fn verbose_responses_carry_the_upstream_status_and_headers() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    Mock::given(method("POST"))
+        .respond_with(
+            deepseek_completion()
+                .insert_header("x-request-id", "ds-req-1")
+                .insert_header("set-cookie", "session=secret"),
+        )
+        .mount(&harness.deepseek)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(
+            claude_message()
+                .insert_header("request-id", "req_1")
+                .insert_header("anthropic-ratelimit-tokens-remaining", "900"),
+        )
+        .mount(&harness.claude)
+        .await;
+
+    let mut body = request("normal", false);
+    body["verbose"] = json!(true);
+    let response: Value = harness.chat(body).await.json().await.unwrap();
+    let deepseek = &response["upstream"]["deepseek"];
+    assert_eq!(deepseek["status"], 200);
+    assert_eq!(deepseek["headers"]["x-request-id"], "ds-req-1");
+    assert!(deepseek["headers"].get("set-cookie").is_none());
+    assert_eq!(deepseek["body"]["id"], "ds-1");
+    let anthropic = &response["upstream"]["anthropic"];
+    assert_eq!(anthropic["headers"]["request-id"], "req_1");
+    assert_eq!(anthropic["headers"]["anthropic-ratelimit-tokens-remaining"], "900");
+    assert_eq!(anthropic["body"]["id"], "msg-1");
+
+    let response: Value = harness.chat(request("normal", false)).await.json().await.unwrap();
+    assert!(response.get("upstream").is_none());
+}
+
+#[tokio::test]
+async fn vetoed_stream_ends_with_content_filter() {
+    let script = std::env::temp_dir().join(format!("deepclaude-moderation-{}.rhai", std::process::id()));
+    std::fs::write(&script, "fn post_response() { throw \"blocked\"; }").unwrap();
+    let path = script.to_string_lossy().into_owned();
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.hooks.scripts = vec![path];
+        config.streams.content_filter_results = true;
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    let events = sse_data(&harness.chat(request("normal", true)).await.text().await.unwrap());
+    assert_eq!(events.last().map(String::as_str), Some("[DONE]"));
+    let (chunks, reasoning, content) = stream_text(&events);
+    assert_eq!(reasoning, REASONING);
+    assert!(content.is_empty());
+    let finish = &chunks.last().unwrap()["choices"][0];
+    assert_eq!(finish["finish_reason"], "content_filter");
+    assert_eq!(finish["content_filter_results"]["hate"]["filtered"], true);
+    std::fs::remove_file(script).unwrap();
+}
+
+#[tokio::test]
+async fn response_model_name_follows_the_configured_format() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.response.model_name = deepclaude::config::ModelName::Requested;
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    let mut body = request("normal", false);
+    body["model"] = json!("deepclaude-pro");
+    let response: Value = harness.chat(body.clone()).await.json().await.unwrap();
+    assert_eq!(response["model"], "deepclaude-pro");
+
+    body["stream"] = json!(true);
+    let events = sse_data(&harness.chat(body).await.text().await.unwrap());
+    let (chunks, _, _) = stream_text(&events);
+    assert!(chunks.iter().all(|chunk| chunk["model"] == "deepclaude-pro"));
+
+    // 请求未指定模型时回退为组合名称
+    let mut body = request("normal", false);
+    body.as_object_mut().unwrap().remove("model");
+    let response: Value = harness.chat(body).await.json().await.unwrap();
+    assert!(response["model"].as_str().unwrap().ends_with("_claude-3-7-sonnet-20250219"));
+}
+
+#[tokio::test]
+async fn stage_model_names_tag_each_chunk_with_its_stage() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.response.model_name = deepclaude::config::ModelName::Stage;
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    let mut body = request("normal", true);
+    body["deepseek_config"]["body"]["model"] = json!("deepseek-reasoner");
+    body["anthropic_config"]["body"]["model"] = json!("claude-3-7-sonnet-20250219");
+    let events = sse_data(&harness.chat(body).await.text().await.unwrap());
+    let (chunks, _, _) = stream_text(&events);
+    let models = |field: &str| -> Vec<&Value> {
+        chunks
+            .iter()
+            .filter(|chunk| chunk["choices"][0]["delta"][field].is_string())
+            .map(|chunk| &chunk["model"])
+            .collect()
+    };
+    let reasoning = models("reasoning_content");
+    let content = models("content");
+    assert!(!reasoning.is_empty() && reasoning.iter().all(|model| *model == "deepseek-reasoner"));
+    assert!(!content.is_empty() && content.iter().all(|model| *model == "claude-3-7-sonnet-20250219"));
+
+    // 非流式响应报告回答模型
+    let response: Value = harness.chat(request("normal", false)).await.json().await.unwrap();
+    assert_eq!(response["model"], "claude-3-7-sonnet-20250219");
+}
+
+#[tokio::test]
+async fn session_requests_send_the_system_prompt_as_a_cached_prefix() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.prompt_cache.enabled = true;
+        config.prompt_cache.min_prefix_chars = 16;
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let mut body = request("normal", false);
+    body["system"] = json!("You are a careful arithmetic tutor.");
+
+    for _ in 0..2 {
+        let response = harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth("deepseek-token")
+            .header("X-Anthropic-API-Token", "claude-token")
+            .header("X-Session-Id", "session-1")
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+    // 不属于会话的请求照常发送纯文本系统提示词
+    assert_eq!(harness.chat(body).await.status(), 200);
+
+    let claude = harness.claude_requests().await;
+    assert_eq!(claude.len(), 3);
+    for sent in &claude[..2] {
+        assert_eq!(sent["system"][0]["cache_control"], json!({ "type": "ephemeral" }));
+        assert!(sent["system"][0]["text"].as_str().unwrap().contains("arithmetic tutor"));
+    }
+    assert!(claude[2]["system"].is_string());
+}
+
+#[tokio::test]
+async fn retries_reuse_the_captured_reasoning() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.reasoning_cache.enabled = true;
+    })
+    .await;
+    Mock::given(method("POST")).respond_with(deepseek_completion()).mount(&harness.deepseek).await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(500).set_body_json(json!({
+            "type": "error",
+            "error": { "type": "api_error", "message": "overloaded" },
+        })))
+        .up_to_n_times(1)
+        .mount(&harness.claude)
+        .await;
+    Mock::given(method("POST")).respond_with(claude_stream()).mount(&harness.claude).await;
+
+    let response = harness.chat(request("normal", false)).await;
+    assert!(!response.status().is_success());
+
+    // 重试时直接复用第一次的推理，流式请求同样适用
+    let response = harness.chat(request("normal", true)).await;
+    assert_eq!(response.status(), 200);
+    let (_, reasoning, content) = stream_text(&sse_data(&response.text().await.unwrap()));
+    assert_eq!(reasoning, REASONING);
+    assert_eq!(content, CLAUDE_ANSWER);
+
+    assert_eq!(harness.deepseek.received_requests().await.unwrap().len(), 1);
+    let claude = harness.claude_requests().await;
+    assert_eq!(claude.len(), 2);
+    assert!(claude[1].to_string().contains(REASONING));
+}
+
+#[tokio::test]
+async fn queued_interactive_requests_run_before_batch_requests() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.concurrency.max_in_flight = 1;
+        config.keys = ["batch", "ide"]
+            .iter()
+            .map(|name| {
+                serde_json::from_value(json!({
+                    "key": format!("sk-{}", name),
+                    "name": name,
+                    "priority": if *name == "batch" { "batch" } else { "interactive" },
+                    "deepseek_api_key": "ds-upstream",
+                }))
+                .unwrap()
+            })
+            .collect();
+    })
+    .await;
+    Mock::given(method("POST"))
+        .respond_with(deepseek_completion().set_delay(std::time::Duration::from_millis(300)))
+        .mount(&harness.deepseek)
+        .await;
+    Mock::given(method("POST")).respond_with(claude_message()).mount(&harness.claude).await;
+
+    let send = |key: &'static str, prompt: &'static str| {
+        let mut body = request("normal", false);
+        body["messages"][0]["content"] = json!(prompt);
+        let request = harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth(key)
+            .header("X-Anthropic-API-Token", "claude-token")
+            .json(&body);
+        tokio::spawn(async move { request.send().await.unwrap().status() })
+    };
+    let pause = || tokio::time::sleep(std::time::Duration::from_millis(100));
+
+    // 批处理请求占满槽位后，后到的交互式请求排在已排队的批处理请求之前
+    let running = send("sk-batch", "nightly job 1");
+    pause().await;
+    let queued = send("sk-batch", "nightly job 2");
+    pause().await;
+    let interactive = send("sk-ide", "quick question");
+    for task in [running, queued, interactive] {
+        assert_eq!(task.await.unwrap(), 200);
+    }
+
+    let prompts: Vec<String> = harness
+        .deepseek
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| request.body_json::<Value>().unwrap()["messages"][0]["content"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(prompts, ["nightly job 1", "quick question", "nightly job 2"]);
+}
+
+#[tokio::test]
+async fn long_answers_get_one_soft_limit_warning() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.soft_limit.output_tokens = 1;
+        config.soft_limit.wrap_up = true;
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    let response = harness.chat(request("normal", true)).await;
+    let (chunks, _, content) = stream_text(&sse_data(&response.text().await.unwrap()));
+    assert_eq!(content, CLAUDE_ANSWER);
+    let warnings: Vec<&Value> = chunks.iter().filter(|chunk| chunk.get("soft_limit").is_some()).collect();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0]["soft_limit"]["limit"], 1);
+    assert_eq!(warnings[0]["choices"][0]["delta"], json!({}));
+
+    let claude = harness.claude_requests().await;
+    assert!(claude[0]["system"].as_str().unwrap().contains("within about 1 tokens"));
+}
+
+#[tokio::test]
+async fn estimate_projects_the_cost_range_without_calling_upstreams() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    let mut body = request("normal", false);
+    body["deepseek_config"]["body"]["max_tokens"] = json!(1000);
+    body["anthropic_config"]["body"]["max_tokens"] = json!(500);
+
+    let response = harness.client.post(format!("{}/v1/estimate", harness.url)).json(&body).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let estimate: Value = response.json().await.unwrap();
+    let prompt = estimate["prompt_tokens"].as_u64().unwrap();
+    assert!(prompt > 0);
+    let stages = estimate["stages"].as_array().unwrap();
+    assert_eq!(stages[0]["max_output_tokens"], 1000);
+    // Claude阶段的输入最多还包括DeepSeek的全部输出
+    assert_eq!(stages[1]["input_tokens"]["max"], prompt + 1000);
+    assert_eq!(stages[1]["max_output_tokens"], 500);
+    let cost = &estimate["cost_usd"];
+    assert!(cost["min"].as_f64().unwrap() < cost["max"].as_f64().unwrap());
+
+    assert!(harness.deepseek.received_requests().await.unwrap().is_empty());
+    assert!(harness.claude_requests().await.is_empty());
+}
+
+#[tokio::test]
+async fn status_reports_a_model_down_after_repeated_failures() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(500).set_body_json(json!({
+            "error": { "message": "upstream down", "type": "server_error" },
+        })))
+        .mount(&harness.deepseek)
+        .await;
+    let mut body = request("normal", false);
+    body["deepseek_config"]["body"]["model"] = json!("deepseek-status-probe");
+    for _ in 0..3 {
+        assert!(!harness.chat(body.clone()).await.status().is_success());
+    }
+
+    let status: Value = harness.client.get(format!("{}/status", harness.url)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(status["status"], "down");
+    let probe = status["models"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|model| model["model"] == "deepseek-status-probe")
+        .unwrap();
+    assert_eq!(probe["state"], "down");
+    assert_eq!(probe["streaming"], false);
+    assert_eq!(probe["consecutive_failures"], 3);
+    assert_eq!(probe["error_rate"], 1.0);
+    assert!(probe["last_success"].is_null());
+    assert!(probe["last_failure"].is_string());
+}
+
+#[tokio::test]
+async fn responses_carry_the_conversation_affinity_key() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| config.affinity.enabled = true).await;
+    mount_upstreams(&harness).await;
+    let affinity = |response: &reqwest::Response| {
+        let key = response.headers()["x-deepclaude-affinity"].to_str().unwrap().to_string();
+        let cookie = response.headers()["set-cookie"].to_str().unwrap();
+        assert!(cookie.starts_with(&format!("deepclaude_affinity={};", key)));
+        key
+    };
+
+    // 同一对话的后续轮次得到相同的键
+    let first = affinity(&harness.chat(request("normal", false)).await);
+    let mut next_turn = request("normal", false);
+    next_turn["messages"].as_array_mut().unwrap().extend([
+        json!({ "role": "assistant", "content": CLAUDE_ANSWER }),
+        json!({ "role": "user", "content": "And times eight?" }),
+    ]);
+    assert_eq!(affinity(&harness.chat(next_turn).await), first);
+
+    let session = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("deepseek-token")
+        .header("X-Anthropic-API-Token", "claude-token")
+        .header("X-Session-Id", "session-1")
+        .json(&request("normal", false))
+        .send()
+        .await
+        .unwrap();
+    assert_ne!(affinity(&session), first);
+}
+
+#[tokio::test]
+async fn debug_tasks_list_running_streams_with_their_age() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.debug.echo = true;
+        config.debug.tasks = true;
+        config.admin.token = "admin-secret".to_string();
+    })
+    .await;
+    // 慢速的回显流在查询期间保持运行
+    let echo = harness
+        .client
+        .post(format!("{}/debug/echo-completions", harness.url))
+        .json(&json!({ "reasoning_tokens": 100, "tokens": 100, "tokens_per_sec": 5.0 }))
+        .send()
+        .await
+        .unwrap();
+    assert!(echo.status().is_success());
+
+    let tasks: Value = harness
+        .client
+        .get(format!("{}/debug/tasks", harness.url))
+        .bearer_auth("admin-secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(tasks["runtime"]["workers"].as_u64().unwrap() >= 1);
+    let task = &tasks["tasks"][0];
+    assert_eq!(task["kind"], "stream");
+    assert_eq!(task["mode"], "echo");
+    assert_eq!(task["state"], "reasoning");
+    assert!(task["age_ms"].as_i64().unwrap() >= task["state_age_ms"].as_i64().unwrap());
+    drop(echo);
+}
+
+#[tokio::test]
+async fn streams_past_the_maximum_duration_end_with_timeout() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.debug.echo = true;
+        config.streams.max_stream_duration_secs = 1;
+    })
+    .await;
+    // 回显流需要约20秒，超过时长限制
+    let body = harness
+        .client
+        .post(format!("{}/debug/echo-completions", harness.url))
+        .json(&json!({ "reasoning_tokens": 100, "tokens": 100, "tokens_per_sec": 10.0 }))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let events = sse_data(&body);
+    assert_eq!(events.last().map(String::as_str), Some("[DONE]"));
+    let (chunks, _, _) = stream_text(&events);
+    assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "timeout");
+}
+
+#[tokio::test]
+async fn only_the_opening_key_can_cancel_a_stream_and_its_usage_is_kept() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+        config.keys = ["alice", "mallory"]
+            .map(|name| {
+                serde_json::from_value(json!({
+                    "key": format!("sk-{}", name),
+                    "name": name,
+                    "deepseek_api_key": "ds-upstream",
+                    "anthropic_api_key": "claude-upstream",
+                }))
+                .unwrap()
+            })
+            .to_vec();
+    })
+    .await;
+    mount_deepseek(&harness).await;
+    // 回答迟迟不来，流停在回答阶段
+    Mock::given(method("POST"))
+        .respond_with(claude_stream().set_delay(std::time::Duration::from_secs(30)))
+        .mount(&harness.claude)
+        .await;
+
+    let mut response = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("sk-alice")
+        .json(&request("normal", true))
+        .send()
+        .await
+        .unwrap();
+    let mut body = String::new();
+    while !body.contains("responder_started") {
+        body.push_str(&String::from_utf8_lossy(&response.chunk().await.unwrap().unwrap()));
+    }
+    let first: Value = serde_json::from_str(&sse_data(&body)[0]).unwrap();
+    let cancel = |token: &'static str| {
+        let url = format!("{}/v1/chat/completions/{}/cancel", harness.url, first["id"].as_str().unwrap());
+        let request = harness.client.post(url);
+        async move {
+            match token {
+                "" => request,
+                token => request.bearer_auth(token),
+            }
+            .send()
+            .await
+            .unwrap()
+            .status()
+        }
+    };
+    assert_eq!(cancel("").await, 401);
+    // 其他密钥看不到这个流
+    assert_eq!(cancel("sk-mallory").await, 404);
+    assert_eq!(cancel("sk-alice").await, 200);
+
+    while let Some(chunk) = response.chunk().await.unwrap() {
+        body.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    let events = sse_data(&body);
+    let last: Value = serde_json::from_str(&events[events.len() - 2]).unwrap();
+    assert_eq!(last["error"]["type"], "cancelled");
+
+    // 取消前产生的推理用量照样记录
+    let mut total = Value::Null;
+    for _ in 0..50 {
+        let report: Value = harness
+            .client
+            .get(format!("{}/v1/usage", harness.url))
+            .bearer_auth("sk-alice")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        total = report["total"].clone();
+        if total["requests"] == 1 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(total["requests"], 1);
+    assert!(total["output_tokens"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn streams_ended_at_the_maximum_duration_still_record_usage() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.streams.max_stream_duration_secs = 1;
+    })
+    .await;
+    mount_deepseek(&harness).await;
+    // 回答方保持连接却始终不回答
+    Mock::given(method("POST"))
+        .respond_with(claude_stream().set_delay(std::time::Duration::from_secs(30)))
+        .mount(&harness.claude)
+        .await;
+
+    let body = harness.chat(request("normal", true)).await.text().await.unwrap();
+    let (chunks, _, _) = stream_text(&sse_data(&body));
+    assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "timeout");
+
+    // 终止前产生的推理和回答用量照样记录
+    harness.state.usage.flush().await;
+    let records = harness.state.usage.read(None, None).await.unwrap();
+    let records: Vec<_> = records.iter().filter(|record| record.id == chunks[0]["id"]).collect();
+    assert_eq!(records.len(), 1);
+    assert!(records[0].deepseek_output_tokens > 0);
+    assert!(records[0].anthropic_input_tokens > 0);
+}
+
+#[tokio::test]
+async fn new_streams_are_shed_above_the_descriptor_limit() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| config.shedding.max_open_fds = 1).await;
+    mount_upstreams(&harness).await;
+
+    let response = harness.chat(request("normal", true)).await;
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()["retry-after"], "10");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "overloaded");
+
+    // 非流式请求仍然正常处理
+    assert!(harness.chat(request("normal", false)).await.status().is_success());
+}
+
+#[tokio::test]
+async fn passthrough_routes_inject_the_upstream_key_and_meter_usage() {
+    // 透传路由的上游独立于流水线的上游
+    let upstream = MockServer::start().await;
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.keys = vec![serde_json::from_value(json!({
+            "key": "sk-direct",
+            "name": "direct-calls",
+            "deepseek_api_key": "ds-upstream",
+            "anthropic_api_key": "claude-upstream",
+        }))
+        .unwrap()];
+        config.proxy.deepseek = true;
+        config.proxy.anthropic = true;
+        config.proxy.deepseek_url = upstream.uri();
+        config.proxy.anthropic_url = upstream.uri();
+    })
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(deepseek_completion())
+        .mount(&upstream)
+        .await;
+    Mock::given(method("POST")).and(path("/v1/messages")).respond_with(claude_stream()).mount(&upstream).await;
+
+    let body = json!({ "model": "deepseek-chat", "messages": [{ "role": "user", "content": "Hi" }] });
+    let response = harness
+        .client
+        .post(format!("{}/deepseek/v1/chat/completions", harness.url))
+        .bearer_auth("sk-direct")
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let answer: Value = response.json().await.unwrap();
+    assert_eq!(answer["choices"][0]["message"]["content"], DEEPSEEK_ANSWER);
+
+    let response = harness
+        .client
+        .post(format!("{}/anthropic/v1/messages", harness.url))
+        .bearer_auth("sk-direct")
+        .json(&json!({ "model": "claude-3-7-sonnet-20250219", "stream": true, "messages": [] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let events = response.text().await.unwrap();
+    assert!(events.contains("message_stop"));
+
+    // 调用方的虚拟密钥被替换为上游密钥，请求体原样转发
+    let received = upstream.received_requests().await.unwrap();
+    assert_eq!(received[0].headers.get("authorization").unwrap(), "Bearer ds-upstream");
+    assert_eq!(received[0].body_json::<Value>().unwrap(), body);
+    assert!(received[1].headers.get("authorization").is_none());
+    assert_eq!(received[1].headers.get("x-api-key").unwrap(), "claude-upstream");
+    assert!(received[1].headers.get("anthropic-version").is_some());
+
+    // 两次调用各记一条用量，写入是异步的
+    let mut total = Value::Null;
+    for _ in 0..50 {
+        let report: Value = harness
+            .client
+            .get(format!("{}/v1/usage", harness.url))
+            .bearer_auth("sk-direct")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        total = report["total"].clone();
+        if total["requests"] == 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(total["requests"], 2);
+    assert_eq!(total["input_tokens"], 10 + 12);
+    assert_eq!(total["output_tokens"], 20 + 6);
+}
+
+#[tokio::test]
+async fn passthrough_routes_are_off_by_default() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    let response = harness
+        .client
+        .post(format!("{}/deepseek/v1/chat/completions", harness.url))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn gemini_requests_run_through_the_pipeline() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    mount_upstreams(&harness).await;
+    let gemini = |method: &str| {
+        harness
+            .client
+            .post(format!("{}/v1beta/models/deepclaude:{}", harness.url, method))
+            .header("x-goog-api-key", "deepseek-token")
+            .header("X-Anthropic-API-Token", "claude-token")
+            .json(&json!({
+                "systemInstruction": { "parts": [{ "text": "Be brief." }] },
+                "contents": [{ "role": "user", "parts": [{ "text": "What is six times seven?" }] }],
+                "generationConfig": { "temperature": 0.2, "maxOutputTokens": 256 },
+            }))
+    };
+
+    let response: Value = gemini("generateContent").send().await.unwrap().json().await.unwrap();
+    let candidate = &response["candidates"][0];
+    assert_eq!(candidate["content"]["role"], "model");
+    assert_eq!(candidate["content"]["parts"][0], json!({ "text": REASONING, "thought": true }));
+    assert_eq!(candidate["content"]["parts"][1]["text"], CLAUDE_ANSWER);
+    assert_eq!(candidate["finishReason"], "STOP");
+    let claude = &harness.claude_requests().await[0];
+    assert_eq!(claude["temperature"], 0.2);
+    assert_eq!(claude["max_tokens"], 256);
+
+    let body = gemini("streamGenerateContent").query(&[("alt", "sse")]).send().await.unwrap().text().await.unwrap();
+    let events: Vec<Value> = sse_data(&body).iter().map(|data| serde_json::from_str(data).unwrap()).collect();
+    let text = |thought: bool| -> String {
+        events
+            .iter()
+            .flat_map(|event| event["candidates"][0]["content"]["parts"].as_array().unwrap())
+            .filter(|part| part["thought"].as_bool().unwrap_or(false) == thought)
+            .map(|part| part["text"].as_str().unwrap())
+            .collect()
+    };
+    assert_eq!(text(true), REASONING);
+    assert_eq!(text(false), CLAUDE_ANSWER);
+    assert_eq!(events.last().unwrap()["candidates"][0]["finishReason"], "STOP");
+
+    // 不带alt=sse时流式回答是一个JSON数组
+    let array: Vec<Value> = gemini("streamGenerateContent").send().await.unwrap().json().await.unwrap();
+    assert_eq!(array.len(), events.len());
+
+    let error = gemini("countTokens").send().await.unwrap();
+    assert_eq!(error.status(), 404);
+    let error: Value = error.json().await.unwrap();
+    assert_eq!(error["error"]["status"], "NOT_FOUND");
+}
+
+#[tokio::test]
+async fn grpc_chat_streams_reasoning_and_answer_events() {
+    use deepclaude::grpc::{self, chat_event::Event, delta::Kind, DeepClaudeClient};
+
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    mount_upstreams(&harness).await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(grpc::serve(listener, harness.state.clone(), std::future::pending()));
+
+    let mut client = DeepClaudeClient::connect(format!("http://{}", address)).await.unwrap();
+    let mut request = tonic::Request::new(grpc::ChatRequest {
+        model: "deepclaude".to_string(),
+        messages: vec![grpc::Message {
+            role: "user".to_string(),
+            content: "What is six times seven?".to_string(),
+        }],
+        ..Default::default()
+    });
+    request.metadata_mut().insert("authorization", "Bearer deepseek-token".parse().unwrap());
+    request.metadata_mut().insert("x-anthropic-api-token", "claude-token".parse().unwrap());
+    let mut stream = client.chat(request).await.unwrap().into_inner();
+
+    let mut reasoning = String::new();
+    let mut answer = String::new();
+    let mut finish = None;
+    while let Some(event) = stream.message().await.unwrap() {
+        match event.event.unwrap() {
+            Event::Delta(delta) if delta.kind == Kind::Reasoning as i32 => reasoning.push_str(&delta.text),
+            Event::Delta(delta) => answer.push_str(&delta.text),
+            Event::Finish(event) => finish = Some(event),
+            Event::Error(error) => panic!("unexpected error event: {}", error.message),
+        }
+    }
+    assert_eq!(reasoning, REASONING);
+    assert_eq!(answer, CLAUDE_ANSWER);
+    assert_eq!(finish.unwrap().finish_reason, "stop");
+
+    // 请求体无效时在流开始前返回对应的状态码
+    let status = client
+        .chat(grpc::ChatRequest {
+            deepseek_body_json: "{".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn rules_rewrite_or_reject_matching_requests() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.rules = serde_json::from_value(json!([
+            {
+                "name": "research",
+                "when": { "header": "x-team: research" },
+                "anthropic_model": "claude-3-opus-20240229",
+                "system": "Cite your sources.",
+            },
+            {
+                "name": "no-keys",
+                "when": { "prompt": "(?i)private key" },
+                "reject": "Requests must not contain private keys",
+            },
+        ]))
+        .unwrap();
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let send = |team: &str, body: Value| {
+        harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth("deepseek-token")
+            .header("X-Anthropic-API-Token", "claude-token")
+            .header("x-team", team)
+            .json(&body)
+            .send()
+    };
+
+    assert_eq!(send("research", request("normal", false)).await.unwrap().status(), 200);
+    let claude = &harness.claude_requests().await[0];
+    assert_eq!(claude["model"], "claude-3-opus-20240229");
+    assert!(claude["system"].to_string().contains("Cite your sources."));
+
+    // 不匹配的请求保持原样
+    assert_eq!(send("sales", request("normal", false)).await.unwrap().status(), 200);
+    assert_ne!(harness.claude_requests().await[1]["model"], "claude-3-opus-20240229");
+
+    let mut leaky = request("normal", false);
+    leaky["messages"][0]["content"] = json!("Here is my PRIVATE KEY, what is it for?");
+    let response = send("sales", leaky).await.unwrap();
+    assert_eq!(response.status(), 403);
+    assert!(response.text().await.unwrap().contains("no-keys"));
+    assert_eq!(harness.claude_requests().await.len(), 2);
+}
+
+#[tokio::test]
+async fn base_system_prompt_is_merged_per_strategy() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.system_prompt = serde_json::from_value(json!({ "base": "Answer in haiku.", "strategy": "append" })).unwrap();
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    let mut body = request("normal", false);
+    body["system"] = json!("Be brief.");
+    assert_eq!(harness.chat(body).await.status(), 200);
+    let deepseek: Value = harness.deepseek.received_requests().await.unwrap()[0].body_json().unwrap();
+    assert_eq!(deepseek["messages"][0]["content"], "Be brief.\n\nAnswer in haiku.");
+    assert!(harness.claude_requests().await[0]["system"].to_string().contains("Be brief.\\n\\nAnswer in haiku."));
+
+    // 没有系统提示的请求只使用基础提示
+    assert_eq!(harness.chat(request("normal", false)).await.status(), 200);
+    let deepseek: Value = harness.deepseek.received_requests().await.unwrap()[1].body_json().unwrap();
+    assert_eq!(deepseek["messages"][0], json!({ "role": "system", "content": "Answer in haiku." }));
+}
+
+#[tokio::test]
+async fn system_prompt_forwarding_follows_the_mode_policy() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.system_prompt.forward =
+            serde_json::from_value(json!({ "full": "claude", "normal": "deepseek" })).unwrap();
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let with_system = |mode: &str| {
+        let mut body = request(mode, false);
+        body["system"] = json!("Reply with a limerick.");
+        body
+    };
+
+    // full模式只把系统提示发给Claude，DeepSeek仍有架构师提示
+    assert_eq!(harness.chat(with_system("full")).await.status(), 200);
+    let deepseek: Value = harness.deepseek.received_requests().await.unwrap()[0].body_json().unwrap();
+    let deepseek_system = deepseek["messages"][0]["content"].as_str().unwrap();
+    assert!(deepseek_system.starts_with("Act as an expert architect engineer"));
+    assert!(!deepseek_system.contains("limerick"));
+    assert!(harness.claude_requests().await[0]["system"].to_string().contains("limerick"));
+
+    // normal模式只发给DeepSeek
+    assert_eq!(harness.chat(with_system("normal")).await.status(), 200);
+    let deepseek: Value = harness.deepseek.received_requests().await.unwrap()[1].body_json().unwrap();
+    assert_eq!(deepseek["messages"][0]["content"], "Reply with a limerick.");
+    assert!(!harness.claude_requests().await[1].to_string().contains("limerick"));
+}
+
+#[tokio::test]
+async fn gateway_usage_is_priced_from_its_token_counts() {
+    let harness = Harness::start_with(ClaudeApi::OpenAi, |config| {
+        config.pricing.gateways = serde_json::from_value(json!({
+            "claude-gw": { "input_price": 4.0, "output_price": 20.0 },
+        }))
+        .unwrap();
+    })
+    .await;
+    mount_deepseek(&harness).await;
+    let usage = json!({ "prompt_tokens": 1_000_000, "completion_tokens": 100_000, "total_tokens": 1_100_000 });
+    let chunk = |delta: Value, finish_reason: Value| {
+        json!({ "id": "gw-1", "model": "claude-gw", "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }] })
+    };
+    let mut last = chunk(json!({}), json!("stop"));
+    last["usage"] = usage.clone();
+    let body = [chunk(json!({ "content": CLAUDE_ANSWER }), Value::Null), last]
+        .iter()
+        .map(|chunk| format!("data: {}\n\n", chunk))
+        .collect::<String>()
+        + "data: [DONE]\n\n";
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({ "stream": true })))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+        .mount(&harness.claude)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "id": "gw-1",
+            "object": "chat.completion",
+            "model": "claude-gw",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": CLAUDE_ANSWER },
+                "finish_reason": "stop",
+            }],
+            "usage": usage,
+        })))
+        .mount(&harness.claude)
+        .await;
+
+    let mut body = request("normal", false);
+    body["anthropic_config"]["body"]["model"] = json!("claude-gw");
+    assert_eq!(harness.chat(body.clone()).await.status(), 200);
+    body["stream"] = json!(true);
+    harness.chat(body).await.text().await.unwrap();
+
+    // 两种响应都按网关报告的token数和网关价格计费
+    harness.state.usage.flush().await;
+    let records = harness.state.usage.read(None, None).await.unwrap();
+    let records: Vec<_> = records.iter().filter(|record| record.anthropic_model == "claude-gw").collect();
+    assert_eq!(records.len(), 2);
+    for record in records {
+        assert_eq!(record.anthropic_input_tokens, 1_000_000);
+        assert_eq!(record.anthropic_output_tokens, 100_000);
+        assert!((record.cost_usd - 6.0).abs() < 0.01, "stream={} cost={}", record.stream, record.cost_usd);
+    }
+}
+
+#[tokio::test]
+async fn identical_requests_share_one_pipeline_run() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.coalesce.enabled = true;
+    })
+    .await;
+    // 推理足够慢，三个请求都在同一次运行结束前到达
+    Mock::given(method("POST"))
+        .respond_with(deepseek_stream().set_delay(std::time::Duration::from_millis(300)))
+        .mount(&harness.deepseek)
+        .await;
+    Mock::given(method("POST")).respond_with(claude_stream()).mount(&harness.claude).await;
+
+    let mut body = request("normal", true);
+    body["messages"][0]["content"] = json!("What is six times seven, once?");
+    let responses = futures::future::join_all((0..3).map(|_| harness.chat(body.clone()))).await;
+    let coalesced = responses.iter().filter(|response| response.headers().contains_key("x-deepclaude-coalesced")).count();
+    assert_eq!(coalesced, 2);
+    let mut bodies = Vec::new();
+    for response in responses {
+        bodies.push(response.text().await.unwrap());
+    }
+    assert!(bodies.iter().all(|text| text == &bodies[0]));
+    let (_, _, content) = stream_text(&sse_data(&bodies[0]));
+    assert_eq!(content, CLAUDE_ANSWER);
+    assert_eq!(harness.deepseek.received_requests().await.unwrap().len(), 1);
+    assert_eq!(harness.claude_requests().await.len(), 1);
+
+    // 退出合并的请求单独运行
+    let response = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("deepseek-token")
+        .header("X-Anthropic-API-Token", "claude-token")
+        .header("X-DeepClaude-No-Coalesce", "1")
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert!(!response.headers().contains_key("x-deepclaude-coalesced"));
+    response.text().await.unwrap();
+    assert_eq!(harness.claude_requests().await.len(), 2);
+}
+
+#[tokio::test]
+async fn admin_flags_change_runtime_settings_with_an_audit_trail() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let flags = |update: Value| {
+        harness
+            .client
+            .put(format!("{}/admin/flags", harness.url))
+            .bearer_auth("admin-secret")
+            .header("X-Admin-User", "alice")
+            .json(&update)
+            .send()
+    };
+
+    let response = flags(json!({ "default_mode": "verbose" })).await.unwrap();
+    assert_eq!(response.status(), 400);
+    let response: Value = flags(json!({ "default_mode": "full", "response_cache": true }))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["flags"]["default_mode"], "full");
+    assert_eq!(response["flags"]["response_cache"], true);
+
+    // 只配置了共用令牌时，.env的读写同样需要它
+    let env_url = format!("{}/v1/env/variables", harness.url);
+    assert_eq!(harness.client.get(&env_url).send().await.unwrap().status(), 401);
+    let update = harness
+        .client
+        .post(format!("{}/v1/env/update", harness.url))
+        .json(&json!({ "variables": { "DEEPSEEK_API_KEY": "stolen" } }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(update.status(), 401);
+
+    // 未指定模式的请求按新的默认模式运行
+    let mut body = request("normal", false);
+    body.as_object_mut().unwrap().remove("mode");
+    harness.chat(body).await.error_for_status().unwrap();
+    let system = harness.claude_requests().await[0]["system"].to_string();
+    assert!(system.contains("SEARCH/REPLACE"), "{}", system);
+
+    let current: Value = harness
+        .client
+        .get(format!("{}/admin/flags", harness.url))
+        .bearer_auth("admin-secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let audit = current["audit"].as_array().unwrap();
+    assert_eq!(audit.len(), 2);
+    assert!(audit.iter().all(|change| change["changed_by"] == "alice"));
+    let mode = audit.iter().find(|change| change["flag"] == "default_mode").unwrap();
+    assert_eq!(mode["to"], "full");
+}
+
+#[tokio::test]
+async fn admin_roles_limit_what_each_token_may_do() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.tokens = ["viewer", "operator", "owner"]
+            .into_iter()
+            .map(|role| deepclaude::config::AdminToken {
+                name: format!("{}-bot", role),
+                token: format!("{}-token", role),
+                role: serde_json::from_value(json!(role)).unwrap(),
+            })
+            .collect();
+    })
+    .await;
+    let call = |method: reqwest::Method, path: &str, token: &str| {
+        let request = harness
+            .client
+            .request(method, format!("{}{}", harness.url, path))
+            .bearer_auth(token);
+        async move { request.json(&json!({})).send().await.unwrap().status().as_u16() }
+    };
+
+    assert_eq!(call(reqwest::Method::GET, "/admin/flags", "viewer-token").await, 200);
+    assert_eq!(call(reqwest::Method::GET, "/v1/usage", "viewer-token").await, 200);
+    assert_eq!(call(reqwest::Method::PUT, "/admin/flags", "viewer-token").await, 403);
+    assert_eq!(call(reqwest::Method::PUT, "/admin/flags", "operator-token").await, 200);
+    assert_eq!(call(reqwest::Method::GET, "/v1/env/variables", "operator-token").await, 403);
+    assert_eq!(call(reqwest::Method::GET, "/admin/flags", "wrong-token").await, 401);
+    // 死信保存了完整的对话，viewer不能查看
+    assert_eq!(call(reqwest::Method::GET, "/admin/deadletter", "viewer-token").await, 403);
+    assert_eq!(call(reqwest::Method::GET, "/admin/deadletter", "operator-token").await, 200);
+
+    // 审计记录使用令牌的名称
+    let response = harness
+        .client
+        .put(format!("{}/admin/flags", harness.url))
+        .bearer_auth("owner-token")
+        .json(&json!({ "coalescing": true }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let flags: Value = harness
+        .client
+        .get(format!("{}/admin/flags", harness.url))
+        .bearer_auth("viewer-token")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(flags["audit"][0]["changed_by"], "owner-bot");
+}
+
+#[tokio::test]
+async fn tenant_owners_issue_and_revoke_their_own_keys() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.storage.backend = StorageBackend::Memory;
+        config.keys = vec![serde_json::from_value(json!({
+            "key": "sk-owner",
+            "name": "owner",
+            "tenant": "acme",
+            "allowed_modes": ["normal"],
+            "manage_keys": true,
+        }))
+        .unwrap()];
+        config.tenants.insert("acme".to_string(), serde_json::from_value(json!({ "max_keys": 1 })).unwrap());
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let keys_url = format!("{}/v1/keys", harness.url);
+    let issue = |token: &str, body: Value| harness.client.post(&keys_url).bearer_auth(token).json(&body).send();
+
+    // 不能授予超出签发者的权限
+    let response = issue("sk-owner", json!({ "name": "ci", "allowed_modes": ["full"] })).await.unwrap();
+    assert_eq!(response.status(), 403);
+
+    // 名称不能和配置的密钥重名
+    assert_eq!(issue("sk-owner", json!({ "name": "owner" })).await.unwrap().status(), 409);
+
+    // 并发签发时也只有一个能用到最后的配额
+    let (first, second) = tokio::join!(issue("sk-owner", json!({ "name": "ci" })), issue("sk-owner", json!({ "name": "ci-2" })));
+    let (first, second) = (first.unwrap(), second.unwrap());
+    let mut statuses = [first.status().as_u16(), second.status().as_u16()];
+    statuses.sort();
+    assert_eq!(statuses, [200, 403]);
+    let issued = if first.status() == 200 { first } else { second };
+    let issued: Value = issued.json().await.unwrap();
+    let secret = issued["key"].as_str().unwrap().to_string();
+    let id = issued["id"].as_str().unwrap().to_string();
+    assert_eq!(issued["allowed_modes"], json!(["normal"]));
+    assert_eq!(issued["issued_by"], "owner");
+
+    // 配额用完后不能再签发，签发出的密钥也不能再签发
+    assert_eq!(issue("sk-owner", json!({ "name": "extra" })).await.unwrap().status(), 403);
+    // 也不能和签发的密钥重名
+    assert_eq!(issue("sk-owner", json!({ "name": issued["name"] })).await.unwrap().status(), 409);
+    assert_eq!(issue(&secret, json!({ "name": "nested" })).await.unwrap().status(), 403);
+
+    let listed: Value = harness.client.get(&keys_url).bearer_auth("sk-owner").send().await.unwrap().json().await.unwrap();
+    assert_eq!(listed["data"].as_array().unwrap().len(), 1);
+    assert_eq!(listed["data"][0]["id"], id.as_str());
+    assert!(listed["data"][0].get("key").is_none());
+
+    let chat = |token: String, mode: &str| {
+        harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth(token)
+            .header("X-Anthropic-API-Token", "claude-token")
+            .json(&request(mode, false))
+            .send()
+    };
+    // 签发的密钥没有上游密钥时使用服务器的DeepSeek密钥，虚拟密钥本身不发往上游
+    std::env::set_var("DEEPSEEK_API_KEY", "ds-server");
+    providers::reload(false, &Default::default()).await.unwrap();
+    assert_eq!(chat(secret.clone(), "normal").await.unwrap().status(), 200);
+    assert_eq!(chat(secret.clone(), "full").await.unwrap().status(), 403);
+    let sent = harness.deepseek.received_requests().await.unwrap();
+    assert!(!sent.is_empty());
+    assert!(sent.iter().all(|request| request.headers["authorization"] == "Bearer ds-server"));
+    // 配置了虚拟密钥后，不带虚拟密钥的请求不能绕过密钥的限制
+    assert_eq!(harness.chat(request("full", false)).await.status(), 401);
+
+    let revoked = harness.client.delete(format!("{}/{}", keys_url, id)).bearer_auth("sk-owner").send().await.unwrap();
+    assert_eq!(revoked.status(), 200);
+    let again = harness.client.delete(format!("{}/{}", keys_url, id)).bearer_auth("sk-owner").send().await.unwrap();
+    assert_eq!(again.status(), 404);
+    // 撤销后的密钥不再是虚拟密钥，也不能再管理密钥
+    assert_eq!(harness.client.get(&keys_url).bearer_auth(&secret).send().await.unwrap().status(), 401);
+}
+
+#[tokio::test]
+async fn client_addresses_are_throttled_and_banned_after_auth_failures() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.auth.managed_keys = true;
+        config.admin.token = "admin-secret".to_string();
+        config.abuse.enabled = true;
+        config.abuse.requests_per_minute = 3;
+        config.abuse.max_auth_failures = 2;
+        config.abuse.trust_forwarded_for = true;
+        config.abuse.trusted_proxies = vec!["127.0.0.1".to_string()];
+        config.abuse.allow = vec!["127.0.0.1".to_string()];
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let chat_from = |ip: &str| {
+        harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth("not-a-virtual-key")
+            .header("X-Forwarded-For", ip)
+            .json(&request("normal", false))
+            .send()
+    };
+    let admin = |method: reqwest::Method, path: &str| {
+        harness
+            .client
+            .request(method, format!("{}{}", harness.url, path))
+            .bearer_auth("admin-secret")
+    };
+
+    // 两次认证失败后该地址被封禁
+    assert_eq!(chat_from("10.0.0.1").await.unwrap().status(), 401);
+    assert_eq!(chat_from("10.0.0.1").await.unwrap().status(), 401);
+    assert_eq!(chat_from("10.0.0.1").await.unwrap().status(), 403);
+    assert_eq!(chat_from("10.0.0.2").await.unwrap().status(), 401);
+    // 客户端自己写在左边的条目不会换掉代理记下的地址
+    assert_eq!(chat_from("10.0.0.5, 10.0.0.1").await.unwrap().status(), 403);
+    assert_eq!(chat_from("10.0.0.1, 127.0.0.1").await.unwrap().status(), 403);
+
+    let bans: Value = admin(reqwest::Method::GET, "/admin/bans").send().await.unwrap().json().await.unwrap();
+    assert_eq!(bans["bans"].as_array().unwrap().len(), 1);
+    assert_eq!(bans["bans"][0]["ip"], "10.0.0.1");
+    assert_eq!(bans["bans"][0]["banned_by"], "automatic");
+
+    let lifted = admin(reqwest::Method::DELETE, "/admin/bans/10.0.0.1").send().await.unwrap();
+    assert_eq!(lifted.status(), 200);
+    assert_eq!(chat_from("10.0.0.1").await.unwrap().status(), 401);
+
+    let banned = admin(reqwest::Method::PUT, "/admin/bans/10.0.0.3")
+        .json(&json!({ "reason": "scraping", "duration_secs": 0 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(banned.status(), 200);
+    let capabilities = |ip: &str| {
+        harness
+            .client
+            .get(format!("{}/v1/capabilities", harness.url))
+            .header("X-Forwarded-For", ip)
+            .send()
+    };
+    assert_eq!(capabilities("10.0.0.3").await.unwrap().status(), 403);
+
+    // 每分钟请求数超出后返回429，允许列表中的地址不受限制
+    for _ in 0..3 {
+        assert_eq!(capabilities("10.0.0.4").await.unwrap().status(), 200);
+    }
+    let throttled = capabilities("10.0.0.4").await.unwrap();
+    assert_eq!(throttled.status(), 429);
+    assert!(throttled.headers().contains_key("retry-after"));
+    for _ in 0..4 {
+        assert_eq!(admin(reqwest::Method::GET, "/admin/bans").send().await.unwrap().status(), 200);
+    }
+}
+
+#[tokio::test]
+async fn admin_writes_and_webhooks_carry_hmac_signatures() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+        config.signing.admin_secret = "admin-hmac".to_string();
+        config.signing.webhook_secret = "webhook-hmac".to_string();
+    })
+    .await;
+    let flags_url = format!("{}/admin/flags", harness.url);
+    let body = json!({ "coalescing": true }).to_string();
+    let put_flags = |timestamp: i64, signature: String| {
+        harness
+            .client
+            .put(&flags_url)
+            .bearer_auth("admin-secret")
+            .header("Content-Type", "application/json")
+            .header(signing::TIMESTAMP_HEADER, timestamp.to_string())
+            .header(signing::SIGNATURE_HEADER, signature)
+            .body(body.clone())
+            .send()
+    };
+    let now = chrono::Utc::now().timestamp();
+    let payload = signing::admin_payload(&reqwest::Method::PUT, "/admin/flags", body.as_bytes());
+
+    // 读取不需要签名，写入缺少签名、签名错误或过期都被拒绝
+    let read = harness.client.get(&flags_url).bearer_auth("admin-secret").send().await.unwrap();
+    assert_eq!(read.status(), 200);
+    let unsigned = harness.client.put(&flags_url).bearer_auth("admin-secret").json(&json!({ "coalescing": true })).send();
+    assert_eq!(unsigned.await.unwrap().status(), 401);
+    assert_eq!(put_flags(now, signing::signature("wrong", now, &payload)).await.unwrap().status(), 401);
+    let stale = now - 3600;
+    assert_eq!(put_flags(stale, signing::signature("admin-hmac", stale, &payload)).await.unwrap().status(), 401);
+    // 签名绑定路由，不能用于其他管理接口
+    let other = signing::admin_payload(&reqwest::Method::PUT, "/admin/bans/10.0.0.1", body.as_bytes());
+    assert_eq!(put_flags(now, signing::signature("admin-hmac", now, &other)).await.unwrap().status(), 401);
+    assert!(!harness.state.coalescer.enabled());
+
+    let signed = put_flags(now, signing::signature("admin-hmac", now, &payload)).await.unwrap();
+    assert_eq!(signed.status(), 200);
+    assert!(harness.state.coalescer.enabled());
+
+    let receiver = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).mount(&receiver).await;
+    let webhook = json!({ "text": "daily summary" });
+    deepclaude::reports::post_webhook(&receiver.uri(), &webhook).await.unwrap();
+    let received = &receiver.received_requests().await.unwrap()[0];
+    let header = |name: &str| received.headers.get(name).unwrap().to_str().unwrap().to_string();
+    let timestamp: i64 = header(signing::TIMESTAMP_HEADER).parse().unwrap();
+    assert_eq!(header(signing::SIGNATURE_HEADER), signing::signature("webhook-hmac", timestamp, &received.body));
+    assert_eq!(serde_json::from_slice::<Value>(&received.body).unwrap(), webhook);
+}
+
+#[tokio::test]
+async fn admin_log_stream_tails_the_entries_of_one_request() {
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+    let _ = tracing_subscriber::registry()
+        .with(tracing_subscriber::filter::LevelFilter::DEBUG)
+        .with(deepclaude::logs::layer())
+        .try_init();
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    let response = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("deepseek-token")
+        .header("X-Anthropic-API-Token", "claude-token")
+        .header("X-Request-Id", "req-logs-1")
+        .json(&request("normal", false))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    harness.chat(request("normal", false)).await;
+
+    let logs_url = format!("{}/admin/logs/stream", harness.url);
+    assert_eq!(harness.client.get(&logs_url).send().await.unwrap().status(), 401);
+    let bad_level = harness.client.get(&logs_url).bearer_auth("admin-secret").query(&[("level", "loud")]);
+    assert_eq!(bad_level.send().await.unwrap().status(), 400);
+
+    let mut tail = harness
+        .client
+        .get(&logs_url)
+        .bearer_auth("admin-secret")
+        .query(&[("level", "info"), ("request_id", "req-logs-1"), ("tail", "2")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(tail.status(), 200);
+    assert_eq!(tail.headers()["content-type"], "text/event-stream");
+    let mut body = String::new();
+    while body.matches("\n\n").count() < 2 {
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), tail.chunk()).await.unwrap().unwrap().unwrap();
+        body.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    // 积压的日志只包含该请求中info及以上级别的条目
+    for data in sse_data(&body).iter().take(2) {
+        let entry: Value = serde_json::from_str(data).unwrap();
+        assert_eq!(entry["request_id"], "req-logs-1");
+        assert!(["INFO", "WARN", "ERROR"].contains(&entry["level"].as_str().unwrap()), "{}", entry);
+    }
+}
+
+#[tokio::test]
+async fn captured_upstream_payloads_are_kept_per_request_id() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let chat = |request_id: &'static str, stream: bool| {
+        harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth("deepseek-token")
+            .header("X-Anthropic-API-Token", "claude-token")
+            .header("X-Request-Id", request_id)
+            .json(&request("normal", stream))
+            .send()
+    };
+    let payloads = |request_id: &str| {
+        harness
+            .client
+            .get(format!("{}/admin/requests/{}/payloads", harness.url, request_id))
+            .bearer_auth("admin-secret")
+            .send()
+    };
+
+    // 开关关闭时不保存请求体
+    assert_eq!(chat("req-before", false).await.unwrap().status(), 200);
+    assert_eq!(payloads("req-before").await.unwrap().status(), 404);
+
+    let enabled = harness
+        .client
+        .put(format!("{}/admin/flags", harness.url))
+        .bearer_auth("admin-secret")
+        .json(&json!({ "payload_capture": true }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(enabled.status(), 200);
+
+    let response = chat("req-captured", true).await.unwrap();
+    assert_eq!(response.headers()["x-request-id"], "req-captured");
+    response.text().await.unwrap();
+    let captured: Value = payloads("req-captured").await.unwrap().json().await.unwrap();
+    let captured = captured["payloads"].as_array().unwrap();
+    assert_eq!(captured.len(), 2);
+    assert_eq!(captured[0]["upstream"], "deepseek");
+    assert_eq!(captured[1]["upstream"], "anthropic");
+    // 保存的正是上游收到的请求体
+    let deepseek = &harness.deepseek.received_requests().await.unwrap()[1];
+    assert_eq!(captured[0]["body"].as_str().unwrap().as_bytes(), deepseek.body.as_slice());
+    let claude = harness.claude.received_requests().await.unwrap();
+    assert_eq!(captured[1]["body"].as_str().unwrap().as_bytes(), claude[1].body.as_slice());
+
+    // 未带X-Request-Id的请求使用响应中返回的生成id
+    let response = harness.chat(request("normal", false)).await;
+    let generated = response.headers()["x-request-id"].to_str().unwrap().to_string();
+    assert_eq!(payloads(&generated).await.unwrap().status(), 200);
+}
+
+#[tokio::test]
+async fn retried_requests_record_their_attempts_and_wasted_cost() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+        config.pricing.deepseek.output_price = 2.0;
+    })
+    .await;
+    Mock::given(method("POST")).respond_with(deepseek_completion()).mount(&harness.deepseek).await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(500).set_body_json(json!({
+            "type": "error",
+            "error": { "type": "api_error", "message": "overloaded" },
+        })))
+        .up_to_n_times(1)
+        .mount(&harness.claude)
+        .await;
+    Mock::given(method("POST")).respond_with(claude_message()).mount(&harness.claude).await;
+
+    // 客户端以同一请求id重试，第一次的推理花费算作浪费
+    let send = || {
+        harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth("deepseek-token")
+            .header("X-Anthropic-API-Token", "claude-token")
+            .header("X-Request-Id", "retried-request")
+            .json(&request("normal", false))
+            .send()
+    };
+    assert!(!send().await.unwrap().status().is_success());
+    assert_eq!(send().await.unwrap().status(), 200);
+
+    harness.state.usage.flush().await;
+    let records = harness.state.usage.read(None, None).await.unwrap();
+    let records: Vec<_> = records
+        .iter()
+        .filter(|record| record.request_id.as_deref() == Some("retried-request"))
+        .collect();
+    assert_eq!(records.len(), 2);
+    let (failed, answered) = (records[0], records[1]);
+    assert_eq!(failed.attempts, 1);
+    assert!(failed.wasted_cost_usd > 0.0);
+    assert_eq!(failed.wasted_cost_usd, failed.cost_usd);
+    assert_eq!(failed.anthropic_output_tokens, 0);
+    assert_eq!(answered.attempts, 2);
+    assert_eq!(answered.wasted_cost_usd, 0.0);
+    assert!(answered.anthropic_output_tokens > 0);
+
+    let report: Value = harness
+        .client
+        .get(format!("{}/v1/usage", harness.url))
+        .bearer_auth("admin-secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(report["total"]["wasted_cost_usd"].as_f64().unwrap() >= failed.wasted_cost_usd);
+}
+
+#[tokio::test]
+async fn batch_requests_are_paced_by_the_reported_rate_limits() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.pacing.enabled = true;
+        config.pacing.max_wait_ms = 200;
+        config.keys = ["batch", "ide"]
+            .iter()
+            .map(|name| {
+                serde_json::from_value(json!({
+                    "key": format!("sk-{}", name),
+                    "name": name,
+                    "priority": if *name == "batch" { "batch" } else { "interactive" },
+                    "deepseek_api_key": "ds-upstream",
+                }))
+                .unwrap()
+            })
+            .collect();
+    })
+    .await;
+    // 只剩10%的配额，低于为交互式请求预留的20%
+    Mock::given(method("POST"))
+        .respond_with(
+            deepseek_completion()
+                .insert_header("x-ratelimit-limit-requests", "60")
+                .insert_header("x-ratelimit-remaining-requests", "6"),
+        )
+        .mount(&harness.deepseek)
+        .await;
+    Mock::given(method("POST")).respond_with(claude_message()).mount(&harness.claude).await;
+
+    let send = |key: &str| {
+        harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth(key)
+            .header("X-Anthropic-API-Token", "claude-token")
+            .json(&request("normal", false))
+            .send()
+    };
+    assert_eq!(send("sk-batch").await.unwrap().status(), 200);
+
+    // 下一个批处理请求要等约20秒，超过最长等待时间后被拒绝，交互式请求不受影响
+    let paced = send("sk-batch").await.unwrap();
+    assert_eq!(paced.status(), 429);
+    assert!(paced.headers().contains_key("retry-after"));
+    assert_eq!(send("sk-ide").await.unwrap().status(), 200);
+
+    let metrics = harness.client.get(format!("{}/metrics", harness.url)).send().await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("deepclaude_batch_pace_requests_per_minute{provider=\"deepseek\"} 3"));
+}
+
+#[tokio::test]
+async fn admin_actions_are_kept_in_an_encrypted_hash_chain() {
+    let log = std::env::temp_dir().join(format!("deepclaude-audit-{}.log", uuid::Uuid::new_v4()));
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        std::env::set_var(crypto::MASTER_KEY_ENV, "audit-master");
+        config.admin.token = "admin-secret".to_string();
+        config.audit.enabled = true;
+        config.audit.encrypt = true;
+        config.audit.path = log.to_string_lossy().into_owned();
+    })
+    .await;
+    let admin = |method: reqwest::Method, route: &str| {
+        harness
+            .client
+            .request(method, format!("{}{}", harness.url, route))
+            .bearer_auth("admin-secret")
+            .header("X-Admin-User", "alice")
+    };
+    let banned = admin(reqwest::Method::PUT, "/admin/bans/10.0.0.9").json(&json!({ "duration_secs": 60 }));
+    assert_eq!(banned.send().await.unwrap().status(), 200);
+    assert_eq!(admin(reqwest::Method::DELETE, "/admin/bans/10.0.0.9").send().await.unwrap().status(), 200);
+    let flags = admin(reqwest::Method::PUT, "/admin/flags").json(&json!({ "coalescing": true }));
+    assert_eq!(flags.send().await.unwrap().status(), 200);
+    std::env::remove_var(crypto::MASTER_KEY_ENV);
+
+    // 记录以密文保存，哈希链由主密钥签名
+    let content = std::fs::read_to_string(&log).unwrap();
+    assert!(!content.contains("10.0.0.9"));
+    let master = MasterKey::from_material("audit-master");
+    let verified = audit::verify(&log, &master).unwrap();
+    assert_eq!((verified.records, verified.first), (3, 1));
+    assert!(audit::verify(&log, &MasterKey::from_material("mallory")).is_err());
+
+    // 删除或修改任意一行都会被发现，包括删掉开头的记录
+    let lines: Vec<&str> = content.lines().collect();
+    std::fs::write(&log, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+    assert!(audit::verify(&log, &master).unwrap_err().to_string().contains("序号"));
+    std::fs::write(&log, format!("{}\n{}\n", lines[1], lines[2])).unwrap();
+    assert!(audit::verify(&log, &master).unwrap_err().to_string().contains("序号"));
+    let mut line: Value = serde_json::from_str(lines[1]).unwrap();
+    line["record"] = json!(master.encrypt(r#"{"at":"2026-01-01T00:00:00Z","actor":"mallory","action":"bans.delete","detail":{}}"#).unwrap());
+    std::fs::write(&log, format!("{}\n{}\n{}\n", lines[0], line, lines[2])).unwrap();
+    assert!(audit::verify(&log, &master).unwrap_err().to_string().contains("哈希不匹配"));
+
+    // 清理留下签名的标记，链从标记接续；伪造的标记不被接受
+    std::fs::write(&log, &content).unwrap();
+    assert_eq!(harness.state.audit.purge(chrono::Utc::now() + chrono::Duration::days(1)).unwrap(), 3);
+    let purged = audit::verify(&log, &master).unwrap();
+    assert_eq!((purged.records, purged.first, purged.head), (0, 4, verified.head));
+    let mut marker: Value = serde_json::from_str(std::fs::read_to_string(&log).unwrap().trim()).unwrap();
+    marker["purged_through"] = json!(1);
+    std::fs::write(&log, format!("{}\n{}\n{}\n", marker, lines[1], lines[2])).unwrap();
+    assert!(audit::verify(&log, &master).unwrap_err().to_string().contains("清理标记"));
+    std::fs::remove_file(&log).unwrap();
+}
+
+#[tokio::test]
+async fn uploaded_files_belong_to_their_key_and_are_erased_with_the_tenant() {
+    let files = std::env::temp_dir().join(format!("deepclaude-files-{}", uuid::Uuid::new_v4()));
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+        config.files.dir = files.to_string_lossy().into_owned();
+        config.keys = [("alice", None), ("mallory", None), ("acme-bot", Some("acme"))]
+            .map(|(name, tenant)| {
+                serde_json::from_value(json!({
+                    "key": format!("sk-{}", name),
+                    "name": name,
+                    "tenant": tenant,
+                    "deepseek_api_key": "ds-upstream",
+                    "anthropic_api_key": "claude-upstream",
+                }))
+                .unwrap()
+            })
+            .to_vec();
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let upload = |token: &'static str| {
+        let body = "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"notes.md\"\r\n\
+                    Content-Type: text/markdown\r\n\r\nThe launch code is 42.\r\n--boundary--\r\n";
+        let request = harness
+            .client
+            .post(format!("{}/v1/files", harness.url))
+            .bearer_auth(token)
+            .header("content-type", "multipart/form-data; boundary=boundary")
+            .body(body);
+        async move {
+            let file: Value = request.send().await.unwrap().json().await.unwrap();
+            file["id"].as_str().unwrap().to_string()
+        }
+    };
+    let call = |method: reqwest::Method, id: &str, token: &'static str| {
+        let request = harness.client.request(method, format!("{}/v1/files/{}", harness.url, id)).bearer_auth(token);
+        async move { request.send().await.unwrap().status() }
+    };
+
+    let id = upload("sk-alice").await;
+    assert_eq!(call(reqwest::Method::GET, &id, "sk-alice").await, 200);
+    // 其他密钥既看不到、删不掉，也不能在对话中引用
+    assert_eq!(call(reqwest::Method::GET, &id, "sk-mallory").await, 404);
+    assert_eq!(call(reqwest::Method::DELETE, &id, "sk-mallory").await, 404);
+    let mut body = request("normal", false);
+    body["file_ids"] = json!([id]);
+    let chat = |token: &'static str| {
+        let request = harness.client.post(format!("{}/v1/chat/completions", harness.url)).bearer_auth(token).json(&body);
+        async move { request.send().await.unwrap().status() }
+    };
+    assert_eq!(chat("sk-mallory").await, 404);
+    assert_eq!(chat("sk-alice").await, 200);
+
+    // 租户的文件随导出一起给出，擦除时一并删除
+    let tenant_file = upload("sk-acme-bot").await;
+    let export = harness
+        .client
+        .get(format!("{}/admin/tenants/acme/export", harness.url))
+        .bearer_auth("admin-secret")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let line = export.lines().map(|line| serde_json::from_str::<Value>(line).unwrap()).find(|line| line["type"] == "file");
+    let line = line.unwrap();
+    assert_eq!(line["file"]["id"], tenant_file);
+    assert!(line["text"].as_str().unwrap().contains("launch code"));
+    let erased: Value = harness
+        .client
+        .post(format!("{}/admin/purge", harness.url))
+        .bearer_auth("admin-secret")
+        .json(&json!({ "tenant": "acme" }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(erased["purged"]["files"], 1);
+    assert_eq!(call(reqwest::Method::GET, &tenant_file, "sk-acme-bot").await, 404);
+    assert_eq!(call(reqwest::Method::DELETE, &id, "sk-alice").await, 200);
+    std::fs::remove_dir_all(&files).unwrap();
+}
+
+#[tokio::test]
+async fn traces_are_served_only_to_their_key_and_admins() {
+    let traces = std::env::temp_dir().join(format!("deepclaude-traces-{}", uuid::Uuid::new_v4()));
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+        config.traces.dir = traces.to_string_lossy().into_owned();
+        config.keys = ["alice", "mallory"]
+            .map(|name| {
+                serde_json::from_value(json!({
+                    "key": format!("sk-{}", name),
+                    "name": name,
+                    "deepseek_api_key": "ds-upstream",
+                    "anthropic_api_key": "claude-upstream",
+                }))
+                .unwrap()
+            })
+            .to_vec();
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let mut body = request("normal", false);
+    body["verbose"] = json!(true);
+    let response: Value = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("sk-alice")
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let url = format!("{}/v1/traces/{}", harness.url, response["id"].as_str().unwrap());
+
+    let fetch = |token: Option<&'static str>| {
+        let request = harness.client.get(&url);
+        async move {
+            match token {
+                Some(token) => request.bearer_auth(token),
+                None => request,
+            }
+            .send()
+            .await
+            .unwrap()
+            .status()
+        }
+    };
+    assert_eq!(fetch(Some("sk-alice")).await, 200);
+    assert_eq!(fetch(Some("admin-secret")).await, 200);
+    // 其他密钥和匿名调用方看不到追踪，也无法得知它存在
+    assert_eq!(fetch(Some("sk-mallory")).await, 404);
+    assert_eq!(fetch(None).await, 404);
+    std::fs::remove_dir_all(&traces).unwrap();
+}
+
+#[tokio::test]
+async fn tenant_data_is_erased_and_expired_usage_purged() {
+    let traces = std::env::temp_dir().join(format!("deepclaude-traces-{}", uuid::Uuid::new_v4()));
+    let dead_letters = std::env::temp_dir().join(format!("deepclaude-deadletter-{}", uuid::Uuid::new_v4()));
+    let shadow = std::env::temp_dir().join(format!("deepclaude-shadow-{}.jsonl", uuid::Uuid::new_v4()));
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+        config.storage.backend = StorageBackend::Memory;
+        config.traces.dir = traces.to_string_lossy().into_owned();
+        config.prompt_cache.enabled = true;
+        config.prompt_cache.min_prefix_chars = 16;
+        config.retention.usage_days = 30;
+        config.deadletter.enabled = true;
+        config.deadletter.dir = dead_letters.to_string_lossy().into_owned();
+        config.shadow.output_path = shadow.to_string_lossy().into_owned();
+        config.debug.payloads = true;
+        config.keys = ["acme", "globex"]
+            .map(|tenant| {
+                serde_json::from_value(json!({
+                    "key": format!("sk-{}", tenant),
+                    "name": format!("{}-app", tenant),
+                    "tenant": tenant,
+                    "deepseek_api_key": "deepseek-token",
+                    "anthropic_api_key": "claude-token",
+                }))
+                .unwrap()
+            })
+            .to_vec();
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let mut body = request("normal", false);
+    body["verbose"] = json!(true);
+    body["system"] = json!("You are a careful arithmetic tutor.");
+    let mut ids = Vec::new();
+    for tenant in ["acme", "globex"] {
+        let response: Value = harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth(format!("sk-{}", tenant))
+            .header("X-Tenant-Id", tenant)
+            .header("X-Session-Id", "session-1")
+            .header("X-Request-Id", format!("request-{}", tenant))
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        ids.push(response["id"].as_str().unwrap().to_string());
+
+        // 租户的失败请求和影子对比记录也要一并删除
+        let entry = json!({
+            "id": uuid::Uuid::new_v4().to_string(),
+            "created_at": chrono::Utc::now(),
+            "last_error": "DeepSeek API error",
+            "attempts": 1,
+            "key_name": null,
+            "tenant": tenant,
+            "request": body,
+        });
+        harness.state.dead_letters.save(&serde_json::from_value(entry).unwrap()).unwrap();
+        let record = json!({ "id": format!("shadow-{}", tenant), "tenant": tenant });
+        let mut lines = std::fs::read_to_string(&shadow).unwrap_or_default();
+        lines.push_str(&format!("{}\n", record));
+        std::fs::write(&shadow, lines).unwrap();
+    }
+    let purge = |body: Value| {
+        harness
+            .client
+            .post(format!("{}/admin/purge", harness.url))
+            .bearer_auth("admin-secret")
+            .json(&body)
+            .send()
+    };
+
+    let erased: Value = purge(json!({ "tenant": "acme" })).await.unwrap().json().await.unwrap();
+    assert_eq!(
+        erased["purged"],
+        json!({
+            "usage": 1, "audit": 0, "traces": 1, "sessions": 1,
+            "dead_letters": 1, "payloads": 1, "shadow": 1, "files": 0,
+        })
+    );
+    let left = harness.state.dead_letters.list().unwrap();
+    assert_eq!(left.len(), 1);
+    assert_eq!(left[0].tenant.as_deref(), Some("globex"));
+    assert!(harness.state.payloads.get("request-acme").is_none());
+    assert!(harness.state.payloads.get("request-globex").is_some());
+    let lines = std::fs::read_to_string(&shadow).unwrap();
+    assert!(!lines.contains("acme") && lines.contains("shadow-globex"));
+    let records = harness.state.usage.read(None, None).await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].tenant.as_deref(), Some("globex"));
+    assert!(harness.state.traces.get(&ids[0]).unwrap().is_none());
+    assert!(harness.state.traces.get(&ids[1]).unwrap().is_some());
+
+    // 超过保留天数的用量记录在清理时删除
+    let mut old = records[0].clone();
+    old.id = "old-record".to_string();
+    old.timestamp = chrono::Utc::now() - chrono::Duration::days(45);
+    harness.state.usage.record(&old);
+    let purged: Value = purge(json!({})).await.unwrap().json().await.unwrap();
+    assert_eq!(purged["purged"]["usage"], 1);
+    let records = harness.state.usage.read(None, None).await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert_ne!(records[0].id, "old-record");
+    std::fs::remove_dir_all(&traces).unwrap();
+    std::fs::remove_dir_all(&dead_letters).unwrap();
+    std::fs::remove_file(&shadow).unwrap();
+    drop(harness);
+
+    // 抽样的请求日志无法删除，开启抽样时拒绝删除租户数据
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+        config.request_log.enabled = true;
+        config.request_log.sample_percent = 10.0;
+    })
+    .await;
+    let refused = harness
+        .client
+        .post(format!("{}/admin/purge", harness.url))
+        .bearer_auth("admin-secret")
+        .json(&json!({ "tenant": "acme" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(refused.status(), 400);
+    assert!(refused.text().await.unwrap().contains("sample_percent"));
+}
+
+#[tokio::test]
+async fn tenant_data_is_exported_as_json_lines() {
+    let traces = std::env::temp_dir().join(format!("deepclaude-traces-{}", uuid::Uuid::new_v4()));
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+        config.storage.backend = StorageBackend::Memory;
+        config.traces.dir = traces.to_string_lossy().into_owned();
+        config.prompt_cache.enabled = true;
+        config.prompt_cache.min_prefix_chars = 16;
+        config.keys = vec![serde_json::from_value(json!({
+            "key": "sk-acme",
+            "name": "acme-app",
+            "tenant": "acme",
+            "deepseek_api_key": "deepseek-token",
+            "anthropic_api_key": "claude-token",
+        }))
+        .unwrap(), serde_json::from_value(json!({
+            "key": "sk-globex",
+            "name": "globex-app",
+            "deepseek_api_key": "deepseek-token",
+            "anthropic_api_key": "claude-token",
+        }))
+        .unwrap()];
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    let mut body = request("normal", false);
+    body["verbose"] = json!(true);
+    body["system"] = json!("You are a careful arithmetic tutor.");
+    for (token, tenant) in [("sk-acme", "acme"), ("sk-globex", "globex")] {
+        let response = harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth(token)
+            .header("X-Anthropic-API-Token", "claude-token")
+            .header("X-Tenant-Id", tenant)
+            .header("X-Session-Id", "session-1")
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    let export = |token: &str| {
+        harness
+            .client
+            .get(format!("{}/admin/tenants/acme/export", harness.url))
+            .bearer_auth(token)
+            .send()
+    };
+    assert_eq!(export("sk-acme").await.unwrap().status(), 401);
+    let response = export("admin-secret").await.unwrap();
+    assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+    let lines: Vec<Value> = response.text().await.unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let types: Vec<&str> = lines.iter().map(|line| line["type"].as_str().unwrap()).collect();
+    assert_eq!(types, ["export", "key", "session", "trace", "usage"]);
+    assert_eq!(lines[1]["name"], "acme-app");
+    assert!(lines[1].get("key").is_none());
+    assert_eq!(lines[2]["id"], "session-1");
+    assert_eq!(lines[3]["trace"]["tenant"], "acme");
+    assert_eq!(lines[4]["record"]["tenant"], "acme");
+    std::fs::remove_dir_all(&traces).unwrap();
+}
+
+#[tokio::test]
+async fn compat_profiles_flush_cjk_answers_by_clause() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.streams.profiles.insert(
+            "chatbox".to_string(),
+            serde_json::from_value(json!({ "user_agent": "Chatbox", "flush_ms": 60000 })).unwrap(),
+        );
+    })
+    .await;
+    mount_deepseek(&harness).await;
+    Mock::given(method("POST"))
+        .respond_with(claude_stream_of(&["你好", "，世", "界。」我", "们开始吧"]))
+        .mount(&harness.claude)
+        .await;
+
+    let answer = |user_agent: &str| {
+        harness
+            .client
+            .post(format!("{}/v1/chat/completions", harness.url))
+            .bearer_auth("deepseek-token")
+            .header("X-Anthropic-API-Token", "claude-token")
+            .header("User-Agent", user_agent)
+            .json(&request("normal", true))
+            .send()
+    };
+    let deltas = |text: String| -> Vec<String> {
+        let (chunks, _, _) = stream_text(&sse_data(&text));
+        chunks
+            .iter()
+            .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+            .map(str::to_string)
+            .collect()
+    };
+
+    // 匹配到的客户端按分句收到回答，结束时发出剩余部分
+    let matched = deltas(answer("Chatbox/1.2").await.unwrap().text().await.unwrap());
+    assert_eq!(matched, ["你好，", "世界。」", "我们开始吧"]);
+    let other = deltas(answer("curl/8").await.unwrap().text().await.unwrap());
+    assert_eq!(other, ["你好", "，世", "界。」我", "们开始吧"]);
+}
+
+#[tokio::test]
+async fn clause_held_text_is_released_once_the_wait_passes() {
+    let (tx, _rx) = tokio::sync::mpsc::channel(8);
+    let built = Arc::new(Mutex::new(Vec::new()));
+    let log = built.clone();
+    let build = Box::new(move |_: Delta, text: &str| {
+        log.lock().unwrap().push(text.to_string());
+        text.to_string()
+    });
+    let flushing = Flushing {
+        clause: Some(std::time::Duration::from_millis(50)),
+        code_cap: None,
+    };
+    let mut sink = EventSink::new(tx, &StreamsConfig::default(), build).with_flushing(flushing);
+
+    sink.delta(Delta::Content, "你好，世").await.unwrap();
+    assert_eq!(*built.lock().unwrap(), ["你好，"]);
+    assert!(sink.held_until().is_some());
+    sink.heartbeat("{}".to_string()).unwrap();
+    assert_eq!(built.lock().unwrap().len(), 1);
+
+    // 上游停顿超过分句等待时间后，心跳发出暂存的文本
+    tokio::time::sleep_until(sink.held_until().unwrap()).await;
+    sink.heartbeat("{}".to_string()).unwrap();
+    assert_eq!(*built.lock().unwrap(), ["你好，", "世"]);
+    assert!(sink.held_until().is_none());
+}
+
+#[tokio::test]
+async fn markdown_safe_streams_hold_code_until_it_closes() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.streams.markdown_safe = true;
+    })
+    .await;
+    mount_deepseek(&harness).await;
+    Mock::given(method("POST"))
+        .respond_with(claude_stream_of(&["Use `ma", "p` here:\n``", "`rust\nlet x", " = 1;\n", "```\nDone", "."]))
+        .mount(&harness.claude)
+        .await;
+
+    let events = sse_data(&harness.chat(request("normal", true)).await.text().await.unwrap());
+    let (chunks, _, content) = stream_text(&events);
+    let deltas: Vec<&str> = chunks.iter().filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str()).collect();
+    assert_eq!(content, "Use `map` here:\n```rust\nlet x = 1;\n```\nDone.");
+    assert_eq!(deltas, ["Use ", "`map` here:\n", "```rust\nlet x = 1;\n```\nDone", "."]);
+}
+
+#[tokio::test]
+async fn routed_hosts_without_a_key_get_no_other_credentials() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    mount_upstreams(&harness).await;
+
+    // OpenRouter没有配置密钥时请求失败，DeepSeek或Claude的密钥不会发给它
+    let mut body = request("normal", false);
+    body["deepseek_config"]["body"]["model"] = json!("openrouter/deepseek/deepseek-r1");
+    let response = harness.chat(body).await;
+    assert_eq!(response.status(), 500);
+    let body: Value = response.json().await.unwrap();
+    assert!(body["error"]["message"].as_str().unwrap().contains("OPENROUTER_API_KEY"));
+    assert!(harness.deepseek.received_requests().await.unwrap_or_default().is_empty());
+    assert!(harness.claude_requests().await.is_empty());
+}
+
+#[tokio::test]
+async fn dead_letters_replay_through_the_pipeline_with_their_key() {
+    let dir = std::env::temp_dir().join(format!("deepclaude-deadletter-{}", uuid::Uuid::new_v4()));
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.admin.token = "admin-secret".to_string();
+        config.deadletter.enabled = true;
+        config.deadletter.dir = dir.to_string_lossy().into_owned();
+        config.keys = vec![serde_json::from_value(json!({
+            "key": "sk-app",
+            "name": "app",
+            "allowed_modes": ["normal"],
+            "deepseek_api_key": "ds-upstream",
+            "anthropic_api_key": "claude-upstream",
+        }))
+        .unwrap()];
+    })
+    .await;
+    mount_deepseek(&harness).await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(500).set_body_string("upstream down"))
+        .mount(&harness.claude)
+        .await;
+    let admin = |method: reqwest::Method, path: &str| {
+        harness
+            .client
+            .request(method, format!("{}{}", harness.url, path))
+            .bearer_auth("admin-secret")
+    };
+
+    let mut body = request("normal", false);
+    body["anthropic_config"]["headers"] = json!({ "x-secret": "do-not-keep" });
+    let failed = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("sk-app")
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert!(!failed.status().is_success());
+
+    let listed: Value = admin(reqwest::Method::GET, "/admin/deadletter").send().await.unwrap().json().await.unwrap();
+    let entries = listed["dead_letters"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["key_name"], "app");
+    assert_eq!(entries[0]["request"]["anthropic_config"]["headers"], json!({}));
+    let id = entries[0]["id"].as_str().unwrap().to_string();
+
+    // 上游恢复后以原来的虚拟密钥重放，密钥的上游密钥照常使用
+    harness.claude.reset().await;
+    mount_upstreams(&harness).await;
+    let replayed = admin(reqwest::Method::POST, &format!("/admin/deadletter/{}/replay", id)).send().await.unwrap();
+    assert_eq!(replayed.status(), 200);
+    let replayed: Value = replayed.json().await.unwrap();
+    assert_eq!(replayed["response"]["choices"][0]["message"]["content"], CLAUDE_ANSWER);
+    let claude = harness.claude.received_requests().await.unwrap();
+    assert_eq!(claude.last().unwrap().headers["x-api-key"], "claude-upstream");
+    let listed: Value = admin(reqwest::Method::GET, "/admin/deadletter").send().await.unwrap().json().await.unwrap();
+    assert!(listed["dead_letters"].as_array().unwrap().is_empty());
+
+    // 调用方自带上游密钥的请求不会改用服务器的密钥重放
+    let own_keys = json!({
+        "id": "0f0e0d0c-0000-4000-8000-000000000001",
+        "created_at": "2026-01-01T00:00:00Z",
+        "last_error": "Anthropic API error: upstream down",
+        "attempts": 1,
+        "key_name": null,
+        "request": request("normal", false),
+    });
+    std::fs::write(dir.join("0f0e0d0c-0000-4000-8000-000000000001.json"), own_keys.to_string()).unwrap();
+    let refused = admin(reqwest::Method::POST, "/admin/deadletter/0f0e0d0c-0000-4000-8000-000000000001/replay")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(refused.status(), 403);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_rejected_stage_hands_back_the_other_stages_rate_budget() {
+    let budgets = [("deepseek", 2), ("anthropic", 1)]
+        .into_iter()
+        .map(|(provider, rpm)| {
+            let budget = serde_json::from_value(json!({ "rpm": rpm, "max_wait_ms": 0 })).unwrap();
+            (provider.to_string(), budget)
+        })
+        .collect();
+    let limiter = RateLimiter::new(&budgets);
+
+    limiter.acquire_all(&["deepseek", "anthropic"], 10).await.unwrap();
+    let rejected = limiter.acquire_all(&["deepseek", "anthropic"], 10).await.unwrap_err();
+    assert!(matches!(rejected, ApiError::RateLimited { ref provider, .. } if provider == "anthropic"));
+    // 被Anthropic预算拒绝的请求不占用DeepSeek的预算
+    assert!(limiter.try_acquire("deepseek", 10));
+    assert!(!limiter.try_acquire("deepseek", 10));
+}
+
+#[tokio::test]
+async fn a_failed_upstream_call_hands_back_its_token_budget() {
+    let budget = serde_json::from_value(json!({ "tpm": 100, "max_wait_ms": 0 })).unwrap();
+    let limiter = RateLimiter::new(&[("anthropic".to_string(), budget)].into_iter().collect());
+
+    limiter.acquire("anthropic", 60).await.unwrap();
+    assert!(!limiter.try_acquire("anthropic", 60));
+    // 上游失败的调用没有用量，预算退还后下一个请求可以立即通过
+    limiter.settle("anthropic", 60, 0);
+    assert!(limiter.try_acquire("anthropic", 60));
+    limiter.settle("anthropic", 60, 90);
+    assert!(!limiter.try_acquire("anthropic", 20));
+}
+
+#[test]
+fn edit_block_dividers_only_count_inside_search_sections() {
+    // 块外的markdown标题下划线和替换内容里的=======都不是分隔线
+    let answer = "Changes\n=======\n\nREADME.md\n<<<<<<< SEARCH\nTitle\n=======\nUsage\n>>>>>>> REPLACE\n\n\
+                  docs/guide.md\n<<<<<<< SEARCH\nold\n=======\nGuide\n=======\n>>>>>>> REPLACE\n";
+    assert!(editblocks::problems(answer).is_empty(), "{:?}", editblocks::problems(answer));
+
+    let missing = "src/main.rs\n<<<<<<< SEARCH\nold\n>>>>>>> REPLACE\n";
+    assert_eq!(editblocks::problems(missing), ["the block at line 2 is missing its ======= divider"]);
+}
+
+#[test]
+fn ark_throttling_maps_to_rate_limited_and_overloaded() {
+    let ark = hosts::profile("ark").unwrap();
+    let body = |code: &str| json!({ "error": { "code": code, "message": "slow down" } }).to_string();
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert("retry-after", "7".parse().unwrap());
+
+    let limited = ark.translate_error(reqwest::StatusCode::TOO_MANY_REQUESTS, &headers, &body("RateLimitExceeded.EndpointRPMExceeded"));
+    assert!(matches!(limited, ApiError::RateLimited { ref provider, retry_after_secs: 7 } if provider == "ark"));
+    let quota = ark.translate_error(reqwest::StatusCode::TOO_MANY_REQUESTS, &Default::default(), &body("QuotaExceeded"));
+    assert!(matches!(quota, ApiError::RateLimited { retry_after_secs: 1, .. }));
+    let overloaded = ark.translate_error(reqwest::StatusCode::SERVICE_UNAVAILABLE, &headers, &body("ServerOverloaded"));
+    assert!(matches!(overloaded, ApiError::Overloaded { retry_after_secs: 7, .. }));
+    let other = ark.translate_error(reqwest::StatusCode::BAD_REQUEST, &headers, &body("InvalidParameter"));
+    assert!(matches!(other, ApiError::DeepSeekError { ref type_, .. } if type_ == "invalid_request"));
+}
+
+#[tokio::test]
+async fn deepseek_failure_is_reported_without_calling_claude() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "error": { "message": "bad request from test", "type": "invalid_request_error" },
+        })))
+        .mount(&harness.deepseek)
+        .await;
+    Mock::given(method("POST")).respond_with(claude_message()).mount(&harness.claude).await;
+
+    let response = harness.chat(request("normal", false)).await;
+    assert!(!response.status().is_success());
+    let body: Value = response.json().await.unwrap();
+    assert!(body["error"]["message"].is_string());
+    assert!(harness.claude_requests().await.is_empty());
+}
+
+#[tokio::test]
+async fn claude_failure_ends_the_stream_with_an_error_chunk() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    Mock::given(method("POST")).respond_with(deepseek_stream()).mount(&harness.deepseek).await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "type": "error",
+            "error": { "type": "invalid_request_error", "message": "bad request from test" },
+        })))
+        .mount(&harness.claude)
+        .await;
+
+    let response = harness.chat(request("normal", true)).await;
+    assert_eq!(response.status(), 200);
+    let events = sse_data(&response.text().await.unwrap());
+
+    // 推理已经发出，之后以finish_reason为error的结束块和[DONE]结束，不发送完成块
+    let chunks: Vec<Value> = events.iter().filter_map(|data| serde_json::from_str(data).ok()).collect();
+    let reasoning: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["reasoning_content"].as_str())
+        .collect();
+    assert_eq!(reasoning, REASONING);
+    assert_eq!(events.last().map(String::as_str), Some("[DONE]"));
+    let last = chunks.last().unwrap();
+    assert_eq!(last["object"], "chat.completion.chunk");
+    assert_eq!(last["choices"][0]["finish_reason"], "error");
+    assert!(last["error"]["message"].as_str().unwrap().contains("bad request from test"));
+    assert!(last["error"]["type"].is_string());
+    assert!(!chunks.iter().any(|chunk| chunk["choices"][0]["finish_reason"] == "stop"));
+}
+
+#[tokio::test]
+async fn a_deepseek_failure_mid_stream_ends_the_stream_with_an_error_chunk() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    let chunk = json!({
+        "id": "ds-1",
+        "object": "chat.completion.chunk",
+        "created": 1,
+        "model": "deepseek-reasoner",
+        "choices": [{ "index": 0, "delta": { "reasoning_content": REASONING }, "finish_reason": null }],
+    });
+    let error = json!({ "error": { "message": "reasoning interrupted", "type": "server_error" } });
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            format!("data: {}\n\ndata: {}\n\n", chunk, error),
+            "text/event-stream",
+        ))
+        .mount(&harness.deepseek)
+        .await;
+    Mock::given(method("POST")).respond_with(claude_stream()).mount(&harness.claude).await;
+
+    let events = sse_data(&harness.chat(request("normal", true)).await.text().await.unwrap());
+    assert_eq!(events.last().map(String::as_str), Some("[DONE]"));
+    let last: Value = serde_json::from_str(&events[events.len() - 2]).unwrap();
+    assert_eq!(last["choices"][0]["finish_reason"], "error");
+    assert!(last["error"]["message"].as_str().unwrap().contains("reasoning interrupted"));
+    // 不完整的推理不交给Claude
+    assert!(harness.claude_requests().await.is_empty());
+}
+
+#[tokio::test]
+async fn missing_upstream_tokens_are_rejected() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+
+    let response = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .json(&request("normal", false))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(harness.deepseek.received_requests().await.unwrap_or_default().is_empty());
+}
+
+/// A `glm` alias for a Zhipu host served by `glm`.
+fn glm_alias(glm: &MockServer) -> ModelAlias {
+    ModelAlias {
+        host: "zhipu".to_string(),
+        model: "glm-4-plus".to_string(),
+        api_url: Some(format!("{}/api/paas/v4/chat/completions", glm.uri())),
+        api_key: Some("key-id.key-secret".to_string()),
+    }
+}
+
+/// A GLM chat completion with the given answer.
+fn glm_completion(content: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "id": "glm-1",
+        "object": "chat.completion",
+        "created": 1,
+        "model": "glm-4-plus",
+        "choices": [{ "index": 0, "message": { "role": "assistant", "content": content }, "finish_reason": "stop" }],
+        "usage": { "prompt_tokens": 40, "completion_tokens": 6, "total_tokens": 46 },
+    }))
+}
+
+#[tokio::test]
+async fn aliased_responder_follows_its_host_conventions() {
+    let glm = MockServer::start().await;
+    let alias = glm_alias(&glm);
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.model_aliases.insert("glm".to_string(), alias);
+    })
+    .await;
+    Mock::given(method("POST")).respond_with(deepseek_completion()).mount(&harness.deepseek).await;
+    Mock::given(method("POST"))
+        .respond_with(glm_completion(CLAUDE_ANSWER))
+        .mount(&glm)
+        .await;
+
+    let mut body = request("normal", false);
+    body["anthropic_config"]["body"]["model"] = json!("glm");
+    let response = harness.chat(body).await;
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["choices"][0]["message"]["content"], CLAUDE_ANSWER);
+    assert!(harness.claude_requests().await.is_empty());
+
+    let requests = glm.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    let sent: Value = requests[0].body_json().unwrap();
+    assert_eq!(sent["model"], "glm-4-plus");
+    assert_eq!(sent["thinking"], json!({ "type": "disabled" }));
+
+    // 智谱的id.secret密钥以签名的JWT发送
+    let authorization = requests[0].headers["authorization"].to_str().unwrap();
+    let token = authorization.strip_prefix("Bearer ").unwrap();
+    let parts: Vec<&str> = token.split('.').collect();
+    assert_eq!(parts.len(), 3);
+    let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+    assert_eq!(claims["api_key"], "key-id");
+    assert!(!token.contains("key-secret"));
+}
+
+#[tokio::test]
+async fn consensus_judge_picks_one_of_two_responders() {
+    let glm = MockServer::start().await;
+    let alias = glm_alias(&glm);
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.model_aliases.insert("glm".to_string(), alias);
+    })
+    .await;
+    Mock::given(method("POST")).respond_with(deepseek_completion()).mount(&harness.deepseek).await;
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({ "model": "claude-3-5-haiku-20241022" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "id": "msg-judge",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-3-5-haiku-20241022",
+            "content": [{ "type": "text", "text": "B" }],
+            "stop_reason": "end_turn",
+            "stop_sequence": null,
+            "usage": { "input_tokens": 50, "output_tokens": 1 },
+        })))
+        .mount(&harness.claude)
+        .await;
+    Mock::given(method("POST")).respond_with(claude_message()).mount(&harness.claude).await;
+    Mock::given(method("POST"))
+        .respond_with(glm_completion("Forty-two."))
+        .mount(&glm)
+        .await;
+
+    let mut body = request("normal", false);
+    body["consensus"] = json!({ "models": ["claude-3-5-sonnet-20241022", "glm"], "strategy": "judge" });
+    let response = harness.chat(body).await;
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["choices"].as_array().unwrap().len(), 1);
+    assert_eq!(body["choices"][0]["message"]["content"], "Forty-two.");
+    assert_eq!(body["consensus"]["verdict"], "b");
+    // 两个回答和裁判的用量都计入响应
+    assert_eq!(body["usage"]["prompt_tokens"], 12 + 40 + 50);
+
+    let sent_to_claude = harness.claude_requests().await;
+    assert_eq!(sent_to_claude.len(), 2);
+    let judge = sent_to_claude.iter().find(|sent| sent["model"] == "claude-3-5-haiku-20241022").unwrap();
+    let prompt = judge["messages"][0]["content"].to_string();
+    assert!(prompt.contains(CLAUDE_ANSWER) && prompt.contains("Forty-two.") && prompt.contains(REASONING.trim()));
+    assert_eq!(glm.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn consensus_is_not_streamed() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    let mut body = request("normal", true);
+    body["consensus"] = json!({ "models": ["claude-3-5-sonnet-20241022", "glm"] });
+    assert_eq!(harness.chat(body).await.status(), 400);
+}
+
+/// Claude's non-streaming answer with the given text and usage.
+fn claude_text(text: &str, input_tokens: u32, output_tokens: u32) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "id": "msg-2",
+        "type": "message",
+        "role": "assistant",
+        "model": "claude-3-7-sonnet-20250219",
+        "content": [{ "type": "text", "text": text }],
+        "stop_reason": "end_turn",
+        "stop_sequence": null,
+        "usage": { "input_tokens": input_tokens, "output_tokens": output_tokens },
+    }))
+}
+
+#[tokio::test]
+async fn refine_critiques_and_rewrites_the_draft_once() {
+    let harness = Harness::start(ClaudeApi::Anthropic).await;
+    Mock::given(method("POST")).respond_with(deepseek_completion()).mount(&harness.deepseek).await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("Review your answer above"))
+        .respond_with(claude_text("It does not show the multiplication.", 30, 8))
+        .mount(&harness.claude)
+        .await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("Rewrite your answer to fix them"))
+        .respond_with(claude_text("Six times seven is 42.", 40, 7))
+        .mount(&harness.claude)
+        .await;
+    Mock::given(method("POST")).respond_with(claude_message()).mount(&harness.claude).await;
+
+    let mut body = request("normal", false);
+    body["refine"] = json!(true);
+    let response = harness.chat(body).await;
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["choices"][0]["message"]["content"], "Six times seven is 42.");
+    assert_eq!(body["usage"]["refine"], json!({ "prompt_tokens": 70, "completion_tokens": 15, "total_tokens": 85 }));
+    assert_eq!(body["usage"]["prompt_tokens"], 12 + 70);
+
+    let sent = harness.claude_requests().await;
+    assert_eq!(sent.len(), 3);
+    // 批评请求带上DeepSeek的推理和草稿
+    let critique = sent[1]["messages"].to_string();
+    assert!(critique.contains(REASONING.trim()) && critique.contains(CLAUDE_ANSWER));
+}
+
+#[tokio::test]
+async fn envelope_answer_is_revised_once_to_match_the_schema() {
+    let glm = MockServer::start().await;
+    let alias = glm_alias(&glm);
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.model_aliases.insert("glm".to_string(), alias);
+        config.envelopes.insert(
+            "glm".to_string(),
+            EnvelopeConfig {
+                schema: json!({
+                    "type": "object",
+                    "required": ["answer", "confidence"],
+                    "properties": { "answer": { "type": "string" }, "confidence": { "type": "number" } },
+                }),
+                json_mode: true,
+            },
+        );
+    })
+    .await;
+    Mock::given(method("POST")).respond_with(deepseek_completion()).mount(&harness.deepseek).await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("does not match the required JSON Schema"))
+        .respond_with(glm_completion("```json\n{\"answer\": \"42\", \"confidence\": 0.9}\n```"))
+        .mount(&glm)
+        .await;
+    Mock::given(method("POST")).respond_with(glm_completion(CLAUDE_ANSWER)).mount(&glm).await;
+
+    let mut body = request("normal", false);
+    body["anthropic_config"]["body"]["model"] = json!("glm");
+    let response = harness.chat(body).await;
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    let answer: Value = serde_json::from_str(body["choices"][0]["message"]["content"].as_str().unwrap()).unwrap();
+    assert_eq!(answer, json!({ "answer": "42", "confidence": 0.9 }));
+
+    let requests = glm.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 2);
+    let sent: Value = requests[0].body_json().unwrap();
+    assert_eq!(sent["response_format"], json!({ "type": "json_object" }));
+    assert!(sent["messages"][0]["content"].as_str().unwrap().contains("JSON Schema"));
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn sqlite_storage_keeps_usage_cache_and_issued_keys() {
+    use deepclaude::storage::{sqlite::Sqlite, KeyBackend, KvCache, Purge, UsageSink};
+    use futures::TryStreamExt;
+
+    let database = std::env::temp_dir().join(format!("deepclaude-{}.db", uuid::Uuid::new_v4()));
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.storage.backend = StorageBackend::Sqlite;
+        config.storage.url = database.to_string_lossy().into_owned();
+        config.keys = vec![serde_json::from_value(json!({
+            "key": "sk-owner",
+            "name": "owner",
+            "tenant": "acme",
+            "manage_keys": true,
+            "deepseek_api_key": "ds-upstream",
+            "anthropic_api_key": "claude-upstream",
+        }))
+        .unwrap()];
+        config.tenants.insert("acme".to_string(), serde_json::from_value(json!({ "max_keys": 1 })).unwrap());
+    })
+    .await;
+    mount_upstreams(&harness).await;
+
+    let issued = harness
+        .client
+        .post(format!("{}/v1/keys", harness.url))
+        .bearer_auth("sk-owner")
+        .json(&json!({ "name": "ci" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(issued.status(), 200);
+    let response: Value = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("sk-owner")
+        .json(&request("normal", false))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let id = response["id"].as_str().unwrap().to_string();
+    harness.state.usage.flush().await;
+
+    // 重新打开数据库文件，用量记录和签发的密钥都还在
+    let store = Sqlite::open(&database.to_string_lossy(), Vec::new()).await.unwrap();
+    let records: Vec<_> = store.read(None, None).try_collect().await.unwrap();
+    let record = records.iter().find(|record| record.id == id).unwrap();
+    assert_eq!(record.tenant.as_deref(), Some("acme"));
+    let today = chrono::Utc::now().date_naive();
+    let tomorrow = today.succ_opt().unwrap();
+    assert!(store.read(Some(tomorrow), None).try_collect::<Vec<_>>().await.unwrap().is_empty());
+    let keys = store.load().await.unwrap();
+    assert_eq!(keys.iter().map(|key| key.name.as_str()).collect::<Vec<_>>(), ["ci"]);
+    store.remove(keys[0].id.as_deref().unwrap()).await.unwrap();
+    assert!(store.load().await.unwrap().is_empty());
+
+    // 缓存条目按前缀字面匹配，过期后不再返回
+    let ttl = std::time::Duration::from_secs(60);
+    store.put("session:a_1", "one".to_string(), ttl).await.unwrap();
+    store.put("session:ab", "two".to_string(), ttl).await.unwrap();
+    store.put("expired", "gone".to_string(), std::time::Duration::ZERO).await.unwrap();
+    assert_eq!(store.get("session:ab").await.unwrap().as_deref(), Some("two"));
+    assert_eq!(store.get("expired").await.unwrap(), None);
+    assert_eq!(store.scan("session:a_").await.unwrap(), [("session:a_1".to_string(), "one".to_string())]);
+    assert_eq!(store.remove_prefix("session:").await.unwrap(), 2);
+    assert_eq!(store.scan("").await.unwrap(), []);
+
+    assert_eq!(store.purge(&Purge::Tenant("acme".to_string())).await.unwrap(), 1);
+    assert_eq!(store.purge(&Purge::Before(tomorrow)).await.unwrap(), 0);
+    std::fs::remove_file(&database).unwrap();
+}
+
+#[tokio::test]
+async fn tenant_headers_without_a_key_are_ignored() {
+    let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+        config.tenants.insert("acme".to_string(), serde_json::from_value(json!({ "monthly_budget_usd": 0.0 })).unwrap());
+    })
+    .await;
+    mount_upstreams(&harness).await;
+    // 不带虚拟密钥的请求不受acme预算限制，也不记到acme名下
+    let response: Value = harness
+        .client
+        .post(format!("{}/v1/chat/completions", harness.url))
+        .bearer_auth("deepseek-token")
+        .header("X-Anthropic-API-Token", "claude-token")
+        .header("OpenAI-Organization", "acme")
+        .header("OpenAI-Project", "search")
+        .json(&request("normal", false))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let id = response["id"].as_str().unwrap();
+    harness.state.usage.flush().await;
+    let records = harness.state.usage.read(None, None).await.unwrap();
+    let record = records.iter().find(|record| record.id == id).unwrap();
+    assert_eq!(record.tenant, None);
+    assert_eq!(record.project, None);
+}
+
+#[tokio::test]
+async fn answers_are_in_chinese_unless_the_language_is_turned_off() {
+    for (target, instructed) in [(None, true), (Some(""), false)] {
+        let harness = Harness::start_with(ClaudeApi::Anthropic, |config| {
+            if let Some(target) = target {
+                config.language.target = target.to_string();
+            }
+        })
+        .await;
+        mount_upstreams(&harness).await;
+        assert_eq!(harness.chat(request("normal", false)).await.status(), 200);
+        let claude = &harness.claude.received_requests().await.unwrap()[0];
+        let body: Value = serde_json::from_slice(&claude.body).unwrap();
+        let system = body["system"].to_string();
+        assert_eq!(system.contains("Always reply to the user in Chinese."), instructed, "{}", system);
+    }
+}
@@ -0,0 +1,120 @@
+//! Shared harness of the end-to-end tests.
+//!
+//! [`Harness::start`] serves the real router on a local port, with the
+//! DeepSeek and Claude upstreams replaced by wiremock servers. The upstream
+//! URLs are process-wide settings, so the tests of a file take turns: the
+//! harness holds a lock until it is dropped.
+
+#![allow(dead_code)]
+
+use deepclaude::{clients::providers, config::Config, handlers::AppState, paths};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+use wiremock::{MockServer, Request};
+
+/// API the Claude upstream is reached through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClaudeApi {
+    /// The Anthropic Messages API, at `ANTHROPIC_API_URL`.
+    Anthropic,
+    /// An OpenAI-format gateway, at `CLAUDE_OPENAI_TYPE_API_URL`.
+    OpenAi,
+}
+
+/// Serializes the tests, since they swap the global provider settings.
+static UPSTREAMS: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// A running proxy and its mocked upstreams.
+pub struct Harness {
+    pub url: String,
+    pub deepseek: MockServer,
+    pub claude: MockServer,
+    pub client: reqwest::Client,
+    pub state: Arc<AppState>,
+    _turn: MutexGuard<'static, ()>,
+}
+
+impl Harness {
+    pub async fn start(claude_api: ClaudeApi) -> Self {
+        Self::start_with(claude_api, |_| {}).await
+    }
+
+    /// Starts the proxy with `configure` applied to its default configuration.
+    pub async fn start_with(claude_api: ClaudeApi, configure: impl FnOnce(&mut Config)) -> Self {
+        let turn = UPSTREAMS.lock().await;
+        let deepseek = MockServer::start().await;
+        let claude = MockServer::start().await;
+
+        // 配置目录指向空的临时目录，避免读取开发环境中的.env
+        let dir = std::env::temp_dir().join(format!("deepclaude-tests-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        paths::init(Some(dir));
+        std::env::set_var("DEEPSEEK_OPENAI_TYPE_API_URL", format!("{}/chat/completions", deepseek.uri()));
+        std::env::set_var("ANTHROPIC_API_URL", format!("{}/v1/messages", claude.uri()));
+        match claude_api {
+            ClaudeApi::Anthropic => std::env::remove_var("CLAUDE_OPENAI_TYPE_API_URL"),
+            ClaudeApi::OpenAi => {
+                std::env::set_var("CLAUDE_OPENAI_TYPE_API_URL", format!("{}/v1/chat/completions", claude.uri()))
+            }
+        }
+        for var in ["DEEPSEEK_API_KEY", "ANTHROPIC_API_KEY", "DEEPSEEK_DEFAULT_MODEL", "CLAUDE_DEFAULT_MODEL"] {
+            std::env::remove_var(var);
+        }
+        providers::reload(false, &Default::default()).await.unwrap();
+
+        let mut config = Config::default();
+        configure(&mut config);
+        config.resolve_paths();
+        let state = Arc::new(AppState::new(config).await.unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = deepclaude::router(state.clone());
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
+                .await
+                .unwrap();
+        });
+
+        Self {
+            url,
+            deepseek,
+            claude,
+            client: reqwest::Client::new(),
+            state,
+            _turn: turn,
+        }
+    }
+
+    /// Sends a chat request with upstream tokens in the headers.
+    pub async fn chat(&self, body: Value) -> reqwest::Response {
+        self.client
+            .post(format!("{}/v1/chat/completions", self.url))
+            .bearer_auth("deepseek-token")
+            .header("X-Anthropic-API-Token", "claude-token")
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    /// Bodies of the requests Claude received.
+    pub async fn claude_requests(&self) -> Vec<Value> {
+        let requests: Vec<Request> = self.claude.received_requests().await.unwrap_or_default();
+        requests.iter().map(|request| request.body_json().unwrap()).collect()
+    }
+}
+
+/// Splits an SSE body into the data of its events, checking the framing.
+pub fn sse_data(body: &str) -> Vec<String> {
+    assert!(body.ends_with("\n\n"), "stream does not end with a blank line: {:?}", body);
+    body.split_terminator("\n\n")
+        .filter(|event| !event.starts_with(':'))
+        .map(|event| {
+            let data: Vec<&str> = event.lines().filter_map(|line| line.strip_prefix("data: ")).collect();
+            assert_eq!(data.len(), 1, "event without exactly one data line: {:?}", event);
+            data[0].to_string()
+        })
+        .collect()
+}
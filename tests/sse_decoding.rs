@@ -0,0 +1,283 @@
+//! Tests of SSE decoding across network chunk boundaries.
+//!
+//! Upstreams flush whenever they like, so a chunk can end inside a line or
+//! inside a multi-byte UTF-8 sequence, and keep-alive comments arrive
+//! between events. Decoding must not depend on where the chunks split.
+
+use deepclaude::{
+    clients::{
+        anthropic::StreamEvent,
+        hosts::{HostRoute, OPENAI_USAGE},
+        repair,
+        parse::{claude_stream_line, repair_line, sse_line, ClaudeLine, LineBuffer, ParseError, Repair, SseLine},
+        DeepSeekClient,
+    },
+    config::ModelAlias,
+    models::request::{ApiConfig, Message, Role},
+};
+use futures::StreamExt;
+use serde_json::json;
+use std::{collections::HashMap, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+const REASONING: [&str; 3] = ["先想一想：", "六乘七等于四十二。", "结果已验证✓"];
+const ANSWER: &str = "答案是42。";
+
+/// A DeepSeek stream with keep-alives, CRLF line endings and a last event
+/// without its line ending.
+fn transcript() -> String {
+    let chunk = |delta: serde_json::Value| {
+        json!({
+            "id": "ds-1",
+            "object": "chat.completion.chunk",
+            "created": 1,
+            "model": "deepseek-r1",
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": null }],
+        })
+    };
+    let mut body = String::from(": keep-alive\n\n");
+    for (i, text) in REASONING.iter().enumerate() {
+        body.push_str(&format!("data: {}\r\n\r\n", chunk(json!({ "reasoning_content": text }))));
+        if i == 0 {
+            body.push_str(": keep-alive\r\n\r\n");
+        }
+    }
+    body.push_str(&format!("data: {}", chunk(json!({ "content": ANSWER }))));
+    body
+}
+
+#[test]
+fn line_buffer_is_independent_of_chunk_boundaries() {
+    let body = transcript();
+    let mut whole = LineBuffer::default();
+    let mut expected = whole.push(body.as_bytes());
+    expected.extend(whole.finish());
+
+    for split in 0..=body.len() {
+        let (head, tail) = body.as_bytes().split_at(split);
+        let mut lines = LineBuffer::default();
+        let mut actual = lines.push(head);
+        actual.extend(lines.push(tail));
+        actual.extend(lines.finish());
+        assert_eq!(actual, expected, "split at byte {}", split);
+    }
+    assert!(expected.iter().any(|line| line.contains("六乘七等于四十二")));
+}
+
+#[test]
+fn line_buffer_holds_partial_lines_and_codepoints() {
+    let mut lines = LineBuffer::default();
+    assert!(lines.push(b"data: a").is_empty());
+    assert_eq!(lines.push(b"b\r\n\ndata: c\n"), ["data: ab", "", "data: c"]);
+
+    // 多字节字符被拆在两个数据块之间时等到完整再解码
+    let text = "data: 答\n".as_bytes();
+    assert!(lines.push(&text[..7]).is_empty());
+    assert_eq!(lines.push(&text[7..]), ["data: 答"]);
+
+    // 没有换行结尾的最后一行在流结束时返回，之后不再重复
+    assert!(lines.push(b"data: [DONE]").is_empty());
+    assert_eq!(lines.finish(), ["data: [DONE]"]);
+    assert!(lines.finish().is_empty());
+}
+
+/// Parses a Claude stream line that must hold events.
+fn claude_events(line: &str) -> Vec<StreamEvent> {
+    match claude_stream_line(line, &OPENAI_USAGE) {
+        Ok(ClaudeLine::Events(events)) => events,
+        other => panic!("expected events from {:?}, got {:?}", line, other),
+    }
+}
+
+#[test]
+fn openai_chunks_become_claude_events() {
+    let content = json!({ "choices": [{ "index": 0, "delta": { "content": "42" }, "finish_reason": null }] });
+    let events = claude_events(&format!("data: {}", content));
+    assert!(matches!(&events[..], [StreamEvent::ContentBlockDelta { delta, .. }] if delta.text == "42"));
+
+    // 完成块的delta为空，完成原因和用量分别变成message_delta和message_stop
+    let finish = json!({
+        "choices": [{ "index": 0, "delta": {}, "finish_reason": "stop" }],
+        "usage": { "prompt_tokens": 10, "completion_tokens": 3 },
+    });
+    let events = claude_events(&format!("data: {}", finish));
+    match &events[..] {
+        [StreamEvent::MessageDelta { delta, usage: Some(usage) }, StreamEvent::MessageStop] => {
+            assert_eq!(delta.stop_reason.as_deref(), Some("stop"));
+            assert_eq!((usage.input_tokens, usage.output_tokens), (10, 3));
+        }
+        other => panic!("unexpected events {:?}", other),
+    }
+
+    // 没有内容也没有完成原因的块不产生事件
+    let empty = json!({ "choices": [{ "index": 0, "delta": { "role": "assistant" }, "finish_reason": null }] });
+    assert!(claude_events(&format!("data: {}", empty)).is_empty());
+    assert!(matches!(claude_stream_line("data: [DONE]", &OPENAI_USAGE), Ok(ClaudeLine::Done)));
+    assert!(matches!(claude_stream_line("data:[DONE]\r", &OPENAI_USAGE), Ok(ClaudeLine::Done)));
+}
+
+#[test]
+fn claude_keepalives_and_split_lines_are_not_events() {
+    for line in ["", "event: ping", ": HEARTBEAT", ": keep-alive", "data: "] {
+        assert!(matches!(claude_stream_line(line, &OPENAI_USAGE), Ok(ClaudeLine::Skip)), "{:?}", line);
+    }
+    assert!(matches!(&claude_events(r#"data: {"type": "ping"}"#)[..], [StreamEvent::Ping]));
+    // data行里的HEARTBEAT不是JSON，由客户端决定忽略
+    assert!(matches!(claude_stream_line("data: HEARTBEAT", &OPENAI_USAGE), Err(ParseError::Json(_))));
+
+    // 被截断的行报告为不完整，按行缓冲拼接后可以正常解析
+    let line = r#"data: {"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "hi"}}"#;
+    assert!(matches!(claude_stream_line(&line[..40], &OPENAI_USAGE), Err(ParseError::Incomplete(_))));
+    let mut lines = LineBuffer::default();
+    assert!(lines.push(&line.as_bytes()[..40]).is_empty());
+    let joined = lines.push(format!("{}\n", &line[40..]).as_bytes());
+    assert!(matches!(&claude_events(&joined[0])[..], [StreamEvent::ContentBlockDelta { delta, .. }] if delta.text == "hi"));
+}
+
+#[test]
+fn sse_lines_are_classified() {
+    assert_eq!(sse_line("data: {}"), SseLine::Data("{}"));
+    assert_eq!(sse_line("data:{}\r"), SseLine::Data("{}"));
+    assert_eq!(sse_line(": keep-alive"), SseLine::Comment("keep-alive"));
+    assert_eq!(sse_line(""), SseLine::Blank);
+    assert_eq!(sse_line("\r"), SseLine::Blank);
+    assert_eq!(sse_line("event: ping"), SseLine::Field);
+}
+
+#[test]
+fn damaged_lines_are_repaired() {
+    let (line, repairs) = repair_line("\u{feff}data: data: {\"a\": [1, 2,], \"b\": \"x,}\",}");
+    assert_eq!(line, "data: {\"a\": [1, 2], \"b\": \"x,}\"}");
+    assert_eq!(repairs, [Repair::Bom, Repair::DuplicateDataPrefix, Repair::TrailingComma]);
+
+    // 完好的行原样返回
+    for line in ["data: {\"a\": \",]\"}", "data: [DONE]", ": keep-alive", ""] {
+        let (repaired, repairs) = repair_line(line);
+        assert_eq!(repaired, line);
+        assert!(repairs.is_empty());
+    }
+}
+
+/// Serves one streaming response, writing `body` in HTTP chunks of `size`
+/// bytes with a pause between them so that each arrives on its own.
+async fn serve_in_chunks(body: String, size: usize) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/v1/chat/completions", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        // 读完请求头和请求体再响应
+        loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    break;
+                }
+            }
+        }
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ntransfer-encoding: chunked\r\n\r\n")
+            .await
+            .unwrap();
+        for piece in body.as_bytes().chunks(size) {
+            socket.write_all(format!("{:x}\r\n", piece.len()).as_bytes()).await.unwrap();
+            socket.write_all(piece).await.unwrap();
+            socket.write_all(b"\r\n").await.unwrap();
+            socket.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        socket.write_all(b"0\r\n\r\n").await.unwrap();
+    });
+    url
+}
+
+#[tokio::test]
+async fn deepseek_stream_decodes_codepoints_split_across_chunks() {
+    // 5字节的块会把三字节的中文字符切开
+    let url = serve_in_chunks(transcript(), 5).await;
+    let aliases = HashMap::from([(
+        "r1".to_string(),
+        ModelAlias {
+            host: "siliconflow".to_string(),
+            model: "deepseek-r1".to_string(),
+            api_url: Some(url),
+            api_key: Some("test-key".to_string()),
+        },
+    )]);
+    let client = DeepSeekClient::new("unused".to_string()).with_route(HostRoute::for_model(&aliases, Some("r1")));
+    let messages = vec![Message {
+        role: Role::User,
+        content: "六乘七是多少？".to_string(),
+    }];
+    let config = ApiConfig {
+        headers: HashMap::new(),
+        body: json!({ "model": "r1" }),
+    };
+
+    let mut stream = client.chat_stream(messages, &config);
+    let mut reasoning = String::new();
+    let mut answer = String::new();
+    while let Some(response) = stream.next().await {
+        let response = response.unwrap();
+        if let Some(choice) = response.choices.first() {
+            reasoning.push_str(choice.delta.reasoning_content.as_deref().unwrap_or_default());
+            answer.push_str(choice.delta.content.as_deref().unwrap_or_default());
+        }
+    }
+    drop(stream);
+
+    assert_eq!(reasoning, REASONING.concat());
+    assert_eq!(answer, ANSWER);
+    assert_eq!(client.stream_timing().unwrap().keep_alives, 2);
+}
+
+#[tokio::test]
+async fn deepseek_stream_keeps_the_tokens_of_damaged_lines() {
+    // 事件带有BOM、重复的data:前缀和尾随逗号，修复后不丢失任何内容
+    let body = transcript()
+        .replacen("data: ", "\u{feff}data: ", 1)
+        .replacen("data: {", "data: data: {", 2)
+        .replace("}],", "},],");
+    let url = serve_in_chunks(body, 64).await;
+    let aliases = HashMap::from([(
+        "r1".to_string(),
+        ModelAlias {
+            host: "siliconflow".to_string(),
+            model: "deepseek-r1".to_string(),
+            api_url: Some(url),
+            api_key: Some("test-key".to_string()),
+        },
+    )]);
+    let client = DeepSeekClient::new("unused".to_string()).with_route(HostRoute::for_model(&aliases, Some("r1")));
+    let config = ApiConfig {
+        headers: HashMap::new(),
+        body: json!({ "model": "r1" }),
+    };
+
+    let messages = vec![Message {
+        role: Role::User,
+        content: "六乘七是多少？".to_string(),
+    }];
+    let mut stream = client.chat_stream(messages, &config);
+    let mut text = String::new();
+    while let Some(response) = stream.next().await {
+        let response = response.unwrap();
+        if let Some(choice) = response.choices.first() {
+            text.push_str(choice.delta.reasoning_content.as_deref().unwrap_or_default());
+            text.push_str(choice.delta.content.as_deref().unwrap_or_default());
+        }
+    }
+    assert_eq!(text, REASONING.concat() + ANSWER);
+    assert!(repair::render().contains("kind=\"trailing_comma\"} 4"));
+}
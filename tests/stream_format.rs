@@ -0,0 +1,108 @@
+//! Golden-file tests of stream format translation.
+//!
+//! Each directory under `tests/fixtures/streams` holds a recorded upstream
+//! transcript per stage (`deepseek.sse`, `claude.sse`), the request shape
+//! (`case.json`) and the OpenAI chunks the client is expected to receive
+//! (`expected.jsonl`, one event's data per line). The upstreams replay the
+//! transcripts byte for byte, so a change to the SSE parsers or the chunk
+//! builders that alters what clients see fails here.
+//!
+//! Chunk ids and timestamps differ between runs and are left out of the
+//! comparison. After an intended change of the output, regenerate the
+//! expected files with `UPDATE_GOLDEN=1 cargo test --test stream_format`
+//! and review the diff.
+
+mod common;
+
+use common::{sse_data, ClaudeApi, Harness};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use wiremock::{matchers::method, Mock, ResponseTemplate};
+
+/// Request shape of a case, from its `case.json`.
+#[derive(Debug, Deserialize)]
+struct Case {
+    mode: String,
+    claude_api: String,
+}
+
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/streams")
+}
+
+fn transcript(body: String) -> ResponseTemplate {
+    ResponseTemplate::new(200).insert_header("content-type", "text/event-stream").set_body_string(body)
+}
+
+/// An event's data with the per-run fields removed.
+fn normalize(data: &str) -> String {
+    let Ok(mut value) = serde_json::from_str::<Value>(data) else {
+        return data.to_string();
+    };
+    if let Some(chunk) = value.as_object_mut() {
+        chunk.remove("id");
+        chunk.remove("created");
+    }
+    value.to_string()
+}
+
+async fn check(name: &str) {
+    let dir = fixtures().join(name);
+    let read = |file: &str| std::fs::read_to_string(dir.join(file)).unwrap();
+    let case: Case = serde_json::from_str(&read("case.json")).unwrap();
+    let claude_api = match case.claude_api.as_str() {
+        "anthropic" => ClaudeApi::Anthropic,
+        "openai" => ClaudeApi::OpenAi,
+        other => panic!("unknown claude_api {:?} in {}", other, name),
+    };
+
+    let harness = Harness::start(claude_api).await;
+    Mock::given(method("POST")).respond_with(transcript(read("deepseek.sse"))).mount(&harness.deepseek).await;
+    Mock::given(method("POST")).respond_with(transcript(read("claude.sse"))).mount(&harness.claude).await;
+
+    let response = harness
+        .chat(json!({
+            "model": "deepclaude",
+            "mode": case.mode,
+            "stream": true,
+            "messages": [{ "role": "user", "content": "What is six times seven?" }],
+            "deepseek_config": { "headers": {}, "body": {} },
+            "anthropic_config": { "headers": {}, "body": {} },
+        }))
+        .await;
+    assert_eq!(response.status(), 200);
+    let actual: String = sse_data(&response.text().await.unwrap())
+        .iter()
+        .map(|data| normalize(data) + "\n")
+        .collect();
+
+    let expected = dir.join("expected.jsonl");
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&expected, &actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(expected).unwrap();
+    assert!(
+        actual == expected,
+        "stream of {} differs from expected.jsonl\n--- expected\n{}--- actual\n{}",
+        name,
+        expected,
+        actual
+    );
+}
+
+#[tokio::test]
+async fn ark_reasoning_with_anthropic_answer() {
+    check("ark_anthropic").await;
+}
+
+#[tokio::test]
+async fn ark_reasoning_with_openai_gateway_answer() {
+    check("ark_openai_gateway").await;
+}
+
+#[tokio::test]
+async fn ark_full_mode_with_anthropic_answer() {
+    check("ark_anthropic_full_mode").await;
+}